@@ -0,0 +1,601 @@
+//! API for driving nh from other programs, like GUIs or deploy daemons.
+//!
+//! The options here don't depend on clap. They are turned into the
+//! arguments of the command behind them by the same parser as the command
+//! line, so options left unset get the same defaults: from `NH_*`
+//! variables, the nh configuration file, or the defaults of the flags. The
+//! flake to build comes from `NH_OS_FLAKE`, `NH_FLAKE` and the like. Results
+//! are returned instead of printed. Progress messages and diffs are still printed, unless
+//! [`crate::output::enable_quiet`] was called.
+//!
+//! ```no_run
+//! use nh::api::{RebuildAction, RebuildOptions, Platform};
+//!
+//! let result = RebuildOptions::new(Platform::NixOS, RebuildAction::Build)
+//!     .installable("/etc/nixos")
+//!     .hostname("web")
+//!     .run()?;
+//! println!("{}", result.out_path.display());
+//! # Ok::<(), color_eyre::Report>(())
+//! ```
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{Args, FromArgMatches};
+use color_eyre::eyre::{Context, bail, eyre};
+
+use crate::Result;
+use crate::checks::{FeatureRequirements, FlakeFeatures, LegacyFeatures, NoFeatures};
+use crate::config;
+use crate::darwin::DarwinRebuildVariant;
+use crate::exit::Failure;
+use crate::home::HomeRebuildVariant;
+use crate::installable::Installable;
+use crate::interface::{CleanMode, DarwinRebuildArgs, HomeRebuildArgs, OsRebuildArgs, SearchArgs};
+pub use crate::json::{CleanResult, RebuildResult};
+use crate::nixos::OsRebuildVariant;
+pub use crate::search::{SearchOutput, SearchResult};
+
+/// What kind of configuration a rebuild is for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    NixOS,
+    HomeManager,
+    Darwin,
+}
+
+/// What to do with the built configuration
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RebuildAction {
+    /// Activate it and make it the default
+    Switch,
+    /// Make it the default on the next boot, `NixOS` only
+    Boot,
+    /// Activate it without making it the default, `NixOS` only
+    Test,
+    /// Only build it
+    Build,
+}
+
+impl Platform {
+    /// The nh command for the platform, which picks its `NH_*_FLAKE`
+    const fn command(self) -> &'static str {
+        match self {
+            Self::NixOS => "os",
+            Self::HomeManager => "home",
+            Self::Darwin => "darwin",
+        }
+    }
+}
+
+impl RebuildAction {
+    const fn name(self) -> &'static str {
+        match self {
+            Self::Switch => "switch",
+            Self::Boot => "boot",
+            Self::Test => "test",
+            Self::Build => "build",
+        }
+    }
+}
+
+/// A rebuild, like `nh os switch`.
+#[derive(Debug, Clone)]
+pub struct RebuildOptions {
+    platform: Platform,
+    action: RebuildAction,
+    installable: Option<String>,
+    hostname: Option<String>,
+    specialisation: Option<String>,
+    target_host: Option<String>,
+    build_host: Option<String>,
+    dry: bool,
+    diff: bool,
+    update: bool,
+    nom: bool,
+    extra_args: Vec<String>,
+}
+
+impl RebuildOptions {
+    #[must_use]
+    pub const fn new(platform: Platform, action: RebuildAction) -> Self {
+        Self {
+            platform,
+            action,
+            installable: None,
+            hostname: None,
+            specialisation: None,
+            target_host: None,
+            build_host: None,
+            dry: false,
+            diff: false,
+            update: false,
+            nom: false,
+            extra_args: Vec::new(),
+        }
+    }
+
+    /// The flake or file to build, `NH_FLAKE` and the like by default
+    #[must_use]
+    pub fn installable(mut self, installable: impl Into<String>) -> Self {
+        self.installable = Some(installable.into());
+        self
+    }
+
+    /// The configuration to build: the host for `NixOS` and nix-darwin, and
+    /// the home configuration for home-manager
+    #[must_use]
+    pub fn hostname(mut self, hostname: impl Into<String>) -> Self {
+        self.hostname = Some(hostname.into());
+        self
+    }
+
+    #[must_use]
+    pub fn specialisation(mut self, specialisation: impl Into<String>) -> Self {
+        self.specialisation = Some(specialisation.into());
+        self
+    }
+
+    /// Deploy to this host over ssh, `NixOS` only
+    #[must_use]
+    pub fn target_host(mut self, host: impl Into<String>) -> Self {
+        self.target_host = Some(host.into());
+        self
+    }
+
+    /// Build on this host over ssh, `NixOS` only
+    #[must_use]
+    pub fn build_host(mut self, host: impl Into<String>) -> Self {
+        self.build_host = Some(host.into());
+        self
+    }
+
+    /// Only build and compare, without activating
+    #[must_use]
+    pub const fn dry(mut self, dry: bool) -> Self {
+        self.dry = dry;
+        self
+    }
+
+    /// Print the package diff to the current configuration
+    #[must_use]
+    pub const fn diff(mut self, diff: bool) -> Self {
+        self.diff = diff;
+        self
+    }
+
+    /// Update all flake inputs before building
+    #[must_use]
+    pub const fn update(mut self, update: bool) -> Self {
+        self.update = update;
+        self
+    }
+
+    /// Show the build with nix-output-monitor
+    #[must_use]
+    pub const fn nom(mut self, nom: bool) -> Self {
+        self.nom = nom;
+        self
+    }
+
+    /// Pass an argument on to `nix build`
+    #[must_use]
+    pub fn extra_arg(mut self, arg: impl Into<String>) -> Self {
+        self.extra_args.push(arg.into());
+        self
+    }
+
+    fn validate(&self) -> Result<()> {
+        let nixos = self.platform == Platform::NixOS;
+        if !nixos && matches!(self.action, RebuildAction::Boot | RebuildAction::Test) {
+            bail!("{} is only supported for NixOS", self.action.name());
+        }
+        if !nixos && (self.target_host.is_some() || self.build_host.is_some()) {
+            bail!("Target and build hosts are only supported for NixOS");
+        }
+        if self.platform == Platform::Darwin && self.specialisation.is_some() {
+            bail!("Specialisations are only supported for NixOS and home-manager");
+        }
+        Ok(())
+    }
+
+    /// The installable given, or the default of the platform.
+    fn resolve_installable(&self) -> Result<Installable> {
+        match &self.installable {
+            Some(installable) => Ok(Installable::parse(installable)),
+            None => Installable::from_env(Some(self.platform.command())).ok_or_else(|| {
+                eyre!("No installable given, and no flake was found in NH_FLAKE or the current directory")
+            }),
+        }
+    }
+
+    /// The command line arguments the options stand for.
+    fn args(&self, installable: &Installable) -> Vec<OsString> {
+        let mut args: Vec<OsString> = installable.to_args().into_iter().map(Into::into).collect();
+        args.extend(["--diff", if self.diff { "always" } else { "never" }].map(Into::into));
+        for (flag, set) in [
+            ("--dry", self.dry),
+            ("--update", self.update),
+            ("--no-nom", !self.nom),
+            // As root, there is nothing to elevate to
+            (
+                "--bypass-root-check",
+                self.platform == Platform::NixOS && nix::unistd::Uid::effective().is_root(),
+            ),
+        ] {
+            if set {
+                args.push(flag.into());
+            }
+        }
+        let hostname = match self.platform {
+            Platform::HomeManager => "--configuration",
+            Platform::NixOS | Platform::Darwin => "--hostname",
+        };
+        for (flag, value) in [
+            (hostname, &self.hostname),
+            ("--specialisation", &self.specialisation),
+            ("--target-host", &self.target_host),
+            ("--build-host", &self.build_host),
+        ] {
+            if let Some(value) = value {
+                args.extend([flag, value].map(Into::into));
+            }
+        }
+        if !self.extra_args.is_empty() {
+            args.push("--".into());
+            args.extend(self.extra_args.iter().map(Into::into));
+        }
+        args
+    }
+
+    /// Build the configuration and activate it as asked.
+    pub fn run(&self) -> Result<RebuildResult> {
+        self.validate()?;
+        let installable = self.resolve_installable()?;
+        check_features(if matches!(installable, Installable::Flake { .. }) {
+            &FlakeFeatures as &dyn FeatureRequirements
+        } else {
+            &LegacyFeatures
+        })?;
+        let args = self.args(&installable);
+
+        match self.platform {
+            Platform::NixOS => {
+                let mut args: OsRebuildArgs = parse(args)?;
+                args.common.ask = false;
+                args.build_and_activate(
+                    &match self.action {
+                        RebuildAction::Switch => OsRebuildVariant::Switch,
+                        RebuildAction::Boot => OsRebuildVariant::Boot,
+                        RebuildAction::Test => OsRebuildVariant::Test,
+                        RebuildAction::Build => OsRebuildVariant::Build,
+                    },
+                    None,
+                )
+            }
+            Platform::HomeManager => {
+                let mut args: HomeRebuildArgs = parse(args)?;
+                args.common.ask = false;
+                args.build_and_activate(&match self.action {
+                    RebuildAction::Switch => HomeRebuildVariant::Switch,
+                    _ => HomeRebuildVariant::Build,
+                })
+            }
+            Platform::Darwin => {
+                let mut args: DarwinRebuildArgs = parse(args)?;
+                args.common.ask = false;
+                args.build_and_activate(&match self.action {
+                    RebuildAction::Switch => DarwinRebuildVariant::Switch,
+                    _ => DarwinRebuildVariant::Build,
+                })
+            }
+        }
+    }
+}
+
+/// What a cleanup looks at
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CleanScope {
+    /// The profiles of all users and the gcroots, which needs root
+    All,
+    /// The profiles of the current user and the gcroots
+    User,
+    /// A single profile
+    Profile(PathBuf),
+}
+
+/// A cleanup, like `nh clean all`.
+#[derive(Debug, Clone)]
+pub struct CleanOptions {
+    scope: CleanScope,
+    keep: Option<u32>,
+    keep_since: Option<Duration>,
+    dry: bool,
+    gc: bool,
+    gcroots: bool,
+}
+
+impl CleanOptions {
+    #[must_use]
+    pub const fn new(scope: CleanScope) -> Self {
+        Self {
+            scope,
+            keep: None,
+            keep_since: None,
+            dry: false,
+            gc: true,
+            gcroots: true,
+        }
+    }
+
+    /// At least keep this number of generations of each profile, 1 unless
+    /// the configuration says otherwise
+    #[must_use]
+    pub const fn keep(mut self, keep: u32) -> Self {
+        self.keep = Some(keep);
+        self
+    }
+
+    /// Keep generations and gcroots younger than this
+    #[must_use]
+    pub const fn keep_since(mut self, keep_since: Duration) -> Self {
+        self.keep_since = Some(keep_since);
+        self
+    }
+
+    /// Only work out what would be removed
+    #[must_use]
+    pub const fn dry(mut self, dry: bool) -> Self {
+        self.dry = dry;
+        self
+    }
+
+    /// Collect garbage in the store afterwards
+    #[must_use]
+    pub const fn gc(mut self, gc: bool) -> Self {
+        self.gc = gc;
+        self
+    }
+
+    /// Remove gcroots like `result` links too
+    #[must_use]
+    pub const fn gcroots(mut self, gcroots: bool) -> Self {
+        self.gcroots = gcroots;
+        self
+    }
+
+    /// The command line arguments the options stand for.
+    fn args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        if let Some(keep) = self.keep {
+            args.extend(["--keep".into(), keep.to_string().into()]);
+        }
+        if let Some(keep_since) = self.keep_since {
+            args.extend([
+                "--keep-since".into(),
+                humantime::format_duration(keep_since).to_string().into(),
+            ]);
+        }
+        for (flag, set) in [
+            ("--dry", self.dry),
+            ("--nogc", !self.gc),
+            ("--nogcroots", !self.gcroots),
+        ] {
+            if set {
+                args.push(flag.into());
+            }
+        }
+        args
+    }
+
+    fn mode(&self) -> Result<CleanMode> {
+        let mut mode = match &self.scope {
+            CleanScope::All => CleanMode::All(parse(self.args())?),
+            CleanScope::User => CleanMode::User(parse(self.args())?),
+            CleanScope::Profile(profile) => {
+                let mut args = self.args();
+                args.extend(["--".into(), profile.into()]);
+                CleanMode::Profile(parse(args)?)
+            }
+        };
+        match &mut mode {
+            CleanMode::All(args) | CleanMode::User(args) => args.ask = false,
+            CleanMode::Profile(args) => args.common.ask = false,
+        }
+        Ok(mode)
+    }
+
+    /// Remove old generations and gcroots, and collect garbage.
+    pub fn run(&self) -> Result<CleanResult> {
+        // The command line restarts itself with sudo instead
+        if self.scope == CleanScope::All && !nix::unistd::Uid::effective().is_root() {
+            bail!("Cleaning the profiles of all users needs root");
+        }
+        check_features(&NoFeatures)?;
+        self.mode()?.clean()
+    }
+}
+
+/// A package search on search.nixos.org, like `nh search`.
+#[derive(Debug, Clone)]
+pub struct SearchQuery {
+    query: String,
+    channel: Option<String>,
+    limit: Option<u64>,
+}
+
+impl SearchQuery {
+    #[must_use]
+    pub fn new(query: impl Into<String>) -> Self {
+        Self {
+            query: query.into(),
+            channel: None,
+            limit: None,
+        }
+    }
+
+    /// The channel to search, like `nixos-25.05`, `nixos-unstable` by
+    /// default
+    #[must_use]
+    pub fn channel(mut self, channel: impl Into<String>) -> Self {
+        self.channel = Some(channel.into());
+        self
+    }
+
+    #[must_use]
+    pub const fn limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    /// The command line arguments the options stand for.
+    fn args(&self) -> Vec<OsString> {
+        let mut args: Vec<OsString> = Vec::new();
+        if let Some(channel) = &self.channel {
+            args.extend(["--channel".into(), channel.into()]);
+        }
+        if let Some(limit) = self.limit {
+            args.extend(["--limit".into(), limit.to_string().into()]);
+        }
+        args.extend(["--".into(), (&self.query).into()]);
+        args
+    }
+
+    /// Search, returning the most relevant package last.
+    pub fn run(&self) -> Result<SearchOutput> {
+        parse::<SearchArgs>(self.args())?.fetch(true)
+    }
+}
+
+impl SearchResult {
+    /// Attribute of the package in nixpkgs, like `python3Packages.requests`
+    #[must_use]
+    pub fn attr(&self) -> &str {
+        &self.package_attr_name
+    }
+
+    #[must_use]
+    pub fn version(&self) -> &str {
+        &self.package_pversion
+    }
+
+    #[must_use]
+    pub fn description(&self) -> Option<&str> {
+        self.package_description.as_deref()
+    }
+
+    #[must_use]
+    pub fn homepages(&self) -> &[String] {
+        &self.package_homepage
+    }
+
+    /// Programs in the `bin` directory of the package
+    #[must_use]
+    pub fn programs(&self) -> &[String] {
+        &self.package_programs
+    }
+
+    #[must_use]
+    pub fn platforms(&self) -> &[String] {
+        &self.package_platforms
+    }
+}
+
+/// Parse the arguments of a command like the command line does, with the
+/// defaults of its flags and the configuration. The command line gets the
+/// defaults from the configuration through the environment, see
+/// [`config::init`], which a library shouldn't change.
+fn parse<T: Args + FromArgMatches>(args: Vec<OsString>) -> Result<T> {
+    let defaults = config::get().env_defaults();
+    let command = T::augment_args(clap::Command::new("nh").no_binary_name(true)).mut_args(|arg| {
+        let default = arg
+            .get_env()
+            .filter(|var| std::env::var_os(var).is_none())
+            .and_then(|var| defaults.iter().find(|(name, _)| var == *name))
+            .map(|(_, value)| value.clone());
+        match default {
+            Some(value) => arg.default_value(value),
+            None => arg,
+        }
+    });
+    let matches = command.try_get_matches_from(args)?;
+    Ok(T::from_arg_matches(&matches)?)
+}
+
+/// Check that nix has the features a command needs.
+fn check_features(requirements: &dyn FeatureRequirements) -> Result<()> {
+    requirements.check_features().wrap_err(Failure::Environment)
+}
+
+#[cfg(test)]
+mod tests {
+    use serial_test::serial;
+
+    use super::*;
+    use crate::interface::DiffType;
+    use crate::test_util::EnvGuard;
+
+    #[test]
+    fn test_rebuild_options() {
+        let options = RebuildOptions::new(Platform::NixOS, RebuildAction::Boot)
+            .installable("/etc/nixos#web")
+            .target_host("root@web")
+            .dry(true)
+            .extra_arg("--impure");
+        options.validate().unwrap();
+        let installable = options.resolve_installable().unwrap();
+        assert!(matches!(
+            &installable,
+            Installable::Flake { reference, attribute }
+                if reference == "/etc/nixos" && attribute == &["web"]
+        ));
+        let args: OsRebuildArgs = parse(options.args(&installable)).unwrap();
+        assert_eq!(args.target_host.as_deref(), Some("root@web"));
+        assert_eq!(args.extra_args, ["--impure"]);
+        assert!(args.common.dry && args.common.no_nom);
+        assert_eq!(args.common.diff, DiffType::Never);
+        assert_eq!(args.common.min_free_space, 1024 * 1024 * 1024);
+
+        for options in [
+            RebuildOptions::new(Platform::HomeManager, RebuildAction::Boot),
+            RebuildOptions::new(Platform::Darwin, RebuildAction::Switch).target_host("mac"),
+            RebuildOptions::new(Platform::Darwin, RebuildAction::Switch).specialisation("x"),
+        ] {
+            assert!(options.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_clean_options() {
+        let options = CleanOptions::new(CleanScope::Profile(PathBuf::from("/tmp/profile")))
+            .keep(3)
+            .keep_since(Duration::from_secs(7 * 86400))
+            .gc(false);
+        let CleanMode::Profile(args) = options.mode().unwrap() else {
+            panic!("not a profile cleanup");
+        };
+        assert_eq!(args.profile, PathBuf::from("/tmp/profile"));
+        assert_eq!(args.common.keep, 3);
+        assert!(args.common.nogc && !args.common.nogcroots && !args.common.ask);
+    }
+
+    #[test]
+    #[serial]
+    fn test_platform_flake() {
+        let _flake = EnvGuard::remove("NH_FLAKE");
+        let _os_flake = EnvGuard::new("NH_OS_FLAKE", "/etc/nixos");
+        let _home_flake = EnvGuard::new("NH_HOME_FLAKE", "/home/me/config");
+
+        let reference = |platform| match RebuildOptions::new(platform, RebuildAction::Build)
+            .resolve_installable()
+            .unwrap()
+        {
+            Installable::Flake { reference, .. } => reference,
+            other => panic!("not a flake: {other:?}"),
+        };
+        assert_eq!(reference(Platform::NixOS), "/etc/nixos");
+        assert_eq!(reference(Platform::HomeManager), "/home/me/config");
+    }
+}
@@ -0,0 +1,256 @@
+//! Building several installables in one invocation.
+//!
+//! Used by `nh build` and `nh os build --hosts`, mostly so that CI jobs can
+//! verify that every configuration in a flake builds.
+
+use std::fmt::Write as _;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::thread;
+
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use tracing::debug;
+
+use crate::commands;
+use crate::events::{self, Event, Phase};
+use crate::installable::{Installable, parse_attribute};
+use crate::interface::{self, NixBuildPassthroughArgs, NixEvalArgs};
+use crate::push::{self, PushTarget};
+use crate::theme::{Role, paint};
+
+/// A single installable to build, along with a name to report it under.
+#[derive(Debug)]
+pub struct Target {
+    pub name: String,
+    pub installable: Installable,
+}
+
+/// Settings shared by all builds of a batch.
+#[derive(Debug)]
+pub struct Options<'a> {
+    /// Out links are created as `<prefix>-<name>`
+    pub out_link_prefix: PathBuf,
+    /// Number of builds to run at the same time
+    pub parallel: usize,
+    pub nom: bool,
+    pub extra_args: &'a [String],
+    pub eval: &'a NixEvalArgs,
+    pub passthrough: &'a NixBuildPassthroughArgs,
+    /// Cache to push each built closure to, with the key to sign it with
+    pub push_to: Option<(&'a PushTarget, Option<&'a Path>)>,
+}
+
+#[derive(Debug)]
+struct Outcome {
+    name: String,
+    result: Result<PathBuf>,
+}
+
+/// Build all `targets`, then print a table of their out paths and statuses.
+///
+/// Every target is attempted even if some fail; an error is returned
+/// afterwards if any of them did.
+pub fn build_all(targets: Vec<Target>, options: &Options) -> Result<()> {
+    let total = targets.len();
+    let workers = options.parallel.clamp(1, total.max(1));
+    // nom can't make sense of several builds writing to the terminal at once
+    let nom = options.nom && workers == 1;
+    debug!(total, workers, nom, "Building batch");
+
+    let queue = Mutex::new(targets.into_iter().enumerate());
+    let outcomes = Mutex::new(Vec::with_capacity(total));
+
+    thread::scope(|scope| {
+        for _ in 0..workers {
+            scope.spawn(|| {
+                loop {
+                    let Some((index, target)) = queue.lock().unwrap().next() else {
+                        break;
+                    };
+                    let outcome = build_one(target, options, nom);
+                    outcomes.lock().unwrap().push((index, outcome));
+                }
+            });
+        }
+    });
+
+    let mut outcomes = outcomes.into_inner().unwrap();
+    outcomes.sort_by_key(|(index, _)| *index);
+    let outcomes: Vec<Outcome> = outcomes.into_iter().map(|(_, outcome)| outcome).collect();
+
+    print!("{}", format_table(&outcomes));
+
+    let failed = outcomes.iter().filter(|o| o.result.is_err()).count();
+    if failed > 0 {
+        bail!("{failed} of {total} builds failed");
+    }
+
+    Ok(())
+}
+
+fn build_one(target: Target, options: &Options, nom: bool) -> Outcome {
+    let out_link = out_link_for(&options.out_link_prefix, &target.name);
+
+    let result = events::phase(Phase::Build, || {
+        commands::Build::new(target.installable)
+            .extra_arg("--out-link")
+            .extra_arg(&out_link)
+            .extra_args(options.extra_args)
+            .eval_args(options.eval)
+            .passthrough(options.passthrough)
+            .message(format!("Building {}", target.name))
+            .nom(nom)
+            .run()?;
+
+        Ok(fs::canonicalize(&out_link)?)
+    })
+    .and_then(|out_path| {
+        if let Some((target, key)) = options.push_to {
+            push::push(target, key, &out_path, false)?;
+        }
+        Ok(out_path)
+    });
+
+    if let Ok(out_path) = &result {
+        events::emit(&Event::BuildOutput {
+            out_path,
+            rev: None,
+        });
+    }
+
+    Outcome {
+        name: target.name,
+        result,
+    }
+}
+
+fn out_link_for(prefix: &std::path::Path, name: &str) -> PathBuf {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '-'
+            }
+        })
+        .collect();
+
+    let mut out_link = prefix.as_os_str().to_owned();
+    out_link.push("-");
+    out_link.push(name);
+    out_link.into()
+}
+
+fn format_table(outcomes: &[Outcome]) -> String {
+    let name_width = outcomes
+        .iter()
+        .map(|o| o.name.len())
+        .chain(std::iter::once("NAME".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = String::new();
+    let _ = writeln!(table, "{:<name_width$}  {:<6}  OUT PATH", "NAME", "STATUS");
+
+    for outcome in outcomes {
+        let _ = match &outcome.result {
+            Ok(out_path) => writeln!(
+                table,
+                "{:<name_width$}  {}  {}",
+                outcome.name,
+                paint(format!("{:<6}", "ok"), Role::Success),
+                out_path.display()
+            ),
+            Err(err) => writeln!(
+                table,
+                "{:<name_width$}  {}  {}",
+                outcome.name,
+                paint(format!("{:<6}", "failed"), Role::Error),
+                err.to_string().lines().next().unwrap_or_default()
+            ),
+        };
+    }
+
+    table
+}
+
+impl interface::BuildArgs {
+    pub fn run(self) -> Result<()> {
+        let targets = self
+            .installables
+            .iter()
+            .map(|installable| {
+                let mut elems = installable.splitn(2, '#');
+                let reference = elems.next().unwrap().to_owned();
+                let attribute = elems.next().map(parse_attribute).unwrap_or_default();
+
+                Target {
+                    name: installable.clone(),
+                    installable: Installable::Flake {
+                        reference,
+                        attribute,
+                    },
+                }
+            })
+            .collect();
+
+        build_all(
+            targets,
+            &Options {
+                out_link_prefix: self.out_link.clone(),
+                parallel: self.parallel,
+                nom: !self.no_nom,
+                extra_args: &self.extra_args,
+                eval: &self.eval,
+                passthrough: &self.passthrough,
+                push_to: None,
+            },
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::eyre;
+
+    use super::*;
+
+    #[test]
+    fn test_out_link_for() {
+        assert_eq!(
+            out_link_for(&PathBuf::from("result"), "laptop"),
+            PathBuf::from("result-laptop")
+        );
+        assert_eq!(
+            out_link_for(&PathBuf::from("/tmp/out"), ".#packages.x86_64-linux.hello"),
+            PathBuf::from("/tmp/out-.-packages.x86_64-linux.hello")
+        );
+    }
+
+    #[test]
+    fn test_format_table() {
+        let outcomes = vec![
+            Outcome {
+                name: "laptop".to_string(),
+                result: Ok(PathBuf::from("/nix/store/abc-nixos-system-laptop")),
+            },
+            Outcome {
+                name: "build-server".to_string(),
+                result: Err(eyre!("Command exited with status Exited(1)")),
+            },
+        ];
+
+        let table = format_table(&outcomes);
+        let lines: Vec<&str> = table.lines().collect();
+
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("NAME          STATUS"));
+        assert!(lines[1].starts_with("laptop  "));
+        assert!(lines[1].ends_with("/nix/store/abc-nixos-system-laptop"));
+        assert!(lines[2].starts_with("build-server  "));
+        assert!(lines[2].ends_with("Command exited with status Exited(1)"));
+    }
+}
@@ -0,0 +1,437 @@
+//! Consistency checks between system generations and bootloader entries.
+//!
+//! Generations can exist in the profile without being bootable, e.g. when the
+//! bootloader's `configurationLimit` is lower than the number of generations
+//! kept, or when installing the bootloader failed. Conversely, entries of
+//! generations that were already deleted can linger on the ESP.
+//!
+//! The booted system, the running one and the boot default can drift apart
+//! as well: `nh os test` activates a system the next boot forgets, and
+//! `nh os boot` sets a default that only runs after a reboot.
+//!
+//! Installing the bootloader copies the kernel and initrd of new generations
+//! to the boot partition before removing those of generations past the
+//! `configurationLimit`, so a nearly full `/boot` fails the switch even if
+//! the old entries would have made room.
+
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use regex::Regex;
+use tracing::debug;
+
+use crate::generations;
+
+/// Directories systemd-boot entries are looked for in
+const SYSTEMD_BOOT_ENTRIES: [&str; 3] = [
+    "/boot/loader/entries",
+    "/efi/loader/entries",
+    "/boot/efi/loader/entries",
+];
+
+const GRUB_CONFIG: &str = "/boot/grub/grub.cfg";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Bootloader {
+    /// Directory containing the entries
+    SystemdBoot(PathBuf),
+    /// Path to `grub.cfg`
+    Grub(PathBuf),
+}
+
+impl Bootloader {
+    /// Root of the partition the bootloader copies kernels to, like `/boot`.
+    #[must_use]
+    pub fn partition(&self) -> &Path {
+        let (Self::SystemdBoot(path) | Self::Grub(path)) = self;
+        path.ancestors().nth(2).unwrap_or(path)
+    }
+
+    /// Where the bootloader keeps the copy of a kernel or initrd, relative to
+    /// its partition.
+    fn copy_of(&self, file: &Path) -> Option<PathBuf> {
+        let name = copy_name(file)?;
+        Some(match self {
+            Self::SystemdBoot(_) => PathBuf::from(format!("EFI/nixos/{name}.efi")),
+            Self::Grub(_) => PathBuf::from(format!("kernels/{name}")),
+        })
+    }
+
+    /// Detect the bootloader in use, if it is one we know how to inspect.
+    #[must_use]
+    pub fn detect() -> Option<Self> {
+        SYSTEMD_BOOT_ENTRIES
+            .iter()
+            .map(PathBuf::from)
+            .find(|dir| dir.is_dir())
+            .map(Self::SystemdBoot)
+            .or_else(|| {
+                let grub = PathBuf::from(GRUB_CONFIG);
+                grub.is_file().then_some(Self::Grub(grub))
+            })
+    }
+
+    #[must_use]
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::SystemdBoot(_) => "systemd-boot",
+            Self::Grub(_) => "GRUB",
+        }
+    }
+
+    /// Generation numbers that have a boot entry.
+    pub fn entries(&self) -> Result<BTreeSet<u64>> {
+        match self {
+            Self::SystemdBoot(dir) => {
+                let entries = fs::read_dir(dir)
+                    .wrap_err(format!("Failed to read boot entries in {}", dir.display()))?;
+
+                Ok(entries
+                    .filter_map(Result::ok)
+                    .filter_map(|entry| {
+                        systemd_boot_generation(&entry.file_name().to_string_lossy())
+                    })
+                    .collect())
+            }
+            Self::Grub(config) => {
+                let contents = fs::read_to_string(config)
+                    .wrap_err(format!("Failed to read {}", config.display()))?;
+                Ok(grub_generations(&contents))
+            }
+        }
+    }
+}
+
+/// Generation number of a systemd-boot entry file name like
+/// `nixos-generation-42.conf` or `nixos-generation-42-specialisation-foo.conf`.
+fn systemd_boot_generation(file_name: &str) -> Option<u64> {
+    let rest = file_name
+        .strip_prefix("nixos-generation-")?
+        .strip_suffix(".conf")?;
+    let number = rest.split('-').next()?;
+    number.parse().ok()
+}
+
+/// Generation numbers referenced by `menuentry "NixOS - Configuration 42 ..."`
+/// lines of a GRUB config.
+fn grub_generations(contents: &str) -> BTreeSet<u64> {
+    let re = Regex::new(r#"menuentry\s+["'][^"']*Configuration (\d+)"#).unwrap();
+
+    re.captures_iter(contents)
+        .filter_map(|caps| caps[1].parse().ok())
+        .collect()
+}
+
+/// Result of comparing profile generations with boot entries.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    /// Generations without a boot entry the bootloader should have kept,
+    /// those newer than its oldest entry
+    pub missing: Vec<u64>,
+    /// Generations older than the oldest boot entry, past the number of
+    /// entries the bootloader keeps (`configurationLimit`). Their entries
+    /// were dropped on purpose, even for pinned generations.
+    pub past_limit: Vec<u64>,
+    /// Boot entries without a generation
+    pub orphaned: Vec<u64>,
+}
+
+impl Report {
+    #[must_use]
+    pub fn compare(generations: &BTreeSet<u64>, entries: &BTreeSet<u64>) -> Self {
+        let oldest = generations.intersection(entries).next().copied();
+        let (missing, past_limit) = generations
+            .difference(entries)
+            .partition(|number| oldest.is_none_or(|oldest| **number > oldest));
+        Self {
+            missing,
+            past_limit,
+            orphaned: entries.difference(generations).copied().collect(),
+        }
+    }
+
+    /// Whether every generation the bootloader should keep has an entry and
+    /// every entry a generation. Generations past its limit don't count.
+    #[must_use]
+    pub fn is_consistent(&self) -> bool {
+        self.missing.is_empty() && self.orphaned.is_empty()
+    }
+}
+
+/// Compare the generations of `profile` with the entries of the detected
+/// bootloader. Returns `None` if no supported bootloader was found.
+pub fn check(profile: &Path) -> Result<Option<(Bootloader, Report)>> {
+    let Some(bootloader) = Bootloader::detect() else {
+        debug!("No supported bootloader found");
+        return Ok(None);
+    };
+
+    let generations: BTreeSet<u64> = generations::generation_links(profile)?
+        .into_iter()
+        .map(|(number, _)| number)
+        .collect();
+    let entries = bootloader.entries()?;
+    debug!(?bootloader, ?generations, ?entries);
+
+    let report = Report::compare(&generations, &entries);
+    Ok(Some((bootloader, report)))
+}
+
+/// Name of the copy of a kernel or initrd in the store, like
+/// `<hash>-linux-6.12-bzImage` for `/nix/store/<hash>-linux-6.12/bzImage`.
+fn copy_name(file: &Path) -> Option<String> {
+    let name = file.file_name()?.to_str()?;
+    let package = file.parent()?.file_name()?.to_str()?;
+    Some(format!("{package}-{name}"))
+}
+
+/// Bytes the bootloader has to copy to its partition for `system`, counting
+/// the kernel and initrd not already there.
+#[must_use]
+pub fn space_needed(bootloader: &Bootloader, system: &Path) -> u64 {
+    ["kernel", "initrd"]
+        .into_iter()
+        .filter_map(|link| system.join(link).canonicalize().ok())
+        .filter(|file| {
+            bootloader
+                .copy_of(file)
+                .is_none_or(|copy| !bootloader.partition().join(copy).exists())
+        })
+        .filter_map(|file| fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum()
+}
+
+/// Boot entries the bootloader removes when installing generation `new`:
+/// those of deleted generations, and, if it already keeps fewer entries
+/// than there are generations, those past that limit.
+#[must_use]
+pub fn removed_entries(
+    entries: &BTreeSet<u64>,
+    generations: &BTreeSet<u64>,
+    new: u64,
+) -> BTreeSet<u64> {
+    let older: BTreeSet<u64> = generations.iter().copied().filter(|n| *n < new).collect();
+    let booted = older.intersection(entries).count();
+    let kept: BTreeSet<u64> = if booted < older.len() {
+        generations.iter().rev().take(booted).copied().collect()
+    } else {
+        generations.clone()
+    };
+    entries.difference(&kept).copied().collect()
+}
+
+/// Files referenced by the `linux` and `initrd` lines of a systemd-boot
+/// entry, relative to its partition.
+fn entry_files(contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once(char::is_whitespace)?;
+            matches!(key, "linux" | "initrd")
+                .then(|| PathBuf::from(value.trim().trim_start_matches('/')))
+        })
+        .collect()
+}
+
+/// The entry files of generations `removed` and the kernels and initrds
+/// only they use, which can be deleted from a full partition ahead of the
+/// bootloader. Only systemd-boot entries can be pruned this way.
+pub fn prunable(bootloader: &Bootloader, removed: &BTreeSet<u64>) -> Result<Vec<PathBuf>> {
+    let Bootloader::SystemdBoot(dir) = bootloader else {
+        return Ok(Vec::new());
+    };
+
+    let mut pruned = Vec::new();
+    let mut pruned_files = BTreeSet::new();
+    let mut kept_files = BTreeSet::new();
+    for entry in fs::read_dir(dir)
+        .wrap_err(format!("Failed to read boot entries in {}", dir.display()))?
+        .filter_map(Result::ok)
+    {
+        let Some(number) = systemd_boot_generation(&entry.file_name().to_string_lossy()) else {
+            continue;
+        };
+        let files = entry_files(&fs::read_to_string(entry.path()).unwrap_or_default());
+        if removed.contains(&number) {
+            pruned.push(entry.path());
+            pruned_files.extend(files);
+        } else {
+            kept_files.extend(files);
+        }
+    }
+
+    let partition = bootloader.partition();
+    pruned.extend(
+        pruned_files
+            .difference(&kept_files)
+            .map(|file| partition.join(file))
+            .filter(|file| file.exists()),
+    );
+    pruned.sort();
+    Ok(pruned)
+}
+
+/// Notes on how the `running` system and the boot `default` differ from the
+/// `booted` one, given as resolved store paths.
+#[must_use]
+pub fn drift(booted: &Path, running: &Path, default: &Path) -> Vec<String> {
+    let mut notes = Vec::new();
+    if running != default {
+        notes.push(if default == booted {
+            "The running system isn't the boot default, the next boot starts the booted system again".to_string()
+        } else {
+            "The boot default isn't running yet, reboot to start it".to_string()
+        });
+    }
+    if running != booted {
+        if let Some(note) = crate::confirm::reboot_note(booted, running) {
+            notes.push(format!("{note} of the running system"));
+        }
+    }
+    notes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_of() {
+        let kernel = Path::new("/nix/store/abc-linux-6.12/bzImage");
+        assert_eq!(
+            Bootloader::SystemdBoot(PathBuf::from("/boot/loader/entries")).copy_of(kernel),
+            Some(PathBuf::from("EFI/nixos/abc-linux-6.12-bzImage.efi"))
+        );
+        assert_eq!(
+            Bootloader::Grub(PathBuf::from("/boot/grub/grub.cfg")).copy_of(kernel),
+            Some(PathBuf::from("kernels/abc-linux-6.12-bzImage"))
+        );
+        assert_eq!(
+            Bootloader::SystemdBoot(PathBuf::from("/efi/loader/entries")).partition(),
+            Path::new("/efi")
+        );
+    }
+
+    #[test]
+    fn test_removed_entries() {
+        let set = |numbers: &[u64]| numbers.iter().copied().collect::<BTreeSet<u64>>();
+
+        // Every generation has an entry, so there is no limit to go by
+        assert_eq!(
+            removed_entries(&set(&[1, 2, 3]), &set(&[2, 3, 4]), 4),
+            set(&[1])
+        );
+        // Only the newest 3 generations are kept
+        assert_eq!(
+            removed_entries(&set(&[3, 4, 5]), &set(&[1, 2, 3, 4, 5, 6]), 6),
+            set(&[3])
+        );
+        assert!(removed_entries(&set(&[1, 2]), &set(&[1, 2, 3]), 3).is_empty());
+    }
+
+    #[test]
+    fn test_entry_files() {
+        let entry = "title NixOS\nversion Generation 42\nlinux /EFI/nixos/abc-bzImage.efi\ninitrd /EFI/nixos/def-initrd.efi\noptions init=/nix/store/x/init\n";
+        assert_eq!(
+            entry_files(entry),
+            [
+                PathBuf::from("EFI/nixos/abc-bzImage.efi"),
+                PathBuf::from("EFI/nixos/def-initrd.efi")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let dir = tempfile::tempdir().unwrap();
+        let system = |name: &str, kernel: &str| {
+            let system = dir.path().join(name);
+            fs::create_dir(&system).unwrap();
+            fs::write(dir.path().join(kernel), "").unwrap();
+            std::os::unix::fs::symlink(dir.path().join(kernel), system.join("kernel")).unwrap();
+            system
+        };
+        let old = system("old", "6.6");
+        let same_kernel = system("same-kernel", "6.6");
+        let new_kernel = system("new-kernel", "6.12");
+
+        assert!(drift(&old, &old, &old).is_empty());
+        // Switched without a new kernel
+        assert!(drift(&old, &same_kernel, &same_kernel).is_empty());
+        assert_eq!(
+            drift(&old, &new_kernel, &new_kernel),
+            ["A reboot is needed to use the new kernel of the running system"]
+        );
+        // `nh os test`
+        assert_eq!(
+            drift(&old, &same_kernel, &old),
+            [
+                "The running system isn't the boot default, the next boot starts the booted system again"
+            ]
+        );
+        // `nh os boot`
+        assert_eq!(
+            drift(&old, &old, &new_kernel),
+            ["The boot default isn't running yet, reboot to start it"]
+        );
+    }
+
+    #[test]
+    fn test_systemd_boot_generation() {
+        assert_eq!(
+            systemd_boot_generation("nixos-generation-42.conf"),
+            Some(42)
+        );
+        assert_eq!(
+            systemd_boot_generation("nixos-generation-42-specialisation-gaming.conf"),
+            Some(42)
+        );
+        assert_eq!(systemd_boot_generation("memtest86.conf"), None);
+        assert_eq!(
+            systemd_boot_generation("nixos-generation-42.conf.tmp"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_grub_generations() {
+        let config = r#"
+menuentry "NixOS - Default" {
+}
+submenu "NixOS - All configurations" {
+menuentry "NixOS - Configuration 43 (2024-01-02 - 24.05)" --class nixos {
+}
+menuentry "NixOS - Configuration 41 (2024-01-01 - 24.05)" --class nixos {
+}
+}
+"#;
+        assert_eq!(grub_generations(config), BTreeSet::from([41, 43]));
+    }
+
+    #[test]
+    fn test_report() {
+        let generations = BTreeSet::from([1, 2, 3]);
+        let entries = BTreeSet::from([2, 3, 4]);
+
+        let report = Report::compare(&generations, &entries);
+        assert!(report.missing.is_empty());
+        assert_eq!(report.past_limit, vec![1]);
+        assert_eq!(report.orphaned, vec![4]);
+        assert!(!report.is_consistent());
+        assert!(Report::compare(&generations, &generations).is_consistent());
+
+        // Generations past the limit are expected to lack an entry
+        let report = Report::compare(&BTreeSet::from([1, 2, 3]), &BTreeSet::from([2, 3]));
+        assert!(report.is_consistent());
+        assert_eq!(report.past_limit, vec![1]);
+
+        // Unless they are newer than the oldest entry
+        let report = Report::compare(&BTreeSet::from([1, 2, 3, 4]), &BTreeSet::from([2, 4]));
+        assert_eq!(report.missing, vec![3]);
+        assert_eq!(report.past_limit, vec![1]);
+    }
+}
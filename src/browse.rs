@@ -0,0 +1,239 @@
+//! The full-screen generation browser of `nh os info --tui`.
+//!
+//! Generations are listed at the top, scrolling along with the selection,
+//! and the pane below shows the diff of the selected generation against the
+//! baseline. Keys ask for an action on the selected generation, which the
+//! caller runs, on the normal screen if it prompts for anything.
+
+use std::io;
+
+use color_eyre::Result;
+use console::{Key, Term};
+
+use crate::theme::{Role, paint};
+
+const ALTERNATE_SCREEN: &str = "\x1b[?1049h";
+const NORMAL_SCREEN: &str = "\x1b[?1049l";
+
+const HELP: &str = "↑/↓ select  PgUp/PgDn scroll diff  r roll back  p pin  t label  d delete  \
+                    b baseline  q quit";
+
+/// What a key asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Request {
+    Rollback,
+    TogglePin,
+    Tag,
+    Delete,
+    Baseline,
+    Quit,
+}
+
+/// What the browser shows.
+#[derive(Debug)]
+pub struct View {
+    pub title: String,
+    /// A line per generation
+    pub items: Vec<String>,
+    pub selected: usize,
+    /// What the diff pane compares with, like `generation 41`
+    pub baseline: String,
+    /// The outcome of the last action
+    pub status: Option<String>,
+    /// The first item on screen
+    offset: usize,
+    /// The first diff line on screen
+    diff_offset: usize,
+}
+
+impl View {
+    #[must_use]
+    pub fn new(title: String, items: Vec<String>, selected: usize, baseline: String) -> Self {
+        Self {
+            title,
+            selected: selected.min(items.len().saturating_sub(1)),
+            items,
+            baseline,
+            status: None,
+            offset: 0,
+            diff_offset: 0,
+        }
+    }
+
+    /// Select the item at `index`, or the last one past the end. Returns
+    /// whether the selection changed.
+    fn select(&mut self, index: usize) -> bool {
+        let index = index.min(self.items.len().saturating_sub(1));
+        let changed = index != self.selected;
+        self.selected = index;
+        if changed {
+            self.diff_offset = 0;
+        }
+        changed
+    }
+}
+
+/// Rows of the list and of the diff pane for `items` on a terminal of
+/// `rows`, besides the title, the pane's header, the status and the help.
+fn layout(rows: usize, items: usize) -> (usize, usize) {
+    let available = rows.saturating_sub(4);
+    let list = items.min((available / 3).max(3)).min(available);
+    (list, available - list)
+}
+
+/// The terminal's alternate screen, which the browser is drawn on while this
+/// exists.
+pub struct Screen {
+    term: Term,
+}
+
+impl Screen {
+    pub fn enter() -> Result<Self> {
+        let term = Term::stdout();
+        term.write_str(ALTERNATE_SCREEN)?;
+        term.hide_cursor()?;
+        Ok(Self { term })
+    }
+
+    fn leave(&self) -> io::Result<()> {
+        self.term.show_cursor()?;
+        self.term.write_str(NORMAL_SCREEN)
+    }
+
+    /// Run `action` on the normal screen, for prompts and command output.
+    pub fn suspended<T>(&self, action: impl FnOnce() -> T) -> Result<T> {
+        self.leave()?;
+        let result = action();
+        self.term.write_str(ALTERNATE_SCREEN)?;
+        self.term.hide_cursor()?;
+        Ok(result)
+    }
+
+    /// Show `view` until a key asks for something, with the lines of the
+    /// diff of the item at an index from `diff`.
+    pub fn run(
+        &self,
+        view: &mut View,
+        diff: &mut dyn FnMut(usize) -> Vec<String>,
+    ) -> Result<Request> {
+        let mut lines: Option<Vec<String>> = None;
+        loop {
+            let diff_lines = match lines.take() {
+                Some(lines) => lines,
+                None => {
+                    self.draw(view, &[paint("Comparing the closures…", Role::Muted)])?;
+                    diff(view.selected)
+                }
+            };
+            self.draw(view, &diff_lines)?;
+
+            let (rows, _) = self.term.size();
+            let (_, page) = layout(rows.into(), view.items.len());
+            let key = self.term.read_key()?;
+            view.status = None;
+            let changed = match key {
+                Key::ArrowUp | Key::Char('k') => view.select(view.selected.saturating_sub(1)),
+                Key::ArrowDown | Key::Char('j') => view.select(view.selected + 1),
+                Key::Home => view.select(0),
+                Key::End => view.select(usize::MAX),
+                Key::PageDown | Key::Char(' ') => {
+                    view.diff_offset += page.max(1);
+                    false
+                }
+                Key::PageUp => {
+                    view.diff_offset = view.diff_offset.saturating_sub(page.max(1));
+                    false
+                }
+                Key::Char('r') => return Ok(Request::Rollback),
+                Key::Char('p') => return Ok(Request::TogglePin),
+                Key::Char('t') => return Ok(Request::Tag),
+                Key::Char('d') => return Ok(Request::Delete),
+                Key::Char('b') => return Ok(Request::Baseline),
+                Key::Escape | Key::Char('q') => return Ok(Request::Quit),
+                _ => false,
+            };
+            if !changed {
+                lines = Some(diff_lines);
+            }
+        }
+    }
+
+    fn draw(&self, view: &mut View, diff: &[String]) -> Result<()> {
+        let (rows, columns) = self.term.size();
+        let (rows, columns) = (usize::from(rows), usize::from(columns));
+        let (list_rows, pane_rows) = layout(rows, view.items.len());
+
+        // Keep the selection and the end of the diff in sight
+        if view.selected < view.offset {
+            view.offset = view.selected;
+        } else if view.selected >= view.offset + list_rows {
+            view.offset = view.selected + 1 - list_rows;
+        }
+        view.diff_offset = view.diff_offset.min(diff.len().saturating_sub(pane_rows));
+
+        let mut screen = vec![paint(&view.title, Role::Heading)];
+        for (index, item) in view
+            .items
+            .iter()
+            .enumerate()
+            .skip(view.offset)
+            .take(list_rows)
+        {
+            screen.push(if index == view.selected {
+                paint(format!("> {item}"), Role::Emphasis)
+            } else {
+                format!("  {item}")
+            });
+        }
+        screen.resize(1 + list_rows, String::new());
+        screen.push(paint(
+            format!("Diff against {}", view.baseline),
+            Role::Heading,
+        ));
+        screen.extend(diff.iter().skip(view.diff_offset).take(pane_rows).cloned());
+        screen.resize(2 + list_rows + pane_rows, String::new());
+        screen.push(view.status.clone().unwrap_or_default());
+        screen.push(paint(HELP, Role::Muted));
+
+        // Over the previous frame instead of clearing, which flickers
+        let frame: Vec<String> = screen
+            .iter()
+            .take(rows)
+            .map(|line| format!("{}\x1b[K", console::truncate_str(line, columns, "…")))
+            .collect();
+        self.term
+            .write_str(&format!("\x1b[H{}\x1b[J", frame.join("\r\n")))?;
+        Ok(())
+    }
+}
+
+impl Drop for Screen {
+    fn drop(&mut self) {
+        let _ = self.leave();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        assert_eq!(layout(40, 100), (12, 24));
+        assert_eq!(layout(40, 5), (5, 31));
+        // Too small for anything but the list
+        assert_eq!(layout(6, 100), (2, 0));
+    }
+
+    #[test]
+    fn test_select() {
+        let items = vec!["1".to_string(), "2".to_string(), "3".to_string()];
+        let mut view = View::new(String::new(), items, 7, String::new());
+        assert_eq!(view.selected, 2);
+        view.diff_offset = 10;
+        assert!(!view.select(usize::MAX));
+        assert_eq!(view.diff_offset, 10);
+        assert!(view.select(0));
+        assert_eq!(view.diff_offset, 0);
+    }
+}
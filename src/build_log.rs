@@ -0,0 +1,155 @@
+//! Logs of failed builds, and `nh log` to show them again.
+//!
+//! When a build fails, the derivation whose builder failed is picked out of
+//! nix's log, and its build log is saved to `logs/` in the state directory
+//! with `nix log`, so that it doesn't have to be copied out of scrollback.
+//! `nh log` prints the log of the last failure, or of any derivation.
+
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::PathBuf;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+use tracing::{debug, info};
+
+use crate::commands::Command;
+use crate::interface::LogArgs;
+use crate::state;
+
+/// Logs kept in the log directory
+const MAX_LOGS: usize = 20;
+
+/// The link to the log of the last failed build
+const LAST: &str = "last";
+
+fn log_dir() -> Result<PathBuf> {
+    Ok(state::state_dir()?.join("logs"))
+}
+
+/// The derivation a build error message of nix is about, like
+/// `builder for '/nix/store/…-hello.drv' failed with exit code 1` or
+/// `Cannot build '/nix/store/…-hello.drv'`.
+#[must_use]
+pub fn failed_drv(message: &str) -> Option<String> {
+    if !message.contains("builder for") && !message.contains("Cannot build") {
+        return None;
+    }
+    let start = message.find("/nix/store/")?;
+    let rest = &message[start..];
+    let drv = &rest[..rest.find(".drv")? + ".drv".len()];
+    (!drv.contains(char::is_whitespace)).then(|| drv.to_string())
+}
+
+/// Remove all but the newest `MAX_LOGS` logs.
+fn prune(dir: &std::path::Path) {
+    let mut logs: Vec<(std::time::SystemTime, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let path = entry.path();
+            if path.extension()? != "log" {
+                return None;
+            }
+            Some((entry.metadata().ok()?.modified().ok()?, path))
+        })
+        .collect();
+    logs.sort();
+    for (_, path) in &logs[..logs.len().saturating_sub(MAX_LOGS)] {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// Save the build log of the first of the `failed` derivations, which is the
+/// one that made the build fail, and point `nh log` at it.
+///
+/// Nothing is saved if nix has no log for it. The build failing is the error
+/// that matters, so problems saving the log are only logged.
+pub fn save_failure(failed: &[String]) {
+    let Some(drv) = failed.first() else {
+        return;
+    };
+    if let Err(err) = try_save(drv) {
+        debug!(?err, "Failed to save the build log of {drv}");
+    }
+}
+
+fn try_save(drv: &str) -> Result<()> {
+    let Some(capture) = Command::new("nix").args(["log", drv]).run_capture_all()? else {
+        return Ok(());
+    };
+    if !capture.exit_status.success() {
+        bail!("nix log failed: {}", capture.stderr_str().trim());
+    }
+
+    let dir = log_dir()?;
+    fs::create_dir_all(&dir)?;
+    let name = drv
+        .rsplit('/')
+        .next()
+        .unwrap_or(drv)
+        .trim_end_matches(".drv");
+    let path = dir.join(format!("{name}.log"));
+    fs::write(&path, capture.stdout)?;
+
+    let last = dir.join(LAST);
+    let _ = fs::remove_file(&last);
+    symlink(&path, &last)?;
+    prune(&dir);
+
+    info!(
+        "The build log of {drv} is saved to {}, show it again with `nh log`",
+        path.display()
+    );
+    Ok(())
+}
+
+impl LogArgs {
+    pub fn run(&self) -> Result<()> {
+        if self.drv != LAST {
+            return Command::new("nix")
+                .with_required_env()
+                .args(["log", &self.drv])
+                .show_output(true)
+                .run();
+        }
+
+        let last = log_dir()?.join(LAST);
+        if !last.exists() {
+            bail!("No failed build has been recorded yet");
+        }
+        debug!("Showing {}", fs::canonicalize(&last)?.display());
+        print!(
+            "{}",
+            String::from_utf8_lossy(
+                &fs::read(&last).wrap_err("Failed to read the last build log")?
+            )
+        );
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_failed_drv() {
+        assert_eq!(
+            failed_drv(
+                "error: builder for '\u{1b}[35;1m/nix/store/abc-hello-2.12.drv\u{1b}[0m' failed with exit code 2;"
+            )
+            .as_deref(),
+            Some("/nix/store/abc-hello-2.12.drv")
+        );
+        assert_eq!(
+            failed_drv("error: Cannot build '/nix/store/abc-foo.drv'.").as_deref(),
+            Some("/nix/store/abc-foo.drv")
+        );
+        assert_eq!(
+            failed_drv("building '/nix/store/abc-hello-2.12.drv'..."),
+            None
+        );
+    }
+}
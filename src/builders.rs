@@ -0,0 +1,525 @@
+//! Distributing builds over several remote builders.
+//!
+//! Builders come from `--remote-builder` or the `[[builders]]` section of the
+//! configuration, and are passed to Nix as a `--builders` machine spec. While
+//! building, Nix's log is read to find out which builder handled which
+//! derivation, which is summarized once the build is done.
+//!
+//! Builds for another platform with `--system` need either binfmt emulation
+//! or a builder for that platform, which is checked before building.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use subprocess::{Exec, ExitStatus, Redirection};
+use tracing::{debug, info};
+
+use crate::build_log;
+use crate::checks::{self, SkippableCheck};
+use crate::commands::Command;
+use crate::eval_trace;
+use crate::events;
+use crate::exit::Failure;
+use crate::hints;
+use crate::theme::{Role, paint};
+
+/// Nix activity type of all derivations built by a command, see
+/// `ActivityType` in Nix's `logging.hh`.
+const ACT_BUILDS: u64 = 104;
+/// Nix activity type of a derivation being built
+const ACT_BUILD: u64 = 105;
+/// Nix result type of an activity's progress, see `ResultType`
+const RES_PROGRESS: u64 = 105;
+
+/// Nix's `lvlInfo`, the most verbose level shown without `--verbose`.
+const LVL_INFO: u64 = 3;
+
+/// A machine Nix may hand derivations to.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RemoteBuilder {
+    /// Store URI like `ssh://host`, a bare host name means `ssh://`
+    pub uri: String,
+    /// Systems the builder can build for, defaults to the local system
+    #[serde(default)]
+    pub systems: Vec<String>,
+    /// SSH identity file used to connect to the builder
+    pub ssh_key: Option<String>,
+    /// Number of builds to run on the builder at the same time
+    pub max_jobs: Option<u32>,
+    /// Relative speed, Nix prefers faster builders
+    pub speed_factor: Option<u32>,
+    /// System features the builder supports, like `kvm` or `big-parallel`
+    #[serde(default)]
+    pub features: Vec<String>,
+}
+
+impl RemoteBuilder {
+    /// A builder with Nix's defaults for everything but the speed factor,
+    /// used for `--build-host`.
+    #[must_use]
+    pub fn host(host: &str) -> Self {
+        Self {
+            uri: host.to_string(),
+            speed_factor: Some(100),
+            ..Self::default()
+        }
+    }
+
+    /// Parse `URI[,systems=SYS:SYS][,max-jobs=N][,speed-factor=N][,ssh-key=FILE][,features=F:F]`,
+    /// for use as a clap value parser.
+    pub fn parse(spec: &str) -> Result<Self, String> {
+        let mut fields = spec.split(',');
+        let uri = fields.next().unwrap_or_default().trim();
+        if uri.is_empty() {
+            return Err("expected a builder like ssh://host,max-jobs=4".to_string());
+        }
+
+        let mut builder = Self {
+            uri: uri.to_string(),
+            ..Self::default()
+        };
+        let list = |value: &str| value.split(':').map(str::to_string).collect();
+        let number = |key: &str, value: &str| {
+            value
+                .parse()
+                .map_err(|_| format!("{key} expects a number, got '{value}'"))
+        };
+
+        for field in fields {
+            let (key, value) = field
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got '{field}'"))?;
+            match key {
+                "systems" => builder.systems = list(value),
+                "features" => builder.features = list(value),
+                "ssh-key" => builder.ssh_key = Some(value.to_string()),
+                "max-jobs" => builder.max_jobs = Some(number(key, value)?),
+                "speed-factor" => builder.speed_factor = Some(number(key, value)?),
+                _ => {
+                    return Err(format!(
+                        "unknown builder setting '{key}', expected one of systems, max-jobs, speed-factor, ssh-key or features"
+                    ));
+                }
+            }
+        }
+
+        Ok(builder)
+    }
+
+    /// The store URI Nix connects to.
+    #[must_use]
+    pub fn store_uri(&self) -> String {
+        if self.uri.contains("://") {
+            self.uri.clone()
+        } else {
+            format!("ssh://{}", self.uri)
+        }
+    }
+
+    /// The line of the builder in Nix's machine spec format, with `-` for
+    /// the fields left at their default.
+    #[must_use]
+    pub fn machine_line(&self) -> String {
+        let or_default = |field: String| {
+            if field.is_empty() {
+                "-".to_string()
+            } else {
+                field
+            }
+        };
+
+        [
+            self.store_uri(),
+            or_default(self.systems.join(",")),
+            or_default(self.ssh_key.clone().unwrap_or_default()),
+            or_default(self.max_jobs.map(|n| n.to_string()).unwrap_or_default()),
+            or_default(self.speed_factor.map(|n| n.to_string()).unwrap_or_default()),
+            or_default(self.features.join(",")),
+        ]
+        .join(" ")
+    }
+}
+
+/// The value of `--builders` for `builders`.
+#[must_use]
+pub fn machines_spec(builders: &[RemoteBuilder]) -> String {
+    builders
+        .iter()
+        .map(RemoteBuilder::machine_line)
+        .collect::<Vec<_>>()
+        .join(" ; ")
+}
+
+/// The value of the Nix setting `name`, like `nix config show` prints it.
+fn nix_setting(name: &str) -> Option<String> {
+    Command::new("nix")
+        .args(["config", "show", name])
+        .run_capture()
+        .ok()
+        .flatten()
+        .map(|value| value.trim().to_string())
+}
+
+/// Whether the machine spec `spec`, in the format of `--builders` or an
+/// `@file` reference to one, has a builder for `system`.
+fn spec_builds(spec: &str, system: &str) -> bool {
+    spec.split([';', '\n']).any(|machine| {
+        let machine = machine.trim();
+        if let Some(file) = machine.strip_prefix('@') {
+            return std::fs::read_to_string(file).is_ok_and(|spec| spec_builds(&spec, system));
+        }
+        machine
+            .split_whitespace()
+            .nth(1)
+            .is_some_and(|systems| systems.split(',').any(|s| s == system))
+    })
+}
+
+/// Whether a binfmt handler for `system` is registered, under the name
+/// `boot.binfmt.emulatedSystems` gives it or the one of qemu-user-static.
+fn binfmt_registered(system: &str) -> bool {
+    let arch = system.split('-').next().unwrap_or_default();
+    let qemu_arch = match arch {
+        "armv6l" | "armv7l" => "arm",
+        "i686" => "i386",
+        other => other,
+    };
+    [system.to_string(), format!("qemu-{qemu_arch}")]
+        .iter()
+        .any(|name| {
+            std::fs::read_to_string(Path::new("/proc/sys/fs/binfmt_misc").join(name))
+                .is_ok_and(|handler| handler.starts_with("enabled"))
+        })
+}
+
+/// Make sure derivations for `system` can be built, by this machine,
+/// through emulation, or by one of `builders` or the builders in the
+/// `--builders` spec or Nix's configuration.
+pub fn check_system(
+    system: &str,
+    builders: &[RemoteBuilder],
+    builders_arg: Option<&str>,
+) -> Result<()> {
+    if checks::is_skipped(SkippableCheck::System) {
+        return Ok(());
+    }
+
+    let native = nix_setting("system").unwrap_or_default();
+    let extra_platforms = nix_setting("extra-platforms").unwrap_or_default();
+    if system == native || extra_platforms.split_whitespace().any(|s| s == system) {
+        return Ok(());
+    }
+    if binfmt_registered(system) {
+        info!("Building for {system} with binfmt emulation");
+        return Ok(());
+    }
+
+    let remote = builders
+        .iter()
+        .any(|b| b.systems.iter().any(|s| s == system))
+        || builders_arg
+            .or(nix_setting("builders").as_deref())
+            .is_some_and(|spec| spec_builds(spec, system));
+    if remote {
+        info!("Building for {system} on remote builders");
+        return Ok(());
+    }
+
+    Err(eyre!(
+        "Can't build for {system} on this {native} machine: there is no binfmt emulation for it and no remote builder that builds it. \
+         Enable emulation with boot.binfmt.emulatedSystems = [ \"{system}\" ] on NixOS, or add a builder with --remote-builder ssh://HOST,systems={system}"
+    )
+    .wrap_err(Failure::Environment))
+}
+
+/// What a line of `--log-format internal-json` output says.
+#[derive(Debug, PartialEq, Eq)]
+enum LogLine {
+    /// A derivation started building, on `machine` or locally if it's empty
+    Build {
+        drv: String,
+        machine: String,
+    },
+    /// The activity `id` of all builds started
+    Builds {
+        id: u64,
+    },
+    /// The activity `id` progressed to `done` out of `expected`
+    Progress {
+        id: u64,
+        done: u64,
+        expected: u64,
+    },
+    /// A message, or the description of an activity, at a log level
+    Text {
+        level: u64,
+        text: String,
+    },
+    Other,
+}
+
+fn parse_log_line(line: &str) -> LogLine {
+    let Some(json) = line.strip_prefix("@nix ") else {
+        // Nix writes some errors before its logger is set up
+        return LogLine::Text {
+            level: 0,
+            text: line.to_string(),
+        };
+    };
+    let Ok(value) = serde_json::from_str::<serde_json::Value>(json) else {
+        return LogLine::Other;
+    };
+
+    let level = value["level"].as_u64().unwrap_or_default();
+    let text = |key: &str| value[key].as_str().unwrap_or_default().to_string();
+    let id = value["id"].as_u64().unwrap_or_default();
+    let field = |index: usize| value["fields"][index].as_u64().unwrap_or_default();
+
+    match value["action"].as_str() {
+        Some("start") if value["type"].as_u64() == Some(ACT_BUILDS) => LogLine::Builds { id },
+        Some("result") if value["type"].as_u64() == Some(RES_PROGRESS) => LogLine::Progress {
+            id,
+            done: field(0),
+            expected: field(1),
+        },
+        Some("start") if value["type"].as_u64() == Some(ACT_BUILD) => LogLine::Build {
+            drv: value["fields"][0].as_str().unwrap_or_default().to_string(),
+            machine: value["fields"][1].as_str().unwrap_or_default().to_string(),
+        },
+        Some("start") if !text("text").is_empty() => LogLine::Text {
+            level,
+            text: text("text"),
+        },
+        Some("msg") => LogLine::Text {
+            level,
+            text: text("msg"),
+        },
+        _ => LogLine::Other,
+    }
+}
+
+/// What `run_tracked` found in the log of a build.
+#[derive(Debug, Default)]
+pub struct Tracked {
+    /// Derivations built remotely, by machine
+    pub remote: BTreeMap<String, Vec<String>>,
+    /// Derivations whose builder failed, in the order they failed
+    pub failed: Vec<String>,
+}
+
+/// Run a `nix build` command while recording which machine built each
+/// derivation, and which failed. The log goes to nom if `nom` is set, or is
+/// otherwise printed like `--log-format raw` would.
+pub fn run_tracked(cmd: Exec, nom: bool) -> Result<(ExitStatus, Tracked)> {
+    let cmd = cmd
+        .args(&["--log-format", "internal-json", "--verbose"])
+        .stderr(Redirection::Pipe)
+        .stdout(Redirection::None);
+    debug!(?cmd);
+    let mut nix = cmd.popen()?;
+
+    let mut nom = if nom {
+        Some(
+            Exec::cmd("nom")
+                .arg("--json")
+                .stdin(Redirection::Pipe)
+                .popen()?,
+        )
+    } else {
+        None
+    };
+    let mut nom_stdin = nom.as_mut().and_then(|nom| nom.stdin.take());
+
+    let mut tracked = Tracked::default();
+    let mut builds = None;
+    if let Some(stderr) = nix.stderr.take() {
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else { break };
+
+            if let Some(stdin) = &mut nom_stdin {
+                let line = eval_trace::fold_log_line(&line).unwrap_or_else(|| line.clone());
+                if writeln!(stdin, "{line}").is_err() {
+                    // Keep reading so that nix doesn't block on a full pipe
+                    nom_stdin = None;
+                }
+            }
+
+            match parse_log_line(&line) {
+                LogLine::Build { drv, machine } if !machine.is_empty() => {
+                    tracked.remote.entry(machine).or_default().push(drv);
+                }
+                LogLine::Text { level, text } => {
+                    hints::observe(&text);
+                    if let Some(drv) = build_log::failed_drv(&text) {
+                        tracked.failed.push(drv);
+                    }
+                    if nom.is_none() && level <= LVL_INFO {
+                        eprintln!("{}", eval_trace::fold(&text));
+                    }
+                }
+                LogLine::Builds { id } => builds = Some(id),
+                LogLine::Progress { id, done, expected } if builds == Some(id) => {
+                    events::progress(done, Some(expected), None);
+                }
+                LogLine::Build { .. } | LogLine::Progress { .. } | LogLine::Other => {}
+            }
+        }
+    }
+    drop(nom_stdin);
+
+    let status = nix.wait()?;
+    if let Some(mut nom) = nom {
+        nom.wait()?;
+    }
+
+    Ok((status, tracked))
+}
+
+/// A summary of which derivations each builder built.
+#[must_use]
+pub fn format_report(remote: &BTreeMap<String, Vec<String>>) -> String {
+    let mut report = String::new();
+    if remote.is_empty() {
+        return report;
+    }
+
+    let _ = writeln!(report, "{}", paint("Remote builds", Role::Heading));
+    for (machine, drvs) in remote {
+        let _ = writeln!(
+            report,
+            "  {} ({} derivation{})",
+            paint(machine, Role::Name),
+            drvs.len(),
+            if drvs.len() == 1 { "" } else { "s" }
+        );
+        for drv in drvs {
+            let name = drv
+                .rsplit('/')
+                .next()
+                .and_then(|name| name.split_once('-'))
+                .map_or(drv.as_str(), |(_, name)| name);
+            let _ = writeln!(report, "    {}", name.trim_end_matches(".drv"));
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_remote_builder() {
+        let builder = RemoteBuilder::parse(
+            "fast,systems=x86_64-linux:aarch64-linux,max-jobs=8,speed-factor=4",
+        )
+        .unwrap();
+        assert_eq!(builder.uri, "fast");
+        assert_eq!(builder.systems, ["x86_64-linux", "aarch64-linux"]);
+        assert_eq!(builder.max_jobs, Some(8));
+        assert_eq!(builder.speed_factor, Some(4));
+        assert_eq!(
+            builder.machine_line(),
+            "ssh://fast x86_64-linux,aarch64-linux - 8 4 -"
+        );
+
+        assert!(RemoteBuilder::parse("").is_err());
+        assert!(RemoteBuilder::parse("host,max-jobs=many").is_err());
+        assert!(RemoteBuilder::parse("host,cores=4").is_err());
+        assert!(RemoteBuilder::parse("host,max-jobs").is_err());
+    }
+
+    #[test]
+    fn test_machines_spec() {
+        let builders = [
+            RemoteBuilder::host("build-host"),
+            RemoteBuilder::parse("ssh-ng://mac,systems=aarch64-darwin,ssh-key=/root/.ssh/mac")
+                .unwrap(),
+        ];
+        assert_eq!(
+            machines_spec(&builders),
+            "ssh://build-host - - - 100 - ; ssh-ng://mac aarch64-darwin /root/.ssh/mac - - -"
+        );
+    }
+
+    #[test]
+    fn test_spec_builds() {
+        let spec = "ssh://pi aarch64-linux,armv7l-linux - 4 ; ssh://mac x86_64-darwin\nssh://local";
+        assert!(spec_builds(spec, "armv7l-linux"));
+        assert!(spec_builds(spec, "x86_64-darwin"));
+        assert!(!spec_builds(spec, "riscv64-linux"));
+        assert!(!spec_builds("@/nonexistent/machines", "aarch64-linux"));
+    }
+
+    #[test]
+    fn test_parse_log_line() {
+        assert_eq!(
+            parse_log_line(
+                r#"@nix {"action":"start","id":1,"level":3,"type":105,"text":"building","fields":["/nix/store/abc-hello-2.12.drv","ssh://fast",1,1],"parent":0}"#
+            ),
+            LogLine::Build {
+                drv: "/nix/store/abc-hello-2.12.drv".to_string(),
+                machine: "ssh://fast".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_log_line(r#"@nix {"action":"msg","level":0,"msg":"error: oops"}"#),
+            LogLine::Text {
+                level: 0,
+                text: "error: oops".to_string(),
+            }
+        );
+        assert_eq!(
+            parse_log_line(
+                r#"@nix {"action":"start","id":2,"level":0,"type":104,"text":"","fields":[],"parent":0}"#
+            ),
+            LogLine::Builds { id: 2 }
+        );
+        assert_eq!(
+            parse_log_line(r#"@nix {"action":"result","id":2,"type":105,"fields":[1,3,1,0]}"#),
+            LogLine::Progress {
+                id: 2,
+                done: 1,
+                expected: 3,
+            }
+        );
+        assert_eq!(
+            parse_log_line(r#"@nix {"action":"stop","id":1}"#),
+            LogLine::Other
+        );
+        assert_eq!(
+            parse_log_line("error: unrecognised flag"),
+            LogLine::Text {
+                level: 0,
+                text: "error: unrecognised flag".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_format_report() {
+        let remote = BTreeMap::from([(
+            "ssh://fast".to_string(),
+            vec![
+                "/nix/store/abc-hello-2.12.drv".to_string(),
+                "/nix/store/def-firefox-130.0.drv".to_string(),
+            ],
+        )]);
+        let report = format_report(&remote);
+        let lines: Vec<&str> = report.lines().collect();
+
+        assert_eq!(lines.len(), 4);
+        assert!(lines[1].contains("ssh://fast"));
+        assert!(lines[1].ends_with("(2 derivations)"));
+        assert_eq!(lines[2], "    hello-2.12");
+        assert_eq!(lines[3], "    firefox-130.0");
+        assert!(format_report(&BTreeMap::new()).is_empty());
+    }
+}
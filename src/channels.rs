@@ -0,0 +1,159 @@
+//! Updating nix channels, which configurations that aren't flakes are built
+//! from, with `nh update --channels` or `-u` of a rebuild.
+//!
+//! NixOS and nix-darwin systems use the channels of root, Home Manager the
+//! ones of the user. Channels built from nixpkgs carry the git revision they
+//! were made from, which is what the update is summarized with.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use tracing::{debug, info};
+
+use crate::commands::Command;
+
+const ROOT_CHANNELS: &str = "/nix/var/nix/profiles/per-user/root/channels";
+
+/// The channels profile of root with `system`, otherwise of the current
+/// user.
+#[must_use]
+pub fn channels_dir(system: bool) -> PathBuf {
+    if system {
+        return PathBuf::from(ROOT_CHANNELS);
+    }
+    let xdg = env::var("HOME")
+        .map(|home| Path::new(&home).join(".local/state/nix/profiles/channels"))
+        .ok()
+        .filter(|dir| dir.exists());
+    xdg.unwrap_or_else(|| {
+        Path::new("/nix/var/nix/profiles/per-user")
+            .join(env::var("USER").unwrap_or_default())
+            .join("channels")
+    })
+}
+
+/// The revision of every channel in `dir`: the git revision for channels
+/// of nixpkgs, or else the name of the store path.
+#[must_use]
+pub fn revisions(dir: &Path) -> BTreeMap<String, String> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        debug!("No channels in {}", dir.display());
+        return BTreeMap::new();
+    };
+
+    entries
+        .filter_map(Result::ok)
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| {
+            let path = entry.path();
+            let revision = fs::read_to_string(path.join(".git-revision"))
+                .map(|revision| revision.trim().chars().take(12).collect())
+                .or_else(|_| {
+                    fs::canonicalize(&path).map(|store_path| {
+                        store_path
+                            .file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                })
+                .unwrap_or_default();
+            (entry.file_name().to_string_lossy().into_owned(), revision)
+        })
+        .collect()
+}
+
+/// Lines describing how the revisions of channels changed.
+#[must_use]
+pub fn format_delta(
+    before: &BTreeMap<String, String>,
+    after: &BTreeMap<String, String>,
+) -> Vec<String> {
+    after
+        .iter()
+        .map(|(name, new)| match before.get(name) {
+            Some(old) if old == new => format!("{name}: {new} (unchanged)"),
+            Some(old) => format!("{name}: {old} -> {new}"),
+            None => format!("{name}: {new} (new)"),
+        })
+        .chain(
+            before
+                .keys()
+                .filter(|name| !after.contains_key(*name))
+                .map(|name| format!("{name}: removed")),
+        )
+        .collect()
+}
+
+/// Update `names`, or all channels if empty, of root with `system` or else
+/// of the current user, and print how their revisions changed.
+pub fn update(names: &[String], system: bool) -> Result<()> {
+    let dir = channels_dir(system);
+    let before = revisions(&dir);
+
+    let elevate = system && !nix::unistd::Uid::effective().is_root();
+    Command::new("nix-channel")
+        .arg("--update")
+        .args(names)
+        .elevate(elevate)
+        .message(if names.is_empty() {
+            "Updating all channels".to_string()
+        } else {
+            format!("Updating channels {}", names.join(", "))
+        })
+        .with_required_env()
+        .run()
+        .wrap_err("Failed to update the channels")?;
+
+    for line in format_delta(&before, &revisions(&dir)) {
+        info!("{line}");
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let nixos = dir.path().join("nixos");
+        fs::create_dir(&nixos).unwrap();
+        fs::write(nixos.join(".git-revision"), "0123456789abcdef\n").unwrap();
+        fs::write(dir.path().join("manifest.nix"), "[ ]").unwrap();
+
+        assert_eq!(
+            revisions(dir.path()),
+            BTreeMap::from([("nixos".to_string(), "0123456789ab".to_string())])
+        );
+    }
+
+    #[test]
+    fn test_format_delta() {
+        let before = BTreeMap::from([
+            ("nixos".to_string(), "aaa".to_string()),
+            ("old".to_string(), "ccc".to_string()),
+            ("home-manager".to_string(), "hm-1".to_string()),
+        ]);
+        let after = BTreeMap::from([
+            ("nixos".to_string(), "bbb".to_string()),
+            ("home-manager".to_string(), "hm-1".to_string()),
+            ("unstable".to_string(), "ddd".to_string()),
+        ]);
+
+        assert_eq!(
+            format_delta(&before, &after),
+            [
+                "home-manager: hm-1 (unchanged)",
+                "nixos: aaa -> bbb",
+                "unstable: ddd (new)",
+                "old: removed",
+            ]
+        );
+    }
+}
@@ -1,17 +1,141 @@
-use std::{cmp::Ordering, env};
-
+use std::{
+    cmp::Ordering,
+    env,
+    path::Path,
+    sync::{Once, OnceLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
 use color_eyre::Result;
+use color_eyre::eyre::{Context, eyre};
 use semver::Version;
+use serde::Deserialize;
 use tracing::{debug, warn};
 
+use crate::commands::Command;
+use crate::config;
+use crate::exit::Failure;
 use crate::util::{self, NixVariant, normalize_version_string};
 
+/// Checks that can be skipped individually, with `--skip-check` or by
+/// listing them in `NH_NO_CHECKS`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SkippableCheck {
+    /// The minimum Nix version
+    Version,
+    /// Experimental features required by the command
+    Features,
+    /// Free space on /nix and /boot before building
+    DiskSpace,
+    /// Room for the new kernel and initrd on the boot partition before
+    /// installing the bootloader
+    BootSpace,
+    /// Reachability of substituters with `--check-substituters`
+    Substituters,
+    /// Whether the current user is trusted, for options that need it
+    Trust,
+    /// Keys for sops-nix and agenix secrets on the target before switching
+    Secrets,
+    /// Platform, Nix and free space of a `--target-host` before copying
+    TargetHost,
+    /// Emulation or a remote builder for the platform given with `--system`
+    System,
+}
+
+/// Checks skipped with `--skip-check`
+static SKIPPED_CHECKS: OnceLock<Vec<SkippableCheck>> = OnceLock::new();
+
+/// Skip `checks` for the rest of the run, in addition to the ones in
+/// `NH_NO_CHECKS`.
+pub fn skip_checks(checks: &[SkippableCheck]) {
+    let _ = SKIPPED_CHECKS.set(checks.to_vec());
+}
+
+/// Values of `NH_NO_CHECKS` that skip all checks, like the traditional
+/// `NH_NO_CHECKS=1`. Any number does too, as any value used to.
+const SKIP_ALL: [&str; 8] = ["", "y", "yes", "t", "true", "on", "all", "1"];
+
+/// Unknown check names in `NH_NO_CHECKS` are only warned about once
+static UNKNOWN_CHECKS_WARNING: Once = Once::new();
+
+/// Parse the value of `NH_NO_CHECKS`, which is either one of [`SKIP_ALL`]
+/// or a number to skip all checks (`None`), or a list of check names.
+/// Unknown names are ignored with a warning, so that a typo doesn't turn
+/// every check off.
+fn parse_skipped_checks(value: &str) -> Option<Vec<SkippableCheck>> {
+    let value = value.trim();
+    if SKIP_ALL.iter().any(|all| value.eq_ignore_ascii_case(all))
+        || value.chars().all(|c| c.is_ascii_digit())
+    {
+        return None;
+    }
+
+    let mut checks = Vec::new();
+    let mut unknown = Vec::new();
+    for name in value
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+    {
+        match SkippableCheck::from_str(name, true) {
+            Ok(check) => checks.push(check),
+            Err(_) => unknown.push(name),
+        }
+    }
+
+    if !unknown.is_empty() {
+        UNKNOWN_CHECKS_WARNING.call_once(|| {
+            warn!(
+                "Ignoring unknown checks in NH_NO_CHECKS: {}",
+                unknown.join(", ")
+            );
+        });
+    }
+
+    Some(checks)
+}
+
+/// Whether `check` was skipped with `--skip-check` or `NH_NO_CHECKS`.
+#[must_use]
+pub fn is_skipped(check: SkippableCheck) -> bool {
+    if SKIPPED_CHECKS
+        .get()
+        .is_some_and(|skipped| skipped.contains(&check))
+    {
+        return true;
+    }
+
+    env::var("NH_NO_CHECKS").is_ok_and(|value| {
+        parse_skipped_checks(&value).is_none_or(|checks| checks.contains(&check))
+    })
+}
+
 /// Verifies if the installed Nix version meets requirements
 ///
 /// # Returns
 ///
 /// * `Result<()>` - Ok if version requirements are met, error otherwise
 pub fn check_nix_version() -> Result<()> {
+    if is_skipped(SkippableCheck::Version) {
+        return Ok(());
+    }
+
+    if let Some(warning) = nix_version_warning()? {
+        warn!("{warning}");
+    }
+
+    Ok(())
+}
+
+/// Compares the installed Nix version against the minimum supported one
+///
+/// # Returns
+///
+/// * `Result<Option<String>>` - A warning if the installed version is older
+///   than recommended, `None` if it is recent enough or can't be parsed
+pub fn nix_version_warning() -> Result<Option<String>> {
     // XXX: Both Nix and Lix follow semantic versioning (semver). Update the
     // versions below once latest stable for either of those packages change.
     // We *also* cannot (or rather, will not) make this check for non-nixpkgs
@@ -22,13 +146,17 @@ pub fn check_nix_version() -> Result<()> {
     const MIN_LIX_VERSION: &str = "2.91.3";
     const MIN_NIX_VERSION: &str = "2.28.4";
 
-    if env::var("NH_NO_CHECKS").is_ok() {
-        return Ok(());
+    let nix_variant = util::get_nix_variant();
+    if let NixVariant::Other(name) = nix_variant {
+        debug!("Not checking the version of {name}, its versioning is unknown");
+        return Ok(None);
     }
 
-    let nix_variant = util::get_nix_variant();
+    // Determinate Nix reports its own version first, compare the version of
+    // Nix it's based on instead
     let version = util::get_nix_version()?;
-    let version_normal = normalize_version_string(&version);
+    let version = util::nix_version_number(&version);
+    let version_normal = normalize_version_string(version);
 
     // Minimum supported versions. Those should generally correspond to
     // latest package versions in the stable branch.
@@ -42,35 +170,30 @@ pub fn check_nix_version() -> Result<()> {
     // to try and support too many versions. NixOS stable and unstable
     // will ALWAYS be supported, but outdated versions will not. If your
     // Nix fork uses a different versioning scheme, please open an issue.
+    //
+    // Users on forks or pinned versions can lower these in the configuration.
+    let checks = &config::get().checks;
     let min_version = match nix_variant {
-        util::NixVariant::Lix => MIN_LIX_VERSION,
-        _ => MIN_NIX_VERSION,
+        util::NixVariant::Lix => checks.min_lix_version.as_deref().unwrap_or(MIN_LIX_VERSION),
+        _ => checks.min_nix_version.as_deref().unwrap_or(MIN_NIX_VERSION),
     };
 
     let current = match Version::parse(&version_normal) {
         Ok(ver) => ver,
         Err(e) => {
             warn!("Failed to parse Nix version '{version_normal}': {e}. Skipping version check.",);
-            return Ok(());
+            return Ok(None);
         }
     };
 
-    let required = Version::parse(min_version)?;
+    let required = Version::parse(&normalize_version_string(min_version))
+        .wrap_err(format!("Invalid minimum version '{min_version}'"))?;
 
     match current.cmp(&required) {
-        Ordering::Less => {
-            let binary_name = match nix_variant {
-                util::NixVariant::Lix => "Lix",
-                util::NixVariant::Determinate => "Determinate Nix",
-                util::NixVariant::Nix => "Nix",
-            };
-            warn!(
-                "Warning: {} version {} is older than the recommended minimum version {}. You may encounter issues.",
-                binary_name, version, min_version
-            );
-            Ok(())
-        }
-        _ => Ok(()),
+        Ordering::Less => Ok(Some(format!(
+            "Warning: {nix_variant} version {version} is older than the recommended minimum version {min_version}. You may encounter issues."
+        ))),
+        _ => Ok(None),
     }
 }
 
@@ -101,6 +224,17 @@ pub fn setup_environment() -> Result<bool> {
         }
     }
 
+    // Let ssh, including the one nix runs for `nix copy` and remote
+    // builders, ask for passphrases with the same program as sudo
+    if let Ok(askpass) = std::env::var("NH_SUDO_ASKPASS") {
+        if std::env::var_os("SSH_ASKPASS").is_none() {
+            unsafe {
+                std::env::set_var("SSH_ASKPASS", askpass);
+                std::env::set_var("SSH_ASKPASS_REQUIRE", "prefer");
+            }
+        }
+    }
+
     Ok(do_warn)
 }
 
@@ -115,7 +249,7 @@ pub fn setup_environment() -> Result<bool> {
 ///
 /// * `Result<()>` - Ok if all checks pass, error otherwise
 pub fn verify_nix_environment() -> Result<()> {
-    if env::var("NH_NO_CHECKS").is_ok() {
+    if is_skipped(SkippableCheck::Version) {
         return Ok(());
     }
 
@@ -125,6 +259,256 @@ pub fn verify_nix_environment() -> Result<()> {
     Ok(())
 }
 
+/// Free space the boot partition needs at least before switching, unless
+/// `--min-free-boot` says otherwise. `nh doctor` checks for it too.
+pub const MIN_FREE_BOOT: u64 = 100 * 1024 * 1024;
+
+/// Verifies that the filesystem containing `path` has at least `required`
+/// bytes free, so a build or switch doesn't run out of space halfway through.
+///
+/// Warns if there is less than twice the required space left. Paths that
+/// don't exist are skipped.
+///
+/// # Returns
+///
+/// * `Result<()>` - Ok if there is enough space, error otherwise
+pub fn check_free_space(path: &Path, required: u64) -> Result<()> {
+    if is_skipped(SkippableCheck::DiskSpace) {
+        return Ok(());
+    }
+
+    let Some(free) = util::free_space(path) else {
+        debug!("Couldn't determine free space on {}", path.display());
+        return Ok(());
+    };
+    debug!(?path, free, required, "Checking free space");
+
+    if free < required {
+        return Err(eyre!(
+            "Only {} free on {}, but at least {} are needed. Run `nh clean all` to free up space, or lower the threshold with --min-free-space",
+            util::format_bytes(free),
+            path.display(),
+            util::format_bytes(required)
+        )
+        .wrap_err(Failure::Environment));
+    } else if free < required.saturating_mul(2) {
+        warn!(
+            "Only {} free on {}, consider running `nh clean all`",
+            util::format_bytes(free),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+/// Render a snippet that adds `values` to the Nix setting `name`, as a
+/// NixOS option when `nixos` is set, and as a line of nix.conf otherwise.
+#[must_use]
+pub fn format_nix_setting(name: &str, values: &[&str], nixos: bool) -> String {
+    if nixos {
+        let values: Vec<String> = values.iter().map(|value| format!("\"{value}\"")).collect();
+        format!("nix.settings.{name} = [ {} ];", values.join(" "))
+    } else {
+        format!("extra-{name} = {}", values.join(" "))
+    }
+}
+
+/// [`format_nix_setting`] for the system nh is running on.
+#[must_use]
+pub fn nix_setting_snippet(name: &str, values: &[&str]) -> String {
+    let nixos = Path::new("/etc/NIXOS").exists();
+    let snippet = format_nix_setting(name, values, nixos);
+    if nixos {
+        format!("add `{snippet}` to your configuration")
+    } else {
+        format!("add `{snippet}` to /etc/nix/nix.conf and restart the nix daemon")
+    }
+}
+
+/// Information about the store reported by `nix store ping --json`
+#[derive(Debug, Deserialize)]
+pub struct StoreInfo {
+    /// 1 if the current user is trusted, missing on older daemons
+    pub trusted: Option<u8>,
+    pub version: Option<String>,
+}
+
+impl StoreInfo {
+    /// Query the store, `None` if it isn't reachable.
+    #[must_use]
+    pub fn query() -> Option<Self> {
+        let output = Command::new("nix")
+            .args(["store", "ping", "--json"])
+            .run_capture()
+            .ok()
+            .flatten()?;
+        serde_json::from_str(&output).ok()
+    }
+
+    #[must_use]
+    pub fn is_trusted(&self) -> Option<bool> {
+        self.trusted.map(|trusted| trusted == 1)
+    }
+}
+
+fn current_user() -> String {
+    nix::unistd::User::from_uid(nix::unistd::Uid::current())
+        .ok()
+        .flatten()
+        .map(|user| user.name)
+        .or_else(|| env::var("USER").ok())
+        .unwrap_or_else(|| "<user>".to_string())
+}
+
+/// Snippet making the current user trusted.
+#[must_use]
+pub fn trusted_user_snippet() -> String {
+    nix_setting_snippet("trusted-users", &[&current_user()])
+}
+
+/// Warn if `feature` is used while the daemon doesn't trust the current
+/// user, which makes Nix silently ignore settings like `--builders`.
+pub fn warn_if_untrusted(feature: &str) {
+    if is_skipped(SkippableCheck::Trust) {
+        return;
+    }
+
+    if StoreInfo::query().and_then(|info| info.is_trusted()) == Some(false) {
+        warn!(
+            "{feature} needs a trusted user, but the nix daemon doesn't trust {}. To fix it, {}",
+            current_user(),
+            trusted_user_snippet()
+        );
+    }
+}
+
+/// The binary cache nixpkgs is built into
+pub const DEFAULT_SUBSTITUTER: &str = "https://cache.nixos.org";
+
+/// Result of querying a substituter's `nix-cache-info`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SubstituterStatus {
+    /// Responded within the given time
+    Reachable(Duration),
+    /// Responded with 401 or 403, usually missing netrc credentials
+    Unauthorized(u16),
+    Unreachable(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct SubstituterProbe {
+    pub url: String,
+    pub status: SubstituterStatus,
+}
+
+impl SubstituterProbe {
+    #[must_use]
+    pub fn is_default(&self) -> bool {
+        self.url.trim_end_matches('/') == DEFAULT_SUBSTITUTER
+    }
+}
+
+fn substituter_status(code: u16, elapsed: Duration) -> SubstituterStatus {
+    match code {
+        200..=299 => SubstituterStatus::Reachable(elapsed),
+        401 | 403 => SubstituterStatus::Unauthorized(code),
+        _ => SubstituterStatus::Unreachable(format!("HTTP status {code}")),
+    }
+}
+
+/// Substituters from the Nix configuration. Only HTTP(S) substituters can be
+/// probed, others like `ssh://` or `s3://` are left out.
+#[must_use]
+pub fn configured_substituters() -> Vec<String> {
+    Command::new("nix")
+        .args(["config", "show", "substituters"])
+        .run_capture()
+        .ok()
+        .flatten()
+        .unwrap_or_default()
+        .split_whitespace()
+        .filter(|url| url.starts_with("http://") || url.starts_with("https://"))
+        .map(String::from)
+        .collect()
+}
+
+/// Query `url/nix-cache-info` and time the response.
+#[must_use]
+pub fn probe_substituter(url: &str) -> SubstituterProbe {
+    let start = Instant::now();
+    let response = reqwest::blocking::Client::new()
+        .get(format!("{}/nix-cache-info", url.trim_end_matches('/')))
+        .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
+        .timeout(Duration::from_secs(5))
+        .send();
+
+    let status = match response {
+        Ok(response) => substituter_status(response.status().as_u16(), start.elapsed()),
+        Err(err) if err.is_timeout() => SubstituterStatus::Unreachable("timed out".to_string()),
+        Err(err) => SubstituterStatus::Unreachable(err.to_string()),
+    };
+
+    SubstituterProbe {
+        url: url.to_string(),
+        status,
+    }
+}
+
+/// Probe all configured substituters in parallel.
+#[must_use]
+pub fn probe_substituters() -> Vec<SubstituterProbe> {
+    let substituters = configured_substituters();
+
+    thread::scope(|scope| {
+        let handles: Vec<_> = substituters
+            .iter()
+            .map(|url| scope.spawn(|| probe_substituter(url)))
+            .collect();
+
+        handles
+            .into_iter()
+            .zip(&substituters)
+            .map(|(handle, url)| {
+                handle.join().unwrap_or_else(|_| SubstituterProbe {
+                    url: url.clone(),
+                    status: SubstituterStatus::Unreachable("probe panicked".to_string()),
+                })
+            })
+            .collect()
+    })
+}
+
+/// Warn about substituters that can't be used, so a build compiling
+/// everything from source doesn't come as a surprise.
+pub fn warn_unusable_substituters() {
+    if is_skipped(SkippableCheck::Substituters) {
+        return;
+    }
+
+    for probe in probe_substituters() {
+        match probe.status {
+            SubstituterStatus::Reachable(elapsed) => {
+                debug!(url = probe.url, ?elapsed, "Substituter is reachable");
+            }
+            SubstituterStatus::Unauthorized(code) => {
+                warn!(
+                    "Substituter {} denied access (HTTP {code}), check the credentials in your netrc-file",
+                    probe.url
+                );
+            }
+            SubstituterStatus::Unreachable(reason) if probe.is_default() => {
+                warn!(
+                    "{DEFAULT_SUBSTITUTER} is unreachable ({reason}), packages will be built from source"
+                );
+            }
+            SubstituterStatus::Unreachable(reason) => {
+                warn!("Substituter {} is unreachable: {reason}", probe.url);
+            }
+        }
+    }
+}
+
 /// Trait for types that have feature requirements
 pub trait FeatureRequirements {
     /// Returns the list of required experimental features
@@ -132,7 +516,7 @@ pub trait FeatureRequirements {
 
     /// Checks if all required features are enabled
     fn check_features(&self) -> Result<()> {
-        if env::var("NH_NO_CHECKS").is_ok() {
+        if is_skipped(SkippableCheck::Features) {
             return Ok(());
         }
 
@@ -156,24 +540,32 @@ pub trait FeatureRequirements {
     }
 }
 
+/// Experimental features commands using flakes need: the list from the
+/// configuration if set, otherwise nix-command and flakes, which Determinate
+/// Nix enables by default.
+#[must_use]
+pub fn flake_features() -> Vec<&'static str> {
+    if let Some(features) = &config::get().checks.required_features {
+        return features.iter().map(String::as_str).collect();
+    }
+
+    // Determinate Nix doesn't require nix-command or flakes to be experimental
+    // as they simply decided to mark those as no-longer-experimental-lol. Remove
+    // redundant experimental features if the Nix variant is determinate.
+    if matches!(util::get_nix_variant(), NixVariant::Determinate) {
+        vec![]
+    } else {
+        vec!["nix-command", "flakes"]
+    }
+}
+
 /// Feature requirements for commands that use flakes
 #[derive(Debug)]
 pub struct FlakeFeatures;
 
 impl FeatureRequirements for FlakeFeatures {
     fn required_features(&self) -> Vec<&'static str> {
-        let mut features = vec![];
-
-        // Determinate Nix doesn't require nix-command or flakes to be experimental
-        // as they simply decided to mark those as no-longer-experimental-lol. Remove
-        // redundant experimental features if the Nix variant is determinate.
-        let variant = util::get_nix_variant();
-        if !matches!(variant, NixVariant::Determinate) {
-            features.push("nix-command");
-            features.push("flakes");
-        }
-
-        features
+        flake_features()
     }
 }
 
@@ -206,14 +598,10 @@ impl FeatureRequirements for OsReplFeatures {
         }
 
         // For flake repls, check if we need experimental features
+        features.extend(flake_features());
         match util::get_nix_variant() {
-            NixVariant::Determinate => {
-                // Determinate Nix doesn't need experimental features
-            }
+            NixVariant::Determinate | NixVariant::Nix | NixVariant::Other(_) => {}
             NixVariant::Lix => {
-                features.push("nix-command");
-                features.push("flakes");
-
                 // Lix-specific repl-flake feature for older versions
                 if let Ok(version) = util::get_nix_version() {
                     let normalized_version = normalize_version_string(&version);
@@ -226,10 +614,6 @@ impl FeatureRequirements for OsReplFeatures {
                     }
                 }
             }
-            NixVariant::Nix => {
-                features.push("nix-command");
-                features.push("flakes");
-            }
         }
 
         features
@@ -252,12 +636,7 @@ impl FeatureRequirements for HomeReplFeatures {
         }
 
         // For flake repls, only need nix-command and flakes
-        let variant = util::get_nix_variant();
-        if !matches!(variant, NixVariant::Determinate) {
-            features.push("nix-command");
-            features.push("flakes");
-        }
-
+        features.extend(flake_features());
         features
     }
 }
@@ -278,12 +657,7 @@ impl FeatureRequirements for DarwinReplFeatures {
         }
 
         // For flake repls, only need nix-command and flakes
-        let variant = util::get_nix_variant();
-        if !matches!(variant, NixVariant::Determinate) {
-            features.push("nix-command");
-            features.push("flakes");
-        }
-
+        features.extend(flake_features());
         features
     }
 }
@@ -306,36 +680,7 @@ mod tests {
     use serial_test::serial;
 
     use super::*;
-
-    // This helps set environment variables safely in tests
-    struct EnvGuard {
-        key: String,
-        original: Option<String>,
-    }
-
-    impl EnvGuard {
-        fn new(key: &str, value: &str) -> Self {
-            let original = env::var(key).ok();
-            unsafe {
-                env::set_var(key, value);
-            }
-            EnvGuard {
-                key: key.to_string(),
-                original,
-            }
-        }
-    }
-
-    impl Drop for EnvGuard {
-        fn drop(&mut self) {
-            unsafe {
-                match &self.original {
-                    Some(val) => env::set_var(&self.key, val),
-                    None => env::remove_var(&self.key),
-                }
-            }
-        }
-    }
+    use crate::test_util::EnvGuard;
 
     proptest! {
         #[test]
@@ -625,6 +970,35 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_parse_skipped_checks() {
+        assert_eq!(parse_skipped_checks("1"), None);
+        assert_eq!(parse_skipped_checks(""), None);
+        assert_eq!(
+            parse_skipped_checks("version, disk-space"),
+            Some(vec![SkippableCheck::Version, SkippableCheck::DiskSpace])
+        );
+        assert_eq!(parse_skipped_checks("TRUE"), None);
+        assert_eq!(parse_skipped_checks("y"), None);
+        assert_eq!(parse_skipped_checks("all"), None);
+        assert_eq!(parse_skipped_checks("0"), None);
+        assert_eq!(parse_skipped_checks("2"), None);
+        assert_eq!(
+            parse_skipped_checks("version,bogus"),
+            Some(vec![SkippableCheck::Version])
+        );
+        assert_eq!(parse_skipped_checks("disk-spac"), Some(vec![]));
+    }
+
+    #[test]
+    #[serial]
+    fn test_nh_no_checks_skips_listed_checks() {
+        let _guard = EnvGuard::new("NH_NO_CHECKS", "version");
+
+        assert!(is_skipped(SkippableCheck::Version));
+        assert!(!is_skipped(SkippableCheck::Features));
+    }
+
     #[test]
     #[serial]
     fn test_check_nix_version_bypassed_with_nh_no_checks() {
@@ -659,4 +1033,33 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn test_substituter_status() {
+        let elapsed = Duration::from_millis(42);
+        assert_eq!(
+            substituter_status(200, elapsed),
+            SubstituterStatus::Reachable(elapsed)
+        );
+        assert_eq!(
+            substituter_status(403, elapsed),
+            SubstituterStatus::Unauthorized(403)
+        );
+        assert!(matches!(
+            substituter_status(404, elapsed),
+            SubstituterStatus::Unreachable(_)
+        ));
+    }
+
+    #[test]
+    fn test_format_nix_setting() {
+        assert_eq!(
+            format_nix_setting("trusted-users", &["alice"], true),
+            r#"nix.settings.trusted-users = [ "alice" ];"#
+        );
+        assert_eq!(
+            format_nix_setting("experimental-features", &["nix-command", "flakes"], false),
+            "extra-experimental-features = nix-command flakes"
+        );
+    }
 }
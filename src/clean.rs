@@ -16,16 +16,190 @@ use regex::Regex;
 use tracing::{Level, debug, info, instrument, span, warn};
 use uzers::os::unix::UserExt;
 
+use crate::events::{self, Phase};
+use crate::exit;
+use crate::gcroots;
+use crate::json::{self, CleanResult, Output};
+use crate::output;
+use crate::template::Fields;
+use crate::theme::{Role, paint};
 use crate::{Result, commands::Command, interface};
 
 // Nix impl:
 // https://github.com/NixOS/nix/blob/master/src/nix-collect-garbage/nix-collect-garbage.cc
 
-#[derive(Debug, Hash, PartialEq, Eq, PartialOrd, Ord)]
-struct Generation {
-    number: u32,
-    last_modified: SystemTime,
-    path: PathBuf,
+/// A generation of a profile, as far as cleaning is concerned
+#[derive(Debug, Clone, Hash, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Generation {
+    pub number: u32,
+    /// When the generation link was created
+    pub last_modified: SystemTime,
+    pub path: PathBuf,
+    /// Pinned generations are never removed
+    pub pinned: bool,
+    /// The generation a GC root outside the profile keeps as the active one,
+    /// like system-manager's. It is never removed either.
+    pub active: bool,
+}
+
+/// Which of `generations` are removed at `now` when keeping the `keep`
+/// newest ones and those younger than `keep_since`. Pinned and active
+/// generations are always kept.
+#[must_use]
+pub fn retention(
+    generations: &[Generation],
+    keep: u32,
+    keep_since: std::time::Duration,
+    now: SystemTime,
+) -> Vec<bool> {
+    // The number of the oldest of the `keep` newest generations
+    let mut numbers: Vec<u32> = generations
+        .iter()
+        .map(|generation| generation.number)
+        .collect();
+    numbers.sort_unstable_by(|a, b| b.cmp(a));
+    let oldest_kept = (keep > 0).then(|| numbers.get(keep as usize - 1).copied().unwrap_or(0));
+
+    generations
+        .iter()
+        .map(|generation| {
+            // Generations from the future count as young
+            let young = now
+                .duration_since(generation.last_modified)
+                .map_or(true, |age| age <= keep_since);
+            let newest = oldest_kept.is_some_and(|oldest| generation.number >= oldest);
+            !(young || newest || generation.pinned || generation.active)
+        })
+        .collect()
+}
+
+/// Kinds of profiles and roots `--keep-since` can be given separately for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProfileClass {
+    /// NixOS, nix-darwin and system-manager systems
+    System,
+    /// Home Manager generations
+    Home,
+    /// Any other profile, like the user's `nix profile`
+    User,
+    /// Out-links like `result` and `.direnv` roots
+    Result,
+}
+
+impl ProfileClass {
+    const ALL: [Self; 4] = [Self::System, Self::Home, Self::User, Self::Result];
+
+    const fn name(self) -> &'static str {
+        match self {
+            Self::System => "system",
+            Self::Home => "home",
+            Self::User => "user",
+            Self::Result => "result",
+        }
+    }
+
+    fn of_profile(profile: &Path) -> Self {
+        if profile == Path::new(crate::system::PROFILE) {
+            return Self::System;
+        }
+        match profile.file_name().and_then(|name| name.to_str()) {
+            Some("system") => Self::System,
+            Some("home-manager") => Self::Home,
+            _ => Self::User,
+        }
+    }
+}
+
+/// `--keep-since`, either one age for everything, or ages per
+/// [`ProfileClass`] like `system=30d,home=7d`. Classes without an age of
+/// their own get the one given without a class, or none.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeepSince {
+    default: humantime::Duration,
+    classes: Vec<(ProfileClass, humantime::Duration)>,
+}
+
+impl From<humantime::Duration> for KeepSince {
+    /// The same age for every class of profile
+    fn from(default: humantime::Duration) -> Self {
+        Self {
+            default,
+            classes: Vec::new(),
+        }
+    }
+}
+
+impl KeepSince {
+    pub fn parse(value: &str) -> std::result::Result<Self, String> {
+        let mut keep_since = Self {
+            default: std::time::Duration::ZERO.into(),
+            classes: Vec::new(),
+        };
+        for part in value
+            .split(',')
+            .map(str::trim)
+            .filter(|part| !part.is_empty())
+        {
+            let parse_duration = |duration: &str| {
+                duration
+                    .parse::<humantime::Duration>()
+                    .map_err(|err| format!("invalid duration '{duration}': {err}"))
+            };
+            match part.split_once('=') {
+                None => keep_since.default = parse_duration(part)?,
+                Some((class, duration)) => {
+                    let class = ProfileClass::ALL
+                        .into_iter()
+                        .find(|c| c.name() == class.trim())
+                        .ok_or_else(|| {
+                            format!(
+                                "unknown profile class '{class}', expected one of system, home, user, result"
+                            )
+                        })?;
+                    keep_since
+                        .classes
+                        .push((class, parse_duration(duration.trim())?));
+                }
+            }
+        }
+        Ok(keep_since)
+    }
+
+    /// The age below which paths of `class` are kept.
+    #[must_use]
+    pub fn of(&self, class: ProfileClass) -> humantime::Duration {
+        self.classes
+            .iter()
+            .rev()
+            .find(|(c, _)| *c == class)
+            .map_or(self.default, |(_, duration)| *duration)
+    }
+
+    /// The value of `--keep-since` this was parsed from, normalized.
+    #[must_use]
+    pub fn to_arg(&self) -> String {
+        std::iter::once(self.default.to_string())
+            .chain(
+                self.classes
+                    .iter()
+                    .map(|(class, duration)| format!("{}={duration}", class.name())),
+            )
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+}
+
+impl fmt::Display for KeepSince {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.classes.is_empty() {
+            return write!(f, "{}", self.default);
+        }
+        let classes: Vec<String> = ProfileClass::ALL
+            .into_iter()
+            .map(|class| format!("{} for {}", self.of(class), class.name()))
+            .collect();
+        write!(f, "{}", classes.join(", "))
+    }
 }
 
 type ToBeRemoved = bool;
@@ -35,9 +209,14 @@ type ProfilesTagged = HashMap<PathBuf, GenerationsTagged>;
 
 impl interface::CleanMode {
     pub fn run(&self) -> Result<()> {
+        let result = self.clean()?;
+        json::emit(&Output::Clean(result))
+    }
+
+    /// Clean up like `run`, returning what was removed instead of printing
+    /// it.
+    pub(crate) fn clean(&self) -> Result<CleanResult> {
         let mut profiles = Vec::new();
-        let mut gcroots_tagged: HashMap<PathBuf, ToBeRemoved> = HashMap::new();
-        let now = SystemTime::now();
         let mut is_profile_clean = false;
 
         // What profiles to clean depending on the call mode
@@ -85,6 +264,15 @@ impl interface::CleanMode {
                         }),
                 );
 
+                // system-manager keeps its profile in a directory of its own,
+                // which only exists on hosts it manages
+                let system_manager_profiles = Path::new(crate::system::PROFILE)
+                    .parent()
+                    .unwrap_or_else(|| Path::new("/nix/var/nix/profiles"));
+                if system_manager_profiles.is_dir() {
+                    profiles.extend(profiles_in_dir(system_manager_profiles));
+                }
+
                 // Most unix systems start regular users at uid 1000+, but macos is special at 501+
                 // https://en.wikipedia.org/wiki/User_identifier
                 let uid_min = if cfg!(target_os = "macos") { 501 } else { 1000 };
@@ -140,166 +328,346 @@ impl interface::CleanMode {
             }
         };
 
-        // Use mutation to raise errors as they come
-        let mut profiles_tagged = ProfilesTagged::new();
-        for p in profiles {
-            profiles_tagged.insert(
-                p.clone(),
-                cleanable_generations(&p, args.keep, args.keep_since)?,
+        clean_profiles(profiles, args, !is_profile_clean)
+    }
+}
+
+/// The system profile and the Home Manager profiles of every user, which is
+/// what `nh darwin clean` cleans.
+#[must_use]
+pub fn darwin_profiles() -> Vec<PathBuf> {
+    let mut profiles = vec![PathBuf::from("/nix/var/nix/profiles/system")];
+    // Regular users start at uid 501 on macOS. Service accounts have no home
+    // directory, or /var/empty.
+    for user in unsafe { uzers::all_users() } {
+        let has_home = user.home_dir().is_dir() && user.home_dir() != Path::new("/var/empty");
+        if user.uid() >= 501 && has_home || user.uid() == 0 {
+            profiles.push(
+                user.home_dir()
+                    .join(".local/state/nix/profiles/home-manager"),
+            );
+            profiles.push(
+                Path::new("/nix/var/nix/profiles/per-user")
+                    .join(user.name())
+                    .join("home-manager"),
             );
         }
+    }
+    profiles.retain(|profile| profile.is_symlink());
+    debug!(?profiles, "Found darwin profiles");
+    profiles
+}
 
-        // Query gcroots
-        let filename_tests = [r".*/.direnv/.*", r".*result.*"];
-        let regexes = filename_tests
-            .into_iter()
-            .map(Regex::new)
-            .collect::<Result<Vec<_>, regex::Error>>()?;
+/// Clean the generations of `profiles`, and `result` and `.direnv` roots
+/// with `gcroots`, as set by `args`.
+pub(crate) fn clean_profiles(
+    profiles: Vec<PathBuf>,
+    args: &interface::CleanArgs,
+    gcroots: bool,
+) -> Result<CleanResult> {
+    let mut gcroots_tagged: HashMap<PathBuf, ToBeRemoved> = HashMap::new();
+    let now = SystemTime::now();
+
+    // Use mutation to raise errors as they come
+    let mut profiles_tagged = ProfilesTagged::new();
+    for p in profiles {
+        profiles_tagged.insert(
+            p.clone(),
+            cleanable_generations(
+                &p,
+                args.keep,
+                args.keep_since.of(ProfileClass::of_profile(&p)),
+                active_root(&p).as_deref(),
+            )?,
+        );
+    }
 
-        if !is_profile_clean && !args.nogcroots {
-            for elem in PathBuf::from("/nix/var/nix/gcroots/auto")
-                .read_dir()
-                .wrap_err("Reading auto gcroots dir")?
+    // Query gcroots
+    let filename_tests = [r".*/.direnv/.*", r".*result.*"];
+    let regexes = filename_tests
+        .into_iter()
+        .map(Regex::new)
+        .collect::<Result<Vec<_>, regex::Error>>()?;
+
+    if gcroots && !args.nogcroots {
+        for elem in PathBuf::from("/nix/var/nix/gcroots/auto")
+            .read_dir()
+            .wrap_err("Reading auto gcroots dir")?
+        {
+            let src = elem.wrap_err("Reading auto gcroots element")?.path();
+            let dst = src.read_link().wrap_err("Reading symlink destination")?;
+            let span = span!(Level::TRACE, "gcroot detection", ?dst);
+            let _entered = span.enter();
+            debug!(?src);
+
+            if !regexes
+                .iter()
+                .any(|next| next.is_match(&dst.to_string_lossy()))
             {
-                let src = elem.wrap_err("Reading auto gcroots element")?.path();
-                let dst = src.read_link().wrap_err("Reading symlink destination")?;
-                let span = span!(Level::TRACE, "gcroot detection", ?dst);
-                let _entered = span.enter();
-                debug!(?src);
+                debug!("dst doesn't match any gcroot regex, skipping");
+                continue;
+            }
 
-                if !regexes
-                    .iter()
-                    .any(|next| next.is_match(&dst.to_string_lossy()))
-                {
-                    debug!("dst doesn't match any gcroot regex, skipping");
-                    continue;
-                }
+            if gcroots::is_in_flight(&dst) {
+                debug!("dst is the result of a running nh, skipping");
+                continue;
+            }
 
-                // Create a file descriptor for the current working directory
-                let dirfd = nix::fcntl::open(
-                    ".",
-                    nix::fcntl::OFlag::O_DIRECTORY,
-                    nix::sys::stat::Mode::empty(),
-                )?;
-
-                // Use .exists to not travel symlinks
-                if match faccessat(
-                    &dirfd,
-                    &dst,
-                    AccessFlags::F_OK | AccessFlags::W_OK,
-                    AtFlags::AT_SYMLINK_NOFOLLOW,
-                ) {
-                    Ok(()) => true,
-                    Err(errno) => match errno {
-                        Errno::EACCES | Errno::ENOENT => false,
-                        _ => {
-                            bail!(
-                                eyre!("Checking access for gcroot {:?}, unknown error", dst)
-                                    .wrap_err(errno)
-                            )
-                        }
-                    },
-                } {
-                    let dur = now.duration_since(
-                        dst.symlink_metadata()
-                            .wrap_err("Reading gcroot metadata")?
-                            .modified()?,
-                    );
-                    debug!(?dur);
-                    match dur {
-                        Err(err) => {
-                            warn!(?err, ?now, "Failed to compare time!");
-                        }
-                        Ok(val) if val <= args.keep_since.into() => {
-                            gcroots_tagged.insert(dst, false);
-                        }
-                        Ok(_) => {
-                            gcroots_tagged.insert(dst, true);
-                        }
+            // Create a file descriptor for the current working directory
+            let dirfd = nix::fcntl::open(
+                ".",
+                nix::fcntl::OFlag::O_DIRECTORY,
+                nix::sys::stat::Mode::empty(),
+            )?;
+
+            // Use .exists to not travel symlinks
+            if match faccessat(
+                &dirfd,
+                &dst,
+                AccessFlags::F_OK | AccessFlags::W_OK,
+                AtFlags::AT_SYMLINK_NOFOLLOW,
+            ) {
+                Ok(()) => true,
+                Err(errno) => match errno {
+                    Errno::EACCES | Errno::ENOENT => false,
+                    _ => {
+                        bail!(
+                            eyre!("Checking access for gcroot {:?}, unknown error", dst)
+                                .wrap_err(errno)
+                        )
+                    }
+                },
+            } {
+                let dur = now.duration_since(
+                    dst.symlink_metadata()
+                        .wrap_err("Reading gcroot metadata")?
+                        .modified()?,
+                );
+                debug!(?dur);
+                match dur {
+                    Err(err) => {
+                        warn!(?err, ?now, "Failed to compare time!");
+                    }
+                    Ok(val) if val <= args.keep_since.of(ProfileClass::Result).into() => {
+                        gcroots_tagged.insert(dst, false);
+                    }
+                    Ok(_) => {
+                        gcroots_tagged.insert(dst, true);
                     }
-                } else {
-                    debug!("dst doesn't exist or is not writable, skipping");
                 }
+            } else {
+                debug!("dst doesn't exist or is not writable, skipping");
             }
         }
+    }
 
-        // Present the user the information about the paths to clean
-        use owo_colors::OwoColorize;
-        println!();
-        println!("{}", "Welcome to nh clean".bold());
-        println!("Keeping {} generation(s)", args.keep.green());
-        println!("Keeping paths newer than {}", args.keep_since.green());
-        println!();
-        println!("legend:");
-        println!("{}: path to be kept", "OK".green());
-        println!("{}: path to be removed", "DEL".red());
-        println!();
-        if !gcroots_tagged.is_empty() {
-            println!(
-                "{}",
-                "gcroots (matching the following regex patterns)"
-                    .blue()
-                    .bold()
-            );
-            for re in regexes {
-                println!("- {}  {}", "RE".purple(), re);
+    // Present the user the information about the paths to clean
+    if !json::output_enabled() {
+        match &args.format {
+            Some(template) => {
+                template.print_all(&plan_entries(&gcroots_tagged, &profiles_tagged))?;
             }
-            for (path, tbr) in &gcroots_tagged {
-                if *tbr {
-                    println!("- {} {}", "DEL".red(), path.to_string_lossy());
-                } else {
-                    println!("- {} {}", "OK ".green(), path.to_string_lossy());
-                }
+            None if output::human() => {
+                print_plan(args, &regexes, &gcroots_tagged, &profiles_tagged);
             }
-            println!();
+            None => {}
         }
-        for (profile, generations_tagged) in &profiles_tagged {
-            println!("{}", profile.to_string_lossy().blue().bold());
-            for (generation, tbr) in generations_tagged.iter().rev() {
-                if *tbr {
-                    println!("- {} {}", "DEL".red(), generation.path.to_string_lossy());
-                } else {
-                    println!("- {} {}", "OK ".green(), generation.path.to_string_lossy());
-                }
-            }
-            println!();
+    }
+
+    // Clean the paths
+    if args.ask {
+        info!("Confirm the cleanup plan?");
+        if !events::confirmation("Confirm the cleanup plan?", || {
+            Ok(dialoguer::Confirm::new().default(false).interact()?)
+        })? {
+            return Err(exit::declined("User rejected the cleanup plan"));
         }
+    }
 
-        // Clean the paths
-        if args.ask {
-            info!("Confirm the cleanup plan?");
-            if !dialoguer::Confirm::new().default(false).interact()? {
-                bail!("User rejected the cleanup plan");
+    if !args.dry {
+        for (path, tbr) in &gcroots_tagged {
+            if *tbr {
+                remove_path_nofail(path);
             }
         }
 
-        if !args.dry {
-            for (path, tbr) in &gcroots_tagged {
+        for generations_tagged in profiles_tagged.values() {
+            for (generation, tbr) in generations_tagged.iter().rev() {
                 if *tbr {
-                    remove_path_nofail(path);
-                }
-            }
-
-            for generations_tagged in profiles_tagged.values() {
-                for (generation, tbr) in generations_tagged.iter().rev() {
-                    if *tbr {
-                        remove_path_nofail(&generation.path);
-                    }
+                    remove_path_nofail(&generation.path);
                 }
             }
         }
+    }
 
-        if !args.nogc {
+    if !args.nogc {
+        events::phase(Phase::Gc, || {
             Command::new("nix")
                 .args(["store", "gc"])
                 .dry(args.dry)
                 .message("Performing garbage collection on the nix store")
-                .show_output(true)
+                .progress(true)
+                .show_output(output::human())
                 .with_required_env()
-                .run()?;
+                .run()
+        })?;
+    }
+
+    let mut removed = Vec::new();
+    let mut kept = Vec::new();
+    for (path, tbr) in gcroots_tagged {
+        if tbr { &mut removed } else { &mut kept }.push(path);
+    }
+    for generations_tagged in profiles_tagged.into_values() {
+        for (generation, tbr) in generations_tagged {
+            if tbr { &mut removed } else { &mut kept }.push(generation.path);
         }
+    }
+
+    Ok(CleanResult {
+        dry: args.dry,
+        removed,
+        kept,
+        gc: !args.nogc,
+    })
+}
+
+/// A path in the cleanup plan, as printed with `--format`
+struct PlanEntry<'a> {
+    path: &'a Path,
+    /// Profile the generation belongs to, or `None` for gcroots
+    profile: Option<&'a Path>,
+    generation: Option<u32>,
+    action: &'static str,
+}
+
+impl Fields for PlanEntry<'_> {
+    const FIELDS: &'static [&'static str] = &["action", "path", "kind", "profile", "generation"];
 
-        Ok(())
+    fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "action" => Some(self.action.to_string()),
+            "path" => Some(self.path.to_string_lossy().into_owned()),
+            "kind" => Some(
+                if self.profile.is_some() {
+                    "generation"
+                } else {
+                    "gcroot"
+                }
+                .to_string(),
+            ),
+            "profile" => self
+                .profile
+                .map(|profile| profile.to_string_lossy().into_owned()),
+            "generation" => self.generation.map(|number| number.to_string()),
+            _ => None,
+        }
+    }
+}
+
+fn plan_entries<'a>(
+    gcroots_tagged: &'a HashMap<PathBuf, ToBeRemoved>,
+    profiles_tagged: &'a ProfilesTagged,
+) -> Vec<PlanEntry<'a>> {
+    let action = |tbr: bool| if tbr { "remove" } else { "keep" };
+
+    let gcroots = gcroots_tagged.iter().map(|(path, tbr)| PlanEntry {
+        path,
+        profile: None,
+        generation: None,
+        action: action(*tbr),
+    });
+    let generations = profiles_tagged
+        .iter()
+        .flat_map(|(profile, generations_tagged)| {
+            generations_tagged
+                .iter()
+                .map(move |(generation, tbr)| PlanEntry {
+                    path: &generation.path,
+                    profile: Some(profile),
+                    generation: Some(generation.number),
+                    action: if generation.pinned {
+                        "pin"
+                    } else {
+                        action(*tbr)
+                    },
+                })
+        });
+
+    gcroots.chain(generations).collect()
+}
+
+fn print_plan(
+    args: &interface::CleanArgs,
+    regexes: &[Regex],
+    gcroots_tagged: &HashMap<PathBuf, ToBeRemoved>,
+    profiles_tagged: &ProfilesTagged,
+) {
+    println!();
+    println!("{}", paint("Welcome to nh clean", Role::Emphasis));
+    println!("Keeping {} generation(s)", paint(args.keep, Role::Value));
+    println!(
+        "Keeping paths newer than {}",
+        paint(&args.keep_since, Role::Value)
+    );
+    println!();
+    println!("legend:");
+    println!("{}: path to be kept", paint("OK", Role::Success));
+    println!("{}: path to be removed", paint("DEL", Role::Removed));
+    println!("{}: pinned path, always kept", paint("PIN", Role::Pinned));
+    println!();
+    if !gcroots_tagged.is_empty() {
+        println!(
+            "{}",
+            paint(
+                "gcroots (matching the following regex patterns)",
+                Role::Heading
+            )
+        );
+        for re in regexes {
+            println!("- {}  {}", paint("RE", Role::Literal), re);
+        }
+        for (path, tbr) in gcroots_tagged {
+            if *tbr {
+                println!(
+                    "- {} {}",
+                    paint("DEL", Role::Removed),
+                    path.to_string_lossy()
+                );
+            } else {
+                println!(
+                    "- {} {}",
+                    paint("OK ", Role::Success),
+                    path.to_string_lossy()
+                );
+            }
+        }
+        println!();
+    }
+    for (profile, generations_tagged) in profiles_tagged {
+        println!("{}", paint(profile.to_string_lossy(), Role::Heading));
+        for (generation, tbr) in generations_tagged.iter().rev() {
+            if *tbr {
+                println!(
+                    "- {} {}",
+                    paint("DEL", Role::Removed),
+                    generation.path.to_string_lossy()
+                );
+            } else if generation.pinned {
+                println!(
+                    "- {} {}",
+                    paint("PIN", Role::Pinned),
+                    generation.path.to_string_lossy()
+                );
+            } else {
+                println!(
+                    "- {} {}",
+                    paint("OK ", Role::Success),
+                    generation.path.to_string_lossy()
+                );
+            }
+        }
+        println!();
     }
 }
 
@@ -344,11 +712,22 @@ fn profiles_in_dir<P: AsRef<Path> + fmt::Debug>(dir: P) -> Vec<PathBuf> {
     res
 }
 
+/// Store path a GC root outside the profile keeps for `profile`, which is
+/// the closure system-manager activated last. It may not be the generation
+/// the profile points to, as when it was activated without nh.
+fn active_root(profile: &Path) -> Option<PathBuf> {
+    if profile != Path::new(crate::system::PROFILE) {
+        return None;
+    }
+    Path::new(crate::system::GCROOT).canonicalize().ok()
+}
+
 #[instrument(err, level = "debug")]
 fn cleanable_generations(
     profile: &Path,
     keep: u32,
     keep_since: humantime::Duration,
+    active: Option<&Path>,
 ) -> Result<GenerationsTagged> {
     let name = profile
         .file_name()
@@ -358,8 +737,9 @@ fn cleanable_generations(
 
     let generation_regex = Regex::new(&format!(r"^{name}-(\d+)-link"))?;
 
-    let mut result = GenerationsTagged::new();
+    let pinned = crate::generations::pinned(profile)?;
 
+    let mut generations = Vec::new();
     for entry in profile
         .parent()
         .context("Reading profile's parent dir")?
@@ -377,34 +757,23 @@ fn cleanable_generations(
                     .modified()
                     .context("Reading modified time")?;
 
-                result.insert(
-                    Generation {
-                        number: number.as_str().parse().unwrap(),
-                        last_modified,
-                        path: path.clone(),
-                    },
-                    true,
-                );
-            }
-        }
-    }
-
-    let now = SystemTime::now();
-    for (generation, tbr) in &mut result {
-        match now.duration_since(generation.last_modified) {
-            Err(err) => {
-                warn!(?err, ?now, ?generation, "Failed to compare time!");
+                let number: u32 = number.as_str().parse().unwrap();
+                generations.push(Generation {
+                    number,
+                    last_modified,
+                    pinned: pinned.contains(&u64::from(number)),
+                    active: active.is_some_and(|active| {
+                        path.canonicalize().is_ok_and(|closure| closure == active)
+                    }),
+                    path,
+                });
             }
-            Ok(val) if val <= keep_since.into() => {
-                *tbr = false;
-            }
-            Ok(_) => {}
         }
     }
+    generations.sort();
 
-    for (_, tbr) in result.iter_mut().rev().take(keep as _) {
-        *tbr = false;
-    }
+    let removed = retention(&generations, keep, keep_since.into(), SystemTime::now());
+    let result: GenerationsTagged = generations.into_iter().zip(removed).collect();
 
     debug!("{:#?}", result);
     Ok(result)
@@ -416,3 +785,180 @@ fn remove_path_nofail(path: &Path) {
         warn!(?path, ?err, "Failed to remove path");
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use proptest::prelude::*;
+    use serial_test::serial;
+
+    use super::*;
+    use crate::test_util::EnvGuard;
+
+    proptest! {
+        #[test]
+        fn test_retention_invariants(
+            ages in prop::collection::vec((0u64..100, any::<bool>()), 0..20),
+            active in any::<prop::sample::Index>(),
+            order in Just((0..20).collect::<Vec<usize>>()).prop_shuffle(),
+            keep in 0u32..5,
+            keep_since in 0u64..100,
+        ) {
+            let now = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+            let keep_since = Duration::from_secs(keep_since);
+            let active = (!ages.is_empty()).then(|| active.index(ages.len()));
+            let generations: Vec<Generation> = (0u32..)
+                .zip(&ages)
+                .map(|(number, (age, pinned))| Generation {
+                    number,
+                    last_modified: now - Duration::from_secs(*age),
+                    path: PathBuf::from(format!("system-{number}-link")),
+                    pinned: *pinned,
+                    active: active == Some(number as usize),
+                })
+                .collect();
+
+            let removed = retention(&generations, keep, keep_since, now);
+            prop_assert_eq!(removed.len(), generations.len());
+
+            // Active and pinned generations are never removed
+            for (generation, removed) in generations.iter().zip(&removed) {
+                if generation.active || generation.pinned {
+                    prop_assert!(!removed, "{generation:?} was removed");
+                }
+            }
+
+            // At least `keep` generations are kept, the newest ones
+            let kept = removed.iter().filter(|removed| !**removed).count();
+            prop_assert!(kept >= generations.len().min(keep as usize));
+            for removed in removed.iter().rev().take(keep as usize) {
+                prop_assert!(!removed);
+            }
+
+            // Keeping more never removes more
+            let removed_keeping_more = retention(&generations, keep + 1, keep_since, now);
+            for (removed, removed_keeping_more) in removed.iter().zip(&removed_keeping_more) {
+                prop_assert!(*removed || !removed_keeping_more);
+            }
+
+            // The order generations are listed in doesn't matter
+            let shuffled: Vec<Generation> = order
+                .iter()
+                .filter(|index| **index < generations.len())
+                .map(|index| generations[*index].clone())
+                .collect();
+            let removed_shuffled = retention(&shuffled, keep, keep_since, now);
+            for (generation, removed_shuffled) in shuffled.iter().zip(&removed_shuffled) {
+                prop_assert_eq!(*removed_shuffled, removed[generation.number as usize]);
+            }
+        }
+    }
+
+    #[test]
+    #[serial]
+    fn test_cleanable_generations_keeps_pinned() {
+        let state = tempfile::tempdir().unwrap();
+        let profiles = tempfile::tempdir().unwrap();
+        let _state = EnvGuard::new("NH_STATE_DIR", state.path());
+
+        let profile = profiles.path().join("system");
+        for number in 1..=4 {
+            std::os::unix::fs::symlink(
+                "/nix/store/aaa-nixos-system",
+                profiles.path().join(format!("system-{number}-link")),
+            )
+            .unwrap();
+        }
+        crate::generations::set_pinned(&profile, 2, true).unwrap();
+
+        // Pins are seen by other users, like root running `nh clean all`
+        let other_state = tempfile::tempdir().unwrap();
+        let _other_state = EnvGuard::new("NH_STATE_DIR", other_state.path());
+
+        let tagged = cleanable_generations(&profile, 1, "0s".parse().unwrap(), None).unwrap();
+        let removed: Vec<u32> = tagged
+            .iter()
+            .filter(|(_, tbr)| **tbr)
+            .map(|(generation, _)| generation.number)
+            .collect();
+
+        assert_eq!(removed, vec![1, 3]);
+    }
+
+    #[test]
+    #[serial]
+    fn test_cleanable_generations_keeps_active_root() {
+        let state = tempfile::tempdir().unwrap();
+        let profiles = tempfile::tempdir().unwrap();
+        let store = tempfile::tempdir().unwrap();
+        let _state = EnvGuard::new("NH_STATE_DIR", state.path());
+
+        let profile = profiles.path().join("system-manager");
+        for number in 1..=3 {
+            let closure = store.path().join(format!("{number}-system-manager"));
+            std::fs::create_dir(&closure).unwrap();
+            std::os::unix::fs::symlink(
+                &closure,
+                profiles
+                    .path()
+                    .join(format!("system-manager-{number}-link")),
+            )
+            .unwrap();
+        }
+
+        // system-manager still roots the closure of the first generation
+        let active = store
+            .path()
+            .join("1-system-manager")
+            .canonicalize()
+            .unwrap();
+        let tagged =
+            cleanable_generations(&profile, 1, "0s".parse().unwrap(), Some(&active)).unwrap();
+        let removed: Vec<u32> = tagged
+            .iter()
+            .filter(|(_, tbr)| **tbr)
+            .map(|(generation, _)| generation.number)
+            .collect();
+
+        assert_eq!(removed, vec![2]);
+    }
+
+    #[test]
+    fn test_keep_since() {
+        let day =
+            |days: u64| humantime::Duration::from(std::time::Duration::from_secs(days * 86400));
+
+        let keep_since = KeepSince::parse("system=30d, home=7d,result=1d").unwrap();
+        assert_eq!(keep_since.of(ProfileClass::System), day(30));
+        assert_eq!(keep_since.of(ProfileClass::Home), day(7));
+        assert_eq!(keep_since.of(ProfileClass::Result), day(1));
+        assert_eq!(keep_since.of(ProfileClass::User), day(0));
+
+        let keep_since = KeepSince::parse("2d,system=30d").unwrap();
+        assert_eq!(keep_since.of(ProfileClass::User), day(2));
+        assert_eq!(
+            keep_since.to_string(),
+            "30days for system, 2days for home, 2days for user, 2days for result"
+        );
+        assert_eq!(KeepSince::parse("7d").unwrap().to_string(), "7days");
+        assert_eq!(keep_since.to_arg(), "2days,system=30days");
+        assert_eq!(KeepSince::parse(&keep_since.to_arg()), Ok(keep_since));
+
+        assert!(KeepSince::parse("boot=1d").is_err());
+        assert!(KeepSince::parse("system=soon").is_err());
+
+        assert_eq!(
+            ProfileClass::of_profile(Path::new("/nix/var/nix/profiles/system")),
+            ProfileClass::System
+        );
+        assert_eq!(
+            ProfileClass::of_profile(Path::new("/home/me/.local/state/nix/profiles/home-manager")),
+            ProfileClass::Home
+        );
+        assert_eq!(
+            ProfileClass::of_profile(Path::new("/home/me/.local/state/nix/profiles/profile")),
+            ProfileClass::User
+        );
+    }
+}
@@ -1,29 +1,259 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+use std::sync::{LazyLock, Mutex};
 
 use color_eyre::{
     Result,
-    eyre::{Context, bail},
+    eyre::{Context, bail, eyre},
 };
-use owo_colors::OwoColorize;
-use subprocess::{Exec, ExitStatus, Redirection};
+use nix::pty::Winsize;
+use nix::sys::signal::{SigHandler, Signal, kill, signal};
+use nix::unistd::Pid;
+use regex::Regex;
+use subprocess::{CaptureData, Exec, ExitStatus, NullFile, Redirection};
 use thiserror::Error;
 use tracing::debug;
 
+use crate::build_log;
+use crate::builders::{self, RemoteBuilder};
+use crate::eval_trace;
+use crate::events;
+use crate::hints;
 use crate::installable::Installable;
-use crate::interface::NixBuildPassthroughArgs;
+use crate::interface::{NixBuildPassthroughArgs, NixEvalArgs};
+use crate::output;
+use crate::theme::{Role, paint};
+
+/// Arguments enabling the experimental features of the nix commands nh wraps
+/// directly, like `nix run` and `nix store`, for users who haven't
+pub const NIX_COMMAND: [&str; 2] = ["--extra-experimental-features", "nix-command flakes"];
+
+/// ssh options of particular hosts, like those of the deploy inventory, on
+/// top of `NIX_SSHOPTS`.
+static HOST_SSH_OPTIONS: Mutex<Option<HashMap<String, String>>> = Mutex::new(None);
+
+/// Pass the ssh `options` when connecting to `host` for the rest of the run,
+/// on top of `NIX_SSHOPTS`.
+pub fn set_host_ssh_options(host: &str, options: &str) {
+    if let Ok(mut host_options) = HOST_SSH_OPTIONS.lock() {
+        host_options
+            .get_or_insert_with(HashMap::new)
+            .insert(host.to_string(), options.to_string());
+    }
+}
+
+/// `NIX_SSHOPTS` for connecting to `host`: those of the environment and those
+/// set for the host. Nix commands that ssh to the host, like `nix copy`,
+/// need it in their environment.
+#[must_use]
+pub fn nix_sshopts(host: &str) -> String {
+    let base = std::env::var("NIX_SSHOPTS").unwrap_or_default();
+    let host_options = HOST_SSH_OPTIONS
+        .lock()
+        .ok()
+        .and_then(|options| options.as_ref()?.get(host).cloned())
+        .unwrap_or_default();
+    [base.trim(), host_options.trim()]
+        .into_iter()
+        .filter(|options| !options.is_empty())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// `ssh` to `host` with `NIX_SSHOPTS`, like nix runs it.
+pub fn ssh_cmd(host: &str) -> Exec {
+    ssh_cmd_with(host, &[])
+}
 
-fn ssh_wrap(cmd: Exec, ssh: Option<&str>) -> Exec {
+/// `ssh` to `host` with `NIX_SSHOPTS` and the additional ssh `options`.
+pub fn ssh_cmd_with(host: &str, options: &[&str]) -> Exec {
+    let sshopts = nix_sshopts(host);
+    Exec::cmd("ssh")
+        .args(&sshopts.split_whitespace().collect::<Vec<_>>())
+        .args(options)
+        .arg("-T")
+        .arg(host)
+}
+
+/// Run `cmd` on the host `ssh` instead, if given, passing `NIX_SSHOPTS` to
+/// ssh like nix does. The command line goes in the arguments of ssh, so
+/// that the remote command can be started without feeding it input.
+pub fn ssh_wrap(cmd: Exec, ssh: Option<&str>) -> Exec {
     if let Some(ssh) = ssh {
-        Exec::cmd("ssh")
-            .arg("-T")
-            .arg(ssh)
-            .stdin(cmd.to_cmdline_lossy().as_str())
+        ssh_cmd(ssh).arg(cmd.to_cmdline_lossy()).stdin(NullFile)
     } else {
         cmd
     }
 }
 
+/// Sudo passwords of remote hosts, or `None` for hosts where sudo doesn't
+/// need one.
+static REMOTE_SUDO_PASSWORDS: Mutex<Option<HashMap<String, Option<String>>>> = Mutex::new(None);
+
+/// The password sudo needs on `host`, asked for once per run.
+fn remote_sudo_password(host: &str, dry: bool) -> Result<Option<String>> {
+    let mut passwords = REMOTE_SUDO_PASSWORDS
+        .lock()
+        .map_err(|_| eyre!("Remote sudo password cache poisoned"))?;
+    let passwords = passwords.get_or_insert_with(HashMap::new);
+    if let Some(password) = passwords.get(host) {
+        return Ok(password.clone());
+    }
+    if dry {
+        return Ok(None);
+    }
+
+    let needs_password = !ssh_cmd(host)
+        .args(&["sudo", "-n", "true"])
+        .stdin(NullFile)
+        .stdout(NullFile)
+        .stderr(NullFile)
+        .join()
+        .is_ok_and(|status| status.success());
+
+    let password = if needs_password {
+        if !io::stdin().is_terminal() {
+            bail!(
+                "sudo on {host} needs a password. Run nh from a terminal, or set \
+                 NH_REMOTE_SUDO_ASKPASS to an askpass program on {host}"
+            );
+        }
+        Some(
+            dialoguer::Password::new()
+                .with_prompt(format!("[sudo] password on {host}"))
+                .interact()?,
+        )
+    } else {
+        None
+    };
+    passwords.insert(host.to_string(), password.clone());
+    Ok(password)
+}
+
+/// Ask for the sudo passwords of `hosts` now, one after another, instead of
+/// when commands are first run there, e.g. by hosts deployed at the same
+/// time whose output would interleave with the prompts.
+pub fn ask_remote_sudo_passwords<'a>(
+    hosts: impl IntoIterator<Item = &'a str>,
+    dry: bool,
+) -> Result<()> {
+    if std::env::var_os("NH_REMOTE_SUDO_ASKPASS").is_some() {
+        return Ok(());
+    }
+    for host in hosts {
+        remote_sudo_password(host, dry)?;
+    }
+    Ok(())
+}
+
+/// Environment variables required for Nix and NH operations
+const REQUIRED_ENV: &[&str] = &[
+    // This is not a part of Nix's environment, but it might be necessary.
+    // nixos-rebuild preserves it, so we do too.
+    "LOCALE_ARCHIVE",
+    // PATH needs to be preserved so that NH can invoke CLI utilities.
+    "PATH",
+    // Make sure NIX_SSHOPTS applies to nix commands that invoke ssh, such as `nix copy`
+    "NIX_SSHOPTS",
+    // This is relevant for Home-Manager systems
+    "HOME_MANAGER_BACKUP_EXT",
+    // Preserve other Nix-related environment variables
+    // TODO: is this everything we need? Previously we only preserved *some* variables
+    // and nh continued to work, but any missing vars might break functionality completely
+    // unexpectedly. This list could change at any moment. This better be enough. Ugh.
+    "NIX_CONFIG",
+    "NIX_PATH",
+    "NIX_REMOTE",
+    "NIX_SSL_CERT_FILE",
+    "NIX_USER_CONF_FILES",
+];
+
+/// Variables kept in clean-environment mode on top of [`REQUIRED_ENV`] and
+/// `NH_*`, as commands can't reasonably be expected to work without them.
+const CLEAN_ENV_BASE: &[&str] = &[
+    "HOME",
+    "USER",
+    "TERM",
+    "LANG",
+    "LC_ALL",
+    "TZ",
+    // Needed by `nix copy` and remote builders
+    "SSH_AUTH_SOCK",
+    // Needed by graphical askpass programs, see `checks::setup_environment`
+    "SSH_ASKPASS",
+    "SSH_ASKPASS_REQUIRE",
+    "DISPLAY",
+    "WAYLAND_DISPLAY",
+];
+
+/// Whether child commands run with a cleared environment, see [`set_clean_env`].
+static CLEAN_ENV: AtomicBool = AtomicBool::new(false);
+
+/// Run child commands with a cleared environment plus an explicit allowlist,
+/// instead of inheriting the environment of nh.
+///
+/// The allowlist is made up of [`CLEAN_ENV_BASE`], [`REQUIRED_ENV`], `NH_*`
+/// variables and whatever [`EnvAction`]s a command configures.
+pub fn set_clean_env(enabled: bool) {
+    CLEAN_ENV.store(enabled, Ordering::Relaxed);
+}
+
+fn clean_env_enabled() -> bool {
+    CLEAN_ENV.load(Ordering::Relaxed)
+}
+
+/// Resolve the variables passed to a child in clean-environment mode.
+fn clean_env_allowlist(env_vars: &HashMap<String, EnvAction>) -> Vec<(String, String)> {
+    let mut actions: HashMap<String, EnvAction> = CLEAN_ENV_BASE
+        .iter()
+        .chain(REQUIRED_ENV)
+        .map(|key| ((*key).to_string(), EnvAction::Preserve))
+        .chain(
+            std::env::vars()
+                .filter(|(key, _)| key.starts_with("NH_"))
+                .map(|(key, _)| (key, EnvAction::Preserve)),
+        )
+        .collect();
+
+    for (key, action) in env_vars {
+        actions.insert(key.clone(), action.clone());
+    }
+
+    let mut resolved = actions
+        .into_iter()
+        .filter_map(|(key, action)| match action {
+            EnvAction::Set(value) => Some((key, value)),
+            EnvAction::Preserve => std::env::var(&key).ok().map(|value| (key, value)),
+            EnvAction::Remove => None,
+        })
+        .collect::<Vec<_>>();
+    resolved.sort();
+    resolved
+}
+
+/// Clear the environment of `cmd`, keeping only the allowlisted variables.
+fn apply_clean_env(mut cmd: Exec, env_vars: &HashMap<String, EnvAction>) -> Exec {
+    let allowlist = clean_env_allowlist(env_vars);
+    debug!(
+        "Clean environment: {}",
+        allowlist
+            .iter()
+            .map(|(key, _)| key.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    );
+
+    cmd = cmd.env_clear();
+    for (key, value) in allowlist {
+        cmd = cmd.env(key, value);
+    }
+    cmd
+}
+
 #[allow(dead_code)] // shut up
 #[derive(Debug, Clone)]
 pub enum EnvAction {
@@ -37,6 +267,14 @@ pub enum EnvAction {
     Remove,
 }
 
+/// Arguments and environment for a `sudo` process, excluding the elevated
+/// program and its arguments.
+#[derive(Debug)]
+struct SudoInvocation {
+    args: Vec<OsString>,
+    env: Vec<(String, String)>,
+}
+
 #[derive(Debug)]
 pub struct Command {
     dry: bool,
@@ -46,6 +284,8 @@ pub struct Command {
     elevate: bool,
     ssh: Option<String>,
     show_output: bool,
+    progress: bool,
+    merge_stderr: bool,
     env_vars: HashMap<String, EnvAction>,
 }
 
@@ -59,6 +299,8 @@ impl Command {
             elevate: false,
             ssh: None,
             show_output: false,
+            progress: false,
+            merge_stderr: false,
             env_vars: HashMap::new(),
         }
     }
@@ -81,6 +323,22 @@ impl Command {
         self
     }
 
+    /// Show a progress bar instead of the log of a nix command like
+    /// `nix copy` or `nix store gc`, when stderr is a terminal.
+    #[must_use]
+    pub fn progress(mut self, progress: bool) -> Self {
+        self.progress = progress;
+        self
+    }
+
+    /// Capture stderr along with stdout in [`Command::run_capture`], for
+    /// commands that report on stderr.
+    #[must_use]
+    pub fn merge_stderr(mut self, merge_stderr: bool) -> Self {
+        self.merge_stderr = merge_stderr;
+        self
+    }
+
     #[must_use]
     pub fn ssh(mut self, ssh: Option<String>) -> Self {
         self.ssh = ssh;
@@ -108,6 +366,16 @@ impl Command {
         self
     }
 
+    /// Set an environment variable for the command
+    #[must_use]
+    pub fn env<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Self {
+        self.env_vars.insert(
+            key.as_ref().to_string(),
+            EnvAction::Set(value.as_ref().to_string()),
+        );
+        self
+    }
+
     /// Preserve multiple environment variables from the current environment
     pub fn preserve_envs<I, K>(mut self, keys: I) -> Self
     where
@@ -124,31 +392,10 @@ impl Command {
     /// Configure environment for Nix and NH operations
     #[must_use]
     pub fn with_required_env(mut self) -> Self {
-        const ENV: &[&str] = &[
-            // This is not a part of Nix's environment, but it might be necessary.
-            // nixos-rebuild preserves it, so we do too.
-            "LOCALE_ARCHIVE",
-            // PATH needs to be preserved so that NH can invoke CLI utilities.
-            "PATH",
-            // Make sure NIX_SSHOPTS applies to nix commands that invoke ssh, such as `nix copy`
-            "NIX_SSHOPTS",
-            // This is relevant for Home-Manager systems
-            "HOME_MANAGER_BACKUP_EXT",
-            // Preserve other Nix-related environment variables
-            // TODO: is this everything we need? Previously we only preserved *some* variables
-            // and nh continued to work, but any missing vars might break functionality completely
-            // unexpectedly. This list could change at any moment. This better be enough. Ugh.
-            "NIX_CONFIG",
-            "NIX_PATH",
-            "NIX_REMOTE",
-            "NIX_SSL_CERT_FILE",
-            "NIX_USER_CONF_FILES",
-        ];
-
         let env_vars = std::env::vars()
             .filter_map(|(key, value)| match key.as_str() {
                 "USER" => Some((key, EnvAction::Set(value))),
-                k if ENV.contains(&k) => Some((key, EnvAction::Preserve)),
+                k if REQUIRED_ENV.contains(&k) => Some((key, EnvAction::Preserve)),
                 k if k.starts_with("NH_") => Some((key, EnvAction::Set(value))),
                 _ => None,
             })
@@ -183,6 +430,10 @@ impl Command {
     }
 
     fn apply_env_to_exec(&self, mut cmd: Exec) -> Exec {
+        if clean_env_enabled() {
+            return apply_clean_env(cmd, &self.env_vars);
+        }
+
         for (key, action) in &self.env_vars {
             match action {
                 EnvAction::Set(value) => {
@@ -202,8 +453,12 @@ impl Command {
         cmd
     }
 
-    fn build_sudo_cmd(&self) -> Exec {
-        let mut cmd = Exec::cmd("sudo");
+    /// Build the argument vector and environment for a `sudo` invocation,
+    /// not including the elevated program itself. A `remote` sudo can't use
+    /// the local askpass program.
+    fn sudo_invocation(&self, remote: bool) -> SudoInvocation {
+        let mut args: Vec<OsString> = Vec::new();
+        let mut env = Vec::new();
 
         // Collect variables to preserve for sudo
         let mut preserve_vars = Vec::new();
@@ -233,62 +488,141 @@ impl Command {
                 .map(|output| output.stdout_str().contains("--preserve-env"))
                 .unwrap_or(false);
 
+            args.push("--set-home".into());
             if has_preserve_env && !preserve_vars.is_empty() {
-                cmd = cmd.args(&[
-                    "--set-home",
-                    &format!("--preserve-env={}", preserve_vars.join(",")),
-                ]);
-            } else {
-                cmd = cmd.arg("--set-home");
+                args.push(format!("--preserve-env={}", preserve_vars.join(",")).into());
             }
         } else {
             // On Linux, use specific environment preservation
             if !preserve_vars.is_empty() {
-                cmd = cmd.arg(format!("--preserve-env={}", preserve_vars.join(",")));
+                args.push(format!("--preserve-env={}", preserve_vars.join(",")).into());
             }
         }
 
         // Use NH_SUDO_ASKPASS program for sudo if present
-        if let Ok(askpass) = std::env::var("NH_SUDO_ASKPASS") {
-            cmd = cmd.env("SUDO_ASKPASS", askpass).arg("-A");
+        if let Some(askpass) = std::env::var("NH_SUDO_ASKPASS").ok().filter(|_| !remote) {
+            env.push(("SUDO_ASKPASS".to_string(), askpass));
+            args.push("-A".into());
         }
 
         // Insert 'env' command to explicitly pass environment variables to the elevated command
         if !explicit_env_vars.is_empty() {
-            cmd = cmd.arg("env");
+            args.push("env".into());
             for (key, value) in explicit_env_vars {
-                cmd = cmd.arg(format!("{key}={value}"));
+                args.push(format!("{key}={value}").into());
             }
         }
 
+        SudoInvocation { args, env }
+    }
+
+    fn build_sudo_cmd(&self) -> Exec {
+        let SudoInvocation { args, env } = self.sudo_invocation(false);
+
+        let mut cmd = Exec::cmd("sudo").args(&args);
+        if clean_env_enabled() {
+            cmd = apply_clean_env(cmd, &self.env_vars);
+        }
+        for (key, value) in env {
+            cmd = cmd.env(key, value);
+        }
+
         cmd
     }
 
-    /// Create a sudo command for self-elevation with proper environment handling
+    /// The sudo invocation of the command on a remote host, using the
+    /// askpass program `askpass` of that host or reading the password from
+    /// stdin with `password`.
+    fn remote_sudo_script(&self, askpass: Option<&str>, password: bool) -> Exec {
+        let SudoInvocation { args, .. } = self.sudo_invocation(true);
+        let sudo = match askpass {
+            Some(askpass) => Exec::cmd("env")
+                .arg(format!("SUDO_ASKPASS={askpass}"))
+                .args(&["sudo", "-A"]),
+            None if password => Exec::cmd("sudo").args(&["-S", "--prompt="]),
+            None => Exec::cmd("sudo"),
+        };
+        sudo.args(&args).arg(&self.command).args(&self.args)
+    }
+
+    /// The command run with sudo on the host `ssh`, and the input to feed
+    /// it. The sudo there uses the askpass program `NH_REMOTE_SUDO_ASKPASS`
+    /// of the target if set, or else reads the password from stdin if it
+    /// needs one.
+    fn remote_sudo_cmd(&self, ssh: &str) -> Result<(Exec, Option<String>)> {
+        if let Ok(askpass) = std::env::var("NH_REMOTE_SUDO_ASKPASS") {
+            return Ok((
+                ssh_wrap(self.remote_sudo_script(Some(&askpass), false), Some(ssh)),
+                None,
+            ));
+        }
+
+        Ok(match remote_sudo_password(ssh, self.dry)? {
+            Some(password) => (
+                ssh_cmd(ssh)
+                    .arg(self.remote_sudo_script(None, true).to_cmdline_lossy())
+                    .stdin(Redirection::Pipe),
+                Some(format!("{password}\n")),
+            ),
+            None => (
+                ssh_wrap(self.remote_sudo_script(None, false), Some(ssh)),
+                None,
+            ),
+        })
+    }
+
+    /// Create a sudo command for self-elevation with proper environment
+    /// handling, setting the variables of `env` on top
     #[must_use]
-    pub fn self_elevate_cmd() -> std::process::Command {
+    pub fn self_elevate_cmd(env: &[(&str, String)]) -> std::process::Command {
         // Get the current executable path
         let current_exe = std::env::current_exe().expect("Failed to get current executable path");
 
-        // Self-elevation with proper environment handling
-        let cmd_builder = Self::new(&current_exe).elevate(true).with_required_env();
-
-        let sudo_exec = cmd_builder.build_sudo_cmd();
-
-        // Add the target executable and arguments to the sudo command
-        let exec_with_args = sudo_exec.arg(&current_exe);
-        let args: Vec<String> = std::env::args().skip(1).collect();
-        let final_exec = exec_with_args.args(&args);
+        Self::elevated_std_cmd(current_exe.as_os_str(), std::env::args_os().skip(1), env)
+    }
 
-        // Convert Exec to std::process::Command by parsing the command line
-        let cmdline = final_exec.to_cmdline_lossy();
-        let parts: Vec<&str> = cmdline.split_whitespace().collect();
+    /// Construct a `std::process::Command` running `program` with `args` under
+    /// sudo. Arguments are passed through as-is, without going through a
+    /// shell-like command line, so spaces and quotes survive intact.
+    fn elevated_std_cmd<I>(
+        program: &OsStr,
+        args: I,
+        env: &[(&str, String)],
+    ) -> std::process::Command
+    where
+        I: IntoIterator,
+        I::Item: AsRef<OsStr>,
+    {
+        let mut cmd_builder = Self::new(program).elevate(true).with_required_env();
+        for (key, value) in env {
+            cmd_builder = cmd_builder.env(key, value);
+        }
 
-        assert!(!parts.is_empty(), "Failed to build sudo command");
+        // Keep using the invoking user's state (e.g. generation labels)
+        // rather than root's
+        if !cmd_builder.env_vars.contains_key("NH_STATE_DIR") {
+            if let Ok(dir) = crate::state::state_dir() {
+                cmd_builder.env_vars.insert(
+                    "NH_STATE_DIR".to_string(),
+                    EnvAction::Set(dir.to_string_lossy().into_owned()),
+                );
+            }
+        }
 
-        let mut std_cmd = std::process::Command::new(parts[0]);
-        if parts.len() > 1 {
-            std_cmd.args(&parts[1..]);
+        let SudoInvocation {
+            args: sudo_args,
+            env,
+        } = cmd_builder.sudo_invocation(false);
+
+        let mut std_cmd = std::process::Command::new("sudo");
+        std_cmd.args(sudo_args).arg(program).args(args);
+        if clean_env_enabled() {
+            std_cmd
+                .env_clear()
+                .envs(clean_env_allowlist(&cmd_builder.env_vars));
+        }
+        for (key, value) in env {
+            std_cmd.env(key, value);
         }
 
         std_cmd
@@ -300,19 +634,32 @@ impl Command {
         } else {
             self.apply_env_to_exec(Exec::cmd(&self.command).args(&self.args))
         };
+        let remote_sudo = match &self.ssh {
+            Some(ssh) if self.elevate => Some(self.remote_sudo_cmd(ssh)?),
+            _ => None,
+        };
 
-        // Configure output redirection based on show_output setting
-        let cmd = ssh_wrap(
-            if self.show_output {
-                cmd.stderr(Redirection::Merge)
-            } else {
-                cmd.stderr(Redirection::None).stdout(Redirection::None)
-            },
-            self.ssh.as_deref(),
-        );
+        let draw = io::stderr().is_terminal();
+        if self.progress && self.ssh.is_none() && output::human() && (draw || events::enabled()) {
+            return self.run_with_progress(cmd, draw);
+        }
 
-        if let Some(m) = &self.message {
-            println!("{} {m}", ">".green());
+        // Configure output redirection based on show_output setting. Commands
+        // that don't show their output still get their stderr forwarded to the
+        // terminal, unless in quiet mode, but it is also buffered so that it
+        // can be attached to the error if the command fails. Output is never
+        // shown when it would end up among the final result on stdout.
+        let show_output = self.show_output && output::human();
+        let (cmd, input) =
+            remote_sudo.unwrap_or_else(|| (ssh_wrap(cmd, self.ssh.as_deref()), None));
+        let cmd = if show_output {
+            cmd.stderr(Redirection::Merge)
+        } else {
+            cmd.stderr(Redirection::Pipe).stdout(Redirection::Pipe)
+        };
+
+        if let Some(m) = self.message.as_ref().filter(|_| output::human()) {
+            println!("{} {m}", paint(">", Role::Info));
         }
 
         debug!(?cmd);
@@ -325,45 +672,262 @@ impl Command {
             .message
             .clone()
             .unwrap_or_else(|| "Command failed".to_string());
-        let res = cmd.capture();
-        if let Err(e) = res {
-            return Err(e).wrap_err(msg);
-        }
 
-        let status = &res.as_ref().unwrap().exit_status;
+        let res = if show_output {
+            match &input {
+                Some(input) => cmd.stdin(input.as_str()),
+                None => cmd,
+            }
+            .capture()
+            .map(|data| (data.exit_status, String::new()))
+            .map_err(color_eyre::Report::from)
+        } else {
+            run_with_stderr_tail(cmd, input.as_deref(), !output::quiet())
+        };
+
+        let (status, stderr) = match res {
+            Ok(res) => res,
+            Err(e) => return Err(e).wrap_err(msg),
+        };
+
         if !status.success() {
-            let stderr = res
-                .as_ref()
-                .map(subprocess::CaptureData::stderr_str)
-                .unwrap_or_default();
+            let report = color_eyre::Report::new(ExitError(status));
             if stderr.trim().is_empty() {
+                return Err(report.wrap_err(format!("{msg} (exit status {status:?})")));
+            }
+            return Err(report.wrap_err(format!(
+                "{msg} (exit status {status:?})\nstderr:\n{}",
+                eval_trace::fold(&stderr)
+            )));
+        }
+
+        Ok(())
+    }
+
+    fn run_with_progress(&self, cmd: Exec, draw: bool) -> Result<()> {
+        if let Some(m) = &self.message {
+            println!("{} {m}", paint(">", Role::Info));
+        }
+
+        debug!(?cmd);
+
+        if self.dry {
+            return Ok(());
+        }
+
+        let msg = self.message.as_deref().unwrap_or("Command failed");
+        let (status, messages) = crate::progress::run(cmd, draw).wrap_err(msg.to_string())?;
+
+        if !status.success() {
+            if messages.trim().is_empty() {
                 bail!("{} (exit status {:?})", msg, status);
             }
-            bail!("{} (exit status {:?})\nstderr:\n{}", msg, status, stderr);
+            bail!(
+                "{} (exit status {:?})\nstderr:\n{}",
+                msg,
+                status,
+                eval_trace::fold(&messages)
+            );
         }
 
         Ok(())
     }
 
-    pub fn run_capture(&self) -> Result<Option<String>> {
-        let cmd = self.apply_env_to_exec(
-            Exec::cmd(&self.command)
-                .args(&self.args)
-                .stderr(Redirection::None)
-                .stdout(Redirection::Pipe),
-        );
+    /// Replace nh with the command, so that it gets the terminal, signals
+    /// and exit code to itself. Only returns if starting the command failed.
+    pub fn exec(&self) -> Result<()> {
+        use std::os::unix::process::CommandExt;
 
-        if let Some(m) = &self.message {
-            println!("{} {m}", ">".green());
+        let mut cmd = std::process::Command::new(&self.command);
+        cmd.args(&self.args);
+        if clean_env_enabled() {
+            cmd.env_clear().envs(clean_env_allowlist(&self.env_vars));
+        } else {
+            for (key, action) in &self.env_vars {
+                match action {
+                    EnvAction::Set(value) => {
+                        cmd.env(key, value);
+                    }
+                    EnvAction::Preserve => {}
+                    EnvAction::Remove => {
+                        cmd.env_remove(key);
+                    }
+                }
+            }
+        }
+
+        if let Some(m) = self.message.as_ref().filter(|_| output::human()) {
+            println!("{} {m}", paint(">", Role::Info));
         }
 
         debug!(?cmd);
 
+        if self.dry {
+            return Ok(());
+        }
+
+        Err(cmd.exec()).wrap_err(format!("Failed to run {}", self.command.to_string_lossy()))
+    }
+
+    /// The command as run by the capturing methods, with elevation and ssh
+    /// applied.
+    fn capture_cmd(&self) -> Result<Exec> {
+        let cmd = match &self.ssh {
+            Some(ssh) if self.elevate => match self.remote_sudo_cmd(ssh)? {
+                (cmd, Some(input)) => cmd.stdin(input.as_str()),
+                (cmd, None) => cmd,
+            },
+            ssh => ssh_wrap(
+                if self.elevate {
+                    self.build_sudo_cmd().arg(&self.command).args(&self.args)
+                } else {
+                    self.apply_env_to_exec(Exec::cmd(&self.command).args(&self.args))
+                },
+                ssh.as_deref(),
+            ),
+        };
+
+        if let Some(m) = self.message.as_ref().filter(|_| output::human()) {
+            println!("{} {m}", paint(">", Role::Info));
+        }
+
+        debug!(?cmd);
+        Ok(cmd)
+    }
+
+    pub fn run_capture(&self) -> Result<Option<String>> {
+        let cmd = self
+            .capture_cmd()?
+            .stderr(if self.merge_stderr {
+                Redirection::Merge
+            } else {
+                Redirection::None
+            })
+            .stdout(Redirection::Pipe);
+
         if self.dry {
             return Ok(None);
         }
         Ok(Some(cmd.capture()?.stdout_str()))
     }
+
+    /// Like [`Command::run_capture`], but also captures stderr and leaves
+    /// checking the exit status to the caller.
+    pub fn run_capture_all(&self) -> Result<Option<CaptureData>> {
+        let cmd = self
+            .capture_cmd()?
+            .stderr(Redirection::Pipe)
+            .stdout(Redirection::Pipe);
+
+        if self.dry {
+            return Ok(None);
+        }
+        Ok(Some(cmd.capture()?))
+    }
+}
+
+/// Maximum amount of stderr output kept around for error reports.
+const STDERR_TAIL_BYTES: usize = 16 * 1024;
+
+/// A byte buffer that only keeps the most recently written `cap` bytes.
+#[derive(Debug)]
+struct RingBuffer {
+    buf: VecDeque<u8>,
+    cap: usize,
+    truncated: bool,
+}
+
+impl RingBuffer {
+    fn new(cap: usize) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(cap),
+            cap,
+            truncated: false,
+        }
+    }
+
+    fn extend(&mut self, data: &[u8]) {
+        // Only the tail of a chunk larger than the buffer can survive
+        let data = if data.len() > self.cap {
+            self.truncated = true;
+            &data[data.len() - self.cap..]
+        } else {
+            data
+        };
+
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.cap);
+        if overflow > 0 {
+            self.truncated = true;
+            self.buf.drain(..overflow);
+        }
+        self.buf.extend(data);
+    }
+
+    /// Convert the buffered bytes into a string. If older output was
+    /// discarded, the (likely partial) first line is dropped as well.
+    fn into_string(self) -> String {
+        let bytes: Vec<u8> = self.buf.into();
+        let text = String::from_utf8_lossy(&bytes);
+
+        if self.truncated {
+            match text.split_once('\n') {
+                Some((_, rest)) => format!("[...]\n{rest}"),
+                None => format!("[...]{text}"),
+            }
+        } else {
+            text.into_owned()
+        }
+    }
+}
+
+/// Run a command whose stdout and stderr are piped, forwarding stderr to our
+/// own stderr while keeping its tail for error reporting. Stdout is discarded.
+/// `input` is written to the stdin of the command, which must be piped then.
+fn run_with_stderr_tail(
+    cmd: Exec,
+    input: Option<&str>,
+    forward: bool,
+) -> Result<(ExitStatus, String)> {
+    let mut process = cmd.popen()?;
+    if let (Some(input), Some(mut stdin)) = (input, process.stdin.take()) {
+        // Closed right away, so that the command sees the end of its input
+        let _ = stdin.write_all(input.as_bytes());
+    }
+
+    let stdout_thread = process.stdout.take().map(|mut stdout| {
+        std::thread::spawn(move || {
+            let _ = io::copy(&mut stdout, &mut io::sink());
+        })
+    });
+
+    let mut tail = RingBuffer::new(STDERR_TAIL_BYTES);
+    if let Some(mut stderr) = process.stderr.take() {
+        let mut terminal = io::stderr();
+        let mut chunk = [0u8; 8192];
+        loop {
+            match stderr.read(&mut chunk) {
+                Ok(0) => break,
+                Ok(n) => {
+                    if forward {
+                        let _ = terminal.write_all(&chunk[..n]);
+                    }
+                    tail.extend(&chunk[..n]);
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => {
+                    debug!(?err, "Failed to read command stderr");
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Some(thread) = stdout_thread {
+        let _ = thread.join();
+    }
+
+    let status = process.wait()?;
+    Ok((status, tail.into_string()))
 }
 
 #[derive(Debug)]
@@ -372,7 +936,7 @@ pub struct Build {
     installable: Installable,
     extra_args: Vec<OsString>,
     nom: bool,
-    builder: Option<String>,
+    builders: Vec<RemoteBuilder>,
 }
 
 impl Build {
@@ -383,7 +947,7 @@ impl Build {
             installable,
             extra_args: vec![],
             nom: false,
-            builder: None,
+            builders: vec![],
         }
     }
 
@@ -403,9 +967,17 @@ impl Build {
         self
     }
 
+    /// Build on `host` over ssh, used for `--build-host`.
     #[must_use]
-    pub fn builder(mut self, builder: Option<String>) -> Self {
-        self.builder = builder;
+    pub fn builder(mut self, host: Option<String>) -> Self {
+        self.builders
+            .extend(host.as_deref().map(RemoteBuilder::host));
+        self
+    }
+
+    #[must_use]
+    pub fn builders(mut self, builders: &[RemoteBuilder]) -> Self {
+        self.builders.extend_from_slice(builders);
         self
     }
 
@@ -425,9 +997,14 @@ impl Build {
         self.extra_args(passthrough.generate_passthrough_args())
     }
 
+    #[must_use]
+    pub fn eval_args(self, eval: &NixEvalArgs) -> Self {
+        self.extra_args(eval.generate_eval_args())
+    }
+
     pub fn run(&self) -> Result<()> {
-        if let Some(m) = &self.message {
-            println!("{} {m}", ">".green());
+        if let Some(m) = self.message.as_ref().filter(|_| output::human()) {
+            println!("{} {m}", paint(">", Role::Info));
         }
 
         let installable_args = self.installable.to_args();
@@ -435,35 +1012,95 @@ impl Build {
         let base_command = Exec::cmd("nix")
             .arg("build")
             .args(&installable_args)
-            .args(&match &self.builder {
-                Some(host) => {
-                    vec!["--builders".to_string(), format!("ssh://{host} - - - 100")]
-                }
-                None => vec![],
+            .args(&if self.builders.is_empty() {
+                vec![]
+            } else {
+                vec![
+                    "--builders".to_string(),
+                    builders::machines_spec(&self.builders),
+                ]
             })
             .args(&self.extra_args);
-
-        let exit = if self.nom {
-            let cmd = {
-                base_command
-                    .args(&["--log-format", "internal-json", "--verbose"])
-                    .stderr(Redirection::Merge)
-                    .stdout(Redirection::Pipe)
-                    | Exec::cmd("nom").args(&["--json"])
-            }
-            .stdout(Redirection::None);
-            debug!(?cmd);
-            cmd.join()
+        let base_command = if clean_env_enabled() {
+            apply_clean_env(base_command, &HashMap::new())
         } else {
-            let cmd = base_command
-                .stderr(Redirection::Merge)
-                .stdout(Redirection::None);
+            base_command
+        };
 
+        // Build logs would end up among the final result on stdout
+        if !output::human() {
+            let cmd = base_command
+                .stderr(Redirection::Pipe)
+                .stdout(Redirection::Pipe);
             debug!(?cmd);
-            cmd.join()
+
+            let (status, stderr) = run_with_stderr_tail(cmd, None, !output::quiet())?;
+            if !status.success() {
+                build_log::save_failure(
+                    &stderr
+                        .lines()
+                        .filter_map(build_log::failed_drv)
+                        .collect::<Vec<_>>(),
+                );
+                bail!(
+                    "{} (exit status {status:?})\nstderr:\n{}",
+                    self.message.as_deref().unwrap_or("Build failed"),
+                    eval_trace::fold(&stderr)
+                );
+            }
+            return Ok(());
+        }
+
+        // Nix's structured log says which builder built what, which
+        // derivation failed, and how far the build got
+        if self.nom || !self.builders.is_empty() || events::enabled() {
+            let (status, tracked) = builders::run_tracked(base_command, self.nom)?;
+            print!("{}", builders::format_report(&tracked.remote));
+            if !status.success() {
+                build_log::save_failure(&tracked.failed);
+                bail!(ExitError(status));
+            }
+            return Ok(());
+        }
+
+        // Nix only draws its progress bar on a terminal, so on one nix gets
+        // a pseudoterminal of the terminal's size, whose output is passed
+        // through with the bar
+        let stderr = if io::stderr().is_terminal() {
+            let pty = nix::pty::openpty(terminal_size().as_ref(), None)
+                .wrap_err("Failed to open a pseudoterminal")?;
+            (
+                Redirection::File(File::from(pty.slave)),
+                Some(File::from(pty.master)),
+            )
+        } else {
+            (Redirection::Pipe, None)
+        };
+        let cmd = base_command.stderr(stderr.0).stdout(Redirection::None);
+        debug!(?cmd);
+        let mut process = cmd.popen()?;
+        let forwarding = match (&stderr.1, process.pid()) {
+            (Some(master), Some(pid)) => {
+                forward_resizes(master.as_raw_fd(), pid)?;
+                true
+            }
+            _ => false,
+        };
+        // Dropping the command closed our end of the pseudoterminal's slave,
+        // so reading the master fails once nix exits
+        let failed = match stderr.1.or_else(|| process.stderr.take()) {
+            Some(log) => scan_build_log(log),
+            None => Vec::new(),
         };
+        let status = process.wait()?;
+        if forwarding {
+            stop_forwarding_resizes()?;
+        }
+        if !status.success() {
+            build_log::save_failure(&failed);
+        }
 
-        match exit? {
+        match status {
             ExitStatus::Exited(0) => (),
             other => bail!(ExitError(other)),
         }
@@ -472,48 +1109,120 @@ impl Build {
     }
 }
 
+nix::ioctl_read_bad!(get_window_size, nix::libc::TIOCGWINSZ, Winsize);
+nix::ioctl_write_ptr_bad!(set_window_size, nix::libc::TIOCSWINSZ, Winsize);
+
+/// The master of the pseudoterminal a build runs on, -1 without one.
+static PTY_MASTER: AtomicI32 = AtomicI32::new(-1);
+/// The pid of the nix running on [`PTY_MASTER`].
+static PTY_CHILD: AtomicI32 = AtomicI32::new(0);
+
+/// The size of the terminal on stderr, if it is one.
+fn terminal_size() -> Option<Winsize> {
+    let mut size = Winsize {
+        ws_row: 0,
+        ws_col: 0,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    };
+    // SAFETY: TIOCGWINSZ only writes a winsize to the valid pointer
+    unsafe { get_window_size(nix::libc::STDERR_FILENO, &mut size) }.ok()?;
+    Some(size)
+}
+
+/// Resize the build's pseudoterminal to the terminal's new size. Nix has no
+/// controlling terminal there, so it is told about the change directly.
+extern "C" fn resized(_: nix::libc::c_int) {
+    let master = PTY_MASTER.load(Ordering::Relaxed);
+    if master < 0 {
+        return;
+    }
+    if let Some(size) = terminal_size() {
+        // SAFETY: TIOCSWINSZ only reads the winsize, and both ioctl and
+        // kill are async-signal-safe
+        let _ = unsafe { set_window_size(master, &size) };
+    }
+    let _ = kill(
+        Pid::from_raw(PTY_CHILD.load(Ordering::Relaxed)),
+        Signal::SIGWINCH,
+    );
+}
+
+/// Keep the pseudoterminal `master` of the build `pid` as large as the
+/// terminal, until [`stop_forwarding_resizes`].
+fn forward_resizes(master: RawFd, pid: u32) -> Result<()> {
+    PTY_CHILD.store(i32::try_from(pid)?, Ordering::Relaxed);
+    PTY_MASTER.store(master, Ordering::Relaxed);
+    // SAFETY: the handler only does async-signal-safe ioctls and kill
+    unsafe { signal(Signal::SIGWINCH, SigHandler::Handler(resized)) }?;
+    Ok(())
+}
+
+fn stop_forwarding_resizes() -> Result<()> {
+    // SAFETY: restoring the default disposition
+    unsafe { signal(Signal::SIGWINCH, SigHandler::SigDfl) }?;
+    PTY_MASTER.store(-1, Ordering::Relaxed);
+    Ok(())
+}
+
+/// Pass the log of a build through to stderr as it comes, and scan its lines
+/// for hints and failed derivations, which are returned.
+fn scan_build_log(mut log: impl Read) -> Vec<String> {
+    let mut failed = Vec::new();
+    let mut terminal = io::stderr();
+    let mut pending = Vec::new();
+    let mut chunk = [0u8; 8192];
+    loop {
+        let n = match log.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            // What a pseudoterminal's master reads once the slave is closed
+            Err(_) => break,
+        };
+        let _ = terminal.write_all(&chunk[..n]);
+        pending.extend_from_slice(&chunk[..n]);
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line: Vec<u8> = pending.drain(..=end).collect();
+            let line = log_text(&String::from_utf8_lossy(&line));
+            hints::observe(&line);
+            failed.extend(build_log::failed_drv(&line));
+        }
+    }
+    failed
+}
+
+static ESCAPE_SEQUENCE: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"\x1b\[[0-?]*[ -/]*[@-~]").unwrap());
+
+/// The text of a line written to a terminal, after the progress bar that
+/// was drawn over with a carriage return, without colors.
+fn log_text(line: &str) -> String {
+    let line = line.trim_end_matches(['\r', '\n']);
+    let line = line.rsplit('\r').next().unwrap_or_default();
+    ESCAPE_SEQUENCE.replace_all(line, "").into_owned()
+}
+
 #[derive(Debug, Error)]
 #[error("Command exited with status {0:?}")]
 pub struct ExitError(ExitStatus);
 
+impl ExitError {
+    /// The exit status of the command.
+    #[must_use]
+    pub const fn status(&self) -> ExitStatus {
+        self.0
+    }
+}
+
 #[cfg(test)]
 mod tests {
-    use std::env;
     use std::ffi::OsString;
 
     use serial_test::serial;
 
     use super::*;
-
-    // Safely manage environment variables in tests
-    struct EnvGuard {
-        key: String,
-        original: Option<String>,
-    }
-
-    impl EnvGuard {
-        fn new(key: &str, value: &str) -> Self {
-            let original = env::var(key).ok();
-            unsafe {
-                env::set_var(key, value);
-            }
-            EnvGuard {
-                key: key.to_string(),
-                original,
-            }
-        }
-    }
-
-    impl Drop for EnvGuard {
-        fn drop(&mut self) {
-            unsafe {
-                match &self.original {
-                    Some(val) => env::set_var(&self.key, val),
-                    None => env::remove_var(&self.key),
-                }
-            }
-        }
-    }
+    use crate::test_util::EnvGuard;
 
     #[test]
     fn test_env_action_variants() {
@@ -626,10 +1335,8 @@ mod tests {
     #[serial]
     fn test_with_required_env_missing_home_user() {
         // Test behavior when HOME/USER are not set
-        unsafe {
-            env::remove_var("HOME");
-            env::remove_var("USER");
-        }
+        let _home_guard = EnvGuard::remove("HOME");
+        let _user_guard = EnvGuard::remove("USER");
 
         let cmd = Command::new("test").with_required_env();
 
@@ -655,6 +1362,16 @@ mod tests {
         }
     }
 
+    #[test]
+    #[serial]
+    fn test_nix_sshopts() {
+        let _guard = EnvGuard::new("NIX_SSHOPTS", "-o ControlMaster=auto");
+        set_host_ssh_options("root@db", "-p 2222");
+
+        assert_eq!(nix_sshopts("root@db"), "-o ControlMaster=auto -p 2222");
+        assert_eq!(nix_sshopts("root@web"), "-o ControlMaster=auto");
+    }
+
     #[test]
     #[serial]
     fn test_with_required_env_nh_vars() {
@@ -859,6 +1576,95 @@ mod tests {
         assert!(cmdline.contains("TEST_VAR2=value2"));
     }
 
+    #[test]
+    #[serial]
+    fn test_elevated_std_cmd_preserves_args_with_spaces() {
+        let std_cmd = Command::elevated_std_cmd(
+            OsStr::new("/path with spaces/nh"),
+            [
+                "os",
+                "switch",
+                "/home/user/my flake",
+                "--",
+                "--option",
+                "a b",
+            ],
+            &[],
+        );
+
+        assert_eq!(std_cmd.get_program(), "sudo");
+
+        let args: Vec<&OsStr> = std_cmd.get_args().collect();
+        let program_idx = args
+            .iter()
+            .position(|arg| *arg == "/path with spaces/nh")
+            .expect("program should be passed as a single argument");
+
+        assert_eq!(
+            &args[program_idx + 1..],
+            &[
+                OsStr::new("os"),
+                OsStr::new("switch"),
+                OsStr::new("/home/user/my flake"),
+                OsStr::new("--"),
+                OsStr::new("--option"),
+                OsStr::new("a b"),
+            ]
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_elevated_std_cmd_preserves_args_with_quotes() {
+        let std_cmd = Command::elevated_std_cmd(
+            OsStr::new("nh"),
+            ["--expr", r#"import <nixpkgs> { config = "it's"; }"#],
+            &[],
+        );
+
+        let args: Vec<&OsStr> = std_cmd.get_args().collect();
+        assert_eq!(
+            args.last().copied(),
+            Some(OsStr::new(r#"import <nixpkgs> { config = "it's"; }"#))
+        );
+        assert!(args.contains(&OsStr::new("--expr")));
+    }
+
+    #[test]
+    #[serial]
+    fn test_elevated_std_cmd_sets_askpass_env() {
+        let _guard = EnvGuard::new("NH_SUDO_ASKPASS", "/path/to/askpass");
+
+        let std_cmd = Command::elevated_std_cmd(OsStr::new("nh"), ["clean", "all"], &[]);
+
+        // SUDO_ASKPASS must be set on the sudo process, not passed as an argument
+        assert!(
+            std_cmd.get_envs().any(|(key, value)| key == "SUDO_ASKPASS"
+                && value == Some(OsStr::new("/path/to/askpass")))
+        );
+        assert!(std_cmd.get_args().any(|arg| arg == "-A"));
+        assert!(
+            !std_cmd
+                .get_args()
+                .any(|arg| arg.to_string_lossy().starts_with("SUDO_ASKPASS="))
+        );
+    }
+
+    #[test]
+    fn test_elevated_std_cmd_sets_env() {
+        let std_cmd = Command::elevated_std_cmd(
+            OsStr::new("nh"),
+            ["clean", "all"],
+            &[(events::RELAY_VAR, "/tmp/events".to_string())],
+        );
+
+        assert!(
+            std_cmd
+                .get_args()
+                .any(|arg| arg == "NH_JSON_EVENTS_RELAY=/tmp/events")
+        );
+    }
+
     #[test]
     fn test_build_new() {
         let installable = Installable::Flake {
@@ -872,7 +1678,7 @@ mod tests {
         assert_eq!(build.installable.to_args(), installable.to_args());
         assert!(build.extra_args.is_empty());
         assert!(!build.nom);
-        assert!(build.builder.is_none());
+        assert!(build.builders.is_empty());
     }
 
     #[test]
@@ -900,7 +1706,7 @@ mod tests {
             ]
         );
         assert!(build.nom);
-        assert_eq!(build.builder, Some("build-host".to_string()));
+        assert_eq!(build.builders, [RemoteBuilder::host("build-host")]);
     }
 
     #[test]
@@ -912,6 +1718,26 @@ mod tests {
         assert!(cmdline.starts_with("ssh"));
         assert!(cmdline.contains("-T"));
         assert!(cmdline.contains("user@host"));
+        assert!(cmdline.ends_with("'echo hello'"));
+    }
+
+    #[test]
+    #[serial]
+    fn test_remote_sudo_script() {
+        let _guard = EnvGuard::new("NH_SUDO_ASKPASS", "/local/askpass");
+        let cmd = Command::new("switch-to-configuration")
+            .arg("switch")
+            .elevate(true);
+
+        let askpass = cmd
+            .remote_sudo_script(Some("/remote/askpass"), false)
+            .to_cmdline_lossy();
+        assert!(askpass.starts_with("env 'SUDO_ASKPASS=/remote/askpass' sudo -A "));
+        assert!(askpass.ends_with("switch-to-configuration switch"));
+        assert!(!askpass.contains("/local/askpass"));
+
+        let password = cmd.remote_sudo_script(None, true).to_cmdline_lossy();
+        assert!(password.starts_with("sudo -S '--prompt=' "));
     }
 
     #[test]
@@ -952,6 +1778,160 @@ mod tests {
         );
     }
 
+    #[test]
+    #[serial]
+    fn test_clean_env_allowlist() {
+        let _locale = EnvGuard::new("LOCALE_ARCHIVE", "/test/locale-archive");
+        let _nix = EnvGuard::new("NIX_CONFIG", "experimental-features = flakes");
+        let _nh = EnvGuard::new("NH_TEST_CLEAN", "nh_value");
+        let _stray = EnvGuard::new("NIX_STRAY_VAR", "stray");
+
+        let mut env_vars = HashMap::new();
+        env_vars.insert("EXPLICIT".to_string(), EnvAction::Set("set".to_string()));
+        env_vars.insert("NIX_CONFIG".to_string(), EnvAction::Remove);
+
+        let allowlist: HashMap<_, _> = clean_env_allowlist(&env_vars).into_iter().collect();
+
+        assert_eq!(
+            allowlist.get("LOCALE_ARCHIVE").map(String::as_str),
+            Some("/test/locale-archive")
+        );
+        assert_eq!(
+            allowlist.get("NH_TEST_CLEAN").map(String::as_str),
+            Some("nh_value")
+        );
+        assert_eq!(allowlist.get("EXPLICIT").map(String::as_str), Some("set"));
+        assert!(!allowlist.contains_key("NIX_CONFIG"));
+        assert!(!allowlist.contains_key("NIX_STRAY_VAR"));
+    }
+
+    #[test]
+    fn test_build_eval_args() {
+        let eval = NixEvalArgs {
+            override_input: vec![
+                "nixpkgs".to_string(),
+                "/home/user/nixpkgs".to_string(),
+                "home-manager".to_string(),
+                "github:nix-community/home-manager".to_string(),
+            ],
+            override_flake: vec!["nixpkgs".to_string(), "path:/tmp/nixpkgs".to_string()],
+            impure: true,
+            accept_flake_config: false,
+            options: vec!["eval-cache".to_string(), "false".to_string()],
+            show_trace: false,
+        };
+
+        let build = Build::new(Installable::Flake {
+            reference: ".".to_string(),
+            attribute: vec![],
+        })
+        .eval_args(&eval);
+
+        assert_eq!(
+            build.extra_args,
+            [
+                "--override-input",
+                "nixpkgs",
+                "/home/user/nixpkgs",
+                "--override-input",
+                "home-manager",
+                "github:nix-community/home-manager",
+                "--override-flake",
+                "nixpkgs",
+                "path:/tmp/nixpkgs",
+                "--impure",
+                "--option",
+                "eval-cache",
+                "false",
+            ]
+            .map(OsString::from)
+        );
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_everything_below_cap() {
+        let mut buf = RingBuffer::new(64);
+        buf.extend(b"error: first\n");
+        buf.extend(b"error: second\n");
+
+        assert_eq!(buf.into_string(), "error: first\nerror: second\n");
+    }
+
+    #[test]
+    fn test_ring_buffer_keeps_tail() {
+        let mut buf = RingBuffer::new(16);
+        buf.extend(b"line one\nline two\nline three\n");
+
+        // Only the last 16 bytes survive, and the partial line is dropped
+        assert_eq!(buf.into_string(), "[...]\nline three\n");
+    }
+
+    #[test]
+    fn test_ring_buffer_across_chunks() {
+        let mut buf = RingBuffer::new(12);
+        for chunk in [&b"aaaa\n"[..], b"bbbb\n", b"cccc\n", b"dd"] {
+            buf.extend(chunk);
+        }
+
+        let out = buf.into_string();
+        assert!(out.starts_with("[...]"));
+        assert!(out.ends_with("cccc\ndd"));
+    }
+
+    #[test]
+    fn test_run_attaches_stderr_tail_on_failure() {
+        let err = Command::new("sh")
+            .args(["-c", "echo 'something went wrong' >&2; exit 3"])
+            .message("Running failing command")
+            .run()
+            .unwrap_err();
+
+        let report = format!("{err:?}");
+        assert!(report.contains("Running failing command"));
+        assert!(report.contains("something went wrong"));
+    }
+
+    #[test]
+    fn test_run_with_stderr_tail_input() {
+        let cmd = Exec::cmd("sh")
+            .args(&["-c", "read line; echo \"got $line\" >&2; exit 2"])
+            .stdin(Redirection::Pipe)
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe);
+        let (status, stderr) = run_with_stderr_tail(cmd, Some("password\n"), false).unwrap();
+        assert!(!status.success());
+        assert_eq!(stderr, "got password\n");
+    }
+
+    #[test]
+    fn test_run_capture_merge_stderr() {
+        let cmd = || Command::new("sh").args(["-c", "echo out; echo err >&2"]);
+        let output = cmd().merge_stderr(true).run_capture().unwrap().unwrap();
+        assert!(output.contains("out") && output.contains("err"));
+        assert_eq!(cmd().run_capture().unwrap().unwrap(), "out\n");
+    }
+
+    #[test]
+    fn test_log_text() {
+        assert_eq!(
+            log_text("[1 built] \r\x1b[Kerror: builder for '/nix/store/abc-foo.drv' failed\r\n"),
+            "error: builder for '/nix/store/abc-foo.drv' failed"
+        );
+        assert_eq!(log_text("\x1b[31;1merror:\x1b[0m boom\n"), "error: boom");
+    }
+
+    #[test]
+    fn test_scan_build_log() {
+        let log = "building '/nix/store/abc-foo.drv'...\n\
+                   error: builder for '/nix/store/abc-foo.drv' failed with exit code 1\n";
+        assert_eq!(
+            scan_build_log(log.as_bytes()),
+            build_log::failed_drv(log.lines().last().unwrap())
+                .into_iter()
+                .collect::<Vec<_>>()
+        );
+    }
+
     #[test]
     fn test_exit_error_display() {
         let exit_status = subprocess::ExitStatus::Exited(1);
@@ -1,15 +1,364 @@
+//! Shell completions.
+//!
+//! `nh completions <shell>` prints a static completion script. With
+//! `--dynamic` it instead prints a small script that calls back into nh on
+//! every `<TAB>`, which lets nh complete values it only knows at completion
+//! time, like the configuration names of the flake:
+//!
+//! ```sh
+//! source <(nh completions bash --dynamic)
+//! ```
+//!
+//! Generation numbers are completed with their dates, e.g. for
+//! `nh os rollback --to`, and specialisations with those of the active
+//! configuration. `nh search` completes the packages found by earlier
+//! searches.
+//!
+//! Configuration names come from the cached outputs of the flake, see
+//! [`crate::flake::outputs`].
+
+use std::env;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Local};
+use clap::{Args, FromArgMatches};
+use clap_complete::engine::{CompletionCandidate, PathCompleter, ValueCompleter};
+use clap_complete::env::{CompleteEnv, Shells};
 use clap_complete::generate;
 use color_eyre::Result;
-use tracing::instrument;
+use color_eyre::eyre::bail;
+use tracing::{debug, instrument};
 
+use crate::generations;
+use crate::installable::{self, Installable};
 use crate::interface;
 use crate::interface::Main;
+use crate::{flake, search};
+
+/// Environment variable the dynamic completion script sets to the shell name
+const ENV_VAR: &str = "NH_COMPLETE";
 
 impl interface::CompletionArgs {
     #[instrument(ret, level = "trace")]
     pub fn run(&self) -> Result<()> {
         let mut cmd = <Main as clap::CommandFactory>::command();
+
+        if self.dynamic {
+            let shells = Shells::builtins();
+            let Some(shell) = shells.completer(&self.shell.to_string()) else {
+                bail!("Dynamic completions aren't supported for {}", self.shell);
+            };
+            shell.write_registration(ENV_VAR, "nh", "nh", "nh", &mut std::io::stdout())?;
+            return Ok(());
+        }
+
         generate(self.shell, &mut cmd, "nh", &mut std::io::stdout());
         Ok(())
     }
 }
+
+/// Answer a completion request from the dynamic completion script and exit,
+/// if this is one.
+pub fn complete_env() {
+    CompleteEnv::with_factory(<Main as clap::CommandFactory>::command)
+        .var(ENV_VAR)
+        .complete();
+}
+
+/// The flake nh would use without an installable argument, from the
+/// environment or by looking for a `flake.nix`.
+fn default_flake() -> Option<String> {
+    let cmd = Installable::augment_args(clap::Command::new("nh"));
+    let matches = cmd.try_get_matches_from(["nh"]).ok()?;
+    match Installable::from_arg_matches(&matches).ok()? {
+        Installable::Flake { reference, .. } => Some(reference),
+        _ => None,
+    }
+}
+
+/// Names in `output` of `flake` starting with `prefix`.
+fn attribute_names(flake: &str, output: &str, prefix: &str) -> Vec<String> {
+    match flake::outputs(flake, true) {
+        Ok(mut outputs) => outputs
+            .remove(output)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| name.starts_with(prefix))
+            .collect(),
+        Err(err) => {
+            debug!("Not completing {output}: {err:#}");
+            Vec::new()
+        }
+    }
+}
+
+/// Completes the names of a configuration output of the default flake, for
+/// `--hostname` and `--configuration`.
+#[derive(Debug, Clone, Copy)]
+pub struct Configurations(pub &'static str);
+
+impl ValueCompleter for Configurations {
+    fn complete(&self, current: &OsStr) -> Vec<CompletionCandidate> {
+        let Some(flake) = default_flake() else {
+            return Vec::new();
+        };
+        attribute_names(&flake, self.0, &current.to_string_lossy())
+            .into_iter()
+            .map(CompletionCandidate::new)
+            .collect()
+    }
+}
+
+/// Completes generation numbers of a profile, newest first, described by
+/// their date and label.
+#[derive(Debug, Clone, Copy)]
+pub struct Generations(pub &'static str);
+
+impl ValueCompleter for Generations {
+    fn complete(&self, current: &OsStr) -> Vec<CompletionCandidate> {
+        let current = current.to_string_lossy();
+        generation_candidates(Path::new(self.0))
+            .into_iter()
+            .filter(|(number, _)| number.to_string().starts_with(current.as_ref()))
+            .enumerate()
+            .map(|(order, (number, help))| {
+                CompletionCandidate::new(number.to_string())
+                    .help(Some(help.into()))
+                    .display_order(Some(order))
+            })
+            .collect()
+    }
+}
+
+/// Generations of `profile`, newest first, with their descriptions.
+fn generation_candidates(profile: &Path) -> Vec<(u64, String)> {
+    let Ok(links) = generations::generation_links(profile) else {
+        return Vec::new();
+    };
+    let labels = generations::labels(profile).unwrap_or_default();
+    let current = std::fs::read_link(profile)
+        .ok()
+        .and_then(|link| generations::from_dir(&link));
+
+    links
+        .iter()
+        .rev()
+        .map(|(number, link)| {
+            let mut help = link
+                .symlink_metadata()
+                .and_then(|meta| meta.modified())
+                .map_or_else(
+                    |_| "unknown date".to_string(),
+                    |modified| {
+                        DateTime::<Local>::from(modified)
+                            .format("%Y-%m-%d %H:%M")
+                            .to_string()
+                    },
+                );
+            if let Some(label) = labels.get(number) {
+                help.push_str(&format!(" {label}"));
+            }
+            if current == Some(*number) {
+                help.push_str(" (current)");
+            }
+            (*number, help)
+        })
+        .collect()
+}
+
+/// Completes specialisation names from the active configuration.
+#[derive(Debug, Clone, Copy)]
+pub enum Specialisations {
+    System,
+    Home,
+}
+
+impl Specialisations {
+    /// Directories the active configuration keeps its specialisations in
+    fn dirs(self) -> Vec<PathBuf> {
+        match self {
+            Self::System => vec![PathBuf::from("/run/current-system/specialisation")],
+            Self::Home => {
+                let mut dirs = Vec::new();
+                if let Some(user) = env::var_os("USER") {
+                    dirs.push(
+                        Path::new("/nix/var/nix/profiles/per-user")
+                            .join(user)
+                            .join("home-manager/specialisation"),
+                    );
+                }
+                if let Some(home) = env::var_os("HOME") {
+                    dirs.push(
+                        Path::new(&home)
+                            .join(".local/state/nix/profiles/home-manager/specialisation"),
+                    );
+                }
+                dirs
+            }
+        }
+    }
+}
+
+impl ValueCompleter for Specialisations {
+    fn complete(&self, current: &OsStr) -> Vec<CompletionCandidate> {
+        let current = current.to_string_lossy();
+        self.dirs()
+            .iter()
+            .find(|dir| dir.is_dir())
+            .map(|dir| specialisation_names(dir))
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|name| name.starts_with(current.as_ref()))
+            .map(CompletionCandidate::new)
+            .collect()
+    }
+}
+
+/// Names of the specialisations in a `specialisation` directory, sorted.
+fn specialisation_names(dir: &Path) -> Vec<String> {
+    let mut names: Vec<String> = std::fs::read_dir(dir)
+        .map(|entries| {
+            entries
+                .filter_map(Result::ok)
+                .map(|entry| entry.file_name().to_string_lossy().into_owned())
+                .collect()
+        })
+        .unwrap_or_default();
+    names.sort();
+    names
+}
+
+/// Value of the option `long` or `short` on the command line being
+/// completed, since completers don't see the other arguments.
+fn arg_value(args: &[String], long: &str, short: &str) -> Option<String> {
+    let mut value = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == long || arg == short {
+            value = args.next().cloned();
+        } else if let Some(rest) = arg
+            .strip_prefix(long)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            value = Some(rest.to_string());
+        }
+    }
+    value
+}
+
+/// Completes package names for `nh search` from the packages found by
+/// earlier searches in the same channel, without querying anything.
+pub fn complete_package(current: &OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    let args: Vec<String> = env::args().collect();
+    let channel = arg_value(&args, "--channel", "-c")
+        .or_else(|| env::var("NH_SEARCH_CHANNEL").ok())
+        .unwrap_or_else(|| search::DEFAULT_CHANNEL.to_string());
+
+    search::cached_packages(&channel)
+        .into_iter()
+        .filter(|(attr, _)| attr.starts_with(current.as_ref()))
+        .map(|(attr, description)| {
+            CompletionCandidate::new(attr)
+                .help((!description.is_empty()).then(|| description.into()))
+        })
+        .collect()
+}
+
+/// Quote an attribute name for an attribute path if needed.
+fn quote_attribute(name: &str) -> String {
+    if name.contains('.') {
+        format!("\"{name}\"")
+    } else {
+        name.to_string()
+    }
+}
+
+/// Completes `FLAKEREF#ATTRPATH` installables with the configurations of the
+/// flake, and paths before the `#`.
+pub fn complete_installable(current: &OsStr) -> Vec<CompletionCandidate> {
+    let current = current.to_string_lossy();
+    let Some((reference, attribute)) = current.split_once('#') else {
+        return PathCompleter::dir().complete(OsStr::new(current.as_ref()));
+    };
+
+    let outputs: &[&str] = match installable::current_command().as_deref() {
+        Some("os") => &["nixosConfigurations"],
+        Some("home") => &["homeConfigurations"],
+        Some("darwin") => &["darwinConfigurations"],
+        Some("sys") => &["systemConfigs"],
+        _ => &[
+            "nixosConfigurations",
+            "homeConfigurations",
+            "darwinConfigurations",
+        ],
+    };
+    let flake = if reference.is_empty() { "." } else { reference };
+
+    outputs
+        .iter()
+        .flat_map(|output| {
+            attribute_names(flake, output, "")
+                .into_iter()
+                .map(move |name| format!("{output}.{}", quote_attribute(&name)))
+        })
+        .filter(|path| path.starts_with(attribute))
+        .map(|path| CompletionCandidate::new(format!("{reference}#{path}")))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arg_value() {
+        let args = |args: &str| args.split(' ').map(String::from).collect::<Vec<_>>();
+        assert_eq!(
+            arg_value(&args("nh search -c nixos-24.11 fire"), "--channel", "-c").as_deref(),
+            Some("nixos-24.11")
+        );
+        assert_eq!(
+            arg_value(
+                &args("nh search --channel=nixos-24.11 fire"),
+                "--channel",
+                "-c"
+            )
+            .as_deref(),
+            Some("nixos-24.11")
+        );
+        assert_eq!(arg_value(&args("nh search fire"), "--channel", "-c"), None);
+    }
+
+    #[test]
+    fn test_specialisation_names() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["work", "gaming"] {
+            std::fs::create_dir(dir.path().join(name)).unwrap();
+        }
+
+        assert_eq!(specialisation_names(dir.path()), vec!["gaming", "work"]);
+        assert!(specialisation_names(&dir.path().join("missing")).is_empty());
+    }
+
+    #[test]
+    fn test_generation_candidates() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("system");
+        for number in [1, 2, 10] {
+            std::os::unix::fs::symlink(
+                "/nonexistent",
+                generations::generation_link(&profile, number),
+            )
+            .unwrap();
+        }
+        std::os::unix::fs::symlink("system-2-link", &profile).unwrap();
+
+        let candidates = generation_candidates(&profile);
+        let numbers: Vec<u64> = candidates.iter().map(|(number, _)| *number).collect();
+        assert_eq!(numbers, vec![10, 2, 1]);
+        assert!(candidates[1].1.ends_with(" (current)"));
+        assert!(!candidates[0].1.ends_with(" (current)"));
+    }
+}
@@ -0,0 +1,515 @@
+//! User configuration for nh.
+//!
+//! The configuration is read from `$NH_CONFIG`, falling back to
+//! `$XDG_CONFIG_HOME/nh/config.toml` and `~/.config/nh/config.toml`. Every
+//! setting is optional and defaults to nh's built-in behaviour:
+//!
+//! ```toml
+//! [checks]
+//! # Warn when Nix or Lix is older than these
+//! min-nix-version = "2.24"
+//! min-lix-version = "2.91"
+//! # Experimental features required by commands that use flakes
+//! required-features = ["nix-command", "flakes"]
+//!
+//! # Colors of output, see `theme.rs` for the available roles
+//! [theme]
+//! heading = "bold magenta"
+//!
+//! # Defaults for command line flags
+//! [flake]
+//! os = "~/dotfiles"          # NH_OS_FLAKE, also home, darwin and default (NH_FLAKE)
+//!
+//! [rebuild]
+//! ask = true                 # NH_ASK, also used by rollback and clean
+//! ask-default = false        # NH_ASK_DEFAULT
+//! ask-timeout = "5m"         # NH_ASK_TIMEOUT
+//! no-nom = true              # NH_NO_NOM
+//! diff = "always"            # NH_DIFF
+//! diff-tool = "builtin"      # NH_DIFF_TOOL
+//! diff-format = "summary"    # NH_DIFF_FORMAT
+//! push-to = "cachix:fleet"   # NH_PUSH_TO
+//! push-key = "~/cache.sec"   # NH_PUSH_KEY
+//! allow-dirty = false        # NH_REQUIRE_CLEAN, inverted
+//! keep-results = 5           # NH_KEEP_RESULTS
+//! # Refuse to activate closures not signed by one of these
+//! trusted-keys = ["cache.example.org-1:..."]
+//!
+//! [clean]
+//! keep = 5                   # NH_CLEAN_KEEP
+//! keep-since = "7d"          # NH_CLEAN_KEEP_SINCE, or "system=30d,home=7d"
+//!
+//! [ssh]
+//! options = "-o ControlMaster=auto"  # NIX_SSHOPTS
+//!
+//! [search]
+//! channel = "nixos-24.11"    # NH_SEARCH_CHANNEL
+//! index-max-age = "7d"       # NH_SEARCH_INDEX_MAX_AGE
+//!
+//! [notify]
+//! desktop = true             # NH_NOTIFY
+//! webhook = "https://..."    # NH_WEBHOOK
+//! after = "2m"               # NH_NOTIFY_AFTER
+//!
+//! # Remote builders, unless --remote-builder or --builders is given
+//! [[builders]]
+//! uri = "ssh://big-builder"
+//! systems = ["x86_64-linux", "aarch64-linux"]
+//! max-jobs = 16
+//! speed-factor = 4
+//!
+//! # Hosts deployed by `nh os deploy`, see `inventory.rs`
+//! [inventory.web-1]
+//! target-host = "root@web-1.example.com"
+//! tags = ["web"]
+//! health-check = "curl -fsS http://localhost/health"
+//!
+//! [rollout.web]
+//! max-parallel = 3
+//! canaries = ["web-1"]
+//!
+//! # Commands run at points of a rebuild, see `hooks.rs`
+//! [hooks]
+//! post-build = ["cachix push fleet \"$NH_OUT_PATH\""]
+//! ```
+//!
+//! Flag defaults are applied through the environment variable shown next to
+//! them, so command line arguments take precedence over environment
+//! variables, which take precedence over the configuration.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::OnceLock;
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+use crate::builders::RemoteBuilder;
+use crate::interface::{DiffFormat, DiffTool, DiffType};
+use crate::inventory::{Inventory, Rollouts};
+use crate::theme::Role;
+
+static CONFIG: OnceLock<Config> = OnceLock::new();
+static LOAD_ERROR: OnceLock<String> = OnceLock::new();
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    #[serde(default)]
+    pub checks: ChecksConfig,
+
+    /// Styles for each kind of colored output
+    #[serde(default)]
+    pub theme: BTreeMap<Role, String>,
+
+    #[serde(default)]
+    pub flake: FlakeConfig,
+
+    #[serde(default)]
+    pub rebuild: RebuildConfig,
+
+    #[serde(default)]
+    pub clean: CleanConfig,
+
+    #[serde(default)]
+    pub ssh: SshConfig,
+
+    #[serde(default)]
+    pub search: SearchConfig,
+
+    /// Commands run at points of a rebuild, see [`crate::hooks`]
+    #[serde(default)]
+    pub hooks: HooksConfig,
+
+    #[serde(default)]
+    pub notify: NotifyConfig,
+
+    /// Machines to distribute builds over, see [`crate::builders`]
+    #[serde(default)]
+    pub builders: Vec<RemoteBuilder>,
+
+    /// Hosts of the fleet, see [`crate::inventory`]
+    #[serde(default)]
+    pub inventory: Inventory,
+
+    /// How groups of hosts are deployed, see [`crate::inventory`]
+    #[serde(default)]
+    pub rollout: Rollouts,
+}
+
+/// Flake references used when no installable is given
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct FlakeConfig {
+    pub default: Option<String>,
+    pub os: Option<String>,
+    pub home: Option<String>,
+    pub darwin: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct RebuildConfig {
+    pub ask: Option<bool>,
+    /// Answer taken when enter is pressed or the prompt times out
+    pub ask_default: Option<bool>,
+    pub ask_timeout: Option<String>,
+    pub no_nom: Option<bool>,
+    pub diff: Option<DiffType>,
+    pub diff_tool: Option<DiffTool>,
+    pub diff_format: Option<DiffFormat>,
+    pub push_to: Option<String>,
+    pub push_key: Option<String>,
+    /// Whether flakes with uncommitted changes may be built
+    pub allow_dirty: Option<bool>,
+    /// Number of results of each configuration kept in the state directory
+    pub keep_results: Option<usize>,
+    /// Public keys the closure has to be signed with before it is activated
+    #[serde(default)]
+    pub trusted_keys: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct CleanConfig {
+    pub keep: Option<u32>,
+    pub keep_since: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SshConfig {
+    /// Options passed to ssh by nh and nix
+    pub options: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct HooksConfig {
+    pub pre_eval: Vec<String>,
+    pub post_build: Vec<String>,
+    pub pre_activate: Vec<String>,
+    pub post_activate: Vec<String>,
+    pub on_failure: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct NotifyConfig {
+    pub desktop: Option<bool>,
+    pub webhook: Option<String>,
+    pub after: Option<String>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct SearchConfig {
+    pub channel: Option<String>,
+    /// Age after which the nix-index database is refreshed before a search
+    pub index_max_age: Option<String>,
+}
+
+/// Requirements checked before running commands
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct ChecksConfig {
+    /// Minimum recommended version of Nix and its forks, except Lix
+    pub min_nix_version: Option<String>,
+
+    /// Minimum recommended version of Lix
+    pub min_lix_version: Option<String>,
+
+    /// Experimental features required by flake commands, replacing the
+    /// built-in list
+    pub required_features: Option<Vec<String>>,
+}
+
+/// Path of the configuration file, which doesn't necessarily exist.
+pub fn config_path() -> Option<PathBuf> {
+    if let Some(path) = env::var_os("NH_CONFIG").filter(|path| !path.is_empty()) {
+        return Some(PathBuf::from(path));
+    }
+
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|dir| !dir.is_empty()) {
+        return Some(PathBuf::from(dir).join("nh/config.toml"));
+    }
+
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(".config/nh/config.toml"))
+}
+
+/// Expand a leading `~/` to the home directory.
+pub(crate) fn expand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), env::var_os("HOME")) {
+        (Some(rest), Some(home)) => PathBuf::from(home)
+            .join(rest)
+            .to_string_lossy()
+            .into_owned(),
+        _ => path.to_string(),
+    }
+}
+
+impl Config {
+    /// Environment variables standing in for the flag defaults set in the
+    /// configuration.
+    #[must_use]
+    pub fn env_defaults(&self) -> Vec<(&'static str, String)> {
+        let mut vars: Vec<(&'static str, String)> = [
+            "NH_FLAKE",
+            "NH_OS_FLAKE",
+            "NH_HOME_FLAKE",
+            "NH_DARWIN_FLAKE",
+        ]
+        .into_iter()
+        .filter_map(|var| Some((var, self.flake(var)?)))
+        .collect();
+
+        let mut set = |var, value: Option<String>| {
+            if let Some(value) = value {
+                vars.push((var, value));
+            }
+        };
+        set("NH_ASK", self.rebuild.ask.map(|ask| ask.to_string()));
+        set(
+            "NH_ASK_DEFAULT",
+            self.rebuild.ask_default.map(|default| default.to_string()),
+        );
+        set("NH_ASK_TIMEOUT", self.rebuild.ask_timeout.clone());
+        set(
+            "NH_NO_NOM",
+            self.rebuild.no_nom.map(|no_nom| no_nom.to_string()),
+        );
+        set(
+            "NH_DIFF",
+            self.rebuild
+                .diff
+                .and_then(|diff| diff.to_possible_value())
+                .map(|diff| diff.get_name().to_string()),
+        );
+        set(
+            "NH_DIFF_TOOL",
+            self.rebuild
+                .diff_tool
+                .and_then(|tool| tool.to_possible_value())
+                .map(|tool| tool.get_name().to_string()),
+        );
+        set(
+            "NH_DIFF_FORMAT",
+            self.rebuild
+                .diff_format
+                .and_then(|format| format.to_possible_value())
+                .map(|format| format.get_name().to_string()),
+        );
+        set("NH_PUSH_TO", self.rebuild.push_to.clone());
+        set(
+            "NH_PUSH_KEY",
+            self.rebuild.push_key.as_deref().map(expand_home),
+        );
+        set(
+            "NH_REQUIRE_CLEAN",
+            self.rebuild.allow_dirty.map(|allow| (!allow).to_string()),
+        );
+        set(
+            "NH_KEEP_RESULTS",
+            self.rebuild.keep_results.map(|keep| keep.to_string()),
+        );
+        set(
+            "NH_CLEAN_KEEP",
+            self.clean.keep.map(|keep| keep.to_string()),
+        );
+        set("NH_CLEAN_KEEP_SINCE", self.clean.keep_since.clone());
+        set("NIX_SSHOPTS", self.ssh.options.clone());
+        set("NH_SEARCH_CHANNEL", self.search.channel.clone());
+        set("NH_SEARCH_INDEX_MAX_AGE", self.search.index_max_age.clone());
+        set(
+            "NH_NOTIFY",
+            self.notify.desktop.map(|desktop| desktop.to_string()),
+        );
+        set("NH_WEBHOOK", self.notify.webhook.clone());
+        set("NH_NOTIFY_AFTER", self.notify.after.clone());
+
+        vars
+    }
+
+    /// The flake set for the variable `var`, like `NH_OS_FLAKE`.
+    #[must_use]
+    pub fn flake(&self, var: &str) -> Option<String> {
+        let flake = match var {
+            "NH_FLAKE" => &self.flake.default,
+            "NH_OS_FLAKE" => &self.flake.os,
+            "NH_HOME_FLAKE" => &self.flake.home,
+            "NH_DARWIN_FLAKE" => &self.flake.darwin,
+            _ => return None,
+        };
+        flake.as_deref().map(expand_home)
+    }
+
+    /// Read the configuration file, or the defaults if there is none.
+    pub fn load() -> Result<Self> {
+        Self::read().map_err(|err| eyre!(err))
+    }
+
+    /// [`Config::load`] with a plain error message, as [`init`] runs before
+    /// the error report hook is installed.
+    fn read() -> std::result::Result<Self, String> {
+        let Some(path) = config_path() else {
+            return Ok(Self::default());
+        };
+
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                debug!("Reading configuration from {}", path.display());
+                toml::from_str(&contents).map_err(|err| {
+                    format!("Failed to parse configuration {}: {err}", path.display())
+                })
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(err) => Err(format!(
+                "Failed to read configuration {}: {err}",
+                path.display()
+            )),
+        }
+    }
+}
+
+/// Load the configuration for the rest of the run. Called once at startup.
+///
+/// This runs before the command line is parsed, so that the flag defaults
+/// from the configuration can be set in the environment. Variables that are
+/// already set are left alone. A broken configuration file doesn't stop
+/// `--help` from working, the defaults are used and the error is kept for
+/// [`check`].
+pub fn init() {
+    let config = Config::read().unwrap_or_else(|err| {
+        let _ = LOAD_ERROR.set(err);
+        Config::default()
+    });
+
+    for (var, value) in config.env_defaults() {
+        if env::var_os(var).is_none() {
+            // SAFETY: nh is still single-threaded at this point
+            unsafe {
+                env::set_var(var, value);
+            }
+        }
+    }
+
+    let _ = CONFIG.set(config);
+}
+
+/// Report a configuration file [`init`] couldn't load, as an error, or only
+/// as a warning when `lenient`, for commands that diagnose the setup.
+pub fn check(lenient: bool) -> Result<()> {
+    match LOAD_ERROR.get() {
+        None => Ok(()),
+        Some(err) if lenient => {
+            warn!("{err}, using the defaults");
+            Ok(())
+        }
+        Some(err) => bail!("{err}"),
+    }
+}
+
+/// The configuration loaded by [`init`]. Without it, like when nh is used
+/// as a library, the configuration file is read on first use, and the
+/// defaults are used if it can't be.
+pub fn get() -> &'static Config {
+    CONFIG.get_or_init(|| {
+        Config::load().unwrap_or_else(|err| {
+            warn!("{err:#}");
+            Config::default()
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config: Config = toml::from_str(
+            r#"
+[checks]
+min-nix-version = "2.18"
+required-features = ["nix-command", "flakes", "pipe-operators"]
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(config.checks.min_nix_version.as_deref(), Some("2.18"));
+        assert_eq!(config.checks.min_lix_version, None);
+        assert_eq!(config.checks.required_features.unwrap().len(), 3);
+        assert_eq!(toml::from_str::<Config>("").unwrap(), Config::default());
+        assert!(toml::from_str::<Config>("[checks]\nmin-version = \"2\"").is_err());
+
+        let config: Config = toml::from_str(
+            r#"
+[[builders]]
+uri = "fast"
+systems = ["x86_64-linux"]
+max-jobs = 8
+"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.builders[0].machine_line(),
+            "ssh://fast x86_64-linux - 8 - -"
+        );
+        assert!(toml::from_str::<Config>("[[builders]]\nhost = \"fast\"").is_err());
+
+        let config: Config = toml::from_str("[theme]\nerror = \"bold red\"").unwrap();
+        assert_eq!(config.theme[&Role::Error], "bold red");
+        assert!(toml::from_str::<Config>("[theme]\ncolour = \"red\"").is_err());
+    }
+
+    #[test]
+    fn test_env_defaults() {
+        let config: Config = toml::from_str(
+            r#"
+[flake]
+os = "/etc/nixos"
+
+[rebuild]
+ask-timeout = "1m"
+no-nom = true
+diff = "never"
+diff-tool = "builtin"
+diff-format = "json"
+allow-dirty = false
+
+[clean]
+keep = 3
+
+[ssh]
+options = "-p 2222"
+
+[search]
+index-max-age = "7d"
+"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            config.env_defaults(),
+            vec![
+                ("NH_OS_FLAKE", "/etc/nixos".to_string()),
+                ("NH_ASK_TIMEOUT", "1m".to_string()),
+                ("NH_NO_NOM", "true".to_string()),
+                ("NH_DIFF", "never".to_string()),
+                ("NH_DIFF_TOOL", "builtin".to_string()),
+                ("NH_DIFF_FORMAT", "json".to_string()),
+                ("NH_REQUIRE_CLEAN", "true".to_string()),
+                ("NH_CLEAN_KEEP", "3".to_string()),
+                ("NIX_SSHOPTS", "-p 2222".to_string()),
+                ("NH_SEARCH_INDEX_MAX_AGE", "7d".to_string()),
+            ]
+        );
+        assert!(Config::default().env_defaults().is_empty());
+        assert!(toml::from_str::<Config>("[rebuild]\ndiff = \"sometimes\"").is_err());
+    }
+}
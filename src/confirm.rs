@@ -0,0 +1,167 @@
+//! The `--ask` prompt before a configuration is applied.
+//!
+//! The question is followed by what applying the configuration would do: the
+//! package changes of the last diff, and notes from the caller like the units
+//! that would be restarted. For semi-automated runs the prompt can answer
+//! itself with a default after a timeout.
+
+use std::io::{self, BufRead};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+use color_eyre::Result;
+use tracing::{info, warn};
+
+use crate::{diff, events};
+
+static SETTINGS: OnceLock<(bool, Option<Duration>)> = OnceLock::new();
+
+/// Set the default answer and how long to wait for one, for the rest of the
+/// run.
+pub fn init(default: bool, timeout: Option<Duration>) {
+    let _ = SETTINGS.set((default, timeout));
+}
+
+fn settings() -> (bool, Option<Duration>) {
+    SETTINGS.get().copied().unwrap_or_default()
+}
+
+/// `question` with the diff summary and `notes` appended.
+fn format_prompt(question: &str, notes: &[String]) -> String {
+    let mut prompt = diff::prompt(question);
+    for note in notes {
+        prompt.push_str("\n  ");
+        prompt.push_str(note);
+    }
+    prompt
+}
+
+/// Ask whether to go on, with `notes` about what would happen.
+pub fn ask(question: &str, notes: &[String]) -> Result<bool> {
+    let prompt = format_prompt(question, notes);
+    info!("{prompt}");
+    events::confirmation(&prompt, || answer(settings().0))
+}
+
+/// Like [`ask`], but answering no without an answer whatever the default
+/// answer of the run is, for what can't be undone.
+pub fn ask_or_decline(question: &str, notes: &[String]) -> Result<bool> {
+    let prompt = format_prompt(question, notes);
+    info!("{prompt}");
+    events::confirmation(&prompt, || answer(false))
+}
+
+fn answer(default: bool) -> Result<bool> {
+    let (_, timeout) = settings();
+    let Some(timeout) = timeout else {
+        return Ok(dialoguer::Confirm::new().default(default).interact()?);
+    };
+
+    // dialoguer can't give up on a prompt, so read a line instead. The reader
+    // is left behind if the timeout runs out, which is fine since the prompt
+    // is the last thing nh reads from stdin.
+    eprint!("{} ", if default { "[Y/n]" } else { "[y/N]" });
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let mut line = String::new();
+        let read = io::stdin().lock().read_line(&mut line);
+        let _ = sender.send(read.map(|_| line));
+    });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(line) => Ok(parse_answer(&line?).unwrap_or(default)),
+        Err(_) => {
+            eprintln!();
+            warn!(
+                "No answer after {}, answering {}",
+                humantime::format_duration(timeout),
+                if default { "yes" } else { "no" }
+            );
+            Ok(default)
+        }
+    }
+}
+
+/// `Some(answer)` for a yes or no, `None` to take the default.
+fn parse_answer(line: &str) -> Option<bool> {
+    match line.trim().to_lowercase().as_str() {
+        "y" | "yes" => Some(true),
+        "n" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// A note for the prompt if booting `new` would run a different kernel,
+/// initrd or kernel modules than the running system, which a switch doesn't
+/// apply.
+#[must_use]
+pub fn reboot_note(booted: &Path, new: &Path) -> Option<String> {
+    let changed: Vec<&str> = ["kernel", "initrd", "kernel-modules"]
+        .into_iter()
+        .filter(|link| {
+            let old = booted.join(link).canonicalize().ok();
+            let new = new.join(link).canonicalize().ok();
+            old.is_some() && new.is_some() && old != new
+        })
+        .collect();
+
+    (!changed.is_empty()).then(|| {
+        format!(
+            "A reboot is needed to use the new {}",
+            changed.join(", ").replace('-', " ")
+        )
+    })
+}
+
+/// Notes for the prompt from the output of `switch-to-configuration
+/// dry-activate`, counting the units that would be stopped, restarted,
+/// reloaded or started.
+#[must_use]
+pub fn unit_notes(dry_activate: &str) -> Vec<String> {
+    dry_activate
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim().strip_prefix("would ")?;
+            let (action, units) = rest.split_once(" the following units: ")?;
+            let units: Vec<&str> = units.split(", ").filter(|u| !u.is_empty()).collect();
+            (!units.is_empty()).then(|| {
+                format!(
+                    "{} unit{} to {action}: {}",
+                    units.len(),
+                    if units.len() == 1 { "" } else { "s" },
+                    units.join(", ")
+                )
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unit_notes() {
+        let output = "\
+stopping the following units: foo.service
+would stop the following units: old.service
+would restart the following units: nginx.service, sshd.service
+would start the following units: new.service
+would reload the following units: dbus.service
+";
+        assert_eq!(
+            unit_notes(output),
+            [
+                "1 unit to stop: old.service",
+                "2 units to restart: nginx.service, sshd.service",
+                "1 unit to start: new.service",
+                "1 unit to reload: dbus.service",
+            ]
+        );
+        assert_eq!(parse_answer(" Yes\n"), Some(true));
+        assert_eq!(parse_answer("\n"), None);
+    }
+}
@@ -1,21 +1,42 @@
 use std::env;
-use std::path::PathBuf;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use color_eyre::eyre::{Context, bail};
 use tracing::{debug, info, warn};
 
 use crate::Result;
+use crate::clean;
 use crate::commands;
 use crate::commands::Command;
+use crate::confirm;
+use crate::diff;
+use crate::events::{self, Event, Phase};
+use crate::exit;
+use crate::hooks::{self, Hook};
 use crate::installable::Installable;
-use crate::interface::{DarwinArgs, DarwinRebuildArgs, DarwinReplArgs, DarwinSubcommand, DiffType};
+use crate::interface::{
+    CleanArgs, DarwinArgs, DarwinCleanArgs, DarwinRebuildArgs, DarwinReplArgs, DarwinSubcommand,
+    DiffType,
+};
+use crate::json;
 use crate::nixos::toplevel_for;
+use crate::output;
+use crate::repl;
 use crate::update::update;
-use crate::util::{get_hostname, print_dix_diff};
+use crate::util::{ensure_flake_configuration, get_hostname};
 
 const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 const CURRENT_PROFILE: &str = "/run/current-system";
 
+const LAUNCHD_LABEL: &str = "org.nix-community.nh.clean";
+const LAUNCHD_PLIST: &str = "/Library/LaunchDaemons/org.nix-community.nh.clean.plist";
+/// launchd starts daemons with only the system directories in PATH, which
+/// has no nix
+const LAUNCHD_PATH: &str =
+    "/run/current-system/sw/bin:/nix/var/nix/profiles/default/bin:/usr/bin:/bin:/usr/sbin:/sbin";
+
 impl DarwinArgs {
     pub fn run(self) -> Result<()> {
         use DarwinRebuildVariant::{Build, Switch};
@@ -28,37 +49,217 @@ impl DarwinArgs {
                 args.rebuild(&Build)
             }
             DarwinSubcommand::Repl(args) => args.run(),
+            DarwinSubcommand::Clean(args) => args.run(),
         }
     }
 }
 
-enum DarwinRebuildVariant {
+impl DarwinCleanArgs {
+    fn run(&self) -> Result<()> {
+        if !cfg!(target_os = "macos") {
+            bail!("nh darwin clean is macOS-only, use nh clean all instead");
+        }
+        if let Some(interval) = self.schedule {
+            return schedule_clean(&self.common, interval.into());
+        }
+        if self.unschedule {
+            return unschedule_clean(self.common.dry);
+        }
+
+        if !nix::unistd::Uid::effective().is_root() {
+            crate::util::self_elevate();
+        }
+        let result = clean::clean_profiles(clean::darwin_profiles(), &self.common, true)?;
+        json::emit(&json::Output::Clean(result))
+    }
+}
+
+/// Install a launchd daemon running `nh darwin clean` with `args` every
+/// `interval`. It runs as root, like `nh clean all`, to clean the system
+/// profile and the profiles of all users.
+fn schedule_clean(args: &CleanArgs, interval: Duration) -> Result<()> {
+    if args.ask {
+        bail!("--ask can't be used with --schedule, the daemon runs without a terminal to ask on");
+    }
+
+    // The system's nh outlives the store path of this one
+    let system_nh = Path::new(CURRENT_PROFILE).join("sw/bin/nh");
+    let program = if system_nh.exists() {
+        system_nh
+    } else {
+        env::current_exe().wrap_err("Failed to find the nh executable")?
+    };
+
+    let mut clean_args = vec![
+        "darwin".to_string(),
+        "clean".to_string(),
+        "--keep".to_string(),
+        args.keep.to_string(),
+        "--keep-since".to_string(),
+        args.keep_since.to_arg(),
+    ];
+    if args.nogc {
+        clean_args.push("--nogc".to_string());
+    }
+    if args.nogcroots {
+        clean_args.push("--nogcroots".to_string());
+    }
+    if let Some(template) = &args.format {
+        clean_args.push("--format".to_string());
+        clean_args.push(template.source().to_string());
+    }
+    let plist = launchd_plist(&program, &clean_args, interval);
+
+    if args.dry {
+        println!("{plist}");
+        return Ok(());
+    }
+
+    let file = tempfile::NamedTempFile::new()?;
+    fs::write(file.path(), plist)?;
+    Command::new("install")
+        .args(["-m", "644", "-o", "root", "-g", "wheel"])
+        .arg(file.path())
+        .arg(LAUNCHD_PLIST)
+        .elevate(true)
+        .run()
+        .wrap_err("Failed to install the launchd daemon")?;
+
+    // Replace a daemon installed before
+    let _ = Command::new("launchctl")
+        .args(["bootout", &format!("system/{LAUNCHD_LABEL}")])
+        .elevate(true)
+        .run_capture();
+    Command::new("launchctl")
+        .args(["bootstrap", "system", LAUNCHD_PLIST])
+        .elevate(true)
+        .message("Loading the launchd daemon")
+        .run()
+        .wrap_err("Failed to load the launchd daemon")?;
+
+    info!(
+        "nh darwin clean will run every {}",
+        humantime::format_duration(interval)
+    );
+    Ok(())
+}
+
+/// Remove the daemon installed by `schedule_clean`.
+fn unschedule_clean(dry: bool) -> Result<()> {
+    if !Path::new(LAUNCHD_PLIST).exists() {
+        info!("No scheduled cleanup to remove");
+        return Ok(());
+    }
+
+    let _ = Command::new("launchctl")
+        .args(["bootout", &format!("system/{LAUNCHD_LABEL}")])
+        .dry(dry)
+        .elevate(true)
+        .run_capture();
+    Command::new("rm")
+        .arg(LAUNCHD_PLIST)
+        .dry(dry)
+        .elevate(true)
+        .message("Removing the launchd daemon")
+        .run()
+        .wrap_err("Failed to remove the launchd daemon")
+}
+
+/// A launchd property list running `program` with `args` every `interval`,
+/// logging to `/var/log/nh-clean.log`.
+fn launchd_plist(program: &Path, args: &[String], interval: Duration) -> String {
+    let escape = |value: &str| {
+        value
+            .replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let arguments: String = std::iter::once(program.to_string_lossy().into_owned())
+        .chain(args.iter().cloned())
+        .map(|arg| format!("\n    <string>{}</string>", escape(&arg)))
+        .collect();
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+  <key>Label</key>
+  <string>{LAUNCHD_LABEL}</string>
+  <key>ProgramArguments</key>
+  <array>{arguments}
+  </array>
+  <key>EnvironmentVariables</key>
+  <dict>
+    <key>PATH</key>
+    <string>{LAUNCHD_PATH}</string>
+  </dict>
+  <key>StartInterval</key>
+  <integer>{}</integer>
+  <key>StandardOutPath</key>
+  <string>/var/log/nh-clean.log</string>
+  <key>StandardErrorPath</key>
+  <string>/var/log/nh-clean.log</string>
+</dict>
+</plist>"#,
+        interval.as_secs()
+    )
+}
+
+pub(crate) enum DarwinRebuildVariant {
     Switch,
     Build,
 }
 
 impl DarwinRebuildArgs {
     fn rebuild(self, variant: &DarwinRebuildVariant) -> Result<()> {
+        let result = self.build_and_activate(variant)?;
+        json::emit(&json::Output::Rebuild(result))
+    }
+
+    pub(crate) fn build_and_activate(
+        self,
+        variant: &DarwinRebuildVariant,
+    ) -> Result<json::RebuildResult> {
         use DarwinRebuildVariant::{Build, Switch};
 
         if nix::unistd::Uid::effective().is_root() {
             bail!("Don't run nh os as root. I will call sudo internally as needed");
         }
 
-        if self.update_args.update_all || self.update_args.update_input.is_some() {
-            update(&self.common.installable, self.update_args.update_input)?;
+        self.common.check_free_space()?;
+        self.common.check_substituters();
+        self.common.check_system()?;
+        if let Some(option) = self.common.trusted_option() {
+            crate::checks::warn_if_untrusted(option);
         }
 
-        let hostname = self.hostname.ok_or(()).or_else(|()| get_hostname())?;
+        let _stash = self.common.prepare_worktree(&self.common.installable)?;
 
-        let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
-            Some(ref p) => Box::new(p.clone()),
-            None => Box::new({
-                let dir = tempfile::Builder::new().prefix("nh-os").tempdir()?;
-                (dir.as_ref().join("result"), dir)
-            }),
+        let pending_update = if self.update_args.enabled() {
+            events::phase(Phase::Update, || {
+                update(&self.common.installable, &self.update_args, true)
+            })?
+        } else {
+            None
         };
 
+        let hostname = self.hostname.ok_or(()).or_else(|()| get_hostname())?;
+
+        hooks::start(
+            "darwin",
+            match variant {
+                Build => "build",
+                Switch => "switch",
+            },
+            Some(&hostname),
+        );
+        hooks::run(Hook::PreEval)?;
+
+        let results_name = format!("darwin-{hostname}");
+        let last_build = crate::results::latest(&results_name);
+        let out_path = self.common.result_link(&results_name, "nh-os")?;
+
         debug!(?out_path);
 
         // Use NH_DARWIN_FLAKE if available, otherwise use the provided installable
@@ -80,6 +281,25 @@ impl DarwinRebuildArgs {
             self.common.installable.clone()
         };
 
+        let installable = self.common.pin_installable(installable)?;
+
+        if let Installable::Flake {
+            reference,
+            attribute,
+        } = &installable
+        {
+            if attribute.is_empty() {
+                events::phase(Phase::Eval, || {
+                    ensure_flake_configuration(
+                        reference,
+                        "darwinConfigurations",
+                        &hostname,
+                        self.common.eval.generate_eval_args(),
+                    )
+                })?;
+            }
+        }
+
         let mut processed_installable = installable;
         if let Installable::Flake {
             ref mut attribute, ..
@@ -92,17 +312,60 @@ impl DarwinRebuildArgs {
             }
         }
 
-        let toplevel = toplevel_for(hostname, processed_installable, "toplevel");
+        let toplevel = toplevel_for(&hostname, processed_installable, "toplevel");
+
+        events::phase(Phase::Build, || {
+            self.common.build_unless_unchanged(
+                &toplevel,
+                &self.extra_args,
+                out_path.get_path(),
+                || {
+                    commands::Build::new(toplevel.clone())
+                        .extra_arg("--out-link")
+                        .extra_arg(out_path.get_path())
+                        .extra_args(&self.extra_args)
+                        .eval_args(&self.common.eval)
+                        .passthrough(&self.common.passthrough)
+                        .builders(&self.common.remote_builders())
+                        .message("Building Darwin configuration")
+                        .nom(!self.common.no_nom)
+                        .run()
+                        .wrap_err("Failed to build Darwin configuration")
+                },
+            )
+        })?;
+
+        if let Some(rev) = &self.common.rev {
+            info!("Built configuration at revision {rev}");
+        }
 
-        commands::Build::new(toplevel)
-            .extra_arg("--out-link")
-            .extra_arg(out_path.get_path())
-            .extra_args(&self.extra_args)
-            .passthrough(&self.common.passthrough)
-            .message("Building Darwin configuration")
-            .nom(!self.common.no_nom)
-            .run()
-            .wrap_err("Failed to build Darwin configuration")?;
+        events::emit(&Event::BuildOutput {
+            out_path: out_path.get_path(),
+            rev: self.common.rev.as_deref(),
+        });
+
+        self.common.prune_results(&results_name)?;
+        self.common.push_closure(out_path.get_path())?;
+
+        hooks::set_out_path(out_path.get_path());
+        hooks::run(Hook::PostBuild)?;
+
+        let mut result = json::RebuildResult {
+            system: "darwin",
+            action: match variant {
+                Build => "build",
+                Switch => "switch",
+            },
+            hostname: Some(hostname.clone()),
+            out_path: std::fs::canonicalize(out_path.get_path())
+                .unwrap_or_else(|_| out_path.get_path().to_path_buf()),
+            revision: self.common.rev.clone(),
+            dry: self.common.dry,
+            activated: false,
+            generation: None,
+            copy: None,
+            diff: None,
+        };
 
         let target_profile = out_path.get_path().to_owned();
 
@@ -127,17 +390,25 @@ impl DarwinRebuildArgs {
         match self.common.diff {
             DiffType::Never => {}
             _ => {
-                let _ = print_dix_diff(&PathBuf::from(CURRENT_PROFILE), &target_profile);
+                let base = self
+                    .common
+                    .diff_base(PathBuf::from(CURRENT_PROFILE), last_build);
+                result.diff = diff::rebuild_diff(&base, &target_profile);
             }
         }
 
-        if self.common.ask && !self.common.dry && !matches!(variant, Build) {
-            info!("Apply the config?");
-            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
+        if self.common.ask
+            && !self.common.dry
+            && !matches!(variant, Build)
+            && !confirm::ask("Apply the config?", &[])?
+        {
+            return Err(exit::declined("User rejected the new config"));
+        }
 
-            if !confirmation {
-                bail!("User rejected the new config");
-            }
+        let activate = matches!(variant, Switch) && !self.common.dry;
+        if activate {
+            self.common.verify_signatures(&target_profile, false)?;
+            hooks::run(Hook::PreActivate)?;
         }
 
         if matches!(variant, Switch) {
@@ -162,15 +433,17 @@ impl DarwinRebuildArgs {
                     .contains("# nix-darwin: deprecated");
 
             // Create and run the activation command with or without elevation
-            Command::new(darwin_rebuild)
-                .arg("activate")
-                .message("Activating configuration")
-                .elevate(needs_elevation)
-                .dry(self.common.dry)
-                .show_output(true)
-                .with_required_env()
-                .run()
-                .wrap_err("Darwin activation failed")?;
+            events::activation(Phase::Activation, "switch", || {
+                Command::new(darwin_rebuild)
+                    .arg("activate")
+                    .message("Activating configuration")
+                    .elevate(needs_elevation)
+                    .dry(self.common.dry)
+                    .show_output(output::human())
+                    .with_required_env()
+                    .run()
+                    .wrap_err("Darwin activation failed")
+            })?;
         }
 
         // Make sure out_path is not accidentally dropped
@@ -181,7 +454,16 @@ impl DarwinRebuildArgs {
         );
         drop(out_path);
 
-        Ok(())
+        if let Some(update) = pending_update {
+            update.finish()?;
+        }
+
+        if activate {
+            hooks::run(Hook::PostActivate)?;
+        }
+
+        result.activated = activate;
+        Ok(result)
     }
 }
 
@@ -222,13 +504,29 @@ impl DarwinReplArgs {
             }
         }
 
-        Command::new("nix")
-            .arg("repl")
-            .args(target_installable.to_args())
-            .with_required_env()
-            .show_output(true)
-            .run()?;
+        repl::run(&target_installable, &self.eval, &self.extra_args, self.bare)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_launchd_plist() {
+        let plist = launchd_plist(
+            Path::new("/run/current-system/sw/bin/nh"),
+            &["darwin".to_string(), "clean".to_string(), "a&b".to_string()],
+            Duration::from_secs(7 * 86400),
+        );
 
-        Ok(())
+        assert!(plist.contains("<string>org.nix-community.nh.clean</string>"));
+        assert!(plist.contains(
+            "<array>\n    <string>/run/current-system/sw/bin/nh</string>\n    <string>darwin</string>\n    <string>clean</string>\n    <string>a&amp;b</string>\n  </array>"
+        ));
+        assert!(plist.contains("<integer>604800</integer>"));
+        assert!(plist.contains(
+            "<key>EnvironmentVariables</key>\n  <dict>\n    <key>PATH</key>\n    <string>/run/current-system/sw/bin:/nix/var/nix/profiles/default/bin:"
+        ));
     }
 }
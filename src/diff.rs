@@ -0,0 +1,536 @@
+//! Package diffs between two closures, shown before activating a new
+//! configuration and by `nh store diff-closures`.
+//!
+//! dix is built into nh and reads the Nix database directly, which needs
+//! read access to it. The built-in differ only needs `nix path-info`, and
+//! is used instead when dix fails, or when chosen with `--diff-tool`. `nvd`
+//! can be used as well if it is installed.
+//!
+//! With `--diff-format json` or `summary`, the diff is printed as a JSON
+//! object on one line or as a one line summary instead, both computed by the
+//! built-in differ. With `--json`, rebuilds put the diff in their result
+//! document instead. The closures of the last diff are kept, so that
+//! confirmation prompts can summarize it whichever tool printed it.
+
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{self, Write as _};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, OnceLock};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, eyre};
+use serde::Serialize;
+use tracing::debug;
+
+use crate::commands::Command;
+use crate::events::{self, Event, Phase};
+use crate::generations::split_name_version;
+use crate::interface::{DiffFormat, DiffTool};
+use crate::store::{self, PathInfo};
+use crate::theme::{Role, paint};
+use crate::util::format_bytes;
+
+static SETTINGS: OnceLock<(DiffTool, DiffFormat)> = OnceLock::new();
+
+/// The closures of the last diff, and their diff once the built-in differ
+/// computed it
+static LAST_DIFF: Mutex<Option<(PathBuf, PathBuf, Option<ClosureDiff>)>> = Mutex::new(None);
+
+/// Set the tool diffs are computed with and how they are printed, for the
+/// rest of the run.
+pub fn init(tool: DiffTool, format: DiffFormat) {
+    let _ = SETTINGS.set((tool, format));
+}
+
+fn settings() -> (DiffTool, DiffFormat) {
+    SETTINGS.get().copied().unwrap_or_default()
+}
+
+/// `question`, followed by a summary of the last diff if there is one.
+///
+/// The summary is computed by the built-in differ if the diff was printed by
+/// another tool.
+#[must_use]
+pub fn prompt(question: &str) -> String {
+    let Some((old, new, diff)) = LAST_DIFF.lock().ok().and_then(|last| last.clone()) else {
+        return question.to_string();
+    };
+    let diff = match diff {
+        Some(diff) => diff,
+        None => match builtin_diff(&old, &new) {
+            Ok(diff) => diff,
+            Err(err) => {
+                debug!("Failed to summarize the diff: {err:#}");
+                return question.to_string();
+            }
+        },
+    };
+    format!("{question} ({})", format_summary(&diff))
+}
+
+/// The diff of a rebuild: printed, or with `--json` computed by the built-in
+/// differ for the result document.
+pub fn rebuild_diff(old_generation: &Path, new_generation: &Path) -> Option<ClosureDiff> {
+    if !crate::json::output_enabled() {
+        let _ = print_diff(old_generation, new_generation);
+        return None;
+    }
+    events::phase(Phase::Diff, || builtin_diff(old_generation, new_generation))
+        .inspect_err(|err| debug!("Failed to compute the diff: {err:#}"))
+        .ok()
+}
+
+struct WriteFmt<W: io::Write>(W);
+
+impl<W: io::Write> fmt::Write for WriteFmt<W> {
+    fn write_str(&mut self, string: &str) -> fmt::Result {
+        self.0.write_all(string.as_bytes()).map_err(|_| fmt::Error)
+    }
+}
+
+/// Print the packages that differ between two generations, and how the
+/// size of the closure changed.
+///
+/// # Errors
+///
+/// Returns an error if the diff tool fails, which callers usually ignore
+/// since the diff is informational.
+pub fn print_diff(old_generation: &Path, new_generation: &Path) -> Result<()> {
+    if let Ok(mut last) = LAST_DIFF.lock() {
+        *last = Some((old_generation.to_owned(), new_generation.to_owned(), None));
+    }
+
+    // The diff goes to stdout, where it would corrupt the JSON document.
+    // `-q` only silences diffs meant for humans.
+    let (tool, format) = settings();
+    if crate::json::output_enabled() || (crate::output::quiet() && format != DiffFormat::Json) {
+        return Ok(());
+    }
+
+    if format != DiffFormat::Human {
+        return events::phase(Phase::Diff, || {
+            let diff = builtin_diff(old_generation, new_generation)?;
+            if format == DiffFormat::Json {
+                let document = DiffDocument {
+                    old: old_generation,
+                    new: new_generation,
+                    diff: &diff,
+                };
+                println!("{}", serde_json::to_string(&document)?);
+            } else {
+                println!("{}", format_summary(&diff));
+            }
+            Ok(())
+        });
+    }
+
+    events::phase(Phase::Diff, || match tool {
+        DiffTool::Auto => write_dix_diff(old_generation, new_generation).or_else(|err| {
+            debug!("dix failed, using the built-in differ: {err:#}");
+            write_builtin_diff(old_generation, new_generation)
+        }),
+        DiffTool::Dix => write_dix_diff(old_generation, new_generation),
+        DiffTool::Builtin => write_builtin_diff(old_generation, new_generation),
+        DiffTool::Nvd => Command::new("nvd")
+            .arg("diff")
+            .args([old_generation, new_generation])
+            .show_output(true)
+            .run()
+            .wrap_err("Failed to compare the closures with nvd"),
+    })
+}
+
+fn write_dix_diff(old_generation: &Path, new_generation: &Path) -> Result<()> {
+    let mut out = WriteFmt(io::stdout());
+
+    // Handle to the thread collecting closure size information.
+    let closure_size_handle =
+        dix::spawn_size_diff(old_generation.to_path_buf(), new_generation.to_path_buf());
+
+    let wrote = dix::write_paths_diffln(&mut out, old_generation, new_generation)
+        .map_err(|err| eyre!("{err:#}"))?;
+
+    if let Ok((size_old, size_new)) = closure_size_handle
+        .join()
+        .map_err(|_| eyre!("Failed to join closure size computation thread"))?
+    {
+        events::emit(&Event::DiffSummary {
+            old: old_generation,
+            new: new_generation,
+            closure_size_old: size_old.bytes(),
+            closure_size_new: size_new.bytes(),
+        });
+
+        if size_old == size_new {
+            println!("No version or size changes.");
+        } else {
+            if wrote > 0 {
+                println!();
+            }
+            dix::write_size_diffln(&mut out, size_old, size_new)?;
+        }
+    }
+    Ok(())
+}
+
+/// Compute the diff with the built-in differ, and keep it for prompts.
+///
+/// # Errors
+///
+/// Returns an error if the closures can't be queried.
+pub fn builtin_diff(old_generation: &Path, new_generation: &Path) -> Result<ClosureDiff> {
+    let old = store::closure_infos(old_generation)?;
+    let new = store::closure_infos(new_generation)?;
+    let diff = ClosureDiff::new(&old, &new);
+
+    events::emit(&Event::DiffSummary {
+        old: old_generation,
+        new: new_generation,
+        closure_size_old: diff.size_old as i64,
+        closure_size_new: diff.size_new as i64,
+    });
+    if let Ok(mut last) = LAST_DIFF.lock() {
+        *last = Some((
+            old_generation.to_owned(),
+            new_generation.to_owned(),
+            Some(diff.clone()),
+        ));
+    }
+
+    Ok(diff)
+}
+
+fn write_builtin_diff(old_generation: &Path, new_generation: &Path) -> Result<()> {
+    let diff = builtin_diff(old_generation, new_generation)?;
+    print!("{}", format_diff(&diff));
+    Ok(())
+}
+
+/// The diff printed with `--diff-format json`
+#[derive(Debug, Serialize)]
+struct DiffDocument<'a> {
+    old: &'a Path,
+    new: &'a Path,
+    #[serde(flatten)]
+    diff: &'a ClosureDiff,
+}
+
+/// The versions of a package in a closure, and the size of its paths.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Package {
+    pub versions: BTreeSet<String>,
+    pub size: u64,
+}
+
+/// A package that was added, removed or changed its version.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct PackageChange {
+    pub name: String,
+    /// Versions in the old closure, empty if the package was added
+    pub old_versions: Vec<String>,
+    /// Versions in the new closure, empty if the package was removed
+    pub new_versions: Vec<String>,
+    /// How much the size of the package's paths changed, in bytes
+    pub size_delta: i64,
+}
+
+/// The differences between two closures, by package.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct ClosureDiff {
+    pub added: Vec<PackageChange>,
+    pub removed: Vec<PackageChange>,
+    pub changed: Vec<PackageChange>,
+    pub size_old: u64,
+    pub size_new: u64,
+}
+
+/// The packages of a closure, by name. Paths without a version, like
+/// generated files, are packages too.
+fn packages(infos: &[PathInfo]) -> BTreeMap<String, Package> {
+    let mut packages: BTreeMap<String, Package> = BTreeMap::new();
+    for info in infos {
+        let base = info.path.rsplit('/').next().unwrap_or_default();
+        let name = base.split_once('-').map_or(base, |(_, name)| name);
+        let (pname, version) = split_name_version(name);
+
+        let package = packages.entry(pname.to_string()).or_default();
+        package.versions.extend(version.map(str::to_string));
+        package.size += info.nar_size;
+    }
+    packages
+}
+
+impl ClosureDiff {
+    #[must_use]
+    pub fn new(old: &[PathInfo], new: &[PathInfo]) -> Self {
+        let old_packages = packages(old);
+        let new_packages = packages(new);
+        let empty = Package::default();
+
+        let mut diff = Self {
+            size_old: old.iter().map(|info| info.nar_size).sum(),
+            size_new: new.iter().map(|info| info.nar_size).sum(),
+            ..Self::default()
+        };
+
+        let names: BTreeSet<&String> = old_packages.keys().chain(new_packages.keys()).collect();
+        for name in names {
+            let old = old_packages.get(name);
+            let new = new_packages.get(name);
+            let (old_package, new_package) = (old.unwrap_or(&empty), new.unwrap_or(&empty));
+
+            let change = PackageChange {
+                name: name.clone(),
+                old_versions: old_package.versions.iter().cloned().collect(),
+                new_versions: new_package.versions.iter().cloned().collect(),
+                size_delta: new_package.size as i64 - old_package.size as i64,
+            };
+            match (old, new) {
+                (None, Some(_)) => diff.added.push(change),
+                (Some(_), None) => diff.removed.push(change),
+                (Some(old), Some(new)) if old.versions != new.versions => {
+                    diff.changed.push(change);
+                }
+                _ => {}
+            }
+        }
+
+        diff
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+impl PackageChange {
+    /// How the single version of a changed package compares to the old
+    /// one, if there is just one of each.
+    #[must_use]
+    pub fn direction(&self) -> Option<Ordering> {
+        match (self.old_versions.as_slice(), self.new_versions.as_slice()) {
+            ([old], [new]) => Some(compare_versions(new, old)),
+            _ => None,
+        }
+    }
+}
+
+/// Compare versions component by component, numerically where both
+/// components are numbers.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let components =
+        |version: &str| -> Vec<String> { version.split(['.', '-']).map(str::to_string).collect() };
+    let (a, b) = (components(a), components(b));
+
+    for (x, y) in a.iter().zip(&b) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            _ => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+/// One line like `2 added, 1 removed, 12 upgraded, +120 MiB`.
+#[must_use]
+pub fn format_summary(diff: &ClosureDiff) -> String {
+    let count = |direction: Option<Ordering>| {
+        diff.changed
+            .iter()
+            .filter(|change| change.direction() == direction)
+            .count()
+    };
+    let counts = [
+        (diff.added.len(), "added"),
+        (diff.removed.len(), "removed"),
+        (count(Some(Ordering::Greater)), "upgraded"),
+        (count(Some(Ordering::Less)), "downgraded"),
+        (count(None) + count(Some(Ordering::Equal)), "changed"),
+    ];
+
+    let mut parts: Vec<String> = counts
+        .iter()
+        .filter(|(count, _)| *count > 0)
+        .map(|(count, what)| format!("{count} {what}"))
+        .collect();
+    if parts.is_empty() {
+        parts.push("no package changes".to_string());
+    }
+    parts.push(format_size_delta(
+        diff.size_new as i64 - diff.size_old as i64,
+    ));
+    parts.join(", ")
+}
+
+/// A size difference like `+12 MiB` or `-300 KiB`.
+#[must_use]
+pub fn format_size_delta(delta: i64) -> String {
+    let sign = if delta < 0 { "-" } else { "+" };
+    let bytes = delta.unsigned_abs();
+    if bytes < 1024 * 1024 {
+        format!("{sign}{} KiB", bytes / 1024)
+    } else {
+        format!("{sign}{}", format_bytes(bytes))
+    }
+}
+
+/// Human readable description of `diff`.
+#[must_use]
+pub fn format_diff(diff: &ClosureDiff) -> String {
+    let mut out = String::new();
+    if diff.is_empty() && diff.size_old == diff.size_new {
+        let _ = writeln!(out, "No version or size changes.");
+        return out;
+    }
+
+    let sections = [
+        ("Changed", &diff.changed),
+        ("Added", &diff.added),
+        ("Removed", &diff.removed),
+    ];
+    for (title, changes) in sections {
+        if changes.is_empty() {
+            continue;
+        }
+        let _ = writeln!(out, "{}", paint(title, Role::Heading));
+        for change in changes {
+            let versions = match (
+                change.old_versions.is_empty(),
+                change.new_versions.is_empty(),
+            ) {
+                (false, false) => format!(
+                    "{} → {}",
+                    change.old_versions.join(", "),
+                    change.new_versions.join(", ")
+                ),
+                (true, _) => change.new_versions.join(", "),
+                (_, true) => change.old_versions.join(", "),
+            };
+            let _ = writeln!(
+                out,
+                "  {}  {}  {}",
+                paint(&change.name, Role::Name),
+                paint(versions, Role::Value),
+                paint(format_size_delta(change.size_delta), Role::Muted)
+            );
+        }
+    }
+
+    if !diff.is_empty() {
+        let _ = writeln!(out);
+    }
+    let _ = writeln!(
+        out,
+        "Closure size: {} → {} ({})",
+        format_bytes(diff.size_old),
+        format_bytes(diff.size_new),
+        format_size_delta(diff.size_new as i64 - diff.size_old as i64)
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn infos(paths: &[(&str, u64)]) -> Vec<PathInfo> {
+        paths
+            .iter()
+            .map(|(path, size)| PathInfo {
+                path: format!("/nix/store/{path}"),
+                nar_size: size * MIB,
+                registration_time: None,
+                ultimate: false,
+                closure_size: None,
+                references: Vec::new(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_closure_diff() {
+        let old = infos(&[
+            ("aaa-firefox-129.0", 200),
+            ("bbb-glibc-2.39-52", 40),
+            ("ccc-foo-1.0", 2),
+            ("ddd-etc", 1),
+        ]);
+        let new = infos(&[
+            ("eee-firefox-130.0", 212),
+            ("bbb-glibc-2.39-52", 40),
+            ("fff-hello-2.12", 1),
+            ("ggg-etc", 1),
+        ]);
+        let diff = ClosureDiff::new(&old, &new);
+
+        assert_eq!(
+            diff.changed,
+            vec![PackageChange {
+                name: "firefox".to_string(),
+                old_versions: vec!["129.0".to_string()],
+                new_versions: vec!["130.0".to_string()],
+                size_delta: 12 * MIB as i64,
+            }]
+        );
+        assert_eq!(diff.added.len(), 1);
+        assert_eq!(diff.added[0].name, "hello");
+        assert_eq!(diff.removed.len(), 1);
+        assert_eq!(diff.removed[0].name, "foo");
+        assert_eq!(diff.size_new - diff.size_old, 11 * MIB);
+
+        assert_eq!(
+            format_diff(&diff),
+            "\
+Changed
+  firefox  129.0 → 130.0  +12 MiB
+Added
+  hello  2.12  +1 MiB
+Removed
+  foo  1.0  -2 MiB
+
+Closure size: 243 MiB → 254 MiB (+11 MiB)
+"
+        );
+    }
+
+    #[test]
+    fn test_no_changes() {
+        let closure = infos(&[("aaa-hello-2.12", 1)]);
+        let diff = ClosureDiff::new(&closure, &closure);
+        assert!(diff.is_empty());
+        assert_eq!(format_diff(&diff), "No version or size changes.\n");
+        assert_eq!(format_size_delta(-300 * 1024), "-300 KiB");
+        assert_eq!(format_summary(&diff), "no package changes, +0 KiB");
+    }
+
+    #[test]
+    fn test_format_summary() {
+        let old = infos(&[
+            ("aaa-firefox-129.0", 200),
+            ("bbb-linux-6.10.9", 100),
+            ("ccc-foo-1.0", 2),
+        ]);
+        let new = infos(&[
+            ("ddd-firefox-130.0", 212),
+            ("eee-linux-6.6.50", 100),
+            ("fff-hello-2.12", 1),
+        ]);
+        let diff = ClosureDiff::new(&old, &new);
+        assert_eq!(
+            format_summary(&diff),
+            "1 added, 1 removed, 1 upgraded, 1 downgraded, +11 MiB"
+        );
+
+        assert_eq!(compare_versions("2.39-52", "2.39-6"), Ordering::Greater);
+        assert_eq!(compare_versions("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(compare_versions("1.0", "1.0"), Ordering::Equal);
+    }
+}
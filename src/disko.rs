@@ -0,0 +1,269 @@
+//! Partitioning new machines with [disko](https://github.com/nix-community/disko)
+//! for `nh os install --disko`.
+//!
+//! The layout in `disko.devices` of the configuration is shown for
+//! confirmation, then its `system.build.diskoScript` is built and run on the
+//! target, which erases the disks, formats them and mounts them under `/mnt`.
+
+use std::collections::BTreeMap;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use serde::Deserialize;
+use tracing::{debug, info, warn};
+
+use crate::commands::{self, Command};
+use crate::events::{self, Phase};
+use crate::exit;
+use crate::installable::Installable;
+use crate::theme::{Role, paint};
+
+/// Applied to `disko.devices` to keep what the layout is shown with, since
+/// the devices also contain functions that can't be turned into JSON.
+const LAYOUT_EXPR: &str = r"devices:
+let
+  pick = builtins.intersectAttrs {
+    device = null; format = null; mountpoint = null; name = null;
+    pool = null; vg = null; size = null; type = null;
+  };
+  describe = v:
+    if builtins.isAttrs v then
+      pick v
+      // (if (v.content or null) != null then { content = describe v.content; } else { })
+      // (let children = v.partitions or v.lvs or v.datasets or null; in
+          if builtins.isAttrs children then { children = builtins.mapAttrs (_: describe) children; } else { })
+    else null;
+in builtins.mapAttrs (_: builtins.mapAttrs (_: describe)) devices";
+
+/// A disk, partition, file system or volume of a disko layout.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct Device {
+    #[serde(rename = "type")]
+    kind: Option<String>,
+    device: Option<String>,
+    format: Option<String>,
+    mountpoint: Option<String>,
+    name: Option<String>,
+    pool: Option<String>,
+    vg: Option<String>,
+    size: Option<String>,
+    content: Option<Box<Device>>,
+    children: BTreeMap<String, Device>,
+}
+
+impl Device {
+    /// What the device holds, e.g. `luks crypted -> btrfs /`.
+    fn summary(&self) -> String {
+        let kind = match self.kind.as_deref() {
+            Some("filesystem" | "nodev") => self.format.as_deref(),
+            kind => kind,
+        };
+        let mut words: Vec<&str> = kind
+            .into_iter()
+            .chain(self.pool.as_deref().or(self.vg.as_deref()))
+            .chain(self.mountpoint.as_deref())
+            .collect();
+        if self.kind.as_deref() == Some("luks") {
+            words.extend(self.name.as_deref());
+        }
+
+        let summary = words.join(" ");
+        match &self.content {
+            Some(content) => format!("{summary} -> {}", content.summary()),
+            None => summary,
+        }
+    }
+}
+
+/// Lines describing the layout, by device type and name.
+fn layout_lines(layout: &BTreeMap<String, BTreeMap<String, Device>>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for (kind, devices) in layout {
+        for (name, device) in devices {
+            let mut header = format!("{kind} {name}");
+            if let Some(path) = &device.device {
+                header.push_str(&format!(" ({path})"));
+            }
+            lines.push(header);
+
+            let (table, children) = match &device.content {
+                Some(content) if !content.children.is_empty() => {
+                    (content.kind.as_deref(), &content.children)
+                }
+                Some(content) => {
+                    lines.push(format!("  {}", content.summary()));
+                    continue;
+                }
+                None if device.children.is_empty() => {
+                    lines.push(format!("  {}", device.summary()));
+                    continue;
+                }
+                None => (None, &device.children),
+            };
+            if let Some(table) = table {
+                lines.push(format!("  {table}"));
+            }
+            for (name, child) in children {
+                let content = child.content.as_deref().unwrap_or(child);
+                lines.push(format!(
+                    "  {name:<12} {:>8}  {}",
+                    child.size.as_deref().unwrap_or(""),
+                    content.summary()
+                ));
+            }
+        }
+    }
+    lines
+}
+
+/// Show the disko layout of `config`, and after confirmation, unless `yes`
+/// is set, build its disko script and run it on `target_host`, or locally.
+pub fn run<I>(
+    config: &Installable,
+    eval_args: I,
+    target_host: Option<&str>,
+    elevate: bool,
+    yes: bool,
+) -> Result<()>
+where
+    I: IntoIterator + Clone,
+    I::Item: AsRef<std::ffi::OsStr>,
+{
+    let attribute = |path: &[&str]| {
+        let mut installable = config.clone();
+        if let Installable::Flake { attribute, .. }
+        | Installable::File { attribute, .. }
+        | Installable::Expression { attribute, .. } = &mut installable
+        {
+            attribute.extend(path.iter().map(|elem| (*elem).to_string()));
+        }
+        installable
+    };
+
+    let layout = events::phase(Phase::Eval, || {
+        let json = Command::new("nix")
+            .with_required_env()
+            .args(["eval", "--json", "--apply", LAYOUT_EXPR])
+            .args(eval_args.clone())
+            .args(attribute(&["disko", "devices"]).to_args())
+            .run_capture()?
+            .unwrap_or_default();
+        serde_json::from_str::<BTreeMap<String, BTreeMap<String, Device>>>(&json)
+            .wrap_err("Failed to evaluate disko.devices, does the configuration import disko?")
+    })?;
+    debug!(?layout);
+
+    let lines = layout_lines(&layout);
+    if lines.is_empty() {
+        bail!("The disko configuration has no devices");
+    }
+    println!("{}", paint("Disk layout:", Role::Heading));
+    for line in lines {
+        println!("{line}");
+    }
+
+    let disks: Vec<&str> = layout
+        .get("disk")
+        .into_iter()
+        .flat_map(|disks| disks.values())
+        .filter_map(|disk| disk.device.as_deref())
+        .collect();
+    let host = target_host.unwrap_or("this machine");
+    warn!(
+        "This erases everything on {} on {host}",
+        if disks.is_empty() {
+            "the disks".to_string()
+        } else {
+            disks.join(", ")
+        }
+    );
+
+    if !yes {
+        let confirmation = events::confirmation("Erase the disks?", || {
+            Ok(dialoguer::Confirm::new()
+                .with_prompt("Erase the disks?")
+                .default(false)
+                .interact()?)
+        })?;
+        if !confirmation {
+            return Err(exit::declined("User didn't erase the disks"));
+        }
+    }
+
+    events::phase(Phase::Disko, || {
+        let script = Command::new("nix")
+            .with_required_env()
+            .args(["build", "--no-link", "--print-out-paths"])
+            .args(eval_args)
+            .args(attribute(&["system", "build", "diskoScript"]).to_args())
+            .message("Building the disko script")
+            .run_capture()?
+            .unwrap_or_default()
+            .trim()
+            .to_string();
+        if script.is_empty() {
+            return Err(eyre!("Building the disko script didn't return a path"));
+        }
+
+        if let Some(target_host) = target_host {
+            Command::new("nix")
+                .args(["copy", "--to", &format!("ssh://{target_host}"), &script])
+                .message("Copying the disko script to the target")
+                .with_required_env()
+                .env("NIX_SSHOPTS", commands::nix_sshopts(target_host))
+                .run()?;
+        }
+
+        info!("Partitioning the disks of {host}");
+        Command::new(&script)
+            .ssh(target_host.map(String::from))
+            .elevate(elevate)
+            .message("Running the disko script")
+            .show_output(true)
+            .run()
+            .wrap_err("Failed to partition the disks")
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout_lines() {
+        let layout = serde_json::from_str(
+            r#"{
+              "disk": {
+                "main": {
+                  "type": "disk",
+                  "device": "/dev/nvme0n1",
+                  "content": {
+                    "type": "gpt",
+                    "children": {
+                      "ESP": {"type": "EF00", "size": "512M", "content": {"type": "filesystem", "format": "vfat", "mountpoint": "/boot"}},
+                      "root": {"size": "100%", "content": {"type": "luks", "name": "crypted", "content": {"type": "filesystem", "format": "ext4", "mountpoint": "/"}}}
+                    }
+                  }
+                }
+              },
+              "nodev": {
+                "/tmp": {"type": "nodev", "format": "tmpfs", "mountpoint": "/tmp"}
+              }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(
+            layout_lines(&layout),
+            vec![
+                "disk main (/dev/nvme0n1)",
+                "  gpt",
+                "  ESP              512M  vfat /boot",
+                "  root             100%  luks crypted -> ext4 /",
+                "nodev /tmp",
+                "  tmpfs /tmp",
+            ]
+        );
+    }
+}
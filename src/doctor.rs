@@ -0,0 +1,351 @@
+//! `nh doctor`, a diagnosis of the environment nh runs in.
+//!
+//! Every check is independent and reports pass, warn or fail along with a
+//! hint on how to fix the problem. Checks never abort the run, so that a
+//! missing `nix` binary still results in a useful report.
+
+use std::env;
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+
+use crate::checks::{self, DEFAULT_SUBSTITUTER, StoreInfo, SubstituterStatus};
+use crate::config::Config;
+use crate::interface::DoctorArgs;
+use crate::theme::{Role, paint};
+use crate::util;
+
+/// Free space below which a filesystem is reported as failing
+const MIN_FREE_NIX: u64 = 1024 * 1024 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Debug)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn pass(name: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Pass,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn warn(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Warn,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn fail(name: &'static str, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Fail,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+
+    fn print(&self) {
+        let status = match self.status {
+            Status::Pass => paint("PASS", Role::Success),
+            Status::Warn => paint("WARN", Role::Warning),
+            Status::Fail => paint("FAIL", Role::Error),
+        };
+        println!("[{status}] {}: {}", self.name, self.message);
+        if let Some(hint) = &self.hint {
+            println!("       {hint}");
+        }
+    }
+}
+
+fn check_nix_version() -> Check {
+    const NAME: &str = "nix version";
+
+    let version = match util::get_nix_version() {
+        Ok(version) => version,
+        Err(err) => {
+            return Check::fail(
+                NAME,
+                format!("couldn't run nix: {err}"),
+                "Install Nix or Lix and make sure `nix` is in $PATH",
+            );
+        }
+    };
+    let variant = util::get_nix_variant();
+    let version = util::nix_version_number(&version);
+
+    match checks::nix_version_warning() {
+        Ok(None) => Check::pass(NAME, format!("{variant} {version}")),
+        Ok(Some(warning)) => Check::warn(
+            NAME,
+            warning.trim_start_matches("Warning: ").to_string(),
+            "Upgrade to the version in the current NixOS stable release or newer",
+        ),
+        Err(err) => Check::warn(
+            NAME,
+            format!("{variant} {version} ({err})"),
+            "Report this as a bug",
+        ),
+    }
+}
+
+fn check_experimental_features() -> Check {
+    const NAME: &str = "experimental features";
+
+    let required = checks::flake_features();
+    match util::get_missing_experimental_features(&required) {
+        Ok(_) if required.is_empty() => Check::pass(NAME, "none required"),
+        Ok(missing) if missing.is_empty() => {
+            Check::pass(NAME, format!("{} enabled", required.join(", ")))
+        }
+        Ok(missing) => Check::warn(
+            NAME,
+            format!("{} not enabled", missing.join(", ")),
+            format!(
+                "If you use flakes, {}",
+                checks::nix_setting_snippet(
+                    "experimental-features",
+                    &missing.iter().map(String::as_str).collect::<Vec<_>>()
+                )
+            ),
+        ),
+        Err(err) => Check::fail(NAME, err.to_string(), "Make sure `nix config show` works"),
+    }
+}
+
+fn check_daemon() -> Vec<Check> {
+    let Some(info) = StoreInfo::query() else {
+        return vec![Check::fail(
+            "nix daemon",
+            "the store is not reachable",
+            "Check that nix-daemon.service (or nix-daemon on macOS) is running",
+        )];
+    };
+
+    let daemon = Check::pass(
+        "nix daemon",
+        format!(
+            "reachable{}",
+            info.version
+                .as_ref()
+                .map(|version| format!(", version {version}"))
+                .unwrap_or_default()
+        ),
+    );
+
+    let trusted = match info.is_trusted() {
+        Some(true) => Check::pass("trusted user", "the current user is trusted"),
+        Some(false) => Check::warn(
+            "trusted user",
+            "the current user is not trusted, so --builders, --build-host and extra substituters won't work",
+            format!("To fix it, {}", checks::trusted_user_snippet()),
+        ),
+        None => Check::warn(
+            "trusted user",
+            "unknown",
+            "Your Nix version doesn't report whether you are trusted",
+        ),
+    };
+
+    vec![daemon, trusted]
+}
+
+fn check_substituters() -> Vec<Check> {
+    const NAME: &str = "substituter";
+
+    let probes = checks::probe_substituters();
+    if probes.is_empty() {
+        return vec![Check::warn(
+            NAME,
+            "no HTTP substituters configured",
+            format!(
+                "Add {DEFAULT_SUBSTITUTER} to `substituters` unless you want to build everything"
+            ),
+        )];
+    }
+
+    probes
+        .into_iter()
+        .map(|probe| match probe.status {
+            SubstituterStatus::Reachable(elapsed) => Check::pass(
+                NAME,
+                format!("{} is reachable ({} ms)", probe.url, elapsed.as_millis()),
+            ),
+            SubstituterStatus::Unauthorized(code) => Check::warn(
+                NAME,
+                format!("{} denied access (HTTP {code})", probe.url),
+                "Add credentials for it to the file set in `netrc-file`",
+            ),
+            SubstituterStatus::Unreachable(reason) if probe.is_default() => Check::fail(
+                NAME,
+                format!("{} is unreachable: {reason}", probe.url),
+                "Without it, Nix builds every package from source. Check your network and proxy settings",
+            ),
+            SubstituterStatus::Unreachable(reason) => Check::warn(
+                NAME,
+                format!("{} is unreachable: {reason}", probe.url),
+                "Check your network connection, or remove the substituter",
+            ),
+        })
+        .collect()
+}
+
+/// Warn at five times the failure threshold, so there's time to clean up.
+fn disk_status(free: u64, minimum: u64) -> Status {
+    if free < minimum {
+        Status::Fail
+    } else if free < minimum.saturating_mul(5) {
+        Status::Warn
+    } else {
+        Status::Pass
+    }
+}
+
+fn check_disk_space(path: &'static str, minimum: u64) -> Option<Check> {
+    const NAME: &str = "disk space";
+
+    let free = util::free_space(Path::new(path))?;
+    let message = format!("{} free on {path}", util::format_bytes(free));
+
+    Some(match disk_status(free, minimum) {
+        Status::Pass => Check::pass(NAME, message),
+        Status::Warn => Check::warn(NAME, message, "Consider running `nh clean all` soon"),
+        Status::Fail => Check::fail(NAME, message, "Run `nh clean all` to free up space"),
+    })
+}
+
+fn check_tools() -> Vec<Check> {
+    let mut results = vec![Check::pass("dix", "built into nh")];
+
+    for (tool, hint) in [
+        ("nom", "Install nix-output-monitor for nicer build output"),
+        ("nvd", "Optional, used with --diff-tool nvd"),
+    ] {
+        results.push(match which::which(tool) {
+            Ok(path) => Check::pass("tool", format!("{tool} found at {}", path.display())),
+            Err(_) => Check::warn("tool", format!("{tool} not found"), hint),
+        });
+    }
+
+    results
+}
+
+fn check_ssh_agent() -> Check {
+    const NAME: &str = "ssh agent";
+
+    if env::var_os("SSH_AUTH_SOCK").is_none() {
+        return Check::warn(
+            NAME,
+            "SSH_AUTH_SOCK is not set",
+            "Only needed for --target-host and --build-host; start ssh-agent to avoid repeated prompts",
+        );
+    }
+
+    let has_keys = std::process::Command::new("ssh-add")
+        .arg("-l")
+        .output()
+        .is_ok_and(|output| output.status.success());
+
+    if has_keys {
+        Check::pass(NAME, "running with keys loaded")
+    } else {
+        Check::warn(
+            NAME,
+            "no keys loaded",
+            "Run `ssh-add` before deploying to remote hosts",
+        )
+    }
+}
+
+fn check_env() -> Vec<Check> {
+    let mut results: Vec<Check> = env::vars()
+        .filter(|(key, _)| key.starts_with("NH_"))
+        .map(|(key, value)| Check::pass("environment", format!("{key}={value}")))
+        .collect();
+
+    if env::var_os("FLAKE").is_some() {
+        results.push(Check::warn(
+            "environment",
+            "FLAKE is set",
+            "FLAKE is deprecated, use NH_FLAKE instead",
+        ));
+    }
+
+    results
+}
+
+/// Run all checks.
+#[must_use]
+fn check_config() -> Check {
+    const NAME: &str = "config";
+
+    match Config::load() {
+        Ok(_) => Check::pass(NAME, "valid"),
+        Err(err) => Check::fail(
+            NAME,
+            format!("{err:#}"),
+            "Fix the file, nh uses the defaults until then",
+        ),
+    }
+}
+
+pub fn diagnose() -> Vec<Check> {
+    let mut results = vec![
+        check_config(),
+        check_nix_version(),
+        check_experimental_features(),
+    ];
+    results.extend(check_daemon());
+    results.extend(check_substituters());
+    results.extend(check_disk_space("/nix", MIN_FREE_NIX));
+    results.extend(check_disk_space("/boot", checks::MIN_FREE_BOOT));
+    results.extend(check_tools());
+    results.push(check_ssh_agent());
+    results.extend(check_env());
+    results
+}
+
+impl DoctorArgs {
+    pub fn run(&self) -> Result<()> {
+        let results = diagnose();
+        for check in &results {
+            check.print();
+        }
+
+        let failed = results.iter().filter(|c| c.status == Status::Fail).count();
+        if failed > 0 {
+            bail!("{failed} checks failed");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_disk_status() {
+        assert_eq!(disk_status(MIN_FREE_NIX - 1, MIN_FREE_NIX), Status::Fail);
+        assert_eq!(disk_status(MIN_FREE_NIX, MIN_FREE_NIX), Status::Warn);
+        assert_eq!(disk_status(MIN_FREE_NIX * 5, MIN_FREE_NIX), Status::Pass);
+    }
+}
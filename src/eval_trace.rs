@@ -0,0 +1,193 @@
+//! Folding of nix evaluation traces.
+//!
+//! Errors from the module system come with dozens of frames inside nixpkgs,
+//! like `lib/modules.nix` merging definitions, around the few frames in the
+//! user's configuration. Runs of frames inside nixpkgs are folded into a
+//! single line when nh shows nix's errors, unless `-vv` is passed.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::util::strip_colors;
+
+static EXPAND: AtomicBool = AtomicBool::new(false);
+
+/// Files of nixpkgs, relative to its source, whose frames are folded
+const NIXPKGS_INTERNAL: [&str; 15] = [
+    "lib/attrsets.nix",
+    "lib/customisation.nix",
+    "lib/fixed-points.nix",
+    "lib/lists.nix",
+    "lib/meta.nix",
+    "lib/modules.nix",
+    "lib/options.nix",
+    "lib/strings.nix",
+    "lib/trivial.nix",
+    "lib/types.nix",
+    "nixos/lib/eval-config.nix",
+    "nixos/modules/",
+    "pkgs/build-support/",
+    "pkgs/stdenv/",
+    "pkgs/top-level/",
+];
+
+/// Show traces in full for the rest of the run.
+pub fn init(expand: bool) {
+    EXPAND.store(expand, Ordering::Relaxed);
+}
+
+/// Whether a frame of a trace is located inside nixpkgs or nix itself.
+fn is_internal(frame: &[&str]) -> bool {
+    // `at <file>:<line>:<column>:`, or `whose name attribute is located at`
+    // for derivations
+    let Some(location) = frame
+        .iter()
+        .map(|line| strip_colors(line))
+        .find_map(|line| {
+            let line = line.trim_start();
+            line.strip_prefix("at ")
+                .or_else(|| line.split_once(" located at ").map(|(_, at)| at))
+                .map(ToString::to_string)
+        })
+    else {
+        return false;
+    };
+    location.starts_with("<nix/")
+        || NIXPKGS_INTERNAL.iter().any(|file| {
+            location.contains(&format!("-source/{file}")) || location.contains(&format!("»/{file}"))
+        })
+}
+
+/// The line replacing `hidden` frames.
+fn folded_line(indent: &str, hidden: usize) -> String {
+    if hidden == 1 {
+        format!("{indent}… 1 frame inside nixpkgs, show it with -vv")
+    } else {
+        format!("{indent}… {hidden} frames inside nixpkgs, show them with -vv")
+    }
+}
+
+/// `text` with each run of frames inside nixpkgs folded into one line.
+#[must_use]
+pub fn fold(text: &str) -> String {
+    if EXPAND.load(Ordering::Relaxed) || !text.contains('…') {
+        return text.to_string();
+    }
+    fold_frames(text)
+}
+
+fn fold_frames(text: &str) -> String {
+    let lines: Vec<&str> = text.lines().collect();
+    let mut folded = Vec::with_capacity(lines.len());
+    let mut hidden = 0;
+    let mut indent = "";
+
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        if !strip_colors(line).trim_start().starts_with('…') {
+            if hidden > 0 && !line.trim().is_empty() {
+                folded.push(folded_line(indent, hidden));
+                folded.push(String::new());
+                hidden = 0;
+            }
+            if hidden == 0 {
+                folded.push(line.to_string());
+            }
+            i += 1;
+            continue;
+        }
+
+        // A frame goes on until the blank line before the next one
+        let end = lines[i..]
+            .iter()
+            .position(|line| line.trim().is_empty())
+            .map_or(lines.len(), |len| i + len);
+        let frame = &lines[i..end];
+        if is_internal(frame) {
+            indent = &line[..line.len() - line.trim_start().len()];
+            hidden += 1;
+        } else {
+            if hidden > 0 {
+                folded.push(folded_line(indent, hidden));
+                folded.push(String::new());
+                hidden = 0;
+            }
+            folded.extend(frame.iter().map(ToString::to_string));
+        }
+        i = end;
+    }
+
+    let mut folded = folded.join("\n");
+    if text.ends_with('\n') {
+        folded.push('\n');
+    }
+    folded
+}
+
+/// A line of nix's `internal-json` log with the trace of an error message
+/// folded, for nom to show.
+#[must_use]
+pub fn fold_log_line(line: &str) -> Option<String> {
+    if EXPAND.load(Ordering::Relaxed) || !line.contains('…') {
+        return None;
+    }
+    let mut value: serde_json::Value = serde_json::from_str(line.strip_prefix("@nix ")?).ok()?;
+    let msg = value.get("msg")?.as_str()?;
+    value["msg"] = fold_frames(msg).into();
+    Some(format!("@nix {value}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fold_frames() {
+        let trace = "error:
+       … while calling the 'head' builtin
+         at /nix/store/aaa-source/lib/attrsets.nix:1205:11:
+         1204|         || pred here (elemAt values 1) (head values) then
+         1205|           head values
+             |           ^
+
+       … while evaluating the attribute 'value'
+         at /nix/store/aaa-source/lib/modules.nix:809:9:
+          808|     in warnDeprecation opt //
+          809|       { value = addErrorContext \"while evaluating the option `${showOption loc}':\" value;
+             |         ^
+
+       … from call site
+         at /nix/store/bbb-source/hosts/web/default.nix:12:3:
+           11|   services.nginx = {
+           12|     enable = 1;
+             |   ^
+
+       error: A definition for option `services.nginx.enable' is not of type `boolean'.
+";
+        assert_eq!(
+            fold_frames(trace),
+            "error:
+       … 2 frames inside nixpkgs, show them with -vv
+
+       … from call site
+         at /nix/store/bbb-source/hosts/web/default.nix:12:3:
+           11|   services.nginx = {
+           12|     enable = 1;
+             |   ^
+
+       error: A definition for option `services.nginx.enable' is not of type `boolean'.
+"
+        );
+
+        let folded = fold_log_line(&format!(
+            "@nix {}",
+            serde_json::json!({"action": "msg", "level": 0, "msg": trace})
+        ))
+        .unwrap();
+        assert!(folded.contains("2 frames inside nixpkgs"));
+        assert_eq!(
+            fold_log_line("@nix {\"action\":\"msg\",\"msg\":\"hi\"}"),
+            None
+        );
+    }
+}
@@ -0,0 +1,392 @@
+//! Machine-readable event stream.
+//!
+//! When `--json-events <FD>` is passed, nh writes one JSON object per line to
+//! the given file descriptor for each notable step of a run (phases starting
+//! and finishing, diffs, confirmation prompts, activation results). This lets
+//! GUIs and deploy wrappers follow progress without scraping log output.
+//!
+//! Emitting events is a no-op unless the stream has been initialized.
+//!
+//! sudo closes all file descriptors but the standard ones, so when nh
+//! elevates itself, the elevated nh writes its events to a FIFO instead,
+//! which the unelevated nh relays to the descriptor.
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::os::fd::{BorrowedFd, FromRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::process::ExitStatus;
+use std::sync::{Mutex, OnceLock};
+use std::thread;
+use std::time::Instant;
+
+use chrono::Utc;
+use color_eyre::Result;
+use color_eyre::eyre::{Context, eyre};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info_span};
+
+use crate::exit::Failure;
+use crate::timings;
+
+static SINK: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// Variable giving an elevated nh the FIFO to write its events to
+pub const RELAY_VAR: &str = "NH_JSON_EVENTS_RELAY";
+
+thread_local! {
+    /// The phase running on this thread, which progress is reported for
+    static CURRENT_PHASE: Cell<Option<Phase>> = const { Cell::new(None) };
+}
+
+/// A phase of an nh run, used to group related events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    Update,
+    /// Evaluating the configuration to check that it exists
+    Eval,
+    Build,
+    Diff,
+    Copy,
+    /// Pushing the built closure to a binary cache
+    Push,
+    /// Partitioning and formatting the disks with disko
+    Disko,
+    /// Verifying the signatures of the closure before activating it
+    Verify,
+    Activation,
+    Bootloader,
+    Gc,
+    /// Running the health check of a deployed host
+    HealthCheck,
+}
+
+impl Phase {
+    #[must_use]
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Update => "update",
+            Self::Eval => "eval",
+            Self::Build => "build",
+            Self::Diff => "diff",
+            Self::Copy => "copy",
+            Self::Push => "push",
+            Self::Disko => "disko",
+            Self::Verify => "verify",
+            Self::Activation => "activation",
+            Self::Bootloader => "bootloader",
+            Self::Gc => "gc",
+            Self::HealthCheck => "health-check",
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    PhaseStarted {
+        phase: Phase,
+    },
+    PhaseFinished {
+        phase: Phase,
+        success: bool,
+        elapsed_ms: u128,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    BuildOutput {
+        out_path: &'a Path,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        rev: Option<&'a str>,
+    },
+    DiffSummary {
+        old: &'a Path,
+        new: &'a Path,
+        closure_size_old: i64,
+        closure_size_new: i64,
+    },
+    ConfirmationRequested {
+        prompt: &'a str,
+    },
+    ConfirmationAnswered {
+        accepted: bool,
+    },
+    ActivationResult {
+        action: &'a str,
+        success: bool,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        error: Option<String>,
+    },
+    /// How far the current phase got: derivations built, paths copied or
+    /// paths deleted, out of those nix expects
+    Progress {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        phase: Option<Phase>,
+        done: u64,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        expected: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_done: Option<u64>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        bytes_expected: Option<u64>,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    timestamp: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// Start writing events to the given file descriptor.
+///
+/// The descriptor must already be open for writing, e.g. `--json-events 3`
+/// together with `3>events.json` in the calling shell.
+pub fn init(fd: RawFd) -> Result<()> {
+    // Make sure the descriptor is actually open before taking ownership of it,
+    // otherwise dropping the `File` would close a descriptor we don't own.
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    nix::fcntl::fcntl(borrowed, nix::fcntl::FcntlArg::F_GETFD).wrap_err(format!(
+        "File descriptor {fd} passed to --json-events is not open"
+    ))?;
+
+    let file = unsafe { File::from_raw_fd(fd) };
+    SINK.set(Mutex::new(file))
+        .map_err(|_| eyre!("Event stream was already initialized"))?;
+
+    debug!("Writing JSON events to fd {fd}");
+    Ok(())
+}
+
+/// Start writing events to the FIFO `path` of the nh that elevated this one,
+/// see [`relay`].
+pub fn init_relay(path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .wrap_err(format!("Failed to open the event relay {}", path.display()))?;
+    SINK.set(Mutex::new(file))
+        .map_err(|_| eyre!("Event stream was already initialized"))?;
+
+    debug!("Writing JSON events to {}", path.display());
+    Ok(())
+}
+
+/// Whether events are written anywhere.
+#[must_use]
+pub fn enabled() -> bool {
+    SINK.get().is_some()
+}
+
+/// Run the elevated nh `command` gives for a FIFO, passing it the FIFO in
+/// [`RELAY_VAR`], and relay the events it writes there to the stream.
+pub fn relay(command: impl FnOnce(&Path) -> std::process::Command) -> Result<ExitStatus> {
+    let dir = tempfile::tempdir()?;
+    let fifo = dir.path().join("events");
+    nix::unistd::mkfifo(
+        &fifo,
+        nix::sys::stat::Mode::S_IRUSR | nix::sys::stat::Mode::S_IWUSR,
+    )?;
+
+    // Opening either end of a FIFO blocks until the other one is open, which
+    // the elevated nh may never do. Holding a writer until it exits also
+    // keeps the relay from seeing the end of the stream early
+    let reader = OpenOptions::new()
+        .read(true)
+        .custom_flags(nix::fcntl::OFlag::O_NONBLOCK.bits())
+        .open(&fifo)?;
+    let writer = OpenOptions::new().write(true).open(&fifo)?;
+    nix::fcntl::fcntl(
+        &reader,
+        nix::fcntl::FcntlArg::F_SETFL(nix::fcntl::OFlag::empty()),
+    )?;
+
+    let relay = thread::spawn(move || {
+        for line in BufReader::new(reader).split(b'\n') {
+            let Ok(mut line) = line else { break };
+            line.push(b'\n');
+            write(&line);
+        }
+    });
+
+    // An interrupt from the terminal reaches the elevated nh too, which
+    // handles it, and nh has to outlive it to relay its last events
+    extern "C" fn interrupted(_: nix::libc::c_int) {}
+    // SAFETY: the handler does nothing, and executing the elevated nh resets
+    // it to the default there
+    unsafe {
+        nix::sys::signal::signal(
+            nix::sys::signal::Signal::SIGINT,
+            nix::sys::signal::SigHandler::Handler(interrupted),
+        )?;
+    }
+
+    let status = command(&fifo).status();
+    drop(writer);
+    let _ = relay.join();
+    Ok(status?)
+}
+
+/// Write a single event to the stream, if enabled.
+///
+/// Failing to write an event never aborts the run, as the consumer going
+/// away should not break a system switch halfway through.
+pub fn emit(event: &Event) {
+    if !enabled() {
+        return;
+    }
+
+    let envelope = Envelope {
+        timestamp: Utc::now().to_rfc3339(),
+        event,
+    };
+
+    let Ok(mut line) = serde_json::to_vec(&envelope) else {
+        debug!(?event, "Failed to serialize event");
+        return;
+    };
+    line.push(b'\n');
+    write(&line);
+}
+
+/// Write a line to the stream, if enabled.
+fn write(line: &[u8]) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+
+    if let Ok(mut file) = sink.lock() {
+        if let Err(err) = file.write_all(line).and_then(|()| file.flush()) {
+            debug!(?err, "Failed to write event");
+        }
+    }
+}
+
+/// Run `f` as the given phase inside a tracing span, emitting
+/// `phase_started` and `phase_finished` events around it and recording its
+/// duration for `--timings`.
+pub fn phase<T>(phase: Phase, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    emit(&Event::PhaseStarted { phase });
+    let start = Instant::now();
+
+    let outer = CURRENT_PHASE.replace(Some(phase));
+    let res = info_span!("phase", phase = phase.name())
+        .in_scope(f)
+        .wrap_err(Failure::from(phase));
+    CURRENT_PHASE.set(outer);
+
+    let elapsed = start.elapsed();
+    timings::record(phase, elapsed);
+    emit(&Event::PhaseFinished {
+        phase,
+        success: res.is_ok(),
+        elapsed_ms: elapsed.as_millis(),
+        error: res.as_ref().err().map(|err| format!("{err:#}")),
+    });
+
+    res
+}
+
+/// Run an activation step as the given phase, additionally emitting an
+/// `activation_result` event for `action` (e.g. `switch`, `boot`).
+pub fn activation<T>(phase_kind: Phase, action: &str, f: impl FnOnce() -> Result<T>) -> Result<T> {
+    let res = phase(phase_kind, f);
+
+    emit(&Event::ActivationResult {
+        action,
+        success: res.is_ok(),
+        error: res.as_ref().err().map(|err| format!("{err:#}")),
+    });
+
+    res
+}
+
+/// Emit how far the current phase got, `done` out of `expected` items and
+/// `bytes` done out of those expected, if known.
+pub fn progress(done: u64, expected: Option<u64>, bytes: Option<(u64, u64)>) {
+    if !enabled() {
+        return;
+    }
+    emit(&Event::Progress {
+        phase: CURRENT_PHASE.get(),
+        done,
+        expected,
+        bytes_done: bytes.map(|(done, _)| done),
+        bytes_expected: bytes.map(|(_, expected)| expected),
+    });
+}
+
+/// Emit the user's answer to a confirmation prompt and pass it through.
+pub fn confirmation(prompt: &str, answer: impl FnOnce() -> Result<bool>) -> Result<bool> {
+    emit(&Event::ConfirmationRequested { prompt });
+    let accepted = answer()?;
+    emit(&Event::ConfirmationAnswered { accepted });
+    Ok(accepted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_serialization() {
+        let event = Event::PhaseFinished {
+            phase: Phase::Build,
+            success: false,
+            elapsed_ms: 42,
+            error: Some("boom".to_string()),
+        };
+        let envelope = Envelope {
+            timestamp: "2024-01-01T00:00:00+00:00".to_string(),
+            event: &event,
+        };
+
+        let value = serde_json::to_value(&envelope).unwrap();
+        assert_eq!(value["event"], "phase_finished");
+        assert_eq!(value["phase"], "build");
+        assert_eq!(value["success"], false);
+        assert_eq!(value["elapsed_ms"], 42);
+        assert_eq!(value["error"], "boom");
+        assert_eq!(value["timestamp"], "2024-01-01T00:00:00+00:00");
+    }
+
+    #[test]
+    fn test_optional_fields_are_skipped() {
+        let event = Event::ActivationResult {
+            action: "switch",
+            success: true,
+            error: None,
+        };
+
+        let value = serde_json::to_value(&event).unwrap();
+        assert_eq!(value["event"], "activation_result");
+        assert!(value.get("error").is_none());
+    }
+
+    #[test]
+    fn test_relay() {
+        let status = relay(|fifo| {
+            let mut cmd = std::process::Command::new("sh");
+            cmd.args(["-c", r#"echo '{}' > "$0"; exit 3"#]).arg(fifo);
+            cmd
+        })
+        .unwrap();
+        assert_eq!(status.code(), Some(3));
+
+        // An elevated nh that never opens the FIFO doesn't hang the relay
+        let status = relay(|_| std::process::Command::new("true")).unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_emit_without_sink_is_noop() {
+        // No sink is initialized in tests, this must not panic
+        emit(&Event::ConfirmationAnswered { accepted: true });
+        let res = phase(Phase::Diff, || Ok(1));
+        assert_eq!(res.unwrap(), 1);
+    }
+}
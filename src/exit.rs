@@ -0,0 +1,144 @@
+//! Exit codes telling wrappers and CI what kind of failure ended a run.
+//!
+//! | Code | Meaning                                   |
+//! |------|-------------------------------------------|
+//! | 0    | Success                                   |
+//! | 1    | Any other error                           |
+//! | 2    | Invalid command line arguments            |
+//! | 3    | Environment check failed (Nix version, experimental features, disk space) |
+//! | 4    | The user declined a confirmation prompt   |
+//! | 5    | Evaluating the configuration failed       |
+//! | 6    | Updating flake inputs failed              |
+//! | 7    | Build failed                              |
+//! | 8    | Computing the diff failed                 |
+//! | 9    | Copying to or from a remote host failed   |
+//! | 10   | Activation failed                         |
+//! | 11   | Installing the bootloader failed          |
+//! | 12   | Garbage collection failed                 |
+//! | 13   | Pushing to a binary cache failed          |
+//! | 14   | Partitioning the disks with disko failed  |
+//! | 15   | The closure isn't signed by a trusted key |
+//! | 16   | A deployed host failed its health check   |
+//!
+//! Errors are tagged by wrapping them with a [`Failure`], which
+//! [`events::phase`](crate::events::phase) does for every phase.
+
+use color_eyre::Report;
+use color_eyre::eyre::eyre;
+use thiserror::Error;
+
+use crate::events::Phase;
+
+/// Exit code for errors that weren't tagged with a [`Failure`]
+pub const GENERIC: i32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Error)]
+pub enum Failure {
+    #[error("Environment check failed")]
+    Environment,
+    #[error("Cancelled")]
+    Declined,
+    #[error("Evaluation failed")]
+    Eval,
+    #[error("Updating flake inputs failed")]
+    Update,
+    #[error("Build failed")]
+    Build,
+    #[error("Computing the diff failed")]
+    Diff,
+    #[error("Copying to the remote host failed")]
+    Copy,
+    #[error("Activation failed")]
+    Activation,
+    #[error("Installing the bootloader failed")]
+    Bootloader,
+    #[error("Garbage collection failed")]
+    Gc,
+    #[error("Pushing to the binary cache failed")]
+    Push,
+    #[error("Partitioning the disks failed")]
+    Disko,
+    #[error("Verifying the signatures of the closure failed")]
+    Signatures,
+    #[error("The health check of the host failed")]
+    HealthCheck,
+}
+
+impl Failure {
+    #[must_use]
+    pub const fn code(self) -> i32 {
+        match self {
+            Self::Environment => 3,
+            Self::Declined => 4,
+            Self::Eval => 5,
+            Self::Update => 6,
+            Self::Build => 7,
+            Self::Diff => 8,
+            Self::Copy => 9,
+            Self::Activation => 10,
+            Self::Bootloader => 11,
+            Self::Gc => 12,
+            Self::Push => 13,
+            Self::Disko => 14,
+            Self::Signatures => 15,
+            Self::HealthCheck => 16,
+        }
+    }
+}
+
+impl From<Phase> for Failure {
+    fn from(phase: Phase) -> Self {
+        match phase {
+            Phase::Update => Self::Update,
+            Phase::Eval => Self::Eval,
+            Phase::Build => Self::Build,
+            Phase::Diff => Self::Diff,
+            Phase::Copy => Self::Copy,
+            Phase::Push => Self::Push,
+            Phase::Disko => Self::Disko,
+            Phase::Verify => Self::Signatures,
+            Phase::Activation => Self::Activation,
+            Phase::Bootloader => Self::Bootloader,
+            Phase::Gc => Self::Gc,
+            Phase::HealthCheck => Self::HealthCheck,
+        }
+    }
+}
+
+/// Error for the user answering no to a confirmation prompt.
+pub fn declined(message: &str) -> Report {
+    eyre!("{message}").wrap_err(Failure::Declined)
+}
+
+/// Exit code for `err`, from the outermost [`Failure`] it was wrapped with.
+#[must_use]
+pub fn code(err: &Report) -> i32 {
+    err.downcast_ref::<Failure>()
+        .map_or(GENERIC, |failure| failure.code())
+}
+
+#[cfg(test)]
+mod tests {
+    use color_eyre::eyre::Context;
+
+    use super::*;
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(code(&eyre!("something broke")), GENERIC);
+
+        let build: color_eyre::Result<()> = Err(eyre!("Command exited with status 1"));
+        let err = build.wrap_err(Failure::Build).unwrap_err();
+        assert_eq!(code(&err), 7);
+
+        // Context added on top doesn't hide the failure
+        let err = err.wrap_err("Failed to build host foo");
+        assert_eq!(code(&err), 7);
+
+        // The outermost failure wins
+        let err = err.wrap_err(Failure::Activation);
+        assert_eq!(code(&err), 10);
+
+        assert_eq!(code(&declined("User rejected the new config")), 4);
+    }
+}
@@ -0,0 +1,233 @@
+//! Skipping the build of configurations that haven't changed since they were
+//! last built.
+//!
+//! After a successful build, the fingerprint of what was built is kept in
+//! the state directory along with the result: the hash of `flake.lock`, the
+//! git tree of the flake including uncommitted changes, the attribute, the
+//! arguments given to nix and the version of nix. When the next build has
+//! the same fingerprint and its result is still in the store, nh links it
+//! instead of evaluating and building the configuration again.
+//!
+//! Only local flakes in a git repository have a fingerprint. Builds that can
+//! depend on more than the flake, with `--impure` or overridden inputs, are
+//! never skipped, and neither are builds with `--always-build`.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::commands::Command;
+use crate::generations::local_flake_dir;
+use crate::installable::Installable;
+use crate::interface::CommonRebuildArgs;
+use crate::state;
+
+const CACHE_FILE: &str = "build-fingerprints.json";
+
+/// What a build of a configuration depends on.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Fingerprint {
+    /// The installable, with the flake as an absolute path
+    installable: String,
+    /// Git hash of `flake.lock`, if the flake has one
+    lock: Option<String>,
+    /// Git tree of the flake, with uncommitted changes to tracked files
+    tree: String,
+    /// Arguments passed to nix for the build
+    args: Vec<String>,
+    nix_version: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedBuild {
+    fingerprint: Fingerprint,
+    out_path: PathBuf,
+}
+
+/// Last successful build of each installable
+type Cache = BTreeMap<String, CachedBuild>;
+
+fn output(program: &str, dir: Option<&Path>, args: &[&str]) -> Option<String> {
+    let mut cmd = process::Command::new(program);
+    if let Some(dir) = dir {
+        cmd.arg("-C").arg(dir);
+    }
+    cmd.args(args)
+        .stderr(process::Stdio::null())
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+impl Fingerprint {
+    /// The fingerprint of building `installable` with `args`, if it is a
+    /// local flake in a git repository.
+    #[must_use]
+    pub fn new(installable: &Installable, args: Vec<String>) -> Option<Self> {
+        let Installable::Flake {
+            reference,
+            attribute,
+        } = installable
+        else {
+            return None;
+        };
+        let dir = local_flake_dir(reference)?.canonicalize().ok()?;
+        let git = |args: &[&str]| output("git", Some(&dir), args);
+
+        // `git stash create` commits the working tree without touching it,
+        // and prints nothing if there are no changes. If it fails, the
+        // changes are unknown and the build can't be skipped.
+        let commit = git(&["stash", "create"])?;
+        let commit = if commit.is_empty() { "HEAD" } else { &commit };
+        let tree = git(&["rev-parse", &format!("{commit}^{{tree}}")])?;
+        let lock = git(&["hash-object", "flake.lock"]);
+        let nix_version = output("nix", None, &["--version"])?;
+
+        let installable = Installable::Flake {
+            reference: dir.to_string_lossy().into_owned(),
+            attribute: attribute.clone(),
+        }
+        .to_args()
+        .join(" ");
+
+        Some(Self {
+            installable,
+            lock,
+            tree,
+            args,
+            nix_version,
+        })
+    }
+
+    /// The result of the last build with this fingerprint, if it is still
+    /// in the store.
+    #[must_use]
+    pub fn cached_out_path(&self) -> Option<PathBuf> {
+        let cache: Cache = state::load(CACHE_FILE)
+            .inspect_err(|err| debug!("Ignoring the build cache: {err:#}"))
+            .ok()?;
+        let cached = cache.get(&self.installable)?;
+
+        (cached.fingerprint == *self && cached.out_path.exists()).then(|| cached.out_path.clone())
+    }
+
+    /// Remember that building this fingerprint resulted in `out_path`.
+    ///
+    /// Entries of results that are no longer in the store are dropped at
+    /// the same time.
+    pub fn record(&self, out_path: &Path) -> Result<()> {
+        let out_path = out_path.canonicalize()?;
+
+        let mut cache: Cache = state::load(CACHE_FILE).unwrap_or_default();
+        cache.retain(|_, cached| cached.out_path.exists());
+        cache.insert(
+            self.installable.clone(),
+            CachedBuild {
+                fingerprint: self.clone(),
+                out_path,
+            },
+        );
+
+        state::save(CACHE_FILE, &cache)
+    }
+}
+
+/// Point `out_link` to `out_path`, a result that is already in the store, as
+/// the build would have.
+pub fn link(out_path: &Path, out_link: &Path) -> Result<()> {
+    Command::new("nix")
+        .args(["--extra-experimental-features", "nix-command", "build"])
+        .arg("--out-link")
+        .arg(out_link)
+        .arg(out_path)
+        .message(format!(
+            "Configuration unchanged, reusing {}",
+            out_path.display()
+        ))
+        .run()
+        .wrap_err("Failed to link the previous build")
+}
+
+impl CommonRebuildArgs {
+    /// The fingerprint of building `installable` with `extra_args`, unless
+    /// the build must not be skipped.
+    #[must_use]
+    pub fn fingerprint(
+        &self,
+        installable: &Installable,
+        extra_args: &[String],
+    ) -> Option<Fingerprint> {
+        let eval = &self.eval;
+        if self.always_build
+            || self.dry
+            || self.passthrough.repair
+            || eval.impure
+            || !eval.override_input.is_empty()
+            || !eval.override_flake.is_empty()
+        {
+            return None;
+        }
+
+        let args = extra_args
+            .iter()
+            .cloned()
+            .chain(eval.generate_eval_args())
+            .chain(self.passthrough.include.iter().cloned())
+            .collect();
+        let fingerprint = Fingerprint::new(installable, args);
+        debug!(?fingerprint);
+        fingerprint
+    }
+
+    /// Build `installable` to `out_link` with `build`, or link the result of
+    /// the last build if nothing changed since. Returns whether the result
+    /// was reused.
+    pub fn build_unless_unchanged(
+        &self,
+        installable: &Installable,
+        extra_args: &[String],
+        out_link: &Path,
+        build: impl FnOnce() -> Result<()>,
+    ) -> Result<bool> {
+        let fingerprint = self.fingerprint(installable, extra_args);
+
+        if let Some(out_path) = fingerprint.as_ref().and_then(Fingerprint::cached_out_path) {
+            link(&out_path, out_link)?;
+            return Ok(true);
+        }
+
+        build()?;
+
+        if let Some(fingerprint) = fingerprint {
+            if let Err(err) = fingerprint.record(out_link) {
+                debug!("Failed to record the build fingerprint: {err:#}");
+            }
+        }
+        Ok(false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint_needs_local_flake() {
+        let remote = Installable::Flake {
+            reference: "github:owner/repo".to_string(),
+            attribute: vec!["nixosConfigurations".to_string()],
+        };
+        assert_eq!(Fingerprint::new(&remote, vec![]), None);
+
+        let store = Installable::Store {
+            path: PathBuf::from("/nix/store/aaa-nixos-system"),
+        };
+        assert_eq!(Fingerprint::new(&store, vec![]), None);
+    }
+}
@@ -0,0 +1,424 @@
+//! `nh flake`, wrappers around the flake commands of nix with nh's output,
+//! for the flake in `NH_FLAKE` or the current directory.
+//!
+//! The outputs of flakes are also listed here for completions, which can't
+//! afford to evaluate the flake on every `<TAB>`. They are cached in the
+//! state directory until the flake changes or the cache gets old.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::commands::Command;
+use crate::installable;
+use crate::interface::{
+    FlakeArchiveArgs, FlakeCommand, FlakeInitArgs, FlakeMetadataArgs, FlakeShowArgs,
+};
+use crate::lockfile::{LockFile, format_age};
+use crate::state;
+use crate::theme::{Role, paint};
+
+const CACHE_FILE: &str = "flake-outputs.json";
+
+/// How long flake outputs are cached for, if the flake doesn't change
+const CACHE_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Outputs nh builds, in the order they are shown
+const CONFIGURATIONS: [&str; 3] = [
+    "nixosConfigurations",
+    "homeConfigurations",
+    "darwinConfigurations",
+];
+
+/// Outputs of a flake, as the attribute names in each output
+pub type FlakeOutputs = BTreeMap<String, Vec<String>>;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedOutputs {
+    /// Seconds since the epoch
+    fetched: u64,
+    outputs: FlakeOutputs,
+}
+
+impl CachedOutputs {
+    /// Whether the cache is younger than [`CACHE_TTL`] and, for a local
+    /// flake, than its `flake.nix` and `flake.lock`.
+    fn is_fresh(&self, flake: &str, now: u64) -> bool {
+        if now.saturating_sub(self.fetched) > CACHE_TTL.as_secs() {
+            return false;
+        }
+
+        ["flake.nix", "flake.lock"].iter().all(|file| {
+            modified_secs(&Path::new(flake).join(file))
+                .is_none_or(|modified| modified <= self.fetched)
+        })
+    }
+}
+
+fn modified_secs(path: &Path) -> Option<u64> {
+    let modified = path.metadata().and_then(|meta| meta.modified()).ok()?;
+    Some(modified.duration_since(UNIX_EPOCH).ok()?.as_secs())
+}
+
+/// Attribute names of each output in `nix flake show --json` output.
+fn parse_flake_show(json: &str) -> Result<FlakeOutputs> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).wrap_err("Failed to parse nix flake show output")?;
+    let outputs = value
+        .as_object()
+        .ok_or_else(|| eyre!("Expected an object from nix flake show"))?;
+
+    Ok(outputs
+        .iter()
+        .map(|(output, attrs)| {
+            let names = attrs
+                .as_object()
+                .map(|attrs| attrs.keys().cloned().collect())
+                .unwrap_or_default();
+            (output.clone(), names)
+        })
+        .collect())
+}
+
+/// The outputs of `flake`. With `cached`, they come from the cache if it's
+/// still fresh, otherwise the flake is evaluated and the cache updated.
+pub fn outputs(flake: &str, cached: bool) -> Result<FlakeOutputs> {
+    // Relative paths differ between directories, so key local flakes by
+    // their absolute path
+    let key = std::fs::canonicalize(flake).map_or_else(
+        |_| flake.to_string(),
+        |path| path.to_string_lossy().into_owned(),
+    );
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+
+    let mut cache: BTreeMap<String, CachedOutputs> = state::load(CACHE_FILE).unwrap_or_default();
+    if let Some(entry) = cache
+        .get(&key)
+        .filter(|entry| cached && entry.is_fresh(&key, now))
+    {
+        return Ok(entry.outputs.clone());
+    }
+
+    debug!("Listing the outputs of {key}");
+    let output = process::Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "flake",
+            "show",
+            "--json",
+            "--no-write-lock-file",
+            &key,
+        ])
+        .stderr(if cached {
+            process::Stdio::null()
+        } else {
+            process::Stdio::inherit()
+        })
+        .output()
+        .wrap_err("Failed to run nix flake show")?;
+    if !output.status.success() {
+        bail!("nix flake show failed for {key}");
+    }
+    let outputs = parse_flake_show(&String::from_utf8_lossy(&output.stdout))?;
+
+    cache.insert(
+        key,
+        CachedOutputs {
+            fetched: now,
+            outputs: outputs.clone(),
+        },
+    );
+    state::save(CACHE_FILE, &cache)?;
+
+    Ok(outputs)
+}
+
+/// Lines of the tree printed by `nh flake show`: the configurations nh can
+/// build, then the names of the other outputs.
+fn tree_lines(outputs: &FlakeOutputs) -> Vec<String> {
+    let mut sections: Vec<(String, Vec<String>)> = CONFIGURATIONS
+        .iter()
+        .filter_map(|output| {
+            let names = outputs.get(*output).filter(|names| !names.is_empty())?;
+            Some((paint(output, Role::Heading), names.clone()))
+        })
+        .collect();
+
+    let others: Vec<String> = outputs
+        .keys()
+        .filter(|output| !CONFIGURATIONS.contains(&output.as_str()))
+        .cloned()
+        .collect();
+    if !others.is_empty() {
+        sections.push((paint("other outputs", Role::Muted), others));
+    }
+
+    let mut lines = Vec::new();
+    for (i, (heading, names)) in sections.iter().enumerate() {
+        let last_section = i + 1 == sections.len();
+        lines.push(format!(
+            "{} {heading}",
+            if last_section {
+                "└──"
+            } else {
+                "├──"
+            }
+        ));
+        for (j, name) in names.iter().enumerate() {
+            lines.push(format!(
+                "{}   {} {}",
+                if last_section { " " } else { "│" },
+                if j + 1 == names.len() {
+                    "└──"
+                } else {
+                    "├──"
+                },
+                paint(name, Role::Name)
+            ));
+        }
+    }
+    lines
+}
+
+/// The parts of `nix flake metadata --json` shown by `nh flake metadata`
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Metadata {
+    description: Option<String>,
+    resolved_url: Option<String>,
+    revision: Option<String>,
+    dirty_revision: Option<String>,
+    last_modified: Option<i64>,
+    locks: Option<LockFile>,
+}
+
+impl FlakeCommand {
+    pub fn run(&self) -> Result<()> {
+        match self {
+            Self::Init(args) => args.run(),
+            Self::Show(args) => args.run(),
+            Self::Metadata(args) => args.run(),
+            Self::Archive(args) => args.run(),
+        }
+    }
+}
+
+impl FlakeInitArgs {
+    fn run(&self) -> Result<()> {
+        let mut cmd = Command::new("nix").show_output(true);
+        cmd = match &self.path {
+            Some(path) => cmd
+                .args(["flake", "new"])
+                .arg(path)
+                .message(format!("Creating a flake in {}", path.display())),
+            None => cmd
+                .args(["flake", "init"])
+                .message("Creating a flake in the current directory"),
+        };
+        if let Some(template) = &self.template {
+            cmd = cmd.args(["--template", template]);
+        }
+        cmd.run()
+    }
+}
+
+impl FlakeShowArgs {
+    fn run(&self) -> Result<()> {
+        let flake = installable::flake_reference(self.flake.as_deref())?;
+        let outputs = outputs(&flake, false)?;
+        println!("{}", paint(&flake, Role::Emphasis));
+        for line in tree_lines(&outputs) {
+            println!("{line}");
+        }
+        Ok(())
+    }
+}
+
+impl FlakeMetadataArgs {
+    fn run(&self) -> Result<()> {
+        let flake = installable::flake_reference(self.flake.as_deref())?;
+        let json = Command::new("nix")
+            .args(["flake", "metadata", "--json", "--no-write-lock-file"])
+            .arg(&flake)
+            .run_capture()?
+            .unwrap_or_default();
+        if json.trim().is_empty() {
+            bail!("Failed to read the metadata of {flake}");
+        }
+        let metadata: Metadata =
+            serde_json::from_str(&json).wrap_err("Failed to parse nix flake metadata output")?;
+
+        let field = |name: &str, value: &str| {
+            println!("{} {value}", paint(format!("{name:<12}"), Role::Emphasis));
+        };
+        field("Flake:", metadata.resolved_url.as_deref().unwrap_or(&flake));
+        if let Some(description) = &metadata.description {
+            field("Description:", description);
+        }
+        match (&metadata.revision, &metadata.dirty_revision) {
+            (Some(rev), _) => field("Revision:", rev),
+            (None, Some(rev)) => field("Revision:", &format!("{rev} (uncommitted changes)")),
+            (None, None) => {}
+        }
+        if let Some(modified) = metadata.last_modified {
+            let now = chrono::Utc::now().timestamp();
+            let date = chrono::DateTime::from_timestamp(modified, 0)
+                .map(|date| date.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default();
+            field(
+                "Modified:",
+                &format!("{date} ({} ago)", format_age(now - modified)),
+            );
+        }
+
+        let inputs = metadata
+            .locks
+            .map(|locks| locks.inputs())
+            .unwrap_or_default();
+        if inputs.is_empty() {
+            return Ok(());
+        }
+
+        let width = inputs.keys().map(String::len).max().unwrap_or_default();
+        println!("{}", paint("Inputs:", Role::Emphasis));
+        for (name, locked) in &inputs {
+            println!(
+                "  {name:<width$}  {}  {}",
+                paint(locked.short_rev(), Role::Value),
+                locked
+                    .age()
+                    .map(|age| format!("{age} old"))
+                    .unwrap_or_default()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl FlakeArchiveArgs {
+    fn run(&self) -> Result<()> {
+        let flake = installable::flake_reference(self.flake.as_deref())?;
+        Command::new("nix")
+            .args(["flake", "archive", "--to", &self.to])
+            .arg(&flake)
+            .message(format!("Copying {flake} and its inputs to {}", self.to))
+            .show_output(true)
+            .dry(self.dry)
+            .run()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_flake_reference() {
+        assert_eq!(
+            installable::flake_reference(Some("/etc/nixos#web")).unwrap(),
+            "/etc/nixos"
+        );
+        assert_eq!(
+            installable::flake_reference(Some("github:NixOS/nixpkgs")).unwrap(),
+            "github:NixOS/nixpkgs"
+        );
+    }
+
+    #[test]
+    fn test_parse_flake_show() {
+        let json = r#"{
+            "nixosConfigurations": {
+                "laptop": { "type": "nixos-configuration" },
+                "server": { "type": "nixos-configuration" }
+            },
+            "homeConfigurations": { "me@laptop": { "type": "unknown" } },
+            "formatter": { "x86_64-linux": { "type": "derivation" } }
+        }"#;
+
+        let outputs = parse_flake_show(json).unwrap();
+        assert_eq!(outputs["nixosConfigurations"], vec!["laptop", "server"]);
+        assert_eq!(outputs["homeConfigurations"], vec!["me@laptop"]);
+        assert!(parse_flake_show("[]").is_err());
+    }
+
+    #[test]
+    fn test_cache_freshness() {
+        let dir = tempfile::tempdir().unwrap();
+        let flake = dir.path().to_str().unwrap();
+        std::fs::write(dir.path().join("flake.nix"), "{}").unwrap();
+        let modified = modified_secs(&dir.path().join("flake.nix")).unwrap();
+
+        let cached = |fetched| CachedOutputs {
+            fetched,
+            outputs: FlakeOutputs::new(),
+        };
+        assert!(cached(modified).is_fresh(flake, modified + 10));
+        assert!(!cached(modified).is_fresh(flake, modified + CACHE_TTL.as_secs() + 1));
+        // flake.nix changed after the outputs were listed
+        assert!(!cached(modified - 1).is_fresh(flake, modified));
+        // Remote flakes only expire
+        assert!(cached(100).is_fresh("github:nixos/nixpkgs", 200));
+    }
+
+    #[test]
+    fn test_tree_lines() {
+        let outputs = FlakeOutputs::from([
+            (
+                "nixosConfigurations".to_string(),
+                vec!["laptop".to_string(), "server".to_string()],
+            ),
+            ("formatter".to_string(), vec!["x86_64-linux".to_string()]),
+            ("packages".to_string(), vec!["x86_64-linux".to_string()]),
+        ]);
+
+        assert_eq!(
+            tree_lines(&outputs),
+            vec![
+                format!("├── {}", paint("nixosConfigurations", Role::Heading)),
+                format!("│   ├── {}", paint("laptop", Role::Name)),
+                format!("│   └── {}", paint("server", Role::Name)),
+                format!("└── {}", paint("other outputs", Role::Muted)),
+                format!("    ├── {}", paint("formatter", Role::Name)),
+                format!("    └── {}", paint("packages", Role::Name)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata: Metadata = serde_json::from_str(
+            r#"{
+                "description": "My machines",
+                "dirtyRevision": "abc1234-dirty",
+                "lastModified": 1700000000,
+                "locks": {
+                    "nodes": {
+                        "nixpkgs": {
+                            "locked": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "0123456789", "lastModified": 1699000000 }
+                        },
+                        "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+                    },
+                    "root": "root",
+                    "version": 7
+                }
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(metadata.description.as_deref(), Some("My machines"));
+        assert_eq!(metadata.revision, None);
+        let inputs = metadata.locks.unwrap().inputs();
+        assert_eq!(inputs["nixpkgs"].short_rev(), "0123456");
+    }
+}
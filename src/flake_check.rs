@@ -0,0 +1,412 @@
+//! `nh check`, running `nix flake check` and summarizing its failures.
+//!
+//! `nix flake check` interleaves progress, evaluation traces and build logs
+//! of every failing check. This groups the errors by the check they belong
+//! to, and keeps only the innermost frames of each trace.
+
+use std::collections::BTreeMap;
+use std::sync::LazyLock;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+use regex::Regex;
+use tracing::{debug, info};
+
+use crate::commands::Command;
+use crate::installable;
+use crate::interface::CheckArgs;
+use crate::theme::{Role, paint};
+
+/// Trace frames shown per failure, unless `--show-all-frames` is passed
+const MAX_FRAMES: usize = 5;
+
+/// A single error reported by `nix flake check`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Failure {
+    /// Attribute of the failing check, like `checks.x86_64-linux.fmt`
+    pub check: String,
+    pub message: String,
+    /// Evaluation trace frames, outermost first, or the tail of a build log
+    pub trace: Vec<String>,
+}
+
+/// Errors that only summarize other errors
+fn is_summary(message: &str) -> bool {
+    message.starts_with("build of ")
+        || message.contains("dependencies of derivation")
+        || message.starts_with("some errors were encountered")
+}
+
+/// The check named by a `checking ...` progress line.
+fn checking_line(line: &str) -> Option<String> {
+    let inner = line.strip_prefix("checking ")?.strip_suffix("...")?;
+    let name = match inner.split('\'').nth(1) {
+        Some(quoted) => quoted,
+        None => inner.rsplit(' ').next()?,
+    };
+    Some(name.to_string())
+}
+
+static WHILE_CHECKING: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"while checking .*'([^']+)'").unwrap());
+static BUILDER_FAILED: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"builder for '/nix/store/[a-z0-9]{32}-([^']+)\.drv' failed").unwrap()
+});
+
+fn parse_block(block: &[&str], current: Option<&str>) -> Option<Failure> {
+    let lines: Vec<&str> = block.iter().map(|line| line.trim()).collect();
+
+    // The innermost error is the actual cause, outer ones only wrap it
+    let start = lines.iter().rposition(|line| line.starts_with("error:"))?;
+    let mut message = vec![lines[start].trim_start_matches("error:").trim()];
+    message.extend(lines[start + 1..].iter().take_while(|line| {
+        !line.is_empty()
+            && !line.starts_with('…')
+            && !line.starts_with('>')
+            && !line.starts_with("last ")
+            && !line.starts_with("For full logs")
+    }));
+    let message = message
+        .into_iter()
+        .filter(|line| !line.is_empty())
+        .collect::<Vec<_>>()
+        .join("\n");
+    if message.is_empty() || is_summary(&message) {
+        return None;
+    }
+
+    let mut trace: Vec<String> = Vec::new();
+    for line in &lines {
+        if let Some(frame) = line.strip_prefix('…') {
+            trace.push(frame.trim().to_string());
+        } else if let (Some(location), Some(last)) = (line.strip_prefix("at "), trace.last_mut()) {
+            if !last.contains(" (at ") {
+                *last = format!("{last} (at {})", location.trim_end_matches(':'));
+            }
+        } else if line.starts_with('>') || line.starts_with("For full logs") {
+            trace.push((*line).to_string());
+        }
+    }
+
+    let check = lines
+        .iter()
+        .rev()
+        .find_map(|line| WHILE_CHECKING.captures(line))
+        .map(|caps| caps[1].to_string())
+        .or_else(|| {
+            lines
+                .iter()
+                .find_map(|line| BUILDER_FAILED.captures(line))
+                .map(|caps| caps[1].to_string())
+        })
+        .or_else(|| current.map(String::from))
+        .unwrap_or_else(|| "flake".to_string());
+
+    Some(Failure {
+        check,
+        message,
+        trace,
+    })
+}
+
+/// Extract the failures from the stderr of `nix flake check` or `nix build`.
+/// Errors that can't be attributed to a check are assigned to `default`.
+#[must_use]
+pub fn parse_failures(output: &str, default: Option<&str>) -> Vec<Failure> {
+    let mut failures = Vec::new();
+    let mut current = default.map(String::from);
+    let mut block: Vec<&str> = Vec::new();
+
+    let mut flush = |block: &mut Vec<&str>, current: Option<&str>| {
+        if !block.is_empty() {
+            failures.extend(parse_block(block, current));
+            block.clear();
+        }
+    };
+
+    for line in output.lines() {
+        if line.starts_with("error:") {
+            flush(&mut block, current.as_deref());
+            block.push(line);
+        } else if !block.is_empty() && (line.is_empty() || line.starts_with(char::is_whitespace)) {
+            block.push(line);
+        } else {
+            flush(&mut block, current.as_deref());
+            if let Some(check) = checking_line(line.trim()) {
+                current = Some(check);
+            }
+        }
+    }
+    flush(&mut block, current.as_deref());
+
+    failures
+}
+
+/// Group failures by check, dropping duplicates reported more than once.
+#[must_use]
+pub fn group(failures: Vec<Failure>) -> BTreeMap<String, Vec<Failure>> {
+    let mut grouped: BTreeMap<String, Vec<Failure>> = BTreeMap::new();
+    for failure in failures {
+        let entry = grouped.entry(failure.check.clone()).or_default();
+        if !entry.contains(&failure) {
+            entry.push(failure);
+        }
+    }
+    grouped
+}
+
+fn print_failures(grouped: &BTreeMap<String, Vec<Failure>>, all_frames: bool) {
+    for (check, failures) in grouped {
+        println!(
+            "{} {}",
+            paint("✗", Role::Error),
+            paint(check, Role::Emphasis)
+        );
+        for failure in failures {
+            for (i, line) in failure.message.lines().enumerate() {
+                if i == 0 {
+                    println!("  {}", paint(line, Role::Error));
+                } else {
+                    println!("  {line}");
+                }
+            }
+
+            let skip = if all_frames {
+                0
+            } else {
+                failure.trace.len().saturating_sub(MAX_FRAMES)
+            };
+            if skip > 0 {
+                println!(
+                    "    {}",
+                    paint(format!("… {skip} more frames"), Role::Muted)
+                );
+            }
+            for frame in &failure.trace[skip..] {
+                println!("    {}", paint(frame, Role::Muted));
+            }
+        }
+    }
+}
+
+/// Run a nix command, returning whether it succeeded and its stderr.
+fn run_nix(args: &[String], message: &str) -> Result<(bool, String)> {
+    info!("{message}");
+    debug!(?args);
+
+    let Some(capture) = Command::new("nix")
+        .args(args)
+        .run_capture_all()
+        .wrap_err("Failed to run nix")?
+    else {
+        return Ok((true, String::new()));
+    };
+
+    let stderr = capture.stderr_str();
+    debug!("{stderr}");
+    Ok((capture.exit_status.success(), stderr))
+}
+
+fn current_system() -> Result<String> {
+    let capture = Command::new("nix")
+        .args([
+            "eval",
+            "--impure",
+            "--raw",
+            "--expr",
+            "builtins.currentSystem",
+        ])
+        .run_capture_all()?
+        .filter(|capture| capture.exit_status.success());
+    match capture {
+        Some(capture) => Ok(capture.stdout_str().trim().to_string()),
+        None => bail!("Failed to determine the current system"),
+    }
+}
+
+/// Names of the checks the flake defines for `system`, empty if it has none.
+fn check_names(flake: &str, system: &str) -> Vec<String> {
+    Command::new("nix")
+        .args(["eval", "--json", "--apply", "builtins.attrNames"])
+        .arg(format!("{flake}#checks.{system}"))
+        .run_capture_all()
+        .ok()
+        .flatten()
+        .filter(|capture| capture.exit_status.success())
+        .and_then(|capture| serde_json::from_str(&capture.stdout_str()).ok())
+        .unwrap_or_default()
+}
+
+impl CheckArgs {
+    pub fn run(&self) -> Result<()> {
+        let flake = installable::flake_reference(self.flake.as_deref())?;
+        let mut failures = Vec::new();
+        let mut ok = true;
+
+        if let Some(hostname) = &self.hostname {
+            // Only the checks of this system and the host's configuration,
+            // instead of everything `nix flake check` would evaluate
+            let system = current_system()?;
+            let toplevel = format!(
+                "{flake}#nixosConfigurations.{hostname}.config.system.build.toplevel.drvPath"
+            );
+
+            let names = check_names(&flake, &system);
+            if !names.is_empty() {
+                let mut args = vec![
+                    "build".to_string(),
+                    "--no-link".to_string(),
+                    "--keep-going".to_string(),
+                ];
+                args.extend(
+                    names
+                        .iter()
+                        .map(|name| format!("{flake}#checks.{system}.{name}")),
+                );
+                args.extend(self.extra_args.iter().cloned());
+
+                let (success, stderr) = run_nix(
+                    &args,
+                    &format!("Building {} checks for {system}", names.len()),
+                )?;
+                ok &= success;
+                failures.extend(parse_failures(&stderr, Some(&format!("checks.{system}"))));
+            }
+
+            let mut args = vec!["eval".to_string(), "--raw".to_string(), toplevel];
+            args.extend(self.extra_args.iter().cloned());
+            let (success, stderr) = run_nix(
+                &args,
+                &format!("Evaluating the configuration of {hostname}"),
+            )?;
+            ok &= success;
+            failures.extend(parse_failures(
+                &stderr,
+                Some(&format!("nixosConfigurations.{hostname}")),
+            ));
+        } else {
+            let mut args = vec![
+                "flake".to_string(),
+                "check".to_string(),
+                flake.clone(),
+                "--keep-going".to_string(),
+            ];
+            if self.all_systems {
+                args.push("--all-systems".to_string());
+            }
+            args.extend(self.extra_args.iter().cloned());
+
+            let (success, stderr) = run_nix(&args, &format!("Checking {flake}"))?;
+            ok &= success;
+            failures.extend(parse_failures(&stderr, None));
+        }
+
+        let grouped = group(failures);
+        if ok && grouped.is_empty() {
+            info!("All checks passed");
+            return Ok(());
+        }
+
+        if grouped.is_empty() {
+            bail!("nix reported a failure nh couldn't parse, rerun with -v to see its output");
+        }
+
+        print_failures(&grouped, self.show_all_frames);
+        bail!(
+            "{} {} failed",
+            grouped.len(),
+            if grouped.len() == 1 {
+                "check"
+            } else {
+                "checks"
+            }
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUILD_FAILURE: &str = "\
+evaluating flake...
+checking flake output 'checks'...
+checking derivation checks.x86_64-linux.fmt...
+checking derivation checks.x86_64-linux.test...
+error: builder for '/nix/store/0c7hd4jv0qk7z3d5v8p4c8k9a1h2q0lr-fmt.drv' failed with exit code 1;
+       last 2 log lines:
+       > checking formatting
+       > src/main.rs is not formatted
+       For full logs, run 'nix log /nix/store/0c7hd4jv0qk7z3d5v8p4c8k9a1h2q0lr-fmt.drv'.
+error: 1 dependencies of derivation '/nix/store/1c7hd4jv0qk7z3d5v8p4c8k9a1h2q0lr-all.drv' failed to build
+";
+
+    const EVAL_FAILURE: &str = "\
+evaluating flake...
+checking flake output 'nixosConfigurations'...
+checking NixOS configuration 'nixosConfigurations.laptop'...
+error:
+       … while checking flake output 'nixosConfigurations'
+
+       … while checking the NixOS configuration 'nixosConfigurations.laptop'
+
+       … while evaluating the attribute 'config.system.build.toplevel'
+         at /nix/store/abc-source/nixos/modules/system/activation/top-level.nix:71:12:
+           70|
+           71|   toplevel = baseSystemAssertWarn;
+             |            ^
+
+       error: The option `services.foo' does not exist. Definition values:
+       - In `/nix/store/abc-source/hosts/laptop.nix'
+";
+
+    #[test]
+    fn test_parse_build_failure() {
+        let failures = parse_failures(BUILD_FAILURE, None);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].check, "fmt");
+        assert!(failures[0].message.starts_with("builder for"));
+        assert_eq!(failures[0].trace.len(), 3);
+        assert_eq!(failures[0].trace[1], "> src/main.rs is not formatted");
+    }
+
+    #[test]
+    fn test_parse_eval_failure() {
+        let failures = parse_failures(EVAL_FAILURE, None);
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].check, "nixosConfigurations.laptop");
+        assert_eq!(
+            failures[0].message,
+            "The option `services.foo' does not exist. Definition values:\n- In `/nix/store/abc-source/hosts/laptop.nix'"
+        );
+        assert_eq!(failures[0].trace.len(), 3);
+        assert!(failures[0].trace[2].ends_with(
+            "(at /nix/store/abc-source/nixos/modules/system/activation/top-level.nix:71:12)"
+        ));
+    }
+
+    #[test]
+    fn test_checking_line() {
+        assert_eq!(
+            checking_line("checking derivation checks.x86_64-linux.fmt..."),
+            Some("checks.x86_64-linux.fmt".to_string())
+        );
+        assert_eq!(
+            checking_line("checking flake output 'checks'..."),
+            Some("checks".to_string())
+        );
+        assert_eq!(checking_line("evaluating flake..."), None);
+    }
+
+    #[test]
+    fn test_group() {
+        let failure = |check: &str| Failure {
+            check: check.to_string(),
+            message: "boom".to_string(),
+            trace: Vec::new(),
+        };
+        let grouped = group(vec![failure("a"), failure("b"), failure("a")]);
+        assert_eq!(grouped.len(), 2);
+        assert_eq!(grouped["a"].len(), 1);
+    }
+}
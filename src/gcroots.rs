@@ -0,0 +1,150 @@
+//! GC roots for the closures nh is about to activate.
+//!
+//! The out-link of a build is an indirect root, but `nh clean` removes roots
+//! named like `result`, and a remote host has no root for the closure at all
+//! between the copy and the activation. Temporary out-links are marked with
+//! the process using them, so that `nh clean` leaves them alone while it
+//! runs, and closures copied to a remote host get a root there until nh is
+//! done with them.
+
+use std::fs;
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, eyre};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use tracing::{debug, warn};
+
+use crate::commands::Command;
+
+/// File next to a temporary out-link holding the pid of the nh using it
+const IN_FLIGHT: &str = ".nh-in-flight";
+
+/// Mark `dir`, the temporary directory of an out-link, as used by this
+/// process.
+pub fn mark_in_flight(dir: &Path) -> Result<()> {
+    let marker = dir.join(IN_FLIGHT);
+    fs::write(&marker, std::process::id().to_string())
+        .wrap_err_with(|| format!("Failed to write {}", marker.display()))
+}
+
+/// Whether the root `link` is the out-link of an nh that is still running.
+#[must_use]
+pub fn is_in_flight(link: &Path) -> bool {
+    let Some(pid) = link
+        .parent()
+        .and_then(|dir| fs::read_to_string(dir.join(IN_FLIGHT)).ok())
+        .and_then(|pid| pid.trim().parse().ok())
+    else {
+        return false;
+    };
+    // Signal 0 only checks that the process exists. Processes of other users
+    // can't be signalled, but exist all the same.
+    matches!(
+        kill(Pid::from_raw(pid), None),
+        Ok(()) | Err(nix::errno::Errno::EPERM)
+    )
+}
+
+/// A root for a closure on a remote host, removed when dropped.
+#[derive(Debug)]
+pub struct RemoteRoot {
+    host: String,
+    dir: String,
+}
+
+/// Copy the closure of `path` to `host` with `copy`, and root it there until
+/// the returned guard is dropped.
+///
+/// The root can only be added once `path` is valid on the host, so a garbage
+/// collection there may remove the closure right after the copy. Rooting
+/// fails then and the closure is copied once more. A path that is still valid
+/// keeps its references valid, so once it is rooted the whole closure is
+/// safe. Failing to add the root only warns, as before nh rooted anything.
+pub fn copy_rooted<T>(
+    host: &str,
+    path: &Path,
+    mut copy: impl FnMut() -> Result<T>,
+) -> Result<(T, Option<RemoteRoot>)> {
+    let copied = copy()?;
+    match add_remote(host, path) {
+        Ok(root) => return Ok((copied, Some(root))),
+        Err(err) => debug!(
+            "Failed to root {} on {host}, copying again: {err:#}",
+            path.display()
+        ),
+    }
+
+    let copied = copy()?;
+    let root = add_remote(host, path)
+        .inspect_err(|err| {
+            warn!("Failed to protect the configuration on {host} from garbage collection: {err}");
+        })
+        .ok();
+    Ok((copied, root))
+}
+
+/// Root the store path `path` points to on `host` until the returned guard
+/// is dropped. Fails if the path isn't valid there.
+fn add_remote(host: &str, path: &Path) -> Result<RemoteRoot> {
+    let path = path
+        .canonicalize()
+        .wrap_err_with(|| format!("Failed to resolve {}", path.display()))?;
+    let path = path
+        .to_str()
+        .ok_or_else(|| eyre!("Store path {} isn't valid UTF-8", path.display()))?;
+    // Not named `result`, which `nh clean` on the host would remove. The
+    // directory doesn't outlive a failure.
+    let script = format!(
+        r#"dir=$(mktemp -d /tmp/nh-root.XXXXXX) || exit 1
+if nix-store --realise '{path}' --add-root "$dir/closure" >/dev/null; then echo "$dir"; else rm -rf "$dir"; exit 1; fi"#
+    );
+    let dir = Command::new("sh")
+        .args(["-c", &script])
+        .ssh(Some(host.to_string()))
+        .run_capture()?
+        .unwrap_or_default()
+        .trim()
+        .to_string();
+    if dir.is_empty() {
+        return Err(eyre!("Failed to add a GC root for {path} on {host}"));
+    }
+    debug!("Rooted {path} on {host} in {dir}");
+
+    Ok(RemoteRoot {
+        host: host.to_string(),
+        dir,
+    })
+}
+
+impl Drop for RemoteRoot {
+    fn drop(&mut self) {
+        let removed = Command::new("rm")
+            .args(["-rf", &self.dir])
+            .ssh(Some(self.host.clone()))
+            .run_capture();
+        if let Err(err) = removed {
+            debug!(?err, "Failed to remove {} on {}", self.dir, self.host);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_in_flight() {
+        let dir = tempfile::tempdir().unwrap();
+        let link = dir.path().join("result");
+        assert!(!is_in_flight(&link));
+
+        mark_in_flight(dir.path()).unwrap();
+        assert!(is_in_flight(&link));
+
+        // A pid that can't exist, left behind by a run that crashed
+        fs::write(dir.path().join(IN_FLIGHT), i32::MAX.to_string()).unwrap();
+        assert!(!is_in_flight(&link));
+    }
+}
@@ -1,15 +1,22 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::fs;
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process;
 
-use chrono::{DateTime, Local, TimeZone, Utc};
-use color_eyre::eyre::{Result, bail};
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone, Utc};
+use color_eyre::eyre::{Context, Result, bail};
+use serde::{Deserialize, Serialize, Serializer};
 use tracing::debug;
 
-#[derive(Debug, Clone)]
+use crate::commands::Command;
+use crate::json::{self, Output};
+use crate::template::{Fields, Template};
+
+#[derive(Debug, Clone, Serialize)]
 pub struct GenerationInfo {
     /// Number of a generation
+    #[serde(serialize_with = "serialize_number")]
     pub number: String,
 
     /// Date on switch a generation was built
@@ -30,6 +37,289 @@ pub struct GenerationInfo {
 
     /// Whether a given generation is the current one.
     pub current: bool,
+
+    /// Closure size in bytes, only filled in on request as it is expensive
+    /// to compute. See [`closure_sizes`].
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closure_size: Option<u64>,
+
+    /// Label set with `nh os tag`, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+
+    /// Whether the generation is protected from cleanup by `nh os pin`.
+    pub pinned: bool,
+
+    /// Git revision of the flake the generation was built from, if it was
+    /// built by nh from a local git checkout.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub flake_revision: Option<FlakeRevision>,
+}
+
+/// Git commit a configuration was built from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FlakeRevision {
+    pub rev: String,
+    /// Whether tracked files had uncommitted changes
+    pub dirty: bool,
+}
+
+impl std::fmt::Display for FlakeRevision {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let short = self.rev.get(..12).unwrap_or(&self.rev);
+        write!(f, "{short}{}", if self.dirty { "-dirty" } else { "" })
+    }
+}
+
+/// State file with generation labels, keyed by profile path and number
+const LABELS_FILE: &str = "labels.json";
+
+/// State file with flake revisions, keyed by the store path that was built
+const REVISIONS_FILE: &str = "revisions.json";
+
+type Labels = BTreeMap<String, BTreeMap<u64, String>>;
+type Revisions = BTreeMap<String, FlakeRevision>;
+
+/// Local directory of a flake reference like `.`, `/etc/nixos` or
+/// `git+file:///etc/nixos?ref=main`, if it refers to one.
+#[must_use]
+pub fn local_flake_dir(reference: &str) -> Option<PathBuf> {
+    let path = reference.split('?').next().unwrap_or_default();
+    let path = ["git+file://", "path:", "git+file:"]
+        .iter()
+        .find_map(|prefix| path.strip_prefix(prefix))
+        .unwrap_or(path);
+
+    // Anything else with a scheme, like `github:` or `https://`, is remote
+    if path.is_empty() || path.contains(':') {
+        return None;
+    }
+
+    let path = PathBuf::from(path);
+    path.is_dir().then_some(path)
+}
+
+/// Revision of the git checkout a flake reference points to, if any.
+#[must_use]
+pub fn flake_revision(reference: &str) -> Option<FlakeRevision> {
+    let dir = local_flake_dir(reference)?;
+    let git = |args: &[&str]| {
+        process::Command::new("git")
+            .arg("-C")
+            .arg(&dir)
+            .args(args)
+            .output()
+            .ok()
+            .filter(|output| output.status.success())
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    };
+
+    let rev = git(&["rev-parse", "HEAD"])?;
+    // Untracked files are not part of the flake, so they don't count
+    let dirty = !git(&["status", "--porcelain", "--untracked-files=no"])?.is_empty();
+
+    Some(FlakeRevision { rev, dirty })
+}
+
+/// Remember the flake revision `out_path` was built from, so that it can be
+/// shown for the generation it becomes.
+///
+/// Entries of store paths that no longer exist are dropped at the same time,
+/// so the state file doesn't grow forever.
+pub fn record_revision(out_path: &Path, revision: FlakeRevision) -> Result<()> {
+    let out_path = fs::canonicalize(out_path)?;
+
+    let mut revisions: Revisions = crate::state::load(REVISIONS_FILE)?;
+    revisions.retain(|path, _| Path::new(path).exists());
+    revisions.insert(out_path.to_string_lossy().into_owned(), revision);
+
+    crate::state::save(REVISIONS_FILE, &revisions)
+}
+
+/// The profile a generation link belongs to, e.g.
+/// `/nix/var/nix/profiles/system` for `/nix/var/nix/profiles/system-42-link`.
+#[must_use]
+pub fn profile_of(generation_dir: &Path) -> Option<PathBuf> {
+    let name = generation_dir.file_name()?.to_str()?;
+    let (profile, _) = name.trim_end_matches("-link").rsplit_once('-')?;
+    Some(generation_dir.with_file_name(profile))
+}
+
+/// Link that pins generation `number` of `profile`, like `system-42-pin`.
+///
+/// Pins live next to the profile rather than in the state directory, so that
+/// they are the same for every user, including root running `nh clean all`.
+/// They point to the store path of the generation, which makes them GC roots
+/// too.
+#[must_use]
+pub fn pin_link(profile: &Path, number: u64) -> PathBuf {
+    let name = profile
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    profile.with_file_name(format!("{name}-{number}-pin"))
+}
+
+/// Numbers of the pinned generations of `profile`.
+pub fn pinned(profile: &Path) -> Result<BTreeSet<u64>> {
+    let name = profile
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dir = profile.parent().unwrap_or_else(|| Path::new("."));
+
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(BTreeSet::new()),
+        Err(err) => return Err(err).wrap_err(format!("Failed to read {}", dir.display())),
+    };
+    Ok(entries
+        .filter_map(Result::ok)
+        .filter_map(|entry| {
+            entry
+                .file_name()
+                .to_str()?
+                .strip_prefix(&name)?
+                .strip_prefix('-')?
+                .strip_suffix("-pin")?
+                .parse()
+                .ok()
+        })
+        .collect())
+}
+
+/// Pin or unpin a generation, as root if the profile belongs to root.
+pub fn set_pinned(profile: &Path, number: u64, pin: bool) -> Result<()> {
+    let link = pin_link(profile, number);
+    let generation = generation_link(profile, number);
+
+    let result = if pin {
+        let target = fs::read_link(&generation)
+            .wrap_err(format!("Failed to read {}", generation.display()))?;
+        match std::os::unix::fs::symlink(&target, &link) {
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => Ok(()),
+            result => result,
+        }
+    } else {
+        match fs::remove_file(&link) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    };
+
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            let cmd = if pin {
+                Command::new("ln")
+                    .arg("-sfn")
+                    .arg(fs::read_link(&generation)?)
+                    .arg(&link)
+            } else {
+                Command::new("rm").arg("-f").arg(&link)
+            };
+            cmd.elevate(true)
+                .with_required_env()
+                .run()
+                .wrap_err(format!("Failed to update {}", link.display()))
+        }
+        result => result.wrap_err(format!("Failed to update {}", link.display())),
+    }
+}
+
+/// Path of the link to generation `number` of `profile`
+#[must_use]
+pub fn generation_link(profile: &Path, number: u64) -> PathBuf {
+    let name = profile
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    profile.with_file_name(format!("{name}-{number}-link"))
+}
+
+/// All generation links of `profile`, sorted by generation number.
+pub fn generation_links(profile: &Path) -> Result<Vec<(u64, PathBuf)>> {
+    let name = profile
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let dir = profile.parent().unwrap_or_else(|| Path::new("."));
+
+    let mut links: Vec<(u64, PathBuf)> = fs::read_dir(dir)?
+        .filter_map(Result::ok)
+        .map(|entry| entry.path())
+        .filter(|path| profile_of(path).is_some_and(|p| p.file_name() == Some(name.as_ref())))
+        .filter_map(|path| Some((from_dir(&path)?, path)))
+        .collect();
+
+    links.sort();
+    Ok(links)
+}
+
+/// Split a store path name like `firefox-121.0.1` into the package name and
+/// version. The version starts at the first dash followed by a digit.
+#[must_use]
+pub fn split_name_version(name: &str) -> (&str, Option<&str>) {
+    name.char_indices()
+        .find(|&(i, c)| {
+            c == '-'
+                && name[i + 1..]
+                    .chars()
+                    .next()
+                    .is_some_and(|c| c.is_ascii_digit())
+        })
+        .map_or((name, None), |(i, _)| (&name[..i], Some(&name[i + 1..])))
+}
+
+/// Versions of `package` among the store paths of a closure, as printed by
+/// `nix-store --query --requisites`.
+#[must_use]
+pub fn package_versions(closure: &str, package: &str) -> BTreeSet<String> {
+    closure
+        .lines()
+        .filter_map(|path| {
+            let base = path.trim().rsplit('/').next()?;
+            // Strip the hash
+            let (_, name) = base.split_once('-')?;
+            match split_name_version(name) {
+                (pname, Some(version)) if pname == package => Some(version.to_string()),
+                (pname, None) if pname == package => Some(String::new()),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Labels of all generations of `profile`, keyed by generation number.
+pub fn labels(profile: &Path) -> Result<BTreeMap<u64, String>> {
+    let mut labels: Labels = crate::state::load(LABELS_FILE)?;
+    Ok(labels
+        .remove(profile.to_string_lossy().as_ref())
+        .unwrap_or_default())
+}
+
+/// Set the label of a generation, removing it if `label` is empty.
+pub fn set_label(profile: &Path, number: u64, label: &str) -> Result<()> {
+    let mut labels: Labels = crate::state::load(LABELS_FILE)?;
+    let profile_labels = labels
+        .entry(profile.to_string_lossy().into_owned())
+        .or_default();
+
+    if label.is_empty() {
+        profile_labels.remove(&number);
+    } else {
+        profile_labels.insert(number, label.to_string());
+    }
+    labels.retain(|_, profile_labels| !profile_labels.is_empty());
+
+    crate::state::save(LABELS_FILE, &labels)
+}
+
+/// Serialize generation numbers as JSON numbers where possible
+fn serialize_number<S: Serializer>(number: &str, serializer: S) -> Result<S::Ok, S::Error> {
+    match number.parse::<u64>() {
+        Ok(number) => serializer.serialize_u64(number),
+        Err(_) => serializer.serialize_str(number),
+    }
 }
 
 #[must_use]
@@ -45,7 +335,147 @@ pub fn from_dir(generation_dir: &Path) -> Option<u64> {
         })
 }
 
-pub fn describe(generation_dir: &Path) -> Option<GenerationInfo> {
+/// State shared by all generations of a profile, loaded once when describing
+/// several of them.
+#[derive(Debug, Default)]
+struct DescribeContext {
+    labels: BTreeMap<u64, String>,
+    pins: BTreeSet<u64>,
+    revisions: Revisions,
+    /// Store path of the active generation: `/run/current-system` for the
+    /// `NixOS` system profile, and what the profile points to otherwise
+    current_system: Option<PathBuf>,
+}
+
+impl DescribeContext {
+    fn load(profile: Option<&Path>) -> Self {
+        Self {
+            labels: profile
+                .and_then(|profile| labels(profile).ok())
+                .unwrap_or_default(),
+            pins: profile
+                .and_then(|profile| pinned(profile).ok())
+                .unwrap_or_default(),
+            revisions: crate::state::load(REVISIONS_FILE).unwrap_or_default(),
+            current_system: match profile {
+                Some(profile) if profile != Path::new(crate::nixos::SYSTEM_PROFILE) => {
+                    fs::canonicalize(profile).ok()
+                }
+                _ => fs::read_link("/run/current-system")
+                    .ok()
+                    .and_then(|p| fs::canonicalize(p).ok()),
+            },
+        }
+    }
+}
+
+/// All generations of a profile, scanned once and sorted by number.
+#[derive(Debug, Clone, Default)]
+pub struct GenerationSet {
+    generations: Vec<GenerationInfo>,
+}
+
+impl GenerationSet {
+    /// Describe every generation of `profile`. The links are described in
+    /// parallel, as reading their metadata dominates on slow disks.
+    pub fn scan(profile: &Path) -> Result<Self> {
+        let links = generation_links(profile)?;
+        let context = DescribeContext::load(Some(profile));
+
+        let workers = std::thread::available_parallelism()
+            .map_or(1, std::num::NonZero::get)
+            .min(links.len().max(1));
+        let chunk_size = links.len().div_ceil(workers).max(1);
+
+        let generations = std::thread::scope(|scope| {
+            let handles: Vec<_> = links
+                .chunks(chunk_size)
+                .map(|chunk| {
+                    let context = &context;
+                    scope.spawn(move || {
+                        chunk
+                            .iter()
+                            .filter_map(|(_, link)| describe(link, context))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap_or_default())
+                .collect()
+        });
+
+        // Chunks are joined in order, and the links were sorted already
+        Ok(Self { generations })
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.generations.is_empty()
+    }
+
+    #[must_use]
+    pub fn get(&self, number: u64) -> Option<&GenerationInfo> {
+        self.generations
+            .iter()
+            .find(|generation| generation.number == number.to_string())
+    }
+
+    #[must_use]
+    pub fn current(&self) -> Option<&GenerationInfo> {
+        self.generations
+            .iter()
+            .find(|generation| generation.current)
+    }
+
+    /// The generation right before the current one.
+    #[must_use]
+    pub fn previous(&self) -> Option<&GenerationInfo> {
+        let current = self.generations.iter().position(|g| g.current)?;
+        current.checked_sub(1).map(|index| &self.generations[index])
+    }
+
+    /// The newest generation built before `cutoff`.
+    #[must_use]
+    pub fn newest_before(&self, cutoff: DateTime<Utc>) -> Option<&GenerationInfo> {
+        self.generations.iter().rev().find(|generation| {
+            DateTime::parse_from_rfc3339(&generation.date).is_ok_and(|date| date < cutoff)
+        })
+    }
+
+    #[must_use]
+    pub fn into_vec(self) -> Vec<GenerationInfo> {
+        self.generations
+    }
+}
+
+/// Parse a point in time like `2024-01-15`, `2024-01-15 14:30` or RFC 3339.
+/// Dates without an offset are in local time.
+pub fn parse_date(date: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = DateTime::parse_from_rfc3339(date) {
+        return Ok(date.with_timezone(&Utc));
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(date, format).ok())
+        .or_else(|| {
+            NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                .ok()
+                .and_then(|date| date.and_hms_opt(0, 0, 0))
+        })
+        .ok_or_else(|| {
+            format!("invalid date '{date}', expected e.g. 2024-01-15 or '2024-01-15 14:30'")
+        })?;
+    Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|date| date.with_timezone(&Utc))
+        .ok_or_else(|| format!("{date} doesn't exist in the local time zone"))
+}
+
+fn describe(generation_dir: &Path, context: &DescribeContext) -> Option<GenerationInfo> {
     let generation_number = from_dir(generation_dir)?;
 
     // Get metadata once and reuse for both date and existence checks
@@ -63,8 +493,21 @@ pub fn describe(generation_dir: &Path) -> Option<GenerationInfo> {
             },
         );
 
-    let nixos_version = fs::read_to_string(generation_dir.join("nixos-version"))
-        .unwrap_or_else(|_| "Unknown".to_string());
+    let gen_store_path = fs::read_link(generation_dir)
+        .ok()
+        .and_then(|p| fs::canonicalize(p).ok());
+
+    let label = context.labels.get(&generation_number).cloned();
+    let pinned = context.pins.contains(&generation_number);
+    let flake_revision = gen_store_path
+        .as_ref()
+        .and_then(|path| context.revisions.get(path.to_string_lossy().as_ref()))
+        .cloned();
+
+    let nixos_version = fs::read_to_string(generation_dir.join("nixos-version")).map_or_else(
+        |_| "Unknown".to_string(),
+        |version| version.trim().to_string(),
+    );
 
     let kernel_dir = generation_dir
         .join("kernel")
@@ -127,37 +570,10 @@ pub fn describe(generation_dir: &Path) -> Option<GenerationInfo> {
     };
 
     // Check if this generation is the current one
-    let Some(run_current_target) = fs::read_link("/run/current-system")
-        .ok()
-        .and_then(|p| fs::canonicalize(p).ok())
-    else {
-        return Some(GenerationInfo {
-            number: generation_number.to_string(),
-            date: build_date,
-            nixos_version,
-            kernel_version,
-            configuration_revision,
-            specialisations,
-            current: false,
-        });
-    };
-
-    let Some(gen_store_path) = fs::read_link(generation_dir)
-        .ok()
-        .and_then(|p| fs::canonicalize(p).ok())
-    else {
-        return Some(GenerationInfo {
-            number: generation_number.to_string(),
-            date: build_date,
-            nixos_version,
-            kernel_version,
-            configuration_revision,
-            specialisations,
-            current: false,
-        });
-    };
-
-    let current = run_current_target == gen_store_path;
+    let current = context
+        .current_system
+        .as_ref()
+        .is_some_and(|current_system| gen_store_path.as_ref() == Some(current_system));
 
     Some(GenerationInfo {
         number: generation_number.to_string(),
@@ -167,9 +583,126 @@ pub fn describe(generation_dir: &Path) -> Option<GenerationInfo> {
         configuration_revision,
         specialisations,
         current,
+        closure_size: None,
+        label,
+        pinned,
+        flake_revision,
     })
 }
 
+/// Closure sizes of the given generation links in bytes, queried with a
+/// single `nix path-info` call. Generations whose size couldn't be determined
+/// are left out.
+pub fn closure_sizes(generation_dirs: &[PathBuf]) -> HashMap<PathBuf, u64> {
+    let store_paths: Vec<(&PathBuf, PathBuf)> = generation_dirs
+        .iter()
+        .filter_map(|dir| fs::canonicalize(dir).ok().map(|path| (dir, path)))
+        .collect();
+
+    if store_paths.is_empty() {
+        return HashMap::new();
+    }
+
+    let sizes = match process::Command::new("nix")
+        .args(["path-info", "--closure-size", "--json"])
+        .args(store_paths.iter().map(|(_, path)| path))
+        .output()
+    {
+        Ok(output) => parse_closure_sizes(&String::from_utf8_lossy(&output.stdout)),
+        Err(err) => {
+            debug!(?err, "Failed to query closure sizes");
+            return HashMap::new();
+        }
+    };
+
+    store_paths
+        .into_iter()
+        .filter_map(|(dir, path)| {
+            let size = sizes.get(path.to_str()?)?;
+            Some((dir.clone(), *size))
+        })
+        .collect()
+}
+
+/// Parse the output of `nix path-info --closure-size --json`, which is a list
+/// of objects in older versions of Nix and an object keyed by path in newer
+/// ones.
+fn parse_closure_sizes(json: &str) -> HashMap<String, u64> {
+    match serde_json::from_str::<serde_json::Value>(json) {
+        Ok(serde_json::Value::Array(infos)) => infos
+            .iter()
+            .filter_map(|info| {
+                Some((
+                    info["path"].as_str()?.to_string(),
+                    info["closureSize"].as_u64()?,
+                ))
+            })
+            .collect(),
+        Ok(serde_json::Value::Object(infos)) => infos
+            .iter()
+            .filter_map(|(path, info)| Some((path.clone(), info["closureSize"].as_u64()?)))
+            .collect(),
+        _ => HashMap::new(),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} GB", bytes as f64 / 1_073_741_824.0)
+}
+
+impl Fields for GenerationInfo {
+    const FIELDS: &'static [&'static str] = &[
+        "generation",
+        "date",
+        "current",
+        "nixos_version",
+        "kernel",
+        "revision",
+        "specialisations",
+        "size",
+        "label",
+        "pinned",
+    ];
+
+    fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "generation" => Some(self.number.clone()),
+            "date" => DateTime::parse_from_rfc3339(&self.date).ok().map(|date| {
+                date.with_timezone(&Local)
+                    .format("%Y-%m-%d %H:%M:%S")
+                    .to_string()
+            }),
+            "current" => Some(self.current.to_string()),
+            "nixos_version" => Some(self.nixos_version.clone()),
+            "kernel" => Some(self.kernel_version.clone()),
+            "revision" => match (&self.flake_revision, self.configuration_revision.as_str()) {
+                (Some(revision), "") => Some(revision.to_string()),
+                (_, revision) => Some(revision.to_string()),
+            },
+            "specialisations" => Some(self.specialisations.join(",")),
+            "size" => self.closure_size.map(|size| size.to_string()),
+            "label" => self.label.clone(),
+            "pinned" => Some(self.pinned.to_string()),
+            _ => None,
+        }
+    }
+}
+
+/// Print one line per generation using `template`, oldest first
+pub fn print_info_formatted(
+    mut generations: Vec<GenerationInfo>,
+    template: &Template,
+) -> Result<()> {
+    generations.sort_by_key(|generation| generation.number.parse::<u64>().unwrap_or(0));
+    template.print_all(&generations)
+}
+
+/// Print generations as a JSON array, sorted by generation number
+pub fn print_info_json(mut generations: Vec<GenerationInfo>) -> Result<()> {
+    generations.sort_by_key(|generation| generation.number.parse::<u64>().unwrap_or(0));
+    json::emit(&Output::Info(generations))
+}
+
 pub fn print_info(mut generations: Vec<GenerationInfo>) -> Result<()> {
     // Get path information for the current generation from /run/current-system
     // By using `--json` we can avoid splitting whitespaces to get the correct
@@ -186,10 +719,9 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) -> Result<()> {
             match serde_json::from_str::<serde_json::Value>(&String::from_utf8_lossy(
                 &output.stdout,
             )) {
-                Ok(json) => json[0]["closureSize"].as_u64().map_or_else(
-                    || "Unknown".to_string(),
-                    |bytes| format!("{:.1} GB", bytes as f64 / 1_073_741_824.0),
-                ),
+                Ok(json) => json[0]["closureSize"]
+                    .as_u64()
+                    .map_or_else(|| "Unknown".to_string(), format_size),
                 Err(_) => "Unknown".to_string(),
             }
         }
@@ -237,13 +769,25 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) -> Result<()> {
         .max()
         .unwrap_or(12); // arbitrary value
 
+    // Closure sizes are only known if they were requested
+    let show_sizes = generations.iter().any(|g| g.closure_size.is_some());
+    let size_column = |size: &str| {
+        if show_sizes {
+            format!("{size:<12} ")
+        } else {
+            String::new()
+        }
+    };
+
     println!(
-        "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {:<22} Specialisations",
+        "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {}{:<22} {:<15} Label",
         "Generation No",
         "Build Date",
         "NixOS Version",
         "Kernel",
+        size_column("Closure Size"),
         "Configuration Revision",
+        "Specialisations",
         width_nixos = max_nixos_version_len,
         width_kernel = max_kernel_len
     );
@@ -267,7 +811,7 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) -> Result<()> {
         };
 
         println!(
-            "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {:<25} {}",
+            "{:<13} {:<20} {:<width_nixos$} {:<width_kernel$} {}{:<25} {:<15} {}",
             format!(
                 "{}{}",
                 generation.number,
@@ -276,11 +820,273 @@ pub fn print_info(mut generations: Vec<GenerationInfo>) -> Result<()> {
             formatted_date,
             generation.nixos_version,
             generation.kernel_version,
-            generation.configuration_revision,
+            size_column(
+                &generation
+                    .closure_size
+                    .map_or_else(|| "Unknown".to_string(), format_size)
+            ),
+            match (
+                &generation.flake_revision,
+                generation.configuration_revision.as_str()
+            ) {
+                (Some(revision), "") => revision.to_string(),
+                (_, configuration_revision) => configuration_revision.to_string(),
+            },
             specialisations,
+            match (generation.pinned, &generation.label) {
+                (true, Some(label)) => format!("[pinned] {label}"),
+                (true, None) => "[pinned]".to_string(),
+                (false, label) => label.clone().unwrap_or_default(),
+            },
             width_nixos = max_nixos_version_len,
             width_kernel = max_kernel_len
         );
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::EnvGuard;
+
+    #[test]
+    fn test_generation_set() {
+        let generation = |number: u64, current: bool| GenerationInfo {
+            number: number.to_string(),
+            date: String::new(),
+            nixos_version: String::new(),
+            kernel_version: String::new(),
+            configuration_revision: String::new(),
+            specialisations: Vec::new(),
+            current,
+            closure_size: None,
+            label: None,
+            pinned: false,
+            flake_revision: None,
+        };
+
+        let set = GenerationSet {
+            generations: vec![
+                generation(3, false),
+                generation(5, true),
+                generation(6, false),
+            ],
+        };
+        assert_eq!(set.current().unwrap().number, "5");
+        assert_eq!(set.previous().unwrap().number, "3");
+        assert_eq!(set.get(6).unwrap().number, "6");
+        assert!(set.get(4).is_none());
+
+        let set = GenerationSet {
+            generations: vec![generation(1, true)],
+        };
+        assert!(set.previous().is_none());
+    }
+
+    #[test]
+    fn test_newest_before() {
+        let generation = |number: u64, date: &str| GenerationInfo {
+            number: number.to_string(),
+            date: date.to_string(),
+            nixos_version: String::new(),
+            kernel_version: String::new(),
+            configuration_revision: String::new(),
+            specialisations: Vec::new(),
+            current: false,
+            closure_size: None,
+            label: None,
+            pinned: false,
+            flake_revision: None,
+        };
+        let set = GenerationSet {
+            generations: vec![
+                generation(1, "2024-01-10T12:00:00+00:00"),
+                generation(2, "Unknown"),
+                generation(3, "2024-01-14T23:00:00+00:00"),
+                generation(4, "2024-01-16T08:00:00+00:00"),
+            ],
+        };
+        let before = |date: &str| {
+            set.newest_before(parse_date(date).unwrap())
+                .map(|generation| generation.number.as_str())
+        };
+        assert_eq!(before("2024-01-15T00:00:00Z"), Some("3"));
+        assert_eq!(before("2024-01-14T22:00:00+00:00"), Some("1"));
+        assert_eq!(before("2024-01-01T00:00:00Z"), None);
+
+        assert!(parse_date("2024-01-15").is_ok());
+        assert!(parse_date("2024-01-15 14:30").is_ok());
+        assert!(parse_date("15/01/2024").is_err());
+    }
+
+    #[test]
+    fn test_generation_info_json() {
+        let info = GenerationInfo {
+            number: "42".to_string(),
+            date: "2024-01-01T00:00:00+00:00".to_string(),
+            nixos_version: "24.11.20240101.abcdef".to_string(),
+            kernel_version: "6.6.8".to_string(),
+            configuration_revision: String::new(),
+            specialisations: vec!["gaming".to_string()],
+            current: true,
+            closure_size: None,
+            label: Some("known good".to_string()),
+            pinned: false,
+            flake_revision: None,
+        };
+
+        let value = serde_json::to_value(&info).unwrap();
+        assert_eq!(value["number"], 42);
+        assert_eq!(value["date"], "2024-01-01T00:00:00+00:00");
+        assert_eq!(value["nixos_version"], "24.11.20240101.abcdef");
+        assert_eq!(value["kernel_version"], "6.6.8");
+        assert_eq!(value["configuration_revision"], "");
+        assert_eq!(value["specialisations"][0], "gaming");
+        assert_eq!(value["current"], true);
+        assert!(value.get("closure_size").is_none());
+        assert_eq!(value["label"], "known good");
+        assert_eq!(value["pinned"], false);
+    }
+
+    #[test]
+    fn test_local_flake_dir() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().to_str().unwrap();
+
+        assert_eq!(local_flake_dir(path), Some(dir.path().to_path_buf()));
+        assert_eq!(
+            local_flake_dir(&format!("git+file://{path}?ref=main")),
+            Some(dir.path().to_path_buf())
+        );
+        assert_eq!(
+            local_flake_dir(&format!("path:{path}")),
+            Some(dir.path().to_path_buf())
+        );
+        assert_eq!(local_flake_dir("github:NixOS/nixpkgs"), None);
+        assert_eq!(local_flake_dir("/does/not/exist"), None);
+    }
+
+    #[test]
+    fn test_flake_revision_display() {
+        let revision = FlakeRevision {
+            rev: "0123456789abcdef0123456789abcdef01234567".to_string(),
+            dirty: true,
+        };
+        assert_eq!(revision.to_string(), "0123456789ab-dirty");
+    }
+
+    #[test]
+    fn test_split_name_version() {
+        assert_eq!(
+            split_name_version("firefox-121.0.1"),
+            ("firefox", Some("121.0.1"))
+        );
+        assert_eq!(
+            split_name_version("linux-firmware-20240115"),
+            ("linux-firmware", Some("20240115"))
+        );
+        assert_eq!(split_name_version("etc"), ("etc", None));
+        assert_eq!(
+            split_name_version("python3.11-numpy-1.26.2"),
+            ("python3.11-numpy", Some("1.26.2"))
+        );
+    }
+
+    #[test]
+    fn test_package_versions() {
+        let closure = "\
+/nix/store/aaa-firefox-121.0.1
+/nix/store/bbb-firefox-unwrapped-121.0.1
+/nix/store/ccc-glibc-2.38-27
+/nix/store/ddd-glibc-2.38-27-bin
+/nix/store/eee-firefox-120.0
+";
+        assert_eq!(
+            package_versions(closure, "firefox"),
+            BTreeSet::from(["120.0".to_string(), "121.0.1".to_string()])
+        );
+        assert_eq!(
+            package_versions(closure, "glibc"),
+            BTreeSet::from(["2.38-27".to_string(), "2.38-27-bin".to_string()])
+        );
+        assert!(package_versions(closure, "chromium").is_empty());
+    }
+
+    #[test]
+    fn test_profile_of() {
+        assert_eq!(
+            profile_of(Path::new("/nix/var/nix/profiles/system-42-link")),
+            Some(PathBuf::from("/nix/var/nix/profiles/system"))
+        );
+        assert_eq!(
+            profile_of(Path::new(
+                "/home/user/.local/state/nix/profiles/home-manager-7-link"
+            )),
+            Some(PathBuf::from(
+                "/home/user/.local/state/nix/profiles/home-manager"
+            ))
+        );
+    }
+
+    #[test]
+    #[serial_test::serial]
+    fn test_labels() {
+        let dir = tempfile::tempdir().unwrap();
+        let _state = EnvGuard::new("NH_STATE_DIR", dir.path());
+
+        let profile = Path::new("/nix/var/nix/profiles/system");
+        set_label(profile, 41, "before GPU driver upgrade").unwrap();
+        set_label(profile, 42, "broken").unwrap();
+        set_label(profile, 42, "").unwrap();
+
+        let labels = labels(profile).unwrap();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[&41], "before GPU driver upgrade");
+        assert!(super::labels(Path::new("/other")).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_pins() {
+        let dir = tempfile::tempdir().unwrap();
+        let profile = dir.path().join("system");
+        for number in [40, 41] {
+            std::os::unix::fs::symlink(
+                format!("/nix/store/{number}-nixos-system"),
+                generation_link(&profile, number),
+            )
+            .unwrap();
+        }
+        std::os::unix::fs::symlink("system-41-link", &profile).unwrap();
+
+        set_pinned(&profile, 41, true).unwrap();
+        set_pinned(&profile, 41, true).unwrap();
+        set_pinned(&profile, 40, true).unwrap();
+        set_pinned(&profile, 40, false).unwrap();
+        set_pinned(&profile, 39, false).unwrap();
+        assert_eq!(pinned(&profile).unwrap(), BTreeSet::from([41]));
+        assert_eq!(
+            fs::read_link(pin_link(&profile, 41)).unwrap(),
+            Path::new("/nix/store/41-nixos-system")
+        );
+        assert!(pinned(&dir.path().join("sys")).unwrap().is_empty());
+        assert!(set_pinned(&profile, 42, true).is_err());
+    }
+
+    #[test]
+    fn test_parse_closure_sizes() {
+        let legacy = r#"[{"path": "/nix/store/aaa-nixos-system", "closureSize": 1024}]"#;
+        assert_eq!(
+            parse_closure_sizes(legacy).get("/nix/store/aaa-nixos-system"),
+            Some(&1024)
+        );
+
+        let current = r#"{"/nix/store/bbb-nixos-system": {"closureSize": 2048}}"#;
+        assert_eq!(
+            parse_closure_sizes(current).get("/nix/store/bbb-nixos-system"),
+            Some(&2048)
+        );
+
+        assert!(parse_closure_sizes("not json").is_empty());
+    }
+}
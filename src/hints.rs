@@ -0,0 +1,145 @@
+//! Hints on how to fix common nix errors.
+//!
+//! Nix output seen during the run and the final error are matched against
+//! failures that have a well known fix, like a disabled experimental feature
+//! or a file that isn't tracked by git, and the fix is printed after the
+//! error.
+
+use std::sync::Mutex;
+
+use color_eyre::Report;
+
+use crate::theme::{Role, paint_err};
+use crate::util;
+
+/// Hints for nix output seen during the run, in the order they were found
+static SEEN: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Hints for a message or log of nix.
+#[must_use]
+pub fn hints(text: &str) -> Vec<String> {
+    let text = &util::strip_colors(text);
+    let mut hints = Vec::new();
+
+    if let Some(feature) = quoted_after(text, "experimental Nix feature ") {
+        hints.push(format!(
+            "Enable the {feature} feature with `extra-experimental-features = {feature}` in ~/.config/nix/nix.conf, or `nix.settings.experimental-features = [ \"{feature}\" ];` on NixOS"
+        ));
+    }
+
+    if text.contains("ignoring untrusted substituter")
+        || text.contains("ignoring the client-specified setting")
+    {
+        hints.push(
+            "The nix daemon ignores substituters and settings from users it doesn't trust. Add yourself to `trusted-users` in /etc/nix/nix.conf (`nix.settings.trusted-users` on NixOS), or add the substituter and its key to the system's nix.conf".to_string(),
+        );
+    }
+
+    if text.contains("hash mismatch in fixed-output derivation") {
+        let got = text
+            .lines()
+            .find_map(|line| line.trim().strip_prefix("got:"))
+            .map(str::trim);
+        hints.push(match got {
+            Some(got) => format!("Replace the hash of the fetcher with {got}"),
+            None => "Replace the hash of the fetcher with the one nix got, printed after `got:`"
+                .to_string(),
+        });
+    }
+
+    if text.contains("infinite recursion encountered") {
+        hints.push(
+            "A value depends on itself. In modules this is usually `imports` or an option's definition reading `config` it sets itself. Run again with --show-trace to see where".to_string(),
+        );
+    }
+
+    if let Some(path) = quoted_after(text, "path ")
+        .filter(|path| path.starts_with("/nix/store/") && path.contains("-source/"))
+        .filter(|_| text.contains("does not exist"))
+    {
+        let file = path.split_once("-source/").map_or(path, |(_, file)| file);
+        hints.push(format!(
+            "Flakes in a git repository only see files tracked by git. If {file} is new, `git add` it"
+        ));
+    }
+
+    hints
+}
+
+/// The text between the quotes right after `prefix`, in `'` or `"`.
+fn quoted_after<'a>(text: &'a str, prefix: &str) -> Option<&'a str> {
+    let rest = &text[text.find(prefix)? + prefix.len()..];
+    let quote = rest.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    let rest = &rest[1..];
+    Some(&rest[..rest.find(quote)?])
+}
+
+/// Look for problems with known fixes in output of nix, to show their hints
+/// if the run fails.
+pub fn observe(text: &str) {
+    let found = hints(text);
+    if found.is_empty() {
+        return;
+    }
+    let mut seen = SEEN
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    for hint in found {
+        if !seen.contains(&hint) {
+            seen.push(hint);
+        }
+    }
+}
+
+/// Print the hints for `err` and the nix output seen before it.
+pub fn print(err: &Report) {
+    for chained in err.chain() {
+        observe(&chained.to_string());
+    }
+    let seen = SEEN
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    for hint in seen.iter() {
+        eprintln!("{} {hint}", paint_err("Hint:", Role::Info));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hints() {
+        let hints_for = |text: &str| hints(text).join("\n");
+
+        assert!(
+            hints_for("error: experimental Nix feature 'flakes' is disabled; add '--extra-experimental-features flakes' to enable it")
+                .starts_with("Enable the flakes feature")
+        );
+        assert!(
+            hints_for("error: experimental Nix feature 'pipe-operators' is disabled; add '--extra-experimental-features pipe-operators' to enable it")
+                .contains("extra-experimental-features = pipe-operators")
+        );
+        assert!(
+            hints_for("warning: ignoring untrusted substituter 'https://cache.example.org', you are not a trusted user.")
+                .contains("trusted-users")
+        );
+        assert_eq!(
+            hints_for(
+                "error: hash mismatch in fixed-output derivation '/nix/store/abc-source.drv':
+         specified: sha256-AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA=
+            got:    sha256-BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB="
+            ),
+            "Replace the hash of the fetcher with sha256-BBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBBB="
+        );
+        assert!(hints_for("error: infinite recursion encountered").contains("--show-trace"));
+        assert_eq!(
+            hints_for(
+                "error: path '\u{1b}[35;1m/nix/store/abc-source/hosts/web/default.nix\u{1b}[0m' does not exist"
+            ),
+            "Flakes in a git repository only see files tracked by git. If hosts/web/default.nix is new, `git add` it"
+        );
+        assert!(hints("error: path '/home/me/foo.nix' does not exist").is_empty());
+        assert!(hints("error: attribute 'foo' missing").is_empty());
+    }
+}
@@ -8,10 +8,20 @@ use tracing::{debug, info, warn};
 
 use crate::commands;
 use crate::commands::Command;
+use crate::confirm;
+use crate::diff;
+use crate::events::{self, Event, Phase};
+use crate::exit;
+use crate::hooks::{self, Hook};
 use crate::installable::Installable;
-use crate::interface::{self, DiffType, HomeRebuildArgs, HomeReplArgs, HomeSubcommand};
+use crate::interface::{
+    self, DiffType, HomeOptionArgs, HomeRebuildArgs, HomeReplArgs, HomeSubcommand,
+};
+use crate::json;
+use crate::options;
+use crate::repl;
 use crate::update::update;
-use crate::util::{get_hostname, print_dix_diff};
+use crate::util::{ensure_flake_configuration, get_hostname};
 
 impl interface::HomeArgs {
     pub fn run(self) -> Result<()> {
@@ -25,32 +35,60 @@ impl interface::HomeArgs {
                 args.rebuild(&Build)
             }
             HomeSubcommand::Repl(args) => args.run(),
+            HomeSubcommand::Option(args) => args.run(),
         }
     }
 }
 
 #[derive(Debug)]
-enum HomeRebuildVariant {
+pub(crate) enum HomeRebuildVariant {
     Build,
     Switch,
 }
 
 impl HomeRebuildArgs {
     fn rebuild(self, variant: &HomeRebuildVariant) -> Result<()> {
-        use HomeRebuildVariant::Build;
+        let result = self.build_and_activate(variant)?;
+        json::emit(&json::Output::Rebuild(result))
+    }
+
+    pub(crate) fn build_and_activate(
+        self,
+        variant: &HomeRebuildVariant,
+    ) -> Result<json::RebuildResult> {
+        use HomeRebuildVariant::{Build, Switch};
 
-        if self.update_args.update_all || self.update_args.update_input.is_some() {
-            update(&self.common.installable, self.update_args.update_input)?;
+        self.common.check_free_space()?;
+        self.common.check_substituters();
+        self.common.check_system()?;
+        if let Some(option) = self.common.trusted_option() {
+            crate::checks::warn_if_untrusted(option);
         }
 
-        let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
-            Some(ref p) => Box::new(p.clone()),
-            None => Box::new({
-                let dir = tempfile::Builder::new().prefix("nh-home").tempdir()?;
-                (dir.as_ref().join("result"), dir)
-            }),
+        let _stash = self.common.prepare_worktree(&self.common.installable)?;
+
+        let pending_update = if self.update_args.enabled() {
+            events::phase(Phase::Update, || {
+                update(&self.common.installable, &self.update_args, false)
+            })?
+        } else {
+            None
         };
 
+        hooks::start(
+            "home-manager",
+            match variant {
+                Build => "build",
+                Switch => "switch",
+            },
+            None,
+        );
+        hooks::run(Hook::PreEval)?;
+
+        let results_name = format!("home-manager-{}", env::var("USER").unwrap_or_default());
+        let last_build = crate::results::latest(&results_name);
+        let out_path = self.common.result_link(&results_name, "nh-home")?;
+
         debug!(?out_path);
 
         // Use NH_HOME_FLAKE if available, otherwise use the provided installable
@@ -72,22 +110,71 @@ impl HomeRebuildArgs {
             self.common.installable.clone()
         };
 
-        let toplevel = toplevel_for(
-            installable,
-            true,
-            &self.extra_args,
-            self.configuration.clone(),
-        )?;
+        let installable = self.common.pin_installable(installable)?;
+
+        // The configuration probes have to see the same inputs as the build
+        let eval_args = self
+            .common
+            .eval
+            .generate_eval_args()
+            .into_iter()
+            .chain(self.extra_args.iter().cloned());
+        let toplevel = events::phase(Phase::Eval, || {
+            toplevel_for(installable, true, eval_args, self.configuration.clone())
+        })?;
+
+        events::phase(Phase::Build, || {
+            self.common.build_unless_unchanged(
+                &toplevel,
+                &self.extra_args,
+                out_path.get_path(),
+                || {
+                    commands::Build::new(toplevel.clone())
+                        .extra_arg("--out-link")
+                        .extra_arg(out_path.get_path())
+                        .extra_args(&self.extra_args)
+                        .eval_args(&self.common.eval)
+                        .passthrough(&self.common.passthrough)
+                        .builders(&self.common.remote_builders())
+                        .message("Building Home-Manager configuration")
+                        .nom(!self.common.no_nom)
+                        .run()
+                        .wrap_err("Failed to build Home-Manager configuration")
+                },
+            )
+        })?;
+
+        if let Some(rev) = &self.common.rev {
+            info!("Built configuration at revision {rev}");
+        }
 
-        commands::Build::new(toplevel)
-            .extra_arg("--out-link")
-            .extra_arg(out_path.get_path())
-            .extra_args(&self.extra_args)
-            .passthrough(&self.common.passthrough)
-            .message("Building Home-Manager configuration")
-            .nom(!self.common.no_nom)
-            .run()
-            .wrap_err("Failed to build Home-Manager configuration")?;
+        events::emit(&Event::BuildOutput {
+            out_path: out_path.get_path(),
+            rev: self.common.rev.as_deref(),
+        });
+
+        self.common.prune_results(&results_name)?;
+        self.common.push_closure(out_path.get_path())?;
+
+        hooks::set_out_path(out_path.get_path());
+        hooks::run(Hook::PostBuild)?;
+
+        let mut result = json::RebuildResult {
+            system: "home-manager",
+            action: match variant {
+                Build => "build",
+                Switch => "switch",
+            },
+            hostname: None,
+            out_path: std::fs::canonicalize(out_path.get_path())
+                .unwrap_or_else(|_| out_path.get_path().to_path_buf()),
+            revision: self.common.rev.clone(),
+            dry: self.common.dry,
+            activated: false,
+            generation: None,
+            copy: None,
+            diff: None,
+        };
 
         let prev_generation: Option<PathBuf> = [
             PathBuf::from("/nix/var/nix/profiles/per-user")
@@ -131,7 +218,8 @@ impl HomeRebuildArgs {
             match self.common.diff {
                 DiffType::Never => {}
                 _ => {
-                    let _ = print_dix_diff(&generation, target_profile.get_path());
+                    let base = self.common.diff_base(generation, last_build);
+                    result.diff = diff::rebuild_diff(&base, target_profile.get_path());
                 }
             }
         }
@@ -140,18 +228,18 @@ impl HomeRebuildArgs {
             if self.common.ask {
                 warn!("--ask has no effect as dry run was requested");
             }
-            return Ok(());
+            if let Some(update) = pending_update {
+                update.finish()?;
+            }
+            return Ok(result);
         }
 
-        if self.common.ask {
-            info!("Apply the config?");
-            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
-
-            if !confirmation {
-                bail!("User rejected the new config");
-            }
+        if self.common.ask && !confirm::ask("Apply the config?", &[])? {
+            return Err(exit::declined("User rejected the new config"));
         }
 
+        hooks::run(Hook::PreActivate)?;
+
         if let Some(ext) = &self.backup_extension {
             info!("Using {} as the backup extension", ext);
             unsafe {
@@ -159,11 +247,13 @@ impl HomeRebuildArgs {
             }
         }
 
-        Command::new(target_profile.get_path().join("activate"))
-            .with_required_env()
-            .message("Activating configuration")
-            .run()
-            .wrap_err("Activation failed")?;
+        events::activation(Phase::Activation, "switch", || {
+            Command::new(target_profile.get_path().join("activate"))
+                .with_required_env()
+                .message("Activating configuration")
+                .run()
+                .wrap_err("Activation failed")
+        })?;
 
         // Make sure out_path is not accidentally dropped
         // https://docs.rs/tempfile/3.12.0/tempfile/index.html#early-drop-pitfall
@@ -173,7 +263,14 @@ impl HomeRebuildArgs {
         );
         drop(target_profile);
 
-        Ok(())
+        if let Some(update) = pending_update {
+            update.finish()?;
+        }
+
+        hooks::run(Hook::PostActivate)?;
+
+        result.activated = !self.common.dry && matches!(variant, Switch);
+        Ok(result)
     }
 }
 
@@ -250,7 +347,14 @@ where
                     }
                     found_config = true;
                 } else {
-                    // Explicit config provided but not found
+                    // Explicit config provided but not found, list what exists if we can
+                    ensure_flake_configuration(
+                        &flake_reference,
+                        "homeConfigurations",
+                        &config_name,
+                        &extra_args,
+                    )?;
+
                     let tried_attr_path = {
                         let mut attr_path = attribute.clone();
                         attr_path.push(config_name);
@@ -372,20 +476,29 @@ impl HomeReplArgs {
             self.installable
         };
 
+        let eval_args = self.eval.generate_eval_args();
         let toplevel = toplevel_for(
             installable,
             false,
-            &self.extra_args,
+            eval_args.iter().chain(&self.extra_args),
             self.configuration.clone(),
         )?;
 
-        Command::new("nix")
-            .with_required_env()
-            .arg("repl")
-            .args(toplevel.to_args())
-            .show_output(true)
-            .run()?;
+        repl::run(&toplevel, &self.eval, &self.extra_args, self.bare)
+    }
+}
+
+impl HomeOptionArgs {
+    fn run(self) -> Result<()> {
+        if matches!(
+            self.installable,
+            Installable::Store { .. } | Installable::System { .. }
+        ) {
+            bail!("Options can't be evaluated from a store path");
+        }
 
-        Ok(())
+        let eval_args = self.eval.generate_eval_args();
+        let configuration = toplevel_for(self.installable, false, &eval_args, self.configuration)?;
+        options::show(&configuration, &self.option, &eval_args)
     }
 }
@@ -0,0 +1,247 @@
+//! User commands run at fixed points of a rebuild, configured in the
+//! `[hooks]` section of the configuration:
+//!
+//! ```toml
+//! [hooks]
+//! pre-activate = ["btrfs subvolume snapshot / /.snapshots/pre-$NH_VARIANT"]
+//! post-build = ["cachix push fleet \"$NH_OUT_PATH\""]
+//! on-failure = ["notify-send \"nh $NH_VARIANT failed\" \"$NH_ERROR\""]
+//! ```
+//!
+//! Each command is run with `sh -c`, with what is known about the run so far
+//! in environment variables:
+//!
+//! | Variable        | Value                                           |
+//! |-----------------|-------------------------------------------------|
+//! | `NH_HOOK`       | The hook, like `post-build`                     |
+//! | `NH_SYSTEM`     | `nixos`, `home-manager` or `darwin`             |
+//! | `NH_VARIANT`    | The action, like `switch` or `boot`             |
+//! | `NH_HOSTNAME`   | The host the configuration is for, if known     |
+//! | `NH_OUT_PATH`   | The built configuration, from `post-build` on   |
+//! | `NH_GENERATION` | The new generation, for `post-activate`         |
+//! | `NH_ERROR`      | The error, for `on-failure`                     |
+//!
+//! A failing hook fails the run, except for `on-failure` hooks.
+//!
+//! Hosts deployed at the same time by `nh os deploy` each run their hooks
+//! with their own variables.
+
+use std::cell::RefCell;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use color_eyre::eyre::Context as _;
+use color_eyre::{Report, Result};
+use tracing::{debug, warn};
+
+use crate::commands::Command;
+use crate::config::{self, HooksConfig};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Hook {
+    /// Before the configuration is evaluated and built
+    PreEval,
+    /// After the configuration was built
+    PostBuild,
+    /// Before the configuration is activated
+    PreActivate,
+    /// After the configuration was activated
+    PostActivate,
+    /// When the run fails
+    OnFailure,
+}
+
+impl fmt::Display for Hook {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::PreEval => "pre-eval",
+            Self::PostBuild => "post-build",
+            Self::PreActivate => "pre-activate",
+            Self::PostActivate => "post-activate",
+            Self::OnFailure => "on-failure",
+        })
+    }
+}
+
+impl HooksConfig {
+    fn commands(&self, hook: Hook) -> &[String] {
+        match hook {
+            Hook::PreEval => &self.pre_eval,
+            Hook::PostBuild => &self.post_build,
+            Hook::PreActivate => &self.pre_activate,
+            Hook::PostActivate => &self.post_activate,
+            Hook::OnFailure => &self.on_failure,
+        }
+    }
+}
+
+/// What is known about the current rebuild
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Context {
+    system: &'static str,
+    variant: String,
+    hostname: Option<String>,
+    out_path: Option<PathBuf>,
+    generation: Option<u64>,
+}
+
+impl Context {
+    /// Environment variables passed to the commands of `hook`.
+    fn env(&self, hook: Hook) -> Vec<(&'static str, String)> {
+        let mut env = vec![
+            ("NH_HOOK", hook.to_string()),
+            ("NH_SYSTEM", self.system.to_string()),
+            ("NH_VARIANT", self.variant.clone()),
+        ];
+        env.extend(self.hostname.clone().map(|host| ("NH_HOSTNAME", host)));
+        env.extend(
+            self.out_path
+                .as_ref()
+                .map(|path| ("NH_OUT_PATH", path.display().to_string())),
+        );
+        env.extend(
+            self.generation
+                .map(|generation| ("NH_GENERATION", generation.to_string())),
+        );
+        env
+    }
+}
+
+thread_local! {
+    /// The rebuild running on this thread. Hosts deployed at the same time
+    /// are rebuilt on threads of their own, and each gets its own context.
+    static CONTEXT: RefCell<Option<Context>> = const { RefCell::new(None) };
+}
+
+/// Set once any rebuild started, so that `on-failure` hooks, notifications
+/// and stats are only for those
+static STARTED: AtomicBool = AtomicBool::new(false);
+
+fn update(f: impl FnOnce(&mut Context)) {
+    CONTEXT.with_borrow_mut(|context| {
+        if let Some(context) = context.as_mut() {
+            f(context);
+        }
+    });
+}
+
+/// Start a rebuild of `system`, like `nixos`, for the hooks run from now on.
+pub fn start(system: &'static str, variant: &str, hostname: Option<&str>) {
+    STARTED.store(true, Ordering::Relaxed);
+    CONTEXT.set(Some(Context {
+        system,
+        variant: variant.to_string(),
+        hostname: hostname.map(String::from),
+        ..Context::default()
+    }));
+}
+
+/// End the rebuild on this thread, after its `on-failure` hooks ran if it
+/// failed.
+pub fn finish() {
+    CONTEXT.set(None);
+}
+
+/// Record the built configuration for the hooks run from now on.
+pub fn set_out_path(out_path: &Path) {
+    let out_path = std::fs::canonicalize(out_path).unwrap_or_else(|_| out_path.to_path_buf());
+    update(|context| context.out_path = Some(out_path));
+}
+
+/// Record the generation the configuration became.
+pub fn set_generation(generation: Option<u64>) {
+    update(|context| context.generation = generation);
+}
+
+fn run_commands(hook: Hook, env: &[(&'static str, String)]) -> Result<()> {
+    for template in config::get().hooks.commands(hook) {
+        debug!(%hook, template, "Running hook");
+        env.iter()
+            .fold(
+                Command::new("sh").args(["-c", template]),
+                |cmd, (key, value)| cmd.env(key, value),
+            )
+            .message(format!("Running {hook} hook"))
+            .show_output(true)
+            .run()
+            .wrap_err(format!("The {hook} hook `{template}` failed"))?;
+    }
+    Ok(())
+}
+
+/// Run the commands configured for `hook`.
+pub fn run(hook: Hook) -> Result<()> {
+    let Some(context) = CONTEXT.with_borrow(Clone::clone) else {
+        return Ok(());
+    };
+    run_commands(hook, &context.env(hook))
+}
+
+/// Whether a rebuild was started.
+pub fn started() -> bool {
+    STARTED.load(Ordering::Relaxed)
+}
+
+/// Run the `on-failure` hooks with `err`, if a rebuild was started on this
+/// thread and not finished yet.
+pub fn on_failure(err: &Report) {
+    let Some(context) = CONTEXT.with_borrow(Clone::clone) else {
+        return;
+    };
+
+    let mut env = context.env(Hook::OnFailure);
+    env.push(("NH_ERROR", format!("{err:#}")));
+    if let Err(err) = run_commands(Hook::OnFailure, &env) {
+        warn!("{err:#}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_context_env() {
+        let mut context = Context {
+            system: "nixos",
+            variant: "switch".to_string(),
+            hostname: Some("laptop".to_string()),
+            ..Context::default()
+        };
+        assert_eq!(
+            context.env(Hook::PreEval),
+            vec![
+                ("NH_HOOK", "pre-eval".to_string()),
+                ("NH_SYSTEM", "nixos".to_string()),
+                ("NH_VARIANT", "switch".to_string()),
+                ("NH_HOSTNAME", "laptop".to_string()),
+            ]
+        );
+
+        context.out_path = Some(PathBuf::from("/nix/store/abc-nixos-system"));
+        context.generation = Some(42);
+        let env = context.env(Hook::PostActivate);
+        assert!(env.contains(&("NH_OUT_PATH", "/nix/store/abc-nixos-system".to_string())));
+        assert!(env.contains(&("NH_GENERATION", "42".to_string())));
+    }
+
+    #[test]
+    fn test_context_per_thread() {
+        let hostname = |host: &'static str| {
+            std::thread::spawn(move || {
+                start("nixos", "switch", Some(host));
+                set_generation(Some(1));
+                CONTEXT.with_borrow(|context| context.clone().and_then(|c| c.hostname))
+            })
+        };
+        let (a, b) = (hostname("a"), hostname("b"));
+        assert_eq!(a.join().unwrap().as_deref(), Some("a"));
+        assert_eq!(b.join().unwrap().as_deref(), Some("b"));
+        assert!(started());
+
+        start("nixos", "boot", None);
+        finish();
+        assert_eq!(CONTEXT.with_borrow(Clone::clone), None);
+    }
+}
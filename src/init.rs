@@ -0,0 +1,449 @@
+//! `nh init`, a flake to start a configuration from.
+//!
+//! The built-in templates name the configuration after the machine and user
+//! nh runs on, which is what `nh os`, `nh home` and `nh darwin` look for when
+//! no configuration is given, so the new flake works without any flags.
+//! Templates, built-in or not, are copied with the placeholders
+//! `@hostname@`, `@username@`, `@system@` and `@home@` replaced.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+use regex::Regex;
+use tracing::{info, warn};
+
+use crate::commands::Command;
+use crate::interface::{InitArgs, InitKind};
+use crate::theme::{Role, paint};
+use crate::util;
+
+/// The hardware configuration generated when NixOS was installed
+const HARDWARE_CONFIGURATION: &str = "/etc/nixos/hardware-configuration.nix";
+/// The configuration generated when NixOS was installed, which the boot
+/// loader settings and state version are taken from
+const CONFIGURATION: &str = "/etc/nixos/configuration.nix";
+
+const OS_FLAKE: &str = include_str!("init/os/flake.nix");
+const OS_CONFIGURATION: &str = include_str!("init/os/configuration.nix");
+const HOME_FLAKE: &str = include_str!("init/home/flake.nix");
+const HOME_CONFIGURATION: &str = include_str!("init/home/home.nix");
+const DARWIN_FLAKE: &str = include_str!("init/darwin/flake.nix");
+const DARWIN_CONFIGURATION: &str = include_str!("init/darwin/configuration.nix");
+const COMBINED_NIXOS_FLAKE: &str = include_str!("init/combined/nixos.nix");
+const COMBINED_DARWIN_FLAKE: &str = include_str!("init/combined/darwin.nix");
+
+/// Values of the template placeholders
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Values {
+    pub hostname: String,
+    pub username: String,
+    /// Nix system, like `x86_64-linux`
+    pub system: String,
+}
+
+impl Values {
+    fn is_darwin(&self) -> bool {
+        self.system.ends_with("-darwin")
+    }
+
+    /// Home directory of the user, where it is by default on the system.
+    fn home(&self) -> String {
+        match (self.is_darwin(), self.username.as_str()) {
+            (true, "root") => "/var/root".to_string(),
+            (false, "root") => "/root".to_string(),
+            (true, user) => format!("/Users/{user}"),
+            (false, user) => format!("/home/{user}"),
+        }
+    }
+
+    /// `text` with the placeholders replaced.
+    #[must_use]
+    pub fn substitute(&self, text: &str) -> String {
+        text.replace("@hostname@", &self.hostname)
+            .replace("@username@", &self.username)
+            .replace("@system@", &self.system)
+            .replace("@home@", &self.home())
+    }
+}
+
+static BOOT_LOADER: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"(?m)^\s*(boot\.loader\.[\w.-]+\s*=[^;]*;)").unwrap());
+static STATE_VERSION: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"(?m)^\s*system\.stateVersion\s*=\s*("[^"]*")\s*;"#).unwrap());
+
+/// Settings of the NixOS template that depend on how the machine was
+/// installed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct NixosSettings {
+    /// `boot.loader.*` definitions
+    boot_loader: Vec<String>,
+    /// `system.stateVersion`, quoted
+    state_version: Option<String>,
+}
+
+impl NixosSettings {
+    /// The settings of a configuration like the one `nixos-generate-config`
+    /// writes, where each is set on a line of its own.
+    fn parse(configuration: &str) -> Self {
+        Self {
+            boot_loader: BOOT_LOADER
+                .captures_iter(configuration)
+                .map(|captures| captures[1].to_string())
+                .collect(),
+            state_version: STATE_VERSION
+                .captures(configuration)
+                .map(|captures| captures[1].to_string()),
+        }
+    }
+
+    fn load() -> Self {
+        match fs::read_to_string(CONFIGURATION) {
+            Ok(configuration) => Self::parse(&configuration),
+            Err(err) => {
+                warn!("Failed to read {CONFIGURATION}: {err}");
+                Self::default()
+            }
+        }
+    }
+
+    /// `text` with `@bootLoader@` and `@stateVersion@` replaced. Settings
+    /// that weren't found are left for the user to fill in, with a comment
+    /// or an evaluation error.
+    fn substitute(&self, text: &str) -> String {
+        let boot_loader = if self.boot_loader.is_empty() {
+            "  # No boot loader settings were found in /etc/nixos/configuration.nix,\n  \
+             # set one up, like `boot.loader.systemd-boot.enable = true;`"
+                .to_string()
+        } else {
+            let mut lines = vec![format!("  # The boot loader settings of {CONFIGURATION}")];
+            lines.extend(self.boot_loader.iter().map(|line| format!("  {line}")));
+            lines.join("\n")
+        };
+        let state_version = self.state_version.clone().unwrap_or_else(|| {
+            format!("throw \"Set system.stateVersion to the value of {CONFIGURATION}\"")
+        });
+        text.replace("@bootLoader@", &boot_loader)
+            .replace("@stateVersion@", &state_version)
+    }
+}
+
+/// Files of the built-in template for `kind`, relative to the flake.
+fn builtin(kind: InitKind, darwin: bool) -> Vec<(PathBuf, String)> {
+    let files: &[(&str, &str)] = match (kind, darwin) {
+        (InitKind::Os, _) => &[
+            ("flake.nix", OS_FLAKE),
+            ("configuration.nix", OS_CONFIGURATION),
+        ],
+        (InitKind::Home, _) => &[("flake.nix", HOME_FLAKE), ("home.nix", HOME_CONFIGURATION)],
+        (InitKind::Darwin, _) => &[
+            ("flake.nix", DARWIN_FLAKE),
+            ("configuration.nix", DARWIN_CONFIGURATION),
+        ],
+        (InitKind::Combined, false) => &[
+            ("flake.nix", COMBINED_NIXOS_FLAKE),
+            ("configuration.nix", OS_CONFIGURATION),
+            ("home.nix", HOME_CONFIGURATION),
+        ],
+        (InitKind::Combined, true) => &[
+            ("flake.nix", COMBINED_DARWIN_FLAKE),
+            ("configuration.nix", DARWIN_CONFIGURATION),
+            ("home.nix", HOME_CONFIGURATION),
+        ],
+    };
+    files
+        .iter()
+        .map(|(name, text)| (PathBuf::from(name), (*text).to_string()))
+        .collect()
+}
+
+/// Files of the template at `path`: every file of a directory, relative to
+/// it, or a single file as `flake.nix`.
+fn user_template(path: &Path) -> Result<Vec<(PathBuf, String)>> {
+    let mut files = Vec::new();
+    if path.is_dir() {
+        read_files(path, path, &mut files)?;
+        if files.is_empty() {
+            bail!("The template {} has no files", path.display());
+        }
+    } else {
+        let text = fs::read_to_string(path)
+            .wrap_err(format!("Failed to read the template {}", path.display()))?;
+        files.push((PathBuf::from("flake.nix"), text));
+    }
+    Ok(files)
+}
+
+fn read_files(root: &Path, dir: &Path, files: &mut Vec<(PathBuf, String)>) -> Result<()> {
+    let entries =
+        fs::read_dir(dir).wrap_err(format!("Failed to read the template {}", dir.display()))?;
+    for entry in entries {
+        let path = entry?.path();
+        if path.file_name().is_some_and(|name| name == ".git") {
+            continue;
+        }
+        if path.is_dir() {
+            read_files(root, &path, files)?;
+        } else {
+            let text =
+                fs::read_to_string(&path).wrap_err(format!("Failed to read {}", path.display()))?;
+            let relative = path.strip_prefix(root).unwrap_or(&path).to_path_buf();
+            files.push((relative, text));
+        }
+    }
+    files.sort();
+    Ok(())
+}
+
+/// The commands that build the configurations of the new flake.
+fn next_commands(kind: InitKind, darwin: bool, dir: &Path) -> Vec<String> {
+    let dir = dir.display();
+    let system = if darwin { "darwin" } else { "os" };
+    match kind {
+        InitKind::Os => vec![format!("nh os switch {dir}")],
+        InitKind::Home => vec![format!("nh home switch {dir}")],
+        InitKind::Darwin => vec![format!("nh darwin switch {dir}")],
+        InitKind::Combined => vec![
+            format!("nh {system} switch {dir}"),
+            format!("nh home switch {dir}"),
+        ],
+    }
+}
+
+impl InitArgs {
+    pub fn run(&self) -> Result<()> {
+        let values = Values {
+            hostname: match &self.hostname {
+                Some(hostname) => hostname.clone(),
+                None => util::get_hostname()?,
+            },
+            username: match &self.username {
+                Some(username) => username.clone(),
+                None => env::var("USER").wrap_err("Couldn't get username, pass --username")?,
+            },
+            system: self.system.clone().unwrap_or_else(util::current_system),
+        };
+        let darwin = values.is_darwin();
+
+        let files = match &self.template {
+            Some(template) => user_template(template)?,
+            None => builtin(self.kind, darwin),
+        };
+        // File names can have placeholders too, like `hosts/@hostname@.nix`
+        let files: Vec<(PathBuf, String)> = files
+            .into_iter()
+            .map(|(file, text)| (values.substitute(&file.to_string_lossy()).into(), text))
+            .collect();
+
+        let existing: Vec<String> = files
+            .iter()
+            .filter(|(file, _)| self.path.join(file).exists())
+            .map(|(file, _)| file.display().to_string())
+            .collect();
+        if !existing.is_empty() {
+            bail!(
+                "{} already exists in {}, not overwriting it",
+                existing.join(", "),
+                self.path.display()
+            );
+        }
+
+        let nixos = self.template.is_none()
+            && matches!(
+                (self.kind, darwin),
+                (InitKind::Os | InitKind::Combined, false)
+            );
+        let settings = if nixos {
+            NixosSettings::load()
+        } else {
+            NixosSettings::default()
+        };
+        if nixos && (settings.boot_loader.is_empty() || settings.state_version.is_none()) {
+            warn!(
+                "Set up the boot loader and state version in configuration.nix before building it"
+            );
+        }
+
+        let mut created = Vec::new();
+        for (file, text) in &files {
+            let path = self.path.join(file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)
+                    .wrap_err(format!("Failed to create {}", parent.display()))?;
+            }
+            fs::write(&path, settings.substitute(&values.substitute(text)))
+                .wrap_err(format!("Failed to write {}", path.display()))?;
+            created.push(file.clone());
+        }
+
+        if nixos {
+            created.extend(self.copy_hardware_configuration()?);
+        }
+
+        if self.git {
+            let git = || Command::new("git").arg("-C").arg(&self.path);
+            git()
+                .arg("init")
+                .message("Initializing a git repository")
+                .run()?;
+            git().arg("add").arg("--").args(&created).run()?;
+        } else if in_git_repo(&self.path) {
+            warn!("Add the new files to git, flakes in a git repository only see tracked files");
+        }
+
+        info!(
+            "Created a flake in {} for {}@{} on {}",
+            self.path.display(),
+            values.username,
+            values.hostname,
+            values.system
+        );
+        println!("Build it with:");
+        for command in next_commands(self.kind, darwin, &self.path) {
+            println!("  {}", paint(command, Role::Literal));
+        }
+        Ok(())
+    }
+
+    /// Copy the hardware configuration of this machine into the flake, which
+    /// the NixOS template imports. Returns the file if it was copied.
+    fn copy_hardware_configuration(&self) -> Result<Option<PathBuf>> {
+        let file = PathBuf::from("hardware-configuration.nix");
+        let to = self.path.join(&file);
+        if to.exists() {
+            return Ok(None);
+        }
+        if !Path::new(HARDWARE_CONFIGURATION).exists() {
+            warn!(
+                "{HARDWARE_CONFIGURATION} doesn't exist, create {} with `nixos-generate-config --show-hardware-config`",
+                to.display()
+            );
+            return Ok(None);
+        }
+        fs::copy(HARDWARE_CONFIGURATION, &to)
+            .wrap_err(format!("Failed to copy {HARDWARE_CONFIGURATION}"))?;
+        Ok(Some(file))
+    }
+}
+
+/// Whether `dir` is in a git repository.
+fn in_git_repo(dir: &Path) -> bool {
+    fs::canonicalize(dir).is_ok_and(|dir| dir.ancestors().any(|dir| dir.join(".git").exists()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn values(system: &str) -> Values {
+        Values {
+            hostname: "web".to_string(),
+            username: "alice".to_string(),
+            system: system.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_substitute() {
+        let values = values("aarch64-darwin");
+        assert_eq!(
+            values.substitute("@username@@@hostname@ @system@ @home@ @other@"),
+            "alice@web aarch64-darwin /Users/alice @other@"
+        );
+        assert_eq!(
+            Values {
+                username: "root".to_string(),
+                ..values
+            }
+            .home(),
+            "/var/root"
+        );
+    }
+
+    #[test]
+    fn test_builtin() {
+        for kind in [
+            InitKind::Os,
+            InitKind::Home,
+            InitKind::Darwin,
+            InitKind::Combined,
+        ] {
+            for system in ["x86_64-linux", "aarch64-darwin"] {
+                let values = values(system);
+                let files = builtin(kind, values.is_darwin());
+                assert_eq!(files[0].0, PathBuf::from("flake.nix"));
+                for (_, text) in &files {
+                    assert!(!values.substitute(text).contains("@system@"));
+                }
+            }
+        }
+
+        let flake = |kind, system| values(system).substitute(&builtin(kind, false)[0].1);
+        assert!(flake(InitKind::Os, "x86_64-linux").contains("nixosConfigurations.\"web\""));
+        assert!(flake(InitKind::Home, "x86_64-linux").contains("homeConfigurations.\"alice\""));
+        assert!(flake(InitKind::Darwin, "aarch64-darwin").contains("darwinConfigurations.\"web\""));
+
+        let combined = builtin(InitKind::Combined, true);
+        assert!(combined[0].1.contains("darwinConfigurations"));
+        assert!(combined[0].1.contains("homeConfigurations"));
+    }
+
+    #[test]
+    fn test_nixos_settings() {
+        let settings = NixosSettings::parse(
+            r#"{ config, pkgs, ... }:
+{
+  imports = [ ./hardware-configuration.nix ];
+
+  boot.loader.grub.enable = true;
+  boot.loader.grub.device = "/dev/vda"; # or "nodev" for efi only
+  # boot.loader.systemd-boot.enable = true;
+
+  system.stateVersion = "23.11"; # Did you read the comment?
+}"#,
+        );
+        assert_eq!(
+            settings.boot_loader,
+            [
+                "boot.loader.grub.enable = true;",
+                r#"boot.loader.grub.device = "/dev/vda";"#
+            ]
+        );
+        assert_eq!(settings.state_version.as_deref(), Some(r#""23.11""#));
+
+        let text = settings.substitute(OS_CONFIGURATION);
+        assert!(text.contains(r#"  boot.loader.grub.device = "/dev/vda";"#));
+        assert!(text.contains(r#"system.stateVersion = "23.11";"#));
+
+        let text = NixosSettings::default().substitute(OS_CONFIGURATION);
+        assert!(!text.contains("boot.loader.grub"));
+        assert!(text.contains("system.stateVersion = throw"));
+    }
+
+    #[test]
+    fn test_user_template() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("hosts")).unwrap();
+        fs::write(dir.path().join("flake.nix"), "{ }").unwrap();
+        fs::write(dir.path().join("hosts/@hostname@.nix"), "{ }").unwrap();
+
+        let files: Vec<PathBuf> = user_template(dir.path())
+            .unwrap()
+            .into_iter()
+            .map(|(file, _)| file)
+            .collect();
+        assert_eq!(
+            files,
+            [
+                PathBuf::from("flake.nix"),
+                PathBuf::from("hosts/@hostname@.nix")
+            ]
+        );
+
+        let files = user_template(&dir.path().join("flake.nix")).unwrap();
+        assert_eq!(files, [(PathBuf::from("flake.nix"), "{ }".to_string())]);
+    }
+}
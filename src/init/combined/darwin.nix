@@ -0,0 +1,33 @@
+{
+  description = "nix-darwin and Home Manager configuration of @hostname@";
+
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixpkgs-unstable";
+    nix-darwin = {
+      url = "github:nix-darwin/nix-darwin/master";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+    home-manager = {
+      url = "github:nix-community/home-manager";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs =
+    {
+      nixpkgs,
+      nix-darwin,
+      home-manager,
+      ...
+    }:
+    {
+      darwinConfigurations."@hostname@" = nix-darwin.lib.darwinSystem {
+        modules = [ ./configuration.nix ];
+      };
+
+      homeConfigurations."@username@" = home-manager.lib.homeManagerConfiguration {
+        pkgs = nixpkgs.legacyPackages."@system@";
+        modules = [ ./home.nix ];
+      };
+    };
+}
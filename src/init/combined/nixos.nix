@@ -0,0 +1,24 @@
+{
+  description = "NixOS and Home Manager configuration of @hostname@";
+
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+    home-manager = {
+      url = "github:nix-community/home-manager";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs =
+    { nixpkgs, home-manager, ... }:
+    {
+      nixosConfigurations."@hostname@" = nixpkgs.lib.nixosSystem {
+        modules = [ ./configuration.nix ];
+      };
+
+      homeConfigurations."@username@" = home-manager.lib.homeManagerConfiguration {
+        pkgs = nixpkgs.legacyPackages."@system@";
+        modules = [ ./home.nix ];
+      };
+    };
+}
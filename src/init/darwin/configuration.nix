@@ -0,0 +1,17 @@
+{ pkgs, ... }:
+{
+  nixpkgs.hostPlatform = "@system@";
+  networking.hostName = "@hostname@";
+
+  nix.settings.experimental-features = [
+    "nix-command"
+    "flakes"
+  ];
+
+  system.primaryUser = "@username@";
+
+  environment.systemPackages = [ pkgs.nh ];
+
+  # See `darwin-help` before changing this
+  system.stateVersion = 6;
+}
@@ -0,0 +1,19 @@
+{
+  description = "nix-darwin configuration of @hostname@";
+
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixpkgs-unstable";
+    nix-darwin = {
+      url = "github:nix-darwin/nix-darwin/master";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs =
+    { nix-darwin, ... }:
+    {
+      darwinConfigurations."@hostname@" = nix-darwin.lib.darwinSystem {
+        modules = [ ./configuration.nix ];
+      };
+    };
+}
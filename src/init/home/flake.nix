@@ -0,0 +1,20 @@
+{
+  description = "Home Manager configuration of @username@";
+
+  inputs = {
+    nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+    home-manager = {
+      url = "github:nix-community/home-manager";
+      inputs.nixpkgs.follows = "nixpkgs";
+    };
+  };
+
+  outputs =
+    { nixpkgs, home-manager, ... }:
+    {
+      homeConfigurations."@username@" = home-manager.lib.homeManagerConfiguration {
+        pkgs = nixpkgs.legacyPackages."@system@";
+        modules = [ ./home.nix ];
+      };
+    };
+}
@@ -0,0 +1,12 @@
+{ pkgs, ... }:
+{
+  home.username = "@username@";
+  home.homeDirectory = "@home@";
+
+  home.packages = [ pkgs.nh ];
+
+  programs.home-manager.enable = true;
+
+  # See `man home-configuration.nix` before changing this
+  home.stateVersion = "25.05";
+}
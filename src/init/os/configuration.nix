@@ -0,0 +1,25 @@
+{ pkgs, ... }:
+{
+  imports = [ ./hardware-configuration.nix ];
+
+  nixpkgs.hostPlatform = "@system@";
+  networking.hostName = "@hostname@";
+
+@bootLoader@
+
+  nix.settings.experimental-features = [
+    "nix-command"
+    "flakes"
+  ];
+
+  users.users."@username@" = {
+    isNormalUser = true;
+    extraGroups = [ "wheel" ];
+  };
+
+  environment.systemPackages = [ pkgs.nh ];
+
+  # The NixOS release the machine was installed with, see
+  # `man configuration.nix` before changing it
+  system.stateVersion = @stateVersion@;
+}
@@ -0,0 +1,13 @@
+{
+  description = "NixOS configuration of @hostname@";
+
+  inputs.nixpkgs.url = "github:NixOS/nixpkgs/nixos-unstable";
+
+  outputs =
+    { nixpkgs, ... }:
+    {
+      nixosConfigurations."@hostname@" = nixpkgs.lib.nixosSystem {
+        modules = [ ./configuration.nix ];
+      };
+    };
+}
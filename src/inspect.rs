@@ -0,0 +1,449 @@
+//! `nh inspect`, a look at what a system or home closure consists of.
+//!
+//! Shows the dependency tree of the closure with the size of the closure of
+//! every dependency, packages that ended up in the closure more than once,
+//! and with `--why`, the chain of references that pulls a path in. All of it
+//! comes from a single `nix path-info --recursive --json`.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt::Write as _;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+
+use crate::commands::Command;
+use crate::generations::split_name_version;
+use crate::interface::InspectArgs;
+use crate::store::{self, PathInfo};
+use crate::theme::{Role, paint};
+use crate::util::format_bytes;
+
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// The store paths of a closure and the references between them.
+#[derive(Debug)]
+pub struct Closure {
+    pub root: String,
+    pub paths: HashMap<String, PathInfo>,
+}
+
+impl Closure {
+    /// Load the closure of the store path `root`.
+    pub fn load(root: &Path) -> Result<Self> {
+        let json = Command::new("nix")
+            .args(["--extra-experimental-features", "nix-command"])
+            .args([
+                "path-info",
+                "--json",
+                "--recursive",
+                "--size",
+                "--closure-size",
+            ])
+            .arg(root)
+            .message("Querying the closure")
+            .run_capture()?
+            .unwrap_or_default();
+
+        Self::from_infos(
+            root.to_string_lossy().into_owned(),
+            store::parse_path_infos(&json)?,
+        )
+    }
+
+    fn from_infos(root: String, infos: Vec<PathInfo>) -> Result<Self> {
+        let paths: HashMap<String, PathInfo> = infos
+            .into_iter()
+            .map(|info| (info.path.clone(), info))
+            .collect();
+        if !paths.contains_key(&root) {
+            bail!("{root} isn't part of its own closure, is it a store path?");
+        }
+        Ok(Self { root, paths })
+    }
+
+    fn closure_size(&self, path: &str) -> u64 {
+        self.paths
+            .get(path)
+            .map_or(0, |info| info.closure_size.unwrap_or(info.nar_size))
+    }
+
+    /// The references of `path`, largest closure first.
+    fn children(&self, path: &str) -> Vec<&str> {
+        let mut children: Vec<&str> = self.paths[path]
+            .references
+            .iter()
+            .map(String::as_str)
+            .filter(|reference| *reference != path && self.paths.contains_key(*reference))
+            .collect();
+        children.sort_by(|a, b| {
+            self.closure_size(b)
+                .cmp(&self.closure_size(a))
+                .then_with(|| a.cmp(b))
+        });
+        children
+    }
+
+    /// The shortest chain of references from the root to `target`.
+    #[must_use]
+    pub fn why_depends(&self, target: &str) -> Option<Vec<&str>> {
+        let mut parents: HashMap<&str, &str> = HashMap::new();
+        let mut queue = VecDeque::from([self.root.as_str()]);
+        let mut seen = HashSet::from([self.root.as_str()]);
+
+        while let Some(path) = queue.pop_front() {
+            if path == target {
+                let mut chain = vec![path];
+                while let Some(parent) = parents.get(chain[chain.len() - 1]) {
+                    chain.push(parent);
+                }
+                chain.reverse();
+                return Some(chain);
+            }
+            for child in self.children(path) {
+                if seen.insert(child) {
+                    parents.insert(child, path);
+                    queue.push_back(child);
+                }
+            }
+        }
+        None
+    }
+
+    /// Packages with more than one store path in the closure, like two
+    /// builds of glibc, with their paths. Outputs like `-bin` and `-dev` of
+    /// the same package are told apart, and packages without a version are
+    /// left out since they are usually generated files.
+    #[must_use]
+    pub fn duplicates(&self) -> Vec<(String, Vec<&str>)> {
+        let mut packages: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+        for path in self.paths.keys() {
+            let (pname, Some(version)) = split_name_version(store_name(path)) else {
+                continue;
+            };
+            let output = version
+                .rsplit_once('-')
+                .map(|(_, output)| output)
+                .filter(|output| output.chars().all(|c| c.is_ascii_lowercase()));
+            let key = match output {
+                Some(output) => format!("{pname} ({output})"),
+                None => pname.to_string(),
+            };
+            packages.entry(key).or_default().push(path);
+        }
+
+        let mut duplicates: Vec<(String, Vec<&str>)> = packages
+            .into_iter()
+            .filter(|(_, paths)| paths.len() > 1)
+            .map(|(name, mut paths)| {
+                paths.sort_unstable();
+                (name, paths)
+            })
+            .collect();
+        duplicates.sort_by_key(|(_, paths)| {
+            std::cmp::Reverse(
+                paths
+                    .iter()
+                    .map(|path| self.paths[*path].nar_size)
+                    .sum::<u64>(),
+            )
+        });
+        duplicates
+    }
+}
+
+/// The name of a store path without the directory and hash.
+fn store_name(path: &str) -> &str {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    base.split_once('-').map_or(base, |(_, name)| name)
+}
+
+/// The dependency tree of the closure down to `depth` levels, hiding
+/// dependencies whose closure is smaller than `min_size`. Paths already
+/// shown are not expanded again.
+#[must_use]
+pub fn format_tree(closure: &Closure, depth: usize, min_size: u64) -> String {
+    let mut out = String::new();
+    let _ = writeln!(
+        out,
+        "{}  {}",
+        paint(store_name(&closure.root), Role::Heading),
+        paint(
+            format_bytes(closure.closure_size(&closure.root)),
+            Role::Value
+        )
+    );
+
+    let mut shown = HashSet::from([closure.root.as_str()]);
+    tree_level(
+        closure,
+        &closure.root,
+        "",
+        depth,
+        min_size,
+        &mut shown,
+        &mut out,
+    );
+    out
+}
+
+fn tree_level<'a>(
+    closure: &'a Closure,
+    path: &str,
+    prefix: &str,
+    depth: usize,
+    min_size: u64,
+    shown: &mut HashSet<&'a str>,
+    out: &mut String,
+) {
+    if depth == 0 {
+        return;
+    }
+
+    let (visible, hidden): (Vec<&str>, Vec<&str>) = closure
+        .children(path)
+        .into_iter()
+        .partition(|child| closure.closure_size(child) >= min_size);
+
+    for (i, child) in visible.iter().enumerate() {
+        let last = i + 1 == visible.len() && hidden.is_empty();
+        let (branch, indent) = if last {
+            ("└── ", "    ")
+        } else {
+            ("├── ", "│   ")
+        };
+        let size = format!("{:>9}", format_bytes(closure.closure_size(child)));
+
+        if shown.insert(child) {
+            let _ = writeln!(
+                out,
+                "{prefix}{branch}{}  {}",
+                paint(size, Role::Value),
+                paint(store_name(child), Role::Name)
+            );
+            tree_level(
+                closure,
+                child,
+                &format!("{prefix}{indent}"),
+                depth - 1,
+                min_size,
+                shown,
+                out,
+            );
+        } else {
+            let _ = writeln!(
+                out,
+                "{prefix}{branch}{}  {} {}",
+                paint(size, Role::Value),
+                store_name(child),
+                paint("(see above)", Role::Muted)
+            );
+        }
+    }
+
+    if !hidden.is_empty() {
+        let _ = writeln!(
+            out,
+            "{prefix}└── {}",
+            paint(
+                format!(
+                    "{} smaller dependenc{}",
+                    hidden.len(),
+                    if hidden.len() == 1 { "y" } else { "ies" }
+                ),
+                Role::Muted
+            )
+        );
+    }
+}
+
+/// The packages found more than once, with the size of each path.
+#[must_use]
+pub fn format_duplicates(closure: &Closure) -> String {
+    let mut out = String::new();
+    let duplicates = closure.duplicates();
+    if duplicates.is_empty() {
+        return out;
+    }
+
+    let _ = writeln!(out, "{}", paint("Duplicate packages", Role::Heading));
+    for (name, paths) in duplicates {
+        let _ = writeln!(out, "  {} ({} paths)", paint(name, Role::Name), paths.len());
+        for path in paths {
+            let _ = writeln!(
+                out,
+                "    {:>9}  {path}",
+                format_bytes(closure.paths[path].nar_size)
+            );
+        }
+    }
+    out
+}
+
+/// Why the closure contains the paths whose name contains `pattern`.
+pub fn format_why(closure: &Closure, pattern: &str) -> Result<String> {
+    let mut targets: Vec<&str> = closure
+        .paths
+        .keys()
+        .map(String::as_str)
+        .filter(|path| store_name(path).contains(pattern))
+        .collect();
+    if targets.is_empty() {
+        bail!("No path in the closure matches {pattern}");
+    }
+    targets.sort_unstable();
+
+    let mut out = String::new();
+    for target in targets {
+        let Some(chain) = closure.why_depends(target) else {
+            continue;
+        };
+        let _ = writeln!(out, "{}", paint(target, Role::Heading));
+        for (i, path) in chain.iter().enumerate() {
+            let arrow = if i == 0 {
+                String::new()
+            } else {
+                format!("{}→ ", "  ".repeat(i - 1))
+            };
+            let _ = writeln!(out, "  {arrow}{}", paint(store_name(path), Role::Name));
+        }
+    }
+    Ok(out)
+}
+
+impl InspectArgs {
+    pub fn run(&self) -> Result<()> {
+        let path = match (&self.path, self.home) {
+            (Some(path), _) => path.clone(),
+            (None, true) => store::home_profile()?,
+            (None, false) => PathBuf::from(CURRENT_SYSTEM),
+        };
+        let store_path = std::fs::canonicalize(&path)
+            .wrap_err(format!("Failed to resolve {}", path.display()))?;
+
+        let closure = Closure::load(&store_path)?;
+
+        if let Some(pattern) = &self.why {
+            print!("{}", format_why(&closure, pattern)?);
+            return Ok(());
+        }
+
+        println!(
+            "{} paths in the closure",
+            paint(closure.paths.len(), Role::Value)
+        );
+        print!("{}", format_tree(&closure, self.depth, self.min_size));
+
+        let duplicates = format_duplicates(&closure);
+        if !duplicates.is_empty() {
+            println!();
+            print!("{duplicates}");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: u64 = 1024 * 1024;
+
+    fn info(path: &str, nar_size: u64, closure_size: u64, references: &[&str]) -> PathInfo {
+        PathInfo {
+            path: format!("/nix/store/{path}"),
+            nar_size: nar_size * MIB,
+            registration_time: None,
+            ultimate: false,
+            closure_size: Some(closure_size * MIB),
+            references: references
+                .iter()
+                .map(|r| format!("/nix/store/{r}"))
+                .collect(),
+        }
+    }
+
+    fn closure() -> Closure {
+        Closure::from_infos(
+            "/nix/store/aaa-nixos-system-host-24.11".to_string(),
+            vec![
+                info(
+                    "aaa-nixos-system-host-24.11",
+                    1,
+                    700,
+                    &["bbb-etc", "ccc-firefox-130.0", "ddd-glibc-2.39-52"],
+                ),
+                info(
+                    "bbb-etc",
+                    1,
+                    41,
+                    &["ddd-glibc-2.39-52", "eee-glibc-2.40-36"],
+                ),
+                info("ccc-firefox-130.0", 600, 640, &["ddd-glibc-2.39-52"]),
+                info("ddd-glibc-2.39-52", 40, 40, &["ddd-glibc-2.39-52"]),
+                info("eee-glibc-2.40-36", 40, 40, &[]),
+                info("fff-glibc-2.39-52-bin", 2, 42, &["ddd-glibc-2.39-52"]),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_format_tree() {
+        let closure = closure();
+        assert_eq!(
+            format_tree(&closure, 2, 0),
+            "\
+nixos-system-host-24.11  700 MiB
+├──   640 MiB  firefox-130.0
+│   └──    40 MiB  glibc-2.39-52
+├──    41 MiB  etc
+│   ├──    40 MiB  glibc-2.39-52 (see above)
+│   └──    40 MiB  glibc-2.40-36
+└──    40 MiB  glibc-2.39-52 (see above)
+"
+        );
+
+        assert_eq!(
+            format_tree(&closure, 1, 100 * MIB),
+            "\
+nixos-system-host-24.11  700 MiB
+├──   640 MiB  firefox-130.0
+└── 2 smaller dependencies
+"
+        );
+    }
+
+    #[test]
+    fn test_duplicates() {
+        let closure = closure();
+        assert_eq!(
+            closure.duplicates(),
+            vec![(
+                "glibc".to_string(),
+                vec![
+                    "/nix/store/ddd-glibc-2.39-52",
+                    "/nix/store/eee-glibc-2.40-36"
+                ]
+            )]
+        );
+    }
+
+    #[test]
+    fn test_why_depends() {
+        let closure = closure();
+        assert_eq!(
+            closure.why_depends("/nix/store/eee-glibc-2.40-36"),
+            Some(vec![
+                "/nix/store/aaa-nixos-system-host-24.11",
+                "/nix/store/bbb-etc",
+                "/nix/store/eee-glibc-2.40-36"
+            ])
+        );
+        assert_eq!(
+            closure.why_depends("/nix/store/fff-glibc-2.39-52-bin"),
+            None
+        );
+        assert!(format_why(&closure, "python").is_err());
+    }
+}
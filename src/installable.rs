@@ -1,9 +1,13 @@
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::{env, fs};
 
 use clap::error::ErrorKind;
 use clap::{Arg, ArgAction, Args, FromArgMatches};
-use color_eyre::owo_colors::OwoColorize;
+use clap_complete::engine::ArgValueCompleter;
+use color_eyre::eyre::{bail, eyre};
+use tracing::debug;
+
+use crate::theme::{Role, paint};
 
 // Reference: https://nix.dev/manual/nix/2.18/command-ref/new-cli/nix
 
@@ -42,12 +46,8 @@ impl FromArgMatches for Installable {
         let expr = matches.get_one::<String>("expr");
 
         if let Some(i) = installable {
-            let canonincal = fs::canonicalize(i);
-
-            if let Ok(p) = canonincal {
-                if p.starts_with("/nix/store") {
-                    return Ok(Self::Store { path: p });
-                }
+            if let store @ Self::Store { .. } = Self::parse(i) {
+                return Ok(store);
             }
         }
 
@@ -65,133 +65,209 @@ impl FromArgMatches for Installable {
             });
         }
 
-        if let Some(i) = installable {
-            let mut elems = i.splitn(2, '#');
-            let reference = elems.next().unwrap().to_owned();
-            return Ok(Self::Flake {
-                reference,
-                attribute: parse_attribute(
-                    elems
-                        .next()
-                        .map(std::string::ToString::to_string)
-                        .unwrap_or_default(),
-                ),
-            });
-        }
-
-        // env var fallbacks
-
-        // Check for command-specific flake env vars first
-        if let Ok(subcommand) = env::var("NH_CURRENT_COMMAND") {
-            if subcommand == "os" {
-                if let Ok(f) = env::var("NH_OS_FLAKE") {
-                    let mut elems = f.splitn(2, '#');
-                    return Ok(Self::Flake {
-                        reference: elems.next().unwrap().to_owned(),
-                        attribute: parse_attribute(
-                            elems
-                                .next()
-                                .map(std::string::ToString::to_string)
-                                .unwrap_or_default(),
-                        ),
+        // A deploy spec (`nh os --spec`) names its own flake
+        if installable.is_none() {
+            if let Ok(Some(spec)) = matches.try_get_one::<PathBuf>("spec") {
+                return crate::spec::DeploySpec::load(spec)
+                    .map(|spec| spec.installable())
+                    .map_err(|err| {
+                        clap::Error::raw(ErrorKind::InvalidValue, format!("{err:#}\n"))
                     });
-                }
-            } else if subcommand == "home" {
-                if let Ok(f) = env::var("NH_HOME_FLAKE") {
-                    let mut elems = f.splitn(2, '#');
-                    return Ok(Self::Flake {
-                        reference: elems.next().unwrap().to_owned(),
-                        attribute: parse_attribute(
-                            elems
-                                .next()
-                                .map(std::string::ToString::to_string)
-                                .unwrap_or_default(),
-                        ),
-                    });
-                }
-            } else if subcommand == "darwin" {
-                if let Ok(f) = env::var("NH_DARWIN_FLAKE") {
-                    let mut elems = f.splitn(2, '#');
-                    return Ok(Self::Flake {
-                        reference: elems.next().unwrap().to_owned(),
-                        attribute: parse_attribute(
-                            elems
-                                .next()
-                                .map(std::string::ToString::to_string)
-                                .unwrap_or_default(),
-                        ),
-                    });
-                }
             }
         }
 
-        if let Ok(f) = env::var("NH_FLAKE") {
-            let mut elems = f.splitn(2, '#');
-            return Ok(Self::Flake {
-                reference: elems.next().unwrap().to_owned(),
-                attribute: parse_attribute(
-                    elems
-                        .next()
-                        .map(std::string::ToString::to_string)
-                        .unwrap_or_default(),
-                ),
-            });
+        if let Some(i) = installable {
+            return Ok(Self::parse(i));
         }
 
-        if let Ok(f) = env::var("NH_OS_FLAKE") {
-            let mut elems = f.splitn(2, '#');
-            return Ok(Self::Flake {
-                reference: elems.next().unwrap().to_owned(),
-                attribute: parse_attribute(
-                    elems
-                        .next()
-                        .map(std::string::ToString::to_string)
-                        .unwrap_or_default(),
-                ),
-            });
+        Self::from_env(current_command().as_deref())
+            .ok_or_else(|| clap::Error::new(ErrorKind::TooFewValues))
+    }
+
+    fn update_from_arg_matches(&mut self, _matches: &clap::ArgMatches) -> Result<(), clap::Error> {
+        todo!()
+    }
+}
+
+impl Installable {
+    /// An installable given on the command line, like `.#web` or a store
+    /// path.
+    #[must_use]
+    pub fn parse(installable: &str) -> Self {
+        if let Ok(path) = fs::canonicalize(installable) {
+            if path.starts_with("/nix/store") {
+                return Self::Store { path };
+            }
         }
+        flake_installable(installable)
+    }
 
-        if let Ok(f) = env::var("NH_HOME_FLAKE") {
-            let mut elems = f.splitn(2, '#');
-            return Ok(Self::Flake {
-                reference: elems.next().unwrap().to_owned(),
-                attribute: parse_attribute(
-                    elems
-                        .next()
-                        .map(std::string::ToString::to_string)
-                        .unwrap_or_default(),
-                ),
-            });
+    /// The installable used when none is given to `command`, like `os` or
+    /// `home`: the flake of the `NH_*_FLAKE` variable or configuration
+    /// setting for it, `NH_FLAKE`, `NH_FILE`, or the closest flake to the
+    /// current directory.
+    #[must_use]
+    pub fn from_env(command: Option<&str>) -> Option<Self> {
+        let config = crate::config::get();
+        let flake = |var: &str| env::var(var).ok().or_else(|| config.flake(var));
+
+        // Check for command-specific flake env vars first
+        let command_var = match command {
+            Some("os") => Some("NH_OS_FLAKE"),
+            Some("home") => Some("NH_HOME_FLAKE"),
+            Some("darwin") => Some("NH_DARWIN_FLAKE"),
+            Some("sys") => Some("NH_SYSTEM_FLAKE"),
+            _ => None,
+        };
+        if let Some(f) = command_var.and_then(flake) {
+            return Some(flake_installable(&f));
         }
 
-        if let Ok(f) = env::var("NH_DARWIN_FLAKE") {
-            let mut elems = f.splitn(2, '#');
-            return Ok(Self::Flake {
-                reference: elems.next().unwrap().to_owned(),
-                attribute: parse_attribute(
-                    elems
-                        .next()
-                        .map(std::string::ToString::to_string)
-                        .unwrap_or_default(),
-                ),
-            });
+        for var in [
+            "NH_FLAKE",
+            "NH_OS_FLAKE",
+            "NH_HOME_FLAKE",
+            "NH_DARWIN_FLAKE",
+        ] {
+            if let Some(f) = flake(var) {
+                return Some(flake_installable(&f));
+            }
         }
 
         if let Ok(f) = env::var("NH_FILE") {
-            return Ok(Self::File {
+            return Some(Self::File {
                 path: PathBuf::from(f),
                 attribute: parse_attribute(env::var("NH_ATTRP").unwrap_or_default()),
             });
         }
 
-        Err(clap::Error::new(ErrorKind::TooFewValues))
+        // Look for a flake in the current directory and its ancestors
+        let output = match command {
+            Some("os") => Some("nixosConfigurations"),
+            Some("home") => Some("homeConfigurations"),
+            Some("darwin") => Some("darwinConfigurations"),
+            Some("sys") => Some("systemConfigs"),
+            _ => None,
+        };
+        let flake = discover_flake(&env::current_dir().ok()?, output)?;
+        debug!("Using flake discovered at {}", flake.display());
+        Some(Self::Flake {
+            reference: flake.to_string_lossy().into_owned(),
+            attribute: vec![],
+        })
     }
+}
 
-    fn update_from_arg_matches(&mut self, _matches: &clap::ArgMatches) -> Result<(), clap::Error> {
-        todo!()
+/// A flake installable like `/etc/nixos#web`.
+fn flake_installable(installable: &str) -> Installable {
+    let mut elems = installable.splitn(2, '#');
+    Installable::Flake {
+        reference: elems.next().unwrap_or_default().to_owned(),
+        attribute: parse_attribute(elems.next().unwrap_or_default()),
+    }
+}
+
+/// The top-level nh subcommand being run.
+///
+/// `NH_CURRENT_COMMAND` is only set once argument parsing is done, so fall
+/// back to looking for the subcommand in our own arguments.
+pub fn current_command() -> Option<String> {
+    env::var("NH_CURRENT_COMMAND").ok().or_else(|| {
+        env::args()
+            .skip(1)
+            .find(|arg| matches!(arg.as_str(), "os" | "home" | "darwin" | "sys"))
+    })
+}
+
+/// The flake of a command working on a whole flake, like `nh flake show`:
+/// `flake` without its attribute, or else the flake nh builds from when no
+/// installable is given.
+pub fn flake_reference(flake: Option<&str>) -> color_eyre::Result<String> {
+    let installable = match flake {
+        Some(flake) => Installable::parse(flake),
+        None => Installable::from_env(None).ok_or_else(|| {
+            eyre!("No flake given, and none was found in NH_FLAKE or the current directory")
+        })?,
+    };
+    match installable {
+        Installable::Flake { reference, .. } => Ok(reference),
+        other => bail!("{} is not a flake", other.to_args().join(" ")),
     }
 }
 
+/// The flake nh uses when no installable is given and no output is
+/// preferred: the flake of `NH_FLAKE`, or the closest one to the current
+/// directory.
+pub fn default_flake() -> Option<String> {
+    if let Some(flake) = env::var("NH_FLAKE").ok().filter(|flake| !flake.is_empty()) {
+        return flake.split('#').next().map(str::to_string);
+    }
+
+    env::current_dir()
+        .ok()
+        .and_then(|cwd| discover_flake(&cwd, None))
+        .map(|flake| flake.to_string_lossy().into_owned())
+}
+
+/// Walk up from `start` looking for a directory with a `flake.nix`, like git
+/// does for `.git`.
+///
+/// If `output` is given, the closest flake mentioning it (e.g.
+/// `nixosConfigurations`) is preferred. Otherwise, or if no flake mentions
+/// it, since outputs may well be defined in other files, the closest flake is
+/// used.
+fn discover_flake(start: &Path, output: Option<&str>) -> Option<PathBuf> {
+    let mut closest = None;
+
+    for dir in start.ancestors() {
+        let flake_nix = dir.join("flake.nix");
+        let Ok(contents) = fs::read_to_string(&flake_nix) else {
+            continue;
+        };
+
+        debug!("Found flake at {}", flake_nix.display());
+        match output {
+            Some(output) if !contents.contains(output) => {
+                closest.get_or_insert_with(|| dir.to_path_buf());
+            }
+            _ => return Some(dir.to_path_buf()),
+        }
+    }
+
+    closest
+}
+
+#[test]
+fn test_discover_flake() {
+    let root = tempfile::tempdir().unwrap();
+    let nested = root.path().join("hosts").join("laptop");
+    fs::create_dir_all(&nested).unwrap();
+
+    assert_eq!(discover_flake(&nested, None), None);
+
+    fs::write(
+        root.path().join("flake.nix"),
+        "{ outputs = _: { nixosConfigurations = {}; }; }",
+    )
+    .unwrap();
+    fs::write(
+        root.path().join("hosts").join("flake.nix"),
+        "{ outputs = _: { packages = {}; }; }",
+    )
+    .unwrap();
+
+    let hosts = root.path().join("hosts");
+    assert_eq!(discover_flake(&nested, None), Some(hosts.clone()));
+    assert_eq!(
+        discover_flake(&nested, Some("nixosConfigurations")),
+        Some(root.path().to_path_buf())
+    );
+    assert_eq!(
+        discover_flake(&nested, Some("homeConfigurations")),
+        Some(hosts)
+    );
+}
+
 impl Args for Installable {
     fn augment_args(cmd: clap::Command) -> clap::Command {
         cmd.arg(
@@ -213,6 +289,9 @@ impl Args for Installable {
             Arg::new("installable")
                 .action(ArgAction::Set)
                 .value_name("INSTALLABLE")
+                .add(ArgValueCompleter::new(
+                    crate::completion::complete_installable,
+                ))
                 .help("Which installable to use")
                 .long_help(format!(
                     r"Which installable to use.
@@ -224,6 +303,7 @@ Nix accepts various kinds of installables:
     [env: NH_OS_FLAKE={}]
     [env: NH_HOME_FLAKE={}]
     [env: NH_DARWIN_FLAKE={}]
+    [env: NH_SYSTEM_FLAKE={}]
 
 {}, {} <FILE> [ATTRPATH]
     Path to file with an optional attribute path.
@@ -240,12 +320,13 @@ Nix accepts various kinds of installables:
                     env::var("NH_OS_FLAKE").unwrap_or_default(),
                     env::var("NH_HOME_FLAKE").unwrap_or_default(),
                     env::var("NH_DARWIN_FLAKE").unwrap_or_default(),
-                    "-f".yellow(),
-                    "--file".yellow(),
+                    env::var("NH_SYSTEM_FLAKE").unwrap_or_default(),
+                    paint("-f", Role::Literal),
+                    paint("--file", Role::Literal),
                     env::var("NH_FILE").unwrap_or_default(),
                     env::var("NH_ATTR").unwrap_or_default(),
-                    "-e".yellow(),
-                    "--expr".yellow(),
+                    paint("-e", Role::Literal),
+                    paint("--expr", Role::Literal),
                 )),
         )
     }
@@ -337,6 +418,46 @@ impl Installable {
     }
 }
 
+impl Installable {
+    /// Set a query parameter such as `rev` or `ref` on a flake reference,
+    /// replacing any value already present for the same key.
+    pub fn set_flake_param(&mut self, key: &str, value: &str) -> color_eyre::Result<()> {
+        let Self::Flake { reference, .. } = self else {
+            bail!("Setting the flake {key} requires a flake installable");
+        };
+
+        *reference = set_query_param(reference, key, value);
+        Ok(())
+    }
+}
+
+fn set_query_param(reference: &str, key: &str, value: &str) -> String {
+    let (base, query) = reference.split_once('?').unwrap_or((reference, ""));
+
+    let mut params: Vec<&str> = query
+        .split('&')
+        .filter(|param| !param.is_empty())
+        .filter(|param| param.split_once('=').map_or(*param, |(k, _)| k) != key)
+        .collect();
+    let new_param = format!("{key}={value}");
+    params.push(&new_param);
+
+    format!("{base}?{}", params.join("&"))
+}
+
+#[test]
+fn test_set_query_param() {
+    assert_eq!(set_query_param(".", "rev", "abc"), ".?rev=abc");
+    assert_eq!(
+        set_query_param("github:foo/bar?dir=sub", "ref", "main"),
+        "github:foo/bar?dir=sub&ref=main"
+    );
+    assert_eq!(
+        set_query_param("git+file:/src?rev=old&submodules=1", "rev", "new"),
+        "git+file:/src?submodules=1&rev=new"
+    );
+}
+
 #[test]
 fn test_installable_to_args() {
     assert_eq!(
@@ -1,17 +1,30 @@
 use std::env;
+use std::ffi::OsString;
 use std::path::PathBuf;
 
 use anstyle::Style;
+use chrono::{DateTime, Utc};
 use clap::ValueEnum;
 use clap::{Args, Parser, Subcommand, builder::Styles};
-use clap_verbosity_flag::WarnLevel;
+use clap_complete::engine::ArgValueCompleter;
+use color_eyre::eyre::Context;
+use tracing::{info, warn};
 
 use crate::Result;
+use crate::builders::RemoteBuilder;
 use crate::checks::{
     DarwinReplFeatures, FeatureRequirements, FlakeFeatures, HomeReplFeatures, LegacyFeatures,
-    NoFeatures, OsReplFeatures,
+    NoFeatures, OsReplFeatures, SkippableCheck,
 };
+use crate::clean::KeepSince;
+use crate::completion::{Configurations, Generations, Specialisations, complete_package};
+use crate::exit::Failure;
 use crate::installable::Installable;
+use crate::logging::{NhLogLevel, SystemLog};
+use crate::push::PushTarget;
+use crate::template::Template;
+use crate::theme::ColorChoice;
+use crate::util::MaybeTempPath;
 
 const fn make_style() -> Styles {
     Styles::plain().header(Style::new().bold()).literal(
@@ -41,7 +54,99 @@ pub struct Main {
     #[command(flatten)]
     /// Increase logging verbosity, can be passed multiple times for
     /// more detailed logs.
-    pub verbosity: clap_verbosity_flag::Verbosity<WarnLevel>,
+    pub verbosity: clap_verbosity_flag::Verbosity<NhLogLevel>,
+
+    /// Write newline-delimited JSON events describing the run to this file
+    /// descriptor, e.g. `--json-events 3 3>events.jsonl`
+    #[arg(long, global = true, value_name = "FD")]
+    pub json_events: Option<i32>,
+
+    /// Run child commands with a cleared environment, only passing through an
+    /// allowlist of variables needed by Nix and nh
+    #[arg(long, global = true, env = "NH_CLEAN_ENV", value_parser = clap::builder::BoolishValueParser::new())]
+    pub clean_env: bool,
+
+    /// Print a single JSON document with the result of the run instead of
+    /// human readable output
+    #[arg(long, global = true, env = "NH_JSON", value_parser = clap::builder::BoolishValueParser::new())]
+    pub json: bool,
+
+    /// Skip a safety check, can be repeated or comma separated. NH_NO_CHECKS
+    /// takes the same list, or skips everything if set to 1, true or all
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        value_delimiter = ',',
+        value_name = "CHECK"
+    )]
+    pub skip_check: Vec<SkippableCheck>,
+
+    /// Tool used to show package diffs between configurations
+    #[arg(
+        long,
+        global = true,
+        env = "NH_DIFF_TOOL",
+        value_enum,
+        default_value_t = DiffTool::Auto,
+        value_name = "TOOL"
+    )]
+    pub diff_tool: DiffTool,
+
+    /// How to print package diffs. `json` and `summary` use the built-in
+    /// differ
+    #[arg(
+        long,
+        global = true,
+        env = "NH_DIFF_FORMAT",
+        value_enum,
+        default_value_t = DiffFormat::Human,
+        value_name = "FORMAT"
+    )]
+    pub diff_format: DiffFormat,
+
+    /// Answer given to --ask prompts when enter is pressed, or when nobody
+    /// answers within --ask-timeout
+    #[arg(long, global = true, env = "NH_ASK_DEFAULT", value_parser = clap::builder::BoolishValueParser::new(), value_name = "YES|NO")]
+    pub ask_default: Option<bool>,
+
+    /// Give up waiting for an answer to --ask prompts after this long, like
+    /// 30s or 5m, and take the default answer
+    #[arg(long, global = true, env = "NH_ASK_TIMEOUT", value_name = "DURATION")]
+    pub ask_timeout: Option<humantime::Duration>,
+
+    /// Print how long each phase took at the end of the run, compared to
+    /// the previous run of the same command
+    #[arg(long, global = true, env = "NH_TIMINGS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub timings: bool,
+
+    /// When to color output. `auto` honors NO_COLOR and CLICOLOR_FORCE
+    #[arg(long, global = true, value_enum, default_value_t = ColorChoice::Auto, value_name = "WHEN")]
+    pub color: ColorChoice,
+
+    /// Also send log messages to the system log, for runs from timers or
+    /// deploy automation
+    #[arg(
+        long,
+        global = true,
+        env = "NH_SYSTEM_LOG",
+        value_enum,
+        value_name = "BACKEND"
+    )]
+    pub system_log: Option<SystemLog>,
+
+    /// Show a desktop notification when a rebuild finishes or fails
+    #[arg(long, global = true, env = "NH_NOTIFY", value_parser = clap::builder::BoolishValueParser::new())]
+    pub notify: bool,
+
+    /// Post the result of a rebuild as JSON to this URL when it finishes or
+    /// fails
+    #[arg(long, global = true, env = "NH_WEBHOOK", value_name = "URL")]
+    pub webhook: Option<String>,
+
+    /// Only notify about rebuilds that took longer than this, like 30s or 5m
+    #[arg(long, global = true, env = "NH_NOTIFY_AFTER", value_name = "DURATION")]
+    pub notify_after: Option<humantime::Duration>,
 
     #[command(subcommand)]
     pub command: NHCommand,
@@ -54,10 +159,28 @@ pub enum NHCommand {
     Home(HomeArgs),
     Darwin(DarwinArgs),
     Sys(SysArgs),
+    Build(BuildArgs),
+    Run(RunArgs),
+    Shell(ShellArgs),
+    Develop(DevelopArgs),
+    Profile(ProfileProxy),
     Search(SearchArgs),
+    Check(CheckArgs),
     Clean(CleanProxy),
+    Update(UpdateProxy),
+    Flake(FlakeProxy),
+    Init(InitArgs),
+    Store(StoreProxy),
+    Inspect(InspectArgs),
+    Stats(StatsArgs),
+    Log(LogArgs),
+    Doctor(DoctorArgs),
+    SelfUpdate(SelfUpdateArgs),
     #[command(hide = true)]
     Completions(CompletionArgs),
+    /// A plugin, `nh-<name>` in `PATH`
+    #[command(external_subcommand)]
+    External(Vec<OsString>),
 }
 
 impl NHCommand {
@@ -68,16 +191,32 @@ impl NHCommand {
             Self::Home(args) => args.get_feature_requirements(),
             Self::Darwin(args) => args.get_feature_requirements(),
             Self::Sys(args) => args.get_feature_requirements(),
+            Self::Build(_) => Box::new(FlakeFeatures),
+            Self::Run(_) | Self::Shell(_) | Self::Develop(_) => Box::new(FlakeFeatures),
+            Self::Profile(_) => Box::new(FlakeFeatures),
             Self::Search(_) => Box::new(NoFeatures),
+            Self::Check(_) => Box::new(FlakeFeatures),
             Self::Clean(_) => Box::new(NoFeatures),
+            Self::Update(_) => Box::new(NoFeatures),
+            Self::Flake(_) => Box::new(FlakeFeatures),
+            Self::Init(_) => Box::new(NoFeatures),
+            Self::Store(_) => Box::new(NoFeatures),
+            Self::Inspect(_) => Box::new(NoFeatures),
+            Self::Stats(_) => Box::new(NoFeatures),
+            Self::Log(_) => Box::new(NoFeatures),
+            Self::Doctor(_) => Box::new(NoFeatures),
+            Self::SelfUpdate(_) => Box::new(NoFeatures),
             Self::Completions(_) => Box::new(NoFeatures),
+            Self::External(_) => Box::new(NoFeatures),
         }
     }
 
     pub fn run(self) -> Result<()> {
         // Check features specific to this command
         let requirements = self.get_feature_requirements();
-        requirements.check_features()?;
+        requirements
+            .check_features()
+            .wrap_err(Failure::Environment)?;
 
         match self {
             Self::Os(args) => {
@@ -86,9 +225,25 @@ impl NHCommand {
                 }
                 args.run()
             }
+            Self::Build(args) => args.run(),
+            Self::Run(args) => args.run(),
+            Self::Shell(args) => args.run(),
+            Self::Develop(args) => args.run(),
+            Self::Profile(proxy) => proxy.command.run(),
             Self::Search(args) => args.run(),
+            Self::Check(args) => args.run(),
             Self::Clean(proxy) => proxy.command.run(),
+            Self::Update(proxy) => proxy.run(),
+            Self::Flake(proxy) => proxy.command.run(),
+            Self::Init(args) => args.run(),
+            Self::Store(proxy) => proxy.command.run(),
+            Self::Inspect(args) => args.run(),
+            Self::Stats(args) => args.run(),
+            Self::Log(args) => args.run(),
+            Self::Doctor(args) => args.run(),
+            Self::SelfUpdate(args) => args.run(),
             Self::Completions(args) => args.run(),
+            Self::External(args) => crate::plugin::run(&args),
             Self::Home(args) => {
                 unsafe {
                     std::env::set_var("NH_CURRENT_COMMAND", "home");
@@ -129,6 +284,14 @@ impl OsArgs {
                 let is_flake = args.uses_flakes();
                 Box::new(OsReplFeatures { is_flake })
             }
+            OsSubcommand::Deploy(_) => Box::new(FlakeFeatures),
+            OsSubcommand::Option(args) => {
+                if matches!(args.installable, Installable::Flake { .. }) {
+                    Box::new(FlakeFeatures)
+                } else {
+                    Box::new(LegacyFeatures)
+                }
+            }
             OsSubcommand::Switch(args)
             | OsSubcommand::Boot(args)
             | OsSubcommand::Test(args)
@@ -139,14 +302,23 @@ impl OsArgs {
                     Box::new(LegacyFeatures)
                 }
             }
-            OsSubcommand::BuildVm(args) => {
-                if args.common.uses_flakes() {
+            OsSubcommand::BuildVm(OsBuildVmArgs { common: args, .. })
+            | OsSubcommand::Install(OsInstallArgs { common: args, .. }) => {
+                if args.uses_flakes() {
                     Box::new(FlakeFeatures)
                 } else {
                     Box::new(LegacyFeatures)
                 }
             }
-            OsSubcommand::Info(_) | OsSubcommand::Rollback(_) => Box::new(LegacyFeatures),
+            OsSubcommand::Info(_)
+            | OsSubcommand::Rollback(_)
+            | OsSubcommand::Tag(_)
+            | OsSubcommand::History(_)
+            | OsSubcommand::Export(_)
+            | OsSubcommand::CheckBoot(_)
+            | OsSubcommand::Status(_)
+            | OsSubcommand::Pin(_)
+            | OsSubcommand::Unpin(_) => Box::new(LegacyFeatures),
         }
     }
 }
@@ -165,17 +337,48 @@ pub enum OsSubcommand {
     /// Build the new configuration
     Build(OsRebuildArgs),
 
+    /// Deploy the configurations of hosts from the inventory, one wave at a time
+    Deploy(OsDeployArgs),
+
     /// Load system in a repl
     Repl(OsReplArgs),
 
+    /// Show the value, type, default, description and definitions of an option
+    Option(OsOptionArgs),
+
     /// List available generations from profile path
     Info(OsGenerationsArgs),
 
     /// Rollback to a previous generation
     Rollback(OsRollbackArgs),
 
+    /// Label a generation, e.g. to mark it as known-good
+    Tag(OsTagArgs),
+
+    /// Show which generations added, removed or changed the version of a package
+    History(OsHistoryArgs),
+
+    /// Copy the closure of a generation to another machine or into a file
+    Export(OsExportArgs),
+
+    /// Check that every generation has a bootloader entry and vice versa
+    CheckBoot(OsCheckBootArgs),
+
+    /// Compare the booted and running systems with the boot default, e.g. to
+    /// see if a reboot is pending
+    Status(OsStatusArgs),
+
+    /// Protect a generation from being removed by `nh clean`
+    Pin(OsPinArgs),
+
+    /// Allow a pinned generation to be removed by `nh clean` again
+    Unpin(OsPinArgs),
+
     /// Build a `NixOS` VM image
     BuildVm(OsBuildVmArgs),
+
+    /// Install the configuration on a new machine, e.g. from the installer
+    Install(OsInstallArgs),
 }
 
 #[derive(Debug, Args)]
@@ -188,6 +391,86 @@ pub struct OsBuildVmArgs {
     pub with_bootloader: bool,
 }
 
+#[derive(Debug, Args)]
+pub struct OsInstallArgs {
+    #[command(flatten)]
+    pub common: OsRebuildArgs,
+
+    /// Where the file systems of the new machine are mounted
+    #[arg(long, default_value = "/mnt")]
+    pub root: PathBuf,
+
+    /// Partition, format and mount the disks with the disko configuration of
+    /// the host first. This erases the disks!
+    #[arg(long)]
+    pub disko: bool,
+
+    /// Don't ask before erasing the disks with --disko
+    #[arg(long, requires = "disko")]
+    pub yes: bool,
+}
+
+#[derive(Debug, Args)]
+#[command(group(
+    clap::ArgGroup::new("selection")
+        .args(["tags", "hosts", "all"])
+        .required(true)
+        .multiple(true)
+))]
+pub struct OsDeployArgs {
+    #[command(flatten)]
+    pub common: CommonRebuildArgs,
+
+    /// Deploy the hosts of the inventory with this tag, can be repeated
+    #[arg(long = "tag", value_name = "TAG")]
+    pub tags: Vec<String>,
+
+    /// Deploy this host of the inventory, can be repeated
+    #[arg(long = "host", value_name = "NAME")]
+    pub hosts: Vec<String>,
+
+    /// Deploy every host of the inventory
+    #[arg(long, conflicts_with_all = ["tags", "hosts"])]
+    pub all: bool,
+
+    /// Make the configurations the boot default instead of activating them
+    #[arg(long)]
+    pub boot: bool,
+
+    #[command(flatten)]
+    pub copy: CopyArgs,
+
+    /// If the connection to a host drops during activation, wait this long
+    /// for it to come back, 5m by default, and check that it runs the new
+    /// configuration
+    #[arg(long, value_name = "TIMEOUT", num_args = 0..=1, default_missing_value = "5m")]
+    pub wait_online: Option<humantime::Duration>,
+
+    /// Before activating, make sure that a key for the sops-nix or agenix
+    /// secrets of the configuration exists on the target. This evaluates
+    /// the configuration once more
+    #[arg(long, env = "NH_CHECK_SECRETS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub check_secrets: bool,
+
+    /// Deploy up to this many hosts at the same time, after the canaries.
+    /// Defaults to the lowest max-parallel of the deployed groups, or 1
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(1..))]
+    pub max_parallel: Option<u32>,
+
+    /// Deploy this host first and on its own, and only go on if it succeeds,
+    /// on top of the canaries of the deployed groups. Can be repeated
+    #[arg(long = "canary", value_name = "NAME")]
+    pub canaries: Vec<String>,
+
+    /// Don't panic if calling nh as root
+    #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
+    pub bypass_root_check: bool,
+
+    /// Extra arguments passed to nix build
+    #[arg(last = true)]
+    pub extra_args: Vec<String>,
+}
+
 #[derive(Debug, Args)]
 pub struct OsRebuildArgs {
     #[command(flatten)]
@@ -197,11 +480,20 @@ pub struct OsRebuildArgs {
     pub update_args: UpdateArgs,
 
     /// When using a flake installable, select this hostname from nixosConfigurations
-    #[arg(long, short = 'H', global = true)]
+    #[arg(long, short = 'H', global = true, add = ArgValueCompleter::new(Configurations("nixosConfigurations")))]
     pub hostname: Option<String>,
 
+    /// Build each of these hosts from nixosConfigurations and summarize the
+    /// results, only supported by `nh os build`
+    #[arg(long, value_delimiter = ',', conflicts_with = "hostname")]
+    pub hosts: Vec<String>,
+
+    /// Number of --hosts builds to run at the same time
+    #[arg(long, default_value_t = 1, requires = "hosts")]
+    pub parallel: usize,
+
     /// Explicitly select some specialisation
-    #[arg(long, short)]
+    #[arg(long, short, add = ArgValueCompleter::new(Specialisations::System))]
     pub specialisation: Option<String>,
 
     /// Ignore specialisations
@@ -223,6 +515,46 @@ pub struct OsRebuildArgs {
     /// Build the configuration to a different host over ssh
     #[arg(long)]
     pub build_host: Option<String>,
+
+    #[command(flatten)]
+    pub copy: CopyArgs,
+
+    /// If the connection to --target-host drops during activation, e.g.
+    /// because it reboots, wait this long for it to come back, 5m by
+    /// default, and check that it runs the new configuration
+    #[arg(long, value_name = "TIMEOUT", num_args = 0..=1, default_missing_value = "5m", requires = "target_host")]
+    pub wait_online: Option<humantime::Duration>,
+
+    /// Stop before switching if the boot partition has less free space than
+    /// this, like 200M, or than the new kernel and initrd need. 100M by
+    /// default
+    #[arg(long, value_name = "SIZE", env = "NH_MIN_FREE_BOOT", value_parser = crate::util::parse_size)]
+    pub min_free_boot: Option<u64>,
+
+    /// Before activating, make sure that a key for the sops-nix or agenix
+    /// secrets of the configuration exists on the target. This evaluates
+    /// the configuration once more
+    #[arg(long, env = "NH_CHECK_SECRETS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub check_secrets: bool,
+
+    /// With --ask, list the units the switch would stop, restart or start by
+    /// running dry-activate before asking
+    #[arg(long, env = "NH_DRY_ACTIVATE", value_parser = clap::builder::BoolishValueParser::new())]
+    pub dry_activate: bool,
+
+    /// Read the flake, hosts, specialisation and extra arguments from a TOML
+    /// or JSON deploy spec. Flags given on the command line take precedence.
+    #[arg(long, value_name = "FILE")]
+    pub spec: Option<PathBuf>,
+
+    /// Scan the new system for known vulnerabilities with vulnix and compare
+    /// it to the current one
+    #[arg(long)]
+    pub vuln_scan: bool,
+
+    /// Like --vuln-scan, but fail if the new system introduces vulnerabilities
+    #[arg(long)]
+    pub fail_on_vuln: bool,
 }
 
 impl OsRebuildArgs {
@@ -238,7 +570,8 @@ impl OsRebuildArgs {
     }
 }
 
-#[derive(ValueEnum, Clone, Default, Debug)]
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum DiffType {
     /// Display package diff only if the of the
     /// current and the deployed configuration matches
@@ -250,6 +583,104 @@ pub enum DiffType {
     Never,
 }
 
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq)]
+pub enum DiffBase {
+    /// The current configuration
+    #[default]
+    Current,
+    /// The last result kept with --keep-results
+    LastBuild,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffTool {
+    /// dix, or the built-in differ if dix can't read the Nix database
+    #[default]
+    Auto,
+    /// dix, which is built into nh
+    Dix,
+    /// nvd, which has to be installed
+    Nvd,
+    /// nh's own differ, which only needs `nix path-info`
+    Builtin,
+}
+
+#[derive(ValueEnum, Clone, Copy, Default, Debug, PartialEq, Eq, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum DiffFormat {
+    /// The output of the diff tool
+    #[default]
+    Human,
+    /// A JSON object on one line, with the added, removed and changed
+    /// packages
+    Json,
+    /// A single line counting added, removed and upgraded packages
+    Summary,
+}
+
+#[derive(Debug, Args)]
+pub struct OsTagArgs {
+    /// Label to set, an empty label removes it
+    pub label: String,
+
+    /// Generation to label, defaults to the current one
+    pub generation: Option<u64>,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsHistoryArgs {
+    /// Package name, as it appears in store paths, e.g. `firefox` or `linux`
+    pub package: String,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsExportArgs {
+    /// Generation number to export
+    pub generation: u64,
+
+    /// Where to copy the closure to. Either a Nix store URL like
+    /// `ssh://host`, or `file:PATH` to write a `nix-store --export` archive
+    #[arg(long)]
+    pub to: String,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsCheckBootArgs {
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsStatusArgs {
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
+#[derive(Debug, Args)]
+pub struct OsPinArgs {
+    /// Generation to (un)pin, defaults to the current one
+    pub generation: Option<u64>,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: PathBuf,
+}
+
 #[derive(Debug, Args)]
 pub struct OsRollbackArgs {
     /// Only print actions, without performing them
@@ -257,11 +688,11 @@ pub struct OsRollbackArgs {
     pub dry: bool,
 
     /// Ask for confirmation
-    #[arg(long, short)]
+    #[arg(long, short, env = "NH_ASK", value_parser = clap::builder::BoolishValueParser::new())]
     pub ask: bool,
 
     /// Explicitly select some specialisation
-    #[arg(long, short)]
+    #[arg(long, short, add = ArgValueCompleter::new(Specialisations::System))]
     pub specialisation: Option<String>,
 
     /// Ignore specialisations
@@ -269,116 +700,888 @@ pub struct OsRollbackArgs {
     pub no_specialisation: bool,
 
     /// Rollback to a specific generation number (defaults to previous generation)
-    #[arg(long, short)]
+    #[arg(long, short, add = ArgValueCompleter::new(Generations(crate::nixos::SYSTEM_PROFILE)))]
     pub to: Option<u64>,
 
+    /// Rollback to the newest generation built before this date, like
+    /// 2024-01-15 or '2024-01-15 14:30'
+    #[arg(long, value_parser = crate::generations::parse_date, conflicts_with_all = ["to", "before"])]
+    pub to_date: Option<DateTime<Utc>>,
+
+    /// Rollback to the newest generation built longer ago than this, like 3d
+    #[arg(long, conflicts_with = "to")]
+    pub before: Option<humantime::Duration>,
+
     /// Don't panic if calling nh as root
     #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
     pub bypass_root_check: bool,
 
     /// Whether to display a package diff
-    #[arg(long, short, value_enum, default_value_t = DiffType::Auto)]
+    #[arg(long, short, value_enum, env = "NH_DIFF", default_value_t = DiffType::Auto)]
     pub diff: DiffType,
+
+    /// Only print the package diff between the current system and the
+    /// generation the rollback would switch to
+    #[arg(long, conflicts_with = "dry")]
+    pub diff_only: bool,
+
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = crate::nixos::SYSTEM_PROFILE, hide = true)]
+    pub profile: PathBuf,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Clone, Args)]
 pub struct CommonRebuildArgs {
     /// Only print actions, without performing them
     #[arg(long, short = 'n')]
     pub dry: bool,
 
     /// Ask for confirmation
-    #[arg(long, short)]
+    #[arg(long, short, env = "NH_ASK", value_parser = clap::builder::BoolishValueParser::new())]
     pub ask: bool,
 
     #[command(flatten)]
     pub installable: Installable,
 
     /// Don't use nix-output-monitor for the build process
-    #[arg(long)]
+    #[arg(long, env = "NH_NO_NOM", value_parser = clap::builder::BoolishValueParser::new())]
     pub no_nom: bool,
 
     /// Path to save the result link, defaults to using a temporary directory
     #[arg(long, short)]
     pub out_link: Option<PathBuf>,
 
+    /// Keep the results of the last N builds of each configuration under
+    /// nh's state directory instead of a temporary directory, to inspect,
+    /// diff against or activate them later
+    #[arg(
+        long,
+        value_name = "N",
+        env = "NH_KEEP_RESULTS",
+        conflicts_with = "out_link"
+    )]
+    pub keep_results: Option<usize>,
+
     /// Whether to display a package diff
-    #[arg(long, short, value_enum, default_value_t = DiffType::Auto)]
+    #[arg(long, short, value_enum, env = "NH_DIFF", default_value_t = DiffType::Auto)]
     pub diff: DiffType,
 
+    /// What to show the package diff against
+    #[arg(long, value_enum, default_value_t = DiffBase::Current, value_name = "BASE")]
+    pub diff_against: DiffBase,
+
+    /// Build the flake at this commit, regardless of the state of the working tree
+    #[arg(long, visible_alias = "commit", value_name = "SHA")]
+    pub rev: Option<String>,
+
+    /// Build the flake at this git branch or tag
+    #[arg(long = "ref", id = "git_ref", value_name = "REF")]
+    pub git_ref: Option<String>,
+
+    /// Abort before building if /nix has less free space than this, like 512M or 5G (0 to disable)
+    #[arg(
+        long,
+        value_name = "SIZE",
+        env = "NH_MIN_FREE_SPACE",
+        default_value = "1G",
+        value_parser = crate::util::parse_size
+    )]
+    pub min_free_space: u64,
+
+    /// Warn before building if a configured substituter is unreachable
+    #[arg(long, env = "NH_CHECK_SUBSTITUTERS")]
+    pub check_substituters: bool,
+
+    /// Push the built closure to a binary cache, either `cachix:NAME` or a
+    /// Nix store URL like `s3://bucket`
+    #[arg(long, value_name = "CACHE", env = "NH_PUSH_TO", value_parser = PushTarget::parse)]
+    pub push_to: Option<PushTarget>,
+
+    /// Secret key to sign the closure with before pushing it to a store URL
+    #[arg(long, value_name = "FILE", env = "NH_PUSH_KEY", requires = "push_to")]
+    pub push_key: Option<PathBuf>,
+
+    /// Commit all changes to the flake's git repository, including untracked
+    /// files, with this message before building
+    #[arg(long, value_name = "MESSAGE", conflicts_with_all = ["rev", "git_ref"])]
+    pub commit_changes: Option<String>,
+
+    /// Stash the changes to the flake's git repository before building, and
+    /// restore them afterwards
+    #[arg(long, conflicts_with_all = ["commit_changes", "rev", "git_ref"])]
+    pub stash: bool,
+
+    /// Build the configuration even if nothing changed since it was last
+    /// built
+    #[arg(long, env = "NH_ALWAYS_BUILD", value_parser = clap::builder::BoolishValueParser::new())]
+    pub always_build: bool,
+
+    /// Refuse to build a flake with uncommitted changes
+    #[arg(long, env = "NH_REQUIRE_CLEAN", value_parser = clap::builder::BoolishValueParser::new())]
+    pub require_clean: bool,
+
+    /// Activate the closure even if it isn't signed by one of the
+    /// `trusted-keys` from the configuration
+    #[arg(long, env = "NH_NO_CHECK_SIGS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_check_sigs: bool,
+
+    /// Distribute the build over this remote builder, can be repeated. Takes
+    /// `HOST[,systems=SYS:SYS][,max-jobs=N][,speed-factor=N][,ssh-key=FILE][,features=F:F]`
+    /// and replaces the builders from the configuration
+    #[arg(
+        long = "remote-builder",
+        value_name = "BUILDER",
+        value_parser = RemoteBuilder::parse,
+        conflicts_with = "builders"
+    )]
+    pub remote_builders: Vec<RemoteBuilder>,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
     #[command(flatten)]
     pub passthrough: NixBuildPassthroughArgs,
 }
 
+impl CommonRebuildArgs {
+    /// Make sure /nix has the space set with `--min-free-space` left.
+    pub fn check_free_space(&self) -> Result<()> {
+        if self.dry || self.min_free_space == 0 {
+            return Ok(());
+        }
+
+        crate::checks::check_free_space(std::path::Path::new("/nix"), self.min_free_space)
+    }
+
+    /// The builders given with `--remote-builder`, or else those from the
+    /// configuration unless `--builders` was passed.
+    #[must_use]
+    pub fn remote_builders(&self) -> Vec<RemoteBuilder> {
+        if !self.remote_builders.is_empty() {
+            self.remote_builders.clone()
+        } else if self.passthrough.builders.is_some() {
+            vec![]
+        } else {
+            crate::config::get().builders.clone()
+        }
+    }
+
+    /// The first passed option that Nix only honors for trusted users.
+    #[must_use]
+    pub fn trusted_option(&self) -> Option<&'static str> {
+        if self.remote_builders().is_empty() {
+            self.passthrough.trusted_option()
+        } else {
+            Some("--remote-builder")
+        }
+    }
+
+    /// Warn about unusable substituters if `--check-substituters` was passed.
+    pub fn check_substituters(&self) {
+        if self.check_substituters && !self.dry {
+            crate::checks::warn_unusable_substituters();
+        }
+    }
+
+    /// Where to link the result of a build of the configuration `name`: the
+    /// `--out-link`, a link kept with `--keep-results`, or otherwise a
+    /// temporary directory starting with `prefix`.
+    pub fn result_link(&self, name: &str, prefix: &str) -> Result<Box<dyn MaybeTempPath>> {
+        Ok(match (&self.out_link, self.keep_results) {
+            (Some(out_link), _) => Box::new(out_link.clone()),
+            (None, Some(keep)) if keep > 0 && !self.dry => {
+                Box::new(crate::results::new_link(name)?)
+            }
+            _ => {
+                let dir = tempfile::Builder::new().prefix(prefix).tempdir()?;
+                crate::gcroots::mark_in_flight(dir.path())?;
+                Box::new((dir.as_ref().join("result"), dir))
+            }
+        })
+    }
+
+    /// Prune the results of the configuration `name` kept with
+    /// `--keep-results`, once its build succeeded.
+    pub fn prune_results(&self, name: &str) -> Result<()> {
+        match (&self.out_link, self.keep_results) {
+            (None, Some(keep)) if keep > 0 && !self.dry => crate::results::prune(name, keep),
+            _ => Ok(()),
+        }
+    }
+
+    /// What to compare the new configuration with, `current` unless
+    /// `--diff-against last-build` was passed and `last_build` is kept.
+    #[must_use]
+    pub fn diff_base(&self, current: PathBuf, last_build: Option<PathBuf>) -> PathBuf {
+        match (self.diff_against, last_build) {
+            (DiffBase::LastBuild, Some(last_build)) => {
+                info!("Comparing with the last build {}", last_build.display());
+                last_build
+            }
+            (DiffBase::LastBuild, None) => {
+                warn!("No earlier build is kept, comparing with the current configuration");
+                current
+            }
+            (DiffBase::Current, _) => current,
+        }
+    }
+
+    /// Make sure the platform given with `--system` can be built for.
+    pub fn check_system(&self) -> Result<()> {
+        match &self.passthrough.system {
+            Some(system) if !self.dry => crate::builders::check_system(
+                system,
+                &self.remote_builders(),
+                self.passthrough.builders.as_deref(),
+            ),
+            _ => Ok(()),
+        }
+    }
+
+    /// Pin a flake installable to the revision or ref selected with `--rev`
+    /// and `--ref`, if any.
+    pub fn pin_installable(&self, mut installable: Installable) -> Result<Installable> {
+        if let Some(rev) = &self.rev {
+            if rev.len() != 40 || !rev.chars().all(|c| c.is_ascii_hexdigit()) {
+                color_eyre::eyre::bail!(
+                    "--rev expects a full 40 character commit hash, got '{rev}'"
+                );
+            }
+            installable.set_flake_param("rev", rev)?;
+        }
+        if let Some(git_ref) = &self.git_ref {
+            installable.set_flake_param("ref", git_ref)?;
+        }
+
+        Ok(installable)
+    }
+}
+
+#[derive(Debug, Args)]
+/// Build several flake installables at once and summarize the results
+pub struct BuildArgs {
+    /// Flake installables to build, like .#packages.x86_64-linux.hello
+    #[arg(required = true)]
+    pub installables: Vec<String>,
+
+    /// Number of builds to run at the same time
+    #[arg(long, default_value_t = 1)]
+    pub parallel: usize,
+
+    /// Prefix of the result links, each installable gets `<prefix>-<name>`
+    #[arg(long, short, default_value = "result")]
+    pub out_link: PathBuf,
+
+    /// Don't use nix-output-monitor for the build process
+    #[arg(long, env = "NH_NO_NOM", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_nom: bool,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    #[command(flatten)]
+    pub passthrough: NixBuildPassthroughArgs,
+
+    /// Extra arguments passed to nix build
+    #[arg(last = true)]
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+/// Run a package, taken from the nixpkgs of your configuration flake
+pub struct RunArgs {
+    /// Package name like hello, or a flake installable like .#tool
+    pub package: String,
+
+    /// Don't use nix-output-monitor for the build process
+    #[arg(long, env = "NH_NO_NOM", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_nom: bool,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    /// Arguments passed to the program
+    #[arg(last = true)]
+    pub args: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+/// Start a shell with packages, taken from the nixpkgs of your configuration flake
+pub struct ShellArgs {
+    /// Package names like hello, or flake installables like .#tool
+    #[arg(required = true)]
+    pub packages: Vec<String>,
+
+    /// Don't use nix-output-monitor for the build process
+    #[arg(long, env = "NH_NO_NOM", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_nom: bool,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    /// Command to run in the shell instead of an interactive shell
+    #[arg(last = true)]
+    pub command: Vec<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileProxy {
+    #[clap(subcommand)]
+    command: ProfileCommand,
+}
+
+#[derive(Debug, Subcommand)]
+/// Install packages imperatively with nix profile
+pub enum ProfileCommand {
+    /// Install packages, taken from the nixpkgs of your configuration flake
+    Install(ProfileInstallArgs),
+    /// Remove packages by the names `nh profile list` shows
+    Remove(ProfileRemoveArgs),
+    /// List the installed packages
+    List(ProfileListArgs),
+    /// Upgrade packages, by default all of them
+    Upgrade(ProfileUpgradeArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileInstallArgs {
+    /// Package names like hello, or flake installables like .#tool
+    #[arg(required = true)]
+    pub packages: Vec<String>,
+
+    /// The profile to change instead of the default one
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+
+    /// Don't use nix-output-monitor for the build process
+    #[arg(long, env = "NH_NO_NOM", value_parser = clap::builder::BoolishValueParser::new())]
+    pub no_nom: bool,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileRemoveArgs {
+    /// Names of the packages to remove
+    #[arg(required = true)]
+    pub packages: Vec<String>,
+
+    /// The profile to change instead of the default one
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileListArgs {
+    /// The profile to list instead of the default one
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+}
+
+#[derive(Debug, Args)]
+pub struct ProfileUpgradeArgs {
+    /// Names of the packages to upgrade, all of them if none are given
+    pub packages: Vec<String>,
+
+    /// The profile to change instead of the default one
+    #[arg(long)]
+    pub profile: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+}
+
+#[derive(Debug, Args)]
+/// Start a development shell of a flake
+pub struct DevelopArgs {
+    #[command(flatten)]
+    pub installable: Installable,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    /// Command to run in the shell instead of an interactive shell
+    #[arg(last = true)]
+    pub command: Vec<String>,
+}
+
 #[derive(Debug, Args)]
 pub struct OsReplArgs {
     #[command(flatten)]
     pub installable: Installable,
 
-    /// When using a flake installable, select this hostname from nixosConfigurations
-    #[arg(long, short = 'H', global = true)]
-    pub hostname: Option<String>,
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    /// When using a flake installable, select this hostname from nixosConfigurations
+    #[arg(long, short = 'H', global = true, add = ArgValueCompleter::new(Configurations("nixosConfigurations")))]
+    pub hostname: Option<String>,
+
+    /// Load the configuration as it is, without adding lib and the flake
+    /// inputs to the scope
+    #[arg(long)]
+    pub bare: bool,
+
+    /// Extra arguments passed to nix repl
+    #[arg(last = true)]
+    pub extra_args: Vec<String>,
+}
+
+impl OsReplArgs {
+    #[must_use]
+    pub fn uses_flakes(&self) -> bool {
+        // Check environment variables first
+        if env::var("NH_OS_FLAKE").is_ok() {
+            return true;
+        }
+
+        // Check installable type
+        matches!(self.installable, Installable::Flake { .. })
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct OsOptionArgs {
+    /// Option path, like `services.nginx.enable`. Groups like
+    /// `services.nginx` list the options they contain
+    pub option: String,
+
+    #[command(flatten)]
+    pub installable: Installable,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    /// When using a flake installable, select this hostname from nixosConfigurations
+    #[arg(long, short = 'H', add = ArgValueCompleter::new(Configurations("nixosConfigurations")))]
+    pub hostname: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct OsGenerationsArgs {
+    /// Path to Nix' profiles directory
+    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
+    pub profile: Option<String>,
+
+    /// Also compute the closure size of each generation, which can be slow
+    #[arg(long, short = 's')]
+    pub sizes: bool,
+
+    /// Browse generations interactively, with actions to diff, roll back,
+    /// pin, tag or delete them
+    #[arg(long)]
+    pub tui: bool,
+
+    /// Print one line per generation from a template like
+    /// '{generation} {date} {size}'. Fields: generation, date, current,
+    /// nixos_version, kernel, revision, specialisations, size, label, pinned
+    #[arg(long, value_parser = Template::parse, conflicts_with = "tui")]
+    pub format: Option<Template>,
+}
+
+#[derive(Args, Debug)]
+/// Run `nix flake check` and summarize failures by check
+pub struct CheckArgs {
+    /// The flake, by default the one of NH_FLAKE or the closest to the
+    /// current directory
+    pub flake: Option<String>,
+
+    /// Only check the current system's checks and this host's NixOS configuration
+    #[arg(
+        long,
+        short = 'H',
+        conflicts_with = "all_systems",
+        add = ArgValueCompleter::new(Configurations("nixosConfigurations"))
+    )]
+    pub hostname: Option<String>,
+
+    /// Check the outputs of all systems, not just the current one
+    #[arg(long)]
+    pub all_systems: bool,
+
+    /// Show full evaluation traces instead of the innermost frames
+    #[arg(long)]
+    pub show_all_frames: bool,
+
+    /// Extra arguments passed to nix
+    #[arg(last = true)]
+    pub extra_args: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+/// Show how rebuilds changed over time
+///
+/// Every rebuild records its duration, closure size and how many paths were
+/// built or substituted. Shows these for each command over a period, compared
+/// with the period before
+pub struct StatsArgs {
+    /// Period to show, like 7d or 2weeks
+    #[arg(long, default_value = "30d")]
+    pub since: humantime::Duration,
+}
+
+#[derive(Args, Debug)]
+/// Show the build log of the last failed build, or of a derivation
+///
+/// The log of the derivation that made a build fail is saved when the build
+/// fails, so it can be shown again without finding its path
+pub struct LogArgs {
+    /// A derivation or installable, or `last` for the last failed build
+    #[arg(default_value = "last")]
+    pub drv: String,
+}
+
+#[derive(Args, Debug)]
+/// Diagnose problems with the Nix installation and environment
+///
+/// Checks the Nix version and features, the daemon, substituters, free disk
+/// space and optional tools, with hints on how to fix anything that's off
+pub struct DoctorArgs {}
+
+#[derive(Args, Debug)]
+/// Check for a newer release of nh and install it
+///
+/// Installs managed by Nix only get upgrade instructions. Standalone binaries
+/// are replaced after verifying the release signature
+pub struct SelfUpdateArgs {
+    /// Only check for a newer release, don't install it
+    #[arg(long)]
+    pub check: bool,
+}
+
+#[derive(Args, Debug)]
+#[command(args_conflicts_with_subcommands = true)]
+/// Searches packages by querying search.nixos.org
+pub struct SearchArgs {
+    #[command(subcommand)]
+    pub subcommand: Option<SearchSubcommand>,
+
+    #[arg(long, short, default_value = "30")]
+    /// Number of search results to display
+    pub limit: u64,
+
+    #[arg(
+        long,
+        short,
+        env = "NH_SEARCH_CHANNEL",
+        default_value = crate::search::DEFAULT_CHANNEL
+    )]
+    /// Name of the channel to query (e.g nixos-23.11, nixos-unstable, etc)
+    pub channel: String,
+
+    #[arg(long, short = 'P', env = "NH_SEARCH_PLATFORM", value_parser = clap::builder::BoolishValueParser::new())]
+    /// Show supported platforms for each package
+    pub platforms: bool,
+
+    /// Deprecated alias of the global --json
+    #[arg(short = 'j', hide = true, env = "NH_SEARCH_JSON", value_parser = clap::builder::BoolishValueParser::new())]
+    pub legacy_json: bool,
+
+    #[arg(long, value_parser = Template::parse)]
+    /// Print one line per package from a template like '{attr} {version}'.
+    /// Fields: attr, pname, version, description, homepage, platforms,
+    /// programs, license, position
+    pub format: Option<Template>,
+
+    /// Find the packages providing this program instead, with the nix-index
+    /// database
+    #[arg(long, conflicts_with = "query")]
+    pub program: Option<String>,
+
+    /// Refresh the nix-index database before a --program search when it is
+    /// older than this, like 7d
+    #[arg(long, env = "NH_SEARCH_INDEX_MAX_AGE")]
+    pub index_max_age: Option<humantime::Duration>,
+
+    /// Name of the package to search
+    #[arg(add = ArgValueCompleter::new(complete_package))]
+    pub query: Vec<String>,
+}
+
+#[derive(Debug, Subcommand)]
+pub enum SearchSubcommand {
+    /// Manage the nix-index database used by --program
+    #[command(subcommand)]
+    Index(IndexCommand),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum IndexCommand {
+    /// Build the database locally with nix-index, from the nixpkgs in
+    /// NIX_PATH. This takes a while
+    Build,
+    /// Download the prebuilt database of nix-index-database
+    Update(IndexUpdateArgs),
+    /// Show where the database is and how old it is
+    Status,
+}
+
+#[derive(Debug, Args)]
+pub struct IndexUpdateArgs {
+    /// Only download when the database is older than this, like 7d, for
+    /// running from a timer
+    #[arg(long)]
+    pub max_age: Option<humantime::Duration>,
+}
+
+#[derive(Debug, Clone, ValueEnum)]
+pub enum SearchNixpkgsFrom {
+    Flake,
+    Path,
+}
+
+#[derive(Debug, Clone, Args)]
+#[command(args_conflicts_with_subcommands = true, arg_required_else_help = true)]
+pub struct UpdateProxy {
+    #[clap(subcommand)]
+    pub command: Option<UpdateCommand>,
+
+    /// Update the nix channels, or only the given ones, for configurations
+    /// that aren't flakes
+    #[arg(long, value_name = "CHANNEL", num_args = 0.., value_delimiter = ',')]
+    pub channels: Option<Vec<String>>,
+
+    /// Update the channels of root, which NixOS and nix-darwin systems are
+    /// built from, instead of the ones of the current user
+    #[arg(long, requires = "channels")]
+    pub system: bool,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+/// Flake input and channel maintenance
+pub enum UpdateCommand {
+    /// Show how old each flake input is and how far behind upstream
+    Status(UpdateStatusArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct UpdateStatusArgs {
+    /// The flake, by default the one of NH_FLAKE or the closest to the
+    /// current directory
+    pub flake: Option<String>,
+
+    /// Don't query upstream repositories
+    #[arg(long)]
+    pub offline: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct FlakeProxy {
+    #[clap(subcommand)]
+    command: FlakeCommand,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+/// Flake utilities
+pub enum FlakeCommand {
+    /// Create a flake from a template
+    Init(FlakeInitArgs),
+    /// Show the configurations and other outputs of a flake
+    Show(FlakeShowArgs),
+    /// Show the revision of a flake and the age of its inputs
+    Metadata(FlakeMetadataArgs),
+    /// Copy a flake and all its inputs to another store, e.g. for machines
+    /// without network access
+    Archive(FlakeArchiveArgs),
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct FlakeInitArgs {
+    /// Directory to create the flake in, instead of the current one
+    pub path: Option<PathBuf>,
+
+    /// Template to use, like `templates#rust`, instead of the default one
+    #[arg(long, short)]
+    pub template: Option<String>,
+}
+
+#[derive(Args, Debug)]
+/// Create a flake for a NixOS, Home Manager or nix-darwin configuration
+///
+/// The configuration is named after this machine and user, so that nh finds
+/// it without --hostname or --configuration. Templates can use the
+/// placeholders @hostname@, @username@, @system@ and @home@, in file names
+/// too
+pub struct InitArgs {
+    /// Kind of configuration to create
+    #[arg(value_enum)]
+    pub kind: InitKind,
+
+    /// Directory to create the flake in
+    #[arg(default_value = ".")]
+    pub path: PathBuf,
+
+    /// Template to use instead of the built-in one: a directory whose files
+    /// are copied, or a file used as flake.nix
+    #[arg(long, short)]
+    pub template: Option<PathBuf>,
+
+    /// Hostname to name the system configuration after, instead of this
+    /// machine's
+    #[arg(long, short = 'H')]
+    pub hostname: Option<String>,
+
+    /// Username to name the Home Manager configuration after, instead of
+    /// $USER
+    #[arg(long, short)]
+    pub username: Option<String>,
+
+    /// System to build for, like `aarch64-darwin`, instead of this machine's
+    #[arg(long)]
+    pub system: Option<String>,
+
+    /// Initialize a git repository and add the files to it, which flakes
+    /// need to see them
+    #[arg(long)]
+    pub git: bool,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InitKind {
+    /// A NixOS configuration, for nh os
+    Os,
+    /// A standalone Home Manager configuration, for nh home
+    Home,
+    /// A nix-darwin configuration, for nh darwin
+    Darwin,
+    /// A NixOS or nix-darwin configuration, depending on the system, and a
+    /// Home Manager configuration
+    Combined,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct FlakeShowArgs {
+    /// The flake, by default the one of NH_FLAKE or the closest to the
+    /// current directory
+    pub flake: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct FlakeMetadataArgs {
+    /// The flake, by default the one of NH_FLAKE or the closest to the
+    /// current directory
+    pub flake: Option<String>,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct FlakeArchiveArgs {
+    /// The flake, by default the one of NH_FLAKE or the closest to the
+    /// current directory
+    pub flake: Option<String>,
+
+    /// Store to copy to, like `file:///mnt/usb` or `ssh://host`
+    #[arg(long)]
+    pub to: String,
+
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct StoreProxy {
+    #[clap(subcommand)]
+    command: StoreCommand,
+}
+
+#[derive(Debug, Clone, Subcommand)]
+/// Nix store utilities
+pub enum StoreCommand {
+    /// Show the packages that differ between two closures
+    DiffClosures(StoreDiffClosuresArgs),
+    /// Check store paths for corruption, by default the current system
+    Verify(StoreVerifyArgs),
+    /// Download or rebuild corrupted store paths
+    Repair(StoreRepairArgs),
+    /// Show the size of a closure, by default the current system
+    PathInfo(StorePathInfoArgs),
 }
 
-impl OsReplArgs {
-    #[must_use]
-    pub fn uses_flakes(&self) -> bool {
-        // Check environment variables first
-        if env::var("NH_OS_FLAKE").is_ok() {
-            return true;
-        }
+#[derive(Debug, Clone, Args)]
+/// Show what a closure consists of, by default the current system
+pub struct InspectArgs {
+    /// Store path or link to a store path
+    #[arg(conflicts_with = "home")]
+    pub path: Option<PathBuf>,
 
-        // Check installable type
-        matches!(self.installable, Installable::Flake { .. })
-    }
+    /// Use the active home-manager generation instead of the current system
+    #[arg(long)]
+    pub home: bool,
+
+    /// Levels of dependencies to show
+    #[arg(long, short, default_value_t = 2)]
+    pub depth: usize,
+
+    /// Hide dependencies whose closure is smaller than this, like 100M
+    #[arg(long, value_name = "SIZE", default_value = "50M", value_parser = crate::util::parse_size)]
+    pub min_size: u64,
+
+    /// Show the chain of references that pulls in the paths whose name
+    /// contains this, like `nix why-depends`
+    #[arg(long, value_name = "NAME")]
+    pub why: Option<String>,
 }
 
-#[derive(Debug, Args)]
-pub struct OsGenerationsArgs {
-    /// Path to Nix' profiles directory
-    #[arg(long, short = 'P', default_value = "/nix/var/nix/profiles/system")]
-    pub profile: Option<String>,
+#[derive(Debug, Clone, Args)]
+pub struct StoreDiffClosuresArgs {
+    /// Old closure, like a generation link or a store path
+    pub old: PathBuf,
+
+    /// New closure
+    pub new: PathBuf,
 }
 
-#[derive(Args, Debug)]
-/// Searches packages by querying search.nixos.org
-pub struct SearchArgs {
-    #[arg(long, short, default_value = "30")]
-    /// Number of search results to display
-    pub limit: u64,
+#[derive(Debug, Clone, Args)]
+pub struct StoreVerifyArgs {
+    /// Store paths whose closures to verify
+    #[arg(conflicts_with = "all")]
+    pub paths: Vec<PathBuf>,
 
-    #[arg(
-        long,
-        short,
-        env = "NH_SEARCH_CHANNEL",
-        default_value = "nixos-unstable"
-    )]
-    /// Name of the channel to query (e.g nixos-23.11, nixos-unstable, etc)
-    pub channel: String,
+    /// Verify every path in the store
+    #[arg(long)]
+    pub all: bool,
 
-    #[arg(long, short = 'P', env = "NH_SEARCH_PLATFORM", value_parser = clap::builder::BoolishValueParser::new())]
-    /// Show supported platforms for each package
-    pub platforms: bool,
+    /// Also check the contents of paths against their hashes, which is slow
+    #[arg(long)]
+    pub check_contents: bool,
 
-    #[arg(long, short = 'j', env = "NH_SEARCH_JSON", value_parser = clap::builder::BoolishValueParser::new())]
-    /// Output results as JSON
-    pub json: bool,
+    /// Repair corrupted paths
+    #[arg(long)]
+    pub repair: bool,
+}
 
-    /// Name of the package to search
-    pub query: Vec<String>,
+#[derive(Debug, Clone, Args)]
+pub struct StoreRepairArgs {
+    /// Store paths to repair
+    #[arg(required = true)]
+    pub paths: Vec<PathBuf>,
 }
 
-#[derive(Debug, Clone, ValueEnum)]
-pub enum SearchNixpkgsFrom {
-    Flake,
-    Path,
+#[derive(Debug, Clone, Args)]
+pub struct StorePathInfoArgs {
+    /// Store path or link to a store path
+    #[arg(conflicts_with = "home")]
+    pub path: Option<PathBuf>,
+
+    /// Use the active home-manager generation instead of the current system
+    #[arg(long)]
+    pub home: bool,
+
+    /// List the largest paths of the closure
+    #[arg(long, short)]
+    pub size: bool,
+
+    /// Number of paths listed with --size
+    #[arg(long, default_value_t = 20, requires = "size")]
+    pub limit: usize,
 }
 
 // Needed a struct to have multiple sub-subcommands
 #[derive(Debug, Clone, Args)]
 pub struct CleanProxy {
     #[clap(subcommand)]
-    command: CleanMode,
+    pub command: CleanMode,
 }
 
 #[derive(Debug, Clone, Subcommand)]
@@ -397,21 +1600,26 @@ pub enum CleanMode {
 /// Enhanced nix cleanup
 ///
 /// For --keep-since, see the documentation of humantime for possible formats: <https://docs.rs/humantime/latest/humantime/fn.parse_duration.html>
+/// It can be given per class of profile, like `system=30d,home=7d,result=1d`.
+/// The classes are system, home, user and result, for gcroots like `result`
+/// links. Classes left out get an age given without a class, like in
+/// `1d,system=30d`, or none.
 pub struct CleanArgs {
-    #[arg(long, short, default_value = "1")]
+    #[arg(long, short, env = "NH_CLEAN_KEEP", default_value = "1")]
     /// At least keep this number of generations
     pub keep: u32,
 
-    #[arg(long, short = 'K', default_value = "0h")]
-    /// At least keep gcroots and generations in this time range since now.
-    pub keep_since: humantime::Duration,
+    #[arg(long, short = 'K', env = "NH_CLEAN_KEEP_SINCE", default_value = "0h", value_parser = KeepSince::parse)]
+    /// At least keep gcroots and generations in this time range since now,
+    /// optionally per class of profile
+    pub keep_since: KeepSince,
 
     /// Only print actions, without performing them
     #[arg(long, short = 'n')]
     pub dry: bool,
 
     /// Ask for confirmation
-    #[arg(long, short)]
+    #[arg(long, short, env = "NH_ASK", value_parser = clap::builder::BoolishValueParser::new())]
     pub ask: bool,
 
     /// Don't run nix store --gc
@@ -421,6 +1629,11 @@ pub struct CleanArgs {
     /// Don't clean gcroots
     #[arg(long)]
     pub nogcroots: bool,
+
+    /// Print the cleanup plan as one line per path from a template like
+    /// '{action} {path}'. Fields: action, path, kind, profile, generation
+    #[arg(long, value_parser = Template::parse)]
+    pub format: Option<Template>,
 }
 
 #[derive(Debug, Clone, Args)]
@@ -454,6 +1667,13 @@ impl HomeArgs {
                     Box::new(LegacyFeatures)
                 }
             }
+            HomeSubcommand::Option(args) => {
+                if matches!(args.installable, Installable::Flake { .. }) {
+                    Box::new(FlakeFeatures)
+                } else {
+                    Box::new(LegacyFeatures)
+                }
+            }
         }
     }
 }
@@ -468,6 +1688,9 @@ pub enum HomeSubcommand {
 
     /// Load a home-manager configuration in a Nix REPL
     Repl(HomeReplArgs),
+
+    /// Show the value, type, default, description and definitions of an option
+    Option(HomeOptionArgs),
 }
 
 #[derive(Debug, Args)]
@@ -481,11 +1704,11 @@ pub struct HomeRebuildArgs {
     /// Name of the flake homeConfigurations attribute, like username@hostname
     ///
     /// If unspecified, will try <username>@<hostname> and <username>
-    #[arg(long, short)]
+    #[arg(long, short, add = ArgValueCompleter::new(Configurations("homeConfigurations")))]
     pub configuration: Option<String>,
 
     /// Explicitly select some specialisation
-    #[arg(long, short)]
+    #[arg(long, short, add = ArgValueCompleter::new(Specialisations::Home))]
     pub specialisation: Option<String>,
 
     /// Ignore specialisations
@@ -519,12 +1742,20 @@ pub struct HomeReplArgs {
     #[command(flatten)]
     pub installable: Installable,
 
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
     /// Name of the flake homeConfigurations attribute, like username@hostname
     ///
     /// If unspecified, will try <username>@<hostname> and <username>
-    #[arg(long, short)]
+    #[arg(long, short, add = ArgValueCompleter::new(Configurations("homeConfigurations")))]
     pub configuration: Option<String>,
 
+    /// Load the configuration as it is, without adding lib and the flake
+    /// inputs to the scope
+    #[arg(long)]
+    pub bare: bool,
+
     /// Extra arguments passed to nix repl
     #[arg(last = true)]
     pub extra_args: Vec<String>,
@@ -543,11 +1774,35 @@ impl HomeReplArgs {
     }
 }
 
+#[derive(Debug, Args)]
+pub struct HomeOptionArgs {
+    /// Option path, like `programs.git.extraConfig`. Groups like
+    /// `programs.git` list the options they contain
+    pub option: String,
+
+    #[command(flatten)]
+    pub installable: Installable,
+
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
+    /// Name of the flake homeConfigurations attribute, like username@hostname
+    ///
+    /// If unspecified, will try <username>@<hostname> and <username>
+    #[arg(long, short, add = ArgValueCompleter::new(Configurations("homeConfigurations")))]
+    pub configuration: Option<String>,
+}
+
 #[derive(Debug, Parser)]
 /// Generate shell completion files into stdout
 pub struct CompletionArgs {
     /// Name of the shell
     pub shell: clap_complete::Shell,
+
+    /// Print a script that asks nh for completions, which can also complete
+    /// configuration names from the flake
+    #[arg(long)]
+    pub dynamic: bool,
 }
 
 /// Nix-darwin functionality
@@ -574,6 +1829,7 @@ impl DarwinArgs {
                     Box::new(LegacyFeatures)
                 }
             }
+            DarwinSubcommand::Clean(_) => Box::new(NoFeatures),
         }
     }
 }
@@ -586,6 +1842,22 @@ pub enum DarwinSubcommand {
     Build(DarwinRebuildArgs),
     /// Load a nix-darwin configuration in a Nix REPL
     Repl(DarwinReplArgs),
+    /// Clean the system and Home Manager profiles, now or periodically
+    Clean(DarwinCleanArgs),
+}
+
+#[derive(Debug, Args)]
+pub struct DarwinCleanArgs {
+    #[command(flatten)]
+    pub common: CleanArgs,
+
+    /// Install a launchd daemon running this cleanup every INTERVAL, like 1w
+    #[arg(long, value_name = "INTERVAL", conflicts_with = "unschedule")]
+    pub schedule: Option<humantime::Duration>,
+
+    /// Remove the launchd daemon installed with --schedule
+    #[arg(long)]
+    pub unschedule: bool,
 }
 
 #[derive(Debug, Args)]
@@ -597,7 +1869,7 @@ pub struct DarwinRebuildArgs {
     pub update_args: UpdateArgs,
 
     /// When using a flake installable, select this hostname from darwinConfigurations
-    #[arg(long, short = 'H', global = true)]
+    #[arg(long, short = 'H', global = true, add = ArgValueCompleter::new(Configurations("darwinConfigurations")))]
     pub hostname: Option<String>,
 
     /// Extra arguments passed to nix build
@@ -623,9 +1895,21 @@ pub struct DarwinReplArgs {
     #[command(flatten)]
     pub installable: Installable,
 
+    #[command(flatten)]
+    pub eval: NixEvalArgs,
+
     /// When using a flake installable, select this hostname from darwinConfigurations
-    #[arg(long, short = 'H', global = true)]
+    #[arg(long, short = 'H', global = true, add = ArgValueCompleter::new(Configurations("darwinConfigurations")))]
     pub hostname: Option<String>,
+
+    /// Load the configuration as it is, without adding lib and the flake
+    /// inputs to the scope
+    #[arg(long)]
+    pub bare: bool,
+
+    /// Extra arguments passed to nix repl
+    #[arg(last = true)]
+    pub extra_args: Vec<String>,
 }
 
 impl DarwinReplArgs {
@@ -642,6 +1926,10 @@ impl DarwinReplArgs {
 }
 
 #[derive(Debug, Args)]
+/// system-manager functionality
+///
+/// Builds and activates system-manager configurations on Linux
+/// distributions other than `NixOS`
 pub struct SysArgs {
     #[command(subcommand)]
     pub subcommand: SysSubcommand,
@@ -650,73 +1938,245 @@ pub struct SysArgs {
 impl SysArgs {
     #[must_use]
     pub fn get_feature_requirements(&self) -> Box<dyn FeatureRequirements> {
-        Box::new(FlakeFeatures)
-    }
-
-    pub fn run(self) -> crate::Result<()> {
-        use crate::system::SystemManager;
-        match self.subcommand {
-            SysSubcommand::Build(args) => SystemManager::build(&args),
-            SysSubcommand::ListGenerations => SystemManager::list_generations(None),
-            SysSubcommand::Rollback(args) => SystemManager::rollback(&args),
+        match &self.subcommand {
+            SysSubcommand::Switch(args) | SysSubcommand::Build(args) => {
+                if args.uses_flakes() {
+                    Box::new(FlakeFeatures)
+                } else {
+                    Box::new(LegacyFeatures)
+                }
+            }
+            SysSubcommand::Info(_) | SysSubcommand::Rollback(_) => Box::new(LegacyFeatures),
         }
     }
 }
 
 #[derive(Debug, Subcommand)]
 pub enum SysSubcommand {
-    Build(SystemBuildArgs),
-    ListGenerations,
+    /// Build and activate a system-manager configuration
+    Switch(SystemRebuildArgs),
+
+    /// Build a system-manager configuration
+    Build(SystemRebuildArgs),
+
+    /// List the generations of the system-manager profile
+    #[command(alias = "list-generations")]
+    Info(SystemInfoArgs),
+
+    /// Roll back to a previous generation of the system-manager profile
     Rollback(SystemRollbackArgs),
 }
 
-#[derive(Debug, Parser)]
-pub struct SystemBuildArgs {
-    #[arg(long)]
-    pub flake: Option<String>,
-
-    #[arg(long)]
-    pub switch: bool,
+#[derive(Debug, Args)]
+pub struct SystemRebuildArgs {
+    #[command(flatten)]
+    pub common: CommonRebuildArgs,
 
-    #[arg(long = "dry-activate")]
-    pub dry_activate: bool,
+    #[command(flatten)]
+    pub update_args: UpdateArgs,
 
-    #[arg(long)]
-    pub no_link: bool,
+    /// When using a flake installable, select this configuration from
+    /// systemConfigs
+    ///
+    /// If unspecified, will try <hostname> and default
+    #[arg(long, short = 'H', add = ArgValueCompleter::new(Configurations("systemConfigs")))]
+    pub hostname: Option<String>,
 
-    #[arg(long)]
-    pub ssh: Option<String>,
+    /// Don't panic if calling nh as root
+    #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
+    pub bypass_root_check: bool,
 
+    /// Deploy the configuration to a different host over ssh, which has
+    /// system-manager in its PATH
     #[arg(long)]
-    pub install_host: Option<String>,
+    pub target_host: Option<String>,
 
     #[command(flatten)]
-    pub passthrough: NixBuildPassthroughArgs,
+    pub copy: CopyArgs,
+
+    /// Extra arguments passed to nix build
+    #[arg(last = true)]
+    pub extra_args: Vec<String>,
+}
+
+impl SystemRebuildArgs {
+    #[must_use]
+    pub fn uses_flakes(&self) -> bool {
+        // Check environment variables first
+        if env::var("NH_SYSTEM_FLAKE").is_ok_and(|v| !v.is_empty()) {
+            return true;
+        }
+
+        // Check installable type
+        matches!(self.common.installable, Installable::Flake { .. })
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct SystemInfoArgs {
+    /// Also compute the closure size of each generation, which can be slow
+    #[arg(long, short = 's')]
+    pub sizes: bool,
+
+    /// Print one line per generation from a template like
+    /// '{generation} {date} {size}'. Fields: generation, date, current,
+    /// revision, size, label, pinned
+    #[arg(long, value_parser = Template::parse)]
+    pub format: Option<Template>,
 }
 
 #[derive(Debug, Args)]
 pub struct SystemRollbackArgs {
-    pub generation: Option<String>,
+    /// Only print actions, without performing them
+    #[arg(long, short = 'n')]
+    pub dry: bool,
 
-    #[arg(long)]
-    pub ssh: Option<String>,
+    /// Ask for confirmation
+    #[arg(long, short, env = "NH_ASK", value_parser = clap::builder::BoolishValueParser::new())]
+    pub ask: bool,
 
-    #[arg(long)]
-    pub install_host: Option<String>,
+    /// Rollback to a specific generation number (defaults to previous generation)
+    #[arg(long, short, add = ArgValueCompleter::new(Generations(crate::system::PROFILE)))]
+    pub to: Option<u64>,
+
+    /// Rollback to the newest generation built before this date, like
+    /// 2024-01-15 or '2024-01-15 14:30'
+    #[arg(long, value_parser = crate::generations::parse_date, conflicts_with_all = ["to", "before"])]
+    pub to_date: Option<DateTime<Utc>>,
+
+    /// Rollback to the newest generation built longer ago than this, like 3d
+    #[arg(long, conflicts_with = "to")]
+    pub before: Option<humantime::Duration>,
+
+    /// Don't panic if calling nh as root
+    #[arg(short = 'R', long, env = "NH_BYPASS_ROOT_CHECK")]
+    pub bypass_root_check: bool,
+
+    /// Whether to display a package diff
+    #[arg(long, short, value_enum, env = "NH_DIFF", default_value_t = DiffType::Auto)]
+    pub diff: DiffType,
 }
 
-#[derive(Debug, Args)]
+#[derive(Debug, Default, Args)]
+#[command(group(
+    clap::ArgGroup::new("update_mode")
+        .args(["update_all", "update_input", "update_interactive"])
+        .multiple(false)
+))]
 pub struct UpdateArgs {
     #[arg(short = 'u', long = "update", conflicts_with = "update_input")]
-    /// Update all flake inputs
+    /// Update all flake inputs, or all channels without a flake
     pub update_all: bool,
 
     #[arg(short = 'U', long = "update-input", conflicts_with = "update_all")]
-    /// Update the specified flake input(s)
+    /// Update the specified flake input(s), or channels without a flake
     pub update_input: Option<Vec<String>>,
+
+    #[arg(long, conflicts_with_all = ["update_all", "update_input"])]
+    /// Pick the flake inputs to update from a list of all inputs
+    pub update_interactive: bool,
+
+    #[arg(long, requires = "update_mode")]
+    /// Restore flake.lock if building or activating the updated configuration fails
+    pub revert_on_failure: bool,
+
+    #[arg(long, requires = "update_mode", env = "NH_UPDATE_COMMIT", value_parser = clap::builder::BoolishValueParser::new())]
+    /// Commit the updated flake.lock once the configuration was built successfully
+    pub update_commit: bool,
+
+    #[arg(long, requires = "update_commit", value_name = "BRANCH")]
+    /// Create this branch for the flake.lock commit
+    pub update_branch: Option<String>,
 }
 
-#[derive(Debug, Args)]
+impl UpdateArgs {
+    /// Whether any kind of update was requested
+    #[must_use]
+    pub const fn enabled(&self) -> bool {
+        self.update_all || self.update_input.is_some() || self.update_interactive
+    }
+}
+
+/// Tuning of copying a configuration to --target-host, for slow links
+#[derive(Debug, Clone, Default, Args)]
+pub struct CopyArgs {
+    /// Compress the copy to the target host with ssh. ssh stores only
+    /// support compression being on or off, to pick an algorithm push to a
+    /// binary cache with --push-to instead, like 's3://cache?compression=zstd'
+    #[arg(long, env = "NH_COPY_COMPRESS", value_parser = clap::builder::BoolishValueParser::new())]
+    pub copy_compress: bool,
+
+    /// Copy to the target host over this many ssh connections at the same time
+    #[arg(
+        long,
+        value_name = "N",
+        env = "NH_COPY_CONNECTIONS",
+        value_parser = clap::value_parser!(u32).range(1..)
+    )]
+    pub copy_connections: Option<u32>,
+}
+
+/// Flake-related arguments passed to every nix evaluation and build
+#[derive(Debug, Clone, Default, Args)]
+pub struct NixEvalArgs {
+    /// Override a flake input with a flake reference, e.g. a local checkout
+    #[arg(long, num_args = 2, value_names = ["INPUT", "FLAKE_REF"])]
+    pub override_input: Vec<String>,
+
+    /// Override a flake in the flake registry with a flake reference
+    #[arg(long, num_args = 2, value_names = ["FLAKE", "FLAKE_REF"])]
+    pub override_flake: Vec<String>,
+
+    /// Allow access to mutable paths and repositories during evaluation
+    #[arg(long)]
+    pub impure: bool,
+
+    /// Accept configuration from flakes
+    #[arg(long)]
+    pub accept_flake_config: bool,
+
+    /// Set a Nix configuration option
+    #[arg(long = "option", num_args = 2, value_names = ["NAME", "VALUE"])]
+    pub options: Vec<String>,
+
+    /// Display tracebacks on errors. Frames inside nixpkgs are folded, unless
+    /// -vv is passed
+    #[arg(long, short = 't')]
+    pub show_trace: bool,
+}
+
+impl NixEvalArgs {
+    #[must_use]
+    pub fn generate_eval_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        for pair in self.override_input.chunks(2) {
+            args.push("--override-input".into());
+            args.extend(pair.iter().cloned());
+        }
+        for pair in self.override_flake.chunks(2) {
+            args.push("--override-flake".into());
+            args.extend(pair.iter().cloned());
+        }
+        if self.impure {
+            args.push("--impure".into());
+        }
+        if self.accept_flake_config {
+            args.push("--accept-flake-config".into());
+        }
+        for pair in self.options.chunks(2) {
+            args.push("--option".into());
+            args.extend(pair.iter().cloned());
+        }
+        if self.show_trace {
+            args.push("--show-trace".into());
+        }
+
+        args
+    }
+}
+
+#[derive(Debug, Clone, Default, Args)]
 pub struct NixBuildPassthroughArgs {
     /// Number of concurrent jobs Nix should run
     #[arg(long, short = 'j')]
@@ -750,6 +2210,11 @@ pub struct NixBuildPassthroughArgs {
     #[arg(long)]
     pub builders: Option<String>,
 
+    /// Build for this platform, like aarch64-linux, with binfmt emulation or
+    /// a remote builder for it
+    #[arg(long, value_name = "SYSTEM")]
+    pub system: Option<String>,
+
     /// Paths to include
     #[arg(long, short = 'I')]
     pub include: Vec<String>,
@@ -758,22 +2223,10 @@ pub struct NixBuildPassthroughArgs {
     #[arg(long, short = 'L')]
     pub print_build_logs: bool,
 
-    /// Display tracebacks on errors
-    #[arg(long, short = 't')]
-    pub show_trace: bool,
-
-    /// Accept configuration from flakes
-    #[arg(long)]
-    pub accept_flake_config: bool,
-
     /// Refresh flakes to the latest revision
     #[arg(long)]
     pub refresh: bool,
 
-    /// Allow impure builds
-    #[arg(long)]
-    pub impure: bool,
-
     /// Build without internet access
     #[arg(long)]
     pub offline: bool,
@@ -809,13 +2262,21 @@ pub struct NixBuildPassthroughArgs {
     /// Use substitutes when copying
     #[arg(long)]
     pub use_substitutes: bool,
-
-    /// Output results in JSON format
-    #[arg(long)]
-    pub json: bool,
 }
 
 impl NixBuildPassthroughArgs {
+    /// The first passed option that Nix only honors for trusted users.
+    #[must_use]
+    pub fn trusted_option(&self) -> Option<&'static str> {
+        if self.builders.is_some() {
+            Some("--builders")
+        } else if self.repair {
+            Some("--repair")
+        } else {
+            None
+        }
+    }
+
     #[must_use]
     pub fn generate_passthrough_args(&self) -> Vec<String> {
         let mut args = Vec::new();
@@ -848,6 +2309,10 @@ impl NixBuildPassthroughArgs {
             args.push("--builders".into());
             args.push(builders.clone());
         }
+        if let Some(ref system) = self.system {
+            args.push("--system".into());
+            args.push(system.clone());
+        }
         for inc in &self.include {
             args.push("--include".into());
             args.push(inc.clone());
@@ -855,18 +2320,9 @@ impl NixBuildPassthroughArgs {
         if self.print_build_logs {
             args.push("--print-build-logs".into());
         }
-        if self.show_trace {
-            args.push("--show-trace".into());
-        }
-        if self.accept_flake_config {
-            args.push("--accept-flake-config".into());
-        }
         if self.refresh {
             args.push("--refresh".into());
         }
-        if self.impure {
-            args.push("--impure".into());
-        }
         if self.offline {
             args.push("--offline".into());
         }
@@ -894,9 +2350,6 @@ impl NixBuildPassthroughArgs {
         if self.use_substitutes {
             args.push("--use-substitutes".into());
         }
-        if self.json {
-            args.push("--json".into());
-        }
 
         args
     }
@@ -0,0 +1,400 @@
+//! The hosts of a fleet, described in the `[inventory]` section of the
+//! configuration, which `nh os deploy` rolls a configuration out to:
+//!
+//! ```toml
+//! [inventory.web-1]
+//! target-host = "root@web-1.example.com"
+//! tags = ["web", "eu"]
+//! health-check = "curl -fsS http://localhost/health"
+//!
+//! [inventory.db]
+//! hostname = "db-primary"        # in nixosConfigurations, defaults to the name
+//! target-host = "root@10.0.0.5"  # defaults to the name
+//! build-host = "builder.example.com"
+//! ssh-options = "-p 2222"
+//! tags = ["db"]
+//! ```
+//!
+//! Hosts are deployed one at a time, in the order of their names. Once a
+//! host is activated its health check is run on it, and the rollout stops at
+//! the first host that fails to deploy or doesn't become healthy, so that a
+//! broken configuration reaches as few hosts as possible.
+//!
+//! Groups of hosts, by tag, can be rolled out differently:
+//!
+//! ```toml
+//! [rollout.web]
+//! max-parallel = 3        # hosts deployed at the same time
+//! canaries = ["web-1"]    # deployed first, on their own
+//! ```
+//!
+//! Canaries have to deploy and become healthy before any other host is
+//! deployed. The rest is deployed in waves of up to `max-parallel` hosts,
+//! and the rollout stops after the first wave with a failing host.
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::commands::Command;
+use crate::events::{self, Phase};
+use crate::json::HostDeploy;
+use crate::theme::{Role, paint};
+
+/// How long a failing health check is retried for, unless the host sets
+/// `health-check-timeout`
+const HEALTH_CHECK_TIMEOUT: u64 = 60;
+
+/// Delay between attempts of a failing health check
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Hosts of the inventory, by name
+pub type Inventory = BTreeMap<String, InventoryHost>;
+
+/// A host of the inventory.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct InventoryHost {
+    /// Configuration to deploy from nixosConfigurations, defaults to the
+    /// name of the host
+    pub hostname: Option<String>,
+
+    /// Where to deploy the configuration to over ssh, defaults to the name
+    /// of the host
+    pub target_host: Option<String>,
+
+    /// Build the configuration on this host over ssh
+    pub build_host: Option<String>,
+
+    /// Roles or groups the host belongs to, selected with `--tag`
+    #[serde(default)]
+    pub tags: Vec<String>,
+
+    /// Options passed to ssh for this host, on top of `NIX_SSHOPTS`
+    pub ssh_options: Option<String>,
+
+    /// Shell command run on the host once it is activated, which has to
+    /// succeed for the rollout to go on
+    pub health_check: Option<String>,
+
+    /// Seconds to retry a failing health check for
+    pub health_check_timeout: Option<u64>,
+}
+
+impl InventoryHost {
+    /// The attribute of nixosConfigurations to deploy to the host `name`.
+    #[must_use]
+    pub fn hostname<'a>(&'a self, name: &'a str) -> &'a str {
+        self.hostname.as_deref().unwrap_or(name)
+    }
+
+    /// The ssh destination of the host `name`.
+    #[must_use]
+    pub fn target_host<'a>(&'a self, name: &'a str) -> &'a str {
+        self.target_host.as_deref().unwrap_or(name)
+    }
+}
+
+/// Rollouts of groups of hosts, by tag
+pub type Rollouts = BTreeMap<String, Rollout>;
+
+/// How the hosts of a group are rolled out.
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Rollout {
+    /// Number of hosts deployed at the same time after the canaries
+    pub max_parallel: Option<usize>,
+
+    /// Hosts deployed first, one at a time, which have to succeed before
+    /// the other hosts are deployed
+    #[serde(default)]
+    pub canaries: Vec<String>,
+}
+
+impl Rollout {
+    /// The rollout of the groups `tags`, or of every group if `all` is set:
+    /// the lowest `max-parallel` of them, and all their canaries.
+    #[must_use]
+    pub fn of_groups(rollouts: &Rollouts, tags: &[String], all: bool) -> Self {
+        let groups: Vec<&Self> = rollouts
+            .iter()
+            .filter(|(tag, _)| all || tags.contains(tag))
+            .map(|(_, rollout)| rollout)
+            .collect();
+        Self {
+            max_parallel: groups.iter().filter_map(|r| r.max_parallel).min(),
+            canaries: groups
+                .iter()
+                .flat_map(|r| r.canaries.iter().cloned())
+                .collect(),
+        }
+    }
+}
+
+/// Waves of `hosts` to deploy one after the other: each canary on its own,
+/// then the other hosts, up to `max-parallel` at a time.
+pub fn waves<'a>(
+    hosts: Vec<(&'a str, &'a InventoryHost)>,
+    rollout: &Rollout,
+) -> Result<Vec<Vec<(&'a str, &'a InventoryHost)>>> {
+    if let Some(canary) = rollout
+        .canaries
+        .iter()
+        .find(|canary| !hosts.iter().any(|(name, _)| name == canary))
+    {
+        bail!("The canary {canary} isn't one of the deployed hosts");
+    }
+
+    let (canaries, rest): (Vec<_>, Vec<_>) = hosts
+        .into_iter()
+        .partition(|(name, _)| rollout.canaries.iter().any(|canary| canary == name));
+    let max_parallel = rollout.max_parallel.unwrap_or(1).max(1);
+
+    let mut waves: Vec<Vec<_>> = canaries.into_iter().map(|host| vec![host]).collect();
+    waves.extend(rest.chunks(max_parallel).map(<[_]>::to_vec));
+    Ok(waves)
+}
+
+/// The hosts of `inventory` named in `hosts` or carrying one of `tags`, or
+/// all of them if `all` is set, in the order they are deployed in.
+pub fn select<'a>(
+    inventory: &'a Inventory,
+    tags: &[String],
+    hosts: &[String],
+    all: bool,
+) -> Result<Vec<(&'a str, &'a InventoryHost)>> {
+    if inventory.is_empty() {
+        bail!(
+            "The inventory is empty, add hosts to the [inventory] section of the nh configuration"
+        );
+    }
+    if let Some(unknown) = hosts.iter().find(|host| !inventory.contains_key(*host)) {
+        bail!("There is no host {unknown} in the inventory");
+    }
+
+    let selected: Vec<_> = inventory
+        .iter()
+        .filter(|(name, host)| {
+            all || hosts.contains(name) || host.tags.iter().any(|tag| tags.contains(tag))
+        })
+        .map(|(name, host)| (name.as_str(), host))
+        .collect();
+
+    if selected.is_empty() {
+        bail!(
+            "No host of the inventory is tagged with {}",
+            tags.join(" or ")
+        );
+    }
+    Ok(selected)
+}
+
+/// Run the health check of the host `name` on `target_host`, retrying it
+/// until it succeeds or the timeout of the host runs out.
+pub fn health_check(name: &str, host: &InventoryHost, target_host: &str) -> Result<()> {
+    let Some(check) = &host.health_check else {
+        return Ok(());
+    };
+
+    events::phase(Phase::HealthCheck, || {
+        info!("Checking the health of {name}");
+
+        let timeout =
+            Duration::from_secs(host.health_check_timeout.unwrap_or(HEALTH_CHECK_TIMEOUT));
+        let cmd = Command::new("sh")
+            .args(["-c", check])
+            .ssh(Some(target_host.to_string()));
+        let start = Instant::now();
+
+        loop {
+            match cmd.run() {
+                Ok(()) => return Ok(()),
+                Err(err) if start.elapsed() >= timeout => {
+                    return Err(err)
+                        .wrap_err(format!("{name} isn't healthy after {}s", timeout.as_secs()));
+                }
+                Err(err) => {
+                    debug!("Health check of {name} failed, retrying: {err:#}");
+                    thread::sleep(HEALTH_CHECK_INTERVAL);
+                }
+            }
+        }
+    })
+}
+
+/// A table of how deploying each host went.
+#[must_use]
+pub fn format_report(outcomes: &[HostDeploy]) -> String {
+    let name_width = outcomes
+        .iter()
+        .map(|o| o.name.len())
+        .chain(std::iter::once("HOST".len()))
+        .max()
+        .unwrap_or_default();
+
+    let mut table = String::new();
+    let _ = writeln!(
+        table,
+        "{:<name_width$}  {:<8}  {:<10}  DETAILS",
+        "HOST", "STATUS", "GENERATION"
+    );
+
+    for outcome in outcomes {
+        let role = match outcome.status {
+            "deployed" => Role::Success,
+            "failed" => Role::Error,
+            _ => Role::Muted,
+        };
+        let details = match (&outcome.error, &outcome.result) {
+            (Some(error), _) => error.clone(),
+            (None, Some(result)) => result.out_path.display().to_string(),
+            (None, None) => String::new(),
+        };
+        let generation = outcome
+            .generation
+            .map_or_else(|| "-".to_string(), |generation| generation.to_string());
+        let _ = writeln!(
+            table,
+            "{:<name_width$}  {}  {generation:<10}  {details}",
+            outcome.name,
+            paint(format!("{:<8}", outcome.status), role),
+        );
+    }
+
+    table
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn inventory() -> Inventory {
+        toml::from_str(
+            r#"
+[web-1]
+tags = ["web", "eu"]
+
+[web-2]
+target-host = "root@10.0.0.2"
+tags = ["web"]
+
+[db]
+hostname = "db-primary"
+ssh-options = "-p 2222"
+tags = ["db"]
+"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_select() {
+        let inventory = inventory();
+        let names = |tags: &[&str], hosts: &[&str], all| {
+            let tags: Vec<String> = tags.iter().map(ToString::to_string).collect();
+            let hosts: Vec<String> = hosts.iter().map(ToString::to_string).collect();
+            select(&inventory, &tags, &hosts, all)
+                .map(|selected| selected.iter().map(|(name, _)| *name).collect::<Vec<_>>())
+        };
+
+        assert_eq!(names(&["web"], &[], false).unwrap(), ["web-1", "web-2"]);
+        assert_eq!(names(&["eu"], &["db"], false).unwrap(), ["db", "web-1"]);
+        assert_eq!(names(&[], &[], true).unwrap(), ["db", "web-1", "web-2"]);
+        assert!(names(&["cache"], &[], false).is_err());
+        assert!(names(&[], &["web-3"], false).is_err());
+        assert!(select(&Inventory::new(), &[], &[], true).is_err());
+    }
+
+    #[test]
+    fn test_waves() {
+        let inventory: Inventory = toml::from_str(
+            r#"
+[a]
+[b]
+[c]
+[d]
+ssh-options = "-p 2222"
+[e]
+"#,
+        )
+        .unwrap();
+        let hosts = || select(&inventory, &[], &[], true).unwrap();
+        let names = |waves: Vec<Vec<(&str, &InventoryHost)>>| {
+            waves
+                .into_iter()
+                .map(|wave| {
+                    wave.into_iter()
+                        .map(|(name, _)| name.to_string())
+                        .collect::<Vec<_>>()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let one_at_a_time = Rollout::default();
+        assert_eq!(
+            names(waves(hosts(), &one_at_a_time).unwrap()),
+            [vec!["a"], vec!["b"], vec!["c"], vec!["d"], vec!["e"]]
+        );
+
+        let rollout = Rollout {
+            max_parallel: Some(3),
+            canaries: vec!["c".to_string()],
+        };
+        assert_eq!(
+            names(waves(hosts(), &rollout).unwrap()),
+            [vec!["c"], vec!["a", "b", "d"], vec!["e"]]
+        );
+
+        let unknown = Rollout {
+            max_parallel: None,
+            canaries: vec!["f".to_string()],
+        };
+        assert!(waves(hosts(), &unknown).is_err());
+    }
+
+    #[test]
+    fn test_rollout_of_groups() {
+        let rollouts: Rollouts = toml::from_str(
+            r#"
+[web]
+max-parallel = 4
+canaries = ["web-1"]
+
+[db]
+max-parallel = 1
+"#,
+        )
+        .unwrap();
+
+        let web = Rollout::of_groups(&rollouts, &["web".to_string()], false);
+        assert_eq!(web.max_parallel, Some(4));
+        assert_eq!(web.canaries, ["web-1"]);
+        assert_eq!(
+            Rollout::of_groups(&rollouts, &[], true).max_parallel,
+            Some(1)
+        );
+        assert_eq!(
+            Rollout::of_groups(&rollouts, &[], false),
+            Rollout::default()
+        );
+    }
+
+    #[test]
+    fn test_host_defaults() {
+        let inventory = inventory();
+
+        assert_eq!(inventory["web-1"].hostname("web-1"), "web-1");
+        assert_eq!(inventory["web-1"].target_host("web-1"), "web-1");
+        assert_eq!(inventory["web-2"].target_host("web-2"), "root@10.0.0.2");
+        assert_eq!(inventory["db"].hostname("db"), "db-primary");
+        assert_eq!(inventory["db"].ssh_options.as_deref(), Some("-p 2222"));
+        assert_eq!(inventory["web-1"].ssh_options, None);
+        assert!(toml::from_str::<Inventory>("[web-1]\ntag = \"web\"").is_err());
+    }
+}
@@ -1,4 +1,202 @@
+//! JSON helpers: indexing into values returned by nix, and the structured
+//! output printed instead of human readable output with `--json`.
+//!
+//! With `--json`, a run prints exactly one [`Output`] document to stdout.
+//! Anything else meant for humans is either skipped or goes to stderr.
+
 use std::fmt::Display;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use color_eyre::Result;
+use serde::Serialize;
+
+use crate::diff::ClosureDiff;
+use crate::generations::GenerationInfo;
+use crate::options::OptionInfo;
+use crate::output;
+use crate::search::SearchOutput;
+use crate::stats::CommandStats;
+
+static OUTPUT_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Switch to JSON output for the rest of the run.
+pub fn enable_output() {
+    OUTPUT_ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether `--json` was passed, so human readable output should be skipped.
+#[must_use]
+pub fn output_enabled() -> bool {
+    OUTPUT_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Result of `nh os`, `nh home` and `nh darwin` rebuilds
+#[derive(Debug, Clone, Serialize)]
+pub struct RebuildResult {
+    /// `nixos`, `home-manager` or `darwin`
+    pub system: &'static str,
+    /// The subcommand, like `switch` or `build`
+    pub action: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Store path of the built configuration
+    pub out_path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    pub dry: bool,
+    /// Whether the configuration was activated or added to the bootloader
+    pub activated: bool,
+    /// Generation of the system profile the configuration became
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+    /// What copying the configuration to the target host took
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub copy: Option<CopyStats>,
+    /// Packages that changed from the current configuration, unless the diff
+    /// was disabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub diff: Option<ClosureDiff>,
+}
+
+/// The transfer of a closure to a target host, see [`crate::transfer`]
+#[derive(Debug, Clone, Serialize)]
+pub struct CopyStats {
+    pub paths_total: usize,
+    /// Paths the target didn't have yet
+    pub paths_copied: usize,
+    /// NAR size of the whole closure
+    pub bytes_total: u64,
+    /// NAR size of the copied paths, before compression
+    pub bytes_copied: u64,
+    /// Share of the closure's bytes the target already had
+    pub dedup_ratio: f64,
+    /// Copied bytes per second
+    pub throughput: u64,
+    pub seconds: f64,
+}
+
+/// Outcome of deploying one host of the inventory with `nh os deploy`
+#[derive(Debug, Serialize)]
+pub struct HostDeploy {
+    pub name: String,
+    /// `deployed`, `failed`, or `skipped` after an earlier host failed
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<RebuildResult>,
+    /// Generation of the system profile of the host after the rollout
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RollbackResult {
+    pub generation: u64,
+    pub path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub specialisation: Option<String>,
+    pub dry: bool,
+}
+
+/// What rolling back would change, as previewed by `nh os rollback
+/// --diff-only`
+#[derive(Debug, Serialize)]
+pub struct DiffResult {
+    /// The generation a rollback would activate
+    pub generation: u64,
+    pub path: PathBuf,
+    pub diff: ClosureDiff,
+}
+
+/// The systems `nh os status` compares
+#[derive(Debug, Serialize)]
+pub struct SystemStatus {
+    pub booted: Option<SystemState>,
+    pub running: Option<SystemState>,
+    /// What the system profile points to, which is booted by default
+    pub default: Option<SystemState>,
+    /// The newest generation, unless it is the default after a rollback
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub newest: Option<SystemState>,
+    /// Differences between them, like a pending reboot
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SystemState {
+    pub path: PathBuf,
+    /// Generation of the system profile, if it still has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub generation: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CleanResult {
+    pub dry: bool,
+    /// Generations and gcroots that were (or would be) removed
+    pub removed: Vec<PathBuf>,
+    pub kept: Vec<PathBuf>,
+    /// Whether the store was garbage collected afterwards
+    pub gc: bool,
+}
+
+/// State of a flake input, as shown by `nh update status`
+#[derive(Debug, Serialize)]
+pub struct InputStatus {
+    pub name: String,
+    pub rev: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_modified: Option<i64>,
+    /// How far behind upstream, absent with `--offline`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<String>,
+}
+
+/// The result of the rebuild of this run, for notifications and `nh stats`
+static LAST_REBUILD: Mutex<Option<RebuildResult>> = Mutex::new(None);
+
+/// The result of the rebuild of this run, once there is one.
+pub fn last_rebuild() -> Option<RebuildResult> {
+    LAST_REBUILD.lock().ok().and_then(|last| last.clone())
+}
+
+/// The document printed by a run with `--json`
+#[derive(Debug, Serialize)]
+#[serde(tag = "command", content = "result", rename_all = "snake_case")]
+pub enum Output {
+    Rebuild(RebuildResult),
+    Deploy(Vec<HostDeploy>),
+    Rollback(RollbackResult),
+    Diff(DiffResult),
+    Clean(CleanResult),
+    Status(SystemStatus),
+    Search(SearchOutput),
+    Info(Vec<GenerationInfo>),
+    Update(Vec<InputStatus>),
+    Stats(Vec<CommandStats>),
+    Option(OptionInfo),
+}
+
+/// Print `output` if `--json` was passed, or its summary with `--quiet`, and
+/// keep it for notifications.
+pub fn emit(output: &Output) -> Result<()> {
+    if let Output::Rebuild(result) = output {
+        if let Ok(mut last) = LAST_REBUILD.lock() {
+            *last = Some(result.clone());
+        }
+    }
+    if output_enabled() {
+        println!("{}", serde_json::to_string_pretty(output)?);
+    } else if output::quiet() {
+        if let Some(summary) = output::summary(output) {
+            println!("{summary}");
+        }
+    }
+    Ok(())
+}
 
 #[derive(Debug, Clone)]
 pub struct Value<'v> {
@@ -85,3 +283,35 @@ fn test_value() {
             .is_ok()
     );
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_output_shape() {
+        let output = Output::Clean(CleanResult {
+            dry: true,
+            removed: vec![PathBuf::from("/nix/var/nix/profiles/system-1-link")],
+            kept: Vec::new(),
+            gc: false,
+        });
+        let value = serde_json::to_value(&output).unwrap();
+        assert_eq!(value["command"], "clean");
+        assert_eq!(value["result"]["dry"], true);
+        assert_eq!(
+            value["result"]["removed"][0],
+            "/nix/var/nix/profiles/system-1-link"
+        );
+
+        let output = Output::Diff(DiffResult {
+            generation: 1,
+            path: PathBuf::from("/nix/var/nix/profiles/system-1-link"),
+            diff: ClosureDiff::default(),
+        });
+        let value = serde_json::to_value(&output).unwrap();
+        assert_eq!(value["command"], "diff");
+        assert_eq!(value["result"]["generation"], 1);
+        assert_eq!(value["result"]["diff"]["added"], serde_json::json!([]));
+    }
+}
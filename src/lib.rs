@@ -1,20 +1,73 @@
-//! Internal library output for NH. This is not meant for public consumption.
+//! Internal library output for NH. Apart from [`api`], which other programs
+//! can use to drive nh, this is not meant for public consumption.
+pub mod api;
+pub mod batch;
+pub mod boot;
+pub mod browse;
+pub mod build_log;
+pub mod builders;
+pub mod channels;
 pub mod checks;
 pub mod clean;
 pub mod commands;
 pub mod completion;
+pub mod config;
+pub mod confirm;
 pub mod darwin;
+pub mod diff;
+pub mod disko;
+pub mod doctor;
+pub mod eval_trace;
+pub mod events;
+pub mod exit;
+pub mod fingerprint;
+pub mod flake;
+pub mod flake_check;
+pub mod gcroots;
 pub mod generations;
+pub mod hints;
 pub mod home;
+pub mod hooks;
+pub mod init;
+pub mod inspect;
 pub mod installable;
 pub mod interface;
+pub mod inventory;
 pub mod json;
+pub mod lockfile;
 pub mod logging;
+pub mod nix_index;
 pub mod nixos;
+pub mod notify;
+pub mod options;
+pub mod output;
+pub mod plugin;
+pub mod probe;
+pub mod profile;
+pub mod progress;
+pub mod push;
+pub mod repl;
+pub mod results;
+pub mod run;
 pub mod search;
+pub mod secrets;
+pub mod self_update;
+pub mod signatures;
+pub mod spec;
+pub mod state;
+pub mod stats;
+pub mod store;
 pub mod system;
+pub mod template;
+#[cfg(test)]
+mod test_util;
+pub mod theme;
+pub mod timings;
+pub mod transfer;
 pub mod update;
 pub mod util;
+pub mod vulns;
+pub mod worktree;
 
 pub use color_eyre::Result;
 
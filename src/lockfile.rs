@@ -0,0 +1,619 @@
+//! Reading `flake.lock` files, to summarize what `nix flake update` changed.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+use tracing::{debug, warn};
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct LockFile {
+    nodes: BTreeMap<String, Node>,
+    root: String,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct Node {
+    #[serde(default)]
+    inputs: BTreeMap<String, InputRef>,
+    locked: Option<Locked>,
+    original: Option<Original>,
+}
+
+/// The input as written in flake.nix, before locking.
+#[derive(Debug, Clone, Deserialize)]
+struct Original {
+    #[serde(rename = "ref")]
+    git_ref: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum InputRef {
+    Node(String),
+    /// `follows` another input, which is reported under its own name
+    Follows(#[allow(dead_code)] Vec<String>),
+}
+
+/// The locked source of an input.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Locked {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub owner: Option<String>,
+    pub repo: Option<String>,
+    pub rev: Option<String>,
+    pub last_modified: Option<i64>,
+    pub nar_hash: Option<String>,
+    pub url: Option<String>,
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+}
+
+/// How far a locked input is behind its upstream branch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Staleness {
+    UpToDate,
+    /// Number of commits the branch is ahead of the locked revision
+    Behind(u64),
+    /// The branch moved to this revision, but the distance is unknown
+    Moved(String),
+    Unknown,
+}
+
+impl std::fmt::Display for Staleness {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::UpToDate => write!(f, "up to date"),
+            Self::Behind(commits) => write!(
+                f,
+                "{commits} commit{} behind",
+                if *commits == 1 { "" } else { "s" }
+            ),
+            Self::Moved(rev) => write!(f, "behind (upstream at {rev})"),
+            Self::Unknown => write!(f, "unknown"),
+        }
+    }
+}
+
+impl Locked {
+    /// Short revision, or a prefix of the NAR hash for inputs without one.
+    #[must_use]
+    pub fn short_rev(&self) -> String {
+        let id = self
+            .rev
+            .as_deref()
+            .or_else(|| {
+                self.nar_hash
+                    .as_deref()
+                    .map(|hash| hash.trim_start_matches("sha256-"))
+            })
+            .unwrap_or("unknown");
+        id.chars().take(7).collect()
+    }
+
+    /// Time since the input was last modified, e.g. `3 days`.
+    #[must_use]
+    pub fn age(&self) -> Option<String> {
+        self.last_modified
+            .map(|modified| format_age(Utc::now().timestamp() - modified))
+    }
+
+    fn github_repo(&self) -> Option<(&str, &str)> {
+        (self.kind == "github").then_some((self.owner.as_deref()?, self.repo.as_deref()?))
+    }
+}
+
+impl LockFile {
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            fs::read_to_string(path).wrap_err(format!("Failed to read {}", path.display()))?;
+        serde_json::from_str(&contents).wrap_err(format!("Failed to parse {}", path.display()))
+    }
+
+    /// The branch or tag a direct input follows, if one was given in
+    /// flake.nix.
+    #[must_use]
+    pub fn original_ref(&self, input: &str) -> Option<&str> {
+        let root = self.nodes.get(&self.root)?;
+        let InputRef::Node(node) = root.inputs.get(input)? else {
+            return None;
+        };
+        self.nodes.get(node)?.original.as_ref()?.git_ref.as_deref()
+    }
+
+    /// Direct inputs of the flake with their locked sources. Inputs that
+    /// `follows` another one are left out.
+    #[must_use]
+    pub fn inputs(&self) -> BTreeMap<String, Locked> {
+        let Some(root) = self.nodes.get(&self.root) else {
+            return BTreeMap::new();
+        };
+
+        root.inputs
+            .iter()
+            .filter_map(|(name, input)| match input {
+                InputRef::Node(node) => {
+                    let locked = self.nodes.get(node)?.locked.clone()?;
+                    Some((name.clone(), locked))
+                }
+                InputRef::Follows(_) => None,
+            })
+            .collect()
+    }
+}
+
+/// Restores a lock file to its previous contents when dropped, unless
+/// [`LockGuard::keep`] was called. Backs `--revert-on-failure`, so that any
+/// error after an update puts the old lock file back.
+#[derive(Debug)]
+#[must_use]
+pub struct LockGuard {
+    path: PathBuf,
+    /// `None` if the lock file didn't exist yet
+    contents: Option<Vec<u8>>,
+    armed: bool,
+}
+
+impl LockGuard {
+    pub fn snapshot(path: &Path) -> Result<Self> {
+        let contents = match fs::read(path) {
+            Ok(contents) => Some(contents),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err).wrap_err(format!("Failed to read {}", path.display())),
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            contents,
+            armed: true,
+        })
+    }
+
+    /// Keep the updated lock file.
+    pub fn keep(mut self) {
+        self.armed = false;
+    }
+
+    fn restore(&self) -> std::io::Result<()> {
+        match &self.contents {
+            Some(contents) => fs::write(&self.path, contents),
+            None => fs::remove_file(&self.path),
+        }
+    }
+}
+
+impl Drop for LockGuard {
+    fn drop(&mut self) {
+        if !self.armed {
+            return;
+        }
+
+        match self.restore() {
+            Ok(()) => warn!(
+                "Restored {} to its state before the update",
+                self.path.display()
+            ),
+            Err(err) => warn!("Failed to restore {}: {err}", self.path.display()),
+        }
+    }
+}
+
+/// How a single input differs between two lock files.
+#[derive(Debug, PartialEq, Eq)]
+pub struct InputChange {
+    pub name: String,
+    pub old: Option<Locked>,
+    pub new: Option<Locked>,
+}
+
+/// Direct inputs that were added, removed or moved to another revision.
+#[must_use]
+pub fn diff(old: &LockFile, new: &LockFile) -> Vec<InputChange> {
+    let mut old = old.inputs();
+    let new = new.inputs();
+
+    let mut changes: Vec<InputChange> = new
+        .into_iter()
+        .filter_map(|(name, new)| {
+            let old = old.remove(&name);
+            let unchanged = old
+                .as_ref()
+                .is_some_and(|old| old.rev == new.rev && old.nar_hash == new.nar_hash);
+            (!unchanged).then_some(InputChange {
+                name,
+                old,
+                new: Some(new),
+            })
+        })
+        .collect();
+
+    changes.extend(old.into_iter().map(|(name, old)| InputChange {
+        name,
+        old: Some(old),
+        new: None,
+    }));
+    changes.sort_by(|a, b| a.name.cmp(&b.name));
+    changes
+}
+
+/// Human readable length of a time span given in seconds, e.g. `3 days`.
+#[must_use]
+pub fn format_age(seconds: i64) -> String {
+    let seconds = seconds.unsigned_abs();
+    let (value, unit) = match seconds {
+        0..60 => (seconds, "second"),
+        60..3600 => (seconds / 60, "minute"),
+        3600..86400 => (seconds / 3600, "hour"),
+        _ => (seconds / 86400, "day"),
+    };
+    format!("{value} {unit}{}", if value == 1 { "" } else { "s" })
+}
+
+/// One line summary of a change. `commits` is the number of commits between
+/// the old and new revision, if known.
+#[must_use]
+pub fn describe_change(change: &InputChange, commits: Option<u64>) -> String {
+    match (&change.old, &change.new) {
+        (None, Some(new)) => format!("{}: added at {}", change.name, new.short_rev()),
+        (Some(_), None) => format!("{}: removed", change.name),
+        (Some(old), Some(new)) => {
+            let mut details = Vec::new();
+            if let (Some(old), Some(new)) = (old.last_modified, new.last_modified) {
+                let direction = if new >= old { "newer" } else { "older" };
+                details.push(format!("{} {direction}", format_age(new - old)));
+            }
+            if let Some(commits) = commits {
+                details.push(format!(
+                    "{commits} commit{}",
+                    if commits == 1 { "" } else { "s" }
+                ));
+            }
+
+            let mut line = format!("{}: {} → {}", change.name, old.short_rev(), new.short_rev());
+            if !details.is_empty() {
+                line = format!("{line} ({})", details.join(", "));
+            }
+            if let Some(link) = compare_link(old, new) {
+                line = format!("{line} {link}");
+            }
+            line
+        }
+        (None, None) => change.name.clone(),
+    }
+}
+
+fn compare_link(old: &Locked, new: &Locked) -> Option<String> {
+    let (owner, repo) = new.github_repo()?;
+    if old.github_repo() != Some((owner, repo)) {
+        return None;
+    }
+    Some(format!(
+        "https://github.com/{owner}/{repo}/compare/{}...{}",
+        old.rev.as_deref()?,
+        new.rev.as_deref()?
+    ))
+}
+
+/// Number of commits between two revisions of a GitHub input, queried from
+/// the GitHub API. Any failure, such as being rate limited, yields `None`.
+fn github_commits(old: &Locked, new: &Locked) -> Option<u64> {
+    let (owner, repo) = new.github_repo()?;
+    if old.github_repo() != Some((owner, repo)) {
+        return None;
+    }
+
+    github_compare(owner, repo, old.rev.as_deref()?, new.rev.as_deref()?)
+}
+
+/// Token for the GitHub API from `GITHUB_TOKEN` or `GH_TOKEN`, which raises
+/// the rate limit of 60 unauthenticated requests an hour.
+fn github_token() -> Option<String> {
+    ["GITHUB_TOKEN", "GH_TOKEN"]
+        .into_iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|token| !token.is_empty())
+}
+
+/// Number of commits `head` is ahead of `base` in a GitHub repository.
+fn github_compare(owner: &str, repo: &str, base: &str, head: &str) -> Option<u64> {
+    #[derive(Deserialize)]
+    struct Comparison {
+        ahead_by: u64,
+    }
+
+    let url = format!("https://api.github.com/repos/{owner}/{repo}/compare/{base}...{head}");
+
+    let mut request = reqwest::blocking::Client::new()
+        .get(&url)
+        .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
+        .timeout(Duration::from_secs(5));
+    if let Some(token) = github_token() {
+        request = request.bearer_auth(token);
+    }
+    let response = request
+        .send()
+        .and_then(reqwest::blocking::Response::error_for_status);
+
+    match response.and_then(reqwest::blocking::Response::json::<Comparison>) {
+        Ok(comparison) => Some(comparison.ahead_by),
+        Err(err) => {
+            debug!(?err, url, "Failed to compare revisions");
+            None
+        }
+    }
+}
+
+/// How far `locked` is behind the upstream branch `original_ref` (or the
+/// default branch). GitHub inputs are compared through the GitHub API, git
+/// inputs with `git ls-remote`.
+#[must_use]
+pub fn staleness(locked: &Locked, original_ref: Option<&str>) -> Staleness {
+    let Some(rev) = locked.rev.as_deref() else {
+        return Staleness::Unknown;
+    };
+
+    if let Some((owner, repo)) = locked.github_repo() {
+        return match github_compare(owner, repo, rev, original_ref.unwrap_or("HEAD")) {
+            Some(0) => Staleness::UpToDate,
+            Some(commits) => Staleness::Behind(commits),
+            None => Staleness::Unknown,
+        };
+    }
+
+    if locked.kind == "git" {
+        let Some(url) = &locked.url else {
+            return Staleness::Unknown;
+        };
+        let git_ref = original_ref.or(locked.git_ref.as_deref()).unwrap_or("HEAD");
+
+        let remote = crate::commands::Command::new("git")
+            .args(["ls-remote", url, git_ref])
+            .run_capture()
+            .ok()
+            .flatten()
+            .and_then(|output| parse_ls_remote(&output));
+
+        return match remote {
+            Some(remote) if remote == rev => Staleness::UpToDate,
+            Some(remote) => Staleness::Moved(remote.chars().take(7).collect()),
+            None => Staleness::Unknown,
+        };
+    }
+
+    Staleness::Unknown
+}
+
+/// First revision printed by `git ls-remote`.
+fn parse_ls_remote(output: &str) -> Option<String> {
+    output
+        .lines()
+        .next()?
+        .split_whitespace()
+        .next()
+        .map(str::to_string)
+}
+
+/// Commit message for a lock file update, listing the changed inputs.
+#[must_use]
+pub fn commit_message(changes: &[InputChange]) -> String {
+    let names: Vec<&str> = changes.iter().map(|c| c.name.as_str()).collect();
+    let mut message = format!("flake.lock: update {}\n", names.join(", "));
+
+    if !changes.is_empty() {
+        message.push('\n');
+        for change in changes {
+            message.push_str(&format!("- {}\n", describe_change(change, None)));
+        }
+    }
+
+    message
+}
+
+/// Print what changed between two versions of a lock file.
+pub fn print_summary(old: &LockFile, new: &LockFile) {
+    if !crate::output::human() {
+        return;
+    }
+
+    let changes = diff(old, new);
+    if changes.is_empty() {
+        println!("No flake inputs changed");
+        return;
+    }
+
+    // The GitHub queries are network bound, so run them all at once rather
+    // than waiting up to their timeout one input after the other
+    let commits: Vec<Option<u64>> = thread::scope(|scope| {
+        let handles: Vec<_> = changes
+            .iter()
+            .map(|change| {
+                scope.spawn(move || match (&change.old, &change.new) {
+                    (Some(old), Some(new)) => github_commits(old, new),
+                    _ => None,
+                })
+            })
+            .collect();
+
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap_or(None))
+            .collect()
+    });
+
+    println!("Updated flake inputs:");
+    for (change, commits) in changes.iter().zip(commits) {
+        println!("  {}", describe_change(change, commits));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lock(nixpkgs_rev: &str, nixpkgs_modified: i64, extra: &str) -> LockFile {
+        serde_json::from_str(&format!(
+            r#"{{
+  "nodes": {{
+    "nixpkgs": {{
+      "locked": {{
+        "type": "github", "owner": "NixOS", "repo": "nixpkgs",
+        "rev": "{nixpkgs_rev}", "lastModified": {nixpkgs_modified},
+        "narHash": "sha256-{nixpkgs_rev}"
+      }}
+    }},
+    "home-manager": {{
+      "inputs": {{ "nixpkgs": ["nixpkgs"] }},
+      "locked": {{
+        "type": "github", "owner": "nix-community", "repo": "home-manager",
+        "rev": "1111111111111111111111111111111111111111", "lastModified": 100,
+        "narHash": "sha256-hm"
+      }}
+    }},
+    {extra}
+    "root": {{
+      "inputs": {{ "nixpkgs": "nixpkgs", "home-manager": "home-manager" {root_extra} }}
+    }}
+  }},
+  "root": "root",
+  "version": 7
+}}"#,
+            root_extra = if extra.is_empty() {
+                ""
+            } else {
+                r#", "local": "local""#
+            },
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_inputs() {
+        let lock = lock("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 0, "");
+        let inputs = lock.inputs();
+        assert_eq!(
+            inputs.keys().collect::<Vec<_>>(),
+            vec!["home-manager", "nixpkgs"]
+        );
+        assert_eq!(inputs["nixpkgs"].short_rev(), "aaaaaaa");
+    }
+
+    #[test]
+    fn test_diff() {
+        let old = lock(
+            "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa",
+            0,
+            r#""local": { "locked": { "type": "path", "narHash": "sha256-local" } },"#,
+        );
+        let new = lock("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb", 3 * 86400, "");
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.len(), 2);
+
+        assert_eq!(changes[0].name, "local");
+        assert_eq!(describe_change(&changes[0], None), "local: removed");
+
+        assert_eq!(changes[1].name, "nixpkgs");
+        assert_eq!(
+            describe_change(&changes[1], Some(42)),
+            "nixpkgs: aaaaaaa → bbbbbbb (3 days newer, 42 commits) \
+             https://github.com/NixOS/nixpkgs/compare/\
+             aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa...bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"
+        );
+
+        assert!(diff(&new, &new).is_empty());
+    }
+
+    #[test]
+    fn test_original_ref() {
+        let lock: LockFile = serde_json::from_str(
+            r#"{
+  "nodes": {
+    "nixpkgs": {
+      "locked": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "rev": "abc" },
+      "original": { "type": "github", "owner": "NixOS", "repo": "nixpkgs", "ref": "nixos-unstable" }
+    },
+    "root": { "inputs": { "nixpkgs": "nixpkgs" } }
+  },
+  "root": "root",
+  "version": 7
+}"#,
+        )
+        .unwrap();
+
+        assert_eq!(lock.original_ref("nixpkgs"), Some("nixos-unstable"));
+        assert_eq!(lock.original_ref("missing"), None);
+    }
+
+    #[test]
+    fn test_parse_ls_remote() {
+        assert_eq!(
+            parse_ls_remote("0123456789abcdef\trefs/heads/main\n").as_deref(),
+            Some("0123456789abcdef")
+        );
+        assert_eq!(parse_ls_remote(""), None);
+    }
+
+    #[test]
+    fn test_staleness_display() {
+        assert_eq!(Staleness::Behind(1).to_string(), "1 commit behind");
+        assert_eq!(Staleness::Behind(12).to_string(), "12 commits behind");
+        assert_eq!(
+            Staleness::Moved("abcdef0".to_string()).to_string(),
+            "behind (upstream at abcdef0)"
+        );
+    }
+
+    #[test]
+    fn test_commit_message() {
+        let old = lock("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", 0, "");
+        let new = lock("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb", 86400, "");
+
+        let message = commit_message(&diff(&old, &new));
+        let lines: Vec<&str> = message.lines().collect();
+        assert_eq!(lines[0], "flake.lock: update nixpkgs");
+        assert_eq!(lines[1], "");
+        assert!(lines[2].starts_with("- nixpkgs: aaaaaaa → bbbbbbb (1 day newer) https://"));
+        assert_eq!(lines.len(), 3);
+    }
+
+    #[test]
+    fn test_lock_guard() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("flake.lock");
+
+        fs::write(&path, "old").unwrap();
+        let guard = LockGuard::snapshot(&path).unwrap();
+        fs::write(&path, "new").unwrap();
+        drop(guard);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "old");
+
+        let guard = LockGuard::snapshot(&path).unwrap();
+        fs::write(&path, "new").unwrap();
+        guard.keep();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        let missing = dir.path().join("missing.lock");
+        let guard = LockGuard::snapshot(&missing).unwrap();
+        fs::write(&missing, "created").unwrap();
+        drop(guard);
+        assert!(!missing.exists());
+    }
+
+    #[test]
+    fn test_format_age() {
+        assert_eq!(format_age(0), "0 seconds");
+        assert_eq!(format_age(59), "59 seconds");
+        assert_eq!(format_age(60), "1 minute");
+        assert_eq!(format_age(2 * 3600), "2 hours");
+        assert_eq!(format_age(-86400), "1 day");
+        assert_eq!(format_age(30 * 86400), "30 days");
+    }
+}
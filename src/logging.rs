@@ -1,15 +1,127 @@
-use clap_verbosity_flag::WarnLevel;
-use owo_colors::OwoColorize;
+use std::fmt::Write as _;
+use std::io::IsTerminal;
+use std::os::unix::net::UnixDatagram;
+use std::path::Path;
+
+use clap::ValueEnum;
+use clap_verbosity_flag::{LogLevel, Verbosity, VerbosityFilter};
 use tracing::Event;
 use tracing::Level;
 use tracing::Subscriber;
+use tracing::field::{Field, Visit};
 use tracing_subscriber::EnvFilter;
 use tracing_subscriber::filter::LevelFilter;
 use tracing_subscriber::fmt::{self, FormatEvent, FormatFields};
+use tracing_subscriber::layer::{Context, Layer};
 use tracing_subscriber::prelude::*;
 use tracing_subscriber::registry::LookupSpan;
 
 use crate::Result;
+use crate::theme::{ColorChoice, Role, paint_err};
+
+/// Logs warnings by default. `-q` also switches to quiet mode, see
+/// [`crate::output`].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NhLogLevel;
+
+impl LogLevel for NhLogLevel {
+    fn default_filter() -> VerbosityFilter {
+        VerbosityFilter::Warn
+    }
+
+    fn quiet_help() -> Option<&'static str> {
+        Some(
+            "Only print the final result, like the out path or generation number. Pass twice to hide errors too",
+        )
+    }
+}
+
+/// Whether `-q` was passed.
+#[must_use]
+pub fn is_quiet(verbosity: &Verbosity<NhLogLevel>) -> bool {
+    matches!(
+        verbosity.filter(),
+        VerbosityFilter::Error | VerbosityFilter::Off
+    )
+}
+
+/// System log to mirror log events to, in addition to stderr
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SystemLog {
+    /// The systemd journal, with event fields as structured journal fields
+    Journald,
+    /// The local syslog daemon, with event fields appended to the message
+    Syslog,
+}
+
+/// Sockets of the local syslog daemon on Linux and macOS
+const SYSLOG_SOCKETS: [&str; 2] = ["/dev/log", "/var/run/syslog"];
+
+/// Syslog message for an event, with the `user` facility and no timestamp,
+/// which the daemon fills in.
+fn syslog_line(level: Level, pid: u32, message: &str) -> String {
+    let severity = match level {
+        Level::ERROR => 3,
+        Level::WARN => 4,
+        Level::INFO => 6,
+        Level::DEBUG | Level::TRACE => 7,
+    };
+    // Facility 1 (user) times 8, plus the severity
+    format!("<{}>nh[{pid}]: {message}", 8 + severity)
+}
+
+#[derive(Default)]
+struct SyslogVisitor {
+    message: String,
+    fields: String,
+}
+
+impl Visit for SyslogVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "message" {
+            self.message = value.to_string();
+        } else {
+            self.record_debug(field, &value);
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            let _ = write!(self.fields, " {}={value:?}", field.name());
+        }
+    }
+}
+
+struct SyslogLayer {
+    socket: UnixDatagram,
+}
+
+impl SyslogLayer {
+    fn new() -> std::io::Result<Self> {
+        let path = SYSLOG_SOCKETS
+            .into_iter()
+            .find(|path| Path::new(path).exists())
+            .ok_or_else(|| std::io::Error::from(std::io::ErrorKind::NotFound))?;
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(Self { socket })
+    }
+}
+
+impl<S: Subscriber> Layer<S> for SyslogLayer {
+    fn on_event(&self, event: &Event<'_>, _ctx: Context<'_, S>) {
+        let mut visitor = SyslogVisitor::default();
+        event.record(&mut visitor);
+        let message = format!("{}{}", visitor.message, visitor.fields);
+
+        // Losing a log line is better than failing the run over it
+        let _ = self
+            .socket
+            .send(syslog_line(*event.metadata().level(), std::process::id(), &message).as_bytes());
+    }
+}
 
 struct InfoFormatter;
 
@@ -30,11 +142,11 @@ where
         let level = metadata.level();
 
         match *level {
-            Level::ERROR => write!(writer, "{} ", "ERROR".red())?,
-            Level::WARN => write!(writer, "{} ", "!".yellow())?,
-            Level::INFO => write!(writer, "{} ", ">".green())?,
-            Level::DEBUG => write!(writer, "{} ", "DEBUG".blue())?,
-            Level::TRACE => write!(writer, "{} ", "TRACE".bright_blue())?,
+            Level::ERROR => write!(writer, "{} ", paint_err("ERROR", Role::Error))?,
+            Level::WARN => write!(writer, "{} ", paint_err("!", Role::Warning))?,
+            Level::INFO => write!(writer, "{} ", paint_err(">", Role::Info))?,
+            Level::DEBUG => write!(writer, "{} ", paint_err("DEBUG", Role::Debug))?,
+            Level::TRACE => write!(writer, "{} ", paint_err("TRACE", Role::Trace))?,
         }
 
         ctx.field_format().format_fields(writer.by_ref(), event)?;
@@ -50,8 +162,18 @@ where
     }
 }
 
-pub fn setup_logging(verbosity: clap_verbosity_flag::Verbosity<WarnLevel>) -> Result<()> {
+pub fn setup_logging(
+    verbosity: Verbosity<NhLogLevel>,
+    system_log: Option<SystemLog>,
+    color: ColorChoice,
+) -> Result<()> {
+    let hook_theme = if color.enabled(std::io::stderr().is_terminal()) {
+        color_eyre::config::Theme::dark()
+    } else {
+        color_eyre::config::Theme::new()
+    };
     color_eyre::config::HookBuilder::default()
+        .theme(hook_theme)
         .display_location_section(true)
         .panic_section("Please report the bug at https://github.com/nix-community/nh/issues")
         .display_env_section(false)
@@ -75,9 +197,56 @@ pub fn setup_logging(verbosity: clap_verbosity_flag::Verbosity<WarnLevel>) -> Re
         .event_format(InfoFormatter)
         .with_filter(EnvFilter::from_env("NH_LOG").add_directive(fallback_level.into()));
 
-    tracing_subscriber::registry().with(layer).init();
+    // Unattended runs are mostly interesting for what they did, so the
+    // system log gets at least informational messages
+    let system_level = fallback_level.max(LevelFilter::INFO);
+    let (journald, syslog, system_log_error) = match system_log {
+        None => (None, None, None),
+        Some(SystemLog::Journald) => match tracing_journald::layer() {
+            Ok(journald) => (
+                Some(
+                    journald
+                        .with_syslog_identifier("nh".to_string())
+                        .with_field_prefix(Some("NH".to_string()))
+                        .with_filter(system_level),
+                ),
+                None,
+                None,
+            ),
+            Err(err) => (None, None, Some(format!("the journal: {err}"))),
+        },
+        Some(SystemLog::Syslog) => match SyslogLayer::new() {
+            Ok(syslog) => (None, Some(syslog.with_filter(system_level)), None),
+            Err(err) => (None, None, Some(format!("syslog: {err}"))),
+        },
+    };
+
+    tracing_subscriber::registry()
+        .with(layer)
+        .with(journald)
+        .with(syslog)
+        .init();
+
+    if let Some(err) = system_log_error {
+        tracing::warn!("Couldn't connect to {err}, only logging to stderr");
+    }
 
     tracing::trace!("Logging OK");
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_syslog_line() {
+        assert_eq!(
+            syslog_line(Level::ERROR, 42, "Activation failed"),
+            "<11>nh[42]: Activation failed"
+        );
+        assert_eq!(syslog_line(Level::INFO, 1, "Done"), "<14>nh[1]: Done");
+        assert_eq!(syslog_line(Level::TRACE, 1, "x"), "<15>nh[1]: x");
+    }
+}
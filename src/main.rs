@@ -1,35 +1,155 @@
+mod batch;
+mod boot;
+mod browse;
+mod build_log;
+mod builders;
+mod channels;
 mod checks;
 mod clean;
 mod commands;
 mod completion;
+mod config;
+mod confirm;
 mod darwin;
+mod diff;
+mod disko;
+mod doctor;
+mod eval_trace;
+mod events;
+mod exit;
+mod fingerprint;
+mod flake;
+mod flake_check;
+mod gcroots;
 mod generations;
+mod hints;
 mod home;
+mod hooks;
+mod init;
+mod inspect;
 mod installable;
 mod interface;
+mod inventory;
 mod json;
+mod lockfile;
 mod logging;
+mod nix_index;
 mod nixos;
+mod notify;
+mod options;
+mod output;
+mod plugin;
+mod probe;
+mod profile;
+mod progress;
+mod push;
+mod repl;
+mod results;
+mod run;
 mod search;
+mod secrets;
+mod self_update;
+mod signatures;
+mod spec;
+mod state;
+mod stats;
+mod store;
 mod system;
+mod template;
+#[cfg(test)]
+mod test_util;
+mod theme;
+mod timings;
+mod transfer;
 mod update;
 mod util;
+mod vulns;
+mod worktree;
 
+use clap_verbosity_flag::VerbosityFilter;
 use color_eyre::Result;
+use color_eyre::eyre::Context;
 
 const NH_VERSION: &str = env!("CARGO_PKG_VERSION");
 const NH_REV: Option<&str> = option_env!("NH_REV");
 
-fn main() -> Result<()> {
-    let args = <crate::interface::Main as clap::Parser>::parse();
+fn main() {
+    if let Err(err) = run() {
+        if !output::silent() {
+            eprintln!("Error: {err:?}");
+            hints::print(&err);
+        }
+        std::process::exit(exit::code(&err));
+    }
+}
+
+fn run() -> Result<()> {
+    completion::complete_env();
+    config::init();
+
+    let mut command = <crate::interface::Main as clap::CommandFactory>::command();
+    // Detecting the Nix variant runs nix, so only do it when it's shown
+    if std::env::args().any(|arg| arg == "--version") {
+        command = command.long_version(String::leak(util::long_version()) as &str);
+    }
+    let matches = command.get_matches();
+    let args = <crate::interface::Main as clap::FromArgMatches>::from_arg_matches(&matches)
+        .unwrap_or_else(|err| err.exit());
 
     // Set up logging
-    crate::logging::setup_logging(args.verbosity)?;
+    crate::logging::setup_logging(args.verbosity, args.system_log, args.color)?;
     tracing::debug!("{args:#?}");
     tracing::debug!(%NH_VERSION, ?NH_REV);
 
-    // Check Nix version upfront
-    checks::verify_nix_environment()?;
+    if args.timings {
+        timings::enable();
+    }
+
+    if let Some(fd) = args.json_events {
+        match std::env::var_os(events::RELAY_VAR) {
+            Some(relay) => events::init_relay(std::path::Path::new(&relay))?,
+            None => events::init(fd)?,
+        }
+    }
+
+    commands::set_clean_env(args.clean_env);
+    diff::init(args.diff_tool, args.diff_format);
+    eval_trace::init(matches!(
+        args.verbosity.filter(),
+        VerbosityFilter::Debug | VerbosityFilter::Trace
+    ));
+    confirm::init(
+        args.ask_default.unwrap_or(false),
+        args.ask_timeout.map(Into::into),
+    );
+    theme::init(args.color, &config::get().theme)?;
+    checks::skip_checks(&args.skip_check);
+    if args.json {
+        json::enable_output();
+    }
+    if logging::is_quiet(&args.verbosity) {
+        output::enable_quiet();
+    }
+    if args.verbosity.filter() == VerbosityFilter::Off {
+        output::enable_silent();
+    }
+
+    config::check(matches!(
+        args.command,
+        crate::interface::NHCommand::Doctor(_)
+    ))?;
+
+    // Check Nix version upfront, except for commands that diagnose the very
+    // environment those checks would fail in, or don't need Nix at all
+    if !matches!(
+        args.command,
+        crate::interface::NHCommand::Doctor(_)
+            | crate::interface::NHCommand::SelfUpdate(_)
+            | crate::interface::NHCommand::Stats(_)
+            | crate::interface::NHCommand::External(_)
+    ) {
+        checks::verify_nix_environment().wrap_err(exit::Failure::Environment)?;
+    }
 
     // Once we assert required Nix features, validate NH environment checks
     // For now, this is just NH_* variables being set. More checks may be
@@ -40,5 +160,37 @@ fn main() -> Result<()> {
         );
     }
 
-    args.command.run()
+    let notify = notify::Targets {
+        desktop: args.notify,
+        webhook: args.webhook.clone(),
+        after: args.notify_after.map(Into::into),
+    };
+    let started = std::time::Instant::now();
+    let started_at = std::time::SystemTime::now();
+    let res = args.command.run();
+    if let Err(err) = &res {
+        hooks::on_failure(err);
+    }
+
+    // The subcommand, like `os switch`
+    let mut name = Vec::new();
+    let mut sub_matches = &matches;
+    while let Some((subcommand, next)) = sub_matches.subcommand() {
+        name.push(subcommand);
+        sub_matches = next;
+    }
+    let name = name.join(" ");
+
+    notify::finish(&notify, &name, &res, started.elapsed());
+    if let Err(err) = stats::record(&name, &res, started_at, started.elapsed()) {
+        tracing::warn!("Failed to save the run metrics: {err:#}");
+    }
+
+    if args.timings {
+        if let Err(err) = timings::report(&name) {
+            tracing::warn!("Failed to save timings: {err:#}");
+        }
+    }
+
+    res
 }
@@ -0,0 +1,220 @@
+//! The nix-index database behind `nh search --program`.
+//!
+//! The database maps the files in the binary cache to the packages providing
+//! them, which is how `--program` finds the package of a command. It lives in
+//! `$XDG_CACHE_HOME/nh/nix-index`, and is either downloaded prebuilt from
+//! nix-index-database, which is quick, or built locally with nix-index,
+//! which takes a while but indexes the nixpkgs in `NIX_PATH`.
+//!
+//! For refreshes from a timer, `nh search index update --max-age 7d` only
+//! downloads a database older than that. Searches refresh the database
+//! themselves with `--index-max-age`.
+
+use std::env;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use tracing::{debug, info};
+
+use crate::NH_VERSION;
+use crate::commands::Command;
+use crate::interface::IndexCommand;
+use crate::output;
+use crate::theme::{Role, paint};
+use crate::util::{self, format_bytes};
+
+const DOWNLOAD_URL: &str =
+    "https://github.com/nix-community/nix-index-database/releases/latest/download";
+
+/// Directory of the database, which nix-index and nix-locate call `--db`.
+pub fn db_dir() -> Result<PathBuf> {
+    let cache = match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir),
+        None => PathBuf::from(
+            env::var_os("HOME").ok_or_else(|| eyre!("Couldn't determine home directory"))?,
+        )
+        .join(".cache"),
+    };
+    Ok(cache.join("nh").join("nix-index"))
+}
+
+/// How long ago the database was built or downloaded, if it exists.
+fn age() -> Option<Duration> {
+    let modified = fs::metadata(db_dir().ok()?.join("files"))
+        .ok()?
+        .modified()
+        .ok()?;
+    Some(
+        SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default(),
+    )
+}
+
+/// `bin` of nix-index, from `$PATH` or else from nixpkgs.
+fn nix_index_command(bin: &str) -> Command {
+    if which::which(bin).is_ok() {
+        Command::new(bin)
+    } else {
+        debug!("{bin} isn't in $PATH, running it from nixpkgs");
+        Command::new("nix").args(["shell", "nixpkgs#nix-index", "--command", bin])
+    }
+}
+
+/// Build the database locally with nix-index.
+pub fn build() -> Result<()> {
+    let dir = db_dir()?;
+    fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+    nix_index_command("nix-index")
+        .arg("--db")
+        .arg(&dir)
+        .message("Building the nix-index database, this takes a while")
+        .show_output(true)
+        .with_required_env()
+        .run()
+}
+
+/// Download the prebuilt database, unless the current one is younger than
+/// `max_age`.
+pub fn update(max_age: Option<Duration>) -> Result<()> {
+    if let (Some(age), Some(max_age)) = (age(), max_age) {
+        if age < max_age {
+            info!(
+                "The nix-index database is {} old, not updating it",
+                humantime::format_duration(Duration::from_secs(age.as_secs()))
+            );
+            return Ok(());
+        }
+    }
+
+    let dir = db_dir()?;
+    fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+    let url = format!("{DOWNLOAD_URL}/index-{}", util::current_system());
+    debug!("Downloading {url}");
+
+    let mut response = reqwest::blocking::Client::new()
+        .get(&url)
+        .header("User-Agent", format!("nh/{NH_VERSION}"))
+        .send()
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .wrap_err_with(|| format!("Failed to download {url}"))?;
+    let total = response.content_length();
+
+    // Replaced at once, so a search never sees half a database
+    let mut file = tempfile::NamedTempFile::new_in(&dir)?;
+    let show_progress = output::human() && io::stderr().is_terminal();
+    let mut downloaded = 0;
+    let mut chunk = vec![0; 256 * 1024];
+    loop {
+        let n = match response.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err).wrap_err_with(|| format!("Failed to download {url}")),
+        };
+        file.write_all(&chunk[..n])?;
+        downloaded += n as u64;
+        if show_progress {
+            eprint!(
+                "\r{} Downloading the nix-index database: {}{}",
+                paint(">", Role::Info),
+                format_bytes(downloaded),
+                total
+                    .map(|total| format!(" of {}", format_bytes(total)))
+                    .unwrap_or_default()
+            );
+        }
+    }
+    if show_progress {
+        eprintln!();
+    }
+
+    let path = dir.join("files");
+    file.persist(&path)
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    info!(
+        "Downloaded the nix-index database ({}) to {}",
+        format_bytes(downloaded),
+        dir.display()
+    );
+    Ok(())
+}
+
+/// Print where the database is, and how old and big it is.
+pub fn status() -> Result<()> {
+    let dir = db_dir()?;
+    println!("Database: {}", paint(dir.display(), Role::Link));
+    match (age(), fs::metadata(dir.join("files"))) {
+        (Some(age), Ok(metadata)) => {
+            println!(
+                "Updated:  {} ago",
+                paint(
+                    humantime::format_duration(Duration::from_secs(age.as_secs())),
+                    Role::Value
+                )
+            );
+            println!(
+                "Size:     {}",
+                paint(format_bytes(metadata.len()), Role::Value)
+            );
+        }
+        _ => println!(
+            "Updated:  never, run {} or {}",
+            paint("nh search index update", Role::Literal),
+            paint("nh search index build", Role::Literal)
+        ),
+    }
+    println!(
+        "nix-locate: {}",
+        if which::which("nix-locate").is_ok() {
+            paint("in $PATH", Role::Success)
+        } else {
+            paint("run from nixpkgs", Role::Muted)
+        }
+    );
+    Ok(())
+}
+
+/// Attributes of the packages with `program` in their `bin` directory,
+/// refreshing the database first if it is older than `max_age`.
+pub fn locate_program(program: &str, max_age: Option<Duration>) -> Result<Vec<String>> {
+    match age() {
+        None => bail!(
+            "There is no nix-index database yet, download one with `nh search index update` or build one with `nh search index build`"
+        ),
+        Some(age) if max_age.is_some_and(|max_age| age >= max_age) => update(None)?,
+        Some(_) => {}
+    }
+
+    let output = nix_index_command("nix-locate")
+        .arg("--db")
+        .arg(db_dir()?)
+        .args(["--minimal", "--top-level", "--whole-name", "--at-root"])
+        .arg(format!("/bin/{program}"))
+        .with_required_env()
+        .run_capture()?
+        .unwrap_or_default();
+
+    let mut attrs: Vec<String> = output
+        .lines()
+        .map(|line| line.trim().trim_end_matches(".out").to_string())
+        .filter(|attr| !attr.is_empty())
+        .collect();
+    attrs.sort();
+    attrs.dedup();
+    Ok(attrs)
+}
+
+impl IndexCommand {
+    pub fn run(&self) -> Result<()> {
+        match self {
+            Self::Build => build(),
+            Self::Update(args) => update(args.max_age.map(Into::into)),
+            Self::Status => status(),
+        }
+    }
+}
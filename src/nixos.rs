@@ -1,25 +1,55 @@
+use std::collections::{BTreeSet, HashMap};
 use std::env;
 use std::fs;
+use std::io::{IsTerminal, Read, Seek, SeekFrom};
 use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
 
+use chrono::{DateTime, Local, Utc};
 use color_eyre::eyre::{Context, bail};
 use color_eyre::eyre::{Result, eyre};
+use subprocess::{Exec, ExitStatus, NullFile, Redirection};
 use tracing::{debug, info, warn};
 
+use crate::batch;
+use crate::boot;
+use crate::browse;
+use crate::checks;
 use crate::commands;
 use crate::commands::Command;
+use crate::confirm;
+use crate::diff;
+use crate::disko;
+use crate::events::{self, Event, Phase};
+use crate::exit;
+use crate::gcroots;
 use crate::generations;
+use crate::hooks::{self, Hook};
 use crate::installable::Installable;
 use crate::interface::OsSubcommand::{self};
 use crate::interface::{
-    self, DiffType, OsBuildVmArgs, OsGenerationsArgs, OsRebuildArgs, OsReplArgs, OsRollbackArgs,
+    self, DiffType, OsBuildVmArgs, OsDeployArgs, OsGenerationsArgs, OsInstallArgs, OsOptionArgs,
+    OsRebuildArgs, OsReplArgs, OsRollbackArgs,
 };
+use crate::inventory;
+use crate::json;
+use crate::options;
+use crate::output;
+use crate::probe;
+use crate::repl;
+use crate::secrets;
+use crate::spec::DeploySpec;
+use crate::theme::{Role, paint};
+use crate::transfer;
 use crate::update::update;
-use crate::util::ensure_ssh_key_login;
-use crate::util::{get_hostname, print_dix_diff};
+use crate::util::{self, ensure_ssh_key_login};
+use crate::util::{ensure_flake_configuration, get_hostname};
+use crate::vulns;
 
-const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
 const CURRENT_PROFILE: &str = "/run/current-system";
+const BOOTED_PROFILE: &str = "/run/booted-system";
 
 const SPEC_LOCATION: &str = "/etc/specialisation";
 
@@ -34,23 +64,57 @@ impl interface::OsArgs {
                 if args.common.ask || args.common.dry {
                     warn!("`--ask` and `--dry` have no effect for `nh os build`");
                 }
-                args.rebuild(&Build, None)
+                if args.hosts.is_empty() {
+                    args.rebuild(&Build, None)
+                } else {
+                    args.build_hosts()
+                }
             }
             OsSubcommand::BuildVm(args) => args.build_vm(),
+            OsSubcommand::Install(args) => args.install(),
+            OsSubcommand::Deploy(args) => args.deploy(),
             OsSubcommand::Repl(args) => args.run(),
+            OsSubcommand::Option(args) => args.run(),
             OsSubcommand::Info(args) => args.info(),
             OsSubcommand::Rollback(args) => args.rollback(),
+            OsSubcommand::Tag(args) => args.tag(),
+            OsSubcommand::History(args) => args.history(),
+            OsSubcommand::Export(args) => args.export(),
+            OsSubcommand::CheckBoot(args) => args.check_boot(),
+            OsSubcommand::Status(args) => args.status(),
+            OsSubcommand::Pin(args) => args.set_pinned(true),
+            OsSubcommand::Unpin(args) => args.set_pinned(false),
         }
     }
 }
 
 #[derive(Debug)]
-enum OsRebuildVariant {
+pub(crate) enum OsRebuildVariant {
     Build,
     Switch,
     Boot,
     Test,
     BuildVm,
+    /// Install into the file systems mounted at `root`, after partitioning
+    /// the disks with disko if `disko` is set
+    Install {
+        root: PathBuf,
+        disko: bool,
+        yes: bool,
+    },
+}
+
+impl OsRebuildVariant {
+    const fn name(&self) -> &'static str {
+        match self {
+            Self::Build => "build",
+            Self::Switch => "switch",
+            Self::Boot => "boot",
+            Self::Test => "test",
+            Self::BuildVm => "build-vm",
+            Self::Install { .. } => "install",
+        }
+    }
 }
 
 impl OsBuildVmArgs {
@@ -62,10 +126,164 @@ impl OsBuildVmArgs {
     }
 }
 
+impl OsInstallArgs {
+    fn install(self) -> Result<()> {
+        let variant = OsRebuildVariant::Install {
+            root: self.root,
+            disko: self.disko,
+            yes: self.yes,
+        };
+        self.common.rebuild(&variant, None)
+    }
+}
+
 impl OsRebuildArgs {
+    /// Use NH_OS_FLAKE if available, otherwise use the provided installable
+    fn installable(&self) -> Installable {
+        if self.spec.is_some() {
+            // The installable was already taken from the spec while parsing
+            self.common.installable.clone()
+        } else if let Ok(os_flake) = env::var("NH_OS_FLAKE") {
+            debug!("Using NH_OS_FLAKE: {}", os_flake);
+
+            let mut elems = os_flake.splitn(2, '#');
+            let reference = elems.next().unwrap().to_owned();
+            let attribute = elems
+                .next()
+                .map(crate::installable::parse_attribute)
+                .unwrap_or_default();
+
+            Installable::Flake {
+                reference,
+                attribute,
+            }
+        } else {
+            self.common.installable.clone()
+        }
+    }
+
+    /// Build every host given with `--hosts`
+    fn build_hosts(mut self) -> Result<()> {
+        self.apply_spec()?;
+
+        let _stash = self.common.prepare_worktree(&self.common.installable)?;
+
+        let pending_update = if self.update_args.enabled() {
+            events::phase(Phase::Update, || {
+                update(&self.common.installable, &self.update_args, true)
+            })?
+        } else {
+            None
+        };
+
+        let installable = self.common.pin_installable(self.installable())?;
+        let Installable::Flake {
+            reference,
+            attribute,
+        } = &installable
+        else {
+            bail!("--hosts requires a flake installable");
+        };
+
+        if attribute.is_empty() {
+            events::phase(Phase::Eval, || {
+                for host in &self.hosts {
+                    ensure_flake_configuration(
+                        reference,
+                        "nixosConfigurations",
+                        host,
+                        self.common.eval.generate_eval_args(),
+                    )?;
+                }
+                Ok(())
+            })?;
+        }
+
+        let targets = self
+            .hosts
+            .iter()
+            .map(|host| batch::Target {
+                name: host.clone(),
+                installable: os_toplevel_for(host, installable.clone(), "toplevel"),
+            })
+            .collect();
+
+        batch::build_all(
+            targets,
+            &batch::Options {
+                out_link_prefix: self
+                    .common
+                    .out_link
+                    .clone()
+                    .unwrap_or_else(|| PathBuf::from("result")),
+                parallel: self.parallel,
+                nom: !self.common.no_nom,
+                extra_args: &self.extra_args,
+                eval: &self.common.eval,
+                passthrough: &self.common.passthrough,
+                push_to: self
+                    .common
+                    .push_to
+                    .as_ref()
+                    .filter(|_| !self.common.dry)
+                    .map(|target| (target, self.common.push_key.as_deref())),
+            },
+        )?;
+
+        if let Some(update) = pending_update {
+            update.finish()?;
+        }
+
+        Ok(())
+    }
+
+    /// Fill in arguments not given on the command line from the deploy spec
+    fn apply_spec(&mut self) -> Result<()> {
+        let Some(path) = &self.spec else {
+            return Ok(());
+        };
+        let spec = DeploySpec::load(path)?;
+        debug!(?spec, "Using deploy spec");
+
+        self.hostname = self.hostname.take().or(spec.hostname);
+        self.target_host = self.target_host.take().or(spec.target_host);
+        self.build_host = self.build_host.take().or(spec.build_host);
+        if !self.no_specialisation {
+            self.specialisation = self.specialisation.take().or(spec.specialisation);
+        }
+        self.extra_args.splice(0..0, spec.extra_args);
+
+        Ok(())
+    }
+
+    /// Check for enough space on /nix before spending time on the build. The
+    /// boot partition is checked once the kernel is known, see
+    /// [`check_boot_space`].
+    fn check_free_space(&self) -> Result<()> {
+        // A --build-host only copies back the paths of the result that aren't
+        // here yet, usually little next to the running system, which the
+        // --min-free-space margin is for
+        self.common.check_free_space()
+    }
+
     // final_attr is the attribute of config.system.build.X to evaluate.
     fn rebuild(self, variant: &OsRebuildVariant, final_attr: Option<String>) -> Result<()> {
-        use OsRebuildVariant::{Boot, Build, BuildVm, Switch, Test};
+        let result = self.build_and_activate(variant, final_attr)?;
+        json::emit(&json::Output::Rebuild(result))
+    }
+
+    pub(crate) fn build_and_activate(
+        mut self,
+        variant: &OsRebuildVariant,
+        final_attr: Option<String>,
+    ) -> Result<json::RebuildResult> {
+        use OsRebuildVariant::{Boot, Build, BuildVm, Install, Switch, Test};
+
+        self.apply_spec()?;
+
+        if !self.hosts.is_empty() {
+            bail!("--hosts is only supported by `nh os build`");
+        }
 
         if self.build_host.is_some() || self.target_host.is_some() {
             // if it fails its okay
@@ -82,10 +300,34 @@ impl OsRebuildArgs {
             true
         };
 
-        if self.update_args.update_all || self.update_args.update_input.is_some() {
-            update(&self.common.installable, self.update_args.update_input)?;
+        self.check_free_space()?;
+        self.common.check_substituters();
+        // The build host has its own platforms and builders, which can't be
+        // judged from this machine
+        if self.build_host.is_none() {
+            self.common.check_system()?;
+        }
+
+        // Copying the result back from the build host imports unsigned paths
+        if let Some(option) = self
+            .build_host
+            .as_ref()
+            .map(|_| "--build-host")
+            .or_else(|| self.common.trusted_option())
+        {
+            checks::warn_if_untrusted(option);
         }
 
+        let _stash = self.common.prepare_worktree(&self.common.installable)?;
+
+        let pending_update = if self.update_args.enabled() {
+            events::phase(Phase::Update, || {
+                update(&self.common.installable, &self.update_args, true)
+            })?
+        } else {
+            None
+        };
+
         let system_hostname = match get_hostname() {
             Ok(hostname) => Some(hostname),
             Err(err) => {
@@ -116,39 +358,59 @@ impl OsRebuildArgs {
             },
         };
 
-        let out_path: Box<dyn crate::util::MaybeTempPath> = match self.common.out_link {
-            Some(ref p) => Box::new(p.clone()),
-            None => match variant {
-                BuildVm | Build => Box::new(PathBuf::from("result")),
-                _ => Box::new({
-                    let dir = tempfile::Builder::new().prefix("nh-os").tempdir()?;
-                    (dir.as_ref().join("result"), dir)
-                }),
-            },
+        hooks::start("nixos", variant.name(), Some(&target_hostname));
+        hooks::run(Hook::PreEval)?;
+
+        let results_name = format!("nixos-{target_hostname}");
+        let last_build = crate::results::latest(&results_name);
+        let keeps_result = self.common.out_link.is_some() || self.common.keep_results.is_some();
+        let out_path: Box<dyn crate::util::MaybeTempPath> = match variant {
+            BuildVm if self.common.out_link.is_none() => Box::new(PathBuf::from("result")),
+            Build if !keeps_result => Box::new(PathBuf::from("result")),
+            _ => self.common.result_link(&results_name, "nh-os")?,
         };
 
         debug!(?out_path);
 
-        // Use NH_OS_FLAKE if available, otherwise use the provided installable
-        let installable = if let Ok(os_flake) = env::var("NH_OS_FLAKE") {
-            debug!("Using NH_OS_FLAKE: {}", os_flake);
+        let installable = self.common.pin_installable(self.installable())?;
 
-            let mut elems = os_flake.splitn(2, '#');
-            let reference = elems.next().unwrap().to_owned();
-            let attribute = elems
-                .next()
-                .map(crate::installable::parse_attribute)
-                .unwrap_or_default();
+        let mut revision = None;
+        if let Installable::Flake {
+            reference,
+            attribute,
+        } = &installable
+        {
+            revision = match &self.common.rev {
+                Some(rev) => Some(generations::FlakeRevision {
+                    rev: rev.clone(),
+                    dirty: false,
+                }),
+                None => generations::flake_revision(reference),
+            };
 
-            Installable::Flake {
-                reference,
-                attribute,
+            if attribute.is_empty() {
+                events::phase(Phase::Eval, || {
+                    ensure_flake_configuration(
+                        reference,
+                        "nixosConfigurations",
+                        &target_hostname,
+                        self.common.eval.generate_eval_args(),
+                    )
+                })?;
             }
-        } else {
-            self.common.installable.clone()
-        };
+        }
+
+        let config = os_config_for(&target_hostname, installable.clone());
+        if self.check_secrets && !self.common.dry && matches!(variant, Switch | Boot | Test) {
+            secrets::preflight(
+                &config,
+                self.common.eval.generate_eval_args(),
+                self.target_host.as_deref(),
+                elevate,
+            )?;
+        }
 
-        let toplevel = toplevel_for(
+        let toplevel = os_toplevel_for(
             &target_hostname,
             installable,
             final_attr.unwrap_or(String::from("toplevel")).as_str(),
@@ -159,16 +421,63 @@ impl OsRebuildArgs {
             _ => "Building NixOS configuration",
         };
 
-        commands::Build::new(toplevel)
-            .extra_arg("--out-link")
-            .extra_arg(out_path.get_path())
-            .extra_args(&self.extra_args)
-            .passthrough(&self.common.passthrough)
-            .builder(self.build_host.clone())
-            .message(message)
-            .nom(!self.common.no_nom)
-            .run()
-            .wrap_err("Failed to build configuration")?;
+        let unchanged = events::phase(Phase::Build, || {
+            self.common.build_unless_unchanged(
+                &toplevel,
+                &self.extra_args,
+                out_path.get_path(),
+                || {
+                    commands::Build::new(toplevel.clone())
+                        .extra_arg("--out-link")
+                        .extra_arg(out_path.get_path())
+                        .extra_args(&self.extra_args)
+                        .eval_args(&self.common.eval)
+                        .passthrough(&self.common.passthrough)
+                        .builder(self.build_host.clone())
+                        .builders(&self.common.remote_builders())
+                        .message(message)
+                        .nom(!self.common.no_nom)
+                        .run()
+                        .wrap_err("Failed to build configuration")
+                },
+            )
+        })?;
+
+        if let Some(rev) = &self.common.rev {
+            info!("Built configuration at revision {rev}");
+        }
+
+        events::emit(&Event::BuildOutput {
+            out_path: out_path.get_path(),
+            rev: self.common.rev.as_deref(),
+        });
+
+        self.common.prune_results(&results_name)?;
+        self.common.push_closure(out_path.get_path())?;
+
+        hooks::set_out_path(out_path.get_path());
+        hooks::run(Hook::PostBuild)?;
+
+        let mut result = json::RebuildResult {
+            system: "nixos",
+            action: variant.name(),
+            hostname: Some(target_hostname.clone()),
+            out_path: fs::canonicalize(out_path.get_path())
+                .unwrap_or_else(|_| out_path.get_path().to_path_buf()),
+            revision: revision.as_ref().map(ToString::to_string),
+            dry: self.common.dry,
+            activated: false,
+            generation: None,
+            copy: None,
+            diff: None,
+        };
+
+        if let Some(revision) = revision {
+            debug!(?revision, "Recording flake revision");
+            if let Err(err) = generations::record_revision(out_path.get_path(), revision) {
+                warn!("Failed to record the flake revision: {err:#}");
+            }
+        }
 
         let current_specialisation = std::fs::read_to_string(SPEC_LOCATION).ok();
 
@@ -202,7 +511,23 @@ impl OsRebuildArgs {
             ));
         }
 
-        if system_hostname.is_none_or(|h| h == target_hostname) {
+        // A machine being installed has no current system to compare to
+        let same_host = system_hostname.is_none_or(|h| h == target_hostname)
+            && !matches!(variant, Install { .. });
+
+        if unchanged
+            && same_host
+            && self.target_host.is_none()
+            && is_up_to_date(variant, &result.out_path, &target_profile)
+        {
+            info!("{target_hostname} is already up to date");
+            if let Some(update) = pending_update {
+                update.finish()?;
+            }
+            return Ok(result);
+        }
+
+        if same_host {
             debug!(
                 "Comparing with target profile: {}",
                 target_profile.display()
@@ -213,44 +538,156 @@ impl OsRebuildArgs {
                 DiffType::Never => {}
                 DiffType::Auto => {
                     if self.target_host.is_none() && self.build_host.is_none() {
-                        let _ = print_dix_diff(&PathBuf::from(CURRENT_PROFILE), &target_profile);
+                        let base = self
+                            .common
+                            .diff_base(PathBuf::from(CURRENT_PROFILE), last_build);
+                        result.diff = diff::rebuild_diff(&base, &target_profile);
                     }
                 }
                 DiffType::Always => {
-                    let _ = print_dix_diff(&PathBuf::from(CURRENT_PROFILE), &target_profile);
+                    let base = self
+                        .common
+                        .diff_base(PathBuf::from(CURRENT_PROFILE), last_build);
+                    result.diff = diff::rebuild_diff(&base, &target_profile);
                 }
             }
         } else {
-            debug!("Not running dix as the target hostname is different from the system hostname.");
+            debug!(
+                "Not showing a diff as the target hostname is different from the system hostname."
+            );
+        }
+
+        if self.vuln_scan || self.fail_on_vuln {
+            let current = PathBuf::from(CURRENT_PROFILE);
+            let is_local = same_host && self.target_host.is_none();
+            let report = vulns::check(is_local.then_some(current.as_path()), &target_profile)?;
+
+            if self.fail_on_vuln && !report.introduced.is_empty() {
+                bail!(
+                    "The new configuration introduces {} vulnerabilities",
+                    report.introduced.len()
+                );
+            }
         }
 
         if self.common.dry || matches!(variant, Build | BuildVm) {
             if self.common.ask {
                 warn!("--ask has no effect as dry run was requested");
             }
-            return Ok(());
+            if let Some(update) = pending_update {
+                update.finish()?;
+            }
+            return Ok(result);
+        }
+
+        if let Some(target_host) = &self.target_host {
+            probe::check_nixos_target(target_host, &target_profile)?;
         }
 
         if self.common.ask {
-            info!("Apply the config?");
-            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
+            let mut notes = Vec::new();
+            if same_host && self.target_host.is_none() {
+                if self.dry_activate && matches!(variant, Test | Switch) {
+                    let output = Command::new(target_profile.join("bin/switch-to-configuration"))
+                        .arg("dry-activate")
+                        .elevate(elevate)
+                        .with_required_env()
+                        // The units are listed on stderr
+                        .merge_stderr(true)
+                        .run_capture()
+                        .wrap_err("Dry activation failed")?;
+                    notes.extend(confirm::unit_notes(&output.unwrap_or_default()));
+                }
+                if matches!(variant, Switch | Boot) {
+                    notes.extend(confirm::reboot_note(
+                        Path::new(BOOTED_PROFILE),
+                        &target_profile,
+                    ));
+                }
+            }
 
-            if !confirmation {
-                bail!("User rejected the new config");
+            if !confirm::ask("Apply the config?", &notes)? {
+                return Err(exit::declined("User rejected the new config"));
             }
         }
 
-        if let Some(target_host) = &self.target_host {
-            Command::new("nix")
-                .args([
-                    "copy",
-                    "--to",
-                    format!("ssh://{target_host}").as_str(),
-                    target_profile.to_str().unwrap(),
-                ])
-                .message("Copying configuration to target")
-                .with_required_env()
-                .run()?;
+        self.common
+            .verify_signatures(&target_profile, self.target_host.is_some())?;
+
+        // The disks have to be mounted before the closure can be copied into
+        // the store on them
+        if let Install {
+            disko: true, yes, ..
+        } = variant
+        {
+            disko::run(
+                &config,
+                self.common.eval.generate_eval_args(),
+                self.target_host.as_deref(),
+                elevate,
+                *yes,
+            )?;
+        }
+
+        // Held until the end of the activation
+        let _remote_root = if let Some(target_host) = &self.target_host {
+            // An installed system's closure goes straight to its store, not
+            // to the one of the installer, which is usually small and gone
+            // after a reboot
+            let root = match variant {
+                Install { root, .. } => Some(root.as_path()),
+                _ => None,
+            };
+            let copy = || {
+                transfer::copy_to_host(
+                    target_host,
+                    &target_profile,
+                    &self.copy,
+                    self.common.eval.generate_eval_args(),
+                    root,
+                )
+            };
+            if root.is_some() {
+                result.copy = copy()?;
+                None
+            } else {
+                let (copy, root) = gcroots::copy_rooted(target_host, &target_profile, copy)?;
+                result.copy = copy;
+                root
+            }
+        } else {
+            None
+        };
+
+        // Before anything is activated or the profile moves, so that a full
+        // boot partition doesn't leave a running system without a boot entry
+        if self.target_host.is_none() && matches!(variant, Boot | Switch) {
+            check_boot_space(
+                out_path.get_path(),
+                self.min_free_boot.unwrap_or(checks::MIN_FREE_BOOT),
+                self.common.ask,
+                elevate,
+            )?;
+        }
+
+        hooks::run(Hook::PreActivate)?;
+
+        if let Install { root, .. } = variant {
+            events::activation(Phase::Activation, "install", || {
+                Command::new("nixos-install")
+                    .arg("--system")
+                    .arg(&target_profile)
+                    .arg("--root")
+                    .arg(root)
+                    .args(["--no-root-passwd", "--no-channel-copy"])
+                    .ssh(self.target_host.clone())
+                    .elevate(elevate)
+                    .message("Installing NixOS")
+                    .show_output(true)
+                    .with_required_env()
+                    .run()
+                    .wrap_err("Installation failed")
+            })?;
         }
 
         if let Test | Switch = variant {
@@ -278,15 +715,18 @@ impl OsRebuildArgs {
                 .to_str()
                 .ok_or_else(|| eyre!("switch-to-configuration path contains invalid UTF-8"))?;
 
-            Command::new(switch_to_configuration)
-                .arg("test")
-                .ssh(self.target_host.clone())
-                .message("Activating configuration")
-                .elevate(elevate)
-                .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
-                .with_required_env()
-                .run()
-                .wrap_err("Activation (test) failed")?;
+            events::activation(Phase::Activation, "test", || {
+                Command::new(switch_to_configuration)
+                    .arg("test")
+                    .ssh(self.target_host.clone())
+                    .message("Activating configuration")
+                    .elevate(elevate)
+                    .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
+                    .with_required_env()
+                    .run()
+                    .wrap_err("Activation (test) failed")
+            })
+            .or_else(|err| self.recover(err, CURRENT_PROFILE, &target_profile))?;
         }
 
         if let Boot | Switch = variant {
@@ -304,6 +744,12 @@ impl OsRebuildArgs {
                 .run()
                 .wrap_err("Failed to set system profile")?;
 
+            if self.target_host.is_none() {
+                result.generation = fs::read_link(SYSTEM_PROFILE)
+                    .ok()
+                    .and_then(|link| generations::from_dir(&link));
+            }
+
             let switch_to_configuration = out_path
                 .get_path()
                 .join("bin")
@@ -326,15 +772,18 @@ impl OsRebuildArgs {
                 .to_str()
                 .ok_or_else(|| eyre!("switch-to-configuration path contains invalid UTF-8"))?;
 
-            Command::new(switch_to_configuration)
-                .arg("boot")
-                .ssh(self.target_host)
-                .elevate(elevate)
-                .message("Adding configuration to bootloader")
-                .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
-                .with_required_env()
-                .run()
-                .wrap_err("Bootloader activation failed")?;
+            events::activation(Phase::Bootloader, "boot", || {
+                Command::new(switch_to_configuration)
+                    .arg("boot")
+                    .ssh(self.target_host.clone())
+                    .elevate(elevate)
+                    .message("Adding configuration to bootloader")
+                    .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
+                    .with_required_env()
+                    .run()
+                    .wrap_err("Bootloader activation failed")
+            })
+            .or_else(|err| self.recover(err, SYSTEM_PROFILE, &canonical_out_path))?;
         }
 
         // Make sure out_path is not accidentally dropped
@@ -345,10 +794,301 @@ impl OsRebuildArgs {
         );
         drop(out_path);
 
-        Ok(())
+        if let Some(update) = pending_update {
+            update.finish()?;
+        }
+
+        hooks::set_generation(result.generation);
+        hooks::run(Hook::PostActivate)?;
+
+        result.activated = true;
+        Ok(result)
+    }
+}
+
+impl OsRebuildArgs {
+    /// With `--wait-online`, wait for the target host to come back after
+    /// the ssh connection dropped while activating on it with `err`, and let
+    /// the rebuild go on if `link` points to `expected` there, as the
+    /// activation did its job. Activations that failed on the host keep
+    /// their error, even though the link may already have moved.
+    fn recover(&self, err: color_eyre::Report, link: &str, expected: &Path) -> Result<()> {
+        let (Some(target_host), Some(timeout)) = (&self.target_host, self.wait_online) else {
+            return Err(err);
+        };
+        if !connection_lost(&err) {
+            return Err(err);
+        }
+
+        warn!("{err:#}");
+        wait_online(target_host, timeout.into())?;
+
+        let expected = expected
+            .canonicalize()
+            .unwrap_or_else(|_| expected.to_path_buf());
+        match remote_path(target_host, link) {
+            Some(path) if path == expected => {
+                info!("{target_host} is back, {link} is the new configuration");
+                Ok(())
+            }
+            Some(path) => Err(err.wrap_err(format!(
+                "{target_host} is back, but {link} is {} instead of the new configuration",
+                path.display()
+            ))),
+            None => Err(err.wrap_err(format!(
+                "{target_host} is back, but {link} couldn't be read"
+            ))),
+        }
+    }
+}
+
+/// Delay between attempts to reach a host that went offline
+const WAIT_ONLINE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// How long one attempt to reach a host that went offline may take
+const WAIT_ONLINE_CONNECT_TIMEOUT: &str = "ConnectTimeout=10";
+
+/// Whether `err` is ssh failing with 255, its exit code for a connection
+/// that couldn't be made or was lost, rather than the remote command.
+fn connection_lost(err: &color_eyre::Report) -> bool {
+    err.chain()
+        .filter_map(|cause| cause.downcast_ref::<commands::ExitError>())
+        .any(|exit| exit.status() == ExitStatus::Exited(255))
+}
+
+/// Poll `target_host` over ssh until it answers, for at most `timeout`.
+fn wait_online(target_host: &str, timeout: Duration) -> Result<()> {
+    info!(
+        "Waiting up to {} for {target_host} to come back",
+        humantime::format_duration(timeout)
+    );
+    let start = Instant::now();
+    loop {
+        let reachable = commands::ssh_cmd_with(target_host, &["-o", WAIT_ONLINE_CONNECT_TIMEOUT])
+            .arg("true")
+            .stdin(NullFile)
+            .stdout(NullFile)
+            .stderr(NullFile)
+            .join()
+            .is_ok_and(|status| status.success());
+        if reachable {
+            return Ok(());
+        }
+        if start.elapsed() >= timeout {
+            bail!(
+                "{target_host} didn't come back within {}",
+                humantime::format_duration(timeout)
+            );
+        }
+        thread::sleep(WAIT_ONLINE_INTERVAL);
+    }
+}
+
+/// The store path `link` resolves to on `target_host`, if it could be read.
+fn remote_path(target_host: &str, link: &str) -> Option<PathBuf> {
+    commands::ssh_cmd(target_host)
+        .args(&["readlink", "-f", link])
+        .stdin(NullFile)
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .capture()
+        .ok()
+        .filter(|capture| capture.exit_status.success())
+        .map(|capture| capture.stdout_str().trim().to_string())
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+}
+
+impl OsDeployArgs {
+    /// Deploy the selected hosts of the inventory wave by wave, stopping
+    /// after the first wave with a host that fails
+    fn deploy(self) -> Result<()> {
+        let variant = if self.boot {
+            OsRebuildVariant::Boot
+        } else {
+            OsRebuildVariant::Switch
+        };
+        let config = crate::config::get();
+        let hosts = inventory::select(&config.inventory, &self.tags, &self.hosts, self.all)?;
+        let mut rollout = inventory::Rollout::of_groups(&config.rollout, &self.tags, self.all);
+        if let Some(max_parallel) = self.max_parallel {
+            rollout.max_parallel = Some(max_parallel as usize);
+        }
+        rollout.canaries.extend(self.canaries.iter().cloned());
+        if self.common.ask && rollout.max_parallel.is_some_and(|n| n > 1) {
+            bail!("--ask can't be combined with deploying several hosts at the same time");
+        }
+
+        for (name, host) in &hosts {
+            if let Some(options) = &host.ssh_options {
+                commands::set_host_ssh_options(host.target_host(name), options);
+            }
+        }
+        let waves = inventory::waves(hosts, &rollout)?;
+
+        let mut outcomes = Vec::new();
+        let mut failure = None;
+        for wave in &waves {
+            if failure.is_some() {
+                outcomes.extend(wave.iter().map(|(name, _)| json::HostDeploy {
+                    name: (*name).to_string(),
+                    status: "skipped",
+                    error: None,
+                    result: None,
+                    generation: None,
+                }));
+                continue;
+            }
+
+            let results: Vec<Result<json::RebuildResult>> = if let [(name, host)] = wave[..] {
+                vec![self.deploy_host(name, host, &variant, true)]
+            } else {
+                if !self.bypass_root_check {
+                    commands::ask_remote_sudo_passwords(
+                        wave.iter().map(|(name, host)| host.target_host(name)),
+                        self.common.dry,
+                    )?;
+                }
+                info!(
+                    "Deploying {}",
+                    wave.iter()
+                        .map(|(name, _)| *name)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                );
+                thread::scope(|scope| {
+                    let handles: Vec<_> = wave
+                        .iter()
+                        .map(|(name, host)| {
+                            scope.spawn(|| self.deploy_host(name, host, &variant, false))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|handle| {
+                            handle
+                                .join()
+                                .unwrap_or_else(|_| Err(eyre!("Deploying panicked")))
+                        })
+                        .collect()
+                })
+            };
+
+            for ((name, _), result) in wave.iter().zip(results) {
+                match result {
+                    Ok(result) => outcomes.push(json::HostDeploy {
+                        name: (*name).to_string(),
+                        status: "deployed",
+                        error: None,
+                        result: Some(result),
+                        generation: None,
+                    }),
+                    Err(err) => {
+                        outcomes.push(json::HostDeploy {
+                            name: (*name).to_string(),
+                            status: "failed",
+                            error: err
+                                .root_cause()
+                                .to_string()
+                                .lines()
+                                .next()
+                                .map(str::to_string),
+                            result: None,
+                            generation: None,
+                        });
+                        failure.get_or_insert(err);
+                    }
+                }
+            }
+        }
+
+        // Which generation every host ended up on, so a halted rollout
+        // shows which hosts have the new configuration
+        if !self.common.dry {
+            for (outcome, (name, host)) in outcomes.iter_mut().zip(waves.iter().flatten()) {
+                outcome.generation = remote_generation(host.target_host(name));
+            }
+        }
+
+        if output::human() {
+            print!("{}", inventory::format_report(&outcomes));
+        }
+        json::emit(&json::Output::Deploy(outcomes))?;
+
+        failure.map_or(Ok(()), Err)
+    }
+
+    /// Build and activate the configuration of the host `name`, and check
+    /// its health. nom and diffs only work with one host deployed at a time,
+    /// as the output of several would interleave.
+    ///
+    /// The `on-failure` hooks of the host run here, with its own context,
+    /// rather than once for the whole deploy.
+    fn deploy_host(
+        &self,
+        name: &str,
+        host: &inventory::InventoryHost,
+        variant: &OsRebuildVariant,
+        nom: bool,
+    ) -> Result<json::RebuildResult> {
+        info!("Deploying {name}");
+        let target_host = host.target_host(name);
+        let mut common = self.common.clone();
+        common.no_nom |= !nom;
+        if !nom {
+            common.diff = DiffType::Never;
+        }
+        let args = OsRebuildArgs {
+            common,
+            update_args: interface::UpdateArgs::default(),
+            hostname: Some(host.hostname(name).to_string()),
+            hosts: vec![],
+            parallel: 1,
+            specialisation: None,
+            no_specialisation: false,
+            extra_args: self.extra_args.clone(),
+            bypass_root_check: self.bypass_root_check,
+            target_host: Some(target_host.to_string()),
+            build_host: host.build_host.clone(),
+            copy: self.copy.clone(),
+            wait_online: self.wait_online,
+            min_free_boot: None,
+            check_secrets: self.check_secrets,
+            dry_activate: false,
+            spec: None,
+            vuln_scan: false,
+            fail_on_vuln: false,
+        };
+
+        let result = args
+            .build_and_activate(variant, None)
+            .and_then(|result| {
+                if result.activated && !self.boot {
+                    inventory::health_check(name, host, target_host)?;
+                }
+                Ok(result)
+            })
+            .wrap_err(format!("Failed to deploy {name}"));
+        if let Err(err) = &result {
+            hooks::on_failure(err);
+        }
+        hooks::finish();
+        result
     }
 }
 
+/// The generation the system profile of `target_host` points to, if it can
+/// be found out.
+fn remote_generation(target_host: &str) -> Option<u64> {
+    let link = Command::new("readlink")
+        .arg(SYSTEM_PROFILE)
+        .ssh(Some(target_host.to_string()))
+        .run_capture()
+        .inspect_err(|err| debug!("Couldn't read the generation of {target_host}: {err:#}"))
+        .ok()??;
+    generations::from_dir(Path::new(link.trim()))
+}
+
 impl OsRollbackArgs {
     fn rollback(&self) -> Result<()> {
         let elevate = if self.bypass_root_check {
@@ -362,19 +1102,51 @@ impl OsRollbackArgs {
         };
 
         // Find previous generation or specific generation
-        let target_generation = if let Some(gen_number) = self.to {
-            find_generation_by_number(gen_number)?
+        let generations = generations::GenerationSet::scan(&self.profile)?;
+        let target_generation = find_target_generation(
+            &generations,
+            self.to,
+            rollback_cutoff(self.to_date, self.before),
+        )?;
+
+        let number: u64 = target_generation.number.parse().wrap_err(format!(
+            "Invalid generation number {}",
+            target_generation.number
+        ))?;
+        let generation_link = generations::generation_link(&self.profile, number);
+
+        if self.diff_only {
+            if json::output_enabled() {
+                return json::emit(&json::Output::Diff(json::DiffResult {
+                    generation: number,
+                    diff: diff::builtin_diff(Path::new(CURRENT_PROFILE), &generation_link)?,
+                    path: generation_link,
+                }));
+            }
+            info!(
+                "Changes from rolling back to generation {}",
+                target_generation.number
+            );
+            return diff::print_diff(Path::new(CURRENT_PROFILE), &generation_link);
+        }
+
+        let details: Vec<String> = target_generation
+            .label
+            .iter()
+            .cloned()
+            .chain(
+                target_generation
+                    .flake_revision
+                    .as_ref()
+                    .map(|revision| format!("rev {revision}")),
+            )
+            .collect();
+        let target_description = if details.is_empty() {
+            target_generation.number.clone()
         } else {
-            find_previous_generation()?
+            format!("{} ({})", target_generation.number, details.join(", "))
         };
-
-        info!("Rolling back to generation {}", target_generation.number);
-
-        // Construct path to the generation
-        let profile_dir = Path::new(SYSTEM_PROFILE)
-            .parent()
-            .unwrap_or(Path::new("/nix/var/nix/profiles"));
-        let generation_link = profile_dir.join(format!("system-{}-link", target_generation.number));
+        info!("Rolling back to generation {target_description}");
 
         // Handle specialisations
         let current_specialisation = fs::read_to_string(SPEC_LOCATION).ok();
@@ -387,11 +1159,18 @@ impl OsRollbackArgs {
 
         debug!("target_specialisation: {target_specialisation:?}");
 
+        let result = json::RollbackResult {
+            generation: number,
+            path: generation_link.clone(),
+            specialisation: target_specialisation.clone(),
+            dry: self.dry,
+        };
+
         // Compare changes between current and target generation
         match self.diff {
             DiffType::Never => {}
             _ => {
-                let _ = print_dix_diff(&PathBuf::from(CURRENT_PROFILE), &generation_link);
+                let _ = diff::print_diff(&PathBuf::from(CURRENT_PROFILE), &generation_link);
             }
         }
 
@@ -400,23 +1179,24 @@ impl OsRollbackArgs {
                 "Dry run: would roll back to generation {}",
                 target_generation.number
             );
-            return Ok(());
+            return json::emit(&json::Output::Rollback(result));
         }
 
         if self.ask {
-            info!("Roll back to generation {}?", target_generation.number);
-            let confirmation = dialoguer::Confirm::new().default(false).interact()?;
-
-            if !confirmation {
-                bail!("User rejected the rollback");
+            let question = format!("Roll back to generation {target_description}?");
+            if !confirm::ask(&question, &[])? {
+                return Err(exit::declined("User rejected the rollback"));
             }
         }
 
         // Get current generation number for potential rollback
-        let current_gen_number = match get_current_generation_number() {
-            Ok(num) => num,
-            Err(e) => {
-                warn!("Failed to get current generation number: {}", e);
+        let current_gen_number = match generations
+            .current()
+            .and_then(|generation| generation.number.parse::<u64>().ok())
+        {
+            Some(num) => num,
+            None => {
+                warn!("Failed to get current generation number");
                 0
             }
         };
@@ -428,7 +1208,7 @@ impl OsRollbackArgs {
         Command::new("ln")
             .arg("-sfn") // force, symbolic link
             .arg(&generation_link)
-            .arg(SYSTEM_PROFILE)
+            .arg(&self.profile)
             .elevate(elevate)
             .message("Setting system profile")
             .with_required_env()
@@ -468,13 +1248,14 @@ impl OsRollbackArgs {
             ));
         }
 
-        match Command::new(&switch_to_configuration)
-            .arg("switch")
-            .elevate(elevate)
-            .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
-            .with_required_env()
-            .run()
-        {
+        match events::activation(Phase::Activation, "switch", || {
+            Command::new(&switch_to_configuration)
+                .arg("switch")
+                .elevate(elevate)
+                .preserve_envs(["NIXOS_INSTALL_BOOTLOADER"])
+                .with_required_env()
+                .run()
+        }) {
             Ok(()) => {
                 info!(
                     "Successfully rolled back to generation {}",
@@ -485,12 +1266,12 @@ impl OsRollbackArgs {
                 // If activation fails, rollback the profile
                 if current_gen_number > 0 {
                     let current_gen_link =
-                        profile_dir.join(format!("system-{current_gen_number}-link"));
+                        generations::generation_link(&self.profile, current_gen_number);
 
                     Command::new("ln")
                         .arg("-sfn") // Force, symbolic link
                         .arg(&current_gen_link)
-                        .arg(SYSTEM_PROFILE)
+                        .arg(&self.profile)
                         .elevate(elevate)
                         .message("Rolling back system profile")
                         .with_required_env()
@@ -503,107 +1284,84 @@ impl OsRollbackArgs {
             }
         }
 
-        Ok(())
+        json::emit(&json::Output::Rollback(result))
     }
 }
 
-fn find_previous_generation() -> Result<generations::GenerationInfo> {
-    let profile_path = PathBuf::from(SYSTEM_PROFILE);
+/// Whether `out_path` already is what `variant` would activate: the running
+/// system for switch and test, and the system booted by default for switch
+/// and boot. The running system is compared with `target_profile`, the
+/// specialisation that gets activated, if any.
+fn is_up_to_date(variant: &OsRebuildVariant, out_path: &Path, target_profile: &Path) -> bool {
+    let points_to = |link: &str, path: &Path| {
+        fs::canonicalize(link)
+            .is_ok_and(|link| fs::canonicalize(path).is_ok_and(|path| link == path))
+    };
+    let running = || points_to(CURRENT_PROFILE, target_profile);
+    let default = || points_to(SYSTEM_PROFILE, out_path);
+
+    match variant {
+        OsRebuildVariant::Switch => running() && default(),
+        OsRebuildVariant::Boot => default(),
+        OsRebuildVariant::Test => running(),
+        _ => false,
+    }
+}
 
-    let mut generations: Vec<generations::GenerationInfo> = fs::read_dir(
-        profile_path
-            .parent()
-            .unwrap_or(Path::new("/nix/var/nix/profiles")),
-    )?
-    .filter_map(|entry| {
-        entry.ok().and_then(|e| {
-            let path = e.path();
-            if let Some(filename) = path.file_name() {
-                if let Some(name) = filename.to_str() {
-                    if name.starts_with("system-") && name.ends_with("-link") {
-                        return generations::describe(&path);
-                    }
-                }
-            }
-            None
-        })
+/// The cutoff of `--to-date` or `--before`, if either was given.
+#[must_use]
+pub fn rollback_cutoff(
+    to_date: Option<DateTime<Utc>>,
+    before: Option<humantime::Duration>,
+) -> Option<DateTime<Utc>> {
+    to_date.or_else(|| {
+        before.map(|before| Utc::now() - chrono::Duration::from_std(*before).unwrap_or_default())
     })
-    .collect();
+}
 
+/// The generation to roll back to: generation `to` if given, otherwise the
+/// newest one built before `cutoff`, or else the one before the current
+/// generation.
+pub fn find_target_generation(
+    generations: &generations::GenerationSet,
+    to: Option<u64>,
+    cutoff: Option<DateTime<Utc>>,
+) -> Result<generations::GenerationInfo> {
     if generations.is_empty() {
         bail!("No generations found");
     }
 
-    generations.sort_by(|a, b| {
-        a.number
-            .parse::<u64>()
-            .unwrap_or(0)
-            .cmp(&b.number.parse::<u64>().unwrap_or(0))
-    });
-
-    let current_idx = generations
-        .iter()
-        .position(|g| g.current)
-        .ok_or_else(|| eyre!("Current generation not found"))?;
-
-    if current_idx == 0 {
-        bail!("No generation older than the current one exists");
-    }
-
-    Ok(generations[current_idx - 1].clone())
-}
-
-fn find_generation_by_number(number: u64) -> Result<generations::GenerationInfo> {
-    let profile_path = PathBuf::from(SYSTEM_PROFILE);
-
-    let generations: Vec<generations::GenerationInfo> = fs::read_dir(
-        profile_path
-            .parent()
-            .unwrap_or(Path::new("/nix/var/nix/profiles")),
-    )?
-    .filter_map(|entry| {
-        entry.ok().and_then(|e| {
-            let path = e.path();
-            if let Some(filename) = path.file_name() {
-                if let Some(name) = filename.to_str() {
-                    if name.starts_with("system-") && name.ends_with("-link") {
-                        return generations::describe(&path);
-                    }
-                }
+    let generation = match (to, cutoff) {
+        (Some(number), _) => generations
+            .get(number)
+            .ok_or_else(|| eyre!("Generation {} not found", number))?,
+        (None, Some(cutoff)) => {
+            let generation = generations.newest_before(cutoff).ok_or_else(|| {
+                eyre!(
+                    "No generation was built before {}",
+                    cutoff.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+                )
+            })?;
+            if generation.current {
+                bail!(
+                    "Generation {}, the current one, is already the newest built before {}",
+                    generation.number,
+                    cutoff.with_timezone(&Local).format("%Y-%m-%d %H:%M:%S")
+                );
             }
-            None
-        })
-    })
-    .filter(|generation| generation.number == number.to_string())
-    .collect();
-
-    if generations.is_empty() {
-        bail!("Generation {} not found", number);
-    }
-
-    Ok(generations[0].clone())
-}
-
-fn get_current_generation_number() -> Result<u64> {
-    let profile_path = PathBuf::from(SYSTEM_PROFILE);
-
-    let generations: Vec<generations::GenerationInfo> = fs::read_dir(
-        profile_path
-            .parent()
-            .unwrap_or(Path::new("/nix/var/nix/profiles")),
-    )?
-    .filter_map(|entry| entry.ok().and_then(|e| generations::describe(&e.path())))
-    .collect();
-
-    let current_gen = generations
-        .iter()
-        .find(|g| g.current)
-        .ok_or_else(|| eyre!("Current generation not found"))?;
+            generation
+        }
+        (None, None) => {
+            if generations.current().is_none() {
+                bail!("Current generation not found");
+            }
+            generations
+                .previous()
+                .ok_or_else(|| eyre!("No generation older than the current one exists"))?
+        }
+    };
 
-    current_gen
-        .number
-        .parse::<u64>()
-        .wrap_err("Invalid generation number")
+    Ok(generation.clone())
 }
 
 #[must_use]
@@ -657,6 +1415,103 @@ pub fn toplevel_for<S: AsRef<str>>(
     res
 }
 
+/// Like [`toplevel_for`], but also turns a file or expression without an
+/// attribute path into a `NixOS` system.
+///
+/// The value is used as is if it already is an evaluated system, like the
+/// result of `nixosSystem` or `import <nixpkgs/nixos>`. Otherwise its
+/// `<hostname>` attribute is used if that is a system, and failing that, it is
+/// treated as a `NixOS` module like `configuration.nix`.
+pub fn os_toplevel_for<S: AsRef<str>>(
+    hostname: S,
+    installable: Installable,
+    final_attr: &str,
+) -> Installable {
+    let installable = match installable {
+        Installable::File { path, attribute } if attribute.is_empty() => {
+            let path = std::path::absolute(&path).unwrap_or(path);
+            let path = format!("(/. + {})", nix_string(&path.to_string_lossy()));
+
+            Installable::Expression {
+                expression: nixos_system_expr(&format!("import {path}"), &path, hostname.as_ref()),
+                attribute,
+            }
+        }
+        Installable::Expression {
+            expression,
+            attribute,
+        } if attribute.is_empty() => Installable::Expression {
+            expression: nixos_system_expr(
+                &format!("({expression})"),
+                "imported",
+                hostname.as_ref(),
+            ),
+            attribute,
+        },
+        other => other,
+    };
+
+    toplevel_for(hostname, installable, final_attr)
+}
+
+/// The `config` of the system [`os_toplevel_for`] builds.
+pub fn os_config_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Installable {
+    let mut res = os_toplevel_for(hostname, installable, "toplevel");
+    if let Installable::Flake { attribute, .. }
+    | Installable::File { attribute, .. }
+    | Installable::Expression { attribute, .. } = &mut res
+    {
+        // Drop `system.build.toplevel`
+        attribute.truncate(attribute.len().saturating_sub(3));
+    }
+    res
+}
+
+/// The evaluated system [`os_toplevel_for`] builds, with its `config` and
+/// `options`.
+pub fn os_system_for<S: AsRef<str>>(hostname: S, installable: Installable) -> Installable {
+    let mut res = os_config_for(hostname, installable);
+    if let Installable::Flake { attribute, .. }
+    | Installable::File { attribute, .. }
+    | Installable::Expression { attribute, .. } = &mut res
+    {
+        attribute.pop();
+    }
+    res
+}
+
+/// Nix expression evaluating `value` to a `NixOS` system, see
+/// [`os_toplevel_for`]. `module` is what gets passed to `eval-config.nix` if
+/// the value turns out to be a module.
+fn nixos_system_expr(value: &str, module: &str, hostname: &str) -> String {
+    let hostname = nix_string(hostname);
+
+    format!(
+        r#"let
+  imported = {value};
+  # Files like default.nix may take arguments that all have defaults
+  value =
+    if builtins.isFunction imported
+      && builtins.all (x: x) (builtins.attrValues (builtins.functionArgs imported))
+    then imported {{ }}
+    else imported;
+  isSystem = x: builtins.isAttrs x && x ? config.system.build;
+in
+if isSystem value then value
+else if builtins.isAttrs value && isSystem (value.{hostname} or null) then value.{hostname}
+else import <nixpkgs/nixos/lib/eval-config.nix> {{ modules = [ {module} ]; }}"#
+    )
+}
+
+/// Quote a string for use in a Nix expression
+pub fn nix_string(s: &str) -> String {
+    let escaped = s
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${");
+    format!("\"{escaped}\"")
+}
+
 impl OsReplArgs {
     fn run(self) -> Result<()> {
         // Use NH_OS_FLAKE if available, otherwise use the provided installable
@@ -697,12 +1552,510 @@ impl OsReplArgs {
             }
         }
 
-        Command::new("nix")
-            .arg("repl")
-            .args(target_installable.to_args())
-            .with_required_env()
-            .show_output(true)
-            .run()?;
+        repl::run(&target_installable, &self.eval, &self.extra_args, self.bare)
+    }
+}
+
+impl OsOptionArgs {
+    fn run(self) -> Result<()> {
+        if matches!(
+            self.installable,
+            Installable::Store { .. } | Installable::System { .. }
+        ) {
+            bail!("Options can't be evaluated from a store path");
+        }
+
+        let hostname = self.hostname.ok_or(()).or_else(|()| get_hostname())?;
+
+        if let Installable::Flake {
+            reference,
+            attribute,
+        } = &self.installable
+        {
+            if attribute.is_empty() {
+                ensure_flake_configuration(
+                    reference,
+                    "nixosConfigurations",
+                    &hostname,
+                    self.eval.generate_eval_args(),
+                )?;
+            }
+        }
+
+        let system = os_system_for(&hostname, self.installable);
+        options::show(&system, &self.option, self.eval.generate_eval_args())
+    }
+}
+
+/// Resolve the generation of `profile` to operate on, defaulting to the
+/// current one, and make sure that it exists.
+fn resolve_generation(profile: &Path, generation: Option<u64>) -> Result<u64> {
+    let number = match generation {
+        Some(number) => number,
+        None => fs::read_link(profile)
+            .ok()
+            .as_deref()
+            .and_then(generations::from_dir)
+            .ok_or_else(|| {
+                eyre!(
+                    "Couldn't determine the current generation of {}",
+                    profile.display()
+                )
+            })?,
+    };
+
+    if !generations::generation_link(profile, number).is_symlink() {
+        bail!("Generation {number} not found");
+    }
+
+    Ok(number)
+}
+
+impl interface::OsHistoryArgs {
+    fn history(&self) -> Result<()> {
+        let links = generations::generation_links(&self.profile)?;
+        if links.is_empty() {
+            bail!("No generations found for {}", self.profile.display());
+        }
+
+        let mut previous: Option<BTreeSet<String>> = None;
+        let mut changes = 0;
+
+        for (number, link) in links {
+            let closure = Command::new("nix-store")
+                .args(["--query", "--requisites"])
+                .arg(&link)
+                .run_capture()
+                .wrap_err(format!("Failed to query closure of generation {number}"))?
+                .unwrap_or_default();
+            let versions = generations::package_versions(&closure, &self.package);
+            debug!(number, ?versions);
+
+            let before = previous.as_ref();
+            if before != Some(&versions) && !(before.is_none() && versions.is_empty()) {
+                let before = before.cloned().unwrap_or_default();
+                let added: Vec<_> = versions
+                    .difference(&before)
+                    .map(|v| version_or_unversioned(v))
+                    .collect();
+                let removed: Vec<_> = before
+                    .difference(&versions)
+                    .map(|v| version_or_unversioned(v))
+                    .collect();
+
+                let change = match (removed.is_empty(), added.is_empty()) {
+                    (true, _) => format!("added {}", added.join(", ")),
+                    (_, true) => format!("removed {}", removed.join(", ")),
+                    _ => format!("{} -> {}", removed.join(", "), added.join(", ")),
+                };
+                println!("Generation {number}: {} {change}", self.package);
+                changes += 1;
+            }
+
+            previous = Some(versions);
+        }
+
+        if changes == 0 {
+            info!("{} is not part of any generation", self.package);
+        }
+
+        Ok(())
+    }
+}
+
+fn version_or_unversioned(version: &str) -> &str {
+    if version.is_empty() {
+        "(unversioned)"
+    } else {
+        version
+    }
+}
+
+/// Destination of `nh os export`.
+#[derive(Debug, PartialEq, Eq)]
+enum ExportTarget {
+    /// A store URL understood by `nix copy --to`
+    Store(String),
+    /// A closure archive as written by `nix-store --export`
+    Archive(PathBuf),
+}
+
+impl ExportTarget {
+    fn parse(to: &str) -> Self {
+        // `file://` is a binary cache store, `file:` without slashes a file
+        match to.strip_prefix("file:") {
+            Some(path) if !path.starts_with("//") => Self::Archive(PathBuf::from(path)),
+            _ => Self::Store(to.to_string()),
+        }
+    }
+}
+
+impl interface::OsExportArgs {
+    fn export(&self) -> Result<()> {
+        let number = resolve_generation(&self.profile, Some(self.generation))?;
+        let link = generations::generation_link(&self.profile, number);
+        let out_path =
+            fs::canonicalize(&link).wrap_err(format!("Failed to resolve generation {number}"))?;
+
+        match ExportTarget::parse(&self.to) {
+            ExportTarget::Store(url) => {
+                if url.starts_with("ssh://") || url.starts_with("ssh-ng://") {
+                    // if it fails its okay
+                    let _ = ensure_ssh_key_login();
+                }
+
+                events::phase(Phase::Copy, || {
+                    Command::new("nix")
+                        .args(["copy", "--to", &url])
+                        .arg(&out_path)
+                        .message(format!("Copying generation {number} to {url}"))
+                        .progress(true)
+                        .with_required_env()
+                        .run()
+                })?;
+            }
+            ExportTarget::Archive(path) => {
+                let closure = Command::new("nix-store")
+                    .args(["--query", "--requisites"])
+                    .arg(&out_path)
+                    .run_capture()
+                    .wrap_err(format!("Failed to query closure of generation {number}"))?
+                    .unwrap_or_default();
+
+                // Read back to continue the archive between batches
+                let mut file = fs::OpenOptions::new()
+                    .read(true)
+                    .write(true)
+                    .create(true)
+                    .truncate(true)
+                    .open(&path)
+                    .wrap_err(format!("Failed to create {}", path.display()))?;
+
+                info!("Exporting generation {number} to {}", path.display());
+                let paths: Vec<&str> = closure.lines().collect();
+                if let Err(err) = export_paths(&paths, &mut file) {
+                    let _ = fs::remove_file(&path);
+                    return Err(err);
+                }
+            }
+        }
+
+        info!("Exported generation {number} ({})", out_path.display());
+        Ok(())
+    }
+}
+
+/// Paths passed to one `nix-store --export`, to stay well below the limit
+/// on the size of a command line
+const EXPORT_BATCH: usize = 1000;
+
+/// What `nix-store --export` ends its output with, after the last path
+const EXPORT_END: [u8; 8] = [0; 8];
+
+/// Export `paths`, with the references of each path before it like
+/// `nix-store --query --requisites` lists them, into a single archive in
+/// `file`. A closure can be too large for one command line, so the paths are
+/// exported in batches, each continuing the archive of the previous one.
+fn export_paths(paths: &[&str], file: &mut fs::File) -> Result<()> {
+    for (index, batch) in paths.chunks(EXPORT_BATCH).enumerate() {
+        if index > 0 {
+            continue_export(file)?;
+        }
+        let status = Exec::cmd("nix-store")
+            .arg("--export")
+            .args(batch)
+            .stdout(Redirection::File(file.try_clone()?))
+            .join()?;
+        if !status.success() {
+            bail!("nix-store --export failed with {status:?}");
+        }
+    }
+    Ok(())
+}
+
+/// Remove the end of the archive `nix-store --export` wrote to `file`, so
+/// that the next export appends to it.
+fn continue_export(file: &mut fs::File) -> Result<()> {
+    let len = file.metadata()?.len();
+    let mut end = [0u8; EXPORT_END.len()];
+    file.seek(SeekFrom::Start(len.saturating_sub(end.len() as u64)))?;
+    file.read_exact(&mut end)
+        .wrap_err("The exported archive is truncated")?;
+    if end != EXPORT_END {
+        bail!("The exported archive doesn't end like nix-store --export output");
+    }
+    file.set_len(len - end.len() as u64)?;
+    file.seek(SeekFrom::End(0))?;
+    Ok(())
+}
+
+impl interface::OsCheckBootArgs {
+    fn check_boot(&self) -> Result<()> {
+        let Some((bootloader, report)) = boot::check(&self.profile)? else {
+            bail!("No supported bootloader (systemd-boot or GRUB) found");
+        };
+
+        if !report.past_limit.is_empty() {
+            info!(
+                "Generations past the number of entries {} keeps: {:?}",
+                bootloader.name(),
+                report.past_limit
+            );
+        }
+        if report.is_consistent() {
+            info!("All kept generations have a {} entry", bootloader.name());
+            return Ok(());
+        }
+
+        for number in &report.missing {
+            println!("Generation {number} has no {} entry", bootloader.name());
+        }
+        for number in &report.orphaned {
+            println!(
+                "{} entry for generation {number} has no generation",
+                bootloader.name()
+            );
+        }
+
+        bail!(
+            "{} generations without boot entries, {} orphaned boot entries",
+            report.missing.len(),
+            report.orphaned.len()
+        );
+    }
+}
+
+/// Make sure the boot partition has room for the kernel and initrd of
+/// `system`, the next generation, and at least `min_free` in any case. If it
+/// doesn't, with `ask` the boot entries the bootloader would remove anyway
+/// can be deleted first.
+fn check_boot_space(system: &Path, min_free: u64, ask: bool, elevate: bool) -> Result<()> {
+    if checks::is_skipped(checks::SkippableCheck::BootSpace) {
+        return Ok(());
+    }
+    let Some(bootloader) = boot::Bootloader::detect() else {
+        return Ok(());
+    };
+    let partition = bootloader.partition();
+    let needed = boot::space_needed(&bootloader, system).max(min_free);
+    let Some(free) = util::free_space(partition) else {
+        return Ok(());
+    };
+    debug!(
+        ?partition,
+        free, needed, "Checking free space for boot entries"
+    );
+    if free >= needed {
+        return Ok(());
+    }
+
+    warn!(
+        "Only {} free on {}, but the new kernel and initrd need {} on it",
+        util::format_bytes(free),
+        partition.display(),
+        util::format_bytes(needed)
+    );
+
+    let generations: BTreeSet<u64> = generations::generation_links(Path::new(SYSTEM_PROFILE))?
+        .into_iter()
+        .map(|(number, _)| number)
+        .collect();
+    let generation = generations.last().map_or(1, |newest| newest + 1);
+    let removed = boot::removed_entries(&bootloader.entries()?, &generations, generation);
+    let prunable = boot::prunable(&bootloader, &removed)?;
+    let freed: u64 = prunable
+        .iter()
+        .filter_map(|file| fs::metadata(file).ok())
+        .map(|metadata| metadata.len())
+        .sum();
+
+    let skip =
+        "lower the threshold with --min-free-boot, or skip this check with --skip-check boot-space";
+    if prunable.is_empty() || free + freed < needed {
+        bail!(
+            "Installing the bootloader would likely fail, run `nh clean` or remove old entries from {} first, {skip}",
+            partition.display()
+        );
+    }
+    if !ask {
+        bail!(
+            "Installing the bootloader would likely fail, pass --ask to remove the boot entries of generations {removed:?} first, {skip}"
+        );
+    }
+
+    let question = format!(
+        "Remove the boot entries of generations {removed:?} first, freeing {}?",
+        util::format_bytes(freed)
+    );
+    if !confirm::ask_or_decline(&question, &[])? {
+        return Err(exit::declined("User kept the old boot entries"));
+    }
+    Command::new("rm")
+        .arg("-f")
+        .args(&prunable)
+        .elevate(elevate)
+        .message("Removing old boot entries")
+        .run()
+        .wrap_err("Failed to remove old boot entries")
+}
+
+/// Warn about generations that can't be booted, without failing if the boot
+/// entries can't be inspected.
+fn warn_boot_inconsistencies(profile: &Path) {
+    match boot::check(profile) {
+        Ok(Some((bootloader, report))) if !report.is_consistent() => {
+            if !report.missing.is_empty() {
+                warn!(
+                    "Generations without a {} entry: {:?}",
+                    bootloader.name(),
+                    report.missing
+                );
+            }
+            if !report.orphaned.is_empty() {
+                warn!(
+                    "{} entries without a generation: {:?}",
+                    bootloader.name(),
+                    report.orphaned
+                );
+            }
+            warn!("Run `nh os check-boot` for details");
+        }
+        Ok(_) => {}
+        Err(err) => debug!("Couldn't check boot entries: {err:#}"),
+    }
+}
+
+impl interface::OsStatusArgs {
+    fn status(&self) -> Result<()> {
+        let status = system_status(&self.profile)?;
+
+        if !json::output_enabled() && !output::quiet() {
+            let describe = |state: &Option<json::SystemState>| match state {
+                Some(json::SystemState {
+                    path,
+                    generation: Some(number),
+                }) => format!("generation {number} ({})", path.display()),
+                Some(json::SystemState { path, .. }) => path.display().to_string(),
+                None => "unknown".to_string(),
+            };
+            println!("Booted:  {}", describe(&status.booted));
+            println!("Running: {}", describe(&status.running));
+            println!("Default: {}", describe(&status.default));
+            if status.newest.is_some() {
+                println!("Newest:  {}", describe(&status.newest));
+            }
+            for note in &status.notes {
+                warn!("{note}");
+            }
+            return Ok(());
+        }
+
+        json::emit(&json::Output::Status(status))
+    }
+}
+
+/// Where the booted and running systems and the boot default of `profile`
+/// are, and how they differ.
+fn system_status(profile: &Path) -> Result<json::SystemStatus> {
+    let links = generations::generation_links(profile)?;
+    let state = |path: &Path| {
+        let path = path.canonicalize().ok()?;
+        // Several generations can have the same system, the newest one counts
+        let generation = links
+            .iter()
+            .rev()
+            .find(|(_, link)| link.canonicalize().ok().as_ref() == Some(&path))
+            .map(|(number, _)| *number);
+        Some(json::SystemState { path, generation })
+    };
+
+    let booted = state(Path::new(BOOTED_PROFILE));
+    let running = state(Path::new(CURRENT_PROFILE));
+    let mut default = state(profile);
+    // The profile itself knows its generation, even if an older one has the
+    // same system
+    if let Some(default) = &mut default {
+        if let Some(number) = fs::read_link(profile)
+            .ok()
+            .as_deref()
+            .and_then(generations::from_dir)
+        {
+            default.generation = Some(number);
+        }
+    }
+
+    let mut notes = Vec::new();
+    if let (Some(booted), Some(running), Some(default)) = (&booted, &running, &default) {
+        notes = boot::drift(&booted.path, &running.path, &default.path);
+    }
+
+    let newest = links.last().and_then(|(number, link)| {
+        let newest = state(link)?;
+        let is_default = default
+            .as_ref()
+            .is_some_and(|default| default.generation == Some(*number));
+        (!is_default).then_some(json::SystemState {
+            generation: Some(*number),
+            ..newest
+        })
+    });
+    if let Some(json::SystemState {
+        generation: Some(number),
+        ..
+    }) = &newest
+    {
+        notes.push(format!(
+            "Generation {number} is newer than the boot default, which was rolled back"
+        ));
+    }
+
+    Ok(json::SystemStatus {
+        booted,
+        running,
+        default,
+        newest,
+        notes,
+    })
+}
+
+/// Warn if a reboot is pending or the running system isn't the boot default.
+fn warn_system_drift(profile: &Path) {
+    match system_status(profile) {
+        Ok(status) => {
+            for note in status.notes {
+                warn!("{note}");
+            }
+        }
+        Err(err) => debug!("Couldn't compare the booted and running systems: {err:#}"),
+    }
+}
+
+impl interface::OsPinArgs {
+    fn set_pinned(&self, pin: bool) -> Result<()> {
+        let number = resolve_generation(&self.profile, self.generation)?;
+        generations::set_pinned(&self.profile, number, pin)?;
+
+        if pin {
+            info!("Pinned generation {number}, it will be kept by nh clean");
+        } else {
+            info!("Unpinned generation {number}");
+        }
+
+        Ok(())
+    }
+}
+
+impl interface::OsTagArgs {
+    fn tag(&self) -> Result<()> {
+        let number = resolve_generation(&self.profile, self.generation)?;
+
+        generations::set_label(&self.profile, number, &self.label)?;
+
+        if self.label.is_empty() {
+            info!("Removed label from generation {number}");
+        } else {
+            info!("Labelled generation {number} as '{}'", self.label);
+        }
 
         Ok(())
     }
@@ -722,32 +2075,523 @@ impl OsGenerationsArgs {
             ));
         }
 
-        let profile_dir = profile.parent().unwrap_or_else(|| Path::new("."));
+        if self.tui {
+            if json::output_enabled() {
+                bail!("--tui can't be combined with --json");
+            }
+            return browse(&profile);
+        }
 
-        let generations: Vec<_> = fs::read_dir(profile_dir)?
-            .filter_map(|entry| {
-                entry.ok().and_then(|e| {
-                    let path = e.path();
-                    if path
-                        .file_name()?
-                        .to_str()?
-                        .starts_with(profile.file_name()?.to_str()?)
-                    {
-                        Some(path)
-                    } else {
-                        None
-                    }
-                })
-            })
+        let sizes = self.sizes || self.format.as_ref().is_some_and(|f| f.uses("size"));
+        let descriptions = describe_generations(&profile, sizes)?;
+
+        if json::output_enabled() {
+            generations::print_info_json(descriptions)?;
+        } else if let Some(template) = &self.format {
+            generations::print_info_formatted(descriptions, template)?;
+        } else {
+            let _ = generations::print_info(descriptions);
+
+            if profile == Path::new(SYSTEM_PROFILE) {
+                warn_boot_inconsistencies(&profile);
+                warn_system_drift(&profile);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Describe all generations of `profile`, optionally with their closure sizes.
+pub fn describe_generations(
+    profile: &Path,
+    sizes: bool,
+) -> Result<Vec<generations::GenerationInfo>> {
+    let mut descriptions = generations::GenerationSet::scan(profile)?.into_vec();
+
+    if sizes {
+        let links: Vec<PathBuf> = generations::generation_links(profile)?
+            .into_iter()
+            .map(|(_, link)| link)
             .collect();
+        let sizes = generations::closure_sizes(&links);
+
+        for generation in &mut descriptions {
+            generation.closure_size = generation
+                .number
+                .parse()
+                .ok()
+                .and_then(|number| sizes.get(&generations::generation_link(profile, number)))
+                .copied();
+        }
+    }
+
+    Ok(descriptions)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BrowseAction {
+    Rollback,
+    Pin,
+    Unpin,
+    Tag,
+    Delete,
+    Baseline,
+}
 
-        let descriptions: Vec<generations::GenerationInfo> = generations
+/// Full-screen generation browser for `nh os info --tui`.
+///
+/// The diff of the selected generation against the baseline (the current
+/// generation unless another one was chosen) is shown below the list, and
+/// keys run actions on the selected generation.
+fn browse(profile: &Path) -> Result<()> {
+    if !std::io::stdin().is_terminal() || !std::io::stdout().is_terminal() {
+        bail!("--tui requires an interactive terminal");
+    }
+
+    let is_system = profile == Path::new(SYSTEM_PROFILE);
+    let mut baseline: Option<u64> = None;
+    let mut selected: Option<u64> = None;
+    let mut status: Option<String> = None;
+    // Comparing closures is slow, and generations don't change
+    let mut diffs: HashMap<(PathBuf, PathBuf), Vec<String>> = HashMap::new();
+    let screen = browse::Screen::enter()?;
+
+    loop {
+        let mut generations = describe_generations(profile, false)?;
+        generations.sort_by_key(|g| g.number.parse::<u64>().unwrap_or_default());
+        if generations.is_empty() {
+            bail!("No generations found for {}", profile.display());
+        }
+        let numbers: Vec<u64> = generations
             .iter()
-            .filter_map(|gen_dir| generations::describe(gen_dir))
+            .map(|g| g.number.parse().unwrap_or_default())
             .collect();
 
-        let _ = generations::print_info(descriptions);
+        if baseline.is_some_and(|baseline| !numbers.contains(&baseline)) {
+            baseline = None;
+        }
+        let baseline_link = match baseline {
+            Some(baseline) => generations::generation_link(profile, baseline),
+            None => fs::read_link(profile)
+                .map(|target| profile.with_file_name(target))
+                .unwrap_or_else(|_| profile.to_path_buf()),
+        };
+
+        let index = selected
+            .and_then(|selected| numbers.iter().position(|&n| n == selected))
+            .or_else(|| generations.iter().position(|g| g.current))
+            .unwrap_or(0);
+        let mut view = browse::View::new(
+            format!("Generations of {}", profile.display()),
+            generations.iter().map(browse_item).collect(),
+            index,
+            baseline.map_or_else(
+                || "the current generation".to_string(),
+                |baseline| format!("generation {baseline}"),
+            ),
+        );
+        view.status = status.take();
+
+        let mut diff = |index: usize| {
+            let link = generations::generation_link(profile, numbers[index]);
+            diffs
+                .entry((baseline_link.clone(), link.clone()))
+                .or_insert_with(|| browse_diff(&baseline_link, &link))
+                .clone()
+        };
+        let request = screen.run(&mut view, &mut diff)?;
+
+        let generation = &generations[view.selected];
+        let number = numbers[view.selected];
+        selected = Some(number);
 
-        Ok(())
+        let action = match request {
+            browse::Request::Quit => return Ok(()),
+            browse::Request::Rollback if !is_system => {
+                status = Some("Only system generations can be rolled back to".to_string());
+                continue;
+            }
+            browse::Request::Rollback if generation.current => {
+                status = Some(format!("Generation {number} is already the current one"));
+                continue;
+            }
+            browse::Request::Rollback => BrowseAction::Rollback,
+            browse::Request::TogglePin if generation.pinned => BrowseAction::Unpin,
+            browse::Request::TogglePin => BrowseAction::Pin,
+            browse::Request::Tag => BrowseAction::Tag,
+            browse::Request::Delete if generation.current => {
+                status = Some("The current generation can't be deleted".to_string());
+                continue;
+            }
+            browse::Request::Delete if generation.pinned => {
+                status = Some(format!(
+                    "Generation {number} is pinned, unpin it to delete it"
+                ));
+                continue;
+            }
+            browse::Request::Delete => BrowseAction::Delete,
+            browse::Request::Baseline => BrowseAction::Baseline,
+        };
+
+        // Prompts and command output need the normal screen
+        let outcome = match action {
+            BrowseAction::Rollback | BrowseAction::Tag | BrowseAction::Delete => {
+                screen.suspended(|| run_browse_action(profile, number, action, &mut baseline))?
+            }
+            _ => run_browse_action(profile, number, action, &mut baseline),
+        };
+        status = Some(outcome.unwrap_or_else(|err| paint(format!("{err:#}"), Role::Error)));
+    }
+}
+
+fn browse_item(generation: &generations::GenerationInfo) -> String {
+    let mut item = format!(
+        "{:>5}  {}  {}",
+        generation.number, generation.date, generation.nixos_version
+    );
+    if generation.current {
+        item.push_str("  (current)");
+    }
+    if generation.pinned {
+        item.push_str("  [pinned]");
+    }
+    if let Some(label) = &generation.label {
+        item.push_str(&format!("  {label}"));
+    }
+    item
+}
+
+/// Lines of the diff pane for `link` against `baseline`.
+fn browse_diff(baseline: &Path, link: &Path) -> Vec<String> {
+    if baseline == link {
+        return vec!["This is the baseline.".to_string()];
+    }
+    match diff::builtin_diff(baseline, link) {
+        Ok(diff) => diff::format_diff(&diff)
+            .lines()
+            .map(str::to_string)
+            .collect(),
+        Err(err) => vec![paint(
+            format!("Could not compare the closures: {err:#}"),
+            Role::Error,
+        )],
+    }
+}
+
+/// Run `action` on generation `number`, returning what happened.
+fn run_browse_action(
+    profile: &Path,
+    number: u64,
+    action: BrowseAction,
+    baseline: &mut Option<u64>,
+) -> Result<String> {
+    match action {
+        BrowseAction::Rollback => {
+            if !dialoguer::Confirm::new()
+                .with_prompt(format!("Roll back to generation {number}?"))
+                .default(false)
+                .interact()?
+            {
+                return Ok("Not rolled back".to_string());
+            }
+            OsRollbackArgs {
+                dry: false,
+                ask: false,
+                specialisation: None,
+                no_specialisation: false,
+                to: Some(number),
+                to_date: None,
+                before: None,
+                bypass_root_check: false,
+                // The diff is already on screen
+                diff: DiffType::Never,
+                diff_only: false,
+                profile: PathBuf::from(SYSTEM_PROFILE),
+            }
+            .rollback()?;
+            Ok(format!("Rolled back to generation {number}"))
+        }
+        BrowseAction::Pin | BrowseAction::Unpin => {
+            let pin = action == BrowseAction::Pin;
+            generations::set_pinned(profile, number, pin)?;
+            Ok(format!(
+                "{} generation {number}",
+                if pin { "Pinned" } else { "Unpinned" }
+            ))
+        }
+        BrowseAction::Tag => {
+            let current = generations::labels(profile)?
+                .remove(&number)
+                .unwrap_or_default();
+            let label: String = dialoguer::Input::new()
+                .with_prompt("Label (empty to remove)")
+                .with_initial_text(current)
+                .allow_empty(true)
+                .interact_text()?;
+            generations::set_label(profile, number, label.trim())?;
+            Ok(if label.trim().is_empty() {
+                format!("Removed the label of generation {number}")
+            } else {
+                format!("Labelled generation {number}")
+            })
+        }
+        BrowseAction::Delete => {
+            if !dialoguer::Confirm::new()
+                .with_prompt(format!("Delete generation {number}?"))
+                .default(false)
+                .interact()?
+            {
+                return Ok(format!("Kept generation {number}"));
+            }
+            let link = generations::generation_link(profile, number);
+            let writable = nix::unistd::access(
+                link.parent().unwrap_or_else(|| Path::new(".")),
+                nix::unistd::AccessFlags::W_OK,
+            )
+            .is_ok();
+
+            Command::new("rm")
+                .arg(&link)
+                .elevate(!writable)
+                .message(format!("Deleting generation {number}"))
+                .with_required_env()
+                .run()?;
+            if *baseline == Some(number) {
+                *baseline = None;
+            }
+            Ok(format!("Deleted generation {number}"))
+        }
+        BrowseAction::Baseline => {
+            *baseline = Some(number);
+            Ok(format!("Comparing with generation {number}"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_continue_export() {
+        use std::io::Write;
+
+        let mut file = tempfile::tempfile().unwrap();
+        file.write_all(b"\x01archive").unwrap();
+        file.write_all(&EXPORT_END).unwrap();
+        continue_export(&mut file).unwrap();
+        file.write_all(b"\x01more").unwrap();
+
+        let mut archive = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut archive).unwrap();
+        assert_eq!(archive, b"\x01archive\x01more");
+
+        // Not the end of an archive
+        assert!(continue_export(&mut file).is_err());
+        assert!(continue_export(&mut tempfile::tempfile().unwrap()).is_err());
+    }
+
+    const TOPLEVEL: [&str; 4] = ["config", "system", "build", "toplevel"];
+
+    #[test]
+    fn test_export_target() {
+        assert_eq!(
+            ExportTarget::parse("ssh://backup"),
+            ExportTarget::Store("ssh://backup".to_string())
+        );
+        assert_eq!(
+            ExportTarget::parse("file:///srv/cache"),
+            ExportTarget::Store("file:///srv/cache".to_string())
+        );
+        assert_eq!(
+            ExportTarget::parse("file:./closure.nar"),
+            ExportTarget::Archive(PathBuf::from("./closure.nar"))
+        );
+    }
+
+    #[test]
+    fn test_connection_lost() {
+        let exit = |code: u32| {
+            Command::new("sh")
+                .args(["-c", &format!("exit {code}")])
+                .run()
+                .unwrap_err()
+        };
+        assert!(connection_lost(&exit(255)));
+        // switch-to-configuration failing units
+        assert!(!connection_lost(&exit(4)));
+        assert!(!connection_lost(&eyre!("Activation (test) failed")));
+    }
+
+    #[test]
+    fn test_browse_item() {
+        let mut generation = generations::GenerationInfo {
+            number: "42".to_string(),
+            date: "2024-01-01 12:00:00".to_string(),
+            nixos_version: "24.05".to_string(),
+            kernel_version: "6.6.1".to_string(),
+            configuration_revision: String::new(),
+            specialisations: Vec::new(),
+            current: false,
+            closure_size: None,
+            label: None,
+            pinned: false,
+            flake_revision: None,
+        };
+        assert_eq!(
+            browse_item(&generation),
+            "   42  2024-01-01 12:00:00  24.05"
+        );
+
+        generation.current = true;
+        generation.pinned = true;
+        generation.label = Some("known-good".to_string());
+        assert_eq!(
+            browse_item(&generation),
+            "   42  2024-01-01 12:00:00  24.05  (current)  [pinned]  known-good"
+        );
+    }
+
+    #[test]
+    fn test_nix_string() {
+        assert_eq!(nix_string("laptop"), r#""laptop""#);
+        assert_eq!(nix_string(r#"/a "b" \c ${d}"#), r#""/a \"b\" \\c \${d}""#);
+    }
+
+    #[test]
+    fn test_os_toplevel_for_flake() {
+        let installable = os_toplevel_for(
+            "laptop",
+            Installable::Flake {
+                reference: ".".to_string(),
+                attribute: vec![],
+            },
+            "toplevel",
+        );
+
+        assert_eq!(
+            installable.to_args(),
+            vec![".#nixosConfigurations.laptop.config.system.build.toplevel"]
+        );
+    }
+
+    #[test]
+    fn test_os_config_for() {
+        let installable = os_config_for(
+            "laptop",
+            Installable::Flake {
+                reference: ".".to_string(),
+                attribute: vec![],
+            },
+        );
+        assert_eq!(
+            installable.to_args(),
+            vec![".#nixosConfigurations.laptop.config"]
+        );
+
+        let installable = os_config_for(
+            "laptop",
+            Installable::File {
+                path: PathBuf::from("/etc/nixos/machines.nix"),
+                attribute: vec!["server".to_string()],
+            },
+        );
+        assert_eq!(
+            installable.to_args(),
+            vec!["--file", "/etc/nixos/machines.nix", "server.config"]
+        );
+    }
+
+    #[test]
+    fn test_os_toplevel_for_file_without_attribute() {
+        let installable = os_toplevel_for(
+            "laptop",
+            Installable::File {
+                path: PathBuf::from("/etc/nixos/configuration.nix"),
+                attribute: vec![],
+            },
+            "toplevel",
+        );
+
+        let Installable::Expression {
+            expression,
+            attribute,
+        } = installable
+        else {
+            panic!("Expected an expression installable, got {installable:?}");
+        };
+        assert_eq!(attribute, TOPLEVEL);
+        assert!(expression.contains(r#"imported = import (/. + "/etc/nixos/configuration.nix");"#));
+        assert!(expression.contains(r#"modules = [ (/. + "/etc/nixos/configuration.nix") ]"#));
+        assert!(expression.contains(r#"value."laptop""#));
+    }
+
+    #[test]
+    fn test_os_toplevel_for_relative_file_is_absolute() {
+        let installable = os_toplevel_for(
+            "laptop",
+            Installable::File {
+                path: PathBuf::from("default.nix"),
+                attribute: vec![],
+            },
+            "vm",
+        );
+
+        let Installable::Expression {
+            expression,
+            attribute,
+        } = installable
+        else {
+            panic!("Expected an expression installable, got {installable:?}");
+        };
+        let cwd = env::current_dir().unwrap();
+        assert!(expression.contains(&format!("{}/default.nix", cwd.display())));
+        assert_eq!(attribute, ["config", "system", "build", "vm"]);
+    }
+
+    #[test]
+    fn test_os_toplevel_for_file_with_attribute() {
+        let installable = os_toplevel_for(
+            "laptop",
+            Installable::File {
+                path: PathBuf::from("default.nix"),
+                attribute: vec!["machines".to_string(), "server".to_string()],
+            },
+            "toplevel",
+        );
+
+        assert_eq!(
+            installable.to_args(),
+            vec![
+                "--file",
+                "default.nix",
+                "machines.server.config.system.build.toplevel"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_os_toplevel_for_expression() {
+        let installable = os_toplevel_for(
+            "laptop",
+            Installable::Expression {
+                expression: "{ boot.isContainer = true; }".to_string(),
+                attribute: vec![],
+            },
+            "toplevel",
+        );
+
+        let Installable::Expression {
+            expression,
+            attribute,
+        } = installable
+        else {
+            panic!("Expected an expression installable, got {installable:?}");
+        };
+        assert_eq!(attribute, TOPLEVEL);
+        assert!(expression.contains("imported = ({ boot.isContainer = true; });"));
+        assert!(expression.contains("modules = [ imported ]"));
     }
 }
@@ -0,0 +1,182 @@
+//! Notifications when a rebuild finishes or fails, for runs long enough that
+//! the terminal isn't watched anymore.
+//!
+//! With `--notify` a desktop notification is shown through `notify-send`, or
+//! `terminal-notifier` on macOS. With `--webhook URL` the result is posted as
+//! JSON:
+//!
+//! ```json
+//! {
+//!   "command": "os switch",
+//!   "success": true,
+//!   "elapsed_ms": 192340,
+//!   "result": { "system": "nixos", "action": "switch", "generation": 42, ... }
+//! }
+//! ```
+//!
+//! `result` is the same as `--json` prints, failed runs have an `error`
+//! instead. `--notify-after` skips runs that finished quicker.
+
+use std::time::Duration;
+
+use color_eyre::eyre::Context;
+use color_eyre::{Report, Result};
+use serde::Serialize;
+use tracing::{debug, warn};
+
+use crate::commands::Command;
+use crate::hooks;
+use crate::json::{self, RebuildResult};
+use crate::timings::format_ms;
+
+/// Where to send notifications to
+#[derive(Debug, Clone, Default)]
+pub struct Targets {
+    pub desktop: bool,
+    pub webhook: Option<String>,
+    /// Only notify about runs that took longer than this
+    pub after: Option<Duration>,
+}
+
+#[derive(Debug, Serialize)]
+struct Payload<'a> {
+    command: &'a str,
+    success: bool,
+    elapsed_ms: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<RebuildResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Payload<'_> {
+    /// Title and body of the desktop notification.
+    fn message(&self) -> (String, String) {
+        let title = format!(
+            "nh {} {}",
+            self.command,
+            if self.success { "finished" } else { "failed" }
+        );
+
+        let mut body = match (&self.result, &self.error) {
+            (_, Some(error)) => error.lines().next().unwrap_or_default().to_string(),
+            (Some(result), None) => match (result.generation, &result.hostname) {
+                (Some(generation), Some(host)) => format!("Generation {generation} on {host}"),
+                (Some(generation), None) => format!("Generation {generation}"),
+                (None, _) => result.out_path.display().to_string(),
+            },
+            (None, None) => String::new(),
+        };
+        if !body.is_empty() {
+            body.push_str(", ");
+        }
+        body.push_str(&format!("after {}", format_ms(self.elapsed_ms)));
+
+        (title, body)
+    }
+}
+
+/// Send the notifications for a finished run of `command`, like `os switch`,
+/// if it was a rebuild.
+pub fn finish(targets: &Targets, command: &str, res: &Result<()>, elapsed: Duration) {
+    if !targets.desktop && targets.webhook.is_none() {
+        return;
+    }
+    if !hooks::started() {
+        debug!("Not notifying, {command} isn't a rebuild");
+        return;
+    }
+    if targets.after.is_some_and(|after| elapsed < after) {
+        debug!(?elapsed, "Not notifying about a quick run");
+        return;
+    }
+
+    let payload = Payload {
+        command,
+        success: res.is_ok(),
+        elapsed_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        result: json::last_rebuild(),
+        error: res.as_ref().err().map(|err: &Report| format!("{err:#}")),
+    };
+
+    if targets.desktop {
+        if let Err(err) = desktop(&payload) {
+            warn!("Failed to show a desktop notification: {err:#}");
+        }
+    }
+    if let Some(url) = &targets.webhook {
+        if let Err(err) = webhook(url, &payload) {
+            warn!("Failed to notify {url}: {err:#}");
+        }
+    }
+}
+
+fn desktop(payload: &Payload) -> Result<()> {
+    let (title, body) = payload.message();
+    let cmd = if cfg!(target_os = "macos") {
+        Command::new("terminal-notifier")
+            .args(["-title", &title, "-message", &body])
+            .args(["-group", "nh"])
+    } else {
+        Command::new("notify-send")
+            .args(["--app-name", "nh"])
+            .args((!payload.success).then_some("--urgency=critical"))
+            .args([&title, &body])
+    };
+    cmd.run()
+}
+
+fn webhook(url: &str, payload: &Payload) -> Result<()> {
+    reqwest::blocking::Client::new()
+        .post(url)
+        .header("User-Agent", format!("nh/{}", crate::NH_VERSION))
+        .timeout(Duration::from_secs(10))
+        .json(payload)
+        .send()
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .wrap_err("Webhook request failed")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_message() {
+        let mut payload = Payload {
+            command: "os switch",
+            success: true,
+            elapsed_ms: 125_000,
+            result: Some(RebuildResult {
+                system: "nixos",
+                action: "switch",
+                hostname: Some("laptop".to_string()),
+                out_path: PathBuf::from("/nix/store/abc-nixos-system"),
+                revision: None,
+                dry: false,
+                activated: true,
+                generation: Some(42),
+                copy: None,
+                diff: None,
+            }),
+            error: None,
+        };
+        assert_eq!(
+            payload.message(),
+            (
+                "nh os switch finished".to_string(),
+                "Generation 42 on laptop, after 2m 05s".to_string()
+            )
+        );
+
+        payload.success = false;
+        payload.error = Some("Build failed: Failed to build configuration".to_string());
+        assert_eq!(
+            payload.message().1,
+            "Build failed: Failed to build configuration, after 2m 05s"
+        );
+    }
+}
@@ -0,0 +1,225 @@
+//! `nh os option` and `nh home option`, showing the value and documentation
+//! of a single option of a configuration like `nixos-option` does.
+//!
+//! The option is looked up in `options` of the evaluated configuration, and
+//! its value in `config`, in one `nix eval`.
+
+use std::fmt::Write as _;
+
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+use serde::{Deserialize, Serialize};
+
+use crate::commands::Command;
+use crate::installable::{Installable, parse_attribute};
+use crate::json;
+use crate::nixos::nix_string;
+use crate::output;
+use crate::theme::{Role, paint};
+
+/// Applied to an evaluated configuration (the result of `nixosSystem` or
+/// `homeManagerConfiguration`), with `@PATH@` replaced by the option path as
+/// a Nix list.
+const OPTION_EXPR: &str = r#"system:
+let
+  lib = system.pkgs.lib;
+  path = @PATH@;
+  found = lib.attrByPath path null system.options;
+  pretty = value:
+    let result = builtins.tryEval (lib.generators.toPretty { } value);
+    in if result.success then result.value else "<error>";
+  text = doc: if builtins.isAttrs doc then doc.text or (pretty doc) else doc;
+in
+if lib.isOption found then {
+  value = pretty (lib.attrByPath path null system.config);
+  type = found.type.description or found.type.name or null;
+  default =
+    if found ? defaultText then text found.defaultText
+    else if found ? default then pretty found.default
+    else null;
+  description = if found ? description then text found.description else null;
+  declarations = map toString (found.declarations or [ ]);
+  definitions = map toString (found.files or [ ]);
+} else if builtins.isAttrs found then {
+  children = builtins.attrNames found;
+} else null"#;
+
+/// An option of a configuration, or a group of options.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OptionInfo {
+    /// The option path, like `services.nginx.enable`
+    #[serde(default)]
+    pub name: String,
+    /// The value in the configuration, as Nix code
+    pub value: Option<String>,
+    #[serde(rename = "type")]
+    pub option_type: Option<String>,
+    pub default: Option<String>,
+    pub description: Option<String>,
+    /// Modules declaring the option
+    #[serde(default)]
+    pub declarations: Vec<String>,
+    /// Files setting the value
+    #[serde(default)]
+    pub definitions: Vec<String>,
+    /// The options below `name`, if it is a group rather than an option
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub children: Vec<String>,
+}
+
+/// The Nix list of the elements of an option path like
+/// `users.users."alice".shell`.
+fn nix_path(name: &str) -> String {
+    let elems: Vec<String> = parse_attribute(name)
+        .iter()
+        .map(|elem| nix_string(elem))
+        .collect();
+    format!("[ {} ]", elems.join(" "))
+}
+
+/// Evaluate the option `name` of the configuration `system`.
+pub fn evaluate<I>(system: &Installable, name: &str, eval_args: I) -> Result<OptionInfo>
+where
+    I: IntoIterator,
+    I::Item: AsRef<std::ffi::OsStr>,
+{
+    let expr = OPTION_EXPR.replace("@PATH@", &nix_path(name));
+
+    let json = Command::new("nix")
+        .with_required_env()
+        .args(["eval", "--json", "--apply", &expr])
+        .args(eval_args)
+        .args(system.to_args())
+        .message(format!("Evaluating {name}"))
+        .run_capture()?
+        .unwrap_or_default();
+
+    if json.trim().is_empty() {
+        bail!("Failed to evaluate {name}");
+    }
+    let info: Option<OptionInfo> = serde_json::from_str(&json)
+        .map_err(|err| eyre!("Failed to parse the evaluated option: {err}"))?;
+
+    match info {
+        Some(info) => Ok(OptionInfo {
+            name: name.to_string(),
+            ..info
+        }),
+        None => bail!("There is no option {name}"),
+    }
+}
+
+/// Human readable description of `info`.
+#[must_use]
+pub fn format_option(info: &OptionInfo) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "{}", paint(&info.name, Role::Heading));
+
+    if !info.children.is_empty() {
+        let _ = writeln!(out, "Group of {} options:", info.children.len());
+        for child in &info.children {
+            let _ = writeln!(
+                out,
+                "  {}",
+                paint(format!("{}.{child}", info.name), Role::Name)
+            );
+        }
+        return out;
+    }
+
+    let mut block = |title: &str, text: Option<&str>| {
+        if let Some(text) = text {
+            let _ = writeln!(out, "{}", paint(title, Role::Emphasis));
+            for line in text.trim_end().lines() {
+                let _ = writeln!(out, "  {line}");
+            }
+        }
+    };
+    block("Value:", info.value.as_deref());
+    block("Type:", info.option_type.as_deref());
+    block("Default:", info.default.as_deref());
+    block("Description:", info.description.as_deref());
+    block(
+        "Declared in:",
+        Some(info.declarations.join("\n"))
+            .filter(|files| !files.is_empty())
+            .as_deref(),
+    );
+    block(
+        "Defined in:",
+        Some(info.definitions.join("\n"))
+            .filter(|files| !files.is_empty())
+            .as_deref(),
+    );
+
+    out
+}
+
+/// Evaluate and print the option `name` of `system`.
+pub fn show<I>(system: &Installable, name: &str, eval_args: I) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<std::ffi::OsStr>,
+{
+    let info = evaluate(system, name, eval_args)?;
+    if output::human() {
+        print!("{}", format_option(&info));
+    }
+    json::emit(&json::Output::Option(info))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nix_path() {
+        assert_eq!(
+            nix_path("services.nginx.enable"),
+            r#"[ "services" "nginx" "enable" ]"#
+        );
+        assert_eq!(
+            nix_path(r#"users.users."alice.smith".shell"#),
+            r#"[ "users" "users" "alice.smith" "shell" ]"#
+        );
+    }
+
+    #[test]
+    fn test_format_option() {
+        let info: OptionInfo = serde_json::from_str(
+            r#"{
+              "value": "true",
+              "type": "boolean",
+              "default": "false",
+              "description": "Whether to enable Nginx Web Server.",
+              "declarations": ["/nix/store/abc-source/nixos/modules/services/web-servers/nginx/default.nix"],
+              "definitions": ["/etc/nixos/configuration.nix"]
+            }"#,
+        )
+        .unwrap();
+        let info = OptionInfo {
+            name: "services.nginx.enable".to_string(),
+            ..info
+        };
+
+        let text = format_option(&info);
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "services.nginx.enable");
+        assert_eq!(lines[1..3], ["Value:", "  true"]);
+        assert_eq!(lines[3..5], ["Type:", "  boolean"]);
+        assert_eq!(
+            lines[lines.len() - 2..],
+            ["Defined in:", "  /etc/nixos/configuration.nix"]
+        );
+
+        let group = OptionInfo {
+            name: "services.nginx".to_string(),
+            children: vec!["enable".to_string(), "package".to_string()],
+            ..OptionInfo::default()
+        };
+        assert_eq!(
+            format_option(&group),
+            "services.nginx\nGroup of 2 options:\n  services.nginx.enable\n  services.nginx.package\n"
+        );
+    }
+}
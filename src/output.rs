@@ -0,0 +1,119 @@
+//! How much nh prints on stdout.
+//!
+//! By default nh reports progress, diffs and plans as it goes. With
+//! `-q/--quiet` only the final result of a run is printed, like the out path
+//! of a build or the new generation number, and with `--json` a single JSON
+//! document replaces it (see [`crate::json`]).
+
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::json::{self, Output};
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+static SILENT: AtomicBool = AtomicBool::new(false);
+
+/// Only print the final result of the run.
+pub fn enable_quiet() {
+    QUIET.store(true, Ordering::Relaxed);
+}
+
+#[must_use]
+pub fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Don't print the error a run fails with either, as with `-qq`. The exit
+/// code still tells it apart.
+pub fn enable_silent() {
+    SILENT.store(true, Ordering::Relaxed);
+}
+
+#[must_use]
+pub fn silent() -> bool {
+    SILENT.load(Ordering::Relaxed)
+}
+
+/// Whether progress messages and human readable reports should be printed.
+#[must_use]
+pub fn human() -> bool {
+    !quiet() && !json::output_enabled()
+}
+
+/// The line printed for `output` in quiet mode, for commands whose normal
+/// output isn't already the result.
+#[must_use]
+pub fn summary(output: &Output) -> Option<String> {
+    match output {
+        Output::Rebuild(result) => Some(match result.generation {
+            Some(generation) => generation.to_string(),
+            None => result.out_path.display().to_string(),
+        }),
+        Output::Deploy(hosts) => Some(format!(
+            "{} of {} hosts deployed",
+            hosts
+                .iter()
+                .filter(|host| host.status == "deployed")
+                .count(),
+            hosts.len()
+        )),
+        Output::Rollback(result) => Some(result.generation.to_string()),
+        Output::Clean(result) => Some(format!(
+            "{} paths {}removed",
+            result.removed.len(),
+            if result.dry { "would be " } else { "" }
+        )),
+        Output::Option(info) => info.value.clone(),
+        Output::Status(status) => Some(if status.notes.is_empty() {
+            "up to date".to_string()
+        } else {
+            status.notes.join("\n")
+        }),
+        Output::Diff(_)
+        | Output::Search(_)
+        | Output::Info(_)
+        | Output::Update(_)
+        | Output::Stats(_) => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::json::{CleanResult, RebuildResult};
+
+    #[test]
+    fn test_summary() {
+        let mut rebuild = RebuildResult {
+            system: "nixos",
+            action: "build",
+            hostname: None,
+            out_path: PathBuf::from("/nix/store/abc-nixos-system"),
+            revision: None,
+            dry: false,
+            activated: false,
+            generation: None,
+            copy: None,
+            diff: None,
+        };
+        assert_eq!(
+            summary(&Output::Rebuild(rebuild.clone())).as_deref(),
+            Some("/nix/store/abc-nixos-system")
+        );
+
+        rebuild.generation = Some(42);
+        assert_eq!(summary(&Output::Rebuild(rebuild)).as_deref(), Some("42"));
+
+        let clean = CleanResult {
+            dry: true,
+            removed: vec![PathBuf::from("/a"), PathBuf::from("/b")],
+            kept: Vec::new(),
+            gc: false,
+        };
+        assert_eq!(
+            summary(&Output::Clean(clean)).as_deref(),
+            Some("2 paths would be removed")
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Subcommands provided by other programs.
+//!
+//! Like cargo and git, `nh foo args...` runs `nh-foo args...` from `PATH`
+//! when nh has no `foo` subcommand itself, so that nh can be extended
+//! without forking it. Plugins inherit the environment, including the
+//! `NH_*` variables set in the config file, and get a few more describing
+//! the nh running them:
+//!
+//! - `NH_BIN`: the nh executable, to call back into nh
+//! - `NH_VERSION`: its version
+//! - `NH_JSON`: `1` with `--json`
+//! - `NH_QUIET`: `1` with `--quiet`
+//!
+//! nh is replaced by the plugin, which gets the terminal, signals and the
+//! exit code of the run.
+
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use color_eyre::Result;
+use color_eyre::eyre::{bail, eyre};
+use tracing::debug;
+
+use crate::NH_VERSION;
+use crate::commands::Command;
+use crate::json;
+use crate::output;
+
+/// The executable of the plugin `name`, if there is one in `PATH`.
+#[must_use]
+pub fn find(name: &str) -> Option<PathBuf> {
+    // Names like `../foo` would escape PATH
+    if name.is_empty() || name.contains('/') {
+        return None;
+    }
+    which::which(format!("nh-{name}")).ok()
+}
+
+/// Run the plugin for `args`, the unknown subcommand and its arguments.
+pub fn run(args: &[OsString]) -> Result<()> {
+    let (name, args) = args
+        .split_first()
+        .ok_or_else(|| eyre!("No subcommand given"))?;
+    let name = name.to_string_lossy();
+    let Some(program) = find(&name) else {
+        bail!("Unknown subcommand '{name}', and there is no nh-{name} in PATH");
+    };
+    debug!("Running plugin {}", program.display());
+
+    let mut cmd = Command::new(&program)
+        .args(args)
+        .env("NH_VERSION", NH_VERSION);
+    if let Ok(exe) = std::env::current_exe() {
+        cmd = cmd.env("NH_BIN", exe.to_string_lossy());
+    }
+    if json::output_enabled() {
+        cmd = cmd.env("NH_JSON", "1");
+    }
+    if output::quiet() {
+        cmd = cmd.env("NH_QUIET", "1");
+    }
+    cmd.exec()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        assert_eq!(find("../bin/sh"), None);
+        assert_eq!(find(""), None);
+        assert_eq!(find("surely-no-such-plugin"), None);
+    }
+}
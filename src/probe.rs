@@ -0,0 +1,191 @@
+//! Probe of a `--target-host` before a closure is copied to it.
+//!
+//! A single ssh session reports the platform, Nix version, store and free
+//! space of the host, so that deploying to the wrong kind of machine fails
+//! before anything is copied instead of halfway through activation.
+
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use subprocess::{Exec, Redirection};
+use tracing::{debug, info, warn};
+
+use crate::checks::{self, SkippableCheck};
+use crate::commands;
+use crate::exit::Failure;
+use crate::generations;
+use crate::util::{self, format_bytes};
+
+/// Prints a `key: value` line for each property of the host. Failing
+/// commands leave their value empty.
+const PROBE_SCRIPT: &str = r#"echo "uname: $(uname -sm)"
+echo "nixos: $(test -e /etc/NIXOS && echo yes)"
+echo "nix: $(nix --version 2>/dev/null)"
+echo "store: $(nix config show store 2>/dev/null)"
+echo "df: $(df -Pk /nix/store 2>/dev/null | tail -n 1)""#;
+
+/// What the probe found out about a host.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct HostInfo {
+    /// Nix system double, like `aarch64-linux`
+    pub system: Option<String>,
+    pub nixos: bool,
+    /// Output of `nix --version`
+    pub nix_version: Option<String>,
+    pub store: Option<String>,
+    /// Free bytes on the filesystem of `/nix/store`
+    pub free_bytes: Option<u64>,
+}
+
+/// The Nix system double of `uname -sm` output.
+fn nix_system(uname: &str) -> Option<String> {
+    let (kernel, machine) = uname.split_once(' ')?;
+    Some(util::nix_system(machine.trim(), kernel))
+}
+
+fn parse(output: &str) -> HostInfo {
+    let mut info = HostInfo::default();
+    for line in output.lines() {
+        let Some((key, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        if value.is_empty() {
+            continue;
+        }
+        match key {
+            "uname" => info.system = nix_system(value),
+            "nixos" => info.nixos = true,
+            "nix" => info.nix_version = Some(value.to_string()),
+            "store" => info.store = Some(value.to_string()),
+            // Filesystem, 1024-blocks, Used, Available, Capacity, Mounted on
+            "df" => {
+                info.free_bytes = value
+                    .split_whitespace()
+                    .nth(3)
+                    .and_then(|available| available.parse::<u64>().ok())
+                    .map(|kib| kib * 1024);
+            }
+            _ => {}
+        }
+    }
+    info
+}
+
+/// Probe `host` over ssh. Fails if the host can't be reached, the script
+/// itself always succeeds.
+pub fn probe(host: &str) -> Result<HostInfo> {
+    // The script goes in the arguments, as ssh exits without reading its
+    // input when it can't connect, which would fail writing it
+    let cmd = commands::ssh_wrap(Exec::cmd("sh").args(&["-c", PROBE_SCRIPT]), Some(host))
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe);
+    debug!(?cmd);
+    let capture = cmd
+        .capture()
+        .wrap_err_with(|| format!("Failed to run ssh to {host}"))?;
+    if !capture.exit_status.success() {
+        bail!(
+            "Failed to connect to {host} over ssh ({:?}): {}",
+            capture.exit_status,
+            capture.stderr_str().trim()
+        );
+    }
+    let info = parse(&capture.stdout_str());
+    debug!(?info, "Probed {host}");
+    Ok(info)
+}
+
+/// Make sure the NixOS system `toplevel` can be deployed to `host`: the host
+/// runs NixOS on the platform the system is built for, has Nix, and likely
+/// has room for the closure.
+pub fn check_nixos_target(host: &str, toplevel: &Path) -> Result<()> {
+    if checks::is_skipped(SkippableCheck::TargetHost) {
+        return Ok(());
+    }
+
+    let info = probe(host).map_err(|err| err.wrap_err(Failure::Environment))?;
+    let fail = |msg: String| {
+        Err(
+            eyre!("{msg}, or skip this check with --skip-check target-host")
+                .wrap_err(Failure::Environment),
+        )
+    };
+
+    let Some(nix_version) = &info.nix_version else {
+        return fail(format!(
+            "Nix isn't installed on {host}, or not in the PATH of non-interactive ssh sessions"
+        ));
+    };
+    info!(
+        "Deploying to {host}: {}, {nix_version}{}",
+        info.system.as_deref().unwrap_or("unknown system"),
+        info.store
+            .as_deref()
+            .map(|store| format!(", store {store}"))
+            .unwrap_or_default()
+    );
+
+    if !info.nixos {
+        return fail(format!(
+            "{host} doesn't run NixOS. Deploy with nh home or nh darwin instead"
+        ));
+    }
+
+    let built_for = std::fs::read_to_string(toplevel.join("system")).ok();
+    if let (Some(built_for), Some(system)) = (built_for.as_deref().map(str::trim), &info.system) {
+        if built_for != system {
+            return fail(format!(
+                "The configuration is built for {built_for}, but {host} is {system}. Set nixpkgs.hostPlatform to {system}"
+            ));
+        }
+    }
+
+    if let Some(free) = info.free_bytes {
+        let closure = generations::closure_sizes(&[toplevel.to_path_buf()])
+            .into_values()
+            .next();
+        if let Some(closure) = closure.filter(|closure| *closure > free) {
+            warn!(
+                "{host} has {} free in /nix/store, less than the {} closure. Paths already on {host} aren't copied again, but the copy may run out of space",
+                format_bytes(free),
+                format_bytes(closure)
+            );
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_probe() {
+        let info = parse(
+            "uname: Linux aarch64
+nixos: yes
+nix: nix (Nix) 2.24.10
+store: auto
+df: /dev/sda2 61255492 30627746 27488628 53% /
+",
+        );
+        assert_eq!(
+            info,
+            HostInfo {
+                system: Some("aarch64-linux".to_string()),
+                nixos: true,
+                nix_version: Some("nix (Nix) 2.24.10".to_string()),
+                store: Some("auto".to_string()),
+                free_bytes: Some(27_488_628 * 1024),
+            }
+        );
+
+        let info = parse("uname: Darwin arm64\nnixos: \nnix: \nstore: \ndf: \n");
+        assert_eq!(info.system.as_deref(), Some("aarch64-darwin"));
+        assert!(!info.nixos);
+        assert_eq!(info.nix_version, None);
+    }
+}
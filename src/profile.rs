@@ -0,0 +1,142 @@
+//! `nh profile`, a wrapper around `nix profile` for packages installed
+//! imperatively.
+//!
+//! Package names are resolved like `nh run` does, from the nixpkgs of the
+//! configuration flake, and the package diff of the profile is printed after
+//! it changed, like after a rebuild.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+
+use crate::commands::{Command, NIX_COMMAND};
+use crate::diff;
+use crate::installable::Installable;
+use crate::interface::{
+    ProfileCommand, ProfileInstallArgs, ProfileListArgs, ProfileRemoveArgs, ProfileUpgradeArgs,
+};
+use crate::output;
+use crate::run::{prebuild, resolve_packages};
+
+/// The profile `nix profile` uses when none is given.
+fn default_profile() -> Result<PathBuf> {
+    let home = PathBuf::from(
+        env::var_os("HOME").ok_or_else(|| eyre!("Couldn't determine home directory"))?,
+    );
+    let nix_profile = home.join(".nix-profile");
+    if nix_profile.symlink_metadata().is_ok() {
+        return Ok(nix_profile);
+    }
+    let state = env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map_or_else(|| home.join(".local/state"), PathBuf::from);
+    Ok(state.join("nix/profiles/profile"))
+}
+
+/// Run `nix profile <subcommand>` on `profile`, then print what changed in
+/// it.
+fn change(
+    profile: Option<&Path>,
+    subcommand: &str,
+    args: Vec<String>,
+    message: &str,
+) -> Result<()> {
+    let path = match profile {
+        Some(profile) => profile.to_path_buf(),
+        None => default_profile()?,
+    };
+    let before = path.canonicalize().ok();
+
+    let mut cmd = Command::new("nix")
+        .args(NIX_COMMAND)
+        .args(["profile", subcommand]);
+    if let Some(profile) = profile {
+        cmd = cmd.arg("--profile").arg(profile);
+    }
+    cmd.args(args)
+        .message(message)
+        .show_output(true)
+        .with_required_env()
+        .run()?;
+
+    if let (Some(before), Ok(after)) = (before, path.canonicalize()) {
+        if before != after && output::human() {
+            diff::print_diff(&before, &after)?;
+        }
+    }
+    Ok(())
+}
+
+impl ProfileCommand {
+    pub fn run(&self) -> Result<()> {
+        match self {
+            Self::Install(args) => args.run(),
+            Self::Remove(args) => args.run(),
+            Self::List(args) => args.run(),
+            Self::Upgrade(args) => args.run(),
+        }
+    }
+}
+
+impl ProfileInstallArgs {
+    fn run(&self) -> Result<()> {
+        let (installables, inputs_from) = resolve_packages(&self.packages);
+        if !self.no_nom {
+            prebuild(&installables, &inputs_from, &self.eval)?;
+        }
+
+        let args = inputs_from
+            .into_iter()
+            .chain(self.eval.generate_eval_args())
+            .chain(installables.iter().flat_map(Installable::to_args))
+            .collect();
+        change(
+            self.profile.as_deref(),
+            "install",
+            args,
+            &format!("Installing {}", self.packages.join(", ")),
+        )
+    }
+}
+
+impl ProfileRemoveArgs {
+    fn run(&self) -> Result<()> {
+        change(
+            self.profile.as_deref(),
+            "remove",
+            self.packages.clone(),
+            &format!("Removing {}", self.packages.join(", ")),
+        )
+    }
+}
+
+impl ProfileUpgradeArgs {
+    fn run(&self) -> Result<()> {
+        let mut args = self.eval.generate_eval_args();
+        if self.packages.is_empty() {
+            args.push("--all".to_string());
+        } else {
+            args.extend(self.packages.iter().cloned());
+        }
+        change(
+            self.profile.as_deref(),
+            "upgrade",
+            args,
+            "Upgrading packages",
+        )
+    }
+}
+
+impl ProfileListArgs {
+    fn run(&self) -> Result<()> {
+        let mut cmd = Command::new("nix")
+            .args(NIX_COMMAND)
+            .args(["profile", "list"]);
+        if let Some(profile) = &self.profile {
+            cmd = cmd.arg("--profile").arg(profile);
+        }
+        cmd.show_output(true).with_required_env().run()
+    }
+}
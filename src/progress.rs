@@ -0,0 +1,264 @@
+//! Progress bars for long `nix copy` and `nix store gc` runs.
+//!
+//! The command is run with `--log-format internal-json`, and its activities
+//! are summed up into a single line on stderr: the paths and bytes copied so
+//! far against the totals nix expects, or the number of paths deleted. Other
+//! messages are printed above the line as nix would print them. The same
+//! progress goes to the `--json-events` stream.
+
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader};
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use subprocess::{Exec, ExitStatus, Redirection};
+use tracing::debug;
+
+use crate::events;
+use crate::theme::{Role, paint};
+use crate::util::format_bytes;
+
+/// Activity of copying a single path
+const ACT_COPY_PATH: u64 = 100;
+/// Activity of copying a closure, made of `ACT_COPY_PATH` activities
+const ACT_COPY_PATHS: u64 = 103;
+const RES_PROGRESS: u64 = 105;
+const RES_SET_EXPECTED: u64 = 106;
+const LVL_INFO: u64 = 3;
+
+const BAR_WIDTH: usize = 30;
+/// How often the progress line is redrawn at most
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// What the activities of a nix command add up to so far.
+#[derive(Debug, Default)]
+struct Progress {
+    /// Activity ids and their types
+    activities: HashMap<u64, u64>,
+    paths_done: u64,
+    paths_expected: u64,
+    /// Bytes copied of each path, by activity
+    bytes_done: HashMap<u64, u64>,
+    bytes_expected: u64,
+    deleted: u64,
+}
+
+/// What a line of `--log-format internal-json` output means for the user.
+#[derive(Debug, PartialEq, Eq)]
+enum Update {
+    /// The progress changed
+    Progress,
+    /// A message to print
+    Message(String),
+    None,
+}
+
+impl Progress {
+    /// Account for a line of nix's log.
+    fn update(&mut self, line: &str) -> Update {
+        let Some(json) = line.strip_prefix("@nix ") else {
+            // Nix writes some errors before its logger is set up
+            return Update::Message(line.to_string());
+        };
+        let Ok(value) = serde_json::from_str::<serde_json::Value>(json) else {
+            return Update::None;
+        };
+        let id = value["id"].as_u64().unwrap_or_default();
+        let field = |index: usize| value["fields"][index].as_u64().unwrap_or_default();
+
+        match value["action"].as_str() {
+            Some("start") => {
+                let kind = value["type"].as_u64().unwrap_or_default();
+                self.activities.insert(id, kind);
+                Update::None
+            }
+            Some("result") => {
+                let kind = self.activities.get(&id).copied();
+                match (kind, value["type"].as_u64()) {
+                    (Some(ACT_COPY_PATHS), Some(RES_PROGRESS)) => {
+                        self.paths_done = field(0);
+                        self.paths_expected = field(1);
+                    }
+                    (Some(ACT_COPY_PATHS), Some(RES_SET_EXPECTED)) if field(0) == ACT_COPY_PATH => {
+                        self.bytes_expected = field(1);
+                    }
+                    (Some(ACT_COPY_PATH), Some(RES_PROGRESS)) => {
+                        self.bytes_done.insert(id, field(0));
+                    }
+                    _ => return Update::None,
+                }
+                Update::Progress
+            }
+            Some("msg") if value["level"].as_u64().unwrap_or_default() <= LVL_INFO => {
+                let msg = value["msg"].as_str().unwrap_or_default();
+                // The garbage collector names every path it deletes
+                if msg.starts_with("deleting '/nix/store/") {
+                    self.deleted += 1;
+                    Update::Progress
+                } else {
+                    Update::Message(msg.to_string())
+                }
+            }
+            _ => Update::None,
+        }
+    }
+
+    /// Emit the progress to the event stream, if there is any.
+    fn emit(&self) {
+        if self.paths_expected > 0 {
+            events::progress(
+                self.paths_done,
+                Some(self.paths_expected),
+                (self.bytes_expected > 0)
+                    .then(|| (self.bytes_done.values().sum(), self.bytes_expected)),
+            );
+        } else if self.deleted > 0 {
+            events::progress(self.deleted, None, None);
+        }
+    }
+
+    /// The progress line, if there is any progress to show.
+    fn line(&self) -> Option<String> {
+        if self.paths_expected > 0 {
+            let bytes_done: u64 = self.bytes_done.values().sum();
+            let ratio = if self.bytes_expected > 0 {
+                bytes_done as f64 / self.bytes_expected as f64
+            } else {
+                self.paths_done as f64 / self.paths_expected as f64
+            };
+            let filled = ((ratio.clamp(0.0, 1.0) * BAR_WIDTH as f64) as usize).min(BAR_WIDTH);
+
+            let mut line = format!(
+                "[{}{}] {}/{} paths",
+                paint("#".repeat(filled), Role::Info),
+                " ".repeat(BAR_WIDTH - filled),
+                self.paths_done,
+                self.paths_expected
+            );
+            if self.bytes_expected > 0 {
+                line.push_str(&format!(
+                    ", {}/{}",
+                    format_bytes(bytes_done),
+                    format_bytes(self.bytes_expected)
+                ));
+            }
+            Some(line)
+        } else if self.deleted > 0 {
+            Some(format!(
+                "{} path{} deleted",
+                self.deleted,
+                if self.deleted == 1 { "" } else { "s" }
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Clear the progress line
+fn clear() {
+    eprint!("\r\x1b[2K");
+}
+
+/// Run a nix command, showing its progress on a single line of stderr if
+/// `draw` is set.
+///
+/// Returns the exit status and the messages nix printed, for error reports.
+pub fn run(cmd: Exec, draw: bool) -> Result<(ExitStatus, String)> {
+    let cmd = cmd
+        .args(&["--log-format", "internal-json"])
+        .stderr(Redirection::Pipe)
+        .stdout(Redirection::None);
+    debug!(?cmd);
+    let mut process = cmd.popen()?;
+
+    let mut progress = Progress::default();
+    let mut messages = Vec::new();
+    let mut drawn = false;
+    let mut last_draw: Option<Instant> = None;
+
+    if let Some(stderr) = process.stderr.take() {
+        for line in BufReader::new(stderr).lines() {
+            let Ok(line) = line else { break };
+
+            match progress.update(&line) {
+                Update::Message(msg) => {
+                    if drawn {
+                        clear();
+                    }
+                    eprintln!("{msg}");
+                    messages.push(msg);
+                    // Redraw right away under the message
+                    last_draw = None;
+                }
+                Update::Progress => {}
+                Update::None => continue,
+            }
+
+            if last_draw.is_none_or(|last| last.elapsed() >= REDRAW_INTERVAL) {
+                progress.emit();
+                last_draw = Some(Instant::now());
+                if let Some(line) = progress.line().filter(|_| draw) {
+                    clear();
+                    eprint!("{line}");
+                    drawn = true;
+                }
+            }
+        }
+    }
+
+    progress.emit();
+    if drawn {
+        clear();
+        if let Some(line) = progress.line() {
+            eprintln!("{line}");
+        }
+    }
+
+    let status = process.wait()?;
+    Ok((status, messages.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_copy_progress() {
+        let mut progress = Progress::default();
+        for line in [
+            r#"@nix {"action":"start","id":1,"level":3,"type":103,"text":"copying 2 paths","fields":[],"parent":0}"#,
+            r#"@nix {"action":"result","id":1,"type":106,"fields":[100,3145728]}"#,
+            r#"@nix {"action":"start","id":2,"level":3,"type":100,"text":"copying path","fields":[],"parent":1}"#,
+            r#"@nix {"action":"result","id":2,"type":105,"fields":[1048576,2097152,0,0]}"#,
+            r#"@nix {"action":"result","id":1,"type":105,"fields":[1,2,1,0]}"#,
+        ] {
+            progress.update(line);
+        }
+
+        assert_eq!(progress.paths_done, 1);
+        assert_eq!(progress.paths_expected, 2);
+        assert_eq!(progress.bytes_done.values().sum::<u64>(), 1_048_576);
+        assert!(progress.line().unwrap().ends_with("1/2 paths, 1 MiB/3 MiB"));
+    }
+
+    #[test]
+    fn test_gc_progress() {
+        let mut progress = Progress::default();
+        assert_eq!(progress.line(), None);
+
+        assert_eq!(
+            progress.update(
+                r#"@nix {"action":"msg","level":3,"msg":"deleting '/nix/store/abc-hello-2.12'"}"#
+            ),
+            Update::Progress
+        );
+        assert_eq!(
+            progress.update(
+                r#"@nix {"action":"msg","level":3,"msg":"1 store paths deleted, 0.1 MiB freed"}"#
+            ),
+            Update::Message("1 store paths deleted, 0.1 MiB freed".to_string())
+        );
+        assert_eq!(progress.line().as_deref(), Some("1 path deleted"));
+    }
+}
@@ -0,0 +1,110 @@
+//! Pushing built closures to a binary cache with `--push-to`, so that other
+//! machines can substitute them instead of building them again.
+
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+
+use crate::commands::Command;
+use crate::events::{self, Phase};
+use crate::interface::CommonRebuildArgs;
+
+/// Where to push a closure to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushTarget {
+    /// A cachix cache, which signs what is pushed itself
+    Cachix(String),
+    /// A Nix store URL like `s3://bucket` or `ssh://host`
+    Store(String),
+}
+
+impl PushTarget {
+    /// Parse `cachix:NAME` or a store URL, for use as a clap value parser.
+    pub fn parse(target: &str) -> Result<Self, String> {
+        match target.strip_prefix("cachix:") {
+            Some("") => Err("missing the cache name after 'cachix:'".to_string()),
+            Some(name) => Ok(Self::Cachix(name.to_string())),
+            None if target.is_empty() => Err("expected cachix:NAME or a store URL".to_string()),
+            None => Ok(Self::Store(target.to_string())),
+        }
+    }
+}
+
+impl std::fmt::Display for PushTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Cachix(name) => write!(f, "cachix:{name}"),
+            Self::Store(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+/// Push the closure of `out_path` to `target`, signing it with `key` first
+/// when pushing to a store URL.
+pub fn push(target: &PushTarget, key: Option<&Path>, out_path: &Path, dry: bool) -> Result<()> {
+    events::phase(Phase::Push, || {
+        push_closure(target, key, out_path, dry).wrap_err(format!("Failed to push to {target}"))
+    })
+}
+
+fn push_closure(target: &PushTarget, key: Option<&Path>, out_path: &Path, dry: bool) -> Result<()> {
+    match target {
+        PushTarget::Cachix(name) => Command::new("cachix")
+            .arg("push")
+            .arg(name)
+            .arg(out_path)
+            .message(format!("Pushing to {target}"))
+            .dry(dry)
+            .run(),
+        PushTarget::Store(url) => {
+            if let Some(key) = key {
+                Command::new("nix")
+                    .args(["store", "sign", "--recursive", "--key-file"])
+                    .arg(key)
+                    .arg(out_path)
+                    .message("Signing the closure")
+                    .dry(dry)
+                    .run()?;
+            }
+
+            Command::new("nix")
+                .args(["copy", "--to", url])
+                .arg(out_path)
+                .message(format!("Pushing to {target}"))
+                .progress(true)
+                .dry(dry)
+                .run()
+        }
+    }
+}
+
+impl CommonRebuildArgs {
+    /// Push the closure of `out_path` to the cache given with `--push-to`,
+    /// if any.
+    pub fn push_closure(&self, out_path: &Path) -> Result<()> {
+        match &self.push_to {
+            Some(target) => push(target, self.push_key.as_deref(), out_path, self.dry),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_push_target() {
+        assert_eq!(
+            PushTarget::parse("cachix:my-fleet"),
+            Ok(PushTarget::Cachix("my-fleet".to_string()))
+        );
+        assert_eq!(
+            PushTarget::parse("s3://cache?region=eu-west-1"),
+            Ok(PushTarget::Store("s3://cache?region=eu-west-1".to_string()))
+        );
+        assert!(PushTarget::parse("cachix:").is_err());
+        assert!(PushTarget::parse("").is_err());
+    }
+}
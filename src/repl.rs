@@ -0,0 +1,153 @@
+//! `nh os repl`, `nh home repl` and `nh darwin repl`.
+//!
+//! Instead of loading the configuration itself, the REPL is started with a
+//! small wrapper expression around it, so that `lib` is in scope next to
+//! `config`, `options` and `pkgs`, and for flakes the flake itself as `self`
+//! and its `inputs` as well. `--bare` loads the configuration as it is.
+
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use tracing::{debug, info};
+
+use crate::commands::Command;
+use crate::generations::local_flake_dir;
+use crate::installable::Installable;
+use crate::interface::NixEvalArgs;
+use crate::nixos::nix_string;
+
+/// The selection of `attribute` in Nix syntax, like `."foo"."bar"`.
+fn select(attribute: &[String]) -> String {
+    attribute
+        .iter()
+        .map(|elem| format!(".{}", nix_string(elem)))
+        .collect()
+}
+
+/// `value` called with its default arguments if it is a function, like nix
+/// does for `--file` and `--expr`.
+fn auto_call(value: &str) -> String {
+    format!("(let value = {value}; in if builtins.isFunction value then value {{ }} else value)")
+}
+
+/// A Nix expression evaluating to the configuration `installable` with
+/// convenient bindings added, or `None` if there is no such expression.
+#[must_use]
+pub fn context_expr(installable: &Installable) -> Option<String> {
+    let (system, flake) = match installable {
+        Installable::Flake {
+            reference,
+            attribute,
+        } => {
+            // `builtins.getFlake` doesn't accept relative paths
+            let reference = match local_flake_dir(reference) {
+                Some(dir) => dir.canonicalize().ok()?.to_string_lossy().into_owned(),
+                None => reference.clone(),
+            };
+            (
+                format!("self{}", select(attribute)),
+                Some(format!("builtins.getFlake {}", nix_string(&reference))),
+            )
+        }
+        Installable::File { path, attribute } => {
+            let path = path.canonicalize().ok()?;
+            let imported = format!("import {}", nix_string(&path.to_string_lossy()));
+            (
+                format!("{}{}", auto_call(&imported), select(attribute)),
+                None,
+            )
+        }
+        Installable::Expression {
+            expression,
+            attribute,
+        } => (
+            format!(
+                "{}{}",
+                auto_call(&format!("({expression})")),
+                select(attribute)
+            ),
+            None,
+        ),
+        Installable::Store { .. } | Installable::System { .. } => return None,
+    };
+
+    Some(match flake {
+        Some(flake) => format!(
+            "let self = {flake}; system = {system}; in \
+             system // {{ lib = system.lib or system.pkgs.lib; inherit self; inherit (self) inputs; }}"
+        ),
+        None => format!(
+            "let system = {system}; in system // {{ lib = system.lib or system.pkgs.lib; }}"
+        ),
+    })
+}
+
+/// Start `nix repl` with the configuration `installable`.
+pub fn run(
+    installable: &Installable,
+    eval: &NixEvalArgs,
+    extra_args: &[String],
+    bare: bool,
+) -> Result<()> {
+    if matches!(
+        installable,
+        Installable::Store { .. } | Installable::System { .. }
+    ) {
+        bail!("Nix doesn't support nix store installables.");
+    }
+
+    // `builtins.getFlake` doesn't see overridden inputs
+    let overridden = !eval.override_input.is_empty() || !eval.override_flake.is_empty();
+    let context = (!bare && !overridden)
+        .then(|| context_expr(installable))
+        .flatten();
+    debug!(?context);
+
+    let target = match context {
+        Some(expr) => {
+            info!("Loading the configuration with lib, config, options and pkgs in scope");
+            vec!["--expr".to_string(), expr]
+        }
+        None => installable.to_args(),
+    };
+
+    Command::new("nix")
+        .with_required_env()
+        .arg("repl")
+        .args(target)
+        .args(eval.generate_eval_args())
+        .args(extra_args)
+        .show_output(true)
+        .run()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn test_context_expr() {
+        let remote = Installable::Flake {
+            reference: "github:owner/repo".to_string(),
+            attribute: vec!["nixosConfigurations".to_string(), "my.host".to_string()],
+        };
+        let expr = context_expr(&remote).unwrap();
+        assert!(expr.contains(r#"self = builtins.getFlake "github:owner/repo";"#));
+        assert!(expr.contains(r#"system = self."nixosConfigurations"."my.host";"#));
+        assert!(expr.contains("inherit (self) inputs;"));
+
+        let expression = Installable::Expression {
+            expression: "import <nixpkgs/nixos> { }".to_string(),
+            attribute: vec![],
+        };
+        let expr = context_expr(&expression).unwrap();
+        assert!(expr.contains("(import <nixpkgs/nixos> { })"));
+        assert!(!expr.contains("inputs"));
+
+        let store = Installable::Store {
+            path: PathBuf::from("/nix/store/aaa-nixos-system"),
+        };
+        assert_eq!(context_expr(&store), None);
+    }
+}
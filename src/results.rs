@@ -0,0 +1,164 @@
+//! Results of rebuilds kept under nh's state directory.
+//!
+//! With `--keep-results`, a rebuild links its result to
+//! `results/<name>-<timestamp>` in the state directory instead of a
+//! temporary directory, where the name is made of the platform and the host,
+//! like `nixos-laptop`. Results of the same second get a `.<n>` suffix. The
+//! links are garbage collector roots, so a recent build can be inspected,
+//! diffed against or activated without building it again. Only the newest
+//! links of each name are kept, pruned once a build succeeded.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{Local, NaiveDateTime};
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use tracing::debug;
+
+use crate::state;
+
+const TIMESTAMP_FORMAT: &str = "%Y%m%d-%H%M%S";
+
+/// The directory results are kept in.
+pub fn dir() -> Result<PathBuf> {
+    Ok(state::state_dir()?.join("results"))
+}
+
+/// The links to results of `name` in `dir`, oldest first.
+fn links_in(dir: &Path, name: &str) -> Vec<PathBuf> {
+    let prefix = format!("{name}-");
+    let mut links: Vec<(NaiveDateTime, u32, PathBuf)> = fs::read_dir(dir)
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| {
+            let entry = entry.ok()?;
+            let file_name = entry.file_name();
+            // Names of other hosts may start with this one, but then what
+            // follows isn't a timestamp
+            let timestamp = file_name.to_str()?.strip_prefix(&prefix)?;
+            let (timestamp, counter) = match timestamp.split_once('.') {
+                Some((timestamp, counter)) => (timestamp, counter.parse().ok()?),
+                None => (timestamp, 0),
+            };
+            let time = NaiveDateTime::parse_from_str(timestamp, TIMESTAMP_FORMAT).ok()?;
+            Some((time, counter, entry.path()))
+        })
+        .collect();
+    links.sort();
+    links.into_iter().map(|(_, _, path)| path).collect()
+}
+
+/// The store path of the newest kept result of `name`, if it still exists.
+#[must_use]
+pub fn latest(name: &str) -> Option<PathBuf> {
+    let dir = dir().ok()?;
+    links_in(&dir, name)
+        .into_iter()
+        .rev()
+        .find_map(|link| link.canonicalize().ok())
+}
+
+/// Remove the links to results of `name` in `dir`, but the newest `keep`.
+fn prune_in(dir: &Path, name: &str, keep: usize) -> Result<()> {
+    let links = links_in(dir, name);
+    for link in &links[..links.len().saturating_sub(keep)] {
+        debug!("Removing old result {}", link.display());
+        fs::remove_file(link)
+            .wrap_err_with(|| format!("Failed to remove old result {}", link.display()))?;
+    }
+    Ok(())
+}
+
+/// Remove the links to results of `name`, but the newest `keep`. Called once
+/// the build linked by [`new_link`] succeeded, so that a failed build doesn't
+/// cost a good result.
+pub fn prune(name: &str, keep: usize) -> Result<()> {
+    prune_in(&dir()?, name, keep)
+}
+
+/// A new link for a result of `name`.
+pub fn new_link(name: &str) -> Result<PathBuf> {
+    let dir = dir()?;
+    fs::create_dir_all(&dir).wrap_err_with(|| format!("Failed to create {}", dir.display()))?;
+    Ok(new_link_in(&dir, name, Local::now().naive_local()))
+}
+
+/// A link for a result of `name` in `dir` at `time` that isn't taken yet.
+fn new_link_in(dir: &Path, name: &str, time: NaiveDateTime) -> PathBuf {
+    let base = format!("{name}-{}", time.format(TIMESTAMP_FORMAT));
+    let mut link = dir.join(&base);
+    let mut counter = 0;
+    while link.symlink_metadata().is_ok() {
+        counter += 1;
+        link = dir.join(format!("{base}.{counter}"));
+    }
+    link
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    #[test]
+    fn test_prune_results() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in [
+            "nixos-web-20240101-120000",
+            "nixos-web-20240301-120000",
+            "nixos-web-20240201-120000",
+            "nixos-web-1-20240101-120000",
+            "nixos-web-latest",
+        ] {
+            symlink("/nix/store/aaa-nixos-system", dir.path().join(name)).unwrap();
+        }
+
+        let links = links_in(dir.path(), "nixos-web");
+        assert_eq!(
+            links,
+            [
+                dir.path().join("nixos-web-20240101-120000"),
+                dir.path().join("nixos-web-20240201-120000"),
+                dir.path().join("nixos-web-20240301-120000"),
+            ]
+        );
+
+        prune_in(dir.path(), "nixos-web", 1).unwrap();
+        assert_eq!(
+            links_in(dir.path(), "nixos-web"),
+            [dir.path().join("nixos-web-20240301-120000")]
+        );
+        assert_eq!(links_in(dir.path(), "nixos-web-1").len(), 1);
+    }
+
+    #[test]
+    fn test_new_link_same_second() {
+        let dir = tempfile::tempdir().unwrap();
+        let time = NaiveDateTime::parse_from_str("20240401-120000", TIMESTAMP_FORMAT).unwrap();
+        let first = new_link_in(dir.path(), "nixos-web", time);
+        assert_eq!(first, dir.path().join("nixos-web-20240401-120000"));
+        symlink("/nix/store/aaa-nixos-system", &first).unwrap();
+
+        let second = new_link_in(dir.path(), "nixos-web", time);
+        assert_eq!(second, dir.path().join("nixos-web-20240401-120000.1"));
+        symlink("/nix/store/bbb-nixos-system", &second).unwrap();
+        symlink(
+            "/nix/store/ccc-nixos-system",
+            dir.path().join("nixos-web-20240301-120000"),
+        )
+        .unwrap();
+
+        assert_eq!(
+            links_in(dir.path(), "nixos-web"),
+            [
+                dir.path().join("nixos-web-20240301-120000"),
+                first,
+                second.clone(),
+            ]
+        );
+        prune_in(dir.path(), "nixos-web", 1).unwrap();
+        assert_eq!(links_in(dir.path(), "nixos-web"), [second]);
+    }
+}
@@ -0,0 +1,179 @@
+//! `nh run`, `nh shell` and `nh develop`, thin wrappers around `nix run`,
+//! `nix shell` and `nix develop`.
+//!
+//! Package names like `hello` are taken from the `nixpkgs` input of the
+//! configuration flake (`NH_FLAKE`, or the closest `flake.nix`), so that ad-hoc
+//! programs come from the same nixpkgs as the system. Flake installables like
+//! `.#tool` or `github:owner/repo` are used as is. Packages are built with nom
+//! first, and nix then replaces nh so that the program gets the terminal and
+//! its exit code is nh's.
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+
+use crate::commands::{self, Command, NIX_COMMAND};
+use crate::installable::{self, Installable, parse_attribute};
+use crate::interface::{DevelopArgs, NixEvalArgs, RunArgs, ShellArgs};
+
+/// The installable for `package`, and whether it is a nixpkgs attribute.
+fn package_installable(package: &str) -> (Installable, bool) {
+    let is_flake = package.contains('#')
+        || package.contains(':')
+        || package.starts_with('.')
+        || package.starts_with('/');
+
+    if is_flake {
+        let mut elems = package.splitn(2, '#');
+        let reference = elems.next().unwrap_or_default().to_string();
+        let attribute = elems.next().map(parse_attribute).unwrap_or_default();
+        (
+            Installable::Flake {
+                reference,
+                attribute,
+            },
+            false,
+        )
+    } else {
+        (
+            Installable::Flake {
+                reference: "nixpkgs".to_string(),
+                attribute: parse_attribute(package),
+            },
+            true,
+        )
+    }
+}
+
+/// Resolve `packages`, along with the `--inputs-from` arguments that make
+/// `nixpkgs` refer to the input of the configuration flake, if any of them
+/// need it.
+pub fn resolve_packages(packages: &[String]) -> (Vec<Installable>, Vec<String>) {
+    let mut from_nixpkgs = false;
+    let installables = packages
+        .iter()
+        .map(|package| {
+            let (installable, nixpkgs) = package_installable(package);
+            from_nixpkgs |= nixpkgs;
+            installable
+        })
+        .collect();
+
+    let inputs_from = match installable::default_flake() {
+        Some(flake) if from_nixpkgs => vec!["--inputs-from".to_string(), flake],
+        _ => vec![],
+    };
+    (installables, inputs_from)
+}
+
+/// Build `installables` with nom, so that the wrapped command finds them in
+/// the store and only has to start.
+pub fn prebuild(
+    installables: &[Installable],
+    extra_args: &[String],
+    eval: &NixEvalArgs,
+) -> Result<()> {
+    for installable in installables {
+        let name = installable.to_args().join(" ");
+        commands::Build::new(installable.clone())
+            .extra_arg("--no-link")
+            .extra_args(extra_args)
+            .eval_args(eval)
+            .message(format!("Building {name}"))
+            .nom(true)
+            .run()
+            .wrap_err(format!("Failed to build {name}"))?;
+    }
+    Ok(())
+}
+
+impl RunArgs {
+    pub fn run(&self) -> Result<()> {
+        let (installables, inputs_from) = resolve_packages(std::slice::from_ref(&self.package));
+        let [installable] = installables.as_slice() else {
+            bail!("Expected a single package to run");
+        };
+
+        // Flake installables may well be apps, which can't be built
+        if !self.no_nom && !inputs_from.is_empty() {
+            prebuild(&installables, &inputs_from, &self.eval)?;
+        }
+
+        Command::new("nix")
+            .args(NIX_COMMAND)
+            .arg("run")
+            .args(&inputs_from)
+            .args(self.eval.generate_eval_args())
+            .args(installable.to_args())
+            .arg("--")
+            .args(&self.args)
+            .with_required_env()
+            .exec()
+    }
+}
+
+impl ShellArgs {
+    pub fn run(&self) -> Result<()> {
+        let (installables, inputs_from) = resolve_packages(&self.packages);
+
+        if !self.no_nom {
+            prebuild(&installables, &inputs_from, &self.eval)?;
+        }
+
+        let mut cmd = Command::new("nix")
+            .args(NIX_COMMAND)
+            .arg("shell")
+            .args(&inputs_from)
+            .args(self.eval.generate_eval_args())
+            .args(installables.iter().flat_map(Installable::to_args));
+        if !self.command.is_empty() {
+            cmd = cmd.arg("--command").args(&self.command);
+        }
+        cmd.with_required_env().exec()
+    }
+}
+
+impl DevelopArgs {
+    pub fn run(&self) -> Result<()> {
+        if matches!(self.installable, Installable::Store { .. }) {
+            bail!("nix develop doesn't support store paths");
+        }
+
+        let mut cmd = Command::new("nix")
+            .args(NIX_COMMAND)
+            .arg("develop")
+            .args(self.eval.generate_eval_args())
+            .args(self.installable.to_args());
+        if !self.command.is_empty() {
+            cmd = cmd.arg("--command").args(&self.command);
+        }
+        cmd.with_required_env().exec()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_package_installable() {
+        let args = |package| {
+            let (installable, nixpkgs) = package_installable(package);
+            (installable.to_args(), nixpkgs)
+        };
+
+        assert_eq!(args("hello"), (vec!["nixpkgs#hello".to_string()], true));
+        assert_eq!(
+            args("python3Packages.requests"),
+            (vec!["nixpkgs#python3Packages.requests".to_string()], true)
+        );
+        assert_eq!(args(".#tool"), (vec![".#tool".to_string()], false));
+        assert_eq!(
+            args("github:owner/repo"),
+            (vec!["github:owner/repo#".to_string()], false)
+        );
+        assert_eq!(
+            args("/etc/nixos#hello"),
+            (vec!["/etc/nixos#hello".to_string()], false)
+        );
+    }
+}
@@ -1,5 +1,6 @@
+use std::collections::BTreeMap;
 use std::process::Stdio;
-use std::time::Instant;
+use std::time::{Duration, Instant, SystemTime};
 
 use color_eyre::eyre::{Context, bail};
 use elasticsearch_dsl::{Operator, Query, Search, SearchResponse, TextQueryType};
@@ -8,58 +9,131 @@ use regex::Regex;
 use serde::{Deserialize, Serialize};
 use tracing::{debug, trace, warn};
 
+use crate::json::{self, Output};
+use crate::template::Fields;
+use crate::theme::{Role, paint};
 use crate::{Result, interface};
 
 // List of deprecated NixOS versions
 // Add new versions as they become deprecated.
 const DEPRECATED_VERSIONS: &[&str] = &["nixos-24.05"];
 
+pub const DEFAULT_CHANNEL: &str = "nixos-unstable";
+
+/// Packages seen in search results, for completing `nh search`
+const CACHE_FILE: &str = "search-cache.json";
+
+/// Packages kept per channel, the ones seen longest ago are evicted first
+const CACHE_MAX_PACKAGES: usize = 5000;
+
+/// Packages no search returned for this long are evicted
+const CACHE_MAX_AGE: Duration = Duration::from_secs(90 * 24 * 60 * 60);
+
+/// A package seen in search results
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+struct CachedPackage {
+    description: String,
+    /// Seconds since the epoch of the last search that returned it
+    seen: u64,
+}
+
+/// The packages seen in each channel, by attribute name
+type PackageCache = BTreeMap<String, BTreeMap<String, CachedPackage>>;
+
 #[derive(Debug, Deserialize, Serialize)]
 #[allow(non_snake_case, dead_code)]
-struct SearchResult {
+pub struct SearchResult {
     // r#type: String,
-    package_attr_name: String,
+    pub(crate) package_attr_name: String,
     package_attr_set: String,
     package_pname: String,
-    package_pversion: String,
-    package_platforms: Vec<String>,
+    pub(crate) package_pversion: String,
+    pub(crate) package_platforms: Vec<String>,
     package_outputs: Vec<String>,
     package_default_output: Option<String>,
-    package_programs: Vec<String>,
+    pub(crate) package_programs: Vec<String>,
     // package_license: Vec<License>,
     package_license_set: Vec<String>,
     // package_maintainers: Vec<HashMap<String, String>>,
-    package_description: Option<String>,
+    pub(crate) package_description: Option<String>,
     package_longDescription: Option<String>,
     package_hydra: (),
     package_system: String,
-    package_homepage: Vec<String>,
+    pub(crate) package_homepage: Vec<String>,
     package_position: Option<String>,
 }
 
+impl Fields for SearchResult {
+    const FIELDS: &'static [&'static str] = &[
+        "attr",
+        "pname",
+        "version",
+        "description",
+        "homepage",
+        "platforms",
+        "programs",
+        "license",
+        "position",
+    ];
+
+    fn field(&self, name: &str) -> Option<String> {
+        match name {
+            "attr" => Some(self.package_attr_name.clone()),
+            "pname" => Some(self.package_pname.clone()),
+            "version" => Some(self.package_pversion.clone()),
+            "description" => self
+                .package_description
+                .as_ref()
+                .map(|desc| desc.replace('\n', " ")),
+            "homepage" => self.package_homepage.first().cloned(),
+            "platforms" => Some(self.package_platforms.join(",")),
+            "programs" => Some(self.package_programs.join(",")),
+            "license" => Some(self.package_license_set.join(",")),
+            "position" => self.package_position.clone(),
+            _ => None,
+        }
+    }
+}
+
 macro_rules! print_hyperlink {
     ($text:expr, $link:expr) => {
         print!("\x1b]8;;{}\x07", $link);
-        print!("{}", $text.underline());
+        print!("{}", paint($text, Role::Link));
         println!("\x1b]8;;\x07");
     };
 }
 
 #[derive(Debug, Serialize)]
-struct JSONOutput {
-    query: String,
-    channel: String,
-    elapsed_ms: u128,
-    results: Vec<SearchResult>,
+pub struct SearchOutput {
+    pub query: String,
+    pub channel: String,
+    pub elapsed_ms: u128,
+    pub results: Vec<SearchResult>,
 }
 
 impl SearchArgs {
     pub fn run(&self) -> Result<()> {
         trace!("args: {self:?}");
 
-        if !supported_branch(&self.channel) {
-            bail!("Channel {} is not supported!", self.channel);
+        if let Some(interface::SearchSubcommand::Index(command)) = &self.subcommand {
+            return command.run();
+        }
+        if let Some(program) = &self.program {
+            return search_program(program, self.index_max_age.map(Into::into));
+        }
+
+        // `-j` and NH_SEARCH_JSON predate the global --json
+        if self.legacy_json {
+            warn!("`nh search -j` and NH_SEARCH_JSON are deprecated, use --json or NH_JSON");
+            json::enable_output();
+        }
+        let json_output = json::output_enabled();
+
+        if let Some(template) = &self.format {
+            template.check::<SearchResult>()?;
         }
+        // Progress messages would get in the way of machine-readable output
+        let quiet = json_output || self.format.is_some();
 
         let nixpkgs_path = std::thread::spawn(|| {
             std::process::Command::new("nix")
@@ -68,6 +142,97 @@ impl SearchArgs {
                 .output()
         });
 
+        let output = self.fetch(quiet)?;
+
+        if json_output {
+            // Output as JSON
+            return json::emit(&Output::Search(output));
+        }
+        let documents = output.results;
+
+        if let Some(template) = &self.format {
+            return template.print_all(documents.iter().rev());
+        }
+
+        let hyperlinks = supports_hyperlinks::supports_hyperlinks();
+        debug!(?hyperlinks);
+
+        let nixpkgs_path = String::from_utf8(
+            nixpkgs_path
+                .join()
+                .unwrap()
+                .context("Evaluating the nixpkgs path location")?
+                .stdout,
+        )
+        .unwrap();
+
+        for elem in documents.iter().rev() {
+            println!();
+            trace!("{elem:#?}");
+
+            print!("{}", paint(&elem.package_attr_name, Role::Name));
+            let v = &elem.package_pversion;
+            if !v.is_empty() {
+                print!(" ({})", paint(v, Role::Value));
+            }
+
+            println!();
+
+            if let Some(ref desc) = elem.package_description {
+                let desc = desc.replace('\n', " ");
+                for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
+                    println!("  {line}");
+                }
+            }
+
+            for url in &elem.package_homepage {
+                print!("  Homepage: ");
+                if hyperlinks {
+                    print_hyperlink!(url, url);
+                } else {
+                    println!("{url}");
+                }
+            }
+
+            if self.platforms && !elem.package_platforms.is_empty() {
+                println!("  Platforms: {}", elem.package_platforms.join(", "));
+            }
+
+            if let Some(position) = &elem.package_position {
+                let position = position.split(':').next().unwrap();
+                print!("  Defined at: ");
+                if hyperlinks {
+                    let position_trimmed = position
+                        .split(':')
+                        .next()
+                        .expect("Removing line number from position");
+
+                    print_hyperlink!(
+                        position,
+                        format!("file://{nixpkgs_path}/{position_trimmed}")
+                    );
+                } else {
+                    println!("{position}");
+                }
+            }
+
+            println!(
+                "  Install: {}",
+                paint(
+                    format!("nh profile install nixpkgs#{}", elem.package_attr_name),
+                    Role::Literal
+                )
+            );
+        }
+
+        Ok(())
+    }
+    /// Query search.nixos.org, printing progress unless `quiet`.
+    pub(crate) fn fetch(&self, quiet: bool) -> Result<SearchOutput> {
+        if !supported_branch(&self.channel) {
+            bail!("Channel {} is not supported!", self.channel);
+        }
+
         let query_s = self.query.join(" ");
         debug!(?query_s);
 
@@ -105,7 +270,7 @@ impl SearchArgs {
             ),
         );
 
-        if !self.json {
+        if !quiet {
             println!(
                 "Querying search.nixos.org, with channel {}...",
                 self.channel
@@ -139,7 +304,7 @@ impl SearchArgs {
         debug!(?elapsed);
         trace!(?response);
 
-        if !self.json {
+        if !quiet {
             println!("Took {}ms", elapsed.as_millis());
             println!("Most relevant results at the end");
             println!();
@@ -154,91 +319,35 @@ impl SearchArgs {
             .documents::<SearchResult>()
             .context("parsing search document")?;
 
-        if self.json {
-            // Output as JSON
-            let json_output = JSONOutput {
-                query: query_s,
-                channel: self.channel.clone(),
-                elapsed_ms: elapsed.as_millis(),
-                results: documents,
-            };
-
-            println!("{}", serde_json::to_string_pretty(&json_output)?);
-            return Ok(());
+        if let Err(err) = remember_packages(&self.channel, &documents) {
+            debug!("Failed to update the search cache: {err:#}");
         }
 
-        let hyperlinks = supports_hyperlinks::supports_hyperlinks();
-        debug!(?hyperlinks);
-
-        let nixpkgs_path = String::from_utf8(
-            nixpkgs_path
-                .join()
-                .unwrap()
-                .context("Evaluating the nixpkgs path location")?
-                .stdout,
-        )
-        .unwrap();
-
-        for elem in documents.iter().rev() {
-            println!();
-            use owo_colors::OwoColorize;
-            trace!("{elem:#?}");
-
-            print!("{}", elem.package_attr_name.blue());
-            let v = &elem.package_pversion;
-            if !v.is_empty() {
-                print!(" ({})", v.green());
-            }
-
-            println!();
-
-            if let Some(ref desc) = elem.package_description {
-                let desc = desc.replace('\n', " ");
-                for line in textwrap::wrap(&desc, textwrap::Options::with_termwidth()) {
-                    println!("  {line}");
-                }
-            }
-
-            for url in &elem.package_homepage {
-                print!("  Homepage: ");
-                if hyperlinks {
-                    print_hyperlink!(url, url);
-                } else {
-                    println!("{url}");
-                }
-            }
-
-            if self.platforms && !elem.package_platforms.is_empty() {
-                println!("  Platforms: {}", elem.package_platforms.join(", "));
-            }
-
-            if let Some(position) = &elem.package_position {
-                let position = position.split(':').next().unwrap();
-                print!("  Defined at: ");
-                if hyperlinks {
-                    let position_trimmed = position
-                        .split(':')
-                        .next()
-                        .expect("Removing line number from position");
-
-                    print_hyperlink!(
-                        position,
-                        format!("file://{nixpkgs_path}/{position_trimmed}")
-                    );
-                } else {
-                    println!("{position}");
-                }
-            }
-        }
+        Ok(SearchOutput {
+            query: query_s,
+            channel: self.channel.clone(),
+            elapsed_ms: elapsed.as_millis(),
+            results: documents,
+        })
+    }
+}
 
-        Ok(())
+/// Print the packages providing `program`, one attribute per line.
+fn search_program(program: &str, index_max_age: Option<std::time::Duration>) -> Result<()> {
+    let attrs = crate::nix_index::locate_program(program, index_max_age)?;
+    if attrs.is_empty() {
+        bail!("No package in the nix-index database provides {program}");
     }
+    for attr in attrs {
+        println!("{}", paint(attr, Role::Name));
+    }
+    Ok(())
 }
 
 fn supported_branch<S: AsRef<str>>(branch: S) -> bool {
     let branch = branch.as_ref();
 
-    if branch == "nixos-unstable" {
+    if branch == DEFAULT_CHANNEL {
         return true;
     }
 
@@ -252,6 +361,66 @@ fn supported_branch<S: AsRef<str>>(branch: S) -> bool {
     re.is_match(branch)
 }
 
+/// Add the packages of a search to the cache.
+fn remember_packages(channel: &str, documents: &[SearchResult]) -> Result<()> {
+    // A cache nh can't read, like one of an older version, is started over
+    let mut cache: PackageCache = crate::state::load(CACHE_FILE).unwrap_or_default();
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let packages = cache.entry(channel.to_string()).or_default();
+    for document in documents {
+        packages.insert(
+            document.package_attr_name.clone(),
+            CachedPackage {
+                description: document
+                    .package_description
+                    .as_deref()
+                    .unwrap_or_default()
+                    .replace('\n', " "),
+                seen: now,
+            },
+        );
+    }
+    evict(&mut cache, now);
+    crate::state::save(CACHE_FILE, &cache)
+}
+
+/// Drop the packages of `cache` last seen more than [`CACHE_MAX_AGE`] before
+/// `now`, and the ones seen longest ago beyond [`CACHE_MAX_PACKAGES`] per
+/// channel.
+fn evict(cache: &mut PackageCache, now: u64) {
+    for packages in cache.values_mut() {
+        packages.retain(|_, package| now.saturating_sub(package.seen) <= CACHE_MAX_AGE.as_secs());
+
+        let excess = packages.len().saturating_sub(CACHE_MAX_PACKAGES);
+        if excess > 0 {
+            let mut by_age: Vec<(u64, String)> = packages
+                .iter()
+                .map(|(name, package)| (package.seen, name.clone()))
+                .collect();
+            by_age.sort();
+            for (_, name) in by_age.into_iter().take(excess) {
+                packages.remove(&name);
+            }
+        }
+    }
+    cache.retain(|_, packages| !packages.is_empty());
+}
+
+/// Attribute names and descriptions of the packages previously found in
+/// `channel`, without querying anything.
+#[must_use]
+pub fn cached_packages(channel: &str) -> BTreeMap<String, String> {
+    crate::state::load::<PackageCache>(CACHE_FILE)
+        .ok()
+        .and_then(|mut cache| cache.remove(channel))
+        .unwrap_or_default()
+        .into_iter()
+        .map(|(name, package)| (name, package.description))
+        .collect()
+}
+
 #[test]
 fn test_supported_branch() {
     assert!(supported_branch("nixos-unstable"));
@@ -262,3 +431,35 @@ fn test_supported_branch() {
     assert!(!supported_branch("nixpkgs-darwin"));
     assert!(!supported_branch("nixpks-21.11-darwin"));
 }
+
+#[test]
+fn test_evict_cache() {
+    let now = CACHE_MAX_AGE.as_secs() * 2;
+    let package = |seen| CachedPackage {
+        description: String::new(),
+        seen,
+    };
+
+    let mut cache = PackageCache::new();
+    cache.insert(
+        "nixos-24.11".to_string(),
+        BTreeMap::from([("hello".to_string(), package(1))]),
+    );
+    let unstable = cache.entry(DEFAULT_CHANNEL.to_string()).or_default();
+    unstable.insert(
+        "old".to_string(),
+        package(now - CACHE_MAX_AGE.as_secs() - 1),
+    );
+    for seen in 0..CACHE_MAX_PACKAGES as u64 + 2 {
+        unstable.insert(format!("package-{seen}"), package(now - seen));
+    }
+
+    evict(&mut cache, now);
+    let unstable = &cache[DEFAULT_CHANNEL];
+    assert_eq!(unstable.len(), CACHE_MAX_PACKAGES);
+    assert!(!unstable.contains_key("old"));
+    assert!(unstable.contains_key("package-0"));
+    assert!(!unstable.contains_key(&format!("package-{}", CACHE_MAX_PACKAGES + 1)));
+    // Channels without a package left are dropped
+    assert!(!cache.contains_key("nixos-24.11"));
+}
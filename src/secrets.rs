@@ -0,0 +1,185 @@
+//! Preflight for configurations that use sops-nix or agenix.
+//!
+//! Both decrypt their secrets during activation, with a key that has to exist
+//! on the machine already. If it doesn't, the switch goes through but every
+//! service depending on a secret fails to start. With `--check-secrets`, nh
+//! evaluates which keys the configuration decrypts with before switching,
+//! and makes sure at least one of them exists on the target.
+
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::eyre;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::checks::{self, SkippableCheck};
+use crate::commands::Command;
+use crate::exit::Failure;
+use crate::installable::Installable;
+
+/// Applied to the `config` of the system to find the keys each tool
+/// decrypts with, `null` for the tools without any secrets.
+const KEYS_EXPR: &str = r"config: {
+  sops =
+    if (config.sops.secrets or { }) != { } then {
+      keyFile = config.sops.age.keyFile or null;
+      generateKey = config.sops.age.generateKey or false;
+      sshKeyPaths = (config.sops.age.sshKeyPaths or [ ]) ++ (config.sops.gnupg.sshKeyPaths or [ ]);
+    } else null;
+  agenix =
+    if (config.age.secrets or { }) != { } then {
+      identityPaths = config.age.identityPaths or [ ];
+    } else null;
+}";
+
+#[derive(Debug, Default, Deserialize)]
+struct SecretKeys {
+    sops: Option<SopsKeys>,
+    agenix: Option<AgenixKeys>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SopsKeys {
+    key_file: Option<String>,
+    generate_key: bool,
+    ssh_key_paths: Vec<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct AgenixKeys {
+    identity_paths: Vec<String>,
+}
+
+impl SecretKeys {
+    /// The tools in use with the keys they can decrypt with, any of which is
+    /// enough.
+    fn required(&self) -> Vec<(&'static str, Vec<String>)> {
+        let mut required = Vec::new();
+        // A generated key is created on activation if it is missing
+        if let Some(sops) = self.sops.as_ref().filter(|sops| !sops.generate_key) {
+            let keys = sops
+                .key_file
+                .iter()
+                .chain(&sops.ssh_key_paths)
+                .cloned()
+                .collect();
+            required.push(("sops-nix", keys));
+        }
+        if let Some(agenix) = &self.agenix {
+            required.push(("agenix", agenix.identity_paths.clone()));
+        }
+        required
+    }
+}
+
+/// Whether `path` exists on `target_host`, or locally. Keys usually aren't
+/// readable by the user, so the check is elevated when needed.
+fn key_exists(path: &str, target_host: Option<&str>, elevate: bool) -> bool {
+    if target_host.is_none() {
+        if let Ok(exists) = Path::new(path).try_exists() {
+            return exists;
+        }
+    }
+
+    Command::new("test")
+        .args(["-e", path])
+        .ssh(target_host.map(String::from))
+        .elevate(elevate)
+        .run()
+        .is_ok()
+}
+
+/// Make sure that the keys the sops-nix and agenix secrets of `config` are
+/// decrypted with exist on `target_host`, or locally, before activating it.
+///
+/// If the configuration can't be evaluated, the check is skipped and the
+/// build is left to report the problem.
+pub fn preflight<I>(
+    config: &Installable,
+    eval_args: I,
+    target_host: Option<&str>,
+    elevate: bool,
+) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<std::ffi::OsStr>,
+{
+    if checks::is_skipped(SkippableCheck::Secrets) {
+        return Ok(());
+    }
+
+    let keys = Command::new("nix")
+        .with_required_env()
+        .args(["eval", "--json", "--apply", KEYS_EXPR])
+        .args(eval_args)
+        .args(config.to_args())
+        .run_capture()
+        .ok()
+        .flatten()
+        .and_then(|out| serde_json::from_str::<SecretKeys>(&out).ok());
+    debug!(?keys, "Evaluated secret keys");
+    let Some(keys) = keys else {
+        debug!("Couldn't evaluate the secret keys, skipping the check");
+        return Ok(());
+    };
+
+    let host = target_host.unwrap_or("this machine");
+    for (tool, paths) in keys.required() {
+        if paths.is_empty() {
+            return Err(eyre!(
+                "The configuration has {tool} secrets, but no key to decrypt them with"
+            )
+            .wrap_err(Failure::Environment));
+        }
+        if !paths
+            .iter()
+            .any(|path| key_exists(path, target_host, elevate))
+        {
+            return Err(eyre!(
+                "The configuration has {tool} secrets, but none of the keys to decrypt them exist on {host}: {}. Provision one of them before switching, or skip this check with --skip-check secrets",
+                paths.join(", ")
+            )
+            .wrap_err(Failure::Environment));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_required_keys() {
+        let keys: SecretKeys = serde_json::from_str(
+            r#"{
+              "sops": {"keyFile": "/var/lib/sops-nix/key.txt", "generateKey": false, "sshKeyPaths": ["/etc/ssh/ssh_host_ed25519_key"]},
+              "agenix": {"identityPaths": []}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            keys.required(),
+            vec![
+                (
+                    "sops-nix",
+                    vec![
+                        "/var/lib/sops-nix/key.txt".to_string(),
+                        "/etc/ssh/ssh_host_ed25519_key".to_string()
+                    ]
+                ),
+                ("agenix", Vec::new())
+            ]
+        );
+
+        let generated: SecretKeys = serde_json::from_str(
+            r#"{"sops": {"keyFile": null, "generateKey": true, "sshKeyPaths": []}, "agenix": null}"#,
+        )
+        .unwrap();
+        assert!(generated.required().is_empty());
+    }
+}
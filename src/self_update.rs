@@ -0,0 +1,225 @@
+//! `nh self-update`, checking for and installing new releases of nh.
+//!
+//! Installs managed by Nix only get upgrade instructions, since replacing a
+//! binary in the store is neither possible nor desirable. Standalone binaries
+//! are replaced in place, but only with a release asset whose minisign
+//! signature checks out against the key nh was built with.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::time::Duration;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use semver::Version;
+use serde::Deserialize;
+use tracing::{debug, info};
+
+use crate::commands::Command;
+use crate::interface::SelfUpdateArgs;
+use crate::{NH_REV, NH_VERSION};
+
+/// Public minisign key release assets are signed with, set at build time.
+/// Builds without it can only check for updates.
+const RELEASE_PUBLIC_KEY: Option<&str> = option_env!("NH_RELEASE_PUBKEY");
+
+#[derive(Debug, Deserialize)]
+struct Release {
+    tag_name: String,
+    html_url: String,
+    #[serde(default)]
+    assets: Vec<Asset>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Asset {
+    name: String,
+    browser_download_url: String,
+}
+
+impl Release {
+    fn latest() -> Result<Self> {
+        let repository = env!("CARGO_PKG_REPOSITORY");
+        let url = format!(
+            "{}/releases/latest",
+            repository.replacen("https://github.com/", "https://api.github.com/repos/", 1)
+        );
+
+        reqwest::blocking::Client::new()
+            .get(&url)
+            .header("User-Agent", format!("nh/{NH_VERSION}"))
+            .timeout(Duration::from_secs(10))
+            .send()
+            .and_then(reqwest::blocking::Response::error_for_status)
+            .wrap_err("Failed to query the latest release")?
+            .json()
+            .wrap_err("Failed to parse the latest release")
+    }
+
+    fn version(&self) -> Result<Version> {
+        parse_tag(&self.tag_name)
+            .ok_or_else(|| eyre!("Release tag '{}' is not a version", self.tag_name))
+    }
+
+    fn asset(&self, name: &str) -> Option<&Asset> {
+        self.assets.iter().find(|asset| asset.name == name)
+    }
+}
+
+/// Version of a release tag like `v4.1.2`.
+fn parse_tag(tag: &str) -> Option<Version> {
+    Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+}
+
+/// Name of the release asset for this platform, like `nh-x86_64-linux`.
+fn asset_name() -> String {
+    format!("nh-{}-{}", env::consts::ARCH, env::consts::OS)
+}
+
+fn print_instructions() {
+    println!("nh is managed by Nix, upgrade it the way you installed it:");
+    println!("  NixOS, nix-darwin or Home Manager: update nixpkgs and rebuild");
+    println!("  nix profile:                       nix profile upgrade nh");
+    println!("  cargo:                             cargo install nh");
+}
+
+fn download(url: &str, to: &Path) -> Result<()> {
+    let bytes = reqwest::blocking::Client::new()
+        .get(url)
+        .header("User-Agent", format!("nh/{NH_VERSION}"))
+        .send()
+        .and_then(reqwest::blocking::Response::error_for_status)
+        .and_then(reqwest::blocking::Response::bytes)
+        .wrap_err(format!("Failed to download {url}"))?;
+
+    fs::File::create(to)
+        .and_then(|mut file| file.write_all(&bytes))
+        .wrap_err(format!("Failed to write {}", to.display()))
+}
+
+/// Replace the running binary with the release asset for this platform.
+fn install(release: &Release, exe: &Path) -> Result<()> {
+    let Some(key) = RELEASE_PUBLIC_KEY else {
+        bail!(
+            "This build of nh has no release key to verify downloads with, install {} manually from {}",
+            release.tag_name,
+            release.html_url
+        );
+    };
+    which::which("minisign").map_err(|_| {
+        eyre!("minisign is required to verify the download, but wasn't found in $PATH")
+    })?;
+
+    let name = asset_name();
+    let (Some(binary), Some(signature)) = (
+        release.asset(&name),
+        release.asset(&format!("{name}.minisig")),
+    ) else {
+        bail!(
+            "Release {} has no signed binary for this platform ({name})",
+            release.tag_name
+        );
+    };
+
+    // Download next to the binary, so the final rename doesn't cross
+    // filesystems
+    let dir = exe
+        .parent()
+        .ok_or_else(|| eyre!("{} has no parent directory", exe.display()))?;
+    let tmp = tempfile::Builder::new()
+        .prefix(".nh-update")
+        .tempdir_in(dir)
+        .wrap_err(format!(
+            "Failed to create a temporary file in {}",
+            dir.display()
+        ))?;
+    let new_exe = tmp.path().join("nh");
+    let sig = tmp.path().join("nh.minisig");
+
+    info!("Downloading {}", binary.browser_download_url);
+    download(&binary.browser_download_url, &new_exe)?;
+    download(&signature.browser_download_url, &sig)?;
+
+    Command::new("minisign")
+        .arg("-V")
+        .args(["-P", key])
+        .arg("-m")
+        .arg(&new_exe)
+        .arg("-x")
+        .arg(&sig)
+        .message("Verifying the signature")
+        .run()
+        .wrap_err("Signature verification failed, not installing the download")?;
+
+    fs::set_permissions(&new_exe, fs::Permissions::from_mode(0o755))?;
+    fs::rename(&new_exe, exe).wrap_err(format!("Failed to replace {}", exe.display()))?;
+
+    info!("Updated nh to {}", release.tag_name);
+    Ok(())
+}
+
+impl SelfUpdateArgs {
+    pub fn run(&self) -> Result<()> {
+        let current = Version::parse(NH_VERSION)?;
+        let release = Release::latest()?;
+        let latest = release.version()?;
+        debug!(%current, %latest, ?NH_REV);
+
+        if latest <= current {
+            let rev = NH_REV.map(|rev| format!(" ({rev})")).unwrap_or_default();
+            println!("nh {current}{rev} is up to date");
+            return Ok(());
+        }
+
+        println!(
+            "nh {latest} is available, you are running {current}\n{}",
+            release.html_url
+        );
+
+        let exe = env::current_exe()?;
+        let exe = fs::canonicalize(&exe).unwrap_or(exe);
+        if exe.starts_with("/nix/store") {
+            print_instructions();
+            return Ok(());
+        }
+
+        if self.check {
+            return Ok(());
+        }
+
+        install(&release, &exe)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_tag() {
+        assert_eq!(parse_tag("v4.1.2"), Some(Version::new(4, 1, 2)));
+        assert_eq!(parse_tag("4.2.0"), Some(Version::new(4, 2, 0)));
+        assert_eq!(parse_tag("nightly"), None);
+    }
+
+    #[test]
+    fn test_release_asset() {
+        let release: Release = serde_json::from_str(
+            r#"{
+                "tag_name": "v4.2.0",
+                "html_url": "https://github.com/nix-community/nh/releases/tag/v4.2.0",
+                "assets": [
+                    { "name": "nh-x86_64-linux", "browser_download_url": "https://example.com/nh" }
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        assert_eq!(release.version().unwrap(), Version::new(4, 2, 0));
+        assert!(release.asset("nh-x86_64-linux").is_some());
+        assert!(release.asset("nh-aarch64-darwin").is_none());
+    }
+}
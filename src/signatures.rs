@@ -0,0 +1,114 @@
+//! Signature verification of a closure before it becomes the system profile.
+//!
+//! Paths substituted from a binary cache, or copied to a deploy target, are
+//! only as trustworthy as the keys they are signed with. When `trusted-keys`
+//! is set in the `[rebuild]` section of the configuration, nh has Nix verify
+//! the closure against exactly those keys before activating it, and refuses
+//! to activate paths without a valid signature unless `--no-check-sigs` is
+//! given.
+
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use subprocess::{Exec, Redirection};
+use tracing::{debug, info};
+
+use crate::events::{self, Phase};
+use crate::interface::CommonRebuildArgs;
+
+/// Untrusted paths listed in the error, the rest are only counted
+const MAX_LISTED: usize = 10;
+
+/// Store paths that `nix store verify` reported as lacking a valid
+/// signature, from lines like `path '/nix/store/...' is untrusted`.
+fn untrusted_paths(stderr: &str) -> Vec<&str> {
+    stderr
+        .lines()
+        .filter(|line| line.contains("is untrusted"))
+        .filter_map(|line| line.split('\'').nth(1))
+        .collect()
+}
+
+/// Verify that every path in the closure of `out_path` is signed by one of
+/// `keys`. Paths built on this machine count as trusted, unless `remote` is
+/// set: the target host they are copied to has no reason to trust them.
+pub fn verify(out_path: &Path, keys: &[String], remote: bool) -> Result<()> {
+    events::phase(Phase::Verify, || {
+        info!("Verifying the signatures of the closure");
+
+        let mut cmd = Exec::cmd("nix")
+            .args(&["--extra-experimental-features", "nix-command"])
+            .args(&["store", "verify", "--recursive", "--no-contents"])
+            .args(&["--sigs-needed", "1"])
+            .args(&["--option", "trusted-public-keys", &keys.join(" ")]);
+        if remote {
+            cmd = cmd.arg("--no-trust");
+        }
+        let cmd = cmd
+            .arg(out_path)
+            .stdout(Redirection::Pipe)
+            .stderr(Redirection::Pipe);
+        debug!(?cmd);
+
+        let capture = cmd.capture().wrap_err("Failed to run nix store verify")?;
+        if capture.exit_status.success() {
+            return Ok(());
+        }
+
+        let stderr = capture.stderr_str();
+        let untrusted = untrusted_paths(&stderr);
+        if untrusted.is_empty() {
+            bail!("Failed to verify the closure:\n{stderr}");
+        }
+
+        let mut listed = untrusted
+            .iter()
+            .take(MAX_LISTED)
+            .map(|path| format!("  {path}"))
+            .collect::<Vec<_>>()
+            .join("\n");
+        if untrusted.len() > MAX_LISTED {
+            listed.push_str(&format!(
+                "\n  ... and {} more",
+                untrusted.len() - MAX_LISTED
+            ));
+        }
+        Err(eyre!(
+            "{} paths of the closure aren't signed by a trusted key:\n{listed}\nSign them with `nix store sign --key-file` or pass --no-check-sigs to activate them anyway",
+            untrusted.len()
+        ))
+    })
+}
+
+impl CommonRebuildArgs {
+    /// Verify the signatures of the closure of `out_path` before activating
+    /// it, if trusted keys are configured and `--no-check-sigs` wasn't given.
+    pub fn verify_signatures(&self, out_path: &Path, remote: bool) -> Result<()> {
+        let keys = &crate::config::get().rebuild.trusted_keys;
+        if self.no_check_sigs || self.dry || keys.is_empty() {
+            return Ok(());
+        }
+
+        verify(out_path, keys, remote)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_untrusted_paths() {
+        let stderr = "\
+path '/nix/store/aaa-hello-2.12' is untrusted
+path '/nix/store/bbb-etc' is untrusted
+error: 2 paths are untrusted
+";
+        assert_eq!(
+            untrusted_paths(stderr),
+            vec!["/nix/store/aaa-hello-2.12", "/nix/store/bbb-etc"]
+        );
+        assert!(untrusted_paths("error: cannot connect to the daemon").is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! Deploy specifications for `nh os --spec`.
+//!
+//! A deploy spec is a TOML (or JSON, by extension) file checked into a
+//! repository that describes what to deploy and where, so deploys can be
+//! reviewed like any other change:
+//!
+//! ```toml
+//! flake = "github:example/infra"
+//! hostname = "web-1"
+//! target-host = "root@web-1.example.com"
+//! build-host = "builder.example.com"
+//! specialisation = "production"
+//! extra-args = ["--show-trace"]
+//! ```
+
+use std::fs;
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::Context;
+use serde::Deserialize;
+
+use crate::installable::{Installable, parse_attribute};
+
+#[derive(Debug, Clone, Default, Deserialize, PartialEq, Eq)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct DeploySpec {
+    /// Flake reference, optionally with an attribute path after `#`
+    pub flake: String,
+
+    /// Attribute path to build, overriding the one in `flake`
+    pub attribute: Option<String>,
+
+    /// Host to select from nixosConfigurations
+    pub hostname: Option<String>,
+
+    /// Deploy the configuration to this host over ssh
+    pub target_host: Option<String>,
+
+    /// Build the configuration on this host over ssh
+    pub build_host: Option<String>,
+
+    /// Specialisation to activate
+    pub specialisation: Option<String>,
+
+    /// Extra arguments passed to nix build
+    #[serde(default)]
+    pub extra_args: Vec<String>,
+}
+
+impl DeploySpec {
+    /// Read a spec from a file, parsed as JSON if it has a `.json`
+    /// extension and as TOML otherwise.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .wrap_err(format!("Failed to read deploy spec {}", path.display()))?;
+
+        let spec = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&contents).map_err(color_eyre::Report::from)
+        } else {
+            toml::from_str(&contents).map_err(color_eyre::Report::from)
+        };
+
+        spec.wrap_err(format!("Failed to parse deploy spec {}", path.display()))
+    }
+
+    #[must_use]
+    pub fn installable(&self) -> Installable {
+        let mut elems = self.flake.splitn(2, '#');
+        let reference = elems.next().unwrap().to_owned();
+        let attribute = self
+            .attribute
+            .as_deref()
+            .or_else(|| elems.next())
+            .map(parse_attribute)
+            .unwrap_or_default();
+
+        Installable::Flake {
+            reference,
+            attribute,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    fn write_spec(suffix: &str, contents: &str) -> tempfile::NamedTempFile {
+        let mut file = tempfile::Builder::new().suffix(suffix).tempfile().unwrap();
+        file.write_all(contents.as_bytes()).unwrap();
+        file
+    }
+
+    #[test]
+    fn test_load_toml() {
+        let file = write_spec(
+            ".toml",
+            r#"
+            flake = "github:example/infra"
+            hostname = "web-1"
+            target-host = "root@web-1"
+            extra-args = ["--show-trace"]
+            "#,
+        );
+
+        let spec = DeploySpec::load(file.path()).unwrap();
+        assert_eq!(
+            spec,
+            DeploySpec {
+                flake: "github:example/infra".to_string(),
+                hostname: Some("web-1".to_string()),
+                target_host: Some("root@web-1".to_string()),
+                extra_args: vec!["--show-trace".to_string()],
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_load_json() {
+        let file = write_spec(
+            ".json",
+            r#"{ "flake": ".", "build-host": "builder", "specialisation": "prod" }"#,
+        );
+
+        let spec = DeploySpec::load(file.path()).unwrap();
+        assert_eq!(spec.build_host.as_deref(), Some("builder"));
+        assert_eq!(spec.specialisation.as_deref(), Some("prod"));
+    }
+
+    #[test]
+    fn test_unknown_fields_are_rejected() {
+        let file = write_spec(".toml", "flake = \".\"\ntarget = \"oops\"\n");
+        assert!(DeploySpec::load(file.path()).is_err());
+    }
+
+    #[test]
+    fn test_installable() {
+        let spec = DeploySpec {
+            flake: "github:example/infra#nixosConfigurations.web-1".to_string(),
+            ..Default::default()
+        };
+        assert_eq!(
+            spec.installable().to_args(),
+            vec!["github:example/infra#nixosConfigurations.web-1"]
+        );
+
+        let spec = DeploySpec {
+            flake: "github:example/infra#ignored".to_string(),
+            attribute: Some("nixosConfigurations.web-2".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            spec.installable().to_args(),
+            vec!["github:example/infra#nixosConfigurations.web-2"]
+        );
+    }
+}
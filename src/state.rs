@@ -0,0 +1,86 @@
+//! Persistent state kept by nh between runs, such as generation labels.
+//!
+//! State lives in `$NH_STATE_DIR`, falling back to `$XDG_STATE_HOME/nh` and
+//! `~/.local/state/nh`. Each kind of state is a JSON file in that directory.
+
+use std::env;
+use std::fs;
+use std::io::Write;
+use std::path::PathBuf;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, eyre};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use tracing::debug;
+
+/// Directory nh keeps its state in. It is not created by this function.
+pub fn state_dir() -> Result<PathBuf> {
+    if let Some(dir) = env::var_os("NH_STATE_DIR").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir));
+    }
+
+    if let Some(dir) = env::var_os("XDG_STATE_HOME").filter(|dir| !dir.is_empty()) {
+        return Ok(PathBuf::from(dir).join("nh"));
+    }
+
+    let home = env::var_os("HOME").ok_or_else(|| eyre!("Couldn't determine home directory"))?;
+    Ok(PathBuf::from(home).join(".local/state/nh"))
+}
+
+/// Load the state file `name`, or the default value if it doesn't exist yet.
+pub fn load<T: DeserializeOwned + Default>(name: &str) -> Result<T> {
+    let path = state_dir()?.join(name);
+
+    match fs::read_to_string(&path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .wrap_err(format!("Failed to parse state file {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(T::default()),
+        Err(err) => Err(err).wrap_err(format!("Failed to read state file {}", path.display())),
+    }
+}
+
+/// Atomically replace the state file `name` with `value`.
+pub fn save<T: Serialize>(name: &str, value: &T) -> Result<()> {
+    let dir = state_dir()?;
+    fs::create_dir_all(&dir).wrap_err(format!(
+        "Failed to create state directory {}",
+        dir.display()
+    ))?;
+
+    let path = dir.join(name);
+    debug!("Writing state file {}", path.display());
+
+    let mut file = tempfile::NamedTempFile::new_in(&dir)?;
+    serde_json::to_writer_pretty(&mut file, value)?;
+    file.write_all(b"\n")?;
+    file.persist(&path)
+        .wrap_err(format!("Failed to write state file {}", path.display()))?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use serial_test::serial;
+
+    use super::*;
+    use crate::test_util::EnvGuard;
+
+    #[test]
+    #[serial]
+    fn test_load_and_save() {
+        let dir = tempfile::tempdir().unwrap();
+        let _state = EnvGuard::new("NH_STATE_DIR", dir.path().join("nested"));
+
+        let empty: BTreeMap<String, u64> = load("test.json").unwrap();
+        assert!(empty.is_empty());
+
+        let value = BTreeMap::from([("a".to_string(), 1u64), ("b".to_string(), 2)]);
+        save("test.json", &value).unwrap();
+        let loaded: BTreeMap<String, u64> = load("test.json").unwrap();
+        assert_eq!(loaded, value);
+    }
+}
@@ -0,0 +1,321 @@
+//! Metrics history of rebuilds, and `nh stats` to show how they trend.
+//!
+//! After every `nh os`, `nh home` and `nh darwin` rebuild, the time spent in
+//! each phase, the closure size and how many paths were built or substituted
+//! are appended to `stats.json` in the state directory. Only the most recent
+//! runs are kept.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::hooks;
+use crate::interface::StatsArgs;
+use crate::json::{self, Output};
+use crate::state;
+use crate::store;
+use crate::theme::{Role, paint};
+use crate::timings::{PhaseTiming, format_ms};
+use crate::util::format_bytes;
+
+const STATE_FILE: &str = "stats.json";
+
+/// Runs kept in the history
+const MAX_RUNS: usize = 1000;
+
+/// Metrics of a single rebuild
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunMetrics {
+    pub date: String,
+    /// The subcommand, like `os switch`
+    pub command: String,
+    pub success: bool,
+    pub total_ms: u64,
+    pub phases: Vec<PhaseTiming>,
+    /// NAR size of the closure of the built configuration
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub closure_size: Option<u64>,
+    /// Paths of the closure built during the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub built: Option<u64>,
+    /// Paths of the closure substituted during the run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub substituted: Option<u64>,
+}
+
+/// How the runs of a command changed over a period
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CommandStats {
+    pub command: String,
+    pub runs: usize,
+    pub failures: usize,
+    /// Median duration of the successful runs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub median_ms: Option<u64>,
+    /// Median duration of the successful runs in the period before
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub previous_median_ms: Option<u64>,
+    /// Closure size of the latest run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub closure_size: Option<u64>,
+    /// Closure size of the first run of the period
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_closure_size: Option<u64>,
+    pub built: u64,
+    pub substituted: u64,
+}
+
+/// Record the metrics of a finished run of `command`, like `os switch`, if
+/// it was a rebuild. `started_at` is when the run started.
+pub fn record(
+    command: &str,
+    res: &Result<()>,
+    started_at: SystemTime,
+    elapsed: Duration,
+) -> Result<()> {
+    if !hooks::started() {
+        return Ok(());
+    }
+
+    let mut metrics = RunMetrics {
+        date: chrono::Local::now().to_rfc3339(),
+        command: command.to_string(),
+        success: res.is_ok(),
+        total_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        phases: crate::timings::recorded(),
+        closure_size: None,
+        built: None,
+        substituted: None,
+    };
+
+    if let Some(result) = json::last_rebuild().filter(|_| res.is_ok()) {
+        match store::closure_infos(&result.out_path) {
+            Ok(infos) => {
+                let started_at = started_at
+                    .duration_since(SystemTime::UNIX_EPOCH)
+                    .map_or(0, |since| since.as_secs());
+                let (built, substituted) = infos
+                    .iter()
+                    .filter(|info| {
+                        info.registration_time
+                            .is_some_and(|time| time >= started_at)
+                    })
+                    .partition::<Vec<_>, _>(|info| info.ultimate);
+                metrics.closure_size = Some(infos.iter().map(|info| info.nar_size).sum());
+                metrics.built = Some(built.len() as u64);
+                metrics.substituted = Some(substituted.len() as u64);
+            }
+            Err(err) => debug!("Not recording the closure metrics: {err:#}"),
+        }
+    }
+
+    let mut runs: Vec<RunMetrics> = state::load(STATE_FILE).unwrap_or_else(|err| {
+        debug!("Ignoring unreadable stats: {err:#}");
+        Vec::new()
+    });
+    runs.push(metrics);
+    let excess = runs.len().saturating_sub(MAX_RUNS);
+    runs.drain(..excess);
+    state::save(STATE_FILE, &runs)
+}
+
+fn median(mut values: Vec<u64>) -> Option<u64> {
+    values.sort_unstable();
+    let mid = values.len() / 2;
+    match values.len() {
+        0 => None,
+        len if len % 2 == 0 => Some((values[mid - 1] + values[mid]) / 2),
+        _ => Some(values[mid]),
+    }
+}
+
+/// Stats of each command over the `period` before `now`, compared with the
+/// period before that.
+fn summarize(runs: &[RunMetrics], now: DateTime<Utc>, period: Duration) -> Vec<CommandStats> {
+    let period = chrono::Duration::from_std(period).unwrap_or(chrono::Duration::MAX);
+    let start = now
+        .checked_sub_signed(period)
+        .unwrap_or(DateTime::<Utc>::MIN_UTC);
+    let previous_start = start
+        .checked_sub_signed(period)
+        .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+    let mut current: BTreeMap<&str, Vec<&RunMetrics>> = BTreeMap::new();
+    let mut previous: BTreeMap<&str, Vec<&RunMetrics>> = BTreeMap::new();
+    for run in runs {
+        let Ok(date) = DateTime::parse_from_rfc3339(&run.date) else {
+            continue;
+        };
+        if date >= start && date <= now {
+            current.entry(&run.command).or_default().push(run);
+        } else if date >= previous_start && date < start {
+            previous.entry(&run.command).or_default().push(run);
+        }
+    }
+
+    let median_ms = |runs: &[&RunMetrics]| {
+        median(
+            runs.iter()
+                .filter(|run| run.success)
+                .map(|run| run.total_ms)
+                .collect(),
+        )
+    };
+
+    current
+        .into_iter()
+        .map(|(command, runs)| {
+            let sizes: Vec<u64> = runs.iter().filter_map(|run| run.closure_size).collect();
+            CommandStats {
+                command: command.to_string(),
+                runs: runs.len(),
+                failures: runs.iter().filter(|run| !run.success).count(),
+                median_ms: median_ms(&runs),
+                previous_median_ms: previous.get(command).and_then(|runs| median_ms(runs)),
+                closure_size: sizes.last().copied(),
+                first_closure_size: sizes.first().copied(),
+                built: runs.iter().filter_map(|run| run.built).sum(),
+                substituted: runs.iter().filter_map(|run| run.substituted).sum(),
+            }
+        })
+        .collect()
+}
+
+/// `current` compared with `previous` in percent, like `+12%`.
+fn change(current: u64, previous: u64) -> String {
+    if previous == 0 {
+        return String::new();
+    }
+    let percent = (current as f64 - previous as f64) / previous as f64 * 100.0;
+    format!("{percent:+.0}%")
+}
+
+fn print_stats(stats: &[CommandStats], period: &str) {
+    for stats in stats {
+        let mut runs = format!("{} runs", stats.runs);
+        if stats.failures > 0 {
+            runs.push_str(&format!(", {} failed", stats.failures));
+        }
+        println!("{} {}", paint(&stats.command, Role::Heading), runs);
+
+        if let Some(median_ms) = stats.median_ms {
+            let previous = stats
+                .previous_median_ms
+                .map(|previous| {
+                    format!(
+                        " ({} vs the previous {period})",
+                        change(median_ms, previous)
+                    )
+                })
+                .unwrap_or_default();
+            println!(
+                "  {:<10} median {}{previous}",
+                "duration",
+                paint(format_ms(median_ms), Role::Value)
+            );
+        }
+
+        if let (Some(size), Some(first)) = (stats.closure_size, stats.first_closure_size) {
+            let trend = if size == first {
+                String::new()
+            } else {
+                format!(" ({} since the first run)", change(size, first))
+            };
+            println!(
+                "  {:<10} {}{trend}",
+                "closure",
+                paint(format_bytes(size), Role::Value)
+            );
+        }
+
+        let paths = stats.built + stats.substituted;
+        if paths > 0 {
+            println!(
+                "  {:<10} {} substituted, {} built ({:.0}% substituted)",
+                "paths",
+                stats.substituted,
+                stats.built,
+                stats.substituted as f64 / paths as f64 * 100.0
+            );
+        }
+    }
+}
+
+impl StatsArgs {
+    pub fn run(&self) -> Result<()> {
+        let runs: Vec<RunMetrics> = state::load(STATE_FILE)?;
+        let stats = summarize(&runs, Utc::now(), self.since.into());
+
+        if json::output_enabled() {
+            return json::emit(&Output::Stats(stats));
+        }
+        if stats.is_empty() {
+            println!("No rebuilds recorded in the last {}", self.since);
+            return Ok(());
+        }
+        print_stats(&stats, &self.since.to_string());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run(date: &str, command: &str, success: bool, total_ms: u64) -> RunMetrics {
+        RunMetrics {
+            date: date.to_string(),
+            command: command.to_string(),
+            success,
+            total_ms,
+            phases: Vec::new(),
+            closure_size: success.then_some(total_ms * 1000),
+            built: success.then_some(1),
+            substituted: success.then_some(3),
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        let runs = vec![
+            run("2026-08-20T10:00:00+00:00", "os switch", true, 200),
+            run("2026-09-20T10:00:00+00:00", "os switch", true, 100),
+            run("2026-10-01T10:00:00+02:00", "os switch", false, 10),
+            run("2026-10-10T10:00:00+00:00", "os switch", true, 140),
+            run("2026-10-11T10:00:00+00:00", "home switch", true, 50),
+            run("not a date", "os switch", true, 1),
+        ];
+        let now = DateTime::parse_from_rfc3339("2026-10-16T00:00:00+00:00")
+            .unwrap()
+            .to_utc();
+
+        let stats = summarize(&runs, now, Duration::from_secs(30 * 24 * 3600));
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].command, "home switch");
+        assert_eq!(
+            stats[1],
+            CommandStats {
+                command: "os switch".to_string(),
+                runs: 3,
+                failures: 1,
+                median_ms: Some(120),
+                previous_median_ms: Some(200),
+                closure_size: Some(140_000),
+                first_closure_size: Some(100_000),
+                built: 2,
+                substituted: 6,
+            }
+        );
+    }
+
+    #[test]
+    fn test_change() {
+        assert_eq!(change(120, 200), "-40%");
+        assert_eq!(change(110, 100), "+10%");
+        assert_eq!(change(5, 0), "");
+    }
+}
@@ -0,0 +1,257 @@
+//! `nh store`, wrappers around the Nix store commands people otherwise run by
+//! hand, defaulting to the closure of the running system.
+
+use std::env;
+use std::path::{Path, PathBuf};
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail};
+
+use crate::commands::{Command, NIX_COMMAND};
+use crate::diff;
+use crate::interface::{
+    StoreCommand, StoreDiffClosuresArgs, StorePathInfoArgs, StoreRepairArgs, StoreVerifyArgs,
+};
+use crate::theme::{Role, paint};
+use crate::util::format_bytes;
+
+const CURRENT_SYSTEM: &str = "/run/current-system";
+
+/// The active home-manager profile.
+pub fn home_profile() -> Result<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Ok(user) = env::var("USER") {
+        candidates.push(
+            Path::new("/nix/var/nix/profiles/per-user")
+                .join(user)
+                .join("home-manager"),
+        );
+    }
+    if let Ok(home) = env::var("HOME") {
+        candidates.push(Path::new(&home).join(".local/state/nix/profiles/home-manager"));
+    }
+
+    match candidates.into_iter().find(|profile| profile.exists()) {
+        Some(profile) => Ok(profile),
+        None => bail!("Couldn't find a home-manager profile"),
+    }
+}
+
+/// A store path in the output of `nix path-info --json --size`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PathInfo {
+    pub path: String,
+    pub nar_size: u64,
+    /// Seconds since the epoch
+    pub registration_time: Option<u64>,
+    /// Whether the path was built locally rather than substituted
+    pub ultimate: bool,
+    /// Size of the closure of the path, with `--closure-size`
+    pub closure_size: Option<u64>,
+    /// Store paths the path refers to
+    pub references: Vec<String>,
+}
+
+/// Parse the output of `nix path-info --json --size`. It is a list of
+/// objects in older versions of Nix and an object keyed by path in newer
+/// ones.
+pub fn parse_path_infos(json: &str) -> Result<Vec<PathInfo>> {
+    let value: serde_json::Value =
+        serde_json::from_str(json).wrap_err("Failed to parse nix path-info output")?;
+
+    let info = |path: &str, info: &serde_json::Value| {
+        Some(PathInfo {
+            path: path.to_string(),
+            nar_size: info["narSize"].as_u64()?,
+            registration_time: info["registrationTime"].as_u64(),
+            ultimate: info["ultimate"].as_bool().unwrap_or(false),
+            closure_size: info["closureSize"].as_u64(),
+            references: info["references"]
+                .as_array()
+                .map(|references| {
+                    references
+                        .iter()
+                        .filter_map(|r| r.as_str().map(str::to_string))
+                        .collect()
+                })
+                .unwrap_or_default(),
+        })
+    };
+    let infos = match value {
+        serde_json::Value::Array(infos) => infos
+            .iter()
+            .filter_map(|i| info(i["path"].as_str()?, i))
+            .collect(),
+        serde_json::Value::Object(infos) => {
+            infos.iter().filter_map(|(path, i)| info(path, i)).collect()
+        }
+        _ => bail!("Unexpected nix path-info output"),
+    };
+    Ok(infos)
+}
+
+/// The paths in the closure of `path`.
+pub fn closure_infos(path: &Path) -> Result<Vec<PathInfo>> {
+    let json = Command::new("nix")
+        .args(NIX_COMMAND)
+        .args(["path-info", "--json", "--size", "--recursive"])
+        .arg(path)
+        .run_capture()?
+        .unwrap_or_default();
+    parse_path_infos(&json)
+}
+
+impl StoreCommand {
+    pub fn run(&self) -> Result<()> {
+        match self {
+            Self::DiffClosures(args) => args.run(),
+            Self::Verify(args) => args.run(),
+            Self::Repair(args) => args.run(),
+            Self::PathInfo(args) => args.run(),
+        }
+    }
+}
+
+impl StoreDiffClosuresArgs {
+    fn run(&self) -> Result<()> {
+        for path in [&self.old, &self.new] {
+            if !path.exists() {
+                bail!("{} doesn't exist", path.display());
+            }
+        }
+        diff::print_diff(&self.old, &self.new)
+    }
+}
+
+impl StoreVerifyArgs {
+    fn run(&self) -> Result<()> {
+        let mut cmd = Command::new("nix")
+            .args(NIX_COMMAND)
+            .args(["store", "verify"])
+            .show_output(true);
+
+        if self.all {
+            cmd = cmd.arg("--all").message("Verifying the whole store");
+        } else if self.paths.is_empty() {
+            cmd = cmd
+                .args(["--recursive", CURRENT_SYSTEM])
+                .message("Verifying the closure of the current system");
+        } else {
+            cmd = cmd
+                .arg("--recursive")
+                .args(&self.paths)
+                .message("Verifying the given closures");
+        }
+        if !self.check_contents {
+            cmd = cmd.arg("--no-contents");
+        }
+        if self.repair {
+            cmd = cmd
+                .arg("--repair")
+                .elevate(!nix::unistd::Uid::effective().is_root());
+        }
+
+        cmd.run()
+    }
+}
+
+impl StoreRepairArgs {
+    fn run(&self) -> Result<()> {
+        Command::new("nix")
+            .args(NIX_COMMAND)
+            .args(["store", "repair"])
+            .args(&self.paths)
+            .message("Repairing store paths")
+            .elevate(!nix::unistd::Uid::effective().is_root())
+            .show_output(true)
+            .run()
+    }
+}
+
+impl StorePathInfoArgs {
+    fn run(&self) -> Result<()> {
+        let path = match (&self.path, self.home) {
+            (Some(path), _) => path.clone(),
+            (None, true) => home_profile()?,
+            (None, false) => PathBuf::from(CURRENT_SYSTEM),
+        };
+        let store_path = std::fs::canonicalize(&path)
+            .wrap_err(format!("Failed to resolve {}", path.display()))?;
+
+        let mut sizes: Vec<(String, u64)> = closure_infos(&store_path)?
+            .into_iter()
+            .map(|info| (info.path, info.nar_size))
+            .collect();
+        sizes.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+
+        println!("{}", paint(store_path.display(), Role::Name));
+        println!(
+            "Closure: {} paths, {}",
+            sizes.len(),
+            paint(
+                format_bytes(sizes.iter().map(|(_, size)| size).sum()),
+                Role::Value
+            )
+        );
+
+        if self.size {
+            println!();
+            for (path, size) in sizes.iter().take(self.limit) {
+                println!("{:>10}  {path}", format_bytes(*size));
+            }
+            if sizes.len() > self.limit {
+                println!(
+                    "{}",
+                    paint(
+                        format!("... and {} smaller paths", sizes.len() - self.limit),
+                        Role::Muted
+                    )
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_path_infos() {
+        let sizes = |json| -> Vec<(String, u64)> {
+            parse_path_infos(json)
+                .unwrap()
+                .into_iter()
+                .map(|info| (info.path, info.nar_size))
+                .collect()
+        };
+
+        let old = r#"[{"path": "/nix/store/aaa-a", "narSize": 10}, {"path": "/nix/store/bbb-b", "narSize": 20}]"#;
+        assert_eq!(
+            sizes(old),
+            vec![
+                ("/nix/store/aaa-a".to_string(), 10),
+                ("/nix/store/bbb-b".to_string(), 20)
+            ]
+        );
+
+        let new = r#"{"/nix/store/aaa-a": {"narSize": 10}, "/nix/store/ccc-c": null}"#;
+        assert_eq!(sizes(new), vec![("/nix/store/aaa-a".to_string(), 10)]);
+        assert!(parse_path_infos("\"nope\"").is_err());
+
+        let built = r#"{"/nix/store/aaa-a": {"narSize": 10, "registrationTime": 1700000000, "ultimate": true}}"#;
+        assert_eq!(
+            parse_path_infos(built).unwrap(),
+            vec![PathInfo {
+                path: "/nix/store/aaa-a".to_string(),
+                nar_size: 10,
+                registration_time: Some(1_700_000_000),
+                ultimate: true,
+                closure_size: None,
+                references: Vec::new(),
+            }]
+        );
+    }
+}
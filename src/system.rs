@@ -12,116 +12,543 @@
 // limitations under the License.
 
 //! Interface to `system-manager`.
+//!
+//! nh builds `systemConfigs.<name>` of the flake itself, like it does for
+//! the other configurations, and leaves registering the new generation in
+//! the system-manager profile and activating it to `system-manager`, on this
+//! machine or on `--target-host` over ssh. If the activation fails, the
+//! profile is set back to the generation it was on.
 
-use color_eyre::eyre::{Context, Result, bail};
-use subprocess::{Exec, ExitStatus};
-use tracing::{debug, instrument};
+use std::path::{Path, PathBuf};
 
-use crate::interface::{SystemBuildArgs, SystemRollbackArgs};
+use color_eyre::eyre::{Context, Result, bail, eyre};
+use subprocess::{NullFile, Redirection};
+use tracing::{debug, info, warn};
 
-fn ssh_wrap(cmd: Exec, ssh: Option<&str>) -> Exec {
-    if let Some(ssh) = ssh {
-        Exec::cmd("ssh")
-            .arg("-T")
-            .arg(ssh)
-            .stdin(cmd.to_cmdline_lossy().as_str())
-    } else {
-        cmd
+use crate::commands::{self, Command};
+use crate::confirm;
+use crate::diff;
+use crate::events::{self, Event, Phase};
+use crate::exit;
+use crate::gcroots;
+use crate::generations;
+use crate::hooks::{self, Hook};
+use crate::installable::Installable;
+use crate::interface::{
+    DiffType, SysArgs, SysSubcommand, SystemInfoArgs, SystemRebuildArgs, SystemRollbackArgs,
+};
+use crate::json;
+use crate::nixos::{describe_generations, find_target_generation, rollback_cutoff};
+use crate::output;
+use crate::transfer;
+use crate::update::update;
+use crate::util::{ensure_flake_configuration, get_hostname, list_flake_configurations};
+
+/// The profile system-manager registers its generations in
+pub const PROFILE: &str = "/nix/var/nix/profiles/system-manager-profiles/system-manager";
+
+/// The GC root system-manager keeps to the generation it activated last
+pub const GCROOT: &str = "/nix/var/nix/gcroots/system-manager-current";
+
+/// Configuration of `systemConfigs` used if there is none named after the
+/// host
+const DEFAULT_CONFIG: &str = "default";
+
+fn ensure_system_manager() -> Result<PathBuf> {
+    if cfg!(target_os = "macos") {
+        bail!("system-manager is Linux-only");
+    }
+
+    which::which("system-manager").wrap_err("`system-manager` not found in $PATH")
+}
+
+/// Whether to call sudo for system-manager, which has to run as root.
+fn elevate(bypass_root_check: bool) -> Result<bool> {
+    if bypass_root_check {
+        warn!("Bypassing root check, now running system-manager as root");
+        return Ok(false);
+    }
+    if nix::unistd::Uid::effective().is_root() {
+        bail!("Don't run nh sys as root. I will call sudo internally as needed");
     }
+    Ok(true)
 }
 
-fn ensure_system_manager() -> Result<String> {
-    which::which("system-manager")
-        .map(|p| p.to_string_lossy().into_owned())
-        .wrap_err("`system-manager` not found in $PATH")
+impl SysArgs {
+    pub fn run(self) -> Result<()> {
+        use SystemRebuildVariant::{Build, Switch};
+        match self.subcommand {
+            SysSubcommand::Switch(args) => args.rebuild(&Switch),
+            SysSubcommand::Build(args) => {
+                if args.common.ask || args.common.dry {
+                    warn!("`--ask` and `--dry` have no effect for `nh sys build`");
+                }
+                args.rebuild(&Build)
+            }
+            SysSubcommand::Info(args) => args.info(),
+            SysSubcommand::Rollback(args) => args.rollback(),
+        }
+    }
 }
 
-pub struct SystemManager;
+enum SystemRebuildVariant {
+    Switch,
+    Build,
+}
 
-impl SystemManager {
-    #[instrument(level = "debug", skip(args))]
-    pub fn build(args: &SystemBuildArgs) -> Result<()> {
-        if cfg!(target_os = "macos") {
-            bail!("system-manager is Linux-only");
+impl SystemRebuildArgs {
+    /// The name of the configuration in `systemConfigs` of `reference`: the
+    /// one given with `--hostname`, or else the one named after this host if
+    /// there is one, and `default` otherwise.
+    fn configuration_name(&self, reference: &str) -> Result<String> {
+        if let Some(hostname) = &self.hostname {
+            return Ok(hostname.clone());
         }
 
-        let bin = ensure_system_manager()?;
-        let mut cmd = Exec::cmd(bin).arg("build");
+        let hostname = get_hostname()?;
+        let names = list_flake_configurations(
+            reference,
+            "systemConfigs",
+            self.common.eval.generate_eval_args(),
+        )
+        .unwrap_or_default();
 
-        if let Some(flake) = &args.flake {
-            cmd = cmd.arg("--flake").arg(flake);
+        if names.contains(&hostname) {
+            Ok(hostname)
+        } else {
+            Ok(DEFAULT_CONFIG.to_string())
         }
-        if args.switch {
-            cmd = cmd.arg("--switch");
+    }
+
+    fn rebuild(self, variant: &SystemRebuildVariant) -> Result<()> {
+        use SystemRebuildVariant::{Build, Switch};
+
+        // The one of the target host is found there
+        let system_manager = match self.target_host {
+            Some(_) => PathBuf::from("system-manager"),
+            None => ensure_system_manager()?,
+        };
+        let elevate = elevate(self.bypass_root_check)?;
+
+        self.common.check_free_space()?;
+        self.common.check_substituters();
+        self.common.check_system()?;
+        if let Some(option) = self.common.trusted_option() {
+            crate::checks::warn_if_untrusted(option);
         }
-        if args.dry_activate {
-            cmd = cmd.arg("--dry-activate");
+
+        let _stash = self.common.prepare_worktree(&self.common.installable)?;
+
+        let pending_update = if self.update_args.enabled() {
+            events::phase(Phase::Update, || {
+                update(&self.common.installable, &self.update_args, true)
+            })?
+        } else {
+            None
+        };
+
+        let installable = self
+            .common
+            .pin_installable(self.common.installable.clone())?;
+
+        let mut toplevel = installable;
+        let mut config_name = self.hostname.clone();
+        if let Installable::Flake {
+            ref reference,
+            ref mut attribute,
+        } = toplevel
+        {
+            // If user explicitly selects some other attribute, don't push systemConfigs
+            if attribute.is_empty() {
+                let name = events::phase(Phase::Eval, || {
+                    let name = self.configuration_name(reference)?;
+                    ensure_flake_configuration(
+                        reference,
+                        "systemConfigs",
+                        &name,
+                        self.common.eval.generate_eval_args(),
+                    )?;
+                    Ok(name)
+                })?;
+                attribute.push(String::from("systemConfigs"));
+                attribute.push(name.clone());
+                config_name = Some(name);
+            }
+        }
+
+        hooks::start(
+            "system-manager",
+            match variant {
+                Build => "build",
+                Switch => "switch",
+            },
+            config_name.as_deref(),
+        );
+        hooks::run(Hook::PreEval)?;
+
+        let results_name = format!(
+            "system-manager-{}",
+            config_name.as_deref().unwrap_or("default")
+        );
+        let last_build = crate::results::latest(&results_name);
+        let out_path = self.common.result_link(&results_name, "nh-sys")?;
+
+        debug!(?out_path);
+
+        events::phase(Phase::Build, || {
+            self.common.build_unless_unchanged(
+                &toplevel,
+                &self.extra_args,
+                out_path.get_path(),
+                || {
+                    commands::Build::new(toplevel.clone())
+                        .extra_arg("--out-link")
+                        .extra_arg(out_path.get_path())
+                        .extra_args(&self.extra_args)
+                        .eval_args(&self.common.eval)
+                        .passthrough(&self.common.passthrough)
+                        .builders(&self.common.remote_builders())
+                        .message("Building system-manager configuration")
+                        .nom(!self.common.no_nom)
+                        .run()
+                        .wrap_err("Failed to build system-manager configuration")
+                },
+            )
+        })?;
+
+        if let Some(rev) = &self.common.rev {
+            info!("Built configuration at revision {rev}");
+        }
+
+        events::emit(&Event::BuildOutput {
+            out_path: out_path.get_path(),
+            rev: self.common.rev.as_deref(),
+        });
+
+        self.common.prune_results(&results_name)?;
+        self.common.push_closure(out_path.get_path())?;
+
+        hooks::set_out_path(out_path.get_path());
+        hooks::run(Hook::PostBuild)?;
+
+        let target_profile = std::fs::canonicalize(out_path.get_path())
+            .unwrap_or_else(|_| out_path.get_path().to_path_buf());
+
+        let mut result = json::RebuildResult {
+            system: "system-manager",
+            action: match variant {
+                Build => "build",
+                Switch => "switch",
+            },
+            hostname: config_name,
+            out_path: target_profile.clone(),
+            revision: self.common.rev.clone(),
+            dry: self.common.dry,
+            activated: false,
+            generation: None,
+            copy: None,
+            diff: None,
+        };
+
+        // Nothing to compare with before the first activation, and the local
+        // profile is not the one of a target host
+        if Path::new(PROFILE).exists() && self.target_host.is_none() {
+            match self.common.diff {
+                DiffType::Never => {}
+                _ => {
+                    let base = self.common.diff_base(PathBuf::from(PROFILE), last_build);
+                    result.diff = diff::rebuild_diff(&base, &target_profile);
+                }
+            }
+        }
+
+        if self.common.dry || matches!(variant, Build) {
+            if let Some(update) = pending_update {
+                update.finish()?;
+            }
+            return json::emit(&json::Output::Rebuild(result));
         }
-        if args.no_link {
-            cmd = cmd.arg("--no-link");
+
+        if self.common.ask && !confirm::ask("Apply the config?", &[])? {
+            return Err(exit::declined("User rejected the new config"));
         }
 
-        cmd = cmd.args(&args.passthrough.generate_passthrough_args());
-        cmd = ssh_wrap(cmd, args.install_host.as_deref());
-        cmd = ssh_wrap(cmd, args.ssh.as_deref());
-        debug!(?cmd);
-        let status = cmd.join()?;
-        match status {
-            ExitStatus::Exited(0) => Ok(()),
-            other => bail!("system-manager failed: {:?}", other),
+        self.common
+            .verify_signatures(&target_profile, self.target_host.is_some())?;
+
+        // Held until the end of the activation
+        let _remote_root = if let Some(target_host) = &self.target_host {
+            let (copy, root) = gcroots::copy_rooted(target_host, &target_profile, || {
+                transfer::copy_to_host(
+                    target_host,
+                    &target_profile,
+                    &self.copy,
+                    self.common.eval.generate_eval_args(),
+                    None,
+                )
+            })?;
+            result.copy = copy;
+            root
+        } else {
+            None
+        };
+
+        hooks::run(Hook::PreActivate)?;
+
+        let previous = profile_generation(self.target_host.as_deref());
+
+        Command::new(&system_manager)
+            .arg("register")
+            .arg("--store-path")
+            .arg(&target_profile)
+            .ssh(self.target_host.clone())
+            .elevate(elevate)
+            .message("Registering the new generation")
+            .with_required_env()
+            .run()
+            .wrap_err("Failed to register the system-manager profile")?;
+
+        events::activation(Phase::Activation, "switch", || {
+            Command::new(&system_manager)
+                .arg("activate")
+                .arg("--store-path")
+                .arg(&target_profile)
+                .ssh(self.target_host.clone())
+                .elevate(elevate)
+                .message("Activating configuration")
+                .show_output(output::human())
+                .with_required_env()
+                .run()
+                .wrap_err("system-manager activation failed")
+        })
+        .inspect_err(|_| {
+            let Some(previous) = &previous else {
+                return;
+            };
+            if let Err(restore_err) = Command::new("ln")
+                .arg("-sfn") // force, symbolic link
+                .arg(previous)
+                .arg(PROFILE)
+                .ssh(self.target_host.clone())
+                .elevate(elevate)
+                .message("Rolling back system-manager profile")
+                .with_required_env()
+                .run()
+            {
+                warn!("Failed to restore the previous system-manager profile: {restore_err:#}");
+            }
+        })?;
+
+        // Make sure out_path is not accidentally dropped
+        // https://docs.rs/tempfile/3.12.0/tempfile/index.html#early-drop-pitfall
+        debug!(
+            "Completed operation with output path: {:?}",
+            out_path.get_path()
+        );
+        drop(out_path);
+
+        if let Some(update) = pending_update {
+            update.finish()?;
         }
+
+        hooks::run(Hook::PostActivate)?;
+
+        result.activated = true;
+        json::emit(&json::Output::Rebuild(result))
     }
+}
 
-    #[instrument(level = "debug")]
-    pub fn list_generations(ssh: Option<&str>) -> Result<()> {
-        if cfg!(target_os = "macos") {
-            bail!("system-manager is Linux-only");
+/// The generation link the system-manager profile points to, here or on
+/// `target_host`, if it has one yet.
+fn profile_generation(target_host: Option<&str>) -> Option<PathBuf> {
+    let Some(target_host) = target_host else {
+        return std::fs::read_link(PROFILE).ok();
+    };
+    commands::ssh_cmd(target_host)
+        .args(&["readlink", PROFILE])
+        .stdin(NullFile)
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe)
+        .capture()
+        .ok()
+        .filter(|capture| capture.exit_status.success())
+        .map(|capture| capture.stdout_str().trim().to_string())
+        .filter(|link| !link.is_empty())
+        .map(PathBuf::from)
+}
+
+impl SystemInfoArgs {
+    fn info(&self) -> Result<()> {
+        let profile = Path::new(PROFILE);
+        if !profile.is_symlink() {
+            bail!("No system-manager profile found at {PROFILE}");
         }
 
-        let bin = ensure_system_manager()?;
-        let mut cmd = Exec::cmd(bin).arg("list-generations");
-        cmd = ssh_wrap(cmd, ssh);
-        debug!(?cmd);
-        let status = cmd.join()?;
-        match status {
-            ExitStatus::Exited(0) => Ok(()),
-            other => bail!("system-manager failed: {:?}", other),
+        let sizes = self.sizes || self.format.as_ref().is_some_and(|f| f.uses("size"));
+        let descriptions = describe_generations(profile, sizes)?;
+
+        if json::output_enabled() {
+            generations::print_info_json(descriptions)
+        } else if let Some(template) = &self.format {
+            generations::print_info_formatted(descriptions, template)
+        } else {
+            generations::print_info(descriptions)
         }
     }
+}
+
+impl SystemRollbackArgs {
+    fn rollback(&self) -> Result<()> {
+        let system_manager = ensure_system_manager()?;
+        let elevate = elevate(self.bypass_root_check)?;
+
+        let profile = Path::new(PROFILE);
+        let generations = generations::GenerationSet::scan(profile)?;
+        let target_generation = find_target_generation(
+            &generations,
+            self.to,
+            rollback_cutoff(self.to_date, self.before),
+        )?;
+
+        let details: Vec<String> = target_generation
+            .label
+            .iter()
+            .cloned()
+            .chain(
+                target_generation
+                    .flake_revision
+                    .as_ref()
+                    .map(|revision| format!("rev {revision}")),
+            )
+            .collect();
+        let target_description = if details.is_empty() {
+            target_generation.number.clone()
+        } else {
+            format!("{} ({})", target_generation.number, details.join(", "))
+        };
+        info!("Rolling back to generation {target_description}");
 
-    #[instrument(level = "debug", skip(args))]
-    pub fn rollback(args: &SystemRollbackArgs) -> Result<()> {
-        if cfg!(target_os = "macos") {
-            bail!("system-manager is Linux-only");
+        let number: u64 = target_generation.number.parse().unwrap_or_default();
+        let generation_link = generations::generation_link(profile, number);
+
+        let result = json::RollbackResult {
+            generation: number,
+            path: generation_link.clone(),
+            specialisation: None,
+            dry: self.dry,
+        };
+
+        match self.diff {
+            DiffType::Never => {}
+            _ => {
+                let _ = diff::print_diff(profile, &generation_link);
+            }
         }
 
-        let bin = ensure_system_manager()?;
-        let mut cmd = Exec::cmd(bin).arg("rollback");
-        if let Some(generation) = &args.generation {
-            cmd = cmd.arg(generation);
+        if self.dry {
+            info!("Dry run: would roll back to generation {number}");
+            return json::emit(&json::Output::Rollback(result));
         }
-        cmd = ssh_wrap(cmd, args.install_host.as_deref());
-        cmd = ssh_wrap(cmd, args.ssh.as_deref());
-        debug!(?cmd);
-        let status = cmd.join()?;
-        match status {
-            ExitStatus::Exited(0) => Ok(()),
-            other => bail!("system-manager failed: {:?}", other),
+
+        if self.ask {
+            let question = format!("Roll back to generation {target_description}?");
+            if !confirm::ask(&question, &[])? {
+                return Err(exit::declined("User rejected the rollback"));
+            }
+        }
+
+        // Get current generation for potential rollback
+        let current_link = generations
+            .current()
+            .and_then(|generation| generation.number.parse().ok())
+            .map(|number| generations::generation_link(profile, number));
+        if current_link.is_none() {
+            warn!("Failed to get current generation number");
         }
+
+        Command::new("ln")
+            .arg("-sfn") // force, symbolic link
+            .arg(&generation_link)
+            .arg(profile)
+            .elevate(elevate)
+            .message("Setting system-manager profile")
+            .with_required_env()
+            .run()
+            .wrap_err("Failed to set system-manager profile during rollback")?;
+
+        let store_path = std::fs::canonicalize(&generation_link)
+            .wrap_err_with(|| format!("Generation {number} is no longer in the store"))?;
+
+        match events::activation(Phase::Activation, "switch", || {
+            Command::new(&system_manager)
+                .arg("activate")
+                .arg("--store-path")
+                .arg(&store_path)
+                .elevate(elevate)
+                .message("Activating configuration")
+                .with_required_env()
+                .run()
+        }) {
+            Ok(()) => {
+                info!("Successfully rolled back to generation {number}");
+            }
+            Err(e) => {
+                // If activation fails, rollback the profile
+                if let Some(current_link) = current_link {
+                    Command::new("ln")
+                        .arg("-sfn") // Force, symbolic link
+                        .arg(&current_link)
+                        .arg(profile)
+                        .elevate(elevate)
+                        .message("Rolling back system-manager profile")
+                        .with_required_env()
+                        .run()
+                        .wrap_err("Failed to restore previous system-manager profile after failed activation")?;
+                }
+
+                return Err(eyre!("Activation failed: {}", e))
+                    .context("Failed to activate configuration");
+            }
+        }
+
+        json::emit(&json::Output::Rollback(result))
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::*;
     use clap::Parser;
 
+    use super::*;
+    use crate::interface::{Main, NHCommand};
+
+    #[test]
+    fn test_system_switch_args_parse() {
+        let args = Main::parse_from(["nh", "sys", "switch", "flake", "-H", "myhost", "--ask"]);
+        let NHCommand::Sys(SysArgs {
+            subcommand: SysSubcommand::Switch(args),
+        }) = args.command
+        else {
+            panic!("expected nh sys switch");
+        };
+        assert_eq!(args.hostname.as_deref(), Some("myhost"));
+        assert!(args.common.ask);
+        assert_eq!(args.target_host, None);
+        assert!(matches!(
+            args.common.installable,
+            Installable::Flake { ref reference, .. } if reference == "flake"
+        ));
+    }
+
     #[test]
-    fn test_system_build_args_parse() {
-        let args =
-            SystemBuildArgs::parse_from(["nh", "--flake", "flake", "--switch", "--dry-activate"]);
-        assert_eq!(args.flake.as_deref(), Some("flake"));
-        assert!(args.switch);
-        assert!(args.dry_activate);
+    fn test_system_switch_target_host() {
+        let args = Main::parse_from(["nh", "sys", "switch", "--target-host", "root@box"]);
+        let NHCommand::Sys(SysArgs {
+            subcommand: SysSubcommand::Switch(args),
+        }) = args.command
+        else {
+            panic!("expected nh sys switch");
+        };
+        assert_eq!(args.target_host.as_deref(), Some("root@box"));
     }
 }
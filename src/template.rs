@@ -0,0 +1,181 @@
+//! `--format` templates for list-producing commands.
+//!
+//! A template is plain text with `{field}` placeholders, rendered once per
+//! item, e.g. `nh os info --format '{generation} {date} {size}'`. Literal
+//! braces are written as `{{` and `}}`, and `\t` and `\n` are expanded so
+//! that tab-separated output doesn't depend on the shell's quoting.
+
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Literal(String),
+    Field(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template {
+    parts: Vec<Part>,
+    /// The template as it was written, to pass it on to another nh
+    source: String,
+}
+
+/// Something a template can be rendered for.
+pub trait Fields {
+    /// Names of the fields that can be used in a template
+    const FIELDS: &'static [&'static str];
+
+    /// Value of the field `name`, which is one of [`Self::FIELDS`]. Missing
+    /// values render as an empty string.
+    fn field(&self, name: &str) -> Option<String>;
+}
+
+impl Template {
+    /// Parse a template, for use as a clap value parser.
+    pub fn parse(template: &str) -> Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '\\' if chars.peek() == Some(&'t') => {
+                    chars.next();
+                    literal.push('\t');
+                }
+                '\\' if chars.peek() == Some(&'n') => {
+                    chars.next();
+                    literal.push('\n');
+                }
+                '{' => {
+                    let mut name = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => return Err(format!("unclosed '{{{name}'")),
+                        }
+                    }
+                    let name = name.trim();
+                    if name.is_empty() {
+                        return Err("empty field name '{}'".to_string());
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Part::Field(name.to_string()));
+                }
+                '}' => return Err("unmatched '}', write '}}' for a literal brace".to_string()),
+                c => literal.push(c),
+            }
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(Self {
+            parts,
+            source: template.to_string(),
+        })
+    }
+
+    fn field_names(&self) -> impl Iterator<Item = &str> {
+        self.parts.iter().filter_map(|part| match part {
+            Part::Field(name) => Some(name.as_str()),
+            Part::Literal(_) => None,
+        })
+    }
+
+    /// The template as it was written.
+    #[must_use]
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Whether the template refers to `field`.
+    #[must_use]
+    pub fn uses(&self, field: &str) -> bool {
+        self.field_names().any(|name| name == field)
+    }
+
+    /// Make sure the template only refers to fields of `T`.
+    pub fn check<T: Fields>(&self) -> Result<()> {
+        if let Some(unknown) = self.field_names().find(|name| !T::FIELDS.contains(name)) {
+            bail!(
+                "Unknown field '{unknown}' in --format, available fields are: {}",
+                T::FIELDS.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn render<T: Fields>(&self, item: &T) -> String {
+        self.parts
+            .iter()
+            .map(|part| match part {
+                Part::Literal(text) => text.clone(),
+                Part::Field(name) => item.field(name).unwrap_or_default(),
+            })
+            .collect()
+    }
+
+    /// Check the template against `T`, then print one line per item.
+    pub fn print_all<'a, T: Fields + 'a>(
+        &self,
+        items: impl IntoIterator<Item = &'a T>,
+    ) -> Result<()> {
+        self.check::<T>()?;
+        for item in items {
+            println!("{}", self.render(item));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Item;
+
+    impl Fields for Item {
+        const FIELDS: &'static [&'static str] = &["name", "size", "missing"];
+
+        fn field(&self, name: &str) -> Option<String> {
+            match name {
+                "name" => Some("hello".to_string()),
+                "size" => Some("42".to_string()),
+                _ => None,
+            }
+        }
+    }
+
+    #[test]
+    fn test_render() {
+        let template = Template::parse(r"{name}\t{ size } {{{missing}}}").unwrap();
+        assert_eq!(template.render(&Item), "hello\t42 {}");
+        assert!(template.uses("size"));
+        assert!(!template.uses("date"));
+        assert_eq!(template.source(), r"{name}\t{ size } {{{missing}}}");
+        assert!(template.check::<Item>().is_ok());
+    }
+
+    #[test]
+    fn test_invalid_templates() {
+        assert!(Template::parse("{name").is_err());
+        assert!(Template::parse("name}").is_err());
+        assert!(Template::parse("{}").is_err());
+        assert!(Template::parse("{date}").unwrap().check::<Item>().is_err());
+    }
+}
@@ -0,0 +1,48 @@
+//! Helpers shared by the unit tests.
+
+use std::env;
+use std::ffi::{OsStr, OsString};
+
+/// Sets an environment variable until it is dropped, also when the test
+/// panics, and restores the previous value then. The environment is shared
+/// by all tests, so tests using it have to be `#[serial]`.
+pub struct EnvGuard {
+    key: String,
+    original: Option<OsString>,
+}
+
+impl EnvGuard {
+    pub fn new(key: &str, value: impl AsRef<OsStr>) -> Self {
+        let original = env::var_os(key);
+        unsafe {
+            env::set_var(key, value);
+        }
+        Self {
+            key: key.to_string(),
+            original,
+        }
+    }
+
+    /// Unset `key` until the guard is dropped.
+    pub fn remove(key: &str) -> Self {
+        let original = env::var_os(key);
+        unsafe {
+            env::remove_var(key);
+        }
+        Self {
+            key: key.to_string(),
+            original,
+        }
+    }
+}
+
+impl Drop for EnvGuard {
+    fn drop(&mut self) {
+        unsafe {
+            match &self.original {
+                Some(value) => env::set_var(&self.key, value),
+                None => env::remove_var(&self.key),
+            }
+        }
+    }
+}
@@ -0,0 +1,240 @@
+//! Output colors, and whether to use them at all.
+//!
+//! Colors follow `--color`. Left on `auto`, they are disabled by a non-empty
+//! `NO_COLOR`, forced by a non-empty `CLICOLOR_FORCE` other than `0`, and
+//! otherwise only used when writing to a terminal. Every colored piece of
+//! output has a [`Role`], whose style can be changed in the `[theme]` section
+//! of the configuration:
+//!
+//! ```toml
+//! [theme]
+//! error = "bold bright-red"
+//! heading = "#5f87af"
+//! muted = "bright-black"
+//! ```
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt::Display;
+use std::io::IsTerminal;
+use std::sync::{LazyLock, OnceLock};
+
+use clap::ValueEnum;
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use owo_colors::{DynColors, OwoColorize, Style};
+use serde::Deserialize;
+
+static THEME: OnceLock<Theme> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum ColorChoice {
+    /// Use colors when writing to a terminal, unless NO_COLOR is set
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl ColorChoice {
+    /// Whether to color output for a stream, given whether it's a terminal.
+    #[must_use]
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        let set = |var: &str| env::var_os(var).filter(|value| !value.is_empty());
+
+        match self {
+            Self::Always => true,
+            Self::Never => false,
+            Self::Auto if set("NO_COLOR").is_some() => false,
+            Self::Auto if set("CLICOLOR_FORCE").is_some_and(|value| value != "0") => true,
+            Self::Auto => is_terminal,
+        }
+    }
+}
+
+/// What a piece of colored output means, to pick its style.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Role {
+    Error,
+    Warning,
+    Info,
+    Debug,
+    Trace,
+    Success,
+    /// Things that are about to be deleted
+    Removed,
+    Pinned,
+    Heading,
+    /// Names of things, like packages
+    Name,
+    /// Values worth noticing, like versions or settings
+    Value,
+    /// Literal command line arguments
+    Literal,
+    Link,
+    Muted,
+    Emphasis,
+}
+
+impl Role {
+    fn default_style(self) -> Style {
+        let style = Style::new();
+        match self {
+            Self::Error | Self::Removed => style.red(),
+            Self::Warning | Self::Literal => style.yellow(),
+            Self::Info | Self::Success | Self::Value => style.green(),
+            Self::Debug | Self::Name => style.blue(),
+            Self::Trace => style.bright_blue(),
+            Self::Pinned => style.cyan(),
+            Self::Heading => style.blue().bold(),
+            Self::Link => style.underline(),
+            Self::Muted => style.dimmed(),
+            Self::Emphasis => style.bold(),
+        }
+    }
+}
+
+/// Parse a style like `bold bright-red` or `#ff8700`.
+fn parse_style(spec: &str) -> Option<Style> {
+    let mut style = Style::new();
+    let mut words = spec.split_whitespace();
+
+    while let Some(word) = words.next() {
+        style = match word {
+            "bold" => style.bold(),
+            "dimmed" => style.dimmed(),
+            "italic" => style.italic(),
+            "underline" => style.underline(),
+            // Also accept owo-colors' spelling, `bright red`
+            "bright" => {
+                let color = words.next()?;
+                style.color(format!("bright {color}").parse::<DynColors>().ok()?)
+            }
+            color => style.color(color.replace('-', " ").parse::<DynColors>().ok()?),
+        };
+    }
+
+    Some(style)
+}
+
+#[derive(Debug)]
+struct Theme {
+    stdout: bool,
+    stderr: bool,
+    styles: BTreeMap<Role, Style>,
+}
+
+impl Theme {
+    fn new(choice: ColorChoice, overrides: &BTreeMap<Role, String>) -> Result<Self> {
+        let mut styles = BTreeMap::new();
+        for (role, spec) in overrides {
+            let Some(style) = parse_style(spec) else {
+                bail!(
+                    "Invalid style '{spec}' for {} in the theme, expected colors like 'red', 'bright-blue' or '#ff8700', optionally with bold, dimmed, italic or underline",
+                    format!("{role:?}").to_lowercase()
+                );
+            };
+            styles.insert(*role, style);
+        }
+
+        Ok(Self {
+            stdout: choice.enabled(std::io::stdout().is_terminal()),
+            stderr: choice.enabled(std::io::stderr().is_terminal()),
+            styles,
+        })
+    }
+
+    fn style(&self, role: Role) -> Style {
+        self.styles
+            .get(&role)
+            .copied()
+            .unwrap_or_else(|| role.default_style())
+    }
+
+    fn paint(&self, text: impl Display, role: Role, enabled: bool) -> String {
+        if enabled {
+            text.style(self.style(role)).to_string()
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+/// Decide on colors for the rest of the run and load the theme from the
+/// configuration.
+pub fn init(choice: ColorChoice, overrides: &BTreeMap<Role, String>) -> Result<()> {
+    let theme = Theme::new(choice, overrides)?;
+
+    // dix draws package diffs with yansi, which has its own switch
+    if theme.stdout {
+        yansi::enable();
+    } else {
+        yansi::disable();
+    }
+
+    let _ = THEME.set(theme);
+    Ok(())
+}
+
+/// The theme set by [`init`], or the default one for output before that.
+fn theme() -> &'static Theme {
+    static DEFAULT: LazyLock<Theme> = LazyLock::new(|| {
+        Theme::new(ColorChoice::Auto, &BTreeMap::new()).expect("The default theme is valid")
+    });
+    THEME.get().unwrap_or(&DEFAULT)
+}
+
+/// Style `text` for `role`, if colors are enabled on stdout.
+#[must_use]
+pub fn paint(text: impl Display, role: Role) -> String {
+    let theme = theme();
+    theme.paint(text, role, theme.stdout)
+}
+
+/// Style `text` for `role`, if colors are enabled on stderr.
+#[must_use]
+pub fn paint_err(text: impl Display, role: Role) -> String {
+    let theme = theme();
+    theme.paint(text, role, theme.stderr)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_style() {
+        assert_eq!(parse_style("red"), Some(Style::new().red()));
+        assert_eq!(
+            parse_style("bold bright-red"),
+            Some(Style::new().bold().bright_red())
+        );
+        assert_eq!(parse_style("bright blue"), Some(Style::new().bright_blue()));
+        assert_eq!(
+            parse_style("#ff8700"),
+            Some(Style::new().color(DynColors::Rgb(0xff, 0x87, 0x00)))
+        );
+        assert_eq!(parse_style("reddish"), None);
+        assert_eq!(parse_style("bright"), None);
+    }
+
+    #[test]
+    fn test_paint() {
+        let theme = Theme {
+            stdout: true,
+            stderr: false,
+            styles: BTreeMap::from([(Role::Error, Style::new().bold())]),
+        };
+
+        assert_eq!(theme.paint("x", Role::Error, false), "x");
+        assert_eq!(
+            theme.paint("x", Role::Error, true),
+            "x".style(Style::new().bold()).to_string()
+        );
+        assert_eq!(
+            theme.paint("x", Role::Success, true),
+            "x".style(Style::new().green()).to_string()
+        );
+    }
+}
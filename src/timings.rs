@@ -0,0 +1,171 @@
+//! Phase timing report for `--timings`.
+//!
+//! Every [`events::phase`](crate::events::phase) records how long it took.
+//! At the end of a run the breakdown is printed to stderr, next to the
+//! timings of the previous run of the same command, which are kept in the
+//! state directory.
+
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::events::Phase;
+use crate::state;
+
+const STATE_FILE: &str = "timings.json";
+
+/// When the run started, set once timings are enabled
+static STARTED: OnceLock<Instant> = OnceLock::new();
+static RECORDED: Mutex<Vec<PhaseTiming>> = Mutex::new(Vec::new());
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PhaseTiming {
+    pub phase: Phase,
+    pub elapsed_ms: u64,
+}
+
+/// Timings of a whole run
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RunTimings {
+    pub date: String,
+    pub phases: Vec<PhaseTiming>,
+    pub total_ms: u64,
+}
+
+impl RunTimings {
+    /// Total time spent in `phase`, which can run more than once.
+    fn phase_ms(&self, phase: Phase) -> Option<u64> {
+        self.phases
+            .iter()
+            .filter(|timing| timing.phase == phase)
+            .map(|timing| timing.elapsed_ms)
+            .reduce(|a, b| a + b)
+    }
+
+    /// Phases in the order they first ran, with their total time.
+    fn totals(&self) -> Vec<(Phase, u64)> {
+        let mut totals: Vec<(Phase, u64)> = Vec::new();
+        for timing in &self.phases {
+            match totals.iter_mut().find(|(phase, _)| *phase == timing.phase) {
+                Some((_, total)) => *total += timing.elapsed_ms,
+                None => totals.push((timing.phase, timing.elapsed_ms)),
+            }
+        }
+        totals
+    }
+}
+
+/// Last run of each command, keyed by the subcommand like `os switch`
+type TimingsState = BTreeMap<String, RunTimings>;
+
+/// Start recording phase timings.
+pub fn enable() {
+    let _ = STARTED.set(Instant::now());
+}
+
+/// Record that `phase` took `elapsed`. Timings are always recorded for
+/// `nh stats`, and only printed if enabled.
+pub fn record(phase: Phase, elapsed: Duration) {
+    if let Ok(mut recorded) = RECORDED.lock() {
+        recorded.push(PhaseTiming {
+            phase,
+            elapsed_ms: u64::try_from(elapsed.as_millis()).unwrap_or(u64::MAX),
+        });
+    }
+}
+
+/// The phases recorded so far.
+pub fn recorded() -> Vec<PhaseTiming> {
+    RECORDED.lock().map(|r| r.clone()).unwrap_or_default()
+}
+
+/// Format a duration in milliseconds, like `1m 23s`.
+pub fn format_ms(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0..60 => format!("{:.1}s", ms as f64 / 1000.0),
+        60..3600 => format!("{}m {:02}s", secs / 60, secs % 60),
+        _ => format!("{}h {:02}m", secs / 3600, (secs % 3600) / 60),
+    }
+}
+
+fn print_report(current: &RunTimings, previous: Option<&RunTimings>) {
+    let compare = |ms: u64, previous_ms: Option<u64>| match previous_ms {
+        Some(previous_ms) => format!(
+            "{:<10} (previous: {})",
+            format_ms(ms),
+            format_ms(previous_ms)
+        ),
+        None => format_ms(ms),
+    };
+
+    eprintln!("Timings:");
+    for (phase, ms) in current.totals() {
+        eprintln!(
+            "  {:<12} {}",
+            phase.name(),
+            compare(ms, previous.and_then(|previous| previous.phase_ms(phase)))
+        );
+    }
+    eprintln!(
+        "  {:<12} {}",
+        "total",
+        compare(current.total_ms, previous.map(|previous| previous.total_ms))
+    );
+}
+
+/// Print the timing breakdown for `command` and remember it for the next run.
+pub fn report(command: &str) -> Result<()> {
+    let Some(started) = STARTED.get() else {
+        return Ok(());
+    };
+
+    let current = RunTimings {
+        date: chrono::Local::now().to_rfc3339(),
+        phases: recorded(),
+        total_ms: u64::try_from(started.elapsed().as_millis()).unwrap_or(u64::MAX),
+    };
+
+    let mut state: TimingsState = state::load(STATE_FILE).unwrap_or_else(|err| {
+        debug!("Ignoring unreadable timings: {err:#}");
+        TimingsState::new()
+    });
+    print_report(&current, state.get(command));
+
+    state.insert(command.to_string(), current);
+    state::save(STATE_FILE, &state)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_phase_totals() {
+        let timing = |phase, elapsed_ms| PhaseTiming { phase, elapsed_ms };
+        let run = RunTimings {
+            date: String::new(),
+            phases: vec![
+                timing(Phase::Build, 1000),
+                timing(Phase::Copy, 200),
+                timing(Phase::Build, 500),
+            ],
+            total_ms: 2000,
+        };
+
+        assert_eq!(run.totals(), vec![(Phase::Build, 1500), (Phase::Copy, 200)]);
+        assert_eq!(run.phase_ms(Phase::Copy), Some(200));
+        assert_eq!(run.phase_ms(Phase::Gc), None);
+    }
+
+    #[test]
+    fn test_format_ms() {
+        assert_eq!(format_ms(460), "0.5s");
+        assert_eq!(format_ms(83_000), "1m 23s");
+        assert_eq!(format_ms(3_720_000), "1h 02m");
+    }
+}
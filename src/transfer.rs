@@ -0,0 +1,235 @@
+//! Copying configurations to `--target-host` with `nix copy`, and what the
+//! copy cost.
+//!
+//! Over slow links the copy is often the longest part of a deploy. It can be
+//! compressed and spread over several ssh connections, and afterwards nh
+//! reports how much of the closure had to be sent, so the effect of tuning
+//! either can be measured.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use color_eyre::Result;
+use color_eyre::eyre::bail;
+use subprocess::Redirection;
+use tracing::{debug, info, warn};
+
+use crate::commands::{self, Command, ssh_cmd};
+use crate::events::{self, Phase};
+use crate::interface::CopyArgs;
+use crate::json::CopyStats;
+use crate::util;
+
+/// The store URL of `target_host`, with the settings of `args`, of the store
+/// under `root` if it is set.
+#[must_use]
+pub fn store_url(target_host: &str, args: &CopyArgs, root: Option<&Path>) -> String {
+    let mut params = Vec::new();
+    if let Some(root) = root {
+        params.push(format!("remote-store=local%3Froot%3D{}", root.display()));
+    }
+    if args.copy_compress {
+        params.push("compress=true".to_string());
+    }
+    if let Some(connections) = args.copy_connections {
+        params.push(format!("max-connections={connections}"));
+    }
+
+    if params.is_empty() {
+        format!("ssh://{target_host}")
+    } else {
+        format!("ssh://{target_host}?{}", params.join("&"))
+    }
+}
+
+/// Copy the closure of `path` to `target_host`, returning what was copied if
+/// it could be found out.
+///
+/// With `root`, the closure goes to the store under it, like that of a system
+/// being installed, instead of the one the host runs on. Nothing is reported
+/// then, as that store is new.
+pub fn copy_to_host(
+    target_host: &str,
+    path: &Path,
+    args: &CopyArgs,
+    eval_args: Vec<String>,
+    root: Option<&Path>,
+) -> Result<Option<CopyStats>> {
+    // Only what the target doesn't have yet is sent
+    let closure = root
+        .is_none()
+        .then(|| closure_nar_sizes(path))
+        .transpose()
+        .inspect_err(|err| debug!("Couldn't query the closure of {}: {err:#}", path.display()))
+        .ok()
+        .flatten();
+    let missing = closure.as_ref().and_then(|closure| {
+        missing_on(target_host, closure.iter().map(|(path, _)| path.as_str()))
+            .inspect_err(|err| {
+                warn!("Couldn't query the paths on {target_host}, not reporting the copy: {err:#}");
+            })
+            .ok()
+    });
+
+    let start = Instant::now();
+    events::phase(Phase::Copy, || {
+        Command::new("nix")
+            .args(["copy", "--to", &store_url(target_host, args, root)])
+            .arg(path)
+            .args(eval_args)
+            .message("Copying configuration to target")
+            .progress(true)
+            .with_required_env()
+            .env("NIX_SSHOPTS", commands::nix_sshopts(target_host))
+            .run()
+    })?;
+    let elapsed = start.elapsed();
+
+    let (Some(closure), Some(missing)) = (closure, missing) else {
+        return Ok(None);
+    };
+    let stats = copy_stats(&closure, &missing, elapsed);
+    info!(
+        "Copied {} of {} paths, {} in {:.1}s ({}/s), {:.0}% of the closure was already on {target_host}",
+        stats.paths_copied,
+        stats.paths_total,
+        util::format_bytes(stats.bytes_copied),
+        elapsed.as_secs_f64(),
+        util::format_bytes(stats.throughput),
+        stats.dedup_ratio * 100.0,
+    );
+    Ok(Some(stats))
+}
+
+/// The store paths of the closure of `path` with their NAR sizes.
+fn closure_nar_sizes(path: &Path) -> Result<Vec<(String, u64)>> {
+    let json = Command::new("nix")
+        .args(["path-info", "--recursive", "--json"])
+        .arg(path)
+        .with_required_env()
+        .run_capture()?
+        .unwrap_or_default();
+    Ok(parse_nar_sizes(&json))
+}
+
+/// Parse the output of `nix path-info --json`, which is a list of objects in
+/// older versions of Nix and an object keyed by path in newer ones.
+fn parse_nar_sizes(json: &str) -> Vec<(String, u64)> {
+    match serde_json::from_str::<serde_json::Value>(json) {
+        Ok(serde_json::Value::Array(infos)) => infos
+            .iter()
+            .filter_map(|info| {
+                Some((
+                    info["path"].as_str()?.to_string(),
+                    info["narSize"].as_u64()?,
+                ))
+            })
+            .collect(),
+        Ok(serde_json::Value::Object(infos)) => infos
+            .iter()
+            .filter_map(|(path, info)| Some((path.clone(), info["narSize"].as_u64()?)))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Which of `paths` aren't in the store of `target_host`. Fails if the
+/// query does, rather than taking the whole closure for present.
+fn missing_on<'a>(
+    target_host: &str,
+    paths: impl IntoIterator<Item = &'a str>,
+) -> Result<BTreeSet<String>> {
+    // A closure has thousands of paths, too many for one command line, so
+    // they go to xargs on stdin
+    let input: String = paths.into_iter().map(|path| format!("{path}\n")).collect();
+    let cmd = ssh_cmd(target_host)
+        .arg("xargs nix-store --check-validity --print-invalid")
+        .stdin(input.as_str())
+        .stdout(Redirection::Pipe)
+        .stderr(Redirection::Pipe);
+    debug!(?cmd);
+    let capture = cmd.capture()?;
+    // --print-invalid succeeds with invalid paths, so this is ssh or nix failing
+    if !capture.exit_status.success() {
+        bail!(
+            "Querying the store of {target_host} failed ({:?}): {}",
+            capture.exit_status,
+            capture.stderr_str().trim()
+        );
+    }
+    Ok(capture.stdout_str().lines().map(str::to_string).collect())
+}
+
+/// What copying the `missing` paths of `closure` in `elapsed` amounts to.
+fn copy_stats(
+    closure: &[(String, u64)],
+    missing: &BTreeSet<String>,
+    elapsed: Duration,
+) -> CopyStats {
+    let bytes_total: u64 = closure.iter().map(|(_, size)| size).sum();
+    let (paths_copied, bytes_copied) = closure
+        .iter()
+        .filter(|(path, _)| missing.contains(path))
+        .fold((0, 0), |(paths, bytes), (_, size)| {
+            (paths + 1, bytes + size)
+        });
+
+    CopyStats {
+        paths_total: closure.len(),
+        paths_copied,
+        bytes_total,
+        bytes_copied,
+        dedup_ratio: if bytes_total == 0 {
+            1.0
+        } else {
+            1.0 - bytes_copied as f64 / bytes_total as f64
+        },
+        throughput: (bytes_copied as f64 / elapsed.as_secs_f64().max(0.001)) as u64,
+        seconds: elapsed.as_secs_f64(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_store_url() {
+        assert_eq!(store_url("web", &CopyArgs::default(), None), "ssh://web");
+        assert_eq!(
+            store_url(
+                "root@web",
+                &CopyArgs {
+                    copy_compress: true,
+                    copy_connections: Some(4),
+                },
+                None
+            ),
+            "ssh://root@web?compress=true&max-connections=4"
+        );
+        assert_eq!(
+            store_url("web", &CopyArgs::default(), Some(Path::new("/mnt"))),
+            "ssh://web?remote-store=local%3Froot%3D/mnt"
+        );
+    }
+
+    #[test]
+    fn test_copy_stats() {
+        let closure = parse_nar_sizes(
+            r#"{"/nix/store/a": {"narSize": 300}, "/nix/store/b": {"narSize": 100}, "/nix/store/c": null}"#,
+        );
+        assert_eq!(
+            parse_nar_sizes(r#"[{"path": "/nix/store/a", "narSize": 300}]"#),
+            [("/nix/store/a".to_string(), 300)]
+        );
+
+        let missing = BTreeSet::from(["/nix/store/b".to_string()]);
+        let stats = copy_stats(&closure, &missing, Duration::from_secs(2));
+        assert_eq!(stats.paths_total, 2);
+        assert_eq!(stats.paths_copied, 1);
+        assert_eq!(stats.bytes_copied, 100);
+        assert_eq!(stats.throughput, 50);
+        assert!((stats.dedup_ratio - 0.75).abs() < f64::EPSILON);
+    }
+}
@@ -1,12 +1,146 @@
-use tracing::warn;
+use std::path::PathBuf;
+use std::thread;
+
+use color_eyre::eyre::{Context, bail};
+use tracing::{debug, info, warn};
 
 use crate::Result;
+use crate::channels;
 use crate::commands::Command;
-use crate::installable::Installable;
+use crate::generations::local_flake_dir;
+use crate::installable::{self, Installable};
+use crate::interface::{self, UpdateArgs};
+use crate::json::{self, InputStatus, Output};
+use crate::lockfile::{self, LockFile, LockGuard, Staleness};
+
+/// A flake update whose result still depends on the build that follows it.
+///
+/// Dropping it without calling [`PendingUpdate::finish`] restores the old
+/// lock file if `--revert-on-failure` was passed.
+#[derive(Debug)]
+#[must_use]
+pub struct PendingUpdate {
+    lock_path: PathBuf,
+    old_lock: Option<LockFile>,
+    guard: Option<LockGuard>,
+    commit: bool,
+    branch: Option<String>,
+}
+
+impl PendingUpdate {
+    /// Keep the updated lock file after the configuration was built (and
+    /// activated) successfully, committing it if requested.
+    pub fn finish(mut self) -> Result<()> {
+        if let Some(guard) = self.guard.take() {
+            guard.keep();
+        }
+
+        if self.commit {
+            self.commit_lock()?;
+        }
+
+        Ok(())
+    }
+
+    fn commit_lock(&self) -> Result<()> {
+        let dir = self
+            .lock_path
+            .parent()
+            .map(PathBuf::from)
+            .unwrap_or_default();
+        let git = || Command::new("git").arg("-C").arg(&dir);
+
+        let status = git()
+            .args(["status", "--porcelain", "--", "flake.lock"])
+            .run_capture()
+            .wrap_err("Failed to check the git status of flake.lock")?
+            .unwrap_or_default();
+        if status.trim().is_empty() {
+            info!("flake.lock is unchanged, nothing to commit");
+            return Ok(());
+        }
+
+        let changes = match (&self.old_lock, LockFile::read(&self.lock_path)) {
+            (Some(old), Ok(new)) => lockfile::diff(old, &new),
+            _ => Vec::new(),
+        };
+        let message = if changes.is_empty() {
+            "flake.lock: update\n".to_string()
+        } else {
+            lockfile::commit_message(&changes)
+        };
+
+        if let Some(branch) = &self.branch {
+            git()
+                .args(["switch", "--create", branch])
+                .message(format!("Creating branch {branch}"))
+                .run()?;
+        }
+
+        git().args(["add", "--", "flake.lock"]).run()?;
+        git()
+            .args(["commit", "--message", &message, "--", "flake.lock"])
+            .message("Committing flake.lock")
+            .run()
+            .wrap_err("Failed to commit flake.lock")?;
+
+        Ok(())
+    }
+}
+
+/// Update the flake inputs as requested by `args`, or the channels for
+/// installables that aren't flakes, those of root with `system_channels`.
+///
+/// Returns the pending update for local flakes, which callers keep around
+/// until the updated configuration was built, then [`PendingUpdate::finish`].
+pub fn update(
+    installable: &Installable,
+    args: &UpdateArgs,
+    system_channels: bool,
+) -> Result<Option<PendingUpdate>> {
+    let mut pending = None;
 
-pub fn update(installable: &Installable, inputs: Option<Vec<String>>) -> Result<()> {
     match installable {
         Installable::Flake { reference, .. } => {
+            let lock_path = local_flake_dir(reference).map(|dir| dir.join("flake.lock"));
+            let old_lock = lock_path
+                .as_deref()
+                .and_then(|path| LockFile::read(path).ok());
+
+            let inputs = if args.update_interactive {
+                let Some(old_lock) = &old_lock else {
+                    bail!("--update-interactive needs a local flake with a flake.lock");
+                };
+                let inputs = select_inputs(old_lock)?;
+                if inputs.is_empty() {
+                    info!("No flake inputs selected, not updating");
+                    return Ok(None);
+                }
+                Some(inputs)
+            } else {
+                args.update_input.clone()
+            };
+
+            match &lock_path {
+                Some(path) => {
+                    pending = Some(PendingUpdate {
+                        lock_path: path.clone(),
+                        old_lock: old_lock.clone(),
+                        guard: if args.revert_on_failure {
+                            Some(LockGuard::snapshot(path)?)
+                        } else {
+                            None
+                        },
+                        commit: args.update_commit,
+                        branch: args.update_branch.clone(),
+                    });
+                }
+                None if args.revert_on_failure || args.update_commit => {
+                    warn!("--revert-on-failure and --update-commit only work with local flakes");
+                }
+                None => {}
+            }
+
             let mut cmd = Command::new("nix").args(["flake", "update"]);
 
             if let Some(inputs) = inputs {
@@ -23,14 +157,151 @@ pub fn update(installable: &Installable, inputs: Option<Vec<String>>) -> Result<
             }
 
             cmd.arg("--flake").arg(reference).run()?;
+
+            if let (Some(path), Some(old_lock)) = (lock_path, old_lock) {
+                match LockFile::read(&path) {
+                    Ok(new_lock) => lockfile::print_summary(&old_lock, &new_lock),
+                    Err(err) => debug!("Not summarizing the update: {err:#}"),
+                }
+            }
+        }
+        Installable::File { .. } | Installable::Expression { .. } => {
+            if args.update_interactive || args.revert_on_failure || args.update_commit {
+                warn!(
+                    "--update-interactive, --revert-on-failure and --update-commit only work with flakes"
+                );
+            }
+            channels::update(
+                args.update_input.as_deref().unwrap_or_default(),
+                system_channels,
+            )?;
         }
         _ => {
             warn!(
-                "Only flake installables can be updated, {} is not supported",
+                "Only flake installables and channels can be updated, {} is not supported",
                 installable.str_kind()
             );
         }
     }
 
-    Ok(())
+    Ok(pending)
+}
+
+/// Let the user pick inputs to update from the direct inputs of a lock file.
+fn select_inputs(lock: &LockFile) -> Result<Vec<String>> {
+    let inputs = lock.inputs();
+    if inputs.is_empty() {
+        bail!("The flake has no inputs to update");
+    }
+
+    let width = inputs.keys().map(String::len).max().unwrap_or_default();
+    let items: Vec<String> = inputs
+        .iter()
+        .map(|(name, locked)| {
+            let age = locked
+                .age()
+                .map(|age| format!(", {age} old"))
+                .unwrap_or_default();
+            format!("{name:<width$}  ({}{age})", locked.short_rev())
+        })
+        .collect();
+
+    let selected = dialoguer::MultiSelect::new()
+        .with_prompt("Select flake inputs to update (space to toggle, enter to confirm)")
+        .items(&items)
+        .interact()?;
+
+    let names: Vec<&String> = inputs.keys().collect();
+    Ok(selected.into_iter().map(|i| names[i].clone()).collect())
+}
+
+impl interface::UpdateProxy {
+    pub fn run(&self) -> Result<()> {
+        match (&self.command, &self.channels) {
+            (Some(command), _) => command.run(),
+            (None, Some(channels)) => channels::update(channels, self.system),
+            (None, None) => bail!("Nothing to update, pass --channels or a subcommand"),
+        }
+    }
+}
+
+impl interface::UpdateCommand {
+    pub fn run(&self) -> Result<()> {
+        match self {
+            Self::Status(args) => args.status(),
+        }
+    }
+}
+
+impl interface::UpdateStatusArgs {
+    fn status(&self) -> Result<()> {
+        let flake = installable::flake_reference(self.flake.as_deref())?;
+        let Some(dir) = local_flake_dir(&flake) else {
+            bail!("{flake} is not a local flake");
+        };
+        let lock = LockFile::read(&dir.join("flake.lock"))?;
+        let inputs = lock.inputs();
+
+        // Upstream queries are network bound, so run them all at once
+        let staleness: Vec<Option<Staleness>> = thread::scope(|scope| {
+            let handles: Vec<_> = inputs
+                .iter()
+                .map(|(name, locked)| {
+                    let original_ref = lock.original_ref(name);
+                    scope.spawn(move || {
+                        (!self.offline).then(|| lockfile::staleness(locked, original_ref))
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().unwrap_or(Some(Staleness::Unknown)))
+                .collect()
+        });
+
+        if json::output_enabled() {
+            let statuses = inputs
+                .iter()
+                .zip(staleness)
+                .map(|((name, locked), staleness)| InputStatus {
+                    name: name.clone(),
+                    rev: locked.short_rev(),
+                    last_modified: locked.last_modified,
+                    status: staleness.map(|s| s.to_string()),
+                })
+                .collect();
+            return json::emit(&Output::Update(statuses));
+        }
+
+        let width = inputs
+            .keys()
+            .map(String::len)
+            .chain(std::iter::once("INPUT".len()))
+            .max()
+            .unwrap_or_default();
+
+        println!(
+            "{:<width$}  {:<7}  {:<10}  {:<10}  STATUS",
+            "INPUT", "REV", "LOCKED", "AGE"
+        );
+        for ((name, locked), staleness) in inputs.iter().zip(staleness) {
+            let locked_date = locked
+                .last_modified
+                .and_then(|modified| chrono::DateTime::from_timestamp(modified, 0))
+                .map_or_else(
+                    || "unknown".to_string(),
+                    |date| date.format("%Y-%m-%d").to_string(),
+                );
+
+            println!(
+                "{name:<width$}  {:<7}  {locked_date:<10}  {:<10}  {}",
+                locked.short_rev(),
+                locked.age().unwrap_or_default(),
+                staleness.map(|s| s.to_string()).unwrap_or_default()
+            );
+        }
+
+        Ok(())
+    }
 }
@@ -1,12 +1,12 @@
 use std::sync::LazyLock;
 use std::{
-    collections::HashSet,
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
     fmt,
-    io::{self},
     path::{Path, PathBuf},
     process::{Command as StdCommand, Stdio},
     str,
-    sync::OnceLock,
+    sync::{Mutex, OnceLock},
 };
 
 use color_eyre::Result;
@@ -22,17 +22,57 @@ pub enum NixVariant {
     Nix,
     Lix,
     Determinate,
+    /// A fork nh doesn't know about, with the name it reports itself as
+    Other(String),
 }
 
-static NIX_VARIANT: OnceLock<NixVariant> = OnceLock::new();
+impl NixVariant {
+    /// Detect the variant from the output of `nix --version`, which looks
+    /// like `nix (Nix) 2.28.4`, `nix (Lix, like Nix) 2.91.1` or
+    /// `nix (Determinate Nix 3.6.2) 2.29.0`.
+    #[must_use]
+    pub fn from_version_output(output: &str) -> Self {
+        let name = output
+            .split_once('(')
+            .and_then(|(_, rest)| rest.split_once(')'))
+            .map_or(output, |(name, _)| name)
+            .trim();
+        let name_lower = name.to_lowercase();
+
+        if name_lower.contains("determinate") {
+            Self::Determinate
+        } else if name_lower.contains("lix") {
+            Self::Lix
+        } else if name_lower == "nix" || name_lower.contains("nix)") || name.is_empty() {
+            Self::Nix
+        } else {
+            Self::Other(name.to_string())
+        }
+    }
 
-struct WriteFmt<W: io::Write>(W);
+    #[must_use]
+    pub fn name(&self) -> &str {
+        match self {
+            Self::Nix => "Nix",
+            Self::Lix => "Lix",
+            Self::Determinate => "Determinate Nix",
+            Self::Other(name) => name,
+        }
+    }
+}
 
-impl<W: io::Write> fmt::Write for WriteFmt<W> {
-    fn write_str(&mut self, string: &str) -> fmt::Result {
-        self.0.write_all(string.as_bytes()).map_err(|_| fmt::Error)
+impl fmt::Display for NixVariant {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
     }
 }
+
+static NIX_VARIANT: OnceLock<NixVariant> = OnceLock::new();
+
+/// Names of the configurations in flake outputs, keyed by `<flake>#<output>`
+static FLAKE_CONFIGURATIONS: LazyLock<Mutex<HashMap<String, Option<Vec<String>>>>> =
+    LazyLock::new(Mutex::default);
+
 /// Get the Nix variant (cached)
 pub fn get_nix_variant() -> &'static NixVariant {
     NIX_VARIANT.get_or_init(|| {
@@ -49,17 +89,7 @@ pub fn get_nix_variant() -> &'static NixVariant {
             None => return NixVariant::Nix, // default to standard Nix variant
         };
 
-        let output_lower = output_str.to_lowercase();
-
-        // FIXME: This fails to account for Nix variants we don't check for and
-        // assumes the environment is mainstream Nix.
-        if output_lower.contains("determinate") {
-            NixVariant::Determinate
-        } else if output_lower.contains("lix") {
-            NixVariant::Lix
-        } else {
-            NixVariant::Nix
-        }
+        NixVariant::from_version_output(output_str.lines().next().unwrap_or_default())
     });
 
     NIX_VARIANT
@@ -150,6 +180,35 @@ pub fn get_nix_version() -> Result<String> {
     Ok(version_str.to_string())
 }
 
+/// The Nix-compatible version in the output of `nix --version`, which is
+/// the last word. Forks like Determinate Nix put their own version before it.
+#[must_use]
+pub fn nix_version_number(version_output: &str) -> &str {
+    version_output
+        .split_whitespace()
+        .last()
+        .unwrap_or(version_output)
+}
+
+/// Version information shown by `nh --version`, including the detected
+/// Nix variant.
+#[must_use]
+pub fn long_version() -> String {
+    let rev = crate::NH_REV
+        .map(|rev| format!(" ({rev})"))
+        .unwrap_or_default();
+    let nix = match get_nix_version() {
+        Ok(version) => format!("{} {}", get_nix_variant(), nix_version_number(&version)),
+        Err(_) => "not found".to_string(),
+    };
+
+    format!(
+        "{}{rev}
+Nix: {nix}",
+        crate::NH_VERSION
+    )
+}
+
 /// Prompts the user for ssh key login if needed
 pub fn ensure_ssh_key_login() -> Result<()> {
     // ssh-add -L checks if there are any currently usable ssh keys
@@ -283,50 +342,308 @@ pub fn get_missing_experimental_features(required_features: &[&str]) -> Result<V
 /// let elevate: fn() -> ! = nh::util::self_elevate;
 /// ```
 pub fn self_elevate() -> ! {
-    use std::os::unix::process::CommandExt;
+    use std::os::unix::process::{CommandExt, ExitStatusExt};
+
+    // The elevated nh can't write to the descriptor of the event stream, see
+    // `events::relay`. nh has to wait for it to relay its events, instead of
+    // being replaced by it
+    if crate::events::enabled() {
+        let status = crate::events::relay(|fifo| {
+            let cmd = crate::commands::Command::self_elevate_cmd(&[(
+                crate::events::RELAY_VAR,
+                fifo.to_string_lossy().into_owned(),
+            )]);
+            debug!("{:?}", cmd);
+            cmd
+        });
+        std::process::exit(match status {
+            Ok(status) => status
+                .code()
+                .unwrap_or_else(|| 128 + status.signal().unwrap_or_default()),
+            Err(err) => {
+                eprintln!("Failed to elevate: {err:#}");
+                1
+            }
+        });
+    }
 
-    let mut cmd = crate::commands::Command::self_elevate_cmd();
+    let mut cmd = crate::commands::Command::self_elevate_cmd(&[]);
     debug!("{:?}", cmd);
     let err = cmd.exec();
     panic!("{}", err);
 }
 
-/// Prints the difference between two generations in terms of paths and closure sizes.
-///
-/// # Arguments
-///
-/// * `old_generation` - A reference to the path of the old generation.
-/// * `new_generation` - A reference to the path of the new generation.
-///
-/// # Returns
+/// List the attribute names of a flake output, e.g. the hosts in
+/// `nixosConfigurations`. Results are cached for the lifetime of the process.
 ///
-/// Returns `Ok(())` if the operation completed successfully, or an error wrapped in `eyre::Result` if something went wrong.
-///
-/// # Errors
+/// Returns `None` if the output couldn't be evaluated.
+pub fn list_flake_configurations<I>(
+    reference: &str,
+    output: &str,
+    eval_args: I,
+) -> Option<Vec<String>>
+where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+{
+    let attr = format!("{reference}#{output}");
+    let mut cache = FLAKE_CONFIGURATIONS.lock().ok()?;
+    if let Some(names) = cache.get(&attr) {
+        return names.clone();
+    }
+
+    let names = Command::new("nix")
+        .with_required_env()
+        .args(["eval", "--json", "--apply", "builtins.attrNames"])
+        .args(eval_args)
+        .arg(&attr)
+        .run_capture()
+        .ok()
+        .flatten()
+        .and_then(|out| serde_json::from_str::<Vec<String>>(&out).ok());
+
+    debug!(?attr, ?names, "Listed flake configurations");
+    cache.insert(attr, names.clone());
+    names
+}
+
+/// Make sure that a configuration exists in a flake output before starting
+/// a potentially long build, and point out the available names if it doesn't.
 ///
-/// Returns an error if the closure size thread panics or if writing size differences fails.
-pub fn print_dix_diff(old_generation: &Path, new_generation: &Path) -> Result<()> {
-    let mut out = WriteFmt(io::stdout());
+/// If the output can't be listed, the check is skipped and the build is left
+/// to report the problem.
+pub fn ensure_flake_configuration<I>(
+    reference: &str,
+    output: &str,
+    name: &str,
+    eval_args: I,
+) -> Result<()>
+where
+    I: IntoIterator,
+    I::Item: AsRef<OsStr>,
+{
+    let Some(names) = list_flake_configurations(reference, output, eval_args) else {
+        debug!("Couldn't list {output} of {reference}, skipping validation");
+        return Ok(());
+    };
 
-    // Handle to the thread collecting closure size information.
-    let closure_size_handle =
-        dix::spawn_size_diff(old_generation.to_path_buf(), new_generation.to_path_buf());
+    if names.iter().any(|n| n == name) {
+        return Ok(());
+    }
 
-    let wrote =
-        dix::write_paths_diffln(&mut out, old_generation, new_generation).unwrap_or_default();
+    let mut message =
+        format!("Configuration '{name}' not found in {output} of flake '{reference}'");
+    if names.is_empty() {
+        message.push_str(&format!("\n{output} is empty"));
+    } else {
+        message.push_str(&format!("\nAvailable configurations: {}", names.join(", ")));
+    }
 
-    if let Ok((size_old, size_new)) = closure_size_handle
-        .join()
-        .map_err(|_| eyre::eyre!("Failed to join closure size computation thread"))?
-    {
-        if size_old == size_new {
-            println!("No version or size changes.");
+    let suggestions = close_matches(name, &names);
+    if !suggestions.is_empty() {
+        message.push_str(&format!("\nDid you mean: {}?", suggestions.join(", ")));
+    }
+
+    eyre::bail!(message)
+}
+
+/// Candidates similar to `name`, closest first.
+fn close_matches<'a>(name: &str, candidates: &'a [String]) -> Vec<&'a str> {
+    let name = name.to_lowercase();
+    let max_distance = (name.chars().count() / 3).max(2);
+
+    let mut matches: Vec<(usize, &str)> = candidates
+        .iter()
+        .filter_map(|candidate| {
+            let lower = candidate.to_lowercase();
+            let distance = edit_distance(&name, &lower);
+            (distance <= max_distance || lower.contains(&name) || name.contains(&lower))
+                .then_some((distance, candidate.as_str()))
+        })
+        .collect();
+
+    matches.sort();
+    matches
+        .into_iter()
+        .map(|(_, candidate)| candidate)
+        .collect()
+}
+
+/// Levenshtein distance between two strings
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut curr = vec![i + 1; b.len() + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = usize::from(ca != *cb);
+            curr[j + 1] = (prev[j] + cost).min(prev[j + 1] + 1).min(curr[j] + 1);
+        }
+        prev = curr;
+    }
+
+    prev[b.len()]
+}
+
+/// Free bytes on the filesystem containing `path`, if it exists.
+pub fn free_space(path: &Path) -> Option<u64> {
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    #[allow(clippy::useless_conversion)]
+    Some(u64::from(stat.blocks_available()) * u64::from(stat.fragment_size()))
+}
+
+/// The Nix system double of an architecture and kernel, named like `uname -sm`
+/// or [`std::env::consts`] do, e.g. `aarch64-linux`.
+#[must_use]
+pub fn nix_system(arch: &str, os: &str) -> String {
+    let arch = match arch {
+        "arm64" => "aarch64",
+        "amd64" => "x86_64",
+        "x86" | "i386" | "i586" | "i686" => "i686",
+        other => other,
+    };
+    let os = match os.to_lowercase().as_str() {
+        "macos" => "darwin".to_string(),
+        os => os.to_string(),
+    };
+    format!("{arch}-{os}")
+}
+
+/// The Nix system double of this machine, like `x86_64-linux`.
+#[must_use]
+pub fn current_system() -> String {
+    nix_system(std::env::consts::ARCH, std::env::consts::OS)
+}
+
+/// Format a byte count in MiB below a GiB, and in GiB above.
+#[must_use]
+pub fn format_bytes(bytes: u64) -> String {
+    const MIB: u64 = 1024 * 1024;
+    const GIB: u64 = 1024 * MIB;
+
+    if bytes >= GIB {
+        format!("{:.1} GiB", bytes as f64 / GIB as f64)
+    } else {
+        format!("{} MiB", bytes / MIB)
+    }
+}
+
+/// `text` without ANSI escape sequences, like the ones nix colors paths
+/// with.
+#[must_use]
+pub fn strip_colors(text: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\u{1b}' {
+            // Up to and including the letter ending the sequence
+            chars.by_ref().find(char::is_ascii_alphabetic);
         } else {
-            if wrote > 0 {
-                println!();
-            }
-            dix::write_size_diffln(&mut out, size_old, size_new)?;
+            stripped.push(c);
         }
     }
-    Ok(())
+    stripped
+}
+
+/// Parse a size like `512M` or `5G` into bytes, for use as a clap value
+/// parser. Suffixes are binary units, a bare number is in bytes.
+pub fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let (number, multiplier) = match size.char_indices().last() {
+        Some((i, 'K' | 'k')) => (&size[..i], 1 << 10),
+        Some((i, 'M' | 'm')) => (&size[..i], 1 << 20),
+        Some((i, 'G' | 'g')) => (&size[..i], 1 << 30),
+        Some((i, 'T' | 't')) => (&size[..i], 1 << 40),
+        _ => (size, 1),
+    };
+
+    number
+        .parse::<u64>()
+        .ok()
+        .and_then(|number| number.checked_mul(multiplier))
+        .ok_or_else(|| format!("invalid size '{size}', expected something like 512M or 5G"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_edit_distance() {
+        assert_eq!(edit_distance("", ""), 0);
+        assert_eq!(edit_distance("laptop", "laptop"), 0);
+        assert_eq!(edit_distance("laptop", "laptpo"), 2);
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+    }
+
+    #[test]
+    fn test_close_matches() {
+        let candidates = ["laptop", "desktop", "server", "Laptop-work"]
+            .map(String::from)
+            .to_vec();
+
+        assert_eq!(close_matches("lapotp", &candidates), vec!["laptop"]);
+        assert_eq!(
+            close_matches("laptop", &candidates),
+            vec!["laptop", "Laptop-work"]
+        );
+        assert!(close_matches("router", &candidates).is_empty());
+    }
+
+    #[test]
+    fn test_nix_system() {
+        assert_eq!(nix_system("x86_64", "linux"), "x86_64-linux");
+        assert_eq!(nix_system("aarch64", "macos"), "aarch64-darwin");
+        assert_eq!(nix_system("arm64", "Darwin"), "aarch64-darwin");
+        assert_eq!(nix_system("amd64", "FreeBSD"), "x86_64-freebsd");
+        assert_eq!(nix_system("x86", "linux"), "i686-linux");
+        assert_eq!(nix_system("i386", "Linux"), "i686-linux");
+    }
+
+    #[test]
+    fn test_format_bytes() {
+        assert_eq!(format_bytes(50 * 1024 * 1024), "50 MiB");
+        assert_eq!(format_bytes(3 * 1024 * 1024 * 1024 / 2), "1.5 GiB");
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("1024"), Ok(1024));
+        assert_eq!(parse_size("512M"), Ok(512 * 1024 * 1024));
+        assert_eq!(parse_size("5g"), Ok(5 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("0"), Ok(0));
+        assert!(parse_size("5GB").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn test_nix_variant() {
+        assert_eq!(
+            NixVariant::from_version_output("nix (Nix) 2.28.4"),
+            NixVariant::Nix
+        );
+        assert_eq!(
+            NixVariant::from_version_output("nix (Lix, like Nix) 2.91.1"),
+            NixVariant::Lix
+        );
+        assert_eq!(
+            NixVariant::from_version_output("nix (Determinate Nix 3.6.2) 2.29.0"),
+            NixVariant::Determinate
+        );
+        assert_eq!(
+            NixVariant::from_version_output("nix (Snix) 0.1.0"),
+            NixVariant::Other("Snix".to_string())
+        );
+    }
+
+    #[test]
+    fn test_nix_version_number() {
+        assert_eq!(
+            nix_version_number("nix (Determinate Nix 3.6.2) 2.29.0"),
+            "2.29.0"
+        );
+        assert_eq!(nix_version_number("nix (Nix) 2.28.4"), "2.28.4");
+    }
 }
@@ -0,0 +1,178 @@
+//! Scanning closures for known vulnerabilities with `vulnix`.
+//!
+//! nh doesn't keep a CVE database itself. Instead it runs `vulnix` on both
+//! the current and the new system, and reports which vulnerabilities the new
+//! configuration introduces or fixes.
+
+use std::collections::BTreeSet;
+use std::path::Path;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::commands::Command;
+use crate::theme::{Role, paint};
+
+/// A package in a closure affected by a vulnerability.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Finding {
+    /// Package name and version, like `openssl-3.0.7`
+    pub package: String,
+    pub cve: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct VulnixEntry {
+    name: String,
+    #[serde(default)]
+    affected_by: Vec<String>,
+}
+
+fn parse_vulnix(output: &str) -> Result<BTreeSet<Finding>> {
+    let entries: Vec<VulnixEntry> =
+        serde_json::from_str(output).wrap_err("Failed to parse vulnix output")?;
+
+    Ok(entries
+        .into_iter()
+        .flat_map(|entry| {
+            entry.affected_by.into_iter().map(move |cve| Finding {
+                package: entry.name.clone(),
+                cve,
+            })
+        })
+        .collect())
+}
+
+/// Vulnerabilities affecting the closure of `path`.
+pub fn scan(path: &Path) -> Result<BTreeSet<Finding>> {
+    which::which("vulnix").map_err(|_| {
+        eyre!("vulnix is required for vulnerability scans, but wasn't found in $PATH")
+    })?;
+
+    // vulnix exits with a non-zero status when it finds anything, so only
+    // its output tells whether the scan worked
+    let output = Command::new("vulnix")
+        .arg("--json")
+        .arg(path)
+        .message(format!("Scanning {} for vulnerabilities", path.display()))
+        .run_capture()?
+        .unwrap_or_default();
+
+    if output.trim().is_empty() {
+        bail!("vulnix produced no output for {}", path.display());
+    }
+
+    parse_vulnix(&output)
+}
+
+/// Vulnerabilities introduced and fixed by going from `old` to `new`.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub introduced: Vec<Finding>,
+    pub fixed: Vec<Finding>,
+    /// Number of vulnerabilities in the new closure, including old ones
+    pub total: usize,
+}
+
+impl Report {
+    #[must_use]
+    pub fn compare(old: &BTreeSet<Finding>, new: &BTreeSet<Finding>) -> Self {
+        Self {
+            introduced: new.difference(old).cloned().collect(),
+            fixed: old.difference(new).cloned().collect(),
+            total: new.len(),
+        }
+    }
+
+    pub fn print(&self) {
+        if !crate::output::human() {
+            return;
+        }
+
+        println!(
+            "Vulnerabilities: {} new, {} fixed, {} total",
+            self.introduced.len(),
+            self.fixed.len(),
+            self.total
+        );
+        for finding in &self.introduced {
+            println!(
+                "{} {} ({})",
+                paint("+", Role::Error),
+                finding.cve,
+                finding.package
+            );
+        }
+        for finding in &self.fixed {
+            println!(
+                "{} {} ({})",
+                paint("-", Role::Success),
+                finding.cve,
+                finding.package
+            );
+        }
+    }
+}
+
+/// Scan `new`, comparing against `old` if given, and print the summary.
+pub fn check(old: Option<&Path>, new: &Path) -> Result<Report> {
+    let new_findings = scan(new)?;
+    let old_findings = match old {
+        Some(old) => scan(old).unwrap_or_else(|err| {
+            debug!("Couldn't scan {}: {err:#}", old.display());
+            BTreeSet::new()
+        }),
+        None => BTreeSet::new(),
+    };
+
+    let report = Report::compare(&old_findings, &new_findings);
+    report.print();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_vulnix() {
+        let output = r#"[
+  {
+    "name": "openssl-3.0.7",
+    "pname": "openssl",
+    "version": "3.0.7",
+    "derivation": "/nix/store/abc-openssl-3.0.7.drv",
+    "affected_by": ["CVE-2023-0286", "CVE-2023-0215"],
+    "whitelisted": []
+  },
+  { "name": "zlib-1.2.13", "affected_by": [] }
+]"#;
+
+        let findings = parse_vulnix(output).unwrap();
+        assert_eq!(findings.len(), 2);
+        assert!(findings.contains(&Finding {
+            package: "openssl-3.0.7".to_string(),
+            cve: "CVE-2023-0286".to_string(),
+        }));
+    }
+
+    #[test]
+    fn test_report() {
+        let finding = |package: &str, cve: &str| Finding {
+            package: package.to_string(),
+            cve: cve.to_string(),
+        };
+        let old = BTreeSet::from([
+            finding("openssl-3.0.7", "CVE-1"),
+            finding("curl-8.0", "CVE-2"),
+        ]);
+        let new = BTreeSet::from([finding("curl-8.0", "CVE-2"), finding("curl-8.0", "CVE-3")]);
+
+        let report = Report::compare(&old, &new);
+        assert_eq!(report.introduced, vec![finding("curl-8.0", "CVE-3")]);
+        assert_eq!(report.fixed, vec![finding("openssl-3.0.7", "CVE-1")]);
+        assert_eq!(report.total, 2);
+    }
+}
@@ -0,0 +1,199 @@
+//! Uncommitted changes in the git checkout of a local flake.
+//!
+//! Nix builds a flake in a git repository from its working tree, but only
+//! sees the files git tracks: modified files are part of the build, new
+//! files that haven't been added aren't. Before building, nh points out both,
+//! and can commit or stash them first with `--commit-changes` and `--stash`.
+//! With `allow-dirty = false` in the `[rebuild]` section of the
+//! configuration (`--require-clean`), only clean trees are built.
+
+use std::path::{Path, PathBuf};
+use std::process;
+
+use color_eyre::Result;
+use color_eyre::eyre::{Context, bail, eyre};
+use tracing::{debug, warn};
+
+use crate::commands::Command;
+use crate::exit::Failure;
+use crate::generations::local_flake_dir;
+use crate::installable::Installable;
+use crate::interface::CommonRebuildArgs;
+
+/// Files listed at most in warnings
+const MAX_LISTED: usize = 5;
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Status {
+    /// Tracked files with changes, which nix sees
+    modified: Vec<String>,
+    /// Files git doesn't know about, which nix doesn't see
+    untracked: Vec<String>,
+}
+
+impl Status {
+    fn is_clean(&self) -> bool {
+        self.modified.is_empty() && self.untracked.is_empty()
+    }
+}
+
+/// Parse the output of `git status --porcelain`.
+fn parse_status(porcelain: &str) -> Status {
+    let mut status = Status::default();
+    for line in porcelain.lines().filter(|line| line.len() > 3) {
+        let (code, path) = line.split_at(3);
+        if code == "?? " {
+            status.untracked.push(path.to_string());
+        } else {
+            status.modified.push(path.to_string());
+        }
+    }
+    status
+}
+
+/// Status of the files under `dir`, `None` if it isn't in a git repository.
+fn status(dir: &Path) -> Option<Status> {
+    let output = process::Command::new("git")
+        .arg("-C")
+        .arg(dir)
+        .args(["status", "--porcelain", "--", "."])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())?;
+    Some(parse_status(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// `files`, shortened to the first few.
+fn list(files: &[String]) -> String {
+    let mut listed = files[..files.len().min(MAX_LISTED)].join(", ");
+    if files.len() > MAX_LISTED {
+        listed.push_str(&format!(" and {} more", files.len() - MAX_LISTED));
+    }
+    listed
+}
+
+/// Changes stashed with `--stash`, restored when dropped.
+#[derive(Debug)]
+pub struct Stash {
+    dir: PathBuf,
+}
+
+impl Drop for Stash {
+    fn drop(&mut self) {
+        debug!("Restoring the stashed changes in {}", self.dir.display());
+        let res = Command::new("git")
+            .arg("-C")
+            .arg(&self.dir)
+            .args(["stash", "pop"])
+            .message("Restoring the stashed changes")
+            .run();
+        if let Err(err) = res {
+            warn!(
+                "Failed to restore the stashed changes, run `git stash pop` in {} yourself: {err:#}",
+                self.dir.display()
+            );
+        }
+    }
+}
+
+impl CommonRebuildArgs {
+    /// Check the git working tree of `installable`, if it is a local flake,
+    /// and commit or stash its changes as requested.
+    ///
+    /// The returned stash restores the changes when it is dropped, so it has
+    /// to be kept until the build is done.
+    pub fn prepare_worktree(&self, installable: &Installable) -> Result<Option<Stash>> {
+        let Installable::Flake { reference, .. } = installable else {
+            return Ok(None);
+        };
+        // A pinned revision doesn't depend on the working tree
+        if self.rev.is_some() || self.git_ref.is_some() {
+            return Ok(None);
+        }
+        let Some(dir) = local_flake_dir(reference) else {
+            return Ok(None);
+        };
+        let Some(status) = status(&dir) else {
+            if self.commit_changes.is_some() || self.stash {
+                bail!("{} isn't a git repository", dir.display());
+            }
+            return Ok(None);
+        };
+        debug!(?dir, ?status, "Checked the git working tree");
+
+        if status.is_clean() {
+            return Ok(None);
+        }
+
+        let git = || Command::new("git").arg("-C").arg(&dir).dry(self.dry);
+
+        if let Some(message) = &self.commit_changes {
+            git().args(["add", "--all", "--", "."]).run()?;
+            git()
+                .args(["commit", "--message", message])
+                .message("Committing the changes to the flake")
+                .run()
+                .wrap_err("Failed to commit the changes to the flake")?;
+            return Ok(None);
+        }
+
+        if self.stash {
+            git()
+                .args(["stash", "push", "--include-untracked", "--", "."])
+                .message("Stashing the changes to the flake")
+                .run()
+                .wrap_err("Failed to stash the changes to the flake")?;
+            return Ok((!self.dry).then_some(Stash { dir }));
+        }
+
+        if self.require_clean {
+            return Err(eyre!(
+                "The flake in {} has uncommitted changes: {}. Commit them, or pass --commit-changes or --stash",
+                dir.display(),
+                list(&[status.modified, status.untracked].concat())
+            )
+            .wrap_err(Failure::Environment));
+        }
+
+        if !status.modified.is_empty() {
+            warn!(
+                "Building with uncommitted changes to {}",
+                list(&status.modified)
+            );
+        }
+        // Flakes referenced with `path:` are copied as they are
+        if !status.untracked.is_empty() && !reference.starts_with("path:") {
+            warn!(
+                "Nix doesn't see untracked files, `git add` them to include them in the build: {}",
+                list(&status.untracked)
+            );
+        }
+
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_status() {
+        let status = parse_status(" M flake.nix\nA  hosts/new.nix\n?? hosts/draft.nix\n");
+        assert_eq!(
+            status,
+            Status {
+                modified: vec!["flake.nix".to_string(), "hosts/new.nix".to_string()],
+                untracked: vec!["hosts/draft.nix".to_string()],
+            }
+        );
+        assert!(parse_status("").is_clean());
+    }
+
+    #[test]
+    fn test_list() {
+        let files: Vec<String> = (1..=7).map(|n| format!("{n}.nix")).collect();
+        assert_eq!(list(&files[..2]), "1.nix, 2.nix");
+        assert_eq!(list(&files), "1.nix, 2.nix, 3.nix, 4.nix, 5.nix and 2 more");
+    }
+}
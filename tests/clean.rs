@@ -0,0 +1,22 @@
+mod common;
+
+use common::FakeNix;
+
+#[test]
+fn test_clean_profile() {
+    let fake = FakeNix::new();
+    let profile = fake.profile("home-manager", &["a", "b", "c", "d"], 4);
+
+    fake.nh(&["clean", "profile", "--keep", "2", profile.to_str().unwrap()]);
+
+    let remaining: Vec<bool> = (1..=4)
+        .map(|number| {
+            profile
+                .with_file_name(format!("home-manager-{number}-link"))
+                .symlink_metadata()
+                .is_ok()
+        })
+        .collect();
+    assert_eq!(remaining, [false, false, true, true]);
+    assert!(fake.calls().contains(&"nix store gc".to_string()));
+}
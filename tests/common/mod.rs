@@ -0,0 +1,162 @@
+//! A fake nix backend for running nh end to end without a nix store.
+//!
+//! `FakeNix` puts scripts named like the programs nh runs, `nix`, `nvd` and
+//! `sudo`, first in the `PATH` of nh. They record how they were called and
+//! succeed, and `nix build --out-link` links the out-link to a fake system
+//! made with `FakeNix::system`. Profiles are laid out like in
+//! `/nix/var/nix/profiles`, with links to fake systems as generations.
+
+#![allow(dead_code)]
+
+use std::fs;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use tempfile::TempDir;
+
+const NIX: &str = r#"#!/bin/sh
+echo "nix $*" >> "$FAKE_NIX_LOG"
+case "$1" in
+  --version) echo "nix (Nix) 2.31.0"; exit 0 ;;
+  config) echo "nix-command flakes"; exit 0 ;;
+esac
+prev=
+for arg in "$@"; do
+  [ "$prev" = --out-link ] && ln -sfn "$FAKE_NIX_SYSTEM" "$arg"
+  [ "$arg" = --print-out-paths ] && echo "$FAKE_NIX_SYSTEM"
+  prev=$arg
+done
+exit 0
+"#;
+
+const NVD: &str = r#"#!/bin/sh
+echo "nvd $*" >> "$FAKE_NIX_LOG"
+"#;
+
+// Runs the command without elevating, past the options nh passes to sudo
+const SUDO: &str = r#"#!/bin/sh
+echo "sudo $*" >> "$FAKE_NIX_LOG"
+while [ $# -gt 0 ]; do
+  case "$1" in
+    -*|env|*=*) shift ;;
+    *) break ;;
+  esac
+done
+exec "$@"
+"#;
+
+pub struct FakeNix {
+    dir: TempDir,
+}
+
+impl FakeNix {
+    pub fn new() -> Self {
+        let dir = tempfile::tempdir().unwrap();
+        let bin = dir.path().join("bin");
+        fs::create_dir(&bin).unwrap();
+        for (name, script) in [("nix", NIX), ("nvd", NVD), ("sudo", SUDO)] {
+            write_script(&bin.join(name), script);
+        }
+        for sub in ["home", "store", "profiles"] {
+            fs::create_dir(dir.path().join(sub)).unwrap();
+        }
+        let fake = Self { dir };
+        fake.system("built");
+        fake
+    }
+
+    pub fn path(&self) -> &Path {
+        self.dir.path()
+    }
+
+    /// A fake system named `name`, whose `switch-to-configuration` records
+    /// its calls like `name switch`.
+    pub fn system(&self, name: &str) -> PathBuf {
+        let system = self.path().join("store").join(name);
+        if !system.exists() {
+            fs::create_dir_all(system.join("bin")).unwrap();
+            fs::write(system.join("nixos-version"), "25.05").unwrap();
+            write_script(
+                &system.join("bin/switch-to-configuration"),
+                &format!("#!/bin/sh\necho \"{name} $*\" >> \"$FAKE_NIX_LOG\"\n"),
+            );
+        }
+        system
+    }
+
+    /// A profile named `name` with a generation for each of `generations`,
+    /// numbered from 1, pointing to the generation `current`.
+    pub fn profile(&self, name: &str, generations: &[&str], current: usize) -> PathBuf {
+        let profile = self.path().join("profiles").join(name);
+        for (number, system) in (1..).zip(generations) {
+            symlink(
+                self.system(system),
+                profile.with_file_name(format!("{name}-{number}-link")),
+            )
+            .unwrap();
+        }
+        symlink(format!("{name}-{current}-link"), &profile).unwrap();
+        profile
+    }
+
+    /// Put a program named `name` running `script` in the `PATH` of nh.
+    pub fn program(&self, name: &str, script: &str) {
+        write_script(&self.path().join("bin").join(name), script);
+    }
+
+    /// Run nh with `args`, with the fake programs in its `PATH`, and make
+    /// sure it succeeds.
+    pub fn nh(&self, args: &[&str]) -> Output {
+        let output = self.nh_with_env(args, &[]);
+        assert!(
+            output.status.success(),
+            "nh {} failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        output
+    }
+
+    /// Run nh with `args` and the additional environment `env`, whether it
+    /// succeeds or not.
+    pub fn nh_with_env(&self, args: &[&str], env: &[(&str, &str)]) -> Output {
+        let path = format!(
+            "{}:{}",
+            self.path().join("bin").display(),
+            std::env::var("PATH").unwrap_or_default()
+        );
+        Command::new(env!("CARGO_BIN_EXE_nh"))
+            .args(args)
+            .env_clear()
+            .env("PATH", path)
+            .env("HOME", self.path().join("home"))
+            .env("USER", "me")
+            .env("XDG_CACHE_HOME", self.path().join("home/.cache"))
+            .env("XDG_STATE_HOME", self.path().join("home/.local/state"))
+            .env("NH_STATE_DIR", self.path().join("state"))
+            .env("NH_BYPASS_ROOT_CHECK", "true")
+            .env("NH_NO_CHECKS", "1")
+            .env("NO_COLOR", "1")
+            .env("FAKE_NIX_LOG", self.path().join("log"))
+            .env("FAKE_NIX_SYSTEM", self.path().join("store/built"))
+            .envs(env.iter().copied())
+            .current_dir(self.path())
+            .output()
+            .unwrap()
+    }
+
+    /// The calls of the fake programs, one per line.
+    pub fn calls(&self) -> Vec<String> {
+        fs::read_to_string(self.path().join("log"))
+            .unwrap_or_default()
+            .lines()
+            .map(ToString::to_string)
+            .collect()
+    }
+}
+
+fn write_script(path: &Path, script: &str) {
+    fs::write(path, script).unwrap();
+    fs::set_permissions(path, fs::Permissions::from_mode(0o755)).unwrap();
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use std::fs;
+
+use common::FakeNix;
+
+#[test]
+fn test_home_build_override_input() {
+    let fake = FakeNix::new();
+    let flake = fake.path().join("flake");
+    fs::create_dir(&flake).unwrap();
+    let checkout = fake.path().join("home-manager");
+
+    fake.nh(&[
+        "home",
+        "build",
+        "--no-nom",
+        "--diff",
+        "never",
+        "--override-input",
+        "home-manager",
+        checkout.to_str().unwrap(),
+        &format!("{}#homeConfigurations.me", flake.display()),
+    ]);
+
+    let calls = fake.calls();
+    let build = calls
+        .iter()
+        .find(|call| call.starts_with("nix build"))
+        .unwrap();
+    assert!(build.contains("#homeConfigurations.me"));
+    assert!(build.contains(&format!(
+        "--override-input home-manager {}",
+        checkout.display()
+    )));
+}
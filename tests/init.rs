@@ -0,0 +1,36 @@
+mod common;
+
+use std::fs;
+
+use common::FakeNix;
+
+#[test]
+fn test_init_combined() {
+    let fake = FakeNix::new();
+    fake.program("git", "#!/bin/sh\necho \"git $*\" >> \"$FAKE_NIX_LOG\"\n");
+    let flake = fake.path().join("config");
+    let dir = flake.to_str().unwrap();
+
+    fake.nh(&[
+        "init",
+        "combined",
+        "--hostname",
+        "web",
+        "--system",
+        "x86_64-linux",
+        "--git",
+        dir,
+    ]);
+
+    let text = fs::read_to_string(flake.join("flake.nix")).unwrap();
+    assert!(text.contains("nixosConfigurations.\"web\""));
+    assert!(text.contains("homeConfigurations.\"me\""));
+    let home = fs::read_to_string(flake.join("home.nix")).unwrap();
+    assert!(home.contains("home.homeDirectory = \"/home/me\";"));
+
+    let calls = fake.calls();
+    assert_eq!(calls[0], format!("git -C {dir} init"));
+    assert!(calls[1].starts_with(&format!(
+        "git -C {dir} add -- flake.nix configuration.nix home.nix"
+    )));
+}
@@ -0,0 +1,139 @@
+mod common;
+
+use std::fs;
+
+use common::FakeNix;
+
+#[test]
+fn test_os_switch() {
+    let fake = FakeNix::new();
+    let flake = fake.path().join("flake");
+    fs::create_dir(&flake).unwrap();
+
+    fake.nh(&[
+        "os",
+        "switch",
+        "--hostname",
+        "web",
+        "--no-nom",
+        "--diff",
+        "never",
+        flake.to_str().unwrap(),
+    ]);
+
+    let calls = fake.calls();
+    let build = calls
+        .iter()
+        .find(|call| call.starts_with("nix build"))
+        .unwrap();
+    assert!(build.contains("#nixosConfigurations.web.config.system.build.toplevel"));
+    assert!(build.contains("--out-link"));
+
+    let activation: Vec<&str> = calls
+        .iter()
+        .filter(|call| call.starts_with("built ") || call.contains("--profile"))
+        .map(String::as_str)
+        .collect();
+    assert_eq!(activation.len(), 3);
+    assert_eq!(activation[0], "built test");
+    assert!(
+        activation[1].starts_with("nix build --no-link --profile /nix/var/nix/profiles/system")
+    );
+    assert_eq!(activation[2], "built boot");
+}
+
+#[test]
+fn test_os_rollback() {
+    let fake = FakeNix::new();
+    let profile = fake.profile("system", &["first", "second", "third"], 3);
+    let profile = profile.to_str().unwrap();
+
+    fake.nh(&["os", "rollback", "--profile", profile, "--diff", "never"]);
+    assert_eq!(fs::canonicalize(profile).unwrap(), fake.system("second"));
+    assert!(fake.calls().contains(&"second switch".to_string()));
+
+    fake.nh(&[
+        "os",
+        "rollback",
+        "--profile",
+        profile,
+        "--to",
+        "1",
+        "--diff",
+        "never",
+        "--dry",
+    ]);
+    assert_eq!(fs::canonicalize(profile).unwrap(), fake.system("second"));
+    assert!(!fake.calls().contains(&"first switch".to_string()));
+}
+
+#[test]
+fn test_os_switch_unreachable_target() {
+    let fake = FakeNix::new();
+    let flake = fake.path().join("flake");
+    fs::create_dir(&flake).unwrap();
+    fake.program(
+        "ssh",
+        "#!/bin/sh\necho \"ssh $*\" >> \"$FAKE_NIX_LOG\"\necho 'Connection refused' >&2\nexit 255\n",
+    );
+
+    let output = fake.nh_with_env(
+        &[
+            "os",
+            "switch",
+            "--hostname",
+            "web",
+            "--target-host",
+            "web",
+            "--no-nom",
+            "--diff",
+            "never",
+            flake.to_str().unwrap(),
+        ],
+        &[(
+            "NH_NO_CHECKS",
+            "version,features,disk-space,substituters,trust,secrets,system",
+        )],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(
+        stderr.contains("Failed to connect to web over ssh"),
+        "{stderr}"
+    );
+    assert!(stderr.contains("Connection refused"), "{stderr}");
+    assert!(!stderr.contains("Nix isn't installed"), "{stderr}");
+    assert!(!fake.calls().iter().any(|call| call.contains("switch")));
+}
+
+#[test]
+fn test_os_switch_silent_error() {
+    let fake = FakeNix::new();
+    let flake = fake.path().join("flake");
+    fs::create_dir(&flake).unwrap();
+    fake.program(
+        "ssh",
+        "#!/bin/sh\necho 'Connection refused' >&2\nexit 255\n",
+    );
+
+    let output = fake.nh_with_env(
+        &[
+            "-qq",
+            "os",
+            "switch",
+            "--hostname",
+            "web",
+            "--target-host",
+            "web",
+            "--no-nom",
+            flake.to_str().unwrap(),
+        ],
+        &[(
+            "NH_NO_CHECKS",
+            "version,features,disk-space,substituters,trust,secrets,system",
+        )],
+    );
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!output.status.success());
+    assert!(!stderr.contains("Failed to connect"), "{stderr}");
+}
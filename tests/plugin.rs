@@ -0,0 +1,21 @@
+mod common;
+
+use common::FakeNix;
+
+#[test]
+fn test_plugin() {
+    let fake = FakeNix::new();
+    fake.program(
+        "nh-hello",
+        "#!/bin/sh\necho \"hello $* from nh $NH_VERSION, json: $NH_JSON\"\n",
+    );
+
+    let output = fake.nh(&["--json", "hello", "--world", "x"]);
+    assert_eq!(
+        String::from_utf8_lossy(&output.stdout),
+        format!(
+            "hello --world x from nh {}, json: 1\n",
+            env!("CARGO_PKG_VERSION")
+        )
+    );
+}